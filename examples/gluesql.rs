@@ -1,170 +1,142 @@
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
-use async_trait::async_trait;
-use futures::stream;
-use tokio::net::TcpListener;
-
+use gluesql::core::data::Interval;
 use gluesql::prelude::*;
 use pgwire::api::auth::noop::NoopStartupHandler;
-use pgwire::api::query::{PlaceholderExtendedQueryHandler, SimpleQueryHandler};
-use pgwire::api::results::{DataRowEncoder, FieldFormat, FieldInfo, QueryResponse, Response, Tag};
-use pgwire::api::{ClientInfo, MakeHandler, StatelessMakeHandler, Type};
+use pgwire::api::blocking::{BlockingQueryHandler, BlockingSimpleQueryHandler, ResponseWriter};
+use pgwire::api::query::PlaceholderExtendedQueryHandler;
+use pgwire::api::results::{DataRowEncoder, FieldFormat, FieldInfo, Response, Tag};
+use pgwire::api::{StatelessMakeHandler, Type};
 use pgwire::error::{PgWireError, PgWireResult};
-use pgwire::tokio::process_socket;
+use pgwire::server::PgWireServerBuilder;
+use pgwire::types::Interval as PgInterval;
 
 pub struct GluesqlProcessor {
-    glue: Arc<Mutex<Glue<MemoryStorage>>>,
+    glue: Mutex<Glue<MemoryStorage>>,
 }
 
-#[async_trait]
-impl SimpleQueryHandler for GluesqlProcessor {
-    async fn do_query<'a, C>(
+// gluesql executes queries synchronously, so it runs on the blocking thread
+// pool instead of the tokio reactor
+impl BlockingQueryHandler for GluesqlProcessor {
+    fn do_query(
         &self,
-        _client: &mut C,
-        query: &'a str,
-    ) -> PgWireResult<Vec<Response<'a>>>
-    where
-        C: ClientInfo + Unpin + Send + Sync,
-    {
+        _metadata: &HashMap<String, String>,
+        query: &str,
+        writer: &mut ResponseWriter,
+    ) -> PgWireResult<()> {
         println!("{:?}", query);
         let mut glue = self.glue.lock().unwrap();
-        futures::executor::block_on(glue.execute(query))
-            .map_err(|err| PgWireError::ApiError(Box::new(err)))
-            .and_then(|payloads| {
-                payloads
-                    .iter()
-                    .map(|payload| match payload {
-                        Payload::Select { labels, rows } => {
-                            let fields = labels
-                                .iter()
-                                .map(|label| {
-                                    FieldInfo::new(
-                                        label.into(),
-                                        None,
-                                        None,
-                                        Type::UNKNOWN,
-                                        FieldFormat::Text,
-                                    )
-                                })
-                                .collect::<Vec<_>>();
-                            let fields = Arc::new(fields);
+        let payloads = futures::executor::block_on(glue.execute(query))
+            .map_err(|err| PgWireError::ApiError(Box::new(err)))?;
 
-                            let mut results = Vec::with_capacity(rows.len());
-                            for row in rows {
-                                let mut encoder = DataRowEncoder::new(fields.clone());
-                                for field in row.iter() {
-                                    match field {
-                                        Value::Bool(v) => encoder
-                                            .encode_field_with_type_and_format(
-                                                v,
-                                                &Type::BOOL,
-                                                FieldFormat::Text,
-                                            )?,
-                                        Value::I8(v) => encoder.encode_field_with_type_and_format(
-                                            v,
-                                            &Type::CHAR,
-                                            FieldFormat::Text,
-                                        )?,
-                                        Value::I16(v) => encoder
-                                            .encode_field_with_type_and_format(
-                                                v,
-                                                &Type::INT2,
-                                                FieldFormat::Text,
-                                            )?,
-                                        Value::I32(v) => encoder
-                                            .encode_field_with_type_and_format(
-                                                v,
-                                                &Type::INT4,
-                                                FieldFormat::Text,
-                                            )?,
-                                        Value::I64(v) => encoder
-                                            .encode_field_with_type_and_format(
-                                                v,
-                                                &Type::INT8,
-                                                FieldFormat::Text,
-                                            )?,
-                                        Value::U8(v) => encoder.encode_field_with_type_and_format(
-                                            &(*v as i8),
-                                            &Type::CHAR,
-                                            FieldFormat::Text,
-                                        )?,
-                                        Value::F64(v) => encoder
-                                            .encode_field_with_type_and_format(
-                                                v,
-                                                &Type::FLOAT8,
-                                                FieldFormat::Text,
-                                            )?,
-                                        Value::Str(v) => encoder
-                                            .encode_field_with_type_and_format(
-                                                v,
-                                                &Type::VARCHAR,
-                                                FieldFormat::Text,
-                                            )?,
-                                        Value::Bytea(v) => encoder
-                                            .encode_field_with_type_and_format(
-                                                v,
-                                                &Type::BYTEA,
-                                                FieldFormat::Text,
-                                            )?,
-                                        Value::Date(v) => encoder
-                                            .encode_field_with_type_and_format(
-                                                v,
-                                                &Type::DATE,
-                                                FieldFormat::Text,
-                                            )?,
-                                        Value::Time(v) => encoder
-                                            .encode_field_with_type_and_format(
-                                                v,
-                                                &Type::TIME,
-                                                FieldFormat::Text,
-                                            )?,
-                                        Value::Timestamp(v) => encoder
-                                            .encode_field_with_type_and_format(
-                                                v,
-                                                &Type::TIMESTAMP,
-                                                FieldFormat::Text,
-                                            )?,
-                                        _ => unimplemented!(),
-                                    }
-                                }
-                                results.push(encoder.finish());
-                            }
+        for payload in payloads {
+            let tag = match payload {
+                Payload::Select { labels, rows } => {
+                    let fields = labels
+                        .iter()
+                        .map(|label| {
+                            FieldInfo::new(
+                                label.into(),
+                                None,
+                                None,
+                                Type::UNKNOWN,
+                                FieldFormat::Text,
+                            )
+                        })
+                        .collect::<Vec<_>>();
+                    let fields = Arc::new(fields);
 
-                            Ok(Response::Query(QueryResponse::new(
-                                fields,
-                                stream::iter(results.into_iter()),
-                            )))
-                        }
-                        Payload::Insert(rows) => Ok(Response::Execution(
-                            Tag::new("INSERT").with_oid(0).with_rows(*rows),
-                        )),
-                        Payload::Delete(rows) => {
-                            Ok(Response::Execution(Tag::new("DELETE").with_rows(*rows)))
-                        }
-                        Payload::Update(rows) => {
-                            Ok(Response::Execution(Tag::new("UPDATE").with_rows(*rows)))
+                    let mut encoder = DataRowEncoder::new(fields.clone());
+                    let mut results = writer.send_query(fields)?;
+                    for row in rows {
+                        for field in row.iter() {
+                            encode_value(&mut encoder, field)?;
                         }
-                        Payload::Create => Ok(Response::Execution(Tag::new("CREATE TABLE"))),
-                        Payload::AlterTable => Ok(Response::Execution(Tag::new("ALTER TABLE"))),
-                        Payload::DropTable => Ok(Response::Execution(Tag::new("DROP TABLE"))),
-                        Payload::CreateIndex => Ok(Response::Execution(Tag::new("CREATE INDEX"))),
-                        Payload::DropIndex => Ok(Response::Execution(Tag::new("DROP INDEX"))),
-                        _ => {
-                            unimplemented!()
-                        }
-                    })
-                    .collect::<Result<Vec<Response>, PgWireError>>()
-            })
+                        results.send(encoder.take_row()?)?;
+                    }
+                    continue;
+                }
+                Payload::Insert(rows) => Tag::insert(rows),
+                Payload::Delete(rows) => Tag::delete(rows),
+                Payload::Update(rows) => Tag::update(rows),
+                Payload::Create => Tag::new("CREATE TABLE"),
+                Payload::AlterTable => Tag::new("ALTER TABLE"),
+                Payload::DropTable => Tag::new("DROP TABLE"),
+                Payload::CreateIndex => Tag::new("CREATE INDEX"),
+                Payload::DropIndex => Tag::new("DROP INDEX"),
+                _ => {
+                    unimplemented!()
+                }
+            };
+            writer.send(Response::Execution(tag))?;
+        }
+        Ok(())
+    }
+}
+
+fn encode_value(encoder: &mut DataRowEncoder, value: &Value) -> PgWireResult<()> {
+    match value {
+        Value::Bool(v) => {
+            encoder.encode_field_with_type_and_format(v, &Type::BOOL, FieldFormat::Text)
+        }
+        Value::I8(v) => encoder.encode_field_with_type_and_format(
+            &i16::from(*v),
+            &Type::INT2,
+            FieldFormat::Text,
+        ),
+        Value::I16(v) => {
+            encoder.encode_field_with_type_and_format(v, &Type::INT2, FieldFormat::Text)
+        }
+        Value::I32(v) => {
+            encoder.encode_field_with_type_and_format(v, &Type::INT4, FieldFormat::Text)
+        }
+        Value::I64(v) => {
+            encoder.encode_field_with_type_and_format(v, &Type::INT8, FieldFormat::Text)
+        }
+        Value::U8(v) => encoder.encode_field_with_type_and_format(
+            &i16::from(*v),
+            &Type::INT2,
+            FieldFormat::Text,
+        ),
+        Value::F64(v) => {
+            encoder.encode_field_with_type_and_format(v, &Type::FLOAT8, FieldFormat::Text)
+        }
+        Value::Str(v) => {
+            encoder.encode_field_with_type_and_format(v, &Type::VARCHAR, FieldFormat::Text)
+        }
+        Value::Bytea(v) => {
+            encoder.encode_field_with_type_and_format(v, &Type::BYTEA, FieldFormat::Text)
+        }
+        Value::Date(v) => {
+            encoder.encode_field_with_type_and_format(v, &Type::DATE, FieldFormat::Text)
+        }
+        Value::Time(v) => {
+            encoder.encode_field_with_type_and_format(v, &Type::TIME, FieldFormat::Text)
+        }
+        Value::Timestamp(v) => {
+            encoder.encode_field_with_type_and_format(v, &Type::TIMESTAMP, FieldFormat::Text)
+        }
+        Value::Interval(v) => {
+            let interval = match v {
+                Interval::Month(months) => PgInterval::new(*months, 0, 0),
+                Interval::Microsecond(micros) => PgInterval::new(0, 0, *micros),
+            };
+            encoder.encode_field_with_type_and_format(&interval, &Type::INTERVAL, FieldFormat::Text)
+        }
+        _ => unimplemented!(),
     }
 }
 
 #[tokio::main]
 pub async fn main() {
     let gluesql = GluesqlProcessor {
-        glue: Arc::new(Mutex::new(Glue::new(MemoryStorage::default()))),
+        glue: Mutex::new(Glue::new(MemoryStorage::default())),
     };
 
-    let processor = Arc::new(StatelessMakeHandler::new(Arc::new(gluesql)));
+    let processor = Arc::new(StatelessMakeHandler::new(Arc::new(
+        BlockingSimpleQueryHandler::new(Arc::new(gluesql)),
+    )));
     // We have not implemented extended query in this server, use placeholder instead
     let placeholder = Arc::new(StatelessMakeHandler::new(Arc::new(
         PlaceholderExtendedQueryHandler,
@@ -172,22 +144,10 @@ pub async fn main() {
     let authenticator = Arc::new(StatelessMakeHandler::new(Arc::new(NoopStartupHandler)));
 
     let server_addr = "127.0.0.1:5432";
-    let listener = TcpListener::bind(server_addr).await.unwrap();
     println!("Listening to {}", server_addr);
-    loop {
-        let incoming_socket = listener.accept().await.unwrap();
-        let authenticator_ref = authenticator.make();
-        let processor_ref = processor.make();
-        let placeholder_ref = placeholder.make();
-        tokio::spawn(async move {
-            process_socket(
-                incoming_socket.0,
-                None,
-                authenticator_ref,
-                processor_ref,
-                placeholder_ref,
-            )
-            .await
-        });
-    }
+    PgWireServerBuilder::new(authenticator, processor, placeholder)
+        .with_address(server_addr)
+        .serve()
+        .await
+        .unwrap();
 }
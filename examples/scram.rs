@@ -6,18 +6,17 @@ use async_trait::async_trait;
 
 use rustls_pemfile::{certs, pkcs8_private_keys};
 use rustls_pki_types::{CertificateDer, PrivateKeyDer};
-use tokio::net::TcpListener;
 use tokio_rustls::rustls::ServerConfig;
 use tokio_rustls::TlsAcceptor;
 
-use pgwire::api::auth::scram::{gen_salted_password, MakeSASLScramAuthStartupHandler};
-use pgwire::api::auth::{AuthSource, DefaultServerParameterProvider, LoginInfo, Password};
+use pgwire::api::auth::scram::{MakeScramSha256StartupHandler, ScramSecret};
+use pgwire::api::auth::{AuthSource, DefaultServerParameterProvider, LoginInfo, Verifier};
 use pgwire::api::query::{PlaceholderExtendedQueryHandler, SimpleQueryHandler};
 use pgwire::api::results::{Response, Tag};
 
-use pgwire::api::{ClientInfo, MakeHandler, StatelessMakeHandler};
+use pgwire::api::{ClientInfo, StatelessMakeHandler};
 use pgwire::error::PgWireResult;
-use pgwire::tokio::process_socket;
+use pgwire::server::PgWireServerBuilder;
 
 pub struct DummyProcessor;
 
@@ -49,12 +48,12 @@ struct DummyAuthDB;
 
 #[async_trait]
 impl AuthSource for DummyAuthDB {
-    async fn get_password(&self, _login: &LoginInfo) -> PgWireResult<Password> {
+    async fn get_verifier(&self, _login: &LoginInfo) -> PgWireResult<Option<Verifier>> {
         let password = "pencil";
         let salt = random_salt();
 
-        let hash_password = gen_salted_password(password, salt.as_ref(), ITERATIONS);
-        Ok(Password::new(Some(salt), hash_password))
+        let secret = ScramSecret::new(password, salt.as_ref(), ITERATIONS);
+        Ok(Some(Verifier::ScramSha256(secret)))
     }
 }
 
@@ -83,11 +82,11 @@ pub async fn main() {
     let placeholder = Arc::new(StatelessMakeHandler::new(Arc::new(
         PlaceholderExtendedQueryHandler,
     )));
-    let mut authenticator = MakeSASLScramAuthStartupHandler::new(
+    let mut authenticator = MakeScramSha256StartupHandler::new(
         Arc::new(DummyAuthDB),
         Arc::new(DefaultServerParameterProvider::default()),
     );
-    authenticator.set_iterations(ITERATIONS);
+    authenticator.set_iterations(ITERATIONS).unwrap();
 
     let cert = fs::read("examples/ssl/server.crt").unwrap();
     authenticator.configure_certificate(cert.as_ref()).unwrap();
@@ -95,23 +94,11 @@ pub async fn main() {
 
     let server_addr = "127.0.0.1:5432";
     let tls_acceptor = Arc::new(setup_tls().unwrap());
-    let listener = TcpListener::bind(server_addr).await.unwrap();
     println!("Listening to {}", server_addr);
-    loop {
-        let incoming_socket = listener.accept().await.unwrap();
-        let tls_acceptor_ref = tls_acceptor.clone();
-        let authenticator_ref = authenticator.make();
-        let processor_ref = processor.make();
-        let placeholder_ref = placeholder.make();
-        tokio::spawn(async move {
-            process_socket(
-                incoming_socket.0,
-                Some(tls_acceptor_ref),
-                authenticator_ref,
-                processor_ref,
-                placeholder_ref,
-            )
-            .await
-        });
-    }
+    PgWireServerBuilder::new(authenticator, processor, placeholder)
+        .with_tls(tls_acceptor)
+        .with_address(server_addr)
+        .serve()
+        .await
+        .unwrap();
 }
@@ -1,21 +1,15 @@
-use std::fs::File;
-use std::io::{BufReader, Error as IOError, ErrorKind};
 use std::sync::Arc;
 
 use async_trait::async_trait;
 use futures::{stream, StreamExt};
-use rustls_pemfile::{certs, pkcs8_private_keys};
-use rustls_pki_types::{CertificateDer, PrivateKeyDer};
-use tokio::net::TcpListener;
-use tokio_rustls::rustls::ServerConfig;
-use tokio_rustls::TlsAcceptor;
 
 use pgwire::api::auth::noop::NoopStartupHandler;
 use pgwire::api::query::{PlaceholderExtendedQueryHandler, SimpleQueryHandler};
 use pgwire::api::results::{DataRowEncoder, FieldFormat, FieldInfo, QueryResponse, Response, Tag};
-use pgwire::api::{ClientInfo, MakeHandler, StatelessMakeHandler, Type};
+use pgwire::api::{ClientInfo, StatelessMakeHandler, Type};
 use pgwire::error::PgWireResult;
-use pgwire::tokio::process_socket;
+use pgwire::server::PgWireServerBuilder;
+use pgwire::tls::acceptor_from_pem_files;
 
 pub struct DummyProcessor;
 
@@ -41,7 +35,7 @@ impl SimpleQueryHandler for DummyProcessor {
                 (Some(2), None),
             ];
             let schema_ref = schema.clone();
-            let data_row_stream = stream::iter(data.into_iter()).map(move |r| {
+            let data_row_stream = stream::iter(data).map(move |r| {
                 let mut encoder = DataRowEncoder::new(schema_ref.clone());
                 encoder.encode_field(&r.0)?;
                 encoder.encode_field(&r.1)?;
@@ -59,23 +53,6 @@ impl SimpleQueryHandler for DummyProcessor {
     }
 }
 
-fn setup_tls() -> Result<TlsAcceptor, IOError> {
-    let cert = certs(&mut BufReader::new(File::open("examples/ssl/server.crt")?))
-        .collect::<Result<Vec<CertificateDer>, IOError>>()?;
-
-    let key = pkcs8_private_keys(&mut BufReader::new(File::open("examples/ssl/server.key")?))
-        .map(|key| key.map(PrivateKeyDer::from))
-        .collect::<Result<Vec<PrivateKeyDer>, IOError>>()?
-        .remove(0);
-
-    let config = ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(cert, key)
-        .map_err(|err| IOError::new(ErrorKind::InvalidInput, err))?;
-
-    Ok(TlsAcceptor::from(Arc::new(config)))
-}
-
 #[tokio::main]
 pub async fn main() {
     let processor = Arc::new(StatelessMakeHandler::new(Arc::new(DummyProcessor)));
@@ -86,25 +63,14 @@ pub async fn main() {
     let authenticator = Arc::new(StatelessMakeHandler::new(Arc::new(NoopStartupHandler)));
 
     let server_addr = "127.0.0.1:5433";
-    let tls_acceptor = Arc::new(setup_tls().unwrap());
-    let listener = TcpListener::bind(server_addr).await.unwrap();
-
+    let tls_acceptor = Arc::new(
+        acceptor_from_pem_files("examples/ssl/server.crt", "examples/ssl/server.key").unwrap(),
+    );
     println!("Listening to {}", server_addr);
-    loop {
-        let incoming_socket = listener.accept().await.unwrap();
-        let tls_acceptor_ref = tls_acceptor.clone();
-        let authenticator_ref = authenticator.make();
-        let processor_ref = processor.make();
-        let placeholder_ref = placeholder.make();
-        tokio::spawn(async move {
-            process_socket(
-                incoming_socket.0,
-                Some(tls_acceptor_ref),
-                authenticator_ref,
-                processor_ref,
-                placeholder_ref,
-            )
-            .await
-        });
-    }
+    PgWireServerBuilder::new(authenticator, processor, placeholder)
+        .with_tls(tls_acceptor)
+        .with_address(server_addr)
+        .serve()
+        .await
+        .unwrap();
 }
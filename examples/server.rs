@@ -3,17 +3,19 @@ use std::sync::Arc;
 
 use async_trait::async_trait;
 use futures::{stream, Sink, SinkExt, StreamExt};
-use tokio::net::TcpListener;
 
 use pgwire::api::auth::noop::NoopStartupHandler;
-use pgwire::api::query::{PlaceholderExtendedQueryHandler, SimpleQueryHandler};
-use pgwire::api::results::{DataRowEncoder, FieldFormat, FieldInfo, QueryResponse, Response, Tag};
-use pgwire::api::{ClientInfo, MakeHandler, StatelessMakeHandler, Type};
+use pgwire::api::portal::Portal;
+use pgwire::api::query::{DefaultExtendedQueryHandler, PreparedQueryHandler, SimpleQueryHandler};
+use pgwire::api::results::{
+    DataRowEncoder, DescribeStatementResponse, FieldFormat, FieldInfo, QueryResponse, Response, Tag,
+};
+use pgwire::api::{ClientInfo, StatelessMakeHandler, Type};
 use pgwire::error::ErrorInfo;
 use pgwire::error::{PgWireError, PgWireResult};
 use pgwire::messages::response::NoticeResponse;
 use pgwire::messages::PgWireBackendMessage;
-use pgwire::tokio::process_socket;
+use pgwire::server::PgWireServerBuilder;
 
 pub struct DummyProcessor;
 
@@ -39,61 +41,94 @@ impl SimpleQueryHandler for DummyProcessor {
             )))
             .await?;
 
-        if query.starts_with("SELECT") {
-            let f1 = FieldInfo::new("id".into(), None, None, Type::INT4, FieldFormat::Text);
-            let f2 = FieldInfo::new("name".into(), None, None, Type::VARCHAR, FieldFormat::Text);
-            let schema = Arc::new(vec![f1, f2]);
+        Ok(vec![dummy_response(query)])
+    }
+}
+
+#[async_trait]
+impl PreparedQueryHandler for DummyProcessor {
+    async fn do_describe<C>(
+        &self,
+        _client: &mut C,
+        query: &str,
+        parameter_types: &[Type],
+    ) -> PgWireResult<DescribeStatementResponse>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let fields = if query.starts_with("SELECT") {
+            dummy_schema().as_ref().clone()
+        } else {
+            vec![]
+        };
+        Ok(DescribeStatementResponse::new(
+            parameter_types.to_vec(),
+            fields,
+        ))
+    }
+
+    async fn do_query_extended<'a, 'b: 'a, C>(
+        &'b self,
+        _client: &mut C,
+        portal: &'a Portal<String>,
+        _max_rows: usize,
+    ) -> PgWireResult<Response<'a>>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        Ok(dummy_response(&portal.statement.statement))
+    }
+}
 
-            let data = vec![
-                (Some(0), Some("Tom")),
-                (Some(1), Some("Jerry")),
-                (Some(2), None),
-            ];
-            let schema_ref = schema.clone();
-            let data_row_stream = stream::iter(data.into_iter()).map(move |r| {
-                let mut encoder = DataRowEncoder::new(schema_ref.clone());
-                encoder.encode_field(&r.0)?;
-                encoder.encode_field(&r.1)?;
+fn dummy_schema() -> Arc<Vec<FieldInfo>> {
+    let f1 = FieldInfo::new("id".into(), None, None, Type::INT4, FieldFormat::Text);
+    let f2 = FieldInfo::new("name".into(), None, None, Type::VARCHAR, FieldFormat::Text);
+    Arc::new(vec![f1, f2])
+}
 
-                encoder.finish()
-            });
+fn dummy_response<'a>(query: &str) -> Response<'a> {
+    if query.starts_with("SELECT") {
+        let schema = dummy_schema();
+        let data = vec![
+            (Some(0), Some("Tom")),
+            (Some(1), Some("Jerry")),
+            (Some(2), None),
+        ];
+        let schema_ref = schema.clone();
+        let data_row_stream = stream::iter(data).map(move |r| {
+            let mut encoder = DataRowEncoder::new(schema_ref.clone());
+            encoder.encode_field(&r.0)?;
+            encoder.encode_field(&r.1)?;
 
-            Ok(vec![Response::Query(QueryResponse::new(
-                schema,
-                data_row_stream,
-            ))])
-        } else {
-            Ok(vec![Response::Execution(Tag::new("OK").with_rows(1))])
-        }
+            encoder.finish()
+        });
+
+        Response::Query(QueryResponse::new(schema, data_row_stream))
+    } else {
+        Response::Execution(Tag::new("OK").with_rows(1))
     }
 }
 
 #[tokio::main]
 pub async fn main() {
-    let processor = Arc::new(StatelessMakeHandler::new(Arc::new(DummyProcessor)));
-    // We have not implemented extended query in this server, use placeholder instead
-    let placeholder = Arc::new(StatelessMakeHandler::new(Arc::new(
-        PlaceholderExtendedQueryHandler,
+    let dummy = Arc::new(DummyProcessor);
+    let processor = Arc::new(StatelessMakeHandler::new(dummy.clone()));
+    // statements and portals of extended query are managed by
+    // `DefaultExtendedQueryHandler`
+    let extended_processor = Arc::new(StatelessMakeHandler::new(Arc::new(
+        DefaultExtendedQueryHandler::new(dummy),
     )));
     let authenticator = Arc::new(StatelessMakeHandler::new(Arc::new(NoopStartupHandler)));
 
     let server_addr = "127.0.0.1:5432";
-    let listener = TcpListener::bind(server_addr).await.unwrap();
     println!("Listening to {}", server_addr);
-    loop {
-        let incoming_socket = listener.accept().await.unwrap();
-        let authenticator_ref = authenticator.make();
-        let processor_ref = processor.make();
-        let placeholder_ref = placeholder.make();
-        tokio::spawn(async move {
-            process_socket(
-                incoming_socket.0,
-                None,
-                authenticator_ref,
-                processor_ref,
-                placeholder_ref,
-            )
-            .await
-        });
-    }
+    PgWireServerBuilder::new(authenticator, processor, extended_processor)
+        .with_address(server_addr)
+        .serve()
+        .await
+        .unwrap();
 }
@@ -3,8 +3,8 @@ use std::sync::{Arc, Mutex};
 use async_trait::async_trait;
 use futures::stream;
 use futures::Stream;
-use pgwire::api::auth::md5pass::{hash_md5_password, MakeMd5PasswordAuthStartupHandler};
-use pgwire::api::auth::{AuthSource, DefaultServerParameterProvider, LoginInfo, Password};
+use pgwire::api::auth::md5pass::MakeMd5PasswordAuthStartupHandler;
+use pgwire::api::auth::{AuthSource, DefaultServerParameterProvider, LoginInfo, Verifier};
 use pgwire::api::portal::{Format, Portal};
 use pgwire::api::query::{ExtendedQueryHandler, SimpleQueryHandler};
 use pgwire::api::results::{
@@ -15,10 +15,9 @@ use pgwire::api::stmt::{NoopQueryParser, StoredStatement};
 use pgwire::api::{ClientInfo, MakeHandler, Type};
 use pgwire::error::{ErrorInfo, PgWireError, PgWireResult};
 use pgwire::messages::data::DataRow;
-use pgwire::tokio::process_socket;
+use pgwire::server::PgWireServerBuilder;
 use rusqlite::Rows;
 use rusqlite::{types::ValueRef, Connection, Statement, ToSql};
-use tokio::net::TcpListener;
 
 pub struct SqliteBackend {
     conn: Arc<Mutex<Connection>>,
@@ -29,15 +28,10 @@ struct DummyAuthSource;
 
 #[async_trait]
 impl AuthSource for DummyAuthSource {
-    async fn get_password(&self, login_info: &LoginInfo) -> PgWireResult<Password> {
+    async fn get_verifier(&self, login_info: &LoginInfo) -> PgWireResult<Option<Verifier>> {
         println!("login info: {:?}", login_info);
 
-        let salt = vec![0, 0, 0, 0];
-        let password = "pencil";
-
-        let hash_password =
-            hash_md5_password(login_info.user().as_ref().unwrap(), password, salt.as_ref());
-        Ok(Password::new(Some(salt), hash_password.as_bytes().to_vec()))
+        Ok(Some(Verifier::Cleartext("pencil".to_owned())))
     }
 }
 
@@ -66,9 +60,7 @@ impl SimpleQueryHandler for SqliteBackend {
         } else {
             conn.execute(query, ())
                 .map(|affected_rows| {
-                    vec![Response::Execution(
-                        Tag::new("OK").with_rows(affected_rows).into(),
-                    )]
+                    vec![Response::Execution(Tag::new("OK").with_rows(affected_rows))]
                 })
                 .map_err(|e| PgWireError::ApiError(Box::new(e)))
         }
@@ -117,8 +109,8 @@ fn encode_row_data(
 ) -> impl Stream<Item = PgWireResult<DataRow>> {
     let mut results = Vec::new();
     let ncols = schema.len();
+    let mut encoder = DataRowEncoder::new(schema.clone());
     while let Ok(Some(row)) = rows.next() {
-        let mut encoder = DataRowEncoder::new(schema.clone());
         for idx in 0..ncols {
             let data = row.get_ref_unwrap::<usize>(idx);
             match data {
@@ -140,10 +132,10 @@ fn encode_row_data(
             }
         }
 
-        results.push(encoder.finish());
+        results.push(encoder.take_row());
     }
 
-    stream::iter(results.into_iter())
+    stream::iter(results)
 }
 
 fn get_params(portal: &Portal<String>) -> Vec<Box<dyn ToSql>> {
@@ -228,9 +220,7 @@ impl ExtendedQueryHandler for SqliteBackend {
                 .map_err(|e| PgWireError::ApiError(Box::new(e)))
         } else {
             stmt.execute::<&[&dyn rusqlite::ToSql]>(params_ref.as_ref())
-                .map(|affected_rows| {
-                    Response::Execution(Tag::new("OK").with_rows(affected_rows).into())
-                })
+                .map(|affected_rows| Response::Execution(Tag::new("OK").with_rows(affected_rows)))
                 .map_err(|e| PgWireError::ApiError(Box::new(e)))
         }
     }
@@ -264,8 +254,7 @@ impl ExtendedQueryHandler for SqliteBackend {
         let stmt = conn
             .prepare_cached(&portal.statement.statement)
             .map_err(|e| PgWireError::ApiError(Box::new(e)))?;
-        row_desc_from_stmt(&stmt, &portal.result_column_format)
-            .map(|fields| DescribePortalResponse::new(fields))
+        row_desc_from_stmt(&stmt, &portal.result_column_format).map(DescribePortalResponse::new)
     }
 }
 
@@ -298,8 +287,8 @@ impl MakeHandler for MakeSqliteBackend {
 
 #[tokio::main]
 pub async fn main() {
-    let mut parameters = DefaultServerParameterProvider::default();
-    parameters.server_version = rusqlite::version().to_owned();
+    let parameters =
+        DefaultServerParameterProvider::default().with_server_version(rusqlite::version());
 
     let authenticator = Arc::new(MakeMd5PasswordAuthStartupHandler::new(
         Arc::new(DummyAuthSource),
@@ -308,21 +297,10 @@ pub async fn main() {
     let processor = Arc::new(MakeSqliteBackend::new());
 
     let server_addr = "127.0.0.1:5432";
-    let listener = TcpListener::bind(server_addr).await.unwrap();
     println!("Listening to {}", server_addr);
-    loop {
-        let incoming_socket = listener.accept().await.unwrap();
-        let authenticator_ref = authenticator.make();
-        let processor_ref = processor.make();
-        tokio::spawn(async move {
-            process_socket(
-                incoming_socket.0,
-                None,
-                authenticator_ref,
-                processor_ref.clone(),
-                processor_ref,
-            )
-            .await
-        });
-    }
+    PgWireServerBuilder::new(authenticator, processor.clone(), processor)
+        .with_address(server_addr)
+        .serve()
+        .await
+        .unwrap();
 }
@@ -0,0 +1,160 @@
+//! Admission control of connections.
+//!
+//! A `ConnectionLimiter` in `ServerOptions` caps the number of concurrent
+//! connections, in total and from each client IP address. Connections over a
+//! limit are rejected with `53300` (too_many_connections) like postgres, before
+//! tls or startup is processed. Connections without an IP address, like unix
+//! sockets passed to `process_stream` with an unspecified address, only count
+//! towards the total.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, SqlState};
+
+#[derive(Debug, Default)]
+struct Admitted {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Limits on concurrent connections, cheap to clone. Clones share the count
+/// of admitted connections.
+#[derive(Debug, Default, Clone)]
+pub struct ConnectionLimiter {
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    admitted: Arc<Mutex<Admitted>>,
+}
+
+impl ConnectionLimiter {
+    /// A limiter without limits, set them with `with_max_connections` and
+    /// `with_max_connections_per_ip`.
+    pub fn new() -> ConnectionLimiter {
+        ConnectionLimiter::default()
+    }
+
+    pub fn with_max_connections(mut self, max: usize) -> ConnectionLimiter {
+        self.max_connections = Some(max);
+        self
+    }
+
+    pub fn with_max_connections_per_ip(mut self, max: usize) -> ConnectionLimiter {
+        self.max_connections_per_ip = Some(max);
+        self
+    }
+
+    /// Number of admitted connections.
+    pub fn connections(&self) -> usize {
+        self.admitted.lock().unwrap().total
+    }
+
+    /// Number of admitted connections from `ip`.
+    pub fn connections_from(&self, ip: IpAddr) -> usize {
+        let admitted = self.admitted.lock().unwrap();
+        admitted.per_ip.get(&ip).copied().unwrap_or_default()
+    }
+
+    /// Admit a connection from `addr` until the returned permit is dropped.
+    /// Fails with the error to send to client if a limit is reached.
+    pub fn try_admit(&self, addr: SocketAddr) -> PgWireResult<AdmissionPermit> {
+        let ip = Some(addr.ip()).filter(|ip| !ip.is_unspecified());
+        let mut admitted = self.admitted.lock().unwrap();
+        if self
+            .max_connections
+            .is_some_and(|max| admitted.total >= max)
+        {
+            return Err(too_many_connections_error(
+                "sorry, too many clients already".to_owned(),
+            ));
+        }
+        if let (Some(ip), Some(max)) = (ip, self.max_connections_per_ip) {
+            if admitted.per_ip.get(&ip).is_some_and(|count| *count >= max) {
+                return Err(too_many_connections_error(format!(
+                    "too many connections from host \"{ip}\""
+                )));
+            }
+        }
+
+        admitted.total += 1;
+        if let Some(ip) = ip {
+            *admitted.per_ip.entry(ip).or_default() += 1;
+        }
+        Ok(AdmissionPermit {
+            admitted: self.admitted.clone(),
+            ip,
+        })
+    }
+}
+
+/// An admitted connection, released when dropped.
+#[derive(Debug)]
+pub struct AdmissionPermit {
+    admitted: Arc<Mutex<Admitted>>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for AdmissionPermit {
+    fn drop(&mut self) {
+        let mut admitted = self.admitted.lock().unwrap();
+        admitted.total -= 1;
+        if let Some(ip) = self.ip {
+            if let Some(count) = admitted.per_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    admitted.per_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+fn too_many_connections_error(message: String) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "FATAL".to_owned(),
+        SqlState::TOO_MANY_CONNECTIONS.into(),
+        message,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_limiter() {
+        let limiter = ConnectionLimiter::new()
+            .with_max_connections(3)
+            .with_max_connections_per_ip(2);
+        let a: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+
+        let a1 = limiter.try_admit(a).unwrap();
+        let _a2 = limiter.try_admit(a).unwrap();
+        let Err(PgWireError::UserError(error)) = limiter.try_admit(a) else {
+            panic!("expected connection to be rejected");
+        };
+        assert_eq!("53300", error.code);
+        assert_eq!("too many connections from host \"10.0.0.1\"", error.message);
+        assert_eq!(2, limiter.connections_from(a.ip()));
+
+        let _b1 = limiter.try_admit(b).unwrap();
+        let Err(PgWireError::UserError(error)) = limiter.try_admit(b) else {
+            panic!("expected connection to be rejected");
+        };
+        assert_eq!("sorry, too many clients already", error.message);
+
+        drop(a1);
+        assert_eq!(2, limiter.connections());
+        assert_eq!(1, limiter.connections_from(a.ip()));
+        assert!(limiter.try_admit(b).is_ok());
+
+        // only the total is limited without an ip address
+        let limiter = ConnectionLimiter::new().with_max_connections_per_ip(1);
+        let unix: SocketAddr = "0.0.0.0:0".parse().unwrap();
+        let _u1 = limiter.try_admit(unix).unwrap();
+        let _u2 = limiter.try_admit(unix).unwrap();
+        assert_eq!(2, limiter.connections());
+    }
+}
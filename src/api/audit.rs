@@ -0,0 +1,71 @@
+//! Audit hook of queries.
+//!
+//! A `QueryAuditor` set with `ServerOptions::with_query_auditor` is called by
+//! the connection before and after each simple query and each `Execute` of
+//! extended query, with the timing and outcome of it. As it's called by the
+//! connection instead of query handlers, no query can skip the audit log.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use super::portal::Format;
+use crate::error::ErrorInfo;
+
+/// Receives start and end of each query.
+pub trait QueryAuditor: Send + Sync {
+    /// Whether parameters of extended queries are passed to the auditor. They
+    /// are redacted by default, as they may contain secrets.
+    fn audit_parameters(&self) -> bool {
+        false
+    }
+
+    /// Called before the query is handled.
+    fn on_query_start(&self, _query: &AuditQuery) {}
+
+    /// Called after the query is handled, and its response is sent.
+    fn on_query_end(&self, query: &AuditQuery, outcome: &QueryOutcome);
+}
+
+/// A query seen by `QueryAuditor`.
+#[non_exhaustive]
+#[derive(Debug, Clone, new)]
+pub struct AuditQuery {
+    pub socket_addr: SocketAddr,
+    /// Metadata of the session, including user and database.
+    pub metadata: HashMap<String, String>,
+    /// Text of simple query, or of the prepared statement run by `Execute`.
+    /// It's empty if the statement is not parsed by
+    /// `ExtendedQueryHandler::on_parse`.
+    #[new(default)]
+    pub query: String,
+    /// Name of the portal run by `Execute`, `None` for simple query.
+    #[new(default)]
+    pub portal: Option<String>,
+    /// Parameters bound to the portal, `None` for simple query or if
+    /// parameters are redacted.
+    #[new(default)]
+    pub parameters: Option<Vec<Option<Bytes>>>,
+    /// Format of `parameters`.
+    #[new(default)]
+    pub parameter_format: Format,
+}
+
+/// How a query ended.
+#[non_exhaustive]
+#[derive(Debug, Clone, new)]
+pub struct QueryOutcome {
+    pub duration: Duration,
+    /// Number of rows sent to client.
+    pub rows: usize,
+    /// Error sent to client, `None` if the query succeeded.
+    pub error: Option<ErrorInfo>,
+}
+
+impl QueryOutcome {
+    pub fn is_success(&self) -> bool {
+        self.error.is_none()
+    }
+}
@@ -0,0 +1,243 @@
+use std::fmt::Debug;
+
+use async_trait::async_trait;
+use futures::sink::{Sink, SinkExt};
+use x509_certificate::certificate::X509Certificate;
+
+use super::{
+    ClientInfo, LoginInfo, PgWireConnectionState, ServerParameterProvider, StartupHandler,
+};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, SqlState};
+use crate::messages::response::ErrorResponse;
+use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+
+/// Identity in client certificate.
+#[non_exhaustive]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CertificateIdentity {
+    /// CN of certificate subject
+    pub common_name: Option<String>,
+    /// dNSName entries of subject alternative name extension
+    pub dns_names: Vec<String>,
+    /// rfc822Name entries of subject alternative name extension
+    pub emails: Vec<String>,
+}
+
+// id-ce-subjectAltName 2.5.29.17
+const OID_SUBJECT_ALT_NAME: &[u8] = &[85, 29, 17];
+
+impl CertificateIdentity {
+    /// Read identity from DER encoded certificate.
+    pub fn from_der(der: &[u8]) -> PgWireResult<CertificateIdentity> {
+        let cert =
+            X509Certificate::from_der(der).map_err(|e| PgWireError::ApiError(Box::new(e)))?;
+
+        let mut identity = CertificateIdentity {
+            common_name: cert.subject_common_name(),
+            ..Default::default()
+        };
+        for ext in cert.iter_extensions() {
+            if ext.id.as_ref() == OID_SUBJECT_ALT_NAME {
+                identity.read_subject_alt_name(&ext.value.to_bytes());
+            }
+        }
+        Ok(identity)
+    }
+
+    // GeneralNames ::= SEQUENCE SIZE (1..MAX) OF GeneralName, names we don't
+    // use are skipped
+    fn read_subject_alt_name(&mut self, der: &[u8]) {
+        let Some((0x30, mut names, _)) = der_tlv(der) else {
+            return;
+        };
+        while let Some((tag, value, rest)) = der_tlv(names) {
+            let value = String::from_utf8_lossy(value).into_owned();
+            match tag {
+                0x81 => self.emails.push(value),
+                0x82 => self.dns_names.push(value),
+                _ => {}
+            }
+            names = rest;
+        }
+    }
+}
+
+// split a DER encoded value into tag, content and remaining bytes
+fn der_tlv(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, der) = der.split_first()?;
+    let (&len, mut der) = der.split_first()?;
+    let len = if len & 0x80 == 0 {
+        len as usize
+    } else {
+        let num_bytes = (len & 0x7f) as usize;
+        if num_bytes > std::mem::size_of::<usize>() || der.len() < num_bytes {
+            return None;
+        }
+        let (len_bytes, rest) = der.split_at(num_bytes);
+        der = rest;
+        len_bytes
+            .iter()
+            .fold(0usize, |acc, b| (acc << 8) | *b as usize)
+    };
+    if der.len() < len {
+        return None;
+    }
+    let (value, rest) = der.split_at(len);
+    Some((tag, value, rest))
+}
+
+/// Maps identity in client certificate to postgres user, like `pg_ident.conf`
+/// does for `cert` authentication in postgres.
+pub trait IdentityMapping: Send + Sync {
+    /// Returns true if the certificate identity is allowed to log in as the
+    /// user in `LoginInfo`.
+    fn is_allowed(&self, identity: &CertificateIdentity, login: &LoginInfo) -> bool;
+}
+
+impl<F> IdentityMapping for F
+where
+    F: Fn(&CertificateIdentity, &LoginInfo) -> bool + Send + Sync,
+{
+    fn is_allowed(&self, identity: &CertificateIdentity, login: &LoginInfo) -> bool {
+        self(identity, login)
+    }
+}
+
+/// The default mapping of postgres, CN of certificate must equal to the user
+/// name.
+#[derive(Debug, Default)]
+pub struct CommonNameMapping;
+
+impl IdentityMapping for CommonNameMapping {
+    fn is_allowed(&self, identity: &CertificateIdentity, login: &LoginInfo) -> bool {
+        identity.common_name.is_some() && identity.common_name.as_deref() == login.user()
+    }
+}
+
+/// Startup handler that authenticates client solely by the certificate it
+/// presented during tls handshake, without asking for password.
+///
+/// This handler doesn't verify the certificate, your tls acceptor must be
+/// configured to require and verify client certificates against your CA, for
+/// example with rustls `WebPkiClientVerifier`.
+#[derive(new)]
+pub struct CertificateAuthStartupHandler<M, P> {
+    identity_mapping: M,
+    parameter_provider: P,
+}
+
+#[async_trait]
+impl<M: IdentityMapping, P: ServerParameterProvider> StartupHandler
+    for CertificateAuthStartupHandler<M, P>
+{
+    async fn on_startup<C>(
+        &self,
+        client: &mut C,
+        message: PgWireFrontendMessage,
+    ) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        if let PgWireFrontendMessage::Startup(ref startup) = message {
+            super::save_startup_parameters_to_metadata(client, startup);
+            client.set_state(PgWireConnectionState::AuthenticationInProgress);
+
+            let identity = client
+                .client_certificates()
+                .and_then(|certs| certs.first())
+                .map(|cert| CertificateIdentity::from_der(cert))
+                .transpose()?;
+
+            let login_info = LoginInfo::from_client_info(client);
+            let message = match identity {
+                Some(identity) if self.identity_mapping.is_allowed(&identity, &login_info) => None,
+                Some(_) => Some(format!(
+                    "certificate authentication failed for user \"{}\"",
+                    login_info.user().unwrap_or_default()
+                )),
+                None => Some("connection requires a valid client certificate".to_owned()),
+            };
+
+            if let Some(message) = message {
+                let error_info = ErrorInfo::new(
+                    "FATAL".to_owned(),
+                    SqlState::INVALID_AUTHORIZATION_SPECIFICATION.into(),
+                    message,
+                );
+                client
+                    .feed(PgWireBackendMessage::ErrorResponse(ErrorResponse::from(
+                        error_info,
+                    )))
+                    .await?;
+                client.close().await?;
+            } else {
+                super::finish_authentication(client, &self.parameter_provider).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::auth::DefaultServerParameterProvider;
+    use crate::api::mock::MockClient;
+    use crate::messages::startup::{Authentication, Startup};
+
+    const SERVER_CERT: &[u8] = include_bytes!("../../../examples/ssl/server.crt");
+
+    #[test]
+    fn test_subject_alt_name() {
+        let mut identity = CertificateIdentity::default();
+        let mut der = vec![0x30, 0x21, 0x82, 0x0e];
+        der.extend_from_slice(b"db.example.com");
+        der.extend_from_slice(&[0x81, 0x0f]);
+        der.extend_from_slice(b"tom@example.com");
+        identity.read_subject_alt_name(&der);
+
+        assert_eq!(vec!["db.example.com".to_owned()], identity.dns_names);
+        assert_eq!(vec!["tom@example.com".to_owned()], identity.emails);
+    }
+
+    async fn authenticate(user: &str, with_cert: bool) -> Vec<PgWireBackendMessage> {
+        let handler = CertificateAuthStartupHandler::new(
+            CommonNameMapping,
+            DefaultServerParameterProvider::default(),
+        );
+        let mut client = MockClient::new();
+        if with_cert {
+            let cert = X509Certificate::from_pem(SERVER_CERT).unwrap();
+            client.info.client_certificates = Some(vec![cert.encode_der().unwrap()]);
+        }
+
+        let mut startup = Startup::new();
+        startup
+            .parameters
+            .insert("user".to_owned(), user.to_owned());
+        handler
+            .on_startup(&mut client, PgWireFrontendMessage::Startup(startup))
+            .await
+            .unwrap();
+        client.sent
+    }
+
+    #[tokio::test]
+    async fn test_certificate_auth() {
+        let sent = authenticate("localhost", true).await;
+        assert!(matches!(
+            sent[0],
+            PgWireBackendMessage::Authentication(Authentication::Ok)
+        ));
+
+        let sent = authenticate("tom", true).await;
+        assert_eq!(1, sent.len());
+        assert!(matches!(sent[0], PgWireBackendMessage::ErrorResponse(_)));
+
+        let sent = authenticate("localhost", false).await;
+        assert_eq!(1, sent.len());
+        assert!(matches!(sent[0], PgWireBackendMessage::ErrorResponse(_)));
+    }
+}
@@ -4,10 +4,10 @@ use async_trait::async_trait;
 use futures::sink::{Sink, SinkExt};
 
 use super::{
-    AuthSource, ClientInfo, LoginInfo, PgWireConnectionState, ServerParameterProvider,
-    StartupHandler,
+    constant_time_eq, md5pass, AuthSource, ClientInfo, LoginInfo, PgWireConnectionState,
+    ServerParameterProvider, StartupHandler, Verifier,
 };
-use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, SqlState};
 use crate::messages::response::ErrorResponse;
 use crate::messages::startup::Authentication;
 use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
@@ -45,13 +45,14 @@ impl<V: AuthSource, P: ServerParameterProvider> StartupHandler
             PgWireFrontendMessage::PasswordMessageFamily(pwd) => {
                 let pwd = pwd.into_password()?;
                 let login_info = LoginInfo::from_client_info(client);
-                let pass = self.auth_source.get_password(&login_info).await?;
-                if pass.password == pwd.password.as_bytes() {
+                let verifier = self.auth_source.get_verifier(&login_info).await?;
+                let user = login_info.user().unwrap_or_default();
+                if verifier.is_some_and(|v| verify_password(&v, user, &pwd.password)) {
                     super::finish_authentication(client, &self.parameter_provider).await
                 } else {
                     let error_info = ErrorInfo::new(
                         "FATAL".to_owned(),
-                        "28P01".to_owned(),
+                        SqlState::INVALID_PASSWORD.into(),
                         "Password authentication failed".to_owned(),
                     );
                     let error = ErrorResponse::from(error_info);
@@ -67,3 +68,25 @@ impl<V: AuthSource, P: ServerParameterProvider> StartupHandler
         Ok(())
     }
 }
+
+/// Check cleartext password against a verifier of any kind.
+pub fn verify_password(verifier: &Verifier, username: &str, password: &str) -> bool {
+    match verifier {
+        Verifier::Cleartext(expected) => constant_time_eq(expected.as_bytes(), password.as_bytes()),
+        Verifier::Md5(expected) => constant_time_eq(
+            expected.as_bytes(),
+            md5pass::md5_verifier(username, password).as_bytes(),
+        ),
+        #[cfg(feature = "scram")]
+        Verifier::ScramSha256(secret) => {
+            use super::scram::{valid_iterations, ScramSecret};
+
+            if !valid_iterations(secret.iterations) {
+                return false;
+            }
+            let computed = ScramSecret::new(password, &secret.salt, secret.iterations);
+            constant_time_eq(&computed.stored_key, &secret.stored_key)
+                && constant_time_eq(&computed.server_key, &secret.server_key)
+        }
+    }
+}
@@ -0,0 +1,315 @@
+//! `GssAcceptor` backed by system GSSAPI library, MIT Kerberos or Heimdal.
+//!
+//! The library is loaded at runtime so it's not required for building.
+//! Server credentials are read from keytab, which is `KRB5_KTNAME` env or the
+//! default of Kerberos library.
+
+use std::ffi::c_void;
+use std::ptr;
+use std::sync::Arc;
+
+use libloading::Library;
+
+use super::{GssAcceptor, GssContext, GssStep};
+use crate::error::{PgWireError, PgWireResult};
+
+type OmUint32 = u32;
+
+#[repr(C)]
+struct GssBufferDesc {
+    length: usize,
+    value: *mut c_void,
+}
+
+impl GssBufferDesc {
+    fn empty() -> GssBufferDesc {
+        GssBufferDesc {
+            length: 0,
+            value: ptr::null_mut(),
+        }
+    }
+}
+
+type GssName = *mut c_void;
+type GssCtx = *mut c_void;
+type GssCred = *mut c_void;
+type GssOid = *mut c_void;
+
+type AcceptSecContextFn = unsafe extern "C" fn(
+    *mut OmUint32,
+    *mut GssCtx,
+    GssCred,
+    *mut GssBufferDesc,
+    *mut c_void,
+    *mut GssName,
+    *mut GssOid,
+    *mut GssBufferDesc,
+    *mut OmUint32,
+    *mut OmUint32,
+    *mut GssCred,
+) -> OmUint32;
+type DisplayNameFn =
+    unsafe extern "C" fn(*mut OmUint32, GssName, *mut GssBufferDesc, *mut GssOid) -> OmUint32;
+type DisplayStatusFn = unsafe extern "C" fn(
+    *mut OmUint32,
+    OmUint32,
+    i32,
+    GssOid,
+    *mut OmUint32,
+    *mut GssBufferDesc,
+) -> OmUint32;
+type ReleaseBufferFn = unsafe extern "C" fn(*mut OmUint32, *mut GssBufferDesc) -> OmUint32;
+type ReleaseNameFn = unsafe extern "C" fn(*mut OmUint32, *mut GssName) -> OmUint32;
+type DeleteSecContextFn =
+    unsafe extern "C" fn(*mut OmUint32, *mut GssCtx, *mut GssBufferDesc) -> OmUint32;
+
+const GSS_S_COMPLETE: OmUint32 = 0;
+const GSS_S_CONTINUE_NEEDED: OmUint32 = 1;
+const GSS_C_GSS_CODE: i32 = 1;
+
+fn is_gss_error(major: OmUint32) -> bool {
+    major & 0xffff_0000 != 0
+}
+
+struct Functions {
+    accept_sec_context: AcceptSecContextFn,
+    display_name: DisplayNameFn,
+    display_status: DisplayStatusFn,
+    release_buffer: ReleaseBufferFn,
+    release_name: ReleaseNameFn,
+    delete_sec_context: DeleteSecContextFn,
+    // keeps functions above valid
+    _library: Library,
+}
+
+const LIBRARY_NAMES: &[&str] = &[
+    "libgssapi_krb5.so.2",
+    "libgssapi_krb5.so",
+    "libgssapi.so.3",
+    "libgssapi_krb5.dylib",
+];
+
+impl Functions {
+    fn load() -> PgWireResult<Functions> {
+        let mut last_error = None;
+        for name in LIBRARY_NAMES {
+            // safety: loading GSSAPI library runs no unusual initialization
+            match unsafe { Library::new(name) } {
+                Ok(library) => return Self::from_library(library),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(PgWireError::ApiError(Box::new(
+            last_error.expect("at least one library name"),
+        )))
+    }
+
+    fn from_library(library: Library) -> PgWireResult<Functions> {
+        // safety: signatures match the GSSAPI C bindings in RFC 2744
+        unsafe {
+            Ok(Functions {
+                accept_sec_context: *library.get(b"gss_accept_sec_context\0").map_err(api_err)?,
+                display_name: *library.get(b"gss_display_name\0").map_err(api_err)?,
+                display_status: *library.get(b"gss_display_status\0").map_err(api_err)?,
+                release_buffer: *library.get(b"gss_release_buffer\0").map_err(api_err)?,
+                release_name: *library.get(b"gss_release_name\0").map_err(api_err)?,
+                delete_sec_context: *library.get(b"gss_delete_sec_context\0").map_err(api_err)?,
+                _library: library,
+            })
+        }
+    }
+
+    // take content of a buffer allocated by GSSAPI library
+    fn take_buffer(&self, buffer: &mut GssBufferDesc) -> Vec<u8> {
+        if buffer.value.is_null() {
+            return Vec::new();
+        }
+        // safety: the buffer is filled by GSSAPI library
+        let data = unsafe {
+            std::slice::from_raw_parts(buffer.value as *const u8, buffer.length).to_vec()
+        };
+        let mut minor = 0;
+        unsafe { (self.release_buffer)(&mut minor, buffer) };
+        data
+    }
+
+    fn status_message(&self, major: OmUint32) -> String {
+        let mut message = String::new();
+        let mut message_context = 0;
+        loop {
+            let mut minor = 0;
+            let mut buffer = GssBufferDesc::empty();
+            let status = unsafe {
+                (self.display_status)(
+                    &mut minor,
+                    major,
+                    GSS_C_GSS_CODE,
+                    ptr::null_mut(),
+                    &mut message_context,
+                    &mut buffer,
+                )
+            };
+            if is_gss_error(status) {
+                break;
+            }
+            if !message.is_empty() {
+                message.push_str(", ");
+            }
+            message.push_str(&String::from_utf8_lossy(&self.take_buffer(&mut buffer)));
+            if message_context == 0 {
+                break;
+            }
+        }
+        message
+    }
+}
+
+fn api_err(e: libloading::Error) -> PgWireError {
+    PgWireError::ApiError(Box::new(e))
+}
+
+/// `GssAcceptor` with system Kerberos library.
+#[derive(Clone)]
+pub struct Krb5Acceptor {
+    functions: Arc<Functions>,
+}
+
+impl std::fmt::Debug for Krb5Acceptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Krb5Acceptor").finish()
+    }
+}
+
+impl Krb5Acceptor {
+    /// Load system GSSAPI library.
+    pub fn new() -> PgWireResult<Krb5Acceptor> {
+        Ok(Krb5Acceptor {
+            functions: Arc::new(Functions::load()?),
+        })
+    }
+}
+
+impl GssAcceptor for Krb5Acceptor {
+    type Context = Krb5Context;
+
+    fn accept(&self) -> PgWireResult<Krb5Context> {
+        Ok(Krb5Context {
+            functions: self.functions.clone(),
+            context: ptr::null_mut(),
+        })
+    }
+}
+
+pub struct Krb5Context {
+    functions: Arc<Functions>,
+    context: GssCtx,
+}
+
+// safety: the context is used by one connection at a time, GSSAPI contexts
+// are not tied to threads
+unsafe impl Send for Krb5Context {}
+
+impl GssContext for Krb5Context {
+    fn step(&mut self, token: &[u8]) -> PgWireResult<GssStep> {
+        let f = &self.functions;
+        let mut minor = 0;
+        let mut input = GssBufferDesc {
+            length: token.len(),
+            value: token.as_ptr() as *mut c_void,
+        };
+        let mut src_name: GssName = ptr::null_mut();
+        let mut output = GssBufferDesc::empty();
+
+        // safety: input is only read by library, others are out parameters
+        let major = unsafe {
+            (f.accept_sec_context)(
+                &mut minor,
+                &mut self.context,
+                ptr::null_mut(),
+                &mut input,
+                ptr::null_mut(),
+                &mut src_name,
+                ptr::null_mut(),
+                &mut output,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        let output = f.take_buffer(&mut output);
+
+        if is_gss_error(major) {
+            release_name(f, &mut src_name);
+            return Err(PgWireError::ApiError(
+                format!(
+                    "accepting GSS security context failed: {}",
+                    f.status_message(major)
+                )
+                .into(),
+            ));
+        }
+
+        match major & 0xffff {
+            GSS_S_CONTINUE_NEEDED => {
+                release_name(f, &mut src_name);
+                Ok(GssStep::Continue(output))
+            }
+            GSS_S_COMPLETE => {
+                let mut name = GssBufferDesc::empty();
+                let status =
+                    unsafe { (f.display_name)(&mut minor, src_name, &mut name, ptr::null_mut()) };
+                release_name(f, &mut src_name);
+                if is_gss_error(status) {
+                    return Err(PgWireError::ApiError(
+                        format!(
+                            "retrieving GSS user name failed: {}",
+                            f.status_message(status)
+                        )
+                        .into(),
+                    ));
+                }
+                let principal = String::from_utf8_lossy(&f.take_buffer(&mut name)).into_owned();
+                Ok(GssStep::Complete {
+                    token: Some(output),
+                    principal,
+                })
+            }
+            _ => Err(PgWireError::ApiError(
+                format!("unexpected GSS status {major}").into(),
+            )),
+        }
+    }
+}
+
+fn release_name(f: &Functions, name: &mut GssName) {
+    if !name.is_null() {
+        let mut minor = 0;
+        unsafe { (f.release_name)(&mut minor, name) };
+    }
+}
+
+impl Drop for Krb5Context {
+    fn drop(&mut self) {
+        if !self.context.is_null() {
+            let mut minor = 0;
+            unsafe {
+                (self.functions.delete_sec_context)(&mut minor, &mut self.context, ptr::null_mut())
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_krb5_invalid_token() {
+        // skip when Kerberos library is not installed
+        let Ok(acceptor) = Krb5Acceptor::new() else {
+            return;
+        };
+        let mut context = acceptor.accept().unwrap();
+        assert!(context.step(b"not a token").is_err());
+    }
+}
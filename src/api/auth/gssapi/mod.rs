@@ -0,0 +1,353 @@
+//! GSSAPI and SSPI authentication, used by clients with Kerberos tickets or
+//! Windows domain logins.
+//!
+//! The message exchange is implemented by `GssapiStartupHandler`, which is
+//! independent of GSSAPI implementations. A `GssAcceptor` backed by system
+//! Kerberos library is available in `krb5` module with `gssapi` feature, and
+//! one backed by Windows SSPI in `sspi` module with `sspi` feature.
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::sink::{Sink, SinkExt};
+use tokio::sync::Mutex;
+
+use super::{
+    ClientInfo, LoginInfo, PgWireConnectionState, ServerParameterProvider, StartupHandler,
+};
+use crate::api::MakeHandler;
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, SqlState};
+use crate::messages::response::ErrorResponse;
+use crate::messages::startup::Authentication;
+use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+
+#[cfg(feature = "gssapi")]
+pub mod krb5;
+#[cfg(feature = "sspi")]
+pub mod sspi;
+
+/// Result of processing a token from client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GssStep {
+    /// Security context is not established yet, the token is sent to client.
+    Continue(Vec<u8>),
+    /// Security context is established for the client principal. The
+    /// optional final token is sent to client.
+    Complete {
+        token: Option<Vec<u8>>,
+        principal: String,
+    },
+}
+
+/// A security context being established with client.
+pub trait GssContext: Send {
+    /// Process a token from client.
+    fn step(&mut self, token: &[u8]) -> PgWireResult<GssStep>;
+}
+
+/// Creates security context for each connection, holds server credentials.
+pub trait GssAcceptor: Send + Sync {
+    type Context: GssContext;
+
+    fn accept(&self) -> PgWireResult<Self::Context>;
+
+    /// Authentication request starting the exchange, `Authentication::GSS`,
+    /// or `Authentication::SSPI` for SSPI clients.
+    fn authentication(&self) -> Authentication {
+        Authentication::GSS
+    }
+}
+
+/// Maps authenticated principal to postgres user.
+pub trait PrincipalMapping: Send + Sync {
+    /// Returns true if the principal is allowed to log in as the user in
+    /// `LoginInfo`.
+    fn is_allowed(&self, principal: &str, login: &LoginInfo) -> bool;
+}
+
+impl<F> PrincipalMapping for F
+where
+    F: Fn(&str, &LoginInfo) -> bool + Send + Sync,
+{
+    fn is_allowed(&self, principal: &str, login: &LoginInfo) -> bool {
+        self(principal, login)
+    }
+}
+
+/// Principal `user@REALM` is allowed to log in as `user`, like postgres with
+/// `include_realm=0`. Set `realm` to accept only principals of the realm.
+#[derive(Debug, Default, new)]
+pub struct StripRealmMapping {
+    realm: Option<String>,
+}
+
+impl PrincipalMapping for StripRealmMapping {
+    fn is_allowed(&self, principal: &str, login: &LoginInfo) -> bool {
+        let (name, realm) = principal
+            .rsplit_once('@')
+            .map_or((principal, None), |(name, realm)| (name, Some(realm)));
+        if self.realm.is_some() && self.realm.as_deref() != realm {
+            return false;
+        }
+        login.user() == Some(name)
+    }
+}
+
+/// Startup handler for GSSAPI authentication.
+pub struct GssapiStartupHandler<G: GssAcceptor, M, P> {
+    acceptor: Arc<G>,
+    mapping: Arc<M>,
+    parameter_provider: Arc<P>,
+    context: Mutex<Option<G::Context>>,
+}
+
+#[async_trait]
+impl<G, M, P> StartupHandler for GssapiStartupHandler<G, M, P>
+where
+    G: GssAcceptor,
+    M: PrincipalMapping,
+    P: ServerParameterProvider,
+{
+    async fn on_startup<C>(
+        &self,
+        client: &mut C,
+        message: PgWireFrontendMessage,
+    ) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        match message {
+            PgWireFrontendMessage::Startup(ref startup) => {
+                super::save_startup_parameters_to_metadata(client, startup);
+                client.set_state(PgWireConnectionState::AuthenticationInProgress);
+                *self.context.lock().await = Some(self.acceptor.accept()?);
+                client
+                    .send(PgWireBackendMessage::Authentication(
+                        self.acceptor.authentication(),
+                    ))
+                    .await?;
+            }
+            PgWireFrontendMessage::PasswordMessageFamily(msg) => {
+                let resp = msg.into_gss_response()?;
+                let step = match self.context.lock().await.as_mut() {
+                    Some(context) => context.step(&resp.data),
+                    None => Err(PgWireError::InvalidStartupMessage),
+                };
+
+                let authenticated = match step {
+                    Ok(GssStep::Continue(token)) => {
+                        client
+                            .send(PgWireBackendMessage::Authentication(
+                                Authentication::GSSContinue(Bytes::from(token)),
+                            ))
+                            .await?;
+                        return Ok(());
+                    }
+                    Ok(GssStep::Complete { token, principal }) => {
+                        if let Some(token) = token.filter(|t| !t.is_empty()) {
+                            client
+                                .feed(PgWireBackendMessage::Authentication(
+                                    Authentication::GSSContinue(Bytes::from(token)),
+                                ))
+                                .await?;
+                        }
+                        let login_info = LoginInfo::from_client_info(client);
+                        self.mapping.is_allowed(&principal, &login_info)
+                    }
+                    Err(_) => false,
+                };
+                self.context.lock().await.take();
+
+                if authenticated {
+                    super::finish_authentication(client, self.parameter_provider.as_ref()).await
+                } else {
+                    let login_info = LoginInfo::from_client_info(client);
+                    let method = match self.acceptor.authentication() {
+                        Authentication::SSPI => "SSPI",
+                        _ => "GSSAPI",
+                    };
+                    let error_info = ErrorInfo::new(
+                        "FATAL".to_owned(),
+                        SqlState::INVALID_AUTHORIZATION_SPECIFICATION.into(),
+                        format!(
+                            "{method} authentication failed for user \"{}\"",
+                            login_info.user().unwrap_or_default()
+                        ),
+                    );
+                    client
+                        .feed(PgWireBackendMessage::ErrorResponse(ErrorResponse::from(
+                            error_info,
+                        )))
+                        .await?;
+                    client.close().await?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Creates `GssapiStartupHandler` for each connection.
+#[derive(Debug, new)]
+pub struct MakeGssapiStartupHandler<G, M, P> {
+    acceptor: Arc<G>,
+    mapping: Arc<M>,
+    parameter_provider: Arc<P>,
+}
+
+impl<G, M, P> MakeHandler for MakeGssapiStartupHandler<G, M, P>
+where
+    G: GssAcceptor,
+    M: PrincipalMapping,
+    P: ServerParameterProvider,
+{
+    type Handler = Arc<GssapiStartupHandler<G, M, P>>;
+
+    fn make(&self) -> Self::Handler {
+        Arc::new(GssapiStartupHandler {
+            acceptor: self.acceptor.clone(),
+            mapping: self.mapping.clone(),
+            parameter_provider: self.parameter_provider.clone(),
+            context: Mutex::new(None),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::auth::DefaultServerParameterProvider;
+    use crate::api::mock::MockClient;
+    use crate::messages::startup::{GSSResponse, PasswordMessageFamily, Startup};
+    use crate::messages::Message;
+    use bytes::BytesMut;
+
+    // accepts tokens "hello" and then "tom@EXAMPLE.COM", as SSPI if true
+    struct FakeAcceptor(bool);
+
+    struct FakeContext(usize);
+
+    impl GssContext for FakeContext {
+        fn step(&mut self, token: &[u8]) -> PgWireResult<GssStep> {
+            self.0 += 1;
+            match (self.0, token) {
+                (1, b"hello") => Ok(GssStep::Continue(b"world".to_vec())),
+                (2, principal) => Ok(GssStep::Complete {
+                    token: None,
+                    principal: String::from_utf8_lossy(principal).into_owned(),
+                }),
+                _ => Err(PgWireError::InvalidStartupMessage),
+            }
+        }
+    }
+
+    impl GssAcceptor for FakeAcceptor {
+        type Context = FakeContext;
+
+        fn accept(&self) -> PgWireResult<FakeContext> {
+            Ok(FakeContext(0))
+        }
+
+        fn authentication(&self) -> Authentication {
+            if self.0 {
+                Authentication::SSPI
+            } else {
+                Authentication::GSS
+            }
+        }
+    }
+
+    fn gss_response(token: &'static [u8]) -> PgWireFrontendMessage {
+        let mut body = BytesMut::new();
+        GSSResponse::new(Bytes::from_static(token))
+            .encode_body(&mut body)
+            .unwrap();
+        PgWireFrontendMessage::PasswordMessageFamily(PasswordMessageFamily::Raw(body))
+    }
+
+    async fn authenticate(sspi: bool, tokens: &[&'static [u8]]) -> Vec<PgWireBackendMessage> {
+        let make = MakeGssapiStartupHandler::new(
+            Arc::new(FakeAcceptor(sspi)),
+            Arc::new(StripRealmMapping::new(Some("EXAMPLE.COM".to_owned()))),
+            Arc::new(DefaultServerParameterProvider::default()),
+        );
+        let handler = make.make();
+        let mut client = MockClient::new();
+
+        let mut startup = Startup::new();
+        startup
+            .parameters
+            .insert("user".to_owned(), "tom".to_owned());
+        handler
+            .on_startup(&mut client, PgWireFrontendMessage::Startup(startup))
+            .await
+            .unwrap();
+        for token in tokens {
+            handler
+                .on_startup(&mut client, gss_response(token))
+                .await
+                .unwrap();
+        }
+        client.sent
+    }
+
+    #[tokio::test]
+    async fn test_gssapi_startup_handler() {
+        let sent = authenticate(false, &[b"hello", b"tom@EXAMPLE.COM"]).await;
+        assert!(matches!(
+            sent[0],
+            PgWireBackendMessage::Authentication(Authentication::GSS)
+        ));
+        assert!(matches!(
+            sent[1],
+            PgWireBackendMessage::Authentication(Authentication::GSSContinue(_))
+        ));
+        assert!(matches!(
+            sent[2],
+            PgWireBackendMessage::Authentication(Authentication::Ok)
+        ));
+
+        for tokens in [
+            &[b"hello".as_slice(), b"jerry@EXAMPLE.COM"],
+            &[b"hello", b"tom@OTHER.COM"],
+            &[b"bye", b"tom@EXAMPLE.COM"],
+        ] {
+            let sent = authenticate(false, tokens).await;
+            assert!(matches!(
+                sent.last(),
+                Some(PgWireBackendMessage::ErrorResponse(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sspi_startup_handler() {
+        let sent = authenticate(true, &[b"hello", b"tom@EXAMPLE.COM"]).await;
+        assert!(matches!(
+            sent[0],
+            PgWireBackendMessage::Authentication(Authentication::SSPI)
+        ));
+        assert!(matches!(
+            sent[1],
+            PgWireBackendMessage::Authentication(Authentication::GSSContinue(_))
+        ));
+        assert!(matches!(
+            sent[2],
+            PgWireBackendMessage::Authentication(Authentication::Ok)
+        ));
+
+        let sent = authenticate(true, &[b"hello", b"jerry@EXAMPLE.COM"]).await;
+        let Some(PgWireBackendMessage::ErrorResponse(error)) = sent.last() else {
+            panic!("expected ErrorResponse");
+        };
+        assert!(error
+            .fields
+            .iter()
+            .any(|(_, value)| value.starts_with("SSPI authentication failed")));
+    }
+}
@@ -0,0 +1,364 @@
+//! `GssAcceptor` backed by Windows SSPI, for clients authenticating with
+//! their domain login, like npgsql with `Integrated Security` or ODBC.
+//!
+//! The library is loaded at runtime so it's not required for building.
+//! Contexts are accepted with the `Negotiate` package, which picks Kerberos
+//! or NTLM, using credentials of the account the server runs as.
+
+use std::ffi::c_void;
+use std::ptr;
+use std::sync::Arc;
+
+use libloading::Library;
+
+use super::{GssAcceptor, GssContext, GssStep};
+use crate::error::{PgWireError, PgWireResult};
+use crate::messages::startup::Authentication;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SecHandle {
+    lower: usize,
+    upper: usize,
+}
+
+#[repr(C)]
+struct SecBuffer {
+    length: u32,
+    buffer_type: u32,
+    buffer: *mut c_void,
+}
+
+#[repr(C)]
+struct SecBufferDesc {
+    version: u32,
+    count: u32,
+    buffers: *mut SecBuffer,
+}
+
+impl SecBufferDesc {
+    fn new(buffer: &mut SecBuffer) -> SecBufferDesc {
+        SecBufferDesc {
+            version: SECBUFFER_VERSION,
+            count: 1,
+            buffers: buffer,
+        }
+    }
+}
+
+type SecurityStatus = i32;
+
+type AcquireCredentialsHandleFn = unsafe extern "system" fn(
+    *const u16,
+    *const u16,
+    u32,
+    *mut c_void,
+    *mut c_void,
+    *mut c_void,
+    *mut c_void,
+    *mut SecHandle,
+    *mut i64,
+) -> SecurityStatus;
+type AcceptSecurityContextFn = unsafe extern "system" fn(
+    *mut SecHandle,
+    *mut SecHandle,
+    *mut SecBufferDesc,
+    u32,
+    u32,
+    *mut SecHandle,
+    *mut SecBufferDesc,
+    *mut u32,
+    *mut i64,
+) -> SecurityStatus;
+type CompleteAuthTokenFn =
+    unsafe extern "system" fn(*mut SecHandle, *mut SecBufferDesc) -> SecurityStatus;
+type QueryContextAttributesFn =
+    unsafe extern "system" fn(*mut SecHandle, u32, *mut c_void) -> SecurityStatus;
+type FreeContextBufferFn = unsafe extern "system" fn(*mut c_void) -> SecurityStatus;
+type DeleteSecurityContextFn = unsafe extern "system" fn(*mut SecHandle) -> SecurityStatus;
+type FreeCredentialsHandleFn = unsafe extern "system" fn(*mut SecHandle) -> SecurityStatus;
+
+const SEC_E_OK: SecurityStatus = 0;
+const SEC_I_CONTINUE_NEEDED: SecurityStatus = 0x0009_0312;
+const SEC_I_COMPLETE_NEEDED: SecurityStatus = 0x0009_0313;
+const SEC_I_COMPLETE_AND_CONTINUE: SecurityStatus = 0x0009_0314;
+const SECPKG_CRED_INBOUND: u32 = 1;
+const SECPKG_ATTR_NAMES: u32 = 1;
+const SECBUFFER_VERSION: u32 = 0;
+const SECBUFFER_TOKEN: u32 = 2;
+const ASC_REQ_ALLOCATE_MEMORY: u32 = 0x100;
+const SECURITY_NATIVE_DREP: u32 = 0x10;
+
+struct Functions {
+    acquire_credentials_handle: AcquireCredentialsHandleFn,
+    accept_security_context: AcceptSecurityContextFn,
+    complete_auth_token: CompleteAuthTokenFn,
+    query_context_attributes: QueryContextAttributesFn,
+    free_context_buffer: FreeContextBufferFn,
+    delete_security_context: DeleteSecurityContextFn,
+    free_credentials_handle: FreeCredentialsHandleFn,
+    // keeps functions above valid
+    _library: Library,
+}
+
+impl Functions {
+    fn load() -> PgWireResult<Functions> {
+        // safety: loading secur32 runs no unusual initialization
+        let library = unsafe { Library::new("secur32.dll") }.map_err(api_err)?;
+        // safety: signatures match the SSPI declarations in sspi.h
+        unsafe {
+            Ok(Functions {
+                acquire_credentials_handle: *library
+                    .get(b"AcquireCredentialsHandleW\0")
+                    .map_err(api_err)?,
+                accept_security_context: *library
+                    .get(b"AcceptSecurityContext\0")
+                    .map_err(api_err)?,
+                complete_auth_token: *library.get(b"CompleteAuthToken\0").map_err(api_err)?,
+                query_context_attributes: *library
+                    .get(b"QueryContextAttributesW\0")
+                    .map_err(api_err)?,
+                free_context_buffer: *library.get(b"FreeContextBuffer\0").map_err(api_err)?,
+                delete_security_context: *library
+                    .get(b"DeleteSecurityContext\0")
+                    .map_err(api_err)?,
+                free_credentials_handle: *library
+                    .get(b"FreeCredentialsHandle\0")
+                    .map_err(api_err)?,
+                _library: library,
+            })
+        }
+    }
+
+    // take content of a buffer allocated by SSPI
+    fn take_buffer(&self, buffer: &mut SecBuffer) -> Vec<u8> {
+        if buffer.buffer.is_null() {
+            return Vec::new();
+        }
+        // safety: the buffer is filled by SSPI
+        let data = unsafe {
+            std::slice::from_raw_parts(buffer.buffer as *const u8, buffer.length as usize).to_vec()
+        };
+        unsafe { (self.free_context_buffer)(buffer.buffer) };
+        buffer.buffer = ptr::null_mut();
+        data
+    }
+}
+
+fn api_err(e: libloading::Error) -> PgWireError {
+    PgWireError::ApiError(Box::new(e))
+}
+
+fn sspi_err(action: &str, status: SecurityStatus) -> PgWireError {
+    PgWireError::ApiError(format!("{action} failed with SSPI status {status:#010x}").into())
+}
+
+fn to_wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(Some(0)).collect()
+}
+
+/// Convert `DOMAIN\user` name of SSPI to `user@DOMAIN`, the form of
+/// principals given to `PrincipalMapping`.
+fn to_principal(name: &str) -> String {
+    match name.split_once('\\') {
+        Some((domain, user)) => format!("{user}@{domain}"),
+        None => name.to_owned(),
+    }
+}
+
+struct Credentials {
+    functions: Functions,
+    handle: SecHandle,
+}
+
+impl Drop for Credentials {
+    fn drop(&mut self) {
+        unsafe { (self.functions.free_credentials_handle)(&mut self.handle) };
+    }
+}
+
+/// `GssAcceptor` with Windows SSPI.
+#[derive(Clone)]
+pub struct SspiAcceptor {
+    credentials: Arc<Credentials>,
+}
+
+impl std::fmt::Debug for SspiAcceptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SspiAcceptor").finish()
+    }
+}
+
+impl SspiAcceptor {
+    /// Load SSPI library and acquire inbound credentials of the `Negotiate`
+    /// package.
+    pub fn new() -> PgWireResult<SspiAcceptor> {
+        let functions = Functions::load()?;
+        let package = to_wide("Negotiate");
+        let mut handle = SecHandle { lower: 0, upper: 0 };
+        let mut expiry = 0;
+        // safety: package is a null-terminated wide string, others are
+        // optional or out parameters
+        let status = unsafe {
+            (functions.acquire_credentials_handle)(
+                ptr::null(),
+                package.as_ptr(),
+                SECPKG_CRED_INBOUND,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                &mut handle,
+                &mut expiry,
+            )
+        };
+        if status != SEC_E_OK {
+            return Err(sspi_err("acquiring credentials", status));
+        }
+        Ok(SspiAcceptor {
+            credentials: Arc::new(Credentials { functions, handle }),
+        })
+    }
+}
+
+impl GssAcceptor for SspiAcceptor {
+    type Context = SspiContext;
+
+    fn accept(&self) -> PgWireResult<SspiContext> {
+        Ok(SspiContext {
+            credentials: self.credentials.clone(),
+            context: None,
+        })
+    }
+
+    fn authentication(&self) -> Authentication {
+        Authentication::SSPI
+    }
+}
+
+pub struct SspiContext {
+    credentials: Arc<Credentials>,
+    context: Option<SecHandle>,
+}
+
+impl SspiContext {
+    fn user_name(&self, context: &mut SecHandle) -> PgWireResult<String> {
+        let f = &self.credentials.functions;
+        // SecPkgContext_NamesW holds a single pointer
+        let mut name: *mut u16 = ptr::null_mut();
+        let status = unsafe {
+            (f.query_context_attributes)(
+                context,
+                SECPKG_ATTR_NAMES,
+                &mut name as *mut *mut u16 as *mut c_void,
+            )
+        };
+        if status != SEC_E_OK || name.is_null() {
+            return Err(sspi_err("retrieving SSPI user name", status));
+        }
+        // safety: the name is a null-terminated wide string allocated by SSPI
+        let user_name = unsafe {
+            let len = (0..).take_while(|&i| *name.add(i) != 0).count();
+            String::from_utf16_lossy(std::slice::from_raw_parts(name, len))
+        };
+        unsafe { (f.free_context_buffer)(name as *mut c_void) };
+        Ok(user_name)
+    }
+}
+
+impl GssContext for SspiContext {
+    fn step(&mut self, token: &[u8]) -> PgWireResult<GssStep> {
+        let credentials = self.credentials.clone();
+        let f = &credentials.functions;
+        let mut credentials_handle = credentials.handle;
+        let mut input_buffer = SecBuffer {
+            length: token.len() as u32,
+            buffer_type: SECBUFFER_TOKEN,
+            buffer: token.as_ptr() as *mut c_void,
+        };
+        let mut input = SecBufferDesc::new(&mut input_buffer);
+        let mut output_buffer = SecBuffer {
+            length: 0,
+            buffer_type: SECBUFFER_TOKEN,
+            buffer: ptr::null_mut(),
+        };
+        let mut output = SecBufferDesc::new(&mut output_buffer);
+        let mut new_context = self.context.unwrap_or(SecHandle { lower: 0, upper: 0 });
+        let mut attributes = 0;
+        let mut expiry = 0;
+
+        // safety: input is only read by library, the existing context is
+        // passed on later steps, others are out parameters
+        let status = unsafe {
+            (f.accept_security_context)(
+                &mut credentials_handle,
+                self.context
+                    .as_mut()
+                    .map_or(ptr::null_mut(), |context| context as *mut SecHandle),
+                &mut input,
+                ASC_REQ_ALLOCATE_MEMORY,
+                SECURITY_NATIVE_DREP,
+                &mut new_context,
+                &mut output,
+                &mut attributes,
+                &mut expiry,
+            )
+        };
+        if !matches!(
+            status,
+            SEC_E_OK | SEC_I_CONTINUE_NEEDED | SEC_I_COMPLETE_NEEDED | SEC_I_COMPLETE_AND_CONTINUE
+        ) {
+            f.take_buffer(&mut output_buffer);
+            return Err(sspi_err("accepting SSPI security context", status));
+        }
+        self.context = Some(new_context);
+
+        if matches!(status, SEC_I_COMPLETE_NEEDED | SEC_I_COMPLETE_AND_CONTINUE) {
+            let status = unsafe { (f.complete_auth_token)(&mut new_context, &mut output) };
+            if status != SEC_E_OK {
+                f.take_buffer(&mut output_buffer);
+                return Err(sspi_err("completing SSPI token", status));
+            }
+        }
+        let output = f.take_buffer(&mut output_buffer);
+
+        if matches!(status, SEC_I_CONTINUE_NEEDED | SEC_I_COMPLETE_AND_CONTINUE) {
+            Ok(GssStep::Continue(output))
+        } else {
+            let principal = to_principal(&self.user_name(&mut new_context)?);
+            Ok(GssStep::Complete {
+                token: Some(output),
+                principal,
+            })
+        }
+    }
+}
+
+impl Drop for SspiContext {
+    fn drop(&mut self) {
+        if let Some(mut context) = self.context.take() {
+            unsafe { (self.credentials.functions.delete_security_context)(&mut context) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_principal() {
+        assert_eq!("tom@EXAMPLE", to_principal("EXAMPLE\\tom"));
+        assert_eq!("tom", to_principal("tom"));
+    }
+
+    #[test]
+    fn test_sspi_invalid_token() {
+        // skip when not on Windows
+        let Ok(acceptor) = SspiAcceptor::new() else {
+            return;
+        };
+        let mut context = acceptor.accept().unwrap();
+        assert!(context.step(b"not a token").is_err());
+    }
+}
@@ -0,0 +1,595 @@
+//! Host based access control, in the way of postgres `pg_hba.conf`.
+//!
+//! Rules are checked in order against connection type, database, user and
+//! client address, the first matching rule decides the authentication method.
+//! Connections matching no rule are rejected.
+
+use std::fmt::Debug;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::sink::{Sink, SinkExt};
+use tokio::sync::Mutex;
+
+#[cfg(feature = "client-cert")]
+use super::cert::{CertificateAuthStartupHandler, CertificateIdentity, IdentityMapping};
+use super::cleartext::CleartextPasswordAuthStartupHandler;
+use super::md5pass::{MakeMd5PasswordAuthStartupHandler, Md5PasswordAuthStartupHandler};
+#[cfg(feature = "scram")]
+use super::scram::{MakeScramSha256StartupHandler, ScramSha256StartupHandler};
+use super::{
+    AuthSource, ClientInfo, LoginInfo, PgWireConnectionState, ServerParameterProvider,
+    StartupHandler,
+};
+use crate::api::MakeHandler;
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, SqlState};
+use crate::messages::response::ErrorResponse;
+use crate::messages::startup::Startup;
+use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HbaConnectionType {
+    /// Any TCP connection
+    Host,
+    /// TCP connection with SSL
+    HostSsl,
+    /// TCP connection without SSL
+    HostNoSsl,
+}
+
+impl HbaConnectionType {
+    fn matches(&self, is_secure: bool) -> bool {
+        match self {
+            HbaConnectionType::Host => true,
+            HbaConnectionType::HostSsl => is_secure,
+            HbaConnectionType::HostNoSsl => !is_secure,
+        }
+    }
+}
+
+/// Database or user field of a rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HbaMatch {
+    All,
+    /// Database with the same name as user, only valid for database.
+    SameUser,
+    Names(Vec<String>),
+}
+
+impl HbaMatch {
+    fn matches(&self, name: &str, user: &str) -> bool {
+        match self {
+            HbaMatch::All => true,
+            HbaMatch::SameUser => name == user,
+            HbaMatch::Names(names) => names.iter().any(|n| n == name),
+        }
+    }
+
+    fn parse(field: &str) -> HbaMatch {
+        match field {
+            "all" => HbaMatch::All,
+            "sameuser" => HbaMatch::SameUser,
+            _ => HbaMatch::Names(
+                field
+                    .split(',')
+                    .map(|n| n.trim_matches('"').to_owned())
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Client address field of a rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HbaAddress {
+    All,
+    Net { addr: IpAddr, prefix_len: u8 },
+}
+
+impl HbaAddress {
+    pub(crate) fn matches(&self, client_addr: IpAddr) -> bool {
+        let HbaAddress::Net { addr, prefix_len } = self else {
+            return true;
+        };
+        // ipv4 clients may connect to dual stack listener
+        let client_addr = match client_addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(client_addr),
+            v4 => v4,
+        };
+        match (addr, client_addr) {
+            (IpAddr::V4(net), IpAddr::V4(client)) => {
+                prefix_matches(&net.octets(), &client.octets(), *prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(client)) => {
+                prefix_matches(&net.octets(), &client.octets(), *prefix_len)
+            }
+            _ => false,
+        }
+    }
+
+    fn parse(field: &str, mask: Option<&str>) -> PgWireResult<HbaAddress> {
+        let invalid = || PgWireError::InvalidHbaRule(format!("invalid address {field}"));
+        if field == "all" {
+            return Ok(HbaAddress::All);
+        }
+
+        let (addr, prefix_len) = match (field.split_once('/'), mask) {
+            (Some((addr, len)), None) => (
+                addr.parse::<IpAddr>().map_err(|_| invalid())?,
+                len.parse::<u8>().map_err(|_| invalid())?,
+            ),
+            (None, Some(mask)) => {
+                let addr = field.parse::<IpAddr>().map_err(|_| invalid())?;
+                let mask = mask.parse::<IpAddr>().map_err(|_| invalid())?;
+                // like postgres, only contiguous masks are valid
+                let (ones, zeros) = match mask {
+                    IpAddr::V4(m) => (u32::from(m).leading_ones(), u32::from(m).trailing_zeros()),
+                    IpAddr::V6(m) => (u128::from(m).leading_ones(), u128::from(m).trailing_zeros()),
+                };
+                let bits = if mask.is_ipv4() { 32 } else { 128 };
+                if ones + zeros != bits {
+                    return Err(invalid());
+                }
+                (addr, ones as u8)
+            }
+            (None, None) => {
+                let addr = field.parse::<IpAddr>().map_err(|_| invalid())?;
+                (addr, if addr.is_ipv4() { 32 } else { 128 })
+            }
+            _ => return Err(invalid()),
+        };
+
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        Ok(HbaAddress::Net { addr, prefix_len })
+    }
+}
+
+fn prefix_matches(net: &[u8], client: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    let rest_bits = prefix_len % 8;
+    if net[..full_bytes] != client[..full_bytes] {
+        return false;
+    }
+    if rest_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rest_bits);
+    net[full_bytes] & mask == client[full_bytes] & mask
+}
+
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HbaMethod {
+    /// Allow without authentication
+    Trust,
+    /// Reject unconditionally
+    Reject,
+    /// Cleartext password
+    Password,
+    Md5,
+    #[cfg(feature = "scram")]
+    ScramSha256,
+    /// TLS client certificate
+    #[cfg(feature = "client-cert")]
+    Cert,
+}
+
+impl HbaMethod {
+    fn parse(field: &str) -> PgWireResult<HbaMethod> {
+        match field {
+            "trust" => Ok(HbaMethod::Trust),
+            "reject" => Ok(HbaMethod::Reject),
+            "password" => Ok(HbaMethod::Password),
+            "md5" => Ok(HbaMethod::Md5),
+            #[cfg(feature = "scram")]
+            "scram-sha-256" => Ok(HbaMethod::ScramSha256),
+            #[cfg(feature = "client-cert")]
+            "cert" => Ok(HbaMethod::Cert),
+            _ => Err(PgWireError::InvalidHbaRule(format!(
+                "unsupported authentication method {field}"
+            ))),
+        }
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, new)]
+pub struct HbaRule {
+    pub connection_type: HbaConnectionType,
+    pub databases: HbaMatch,
+    pub users: HbaMatch,
+    pub address: HbaAddress,
+    pub method: HbaMethod,
+}
+
+impl HbaRule {
+    /// Parse a line of `pg_hba.conf`, returns `None` for empty or comment
+    /// lines. Options after method are not supported.
+    pub fn parse(line: &str) -> PgWireResult<Option<HbaRule>> {
+        let line = line.split('#').next().unwrap_or_default();
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        if fields.is_empty() {
+            return Ok(None);
+        }
+
+        let connection_type = match fields[0] {
+            "host" => HbaConnectionType::Host,
+            "hostssl" => HbaConnectionType::HostSsl,
+            "hostnossl" => HbaConnectionType::HostNoSsl,
+            other => {
+                return Err(PgWireError::InvalidHbaRule(format!(
+                    "unsupported connection type {other}"
+                )))
+            }
+        };
+
+        let (address, method) = match fields.len() {
+            5 => (HbaAddress::parse(fields[3], None)?, fields[4]),
+            6 => (HbaAddress::parse(fields[3], Some(fields[4]))?, fields[5]),
+            _ => return Err(PgWireError::InvalidHbaRule(line.trim().to_owned())),
+        };
+
+        Ok(Some(HbaRule {
+            connection_type,
+            databases: HbaMatch::parse(fields[1]),
+            users: HbaMatch::parse(fields[2]),
+            address,
+            method: HbaMethod::parse(method)?,
+        }))
+    }
+
+    /// Parse content of `pg_hba.conf`.
+    pub fn parse_config(config: &str) -> PgWireResult<Vec<HbaRule>> {
+        config
+            .lines()
+            .filter_map(|line| HbaRule::parse(line).transpose())
+            .collect()
+    }
+
+    fn matches(&self, client_addr: IpAddr, is_secure: bool, database: &str, user: &str) -> bool {
+        self.connection_type.matches(is_secure)
+            && self.databases.matches(database, user)
+            && self.users.matches(user, user)
+            && self.address.matches(client_addr)
+    }
+}
+
+#[cfg(feature = "client-cert")]
+type BoxedIdentityMapping = Box<dyn Fn(&CertificateIdentity, &LoginInfo) -> bool + Send + Sync>;
+
+/// Startup handler that checks `HbaRule`s and then authenticates with the
+/// method of matching rule, all methods share the same `AuthSource`.
+///
+/// Created for each connection by `MakeHostBasedAuth`.
+pub struct HostBasedAuth<A, P> {
+    rules: Arc<Vec<HbaRule>>,
+    parameter_provider: Arc<P>,
+    cleartext: Arc<CleartextPasswordAuthStartupHandler<Arc<A>, Arc<P>>>,
+    md5: Arc<Md5PasswordAuthStartupHandler<A, P>>,
+    #[cfg(feature = "scram")]
+    scram: Arc<ScramSha256StartupHandler<A, P>>,
+    #[cfg(feature = "client-cert")]
+    cert: Arc<CertificateAuthStartupHandler<BoxedIdentityMapping, Arc<P>>>,
+    method: Mutex<Option<HbaMethod>>,
+}
+
+impl<A, P> HostBasedAuth<A, P> {
+    fn find_method<C: ClientInfo>(&self, client: &C, startup: &Startup) -> Option<HbaMethod> {
+        let user = startup.parameters.get("user").map(String::as_str);
+        let user = user.unwrap_or_default();
+        let database = startup
+            .parameters
+            .get("database")
+            .map(String::as_str)
+            .unwrap_or(user);
+        let client_addr = client.socket_addr().ip();
+
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(client_addr, client.is_secure(), database, user))
+            .map(|rule| rule.method)
+    }
+}
+
+#[async_trait]
+impl<A: AuthSource, P: ServerParameterProvider> StartupHandler for HostBasedAuth<A, P> {
+    async fn on_startup<C>(
+        &self,
+        client: &mut C,
+        message: PgWireFrontendMessage,
+    ) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let method = if let PgWireFrontendMessage::Startup(ref startup) = message {
+            let method = self.find_method(client, startup);
+            *self.method.lock().await = method;
+
+            if matches!(method, None | Some(HbaMethod::Reject | HbaMethod::Trust)) {
+                super::save_startup_parameters_to_metadata(client, startup);
+            }
+            method
+        } else {
+            *self.method.lock().await
+        };
+
+        match method {
+            Some(HbaMethod::Trust) => {
+                super::finish_authentication(client, self.parameter_provider.as_ref()).await;
+            }
+            Some(HbaMethod::Password) => self.cleartext.on_startup(client, message).await?,
+            Some(HbaMethod::Md5) => self.md5.on_startup(client, message).await?,
+            #[cfg(feature = "scram")]
+            Some(HbaMethod::ScramSha256) => self.scram.on_startup(client, message).await?,
+            #[cfg(feature = "client-cert")]
+            Some(HbaMethod::Cert) => self.cert.on_startup(client, message).await?,
+            Some(HbaMethod::Reject) | None => {
+                if !matches!(message, PgWireFrontendMessage::Startup(_)) {
+                    return Ok(());
+                }
+                client.set_state(PgWireConnectionState::AuthenticationInProgress);
+
+                let login_info = LoginInfo::from_client_info(client);
+                let message = format!(
+                    "{} for host \"{}\", user \"{}\", database \"{}\", {}",
+                    if method.is_some() {
+                        "pg_hba.conf rejects connection"
+                    } else {
+                        "no pg_hba.conf entry"
+                    },
+                    login_info.host(),
+                    login_info.user().unwrap_or_default(),
+                    login_info
+                        .database()
+                        .or(login_info.user())
+                        .unwrap_or_default(),
+                    if client.is_secure() {
+                        "SSL encryption"
+                    } else {
+                        "no encryption"
+                    }
+                );
+                let error_info = ErrorInfo::new(
+                    "FATAL".to_owned(),
+                    SqlState::INVALID_AUTHORIZATION_SPECIFICATION.into(),
+                    message,
+                );
+                client
+                    .feed(PgWireBackendMessage::ErrorResponse(ErrorResponse::from(
+                        error_info,
+                    )))
+                    .await?;
+                client.close().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Creates `HostBasedAuth` for each connection.
+pub struct MakeHostBasedAuth<A, P> {
+    rules: Arc<Vec<HbaRule>>,
+    auth_source: Arc<A>,
+    parameter_provider: Arc<P>,
+    md5: MakeMd5PasswordAuthStartupHandler<A, P>,
+    #[cfg(feature = "scram")]
+    scram: MakeScramSha256StartupHandler<A, P>,
+    #[cfg(feature = "client-cert")]
+    cert: Arc<CertificateAuthStartupHandler<BoxedIdentityMapping, Arc<P>>>,
+}
+
+impl<A, P> MakeHostBasedAuth<A, P> {
+    pub fn new(rules: Vec<HbaRule>, auth_source: Arc<A>, parameter_provider: Arc<P>) -> Self {
+        MakeHostBasedAuth {
+            rules: Arc::new(rules),
+            md5: MakeMd5PasswordAuthStartupHandler::new(
+                auth_source.clone(),
+                parameter_provider.clone(),
+            ),
+            #[cfg(feature = "scram")]
+            scram: MakeScramSha256StartupHandler::new(
+                auth_source.clone(),
+                parameter_provider.clone(),
+            ),
+            #[cfg(feature = "client-cert")]
+            cert: Arc::new(CertificateAuthStartupHandler::new(
+                Box::new(|identity, login| {
+                    super::cert::CommonNameMapping.is_allowed(identity, login)
+                }),
+                parameter_provider.clone(),
+            )),
+            auth_source,
+            parameter_provider,
+        }
+    }
+
+    /// Configure the SCRAM handler, for example to enable channel binding.
+    #[cfg(feature = "scram")]
+    pub fn scram_handler_mut(&mut self) -> &mut MakeScramSha256StartupHandler<A, P> {
+        &mut self.scram
+    }
+
+    /// Map certificate identity to user for `cert` rules, defaults to
+    /// `CommonNameMapping`.
+    #[cfg(feature = "client-cert")]
+    pub fn with_identity_mapping<M>(mut self, mapping: M) -> Self
+    where
+        M: IdentityMapping + 'static,
+    {
+        self.cert = Arc::new(CertificateAuthStartupHandler::new(
+            Box::new(move |identity, login| mapping.is_allowed(identity, login)),
+            self.parameter_provider.clone(),
+        ));
+        self
+    }
+}
+
+impl<A, P> MakeHandler for MakeHostBasedAuth<A, P>
+where
+    A: AuthSource,
+    P: ServerParameterProvider,
+{
+    type Handler = Arc<HostBasedAuth<A, P>>;
+
+    fn make(&self) -> Self::Handler {
+        Arc::new(HostBasedAuth {
+            rules: self.rules.clone(),
+            parameter_provider: self.parameter_provider.clone(),
+            cleartext: Arc::new(CleartextPasswordAuthStartupHandler::new(
+                self.auth_source.clone(),
+                self.parameter_provider.clone(),
+            )),
+            md5: self.md5.make(),
+            #[cfg(feature = "scram")]
+            scram: self.scram.make(),
+            #[cfg(feature = "client-cert")]
+            cert: self.cert.clone(),
+            method: Mutex::new(None),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::auth::{DefaultServerParameterProvider, Verifier};
+    use crate::api::mock::MockClient;
+    use crate::messages::startup::Authentication;
+
+    struct Users;
+
+    #[async_trait]
+    impl AuthSource for Users {
+        async fn get_verifier(&self, _login: &LoginInfo) -> PgWireResult<Option<Verifier>> {
+            Ok(Some(Verifier::Cleartext("secret".to_owned())))
+        }
+    }
+
+    #[test]
+    fn test_parse_hba_rules() {
+        let rules = HbaRule::parse_config(
+            "# local network
+             hostssl  all       all       10.0.0.0/8             md5
+             host     sameuser  all       192.168.1.0 255.255.255.0 password # lan
+             hostnossl app,\"b\"  tom     ::1/128                trust
+
+             host     all       all       all                    reject",
+        )
+        .unwrap();
+        assert_eq!(4, rules.len());
+        assert_eq!(HbaConnectionType::HostSsl, rules[0].connection_type);
+        assert_eq!(
+            HbaAddress::Net {
+                addr: "192.168.1.0".parse().unwrap(),
+                prefix_len: 24
+            },
+            rules[1].address
+        );
+        assert_eq!(
+            HbaMatch::Names(vec!["app".to_owned(), "b".to_owned()]),
+            rules[2].databases
+        );
+        assert_eq!(HbaMethod::Reject, rules[3].method);
+
+        assert!(HbaRule::parse("local all all trust").is_err());
+        assert!(HbaRule::parse("host all all 10.0.0.0/33 trust").is_err());
+        assert!(HbaRule::parse("host all all all ident").is_err());
+        assert!(HbaRule::parse("host all all 10.0.0.0 255.0.255.0 trust").is_err());
+        assert!(HbaRule::parse("host all all ::1 ffff:0:ffff:: trust").is_err());
+        assert_eq!(
+            HbaAddress::Net {
+                addr: "0.0.0.0".parse().unwrap(),
+                prefix_len: 0
+            },
+            HbaRule::parse("host all all 0.0.0.0 0.0.0.0 trust")
+                .unwrap()
+                .unwrap()
+                .address
+        );
+    }
+
+    #[test]
+    fn test_match_hba_rules() {
+        let rules = HbaRule::parse_config(
+            "hostssl  all       all       10.0.0.0/8             md5
+             host     sameuser  all       192.168.1.128/25       password
+             host     all       all       all                    reject",
+        )
+        .unwrap();
+        let find = |addr: &str, is_secure, database, user| {
+            let addr = addr.parse().unwrap();
+            rules
+                .iter()
+                .find(|r| r.matches(addr, is_secure, database, user))
+                .map(|r| r.method)
+        };
+
+        assert_eq!(Some(HbaMethod::Md5), find("10.1.2.3", true, "db", "tom"));
+        assert_eq!(
+            Some(HbaMethod::Md5),
+            find("::ffff:10.1.2.3", true, "db", "tom")
+        );
+        assert_eq!(
+            Some(HbaMethod::Reject),
+            find("10.1.2.3", false, "db", "tom")
+        );
+        assert_eq!(
+            Some(HbaMethod::Password),
+            find("192.168.1.200", false, "tom", "tom")
+        );
+        assert_eq!(
+            Some(HbaMethod::Reject),
+            find("192.168.1.100", false, "tom", "tom")
+        );
+        assert_eq!(
+            Some(HbaMethod::Reject),
+            find("192.168.1.200", false, "db", "tom")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_host_based_auth() {
+        let rules = HbaRule::parse_config(
+            "host all tom  127.0.0.1/32 trust
+             host all jerry all          password
+             host all all  all          reject",
+        )
+        .unwrap();
+        let make = MakeHostBasedAuth::new(
+            rules,
+            Arc::new(Users),
+            Arc::new(DefaultServerParameterProvider::default()),
+        );
+
+        let mut sent = Vec::new();
+        for user in ["tom", "jerry", "spike"] {
+            let handler = make.make();
+            let mut client = MockClient::new();
+            let mut startup = Startup::new();
+            startup
+                .parameters
+                .insert("user".to_owned(), user.to_owned());
+            handler
+                .on_startup(&mut client, PgWireFrontendMessage::Startup(startup))
+                .await
+                .unwrap();
+            sent.push(client.sent.remove(0));
+        }
+
+        assert!(matches!(
+            sent[0],
+            PgWireBackendMessage::Authentication(Authentication::Ok)
+        ));
+        assert!(matches!(
+            sent[1],
+            PgWireBackendMessage::Authentication(Authentication::CleartextPassword)
+        ));
+        assert!(matches!(sent[2], PgWireBackendMessage::ErrorResponse(_)));
+    }
+}
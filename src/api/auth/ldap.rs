@@ -0,0 +1,274 @@
+//! LDAP authentication, like the `ldap` method of postgres.
+//!
+//! Client sends password in cleartext, which is then verified by binding to
+//! LDAP server as the user. Use it with TLS, and enable tls features of
+//! `ldap3` crate for `ldaps://` urls.
+
+use std::fmt::Debug;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::sink::{Sink, SinkExt};
+use ldap3::{dn_escape, ldap_escape, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+
+use super::{
+    ClientInfo, LoginInfo, PgWireConnectionState, ServerParameterProvider, StartupHandler,
+};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, SqlState};
+use crate::messages::response::ErrorResponse;
+use crate::messages::startup::Authentication;
+use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+
+/// How to find the DN of user to bind with.
+#[derive(Debug, Clone)]
+pub enum LdapBindMode {
+    /// Bind with DN `prefix + username + suffix`
+    Simple { prefix: String, suffix: String },
+    /// Search the user under `base_dn`, optionally after binding with
+    /// `bind_dn`, then bind with the DN found.
+    SearchBind {
+        base_dn: String,
+        bind_dn: Option<String>,
+        bind_password: Option<String>,
+        /// Filter with `$username` placeholder, like `(uid=$username)`
+        search_filter: String,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct LdapConfig {
+    url: String,
+    mode: LdapBindMode,
+    timeout: Duration,
+}
+
+impl LdapConfig {
+    /// Simple bind mode, for example `ldap://localhost`, `cn=`,
+    /// `,dc=example,dc=com`.
+    pub fn simple_bind(url: &str, prefix: &str, suffix: &str) -> LdapConfig {
+        LdapConfig {
+            url: url.to_owned(),
+            mode: LdapBindMode::Simple {
+                prefix: prefix.to_owned(),
+                suffix: suffix.to_owned(),
+            },
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Search+bind mode, users are searched by `uid` attribute under
+    /// `base_dn` with anonymous bind by default.
+    pub fn search_bind(url: &str, base_dn: &str) -> LdapConfig {
+        LdapConfig {
+            url: url.to_owned(),
+            mode: LdapBindMode::SearchBind {
+                base_dn: base_dn.to_owned(),
+                bind_dn: None,
+                bind_password: None,
+                search_filter: "(uid=$username)".to_owned(),
+            },
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Credentials to bind with before searching, only used in search+bind
+    /// mode.
+    pub fn with_bind_credentials(mut self, dn: &str, password: &str) -> LdapConfig {
+        if let LdapBindMode::SearchBind {
+            ref mut bind_dn,
+            ref mut bind_password,
+            ..
+        } = self.mode
+        {
+            *bind_dn = Some(dn.to_owned());
+            *bind_password = Some(password.to_owned());
+        }
+        self
+    }
+
+    /// Search users by the attribute, only used in search+bind mode.
+    pub fn with_search_attribute(self, attribute: &str) -> LdapConfig {
+        self.with_search_filter(&format!("({attribute}=$username)"))
+    }
+
+    /// Search filter with `$username` placeholder, only used in search+bind
+    /// mode.
+    pub fn with_search_filter(mut self, filter: &str) -> LdapConfig {
+        if let LdapBindMode::SearchBind {
+            ref mut search_filter,
+            ..
+        } = self.mode
+        {
+            *search_filter = filter.to_owned();
+        }
+        self
+    }
+
+    /// Timeout of each LDAP operation, 10 seconds by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> LdapConfig {
+        self.timeout = timeout;
+        self
+    }
+
+    fn search_filter(filter: &str, username: &str) -> String {
+        filter.replace("$username", &ldap_escape(username))
+    }
+
+    /// Bind to LDAP server as the user, returns false if the user doesn't
+    /// exist or password is incorrect.
+    pub async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> ldap3::result::Result<bool> {
+        // empty password means anonymous bind to LDAP server
+        if username.is_empty() || password.is_empty() {
+            return Ok(false);
+        }
+
+        let settings = LdapConnSettings::new().set_conn_timeout(self.timeout);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.url).await?;
+        ldap3::drive!(conn);
+        ldap.with_timeout(self.timeout);
+
+        let user_dn = match self.mode {
+            LdapBindMode::Simple {
+                ref prefix,
+                ref suffix,
+            } => Some(format!("{prefix}{}{suffix}", dn_escape(username))),
+            LdapBindMode::SearchBind {
+                ref base_dn,
+                ref bind_dn,
+                ref bind_password,
+                ref search_filter,
+            } => {
+                if let Some(bind_dn) = bind_dn {
+                    ldap.simple_bind(bind_dn, bind_password.as_deref().unwrap_or_default())
+                        .await?
+                        .success()?;
+                }
+                ldap.with_timeout(self.timeout);
+                let (entries, _) = ldap
+                    .search(
+                        base_dn,
+                        Scope::Subtree,
+                        &Self::search_filter(search_filter, username),
+                        vec!["1.1"],
+                    )
+                    .await?
+                    .success()?;
+                // the user must be unique
+                if entries.len() == 1 {
+                    entries
+                        .into_iter()
+                        .next()
+                        .map(|entry| SearchEntry::construct(entry).dn)
+                } else {
+                    None
+                }
+            }
+        };
+
+        let authenticated = if let Some(user_dn) = user_dn {
+            ldap.with_timeout(self.timeout);
+            ldap.simple_bind(&user_dn, password)
+                .await?
+                .success()
+                .is_ok()
+        } else {
+            false
+        };
+        let _ = ldap.unbind().await;
+        Ok(authenticated)
+    }
+}
+
+/// Startup handler that asks cleartext password and verifies it against LDAP
+/// server.
+#[derive(new)]
+pub struct LdapAuthStartupHandler<P> {
+    config: LdapConfig,
+    parameter_provider: P,
+}
+
+#[async_trait]
+impl<P: ServerParameterProvider> StartupHandler for LdapAuthStartupHandler<P> {
+    async fn on_startup<C>(
+        &self,
+        client: &mut C,
+        message: PgWireFrontendMessage,
+    ) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        match message {
+            PgWireFrontendMessage::Startup(ref startup) => {
+                super::save_startup_parameters_to_metadata(client, startup);
+                client.set_state(PgWireConnectionState::AuthenticationInProgress);
+                client
+                    .send(PgWireBackendMessage::Authentication(
+                        Authentication::CleartextPassword,
+                    ))
+                    .await?;
+            }
+            PgWireFrontendMessage::PasswordMessageFamily(pwd) => {
+                let pwd = pwd.into_password()?;
+                let login_info = LoginInfo::from_client_info(client);
+                let user = login_info.user().unwrap_or_default();
+
+                // failures of LDAP server are reported as authentication
+                // failure, without leaking details to client
+                let authenticated = self
+                    .config
+                    .authenticate(user, &pwd.password)
+                    .await
+                    .unwrap_or(false);
+
+                if authenticated {
+                    super::finish_authentication(client, &self.parameter_provider).await
+                } else {
+                    let error_info = ErrorInfo::new(
+                        "FATAL".to_owned(),
+                        SqlState::INVALID_PASSWORD.into(),
+                        format!("LDAP authentication failed for user \"{user}\""),
+                    );
+                    let error = ErrorResponse::from(error_info);
+
+                    client
+                        .feed(PgWireBackendMessage::ErrorResponse(error))
+                        .await?;
+                    client.close().await?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ldap_search_filter() {
+        let config = LdapConfig::search_bind("ldap://localhost", "dc=example,dc=com")
+            .with_search_attribute("sAMAccountName");
+        let LdapBindMode::SearchBind { search_filter, .. } = &config.mode else {
+            panic!("expected search+bind mode");
+        };
+        assert_eq!(
+            "(sAMAccountName=tom\\2a\\29)",
+            LdapConfig::search_filter(search_filter, "tom*)")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ldap_empty_password() {
+        // rejected without connecting to server
+        let config = LdapConfig::simple_bind("ldap://localhost:1", "cn=", ",dc=example,dc=com");
+        assert!(!config.authenticate("tom", "").await.unwrap());
+    }
+}
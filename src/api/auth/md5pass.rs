@@ -6,11 +6,11 @@ use futures::sink::{Sink, SinkExt};
 use tokio::sync::Mutex;
 
 use super::{
-    AuthSource, ClientInfo, LoginInfo, PgWireConnectionState, ServerParameterProvider,
-    StartupHandler,
+    constant_time_eq, AuthSource, ClientInfo, LoginInfo, PgWireConnectionState,
+    ServerParameterProvider, StartupHandler, Verifier,
 };
 use crate::api::MakeHandler;
-use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, SqlState};
 use crate::messages::response::ErrorResponse;
 use crate::messages::startup::Authentication;
 use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
@@ -18,7 +18,7 @@ use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
 pub struct Md5PasswordAuthStartupHandler<A, P> {
     auth_source: Arc<A>,
     parameter_provider: Arc<P>,
-    cached_password: Mutex<Vec<u8>>,
+    salt: Mutex<[u8; 4]>,
 }
 
 #[async_trait]
@@ -40,35 +40,38 @@ impl<A: AuthSource, P: ServerParameterProvider> StartupHandler
                 super::save_startup_parameters_to_metadata(client, startup);
                 client.set_state(PgWireConnectionState::AuthenticationInProgress);
 
-                let login_info = LoginInfo::from_client_info(client);
-                let salt_and_pass = self.auth_source.get_password(&login_info).await?;
-
-                let salt = salt_and_pass
-                    .salt
-                    .as_ref()
-                    .expect("Salt is required for Md5Password authentication");
-
-                self.cached_password
-                    .lock()
-                    .await
-                    .clone_from(&salt_and_pass.password);
+                let salt = rand::random::<[u8; 4]>();
+                *self.salt.lock().await = salt;
 
                 client
                     .send(PgWireBackendMessage::Authentication(
-                        Authentication::MD5Password(salt.clone()),
+                        Authentication::MD5Password(salt.to_vec()),
                     ))
                     .await?;
             }
             PgWireFrontendMessage::PasswordMessageFamily(pwd) => {
                 let pwd = pwd.into_password()?;
-                let cached_pass = self.cached_password.lock().await;
+                let salt = *self.salt.lock().await;
 
-                if pwd.password.as_bytes() == *cached_pass {
+                let login_info = LoginInfo::from_client_info(client);
+                let user = login_info.user().unwrap_or_default();
+                let expected = match self.auth_source.get_verifier(&login_info).await? {
+                    Some(Verifier::Cleartext(password)) => {
+                        Some(hash_md5_password(user, &password, &salt))
+                    }
+                    Some(Verifier::Md5(verifier)) => Some(salt_md5_verifier(&verifier, &salt)),
+                    // users with SCRAM secret can't log in with md5
+                    _ => None,
+                };
+
+                if expected.is_some_and(|expected| {
+                    constant_time_eq(expected.as_bytes(), pwd.password.as_bytes())
+                }) {
                     super::finish_authentication(client, self.parameter_provider.as_ref()).await
                 } else {
                     let error_info = ErrorInfo::new(
                         "FATAL".to_owned(),
-                        "28P01".to_owned(),
+                        SqlState::INVALID_PASSWORD.into(),
                         "Password authentication failed".to_owned(),
                     );
                     let error = ErrorResponse::from(error_info);
@@ -88,12 +91,25 @@ impl<A: AuthSource, P: ServerParameterProvider> StartupHandler
 /// This function is to compute postgres standard md5 hashed password
 ///
 /// concat('md5', md5(concat(md5(concat(password, username)), random-salt)))
-///
-/// the input parameter `md5hashed_username_password` represents
-/// `md5(concat(password, username))` so that your can store hashed password in
-/// storage.
 pub fn hash_md5_password(username: &str, password: &str, salt: &[u8]) -> String {
-    let hashed_bytes = format!("{:x}", md5::compute(format!("{password}{username}")));
+    salt_md5_verifier(&md5_verifier(username, password), salt)
+}
+
+/// Compute md5 verifier of user for storage, as in `pg_authid.rolpassword`
+///
+/// concat('md5', md5(concat(password, username)))
+pub fn md5_verifier(username: &str, password: &str) -> String {
+    format!("md5{:x}", md5::compute(format!("{password}{username}")))
+}
+
+pub(crate) fn is_md5_verifier(verifier: &str) -> bool {
+    verifier.len() == 35
+        && verifier.starts_with("md5")
+        && verifier[3..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn salt_md5_verifier(verifier: &str, salt: &[u8]) -> String {
+    let hashed_bytes = verifier.strip_prefix("md5").unwrap_or(verifier);
     let mut bytes = Vec::with_capacity(hashed_bytes.len() + 4);
     bytes.extend_from_slice(hashed_bytes.as_ref());
     bytes.extend_from_slice(salt);
@@ -118,7 +134,7 @@ where
         Arc::new(Md5PasswordAuthStartupHandler {
             auth_source: self.auth_source.clone(),
             parameter_provider: self.parameter_provider.clone(),
-            cached_password: Mutex::new(vec![]),
+            salt: Mutex::new([0; 4]),
         })
     }
 }
@@ -136,4 +152,19 @@ mod tests {
 
         assert_eq!(result, super::hash_md5_password(username, password, &salt));
     }
+
+    #[test]
+    fn test_md5_verifier() {
+        let verifier = super::md5_verifier("zmjiang", "themanwhochangedchina");
+        assert!(super::is_md5_verifier(&verifier));
+        assert!(!super::is_md5_verifier("md5themanwhochangedchina"));
+        assert_eq!(
+            crate::api::auth::Verifier::Md5(verifier.clone()),
+            crate::api::auth::Verifier::parse(&verifier)
+        );
+        assert_eq!(
+            crate::api::auth::Verifier::Cleartext("themanwhochangedchina".to_owned()),
+            crate::api::auth::Verifier::parse("themanwhochangedchina")
+        );
+    }
 }
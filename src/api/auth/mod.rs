@@ -1,14 +1,18 @@
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::sync::Arc;
 
 use async_trait::async_trait;
 use futures::sink::{Sink, SinkExt};
 use futures::stream;
 
-use super::{ClientInfo, PgWireConnectionState, METADATA_DATABASE, METADATA_USER};
-use crate::error::{PgWireError, PgWireResult};
+use super::encoding::ClientEncoding;
+use super::{ClientInfo, PgWireConnectionState};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, SqlState};
 use crate::messages::response::{ReadyForQuery, TransactionStatus};
-use crate::messages::startup::{Authentication, BackendKeyData, ParameterStatus, Startup};
+use crate::messages::startup::{
+    Authentication, BackendKeyData, NegotiateProtocolVersion, ParameterStatus, Startup,
+};
 use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
 
 /// Handles startup process and frontend messages
@@ -30,19 +34,58 @@ pub trait ServerParameterProvider: Send + Sync {
     fn server_parameters<C>(&self, _client: &C) -> Option<HashMap<String, String>>
     where
         C: ClientInfo;
+
+    /// Notices sent to client after authentication, before `ReadyForQuery`.
+    ///
+    /// Clients like psql display them, this can be used for banners like
+    /// maintenance windows or deprecation warnings.
+    fn connect_notices<C>(&self, _client: &C) -> Vec<ErrorInfo>
+    where
+        C: ClientInfo,
+    {
+        Vec::new()
+    }
+}
+
+impl<P> ServerParameterProvider for Arc<P>
+where
+    P: ServerParameterProvider,
+{
+    fn server_parameters<C>(&self, client: &C) -> Option<HashMap<String, String>>
+    where
+        C: ClientInfo,
+    {
+        (**self).server_parameters(client)
+    }
+
+    fn connect_notices<C>(&self, client: &C) -> Vec<ErrorInfo>
+    where
+        C: ClientInfo,
+    {
+        (**self).connect_notices(client)
+    }
 }
 
 /// Default noop parameter provider.
 ///
 /// This provider responds frontend with default parameters:
 ///
+/// - `server_version`: version of this library. Drivers gate features on
+///   it, servers emulating postgres should claim the version they are
+///   compatible with, like `16.2`.
 /// - `DateStyle: ISO YMD`: the default text serialization in this library is
 ///   using `YMD` style date. If you override this, or use your own serialization
 ///   for date types, remember to update this as well.
 /// - `server_encoding: UTF8`
 /// - `client_encoding: UTF8`
-/// - `integer_datetimes: on`:
+/// - `TimeZone: Etc/UTC`
+/// - `integer_datetimes: on`
+/// - `standard_conforming_strings: on`
 ///
+/// Other parameters, like `application_name` or `is_superuser`, can be added
+/// with `with_parameter`.
+///
+/// Messages in `connect_notices` are sent as `NOTICE` after authentication.
 #[non_exhaustive]
 #[derive(Debug)]
 pub struct DefaultServerParameterProvider {
@@ -50,7 +93,11 @@ pub struct DefaultServerParameterProvider {
     pub server_encoding: String,
     pub client_encoding: String,
     pub date_style: String,
+    pub time_zone: String,
     pub integer_datetimes: String,
+    pub standard_conforming_strings: String,
+    pub parameters: Vec<(String, String)>,
+    pub connect_notices: Vec<String>,
 }
 
 impl Default for DefaultServerParameterProvider {
@@ -60,30 +107,145 @@ impl Default for DefaultServerParameterProvider {
             server_encoding: "UTF8".to_owned(),
             client_encoding: "UTF8".to_owned(),
             date_style: "ISO YMD".to_owned(),
+            time_zone: "Etc/UTC".to_owned(),
             integer_datetimes: "on".to_owned(),
+            standard_conforming_strings: "on".to_owned(),
+            parameters: Vec::new(),
+            connect_notices: Vec::new(),
         }
     }
 }
 
+impl DefaultServerParameterProvider {
+    pub fn with_server_version(mut self, server_version: impl Into<String>) -> Self {
+        self.server_version = server_version.into();
+        self
+    }
+
+    pub fn with_server_encoding(mut self, server_encoding: impl Into<String>) -> Self {
+        self.server_encoding = server_encoding.into();
+        self
+    }
+
+    pub fn with_client_encoding(mut self, client_encoding: impl Into<String>) -> Self {
+        self.client_encoding = client_encoding.into();
+        self
+    }
+
+    pub fn with_date_style(mut self, date_style: impl Into<String>) -> Self {
+        self.date_style = date_style.into();
+        self
+    }
+
+    pub fn with_time_zone(mut self, time_zone: impl Into<String>) -> Self {
+        self.time_zone = time_zone.into();
+        self
+    }
+
+    pub fn with_integer_datetimes(mut self, integer_datetimes: impl Into<String>) -> Self {
+        self.integer_datetimes = integer_datetimes.into();
+        self
+    }
+
+    pub fn with_standard_conforming_strings(
+        mut self,
+        standard_conforming_strings: impl Into<String>,
+    ) -> Self {
+        self.standard_conforming_strings = standard_conforming_strings.into();
+        self
+    }
+
+    /// Send another parameter, or override one of the above.
+    pub fn with_parameter(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.parameters.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn with_connect_notice(mut self, notice: impl Into<String>) -> Self {
+        self.connect_notices.push(notice.into());
+        self
+    }
+}
+
 impl ServerParameterProvider for DefaultServerParameterProvider {
     fn server_parameters<C>(&self, _client: &C) -> Option<HashMap<String, String>>
     where
         C: ClientInfo,
     {
-        let mut params = HashMap::with_capacity(5);
+        let mut params = HashMap::with_capacity(7 + self.parameters.len());
         params.insert("server_version".to_owned(), self.server_version.clone());
         params.insert("server_encoding".to_owned(), self.server_encoding.clone());
         params.insert("client_encoding".to_owned(), self.client_encoding.clone());
         params.insert("DateStyle".to_owned(), self.date_style.clone());
+        params.insert("TimeZone".to_owned(), self.time_zone.clone());
         params.insert(
             "integer_datetimes".to_owned(),
             self.integer_datetimes.clone(),
         );
+        params.insert(
+            "standard_conforming_strings".to_owned(),
+            self.standard_conforming_strings.clone(),
+        );
+        params.extend(self.parameters.iter().cloned());
 
         Some(params)
     }
+
+    fn connect_notices<C>(&self, _client: &C) -> Vec<ErrorInfo>
+    where
+        C: ClientInfo,
+    {
+        self.connect_notices
+            .iter()
+            .map(|m| {
+                ErrorInfo::new(
+                    "NOTICE".to_owned(),
+                    SqlState::SUCCESSFUL_COMPLETION.into(),
+                    m.clone(),
+                )
+            })
+            .collect()
+    }
+}
+
+/// Stored credential of a user, which password authentication handlers
+/// verify client password against.
+///
+/// Every verifier works with cleartext password authentication. md5 password
+/// authentication requires a `Cleartext` or `Md5` verifier, and SCRAM
+/// authentication requires a `Cleartext` or `ScramSha256` verifier.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verifier {
+    /// Password in cleartext.
+    Cleartext(String),
+    /// md5 hashed password as postgres stores it: `md5` followed by hex
+    /// encoded `md5(concat(password, username))`. See
+    /// `md5pass::md5_verifier`.
+    Md5(String),
+    /// SCRAM-SHA-256 secret.
+    #[cfg(feature = "scram")]
+    ScramSha256(scram::ScramSecret),
+}
+
+impl Verifier {
+    /// Parse verifier in the format of `pg_authid.rolpassword`, passwords
+    /// neither md5 hashed nor SCRAM secret are treated as cleartext.
+    pub fn parse(verifier: &str) -> Verifier {
+        #[cfg(feature = "scram")]
+        if let Ok(secret) = scram::ScramSecret::parse(verifier) {
+            return Verifier::ScramSha256(secret);
+        }
+
+        if md5pass::is_md5_verifier(verifier) {
+            Verifier::Md5(verifier.to_owned())
+        } else {
+            Verifier::Cleartext(verifier.to_owned())
+        }
+    }
 }
 
+/// Password returned by the deprecated `AuthSource::get_password`.
 #[derive(Debug, new, Clone)]
 pub struct Password {
     salt: Option<Vec<u8>>,
@@ -120,31 +282,82 @@ impl<'a> LoginInfo<'a> {
         &self.host
     }
 
-    pub fn from_client_info<C>(client: &'a C) -> LoginInfo
+    pub fn from_client_info<C>(client: &'a C) -> LoginInfo<'a>
     where
         C: ClientInfo,
     {
         LoginInfo {
-            user: client.metadata().get(METADATA_USER).map(|s| s.as_str()),
-            database: client.metadata().get(METADATA_DATABASE).map(|s| s.as_str()),
+            user: client.user(),
+            database: client.database(),
             host: client.socket_addr().ip().to_string(),
         }
     }
 }
 
-/// Represents auth source, the source returns password either in cleartext or
-/// hashed with salt.
+/// Represents auth source, which looks up stored credential of users.
 ///
-/// When using with different authentication mechanism, the developer can choose
-/// specific implementation of `AuthSource`. For example, with cleartext
-/// authentication, salt is not required, while in md5pass, a 4-byte salt is
-/// needed.
+/// All password authentication handlers consume an `AuthSource` so user
+/// credentials can be stored in files, databases or vaults, independent of
+/// the authentication mechanism. The source returns the `Verifier` of the
+/// user, handlers then run the protocol exchange against it.
 #[async_trait]
 pub trait AuthSource: Send + Sync {
+    /// Get verifier of the user and database in `LoginInfo`.
+    ///
+    /// Returns `None` if the user doesn't exist or is not allowed to access the
+    /// database, authentication will fail in that case.
+    ///
+    /// The default implementation falls back to the deprecated `get_password`,
+    /// whose password must be in cleartext, without salt.
+    async fn get_verifier(&self, login: &LoginInfo) -> PgWireResult<Option<Verifier>> {
+        #[allow(deprecated)]
+        let password = self.get_password(login).await?;
+        if password.salt().is_some() {
+            return Err(PgWireError::ApiError(
+                "salted password of get_password is no longer supported, implement get_verifier"
+                    .into(),
+            ));
+        }
+        let password =
+            String::from_utf8(password.password).map_err(|e| PgWireError::ApiError(Box::new(e)))?;
+        Ok(Some(Verifier::Cleartext(password)))
+    }
+
     /// Get password from the `AuthSource`.
     ///
-    /// `Password` has a an optional salt field when it's hashed.
-    async fn get_password(&self, login: &LoginInfo) -> PgWireResult<Password>;
+    /// Only used by the default `get_verifier`, which fails unless this is
+    /// implemented.
+    #[deprecated(note = "implement get_verifier instead")]
+    async fn get_password(&self, _login: &LoginInfo) -> PgWireResult<Password> {
+        Err(PgWireError::ApiError(
+            "AuthSource must implement get_verifier".into(),
+        ))
+    }
+}
+
+#[async_trait]
+impl<A> AuthSource for Arc<A>
+where
+    A: AuthSource + ?Sized,
+{
+    async fn get_verifier(&self, login: &LoginInfo) -> PgWireResult<Option<Verifier>> {
+        (**self).get_verifier(login).await
+    }
+
+    #[allow(deprecated)]
+    async fn get_password(&self, login: &LoginInfo) -> PgWireResult<Password> {
+        (**self).get_password(login).await
+    }
+}
+
+/// Compare secrets in time depending only on their length, so response time
+/// doesn't reveal how much of a guess is right.
+pub(crate) fn constant_time_eq(lhs: &[u8], rhs: &[u8]) -> bool {
+    if lhs.len() != rhs.len() {
+        return false;
+    }
+    let diff = lhs.iter().zip(rhs).fold(0u8, |acc, (l, r)| acc | (l ^ r));
+    std::hint::black_box(diff) == 0
 }
 
 pub fn save_startup_parameters_to_metadata<C>(client: &mut C, startup_message: &Startup)
@@ -156,8 +369,54 @@ where
         startup_message
             .parameters
             .iter()
+            .filter(|(k, _)| !k.starts_with(Startup::PROTOCOL_OPTION_PREFIX))
             .map(|(k, v)| (k.to_owned(), v.to_owned())),
     );
+    if let Some(encoding) = startup_message
+        .parameters
+        .get("client_encoding")
+        .and_then(|name| ClientEncoding::from_name(name))
+    {
+        client.set_client_encoding(encoding);
+    }
+}
+
+/// Newest minor version of protocol 3 supported.
+pub const PROTOCOL_MINOR_VERSION: u16 = 0;
+
+/// Negotiate the minor protocol version with client, like postgres. If client
+/// asks for a newer minor version, or for protocol options, none of which is
+/// supported, `NegotiateProtocolVersion` is sent before authentication. The
+/// negotiated version is set to `client`.
+pub async fn negotiate_protocol_version<C>(
+    client: &mut C,
+    startup_message: &Startup,
+) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    let requested = startup_message.protocol_number_minor;
+    let newer = requested > PROTOCOL_MINOR_VERSION;
+    let minor = if newer {
+        PROTOCOL_MINOR_VERSION
+    } else {
+        requested
+    };
+    client.set_protocol_minor_version(minor);
+
+    let unsupported_options: Vec<String> = startup_message
+        .protocol_options()
+        .map(str::to_owned)
+        .collect();
+    if newer || !unsupported_options.is_empty() {
+        client
+            .send(PgWireBackendMessage::NegotiateProtocolVersion(
+                NegotiateProtocolVersion::new(minor as i32, unsupported_options),
+            ))
+            .await?;
+    }
+    Ok(())
 }
 
 pub async fn finish_authentication<C, P>(client: &mut C, server_parameter_provider: &P)
@@ -168,7 +427,14 @@ where
 {
     let mut messages = vec![PgWireBackendMessage::Authentication(Authentication::Ok)];
 
-    if let Some(parameters) = server_parameter_provider.server_parameters(client) {
+    if let Some(mut parameters) = server_parameter_provider.server_parameters(client) {
+        // report the encoding negotiated from startup parameters
+        if client.client_encoding() != ClientEncoding::default() {
+            parameters.insert(
+                "client_encoding".to_owned(),
+                client.client_encoding().name().to_owned(),
+            );
+        }
         for (k, v) in parameters {
             messages.push(PgWireBackendMessage::ParameterStatus(ParameterStatus::new(
                 k, v,
@@ -176,11 +442,14 @@ where
         }
     }
 
-    // TODO: store this backend key
-    messages.push(PgWireBackendMessage::BackendKeyData(BackendKeyData::new(
-        std::process::id() as i32,
-        rand::random::<i32>(),
-    )));
+    let backend_key_data = client
+        .cancel_handle()
+        .map(|handle| handle.backend_key_data())
+        .unwrap_or_else(|| BackendKeyData::new(std::process::id() as i32, rand::random::<i32>()));
+    messages.push(PgWireBackendMessage::BackendKeyData(backend_key_data));
+    for notice in server_parameter_provider.connect_notices(client) {
+        messages.push(PgWireBackendMessage::NoticeResponse(notice.into()));
+    }
     messages.push(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
         TransactionStatus::Idle,
     )));
@@ -189,8 +458,73 @@ where
     client.set_state(PgWireConnectionState::ReadyForQuery);
 }
 
+#[cfg(feature = "client-cert")]
+pub mod cert;
 pub mod cleartext;
+pub mod gssapi;
+pub mod hba;
+#[cfg(feature = "ldap")]
+pub mod ldap;
 pub mod md5pass;
 pub mod noop;
+#[cfg(feature = "client-api")]
+pub mod passthrough;
 #[cfg(feature = "scram")]
 pub mod scram;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::mock::MockClient;
+
+    #[test]
+    fn test_default_server_parameters() {
+        let client = MockClient::new();
+        let parameters = DefaultServerParameterProvider::default()
+            .server_parameters(&client)
+            .unwrap();
+        assert_eq!("on", parameters["standard_conforming_strings"]);
+        assert_eq!("Etc/UTC", parameters["TimeZone"]);
+
+        let parameters = DefaultServerParameterProvider::default()
+            .with_server_version("16.2")
+            .with_time_zone("Asia/Tokyo")
+            .with_parameter("is_superuser", "off")
+            .with_parameter("DateStyle", "ISO, MDY")
+            .server_parameters(&client)
+            .unwrap();
+        assert_eq!("16.2", parameters["server_version"]);
+        assert_eq!("Asia/Tokyo", parameters["TimeZone"]);
+        assert_eq!("off", parameters["is_superuser"]);
+        assert_eq!("ISO, MDY", parameters["DateStyle"]);
+    }
+
+    struct LegacySource;
+
+    #[async_trait]
+    impl AuthSource for LegacySource {
+        async fn get_password(&self, login: &LoginInfo) -> PgWireResult<Password> {
+            let salt = (login.user() == Some("bob")).then(|| vec![0; 4]);
+            Ok(Password::new(salt, b"secret".to_vec()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_password_fallback() {
+        let login = LoginInfo::new(Some("alice"), None, "127.0.0.1".to_owned());
+        assert_eq!(
+            Some(Verifier::Cleartext("secret".to_owned())),
+            Arc::new(LegacySource).get_verifier(&login).await.unwrap()
+        );
+        let login = LoginInfo::new(Some("bob"), None, "127.0.0.1".to_owned());
+        assert!(LegacySource.get_verifier(&login).await.is_err());
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret!"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
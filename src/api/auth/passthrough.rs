@@ -0,0 +1,816 @@
+//! Authenticate clients against an upstream postgres server.
+//!
+//! `PassthroughStartupHandler` lets proxies use the users of the postgres
+//! server behind them, instead of keeping their own user database. An
+//! `UpstreamConnector` opens the upstream connection for each login, which
+//! is where users are mapped to servers, and receives the authenticated
+//! connection afterwards.
+//!
+//! In `PassthroughMode::Relay`, the authentication exchange between client
+//! and upstream is relayed as is, any method upstream asks for works except
+//! SCRAM channel binding. In `PassthroughMode::Password`, client is asked for
+//! cleartext password which is then used to authenticate to upstream, and
+//! verified passwords can be kept in a `CredentialCache`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures::{Sink, SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_util::codec::Framed;
+
+use super::{
+    constant_time_eq, md5pass, ClientInfo, LoginInfo, PgWireConnectionState,
+    ServerParameterProvider, StartupHandler,
+};
+use crate::api::MakeHandler;
+use crate::client::PgWireMessageClientCodec;
+use crate::error::{PgWireError, PgWireResult};
+use crate::messages::response::ErrorResponse;
+use crate::messages::startup::{Authentication, Password, PasswordMessageFamily, Startup};
+use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+use crate::tokio::Transport;
+
+/// Connection to an upstream server.
+pub type UpstreamConnection<S> = Framed<S, PgWireMessageClientCodec>;
+
+/// Opens upstream connections for logins.
+#[async_trait]
+pub trait UpstreamConnector: Send + Sync {
+    type Stream: Transport;
+
+    /// Connect to the upstream serving `login`.
+    async fn connect(&self, login: &LoginInfo<'_>) -> PgWireResult<Self::Stream>;
+
+    /// Called with the upstream connection once client is authenticated. The
+    /// rest of upstream startup, like `ParameterStatus`, `BackendKeyData` and
+    /// `ReadyForQuery`, is not read yet. Proxies can keep the connection to
+    /// serve queries of the client, it's closed by default.
+    async fn on_authenticated(
+        &self,
+        _login: &LoginInfo<'_>,
+        _upstream: UpstreamConnection<Self::Stream>,
+    ) {
+    }
+}
+
+/// Connects to a fixed upstream address over TCP.
+#[derive(Debug, Clone, new)]
+pub struct TcpUpstreamConnector {
+    addr: String,
+}
+
+#[async_trait]
+impl UpstreamConnector for TcpUpstreamConnector {
+    type Stream = TcpStream;
+
+    async fn connect(&self, _login: &LoginInfo<'_>) -> PgWireResult<TcpStream> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+}
+
+/// How client is authenticated against upstream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PassthroughMode {
+    /// Relay the authentication exchange between client and upstream.
+    #[default]
+    Relay,
+    /// Ask client for cleartext password, and authenticate to upstream with
+    /// it. SCRAM-SHA-256 upstreams require the `scram` feature.
+    Password,
+}
+
+/// Passwords recently verified by upstream, used in
+/// `PassthroughMode::Password` to skip the upstream round trip. Only salted
+/// hashes are kept, and they expire after `ttl`. When client is authenticated
+/// from cache, `UpstreamConnector::on_authenticated` is not called.
+#[derive(Debug)]
+pub struct CredentialCache {
+    ttl: Duration,
+    entries: StdMutex<HashMap<String, CachedCredential>>,
+}
+
+#[derive(Debug)]
+struct CachedCredential {
+    salt: [u8; 16],
+    hash: String,
+    expires_at: Instant,
+}
+
+impl CredentialCache {
+    pub fn new(ttl: Duration) -> CredentialCache {
+        CredentialCache {
+            ttl,
+            entries: StdMutex::new(HashMap::new()),
+        }
+    }
+
+    fn verify(&self, user: &str, password: &str) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(user) {
+            Some(entry) if entry.expires_at <= Instant::now() => {
+                entries.remove(user);
+                false
+            }
+            Some(entry) => constant_time_eq(
+                entry.hash.as_bytes(),
+                md5pass::hash_md5_password(user, password, &entry.salt).as_bytes(),
+            ),
+            None => false,
+        }
+    }
+
+    fn insert(&self, user: &str, password: &str) {
+        let salt = rand::random::<[u8; 16]>();
+        let entry = CachedCredential {
+            salt,
+            hash: md5pass::hash_md5_password(user, password, &salt),
+            expires_at: Instant::now() + self.ttl,
+        };
+        self.entries.lock().unwrap().insert(user.to_owned(), entry);
+    }
+
+    /// Forget cached password of `user`, for example after it's changed.
+    pub fn invalidate(&self, user: &str) {
+        self.entries.lock().unwrap().remove(user);
+    }
+}
+
+fn upstream_closed() -> PgWireError {
+    PgWireError::IoError(std::io::Error::new(
+        std::io::ErrorKind::UnexpectedEof,
+        "upstream closed connection during authentication",
+    ))
+}
+
+// owned parts of `LoginInfo`, client can't be borrowed across awaits
+struct Login {
+    user: Option<String>,
+    database: Option<String>,
+    host: String,
+}
+
+impl Login {
+    fn from_client_info<C: ClientInfo>(client: &C) -> Login {
+        let login = LoginInfo::from_client_info(client);
+        Login {
+            user: login.user().map(str::to_owned),
+            database: login.database().map(str::to_owned),
+            host: login.host().to_owned(),
+        }
+    }
+
+    fn info(&self) -> LoginInfo<'_> {
+        LoginInfo::new(
+            self.user.as_deref(),
+            self.database.as_deref(),
+            self.host.clone(),
+        )
+    }
+}
+
+async fn next_message<S: Transport>(
+    upstream: &mut UpstreamConnection<S>,
+) -> PgWireResult<PgWireBackendMessage> {
+    upstream.next().await.ok_or_else(upstream_closed)?
+}
+
+pub struct PassthroughStartupHandler<U: UpstreamConnector, P> {
+    connector: Arc<U>,
+    parameter_provider: Arc<P>,
+    mode: PassthroughMode,
+    cache: Option<Arc<CredentialCache>>,
+    // upstream waiting for client's reply in relay mode
+    upstream: Mutex<Option<UpstreamConnection<U::Stream>>>,
+}
+
+impl<U: UpstreamConnector, P: ServerParameterProvider> PassthroughStartupHandler<U, P> {
+    /// Connect to upstream and send client's startup parameters.
+    async fn connect<C: ClientInfo>(
+        &self,
+        client: &mut C,
+    ) -> PgWireResult<UpstreamConnection<U::Stream>> {
+        let mut startup = Startup::new();
+        startup.parameters = client
+            .metadata()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<BTreeMap<_, _>>();
+        let login = Login::from_client_info(client);
+        let stream = self.connector.connect(&login.info()).await?;
+        let mut upstream = Framed::new(stream, PgWireMessageClientCodec::new());
+        upstream
+            .send(PgWireFrontendMessage::Startup(startup))
+            .await?;
+        Ok(upstream)
+    }
+
+    async fn authenticated<C>(
+        &self,
+        client: &mut C,
+        upstream: Option<UpstreamConnection<U::Stream>>,
+    ) where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+    {
+        super::finish_authentication(client, self.parameter_provider.as_ref()).await;
+        if let Some(upstream) = upstream {
+            let login = Login::from_client_info(client);
+            self.connector
+                .on_authenticated(&login.info(), upstream)
+                .await;
+        }
+    }
+
+    /// Forward upstream messages to client until upstream needs client's
+    /// reply, or authentication finishes.
+    async fn relay<C>(
+        &self,
+        client: &mut C,
+        mut upstream: UpstreamConnection<U::Stream>,
+    ) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        loop {
+            match next_message(&mut upstream).await? {
+                PgWireBackendMessage::Authentication(Authentication::Ok) => {
+                    self.authenticated(client, Some(upstream)).await;
+                    return Ok(());
+                }
+                PgWireBackendMessage::Authentication(Authentication::SASLFinal(data)) => {
+                    client
+                        .feed(PgWireBackendMessage::Authentication(
+                            Authentication::SASLFinal(data),
+                        ))
+                        .await?;
+                }
+                PgWireBackendMessage::Authentication(Authentication::SASL(mechanisms)) => {
+                    // channel binding is bound to the tls connection to
+                    // upstream, which client can't see
+                    let mechanisms = mechanisms
+                        .into_iter()
+                        .filter(|m| !m.ends_with("-PLUS"))
+                        .collect();
+                    client
+                        .send(PgWireBackendMessage::Authentication(Authentication::SASL(
+                            mechanisms,
+                        )))
+                        .await?;
+                    break;
+                }
+                PgWireBackendMessage::Authentication(auth) => {
+                    client
+                        .send(PgWireBackendMessage::Authentication(auth))
+                        .await?;
+                    break;
+                }
+                PgWireBackendMessage::ErrorResponse(error) => {
+                    client
+                        .feed(PgWireBackendMessage::ErrorResponse(error))
+                        .await?;
+                    client.close().await?;
+                    return Ok(());
+                }
+                message => {
+                    client.feed(message).await?;
+                }
+            }
+        }
+        *self.upstream.lock().await = Some(upstream);
+        Ok(())
+    }
+
+    async fn authenticate_with_password<C>(
+        &self,
+        client: &mut C,
+        password: &str,
+    ) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let user = client.user().unwrap_or_default().to_owned();
+        if self
+            .cache
+            .as_ref()
+            .is_some_and(|cache| cache.verify(&user, password))
+        {
+            self.authenticated(client, None).await;
+            return Ok(());
+        }
+
+        let mut upstream = self.connect(client).await?;
+        match login(&mut upstream, &user, password).await? {
+            None => {
+                if let Some(cache) = &self.cache {
+                    cache.insert(&user, password);
+                }
+                self.authenticated(client, Some(upstream)).await;
+            }
+            Some(error) => {
+                if let Some(cache) = &self.cache {
+                    cache.invalidate(&user);
+                }
+                client
+                    .feed(PgWireBackendMessage::ErrorResponse(error))
+                    .await?;
+                client.close().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Authenticate to upstream with password, returns upstream's error if
+/// authentication failed.
+pub(crate) async fn login<S: Transport>(
+    upstream: &mut UpstreamConnection<S>,
+    user: &str,
+    password: &str,
+) -> PgWireResult<Option<ErrorResponse>> {
+    loop {
+        let password = match next_message(upstream).await? {
+            PgWireBackendMessage::Authentication(Authentication::Ok) => return Ok(None),
+            PgWireBackendMessage::Authentication(Authentication::CleartextPassword) => {
+                password.to_owned()
+            }
+            PgWireBackendMessage::Authentication(Authentication::MD5Password(salt)) => {
+                md5pass::hash_md5_password(user, password, &salt)
+            }
+            #[cfg(feature = "scram")]
+            PgWireBackendMessage::Authentication(Authentication::SASL(mechanisms))
+                if mechanisms.iter().any(|m| m == scram::SCRAM_SHA_256) =>
+            {
+                if let Some(error) = scram::login(upstream, password).await? {
+                    return Ok(Some(error));
+                }
+                continue;
+            }
+            PgWireBackendMessage::Authentication(_) => {
+                return Err(PgWireError::ApiError(
+                    "authentication method of upstream is not supported".into(),
+                ));
+            }
+            PgWireBackendMessage::ErrorResponse(error) => return Ok(Some(error)),
+            _ => continue,
+        };
+        upstream
+            .send(PgWireFrontendMessage::PasswordMessageFamily(
+                PasswordMessageFamily::Password(Password::new(password)),
+            ))
+            .await?;
+    }
+}
+
+#[cfg(feature = "scram")]
+mod scram {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use bytes::Bytes;
+    use futures::SinkExt;
+
+    use super::{next_message, UpstreamConnection};
+    use crate::api::auth::constant_time_eq;
+    use crate::api::auth::scram::{
+        gen_salted_password, h, hmac, random_nonce, valid_iterations, xor,
+    };
+    use crate::error::{PgWireError, PgWireResult};
+    use crate::messages::response::ErrorResponse;
+    use crate::messages::startup::{
+        Authentication, PasswordMessageFamily, SASLInitialResponse, SASLResponse,
+    };
+    use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+    use crate::tokio::Transport;
+
+    pub(super) const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+
+    fn invalid(message: &str) -> PgWireError {
+        PgWireError::InvalidScramMessage(message.to_owned())
+    }
+
+    /// Client side of SCRAM-SHA-256 exchange, without channel binding.
+    pub(super) async fn login<S: Transport>(
+        upstream: &mut UpstreamConnection<S>,
+        password: &str,
+    ) -> PgWireResult<Option<ErrorResponse>> {
+        let client_nonce = random_nonce();
+        // user name in startup message is used by postgres
+        let client_first_bare = format!("n=,r={client_nonce}");
+        upstream
+            .send(PgWireFrontendMessage::PasswordMessageFamily(
+                PasswordMessageFamily::SASLInitialResponse(SASLInitialResponse::new(
+                    SCRAM_SHA_256.to_owned(),
+                    Some(Bytes::from(format!("n,,{client_first_bare}"))),
+                )),
+            ))
+            .await?;
+
+        let server_first = match next_message(upstream).await? {
+            PgWireBackendMessage::Authentication(Authentication::SASLContinue(data)) => {
+                String::from_utf8(data.to_vec()).map_err(|_| invalid("server-first-message"))?
+            }
+            PgWireBackendMessage::ErrorResponse(error) => return Ok(Some(error)),
+            _ => return Err(invalid("server-first-message")),
+        };
+        let (mut nonce, mut salt, mut iterations) = (None, None, None);
+        for attr in server_first.split(',') {
+            match attr.split_once('=') {
+                Some(("r", v)) => nonce = Some(v),
+                Some(("s", v)) => salt = STANDARD.decode(v).ok(),
+                Some(("i", v)) => iterations = v.parse::<usize>().ok(),
+                _ => {}
+            }
+        }
+        let (Some(nonce), Some(salt), Some(iterations)) = (nonce, salt, iterations) else {
+            return Err(invalid("server-first-message"));
+        };
+        if !nonce.starts_with(&client_nonce) || !valid_iterations(iterations) {
+            return Err(invalid("server-first-message"));
+        }
+
+        let salted_password = gen_salted_password(password, &salt, iterations);
+        let client_key = hmac(&salted_password, b"Client Key");
+        let client_final_without_proof = format!("c=biws,r={nonce}");
+        let auth_message =
+            format!("{client_first_bare},{server_first},{client_final_without_proof}");
+        let client_signature = hmac(&h(&client_key), auth_message.as_bytes());
+        let proof = STANDARD.encode(xor(&client_key, &client_signature));
+        upstream
+            .send(PgWireFrontendMessage::PasswordMessageFamily(
+                PasswordMessageFamily::SASLResponse(SASLResponse::new(Bytes::from(format!(
+                    "{client_final_without_proof},p={proof}"
+                )))),
+            ))
+            .await?;
+
+        match next_message(upstream).await? {
+            PgWireBackendMessage::Authentication(Authentication::SASLFinal(data)) => {
+                let server_key = hmac(&salted_password, b"Server Key");
+                let expected = format!(
+                    "v={}",
+                    STANDARD.encode(hmac(&server_key, auth_message.as_bytes()))
+                );
+                if !constant_time_eq(data.as_ref(), expected.as_bytes()) {
+                    return Err(invalid("server signature mismatch"));
+                }
+                Ok(None)
+            }
+            PgWireBackendMessage::ErrorResponse(error) => Ok(Some(error)),
+            _ => Err(invalid("server-final-message")),
+        }
+    }
+}
+
+#[async_trait]
+impl<U: UpstreamConnector, P: ServerParameterProvider> StartupHandler
+    for PassthroughStartupHandler<U, P>
+{
+    async fn on_startup<C>(
+        &self,
+        client: &mut C,
+        message: PgWireFrontendMessage,
+    ) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        match message {
+            PgWireFrontendMessage::Startup(ref startup) => {
+                super::save_startup_parameters_to_metadata(client, startup);
+                client.set_state(PgWireConnectionState::AuthenticationInProgress);
+                match self.mode {
+                    PassthroughMode::Relay => {
+                        let upstream = self.connect(client).await?;
+                        self.relay(client, upstream).await?;
+                    }
+                    PassthroughMode::Password => {
+                        client
+                            .send(PgWireBackendMessage::Authentication(
+                                Authentication::CleartextPassword,
+                            ))
+                            .await?;
+                    }
+                }
+            }
+            PgWireFrontendMessage::PasswordMessageFamily(message) => match self.mode {
+                PassthroughMode::Relay => {
+                    let Some(mut upstream) = self.upstream.lock().await.take() else {
+                        return Ok(());
+                    };
+                    upstream
+                        .send(PgWireFrontendMessage::PasswordMessageFamily(message))
+                        .await?;
+                    self.relay(client, upstream).await?;
+                }
+                PassthroughMode::Password => {
+                    let password = message.into_password()?;
+                    self.authenticate_with_password(client, &password.password)
+                        .await?;
+                }
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+pub struct MakePassthroughStartupHandler<U, P> {
+    connector: Arc<U>,
+    parameter_provider: Arc<P>,
+    mode: PassthroughMode,
+    cache: Option<Arc<CredentialCache>>,
+}
+
+impl<U, P> MakePassthroughStartupHandler<U, P> {
+    pub fn new(connector: Arc<U>, parameter_provider: Arc<P>) -> Self {
+        MakePassthroughStartupHandler {
+            connector,
+            parameter_provider,
+            mode: PassthroughMode::default(),
+            cache: None,
+        }
+    }
+
+    pub fn with_mode(mut self, mode: PassthroughMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Cache verified passwords, only used in `PassthroughMode::Password`.
+    pub fn with_credential_cache(mut self, cache: Arc<CredentialCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+}
+
+impl<U, P> MakeHandler for MakePassthroughStartupHandler<U, P>
+where
+    U: UpstreamConnector,
+    P: ServerParameterProvider,
+{
+    type Handler = Arc<PassthroughStartupHandler<U, P>>;
+
+    fn make(&self) -> Self::Handler {
+        Arc::new(PassthroughStartupHandler {
+            connector: self.connector.clone(),
+            parameter_provider: self.parameter_provider.clone(),
+            mode: self.mode,
+            cache: self.cache.clone(),
+            upstream: Mutex::new(None),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use bytes::BytesMut;
+    use tokio::io::DuplexStream;
+    use tokio_rustls::TlsAcceptor;
+
+    use super::*;
+    use crate::api::auth::md5pass::MakeMd5PasswordAuthStartupHandler;
+    use crate::api::auth::{AuthSource, DefaultServerParameterProvider, Verifier};
+    use crate::api::mock::MockClient;
+    use crate::api::query::{PlaceholderExtendedQueryHandler, SimpleQueryHandler};
+    use crate::api::results::Response;
+    use crate::tokio::{process_stream, Handlers, ServerOptions};
+
+    struct Pencil;
+
+    #[async_trait]
+    impl AuthSource for Pencil {
+        async fn get_verifier(&self, _login: &LoginInfo) -> PgWireResult<Option<Verifier>> {
+            Ok(Some(Verifier::Cleartext("pencil".to_owned())))
+        }
+    }
+
+    struct NoopQueryHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for NoopQueryHandler {
+        async fn do_query<'a, C>(
+            &self,
+            _client: &mut C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(vec![])
+        }
+    }
+
+    /// Upstream is a pgwire server with `authenticator`.
+    struct TestUpstream<M> {
+        authenticator: M,
+        connects: AtomicUsize,
+        authenticated: AtomicUsize,
+    }
+
+    impl<M> TestUpstream<M> {
+        fn new(authenticator: M) -> TestUpstream<M> {
+            TestUpstream {
+                authenticator,
+                connects: AtomicUsize::new(0),
+                authenticated: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    fn md5_upstream(
+    ) -> TestUpstream<MakeMd5PasswordAuthStartupHandler<Pencil, DefaultServerParameterProvider>>
+    {
+        TestUpstream::new(MakeMd5PasswordAuthStartupHandler::new(
+            Arc::new(Pencil),
+            Arc::new(DefaultServerParameterProvider::default()),
+        ))
+    }
+
+    #[async_trait]
+    impl<M, A> UpstreamConnector for TestUpstream<M>
+    where
+        M: MakeHandler<Handler = Arc<A>> + Send + Sync,
+        A: StartupHandler + 'static,
+    {
+        type Stream = DuplexStream;
+
+        async fn connect(&self, _login: &LoginInfo<'_>) -> PgWireResult<DuplexStream> {
+            self.connects.fetch_add(1, Ordering::SeqCst);
+            let (proxy, upstream) = tokio::io::duplex(4096);
+            tokio::spawn(process_stream(
+                upstream,
+                "127.0.0.1:0".parse().unwrap(),
+                BytesMut::new(),
+                None::<Arc<TlsAcceptor>>,
+                Arc::new(ServerOptions::default()),
+                Handlers::new(
+                    self.authenticator.make(),
+                    Arc::new(NoopQueryHandler),
+                    Arc::new(PlaceholderExtendedQueryHandler),
+                ),
+            ));
+            Ok(proxy)
+        }
+
+        async fn on_authenticated(
+            &self,
+            login: &LoginInfo<'_>,
+            mut upstream: UpstreamConnection<DuplexStream>,
+        ) {
+            assert_eq!(login.user(), Some("tomcat"));
+            // upstream startup continues after authentication
+            while let Some(Ok(message)) = upstream.next().await {
+                if let PgWireBackendMessage::ReadyForQuery(_) = message {
+                    self.authenticated.fetch_add(1, Ordering::SeqCst);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn startup() -> PgWireFrontendMessage {
+        let mut startup = Startup::new();
+        startup
+            .parameters
+            .insert("user".to_owned(), "tomcat".to_owned());
+        PgWireFrontendMessage::Startup(startup)
+    }
+
+    // as decoded by server, before it's coerced into concrete type
+    fn password(password: String) -> PgWireFrontendMessage {
+        PgWireFrontendMessage::PasswordMessageFamily(PasswordMessageFamily::Raw(BytesMut::from(
+            format!("{password}\0").as_bytes(),
+        )))
+    }
+
+    fn is_authenticated(client: &MockClient) -> bool {
+        client
+            .sent
+            .iter()
+            .any(|m| matches!(m, PgWireBackendMessage::Authentication(Authentication::Ok)))
+    }
+
+    #[tokio::test]
+    async fn test_relay_mode() {
+        let upstream = Arc::new(md5_upstream());
+        let make = MakePassthroughStartupHandler::new(
+            upstream.clone(),
+            Arc::new(DefaultServerParameterProvider::default()),
+        );
+
+        let handler = make.make();
+        let mut client = MockClient::new();
+        handler.on_startup(&mut client, startup()).await.unwrap();
+        let salt = match &client.sent[..] {
+            [PgWireBackendMessage::Authentication(Authentication::MD5Password(salt))] => {
+                salt.clone()
+            }
+            _ => panic!("expect md5 password request from upstream"),
+        };
+        let hashed = md5pass::hash_md5_password("tomcat", "pencil", &salt);
+        handler
+            .on_startup(&mut client, password(hashed))
+            .await
+            .unwrap();
+        assert!(is_authenticated(&client));
+        assert!(matches!(
+            client.state(),
+            PgWireConnectionState::ReadyForQuery
+        ));
+        assert_eq!(upstream.authenticated.load(Ordering::SeqCst), 1);
+
+        let handler = make.make();
+        let mut client = MockClient::new();
+        handler.on_startup(&mut client, startup()).await.unwrap();
+        handler
+            .on_startup(&mut client, password("md5wrong".to_owned()))
+            .await
+            .unwrap();
+        assert!(!is_authenticated(&client));
+        assert!(matches!(
+            client.sent.last(),
+            Some(PgWireBackendMessage::ErrorResponse(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_password_mode_with_cache() {
+        let upstream = Arc::new(md5_upstream());
+        let make = MakePassthroughStartupHandler::new(
+            upstream.clone(),
+            Arc::new(DefaultServerParameterProvider::default()),
+        )
+        .with_mode(PassthroughMode::Password)
+        .with_credential_cache(Arc::new(CredentialCache::new(Duration::from_secs(60))));
+
+        let login = |pass: &'static str| {
+            let handler = make.make();
+            async move {
+                let mut client = MockClient::new();
+                handler.on_startup(&mut client, startup()).await.unwrap();
+                assert!(matches!(
+                    client.sent[..],
+                    [PgWireBackendMessage::Authentication(
+                        Authentication::CleartextPassword
+                    )]
+                ));
+                handler
+                    .on_startup(&mut client, password(pass.to_owned()))
+                    .await
+                    .unwrap();
+                is_authenticated(&client)
+            }
+        };
+
+        assert!(login("pencil").await);
+        assert_eq!(upstream.connects.load(Ordering::SeqCst), 1);
+        assert_eq!(upstream.authenticated.load(Ordering::SeqCst), 1);
+        // verified from cache
+        assert!(login("pencil").await);
+        assert_eq!(upstream.connects.load(Ordering::SeqCst), 1);
+        // cache miss is checked by upstream
+        assert!(!login("eraser").await);
+        assert_eq!(upstream.connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[cfg(feature = "scram")]
+    #[tokio::test]
+    async fn test_password_mode_scram() {
+        use crate::api::auth::scram::MakeScramSha256StartupHandler;
+
+        let upstream = Arc::new(TestUpstream::new(MakeScramSha256StartupHandler::new(
+            Arc::new(Pencil),
+            Arc::new(DefaultServerParameterProvider::default()),
+        )));
+        let make = MakePassthroughStartupHandler::new(
+            upstream.clone(),
+            Arc::new(DefaultServerParameterProvider::default()),
+        )
+        .with_mode(PassthroughMode::Password);
+
+        for (pass, ok) in [("pencil", true), ("eraser", false)] {
+            let handler = make.make();
+            let mut client = MockClient::new();
+            handler.on_startup(&mut client, startup()).await.unwrap();
+            handler
+                .on_startup(&mut client, password(pass.to_owned()))
+                .await
+                .unwrap();
+            assert_eq!(is_authenticated(&client), ok);
+        }
+        assert_eq!(upstream.authenticated.load(Ordering::SeqCst), 1);
+    }
+}
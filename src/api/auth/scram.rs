@@ -18,33 +18,15 @@ use aws_lc_rs::{digest, hmac, pbkdf2};
 #[cfg(feature = "ring")]
 use ring::{digest, hmac, pbkdf2};
 
-use crate::api::auth::{AuthSource, LoginInfo, Password};
+use crate::api::auth::{constant_time_eq, AuthSource, LoginInfo, Verifier};
 use crate::api::{ClientInfo, MakeHandler, PgWireConnectionState};
-use crate::error::{PgWireError, PgWireResult};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, SqlState};
+use crate::messages::response::ErrorResponse;
 use crate::messages::startup::Authentication;
 use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
 
 use super::{ServerParameterProvider, StartupHandler};
 
-#[derive(Debug)]
-pub enum ScramState {
-    Initial,
-    // cached password, channel_binding and partial auth-message
-    ServerFirstSent(Password, String, String),
-}
-
-#[derive(Debug)]
-pub struct SASLScramAuthStartupHandler<A, P> {
-    auth_db: Arc<A>,
-    parameter_provider: Arc<P>,
-    /// state of the client-server communication
-    state: Mutex<ScramState>,
-    /// base64 encoded certificate signature for tls-server-end-point channel binding
-    server_cert_sig: Option<Arc<String>>,
-    /// iterations
-    iterations: usize,
-}
-
 /// Compute salted password from raw password as defined in
 /// [RFC5802](https://www.rfc-editor.org/rfc/rfc5802#section-3)
 ///
@@ -54,6 +36,10 @@ pub struct SASLScramAuthStartupHandler<A, P> {
 ///
 /// This is a helper function for `AuthSource` implementation if passwords are
 /// stored in cleartext.
+///
+/// # Panics
+///
+/// If `iters` is 0 or larger than `u32::MAX`.
 pub fn gen_salted_password(password: &str, salt: &[u8], iters: usize) -> Vec<u8> {
     // according to postgres doc, if we failed to normalize password, use
     // original password instead of throwing error
@@ -71,27 +57,170 @@ pub fn random_nonce() -> String {
     STANDARD.encode(buf)
 }
 
-impl<A, P> SASLScramAuthStartupHandler<A, P> {
-    fn compute_channel_binding(&self, client_channel_binding: &str) -> String {
-        if client_channel_binding.starts_with("p=tls-server-end-point") {
-            format!(
-                "{}{}",
-                STANDARD.encode(client_channel_binding),
-                self.server_cert_sig
-                    .as_deref()
-                    .map(|v| &v[..])
-                    .unwrap_or("")
-            )
-        } else {
-            STANDARD.encode(client_channel_binding.as_bytes())
+/// Deprecated name of `ScramSha256StartupHandler`.
+#[deprecated(note = "use ScramSha256StartupHandler")]
+pub type SASLScramAuthStartupHandler<A, P> = ScramSha256StartupHandler<A, P>;
+
+/// Deprecated name of `MakeScramSha256StartupHandler`.
+#[deprecated(note = "use MakeScramSha256StartupHandler")]
+pub type MakeSASLScramAuthStartupHandler<A, P> = MakeScramSha256StartupHandler<A, P>;
+
+/// SCRAM-SHA-256 secret of a user, in the form postgres stores in
+/// `pg_authid.rolpassword`:
+///
+/// ```text
+/// SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>
+/// ```
+///
+/// Unlike salted password, the secret can't be used to log in as the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScramSecret {
+    pub iterations: usize,
+    pub salt: Vec<u8>,
+    pub stored_key: Vec<u8>,
+    pub server_key: Vec<u8>,
+}
+
+impl ScramSecret {
+    /// Compute secret from cleartext password.
+    ///
+    /// # Panics
+    ///
+    /// If `iterations` is 0 or larger than `u32::MAX`.
+    pub fn new(password: &str, salt: &[u8], iterations: usize) -> ScramSecret {
+        ScramSecret::from_salted_password(
+            &gen_salted_password(password, salt, iterations),
+            salt,
+            iterations,
+        )
+    }
+
+    /// Compute secret from salted password, see `gen_salted_password`.
+    pub fn from_salted_password(
+        salted_password: &[u8],
+        salt: &[u8],
+        iterations: usize,
+    ) -> ScramSecret {
+        let client_key = hmac(salted_password, b"Client Key");
+        ScramSecret {
+            iterations,
+            salt: salt.to_vec(),
+            stored_key: h(&client_key),
+            server_key: hmac(salted_password, b"Server Key"),
+        }
+    }
+
+    /// Parse secret in postgres format.
+    pub fn parse(secret: &str) -> PgWireResult<ScramSecret> {
+        let invalid = || PgWireError::InvalidScramMessage("Invalid SCRAM secret".to_owned());
+        let rest = secret.strip_prefix("SCRAM-SHA-256$").ok_or_else(invalid)?;
+        let (params, keys) = rest.split_once('$').ok_or_else(invalid)?;
+        let (iterations, salt) = params.split_once(':').ok_or_else(invalid)?;
+        let (stored_key, server_key) = keys.split_once(':').ok_or_else(invalid)?;
+
+        let decode = |v: &str| STANDARD.decode(v).map_err(|_| invalid());
+        Ok(ScramSecret {
+            iterations: iterations
+                .parse()
+                .ok()
+                .filter(|i| valid_iterations(*i))
+                .ok_or_else(invalid)?,
+            salt: decode(salt)?,
+            stored_key: decode(stored_key)?,
+            server_key: decode(server_key)?,
+        })
+    }
+}
+
+impl std::fmt::Display for ScramSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "SCRAM-SHA-256${}:{}${}:{}",
+            self.iterations,
+            STANDARD.encode(&self.salt),
+            STANDARD.encode(&self.stored_key),
+            STANDARD.encode(&self.server_key)
+        )
+    }
+}
+
+#[derive(Debug)]
+enum ScramExchange {
+    Initial,
+    ServerFirstSent {
+        secret: ScramSecret,
+        // false when authenticating an unknown user, which always fails
+        user_exists: bool,
+        channel_binding: String,
+        nonce: String,
+        // client-first-message-bare,server-first-message
+        partial_auth_msg: String,
+    },
+    Finished,
+}
+
+/// Startup handler for SCRAM-SHA-256 authentication, the default password
+/// authentication of postgres.
+///
+/// This handler runs the whole SASL exchange against verifiers from
+/// `AuthSource`. For cleartext verifiers, a secret is derived with a random
+/// salt. Unknown users, and users with md5 verifiers, go through the same
+/// exchange with a mock secret and fail at the end, so clients can't tell
+/// whether a user exists. The handler keeps
+/// per-connection state, use `MakeScramSha256StartupHandler` to create one
+/// for each connection.
+#[derive(Debug)]
+pub struct ScramSha256StartupHandler<A, P> {
+    auth_source: Arc<A>,
+    parameter_provider: Arc<P>,
+    server_cert_sig: Option<Arc<Vec<u8>>>,
+    mock_key: Arc<Vec<u8>>,
+    iterations: usize,
+    state: Mutex<ScramExchange>,
+}
+
+impl<A, P> ScramSha256StartupHandler<A, P> {
+    fn mock_secret(&self, user: &str) -> ScramSecret {
+        // deterministic per user so repeated attempts see the same salt
+        let salt = hmac(&self.mock_key, user.as_bytes());
+        let key = hmac(&self.mock_key, &salt);
+        ScramSecret {
+            iterations: self.iterations,
+            salt: salt[..16].to_vec(),
+            stored_key: key.clone(),
+            server_key: key,
+        }
+    }
+
+    // base64 of gs2 header and channel binding data, expected in client-final
+    fn expected_channel_binding(&self, gs2_header: &str) -> PgWireResult<String> {
+        let mut data = gs2_header.as_bytes().to_vec();
+        if gs2_header.starts_with("p=tls-server-end-point,") {
+            let sig = self.server_cert_sig.as_deref().ok_or_else(|| {
+                PgWireError::InvalidScramMessage("Channel binding not supported".to_owned())
+            })?;
+            data.extend_from_slice(sig);
+        } else if gs2_header.starts_with("p=") {
+            return Err(PgWireError::InvalidScramMessage(format!(
+                "Unsupported channel binding: {gs2_header}"
+            )));
         }
+        Ok(STANDARD.encode(data))
     }
 }
 
+fn authentication_failed(user: &str) -> ErrorResponse {
+    ErrorInfo::new(
+        "FATAL".to_owned(),
+        SqlState::INVALID_PASSWORD.into(),
+        format!("password authentication failed for user \"{user}\""),
+    )
+    .into()
+}
+
 #[async_trait]
-impl<A: AuthSource, P: ServerParameterProvider> StartupHandler
-    for SASLScramAuthStartupHandler<A, P>
-{
+impl<A: AuthSource, P: ServerParameterProvider> StartupHandler for ScramSha256StartupHandler<A, P> {
     async fn on_startup<C>(
         &self,
         client: &mut C,
@@ -106,123 +235,144 @@ impl<A: AuthSource, P: ServerParameterProvider> StartupHandler
             PgWireFrontendMessage::Startup(ref startup) => {
                 super::save_startup_parameters_to_metadata(client, startup);
                 client.set_state(PgWireConnectionState::AuthenticationInProgress);
-                let supported_mechanisms = if self.server_cert_sig.is_some() {
-                    vec!["SCRAM-SHA-256".to_owned(), "SCRAM-SHA-256-PLUS".to_owned()]
-                } else {
-                    vec!["SCRAM-SHA-256".to_owned()]
-                };
+                let mut mechanisms = vec![SCRAM_SHA_256.to_owned()];
+                if self.server_cert_sig.is_some() {
+                    mechanisms.push(SCRAM_SHA_256_PLUS.to_owned());
+                }
                 client
                     .send(PgWireBackendMessage::Authentication(Authentication::SASL(
-                        supported_mechanisms,
+                        mechanisms,
                     )))
                     .await?;
             }
             PgWireFrontendMessage::PasswordMessageFamily(msg) => {
-                let salt_and_salted_pass = {
-                    let state = self.state.lock().await;
-                    match *state {
-                        ScramState::Initial => {
-                            let login_info = LoginInfo::from_client_info(client);
-                            self.auth_db.get_password(&login_info).await?
+                let mut state = self.state.lock().await;
+                match std::mem::replace(&mut *state, ScramExchange::Finished) {
+                    ScramExchange::Initial => {
+                        let resp = msg.into_sasl_initial_response()?;
+                        let data = resp.data.as_ref().ok_or_else(|| {
+                            PgWireError::InvalidScramMessage("Empty client-first".to_owned())
+                        })?;
+                        let client_first =
+                            ClientFirst::try_new(String::from_utf8_lossy(data).as_ref())?;
+
+                        // the mechanism and channel binding flag must agree,
+                        // and a client supporting channel binding must use it
+                        // when the server offers it
+                        let cbind_flag = client_first.cbind_flag.as_str();
+                        let valid = match resp.auth_method.as_str() {
+                            SCRAM_SHA_256_PLUS => cbind_flag.starts_with("p="),
+                            SCRAM_SHA_256 => {
+                                cbind_flag == "n"
+                                    || (cbind_flag == "y" && self.server_cert_sig.is_none())
+                            }
+                            _ => false,
+                        };
+                        if !valid {
+                            return Err(PgWireError::InvalidScramMessage(format!(
+                                "Invalid mechanism {} with channel binding flag {}",
+                                resp.auth_method, cbind_flag
+                            )));
                         }
-                        ScramState::ServerFirstSent(ref pass, _, _) => pass.clone(),
+
+                        let login_info = LoginInfo::from_client_info(client);
+                        let user = login_info.user().unwrap_or_default().to_owned();
+                        let (secret, user_exists) =
+                            match self.auth_source.get_verifier(&login_info).await? {
+                                Some(Verifier::ScramSha256(secret)) => (secret, true),
+                                Some(Verifier::Cleartext(password)) => {
+                                    let salt = rand::random::<[u8; 16]>();
+                                    (ScramSecret::new(&password, &salt, self.iterations), true)
+                                }
+                                _ => (self.mock_secret(&user), false),
+                            };
+
+                        let mut nonce = client_first.nonce.clone();
+                        nonce.push_str(&random_nonce());
+                        let server_first = ServerFirst::new(
+                            nonce.clone(),
+                            STANDARD.encode(&secret.salt),
+                            secret.iterations,
+                        );
+                        let server_first_message = server_first.message();
+
+                        *state = ScramExchange::ServerFirstSent {
+                            secret,
+                            user_exists,
+                            channel_binding: client_first.channel_binding(),
+                            nonce,
+                            partial_auth_msg: format!(
+                                "{},{}",
+                                client_first.bare(),
+                                server_first_message
+                            ),
+                        };
+                        client
+                            .send(PgWireBackendMessage::Authentication(
+                                Authentication::SASLContinue(Bytes::from(server_first_message)),
+                            ))
+                            .await?;
                     }
-                };
-
-                let mut success = false;
-                let resp = {
-                    // this should never block
-                    let mut state = self.state.lock().await;
-                    match *state {
-                        ScramState::Initial => {
-                            // initial response, client_first
-                            let resp = msg.into_sasl_initial_response()?;
-                            // parse into client_first
-                            let client_first = resp
-                                .data
-                                .as_ref()
-                                .ok_or_else(|| {
-                                    PgWireError::InvalidScramMessage(
-                                        "Empty client-first".to_owned(),
-                                    )
-                                })
-                                .and_then(|data| {
-                                    ClientFirst::try_new(String::from_utf8_lossy(data).as_ref())
-                                })?;
-                            // dbg!(&client_first);
-
-                            // create server_first and send
-                            let mut new_nonce = client_first.nonce.clone();
-                            new_nonce.push_str(random_nonce().as_str());
-
-                            let server_first = ServerFirst::new(
-                                new_nonce,
-                                STANDARD.encode(
-                                    salt_and_salted_pass
-                                        .salt
-                                        .as_ref()
-                                        .expect("Salt required for SCRAM auth source"),
-                                ),
-                                self.iterations,
-                            );
-                            let server_first_message = server_first.message();
-
-                            *state = ScramState::ServerFirstSent(
-                                salt_and_salted_pass,
-                                client_first.channel_binding(),
-                                format!("{},{}", client_first.bare(), &server_first_message),
-                            );
-                            Authentication::SASLContinue(Bytes::from(server_first_message))
+                    ScramExchange::ServerFirstSent {
+                        secret,
+                        user_exists,
+                        channel_binding,
+                        nonce,
+                        partial_auth_msg,
+                    } => {
+                        let resp = msg.into_sasl_response()?;
+                        let client_final =
+                            ClientFinal::try_new(String::from_utf8_lossy(&resp.data).as_ref())?;
+
+                        client_final.validate_channel_binding(
+                            &self.expected_channel_binding(&channel_binding)?,
+                        )?;
+                        if client_final.nonce != nonce {
+                            return Err(PgWireError::InvalidScramMessage(
+                                "Nonce mismatch".to_owned(),
+                            ));
                         }
-                        ScramState::ServerFirstSent(
-                            _,
-                            ref channel_binding_prefix,
-                            ref partial_auth_msg,
-                        ) => {
-                            // second response, client_final
-                            let resp = msg.into_sasl_response()?;
-                            let client_final =
-                                ClientFinal::try_new(String::from_utf8_lossy(&resp.data).as_ref())?;
-                            // dbg!(&client_final);
-
-                            let channel_binding =
-                                self.compute_channel_binding(channel_binding_prefix);
-                            client_final.validate_channel_binding(&channel_binding)?;
-
-                            let salted_password = salt_and_salted_pass.password;
-                            let client_key = hmac(salted_password.as_ref(), b"Client Key");
-                            let stored_key = h(client_key.as_ref());
-                            let auth_msg =
-                                format!("{},{}", partial_auth_msg, client_final.without_proof());
-                            let client_signature = hmac(stored_key.as_ref(), auth_msg.as_bytes());
-
-                            let computed_client_proof = STANDARD.encode(
-                                xor(client_key.as_ref(), client_signature.as_ref()).as_slice(),
-                            );
-
-                            if computed_client_proof == client_final.proof {
-                                let server_key = hmac(salted_password.as_ref(), b"Server Key");
-                                let server_signature =
-                                    hmac(server_key.as_ref(), auth_msg.as_bytes());
-                                let server_final =
-                                    ServerFinalSuccess::new(STANDARD.encode(server_signature));
-                                success = true;
-                                Authentication::SASLFinal(Bytes::from(server_final.message()))
-                            } else {
-                                let server_final =
-                                    ServerFinalError::new("invalid-proof".to_owned());
-                                Authentication::SASLFinal(Bytes::from(server_final.message()))
-                            }
+
+                        let auth_msg =
+                            format!("{},{}", partial_auth_msg, client_final.without_proof());
+                        let client_signature = hmac(&secret.stored_key, auth_msg.as_bytes());
+                        let verified = STANDARD
+                            .decode(&client_final.proof)
+                            .ok()
+                            .filter(|proof| proof.len() == client_signature.len())
+                            .map(|proof| {
+                                // recover ClientKey from proof and check its hash
+                                let client_key = xor(&proof, &client_signature);
+                                constant_time_eq(&h(&client_key), &secret.stored_key)
+                            })
+                            .unwrap_or(false);
+
+                        if verified && user_exists {
+                            let server_signature = hmac(&secret.server_key, auth_msg.as_bytes());
+                            let server_final =
+                                ServerFinalSuccess::new(STANDARD.encode(server_signature));
+                            client
+                                .send(PgWireBackendMessage::Authentication(
+                                    Authentication::SASLFinal(Bytes::from(server_final.message())),
+                                ))
+                                .await?;
+                            super::finish_authentication(client, self.parameter_provider.as_ref())
+                                .await;
+                        } else {
+                            let login_info = LoginInfo::from_client_info(client);
+                            let error =
+                                authentication_failed(login_info.user().unwrap_or_default());
+                            client
+                                .feed(PgWireBackendMessage::ErrorResponse(error))
+                                .await?;
+                            client.close().await?;
                         }
                     }
-                };
-
-                client
-                    .send(PgWireBackendMessage::Authentication(resp))
-                    .await?;
-
-                if success {
-                    super::finish_authentication(client, self.parameter_provider.as_ref()).await
+                    ScramExchange::Finished => {
+                        return Err(PgWireError::InvalidScramMessage(
+                            "Unexpected SASL message".to_owned(),
+                        ));
+                    }
                 }
             }
             _ => {}
@@ -232,59 +382,75 @@ impl<A: AuthSource, P: ServerParameterProvider> StartupHandler
     }
 }
 
-#[derive(Debug, new)]
-pub struct MakeSASLScramAuthStartupHandler<A, P> {
-    auth_db: Arc<A>,
+/// Creates a `ScramSha256StartupHandler` for each connection.
+#[derive(Debug)]
+pub struct MakeScramSha256StartupHandler<A, P> {
+    auth_source: Arc<A>,
     parameter_provider: Arc<P>,
-    #[new(default)]
-    server_cert_sig: Option<Arc<String>>,
-    #[new(value = "4096")]
+    server_cert_sig: Option<Arc<Vec<u8>>>,
+    mock_key: Arc<Vec<u8>>,
     iterations: usize,
 }
 
-impl<A, P> MakeSASLScramAuthStartupHandler<A, P> {
-    /// enable channel binding (SCRAM-SHA-256-PLUS) by configuring server
-    /// certificate.
-    ///
-    /// Original pem data is required here. We will decode pem and use the first
-    /// certificate as server certificate.
+impl<A, P> MakeScramSha256StartupHandler<A, P> {
+    pub fn new(auth_source: Arc<A>, parameter_provider: Arc<P>) -> Self {
+        MakeScramSha256StartupHandler {
+            auth_source,
+            parameter_provider,
+            server_cert_sig: None,
+            mock_key: Arc::new((0..32).map(|_| rand::random::<u8>()).collect()),
+            iterations: 4096,
+        }
+    }
+
+    /// Enable channel binding (SCRAM-SHA-256-PLUS) with the server
+    /// certificate in pem format. The first certificate is used.
     pub fn configure_certificate(&mut self, certs_pem: &[u8]) -> PgWireResult<()> {
         let sig = compute_cert_signature(certs_pem)?;
-        self.server_cert_sig = Some(Arc::new(STANDARD.encode(sig)));
+        self.server_cert_sig = Some(Arc::new(sig));
         Ok(())
     }
 
-    /// Set password hash iteration count, according to SCRAM RFC, a minimal of
-    /// 4096 is required.
+    /// Set iteration count of secrets derived from cleartext verifiers, and of
+    /// the mock secret for unknown users, 4096 by default. According to SCRAM
+    /// RFC, a minimal of 4096 is required.
     ///
-    /// Note that this implementation does not hash password, it just tells
-    /// client to hash with this iteration count. You have to implement password
-    /// hashing in your `AuthSource` implementation, either after fetching
-    /// cleartext password, or before storing hashed password. And this number
-    /// should be identical to your `AuthSource` implementation.
-    pub fn set_iterations(&mut self, iterations: usize) {
+    /// It should match iteration count of your stored SCRAM secrets, so that
+    /// unknown users are indistinguishable. Fails if `iterations` is 0 or
+    /// larger than `u32::MAX`.
+    pub fn set_iterations(&mut self, iterations: usize) -> PgWireResult<()> {
+        if !valid_iterations(iterations) {
+            return Err(PgWireError::InvalidScramMessage(format!(
+                "Invalid iteration count {iterations}"
+            )));
+        }
         self.iterations = iterations;
+        Ok(())
     }
 }
 
-impl<A, P> MakeHandler for MakeSASLScramAuthStartupHandler<A, P>
+impl<A, P> MakeHandler for MakeScramSha256StartupHandler<A, P>
 where
     A: AuthSource,
     P: ServerParameterProvider,
 {
-    type Handler = Arc<SASLScramAuthStartupHandler<A, P>>;
+    type Handler = Arc<ScramSha256StartupHandler<A, P>>;
 
     fn make(&self) -> Self::Handler {
-        Arc::new(SASLScramAuthStartupHandler {
-            auth_db: self.auth_db.clone(),
+        Arc::new(ScramSha256StartupHandler {
+            auth_source: self.auth_source.clone(),
             parameter_provider: self.parameter_provider.clone(),
-            state: Mutex::new(ScramState::Initial),
             server_cert_sig: self.server_cert_sig.clone(),
+            mock_key: self.mock_key.clone(),
             iterations: self.iterations,
+            state: Mutex::new(ScramExchange::Initial),
         })
     }
 }
 
+const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+const SCRAM_SHA_256_PLUS: &str = "SCRAM-SHA-256-PLUS";
+
 #[allow(dead_code)]
 #[derive(Debug)]
 struct ClientFirst {
@@ -410,23 +576,22 @@ impl ServerFinalSuccess {
     }
 }
 
-#[derive(Debug, new)]
-struct ServerFinalError {
-    error: String,
-}
-
-impl ServerFinalError {
-    fn message(&self) -> String {
-        format!("e={}", self.error)
-    }
+/// Whether `iterations` is a valid iteration count of PBKDF2, a positive
+/// `u32`.
+pub(crate) fn valid_iterations(iterations: usize) -> bool {
+    u32::try_from(iterations).is_ok_and(|i| i > 0)
 }
 
 fn hi(normalized_password: &[u8], salt: &[u8], iterations: usize) -> Vec<u8> {
     let mut buf = [0u8; 32];
+    let iterations = u32::try_from(iterations)
+        .ok()
+        .and_then(NonZeroU32::new)
+        .expect("iteration count must be between 1 and u32::MAX");
 
     pbkdf2::derive(
         pbkdf2::PBKDF2_HMAC_SHA256,
-        NonZeroU32::new(iterations as u32).unwrap(),
+        iterations,
         salt,
         normalized_password,
         &mut buf,
@@ -434,16 +599,16 @@ fn hi(normalized_password: &[u8], salt: &[u8], iterations: usize) -> Vec<u8> {
     buf.to_vec()
 }
 
-fn hmac(key: &[u8], msg: &[u8]) -> Vec<u8> {
+pub(crate) fn hmac(key: &[u8], msg: &[u8]) -> Vec<u8> {
     let mac = hmac::Key::new(hmac::HMAC_SHA256, key);
     hmac::sign(&mac, msg).as_ref().to_vec()
 }
 
-fn h(msg: &[u8]) -> Vec<u8> {
+pub(crate) fn h(msg: &[u8]) -> Vec<u8> {
     digest::digest(&digest::SHA256, msg).as_ref().to_vec()
 }
 
-fn xor(lhs: &[u8], rhs: &[u8]) -> Vec<u8> {
+pub(crate) fn xor(lhs: &[u8], rhs: &[u8]) -> Vec<u8> {
     lhs.iter()
         .zip(rhs.iter())
         .map(|(l, r)| l.bitxor(r))
@@ -480,3 +645,162 @@ fn compute_cert_signature(cert: &[u8]) -> PgWireResult<Vec<u8>> {
         _ => Err(PgWireError::UnsupportedCertificateSignatureAlgorithm),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::auth::{md5pass, DefaultServerParameterProvider};
+    use crate::api::mock::MockClient;
+    use crate::messages::startup::{
+        PasswordMessageFamily, SASLInitialResponse, SASLResponse, Startup,
+    };
+    use crate::messages::Message;
+    use bytes::BytesMut;
+
+    struct Users;
+
+    #[async_trait]
+    impl AuthSource for Users {
+        async fn get_verifier(&self, login: &LoginInfo) -> PgWireResult<Option<Verifier>> {
+            Ok(match login.user() {
+                Some("alice") => Some(Verifier::ScramSha256(ScramSecret::new(
+                    "secret",
+                    b"0123456789abcdef",
+                    4096,
+                ))),
+                Some("carol") => Some(Verifier::Cleartext("secret".to_owned())),
+                Some("dave") => Some(Verifier::Md5(md5pass::md5_verifier("dave", "secret"))),
+                _ => None,
+            })
+        }
+    }
+
+    // password messages arrive undecoded from the wire
+    fn raw<M: Message>(message: M) -> PgWireFrontendMessage {
+        let mut body = BytesMut::new();
+        message.encode_body(&mut body).unwrap();
+        PgWireFrontendMessage::PasswordMessageFamily(PasswordMessageFamily::Raw(body))
+    }
+
+    // runs the client side of the exchange, returns messages sent by server
+    async fn authenticate(user: &str, password: &str) -> Vec<PgWireBackendMessage> {
+        let make = MakeScramSha256StartupHandler::new(
+            Arc::new(Users),
+            Arc::new(DefaultServerParameterProvider::default()),
+        );
+        let handler = make.make();
+        let mut client = MockClient::new();
+
+        let mut startup = Startup::new();
+        startup
+            .parameters
+            .insert("user".to_owned(), user.to_owned());
+        handler
+            .on_startup(&mut client, PgWireFrontendMessage::Startup(startup))
+            .await
+            .unwrap();
+
+        let client_first_bare = format!("n={user},r=clientnonce");
+        handler
+            .on_startup(
+                &mut client,
+                raw(SASLInitialResponse::new(
+                    SCRAM_SHA_256.to_owned(),
+                    Some(Bytes::from(format!("n,,{client_first_bare}"))),
+                )),
+            )
+            .await
+            .unwrap();
+
+        let PgWireBackendMessage::Authentication(Authentication::SASLContinue(server_first)) =
+            &client.sent[1]
+        else {
+            panic!("expected server-first message");
+        };
+        let server_first = String::from_utf8(server_first.to_vec()).unwrap();
+        let mut fields = server_first.split(',');
+        let nonce = fields.next().unwrap().strip_prefix("r=").unwrap();
+        let salt = STANDARD
+            .decode(fields.next().unwrap().strip_prefix("s=").unwrap())
+            .unwrap();
+        let iterations = fields.next().unwrap().strip_prefix("i=").unwrap();
+        assert!(nonce.starts_with("clientnonce"));
+
+        let salted_password = gen_salted_password(password, &salt, iterations.parse().unwrap());
+        let client_key = hmac(&salted_password, b"Client Key");
+        let without_proof = format!("c={},r={nonce}", STANDARD.encode("n,,"));
+        let auth_msg = format!("{client_first_bare},{server_first},{without_proof}");
+        let proof = xor(&client_key, &hmac(&h(&client_key), auth_msg.as_bytes()));
+        handler
+            .on_startup(
+                &mut client,
+                raw(SASLResponse::new(Bytes::from(format!(
+                    "{without_proof},p={}",
+                    STANDARD.encode(proof)
+                )))),
+            )
+            .await
+            .unwrap();
+
+        client.sent
+    }
+
+    #[test]
+    fn test_scram_secret() {
+        let secret = ScramSecret::new("secret", b"0123456789abcdef", 4096);
+        let parsed = ScramSecret::parse(&secret.to_string()).unwrap();
+        assert_eq!(secret, parsed);
+        assert!(ScramSecret::parse("md5abcdef").is_err());
+
+        let invalid = ScramSecret {
+            iterations: 0,
+            ..secret
+        };
+        assert!(ScramSecret::parse(&invalid.to_string()).is_err());
+        let invalid = invalid.to_string().replacen("$0:", "$-1:", 1);
+        assert!(ScramSecret::parse(&invalid).is_err());
+        let invalid = invalid.replacen("$-1:", "$4294967296:", 1);
+        assert!(ScramSecret::parse(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_set_iterations() {
+        let mut authenticator = MakeScramSha256StartupHandler::new(
+            Arc::new(Users),
+            Arc::new(DefaultServerParameterProvider::default()),
+        );
+        assert!(authenticator.set_iterations(0).is_err());
+        assert!(authenticator.set_iterations(u32::MAX as usize + 1).is_err());
+        assert!(authenticator.set_iterations(10000).is_ok());
+        assert_eq!(10000, authenticator.iterations);
+    }
+
+    #[tokio::test]
+    async fn test_scram_sha_256_startup_handler() {
+        let sent = authenticate("alice", "secret").await;
+        assert!(matches!(
+            sent[2],
+            PgWireBackendMessage::Authentication(Authentication::SASLFinal(_))
+        ));
+        assert!(matches!(
+            sent[3],
+            PgWireBackendMessage::Authentication(Authentication::Ok)
+        ));
+
+        let sent = authenticate("carol", "secret").await;
+        assert!(matches!(
+            sent[3],
+            PgWireBackendMessage::Authentication(Authentication::Ok)
+        ));
+
+        let sent = authenticate("alice", "wrong").await;
+        assert_eq!(3, sent.len());
+        assert!(matches!(sent[2], PgWireBackendMessage::ErrorResponse(_)));
+
+        for user in ["bob", "dave"] {
+            let sent = authenticate(user, "secret").await;
+            assert_eq!(3, sent.len());
+            assert!(matches!(sent[2], PgWireBackendMessage::ErrorResponse(_)));
+        }
+    }
+}
@@ -0,0 +1,318 @@
+//! Run blocking query handlers off the async runtime.
+//!
+//! Storage engines that block, or queries that are CPU heavy, stall other
+//! connections when running on the reactor. Implement `BlockingQueryHandler`
+//! and wrap it with `BlockingSimpleQueryHandler`, the query runs on a
+//! `BlockingExecutor`, tokio's blocking thread pool by default, and its
+//! responses and rows are streamed back to the connection through bounded
+//! channels. A slow client blocks the handler thread, instead of buffering
+//! the whole result set.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::executor::block_on;
+use futures::{Sink, SinkExt, StreamExt};
+use tokio_util::sync::CancellationToken;
+
+use super::query::{is_empty_query, send_simple_query_response, SimpleQueryHandler};
+use super::results::{FieldInfo, QueryResponse, Response};
+use super::{ClientInfo, PgWireConnectionState};
+use crate::error::{PgWireError, PgWireResult};
+use crate::messages::data::DataRow;
+use crate::messages::response::{EmptyQueryResponse, ReadyForQuery};
+use crate::messages::simplequery::Query;
+use crate::messages::PgWireBackendMessage;
+
+/// Runs blocking tasks on a thread pool.
+pub trait BlockingExecutor: Send + Sync {
+    fn execute(&self, task: Box<dyn FnOnce() + Send + 'static>);
+}
+
+/// `BlockingExecutor` using tokio's `spawn_blocking`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioBlockingExecutor;
+
+impl BlockingExecutor for TokioBlockingExecutor {
+    fn execute(&self, task: Box<dyn FnOnce() + Send + 'static>) {
+        tokio::task::spawn_blocking(task);
+    }
+}
+
+/// Simple query handler that runs on a blocking thread.
+pub trait BlockingQueryHandler: Send + Sync + 'static {
+    /// Run `query` and write its responses to `writer` in order. `metadata` is
+    /// a snapshot of the client's metadata taken when the query arrived.
+    fn do_query(
+        &self,
+        metadata: &HashMap<String, String>,
+        query: &str,
+        writer: &mut ResponseWriter,
+    ) -> PgWireResult<()>;
+}
+
+fn disconnected() -> PgWireError {
+    PgWireError::IoError(std::io::Error::new(
+        std::io::ErrorKind::BrokenPipe,
+        "query is cancelled or client disconnected",
+    ))
+}
+
+/// Sends responses of a blocking query to the connection.
+pub struct ResponseWriter {
+    sender: Sender<PgWireResult<Response<'static>>>,
+    capacity: usize,
+    token: CancellationToken,
+}
+
+impl ResponseWriter {
+    /// Whether the query is cancelled, or the client has disconnected. The
+    /// handler should stop then, see `ClientInfo::cancellation_token`.
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Send a response. Returns error if the query is cancelled or the
+    /// client has disconnected, the handler should stop then.
+    pub fn send(&mut self, response: Response<'static>) -> PgWireResult<()> {
+        block_on(self.sender.send(Ok(response))).map_err(|_| disconnected())
+    }
+
+    /// Start a query response with `fields`, rows are sent with the returned
+    /// `RowWriter`. The response completes when the writer is dropped.
+    pub fn send_query(&mut self, fields: Arc<Vec<FieldInfo>>) -> PgWireResult<RowWriter<'_>> {
+        let (sender, receiver) = channel(self.capacity);
+        self.send(Response::Query(QueryResponse::new(fields, receiver)))?;
+        Ok(RowWriter {
+            sender,
+            _writer: self,
+        })
+    }
+}
+
+/// Sends rows of a query response. It borrows the `ResponseWriter`, so the
+/// response must be finished before sending the next one.
+pub struct RowWriter<'a> {
+    sender: Sender<PgWireResult<DataRow>>,
+    _writer: &'a mut ResponseWriter,
+}
+
+impl<'a> RowWriter<'a> {
+    /// Send a row, blocks when client is slower than the handler.
+    pub fn send(&mut self, row: DataRow) -> PgWireResult<()> {
+        block_on(self.sender.send(Ok(row))).map_err(|_| disconnected())
+    }
+
+    /// Abort the response with an error.
+    pub fn fail(mut self, error: PgWireError) {
+        let _ = block_on(self.sender.send(Err(error)));
+    }
+}
+
+/// Adapts a `BlockingQueryHandler` to `SimpleQueryHandler`.
+pub struct BlockingSimpleQueryHandler<H> {
+    handler: Arc<H>,
+    executor: Arc<dyn BlockingExecutor>,
+    capacity: usize,
+}
+
+impl<H: BlockingQueryHandler> BlockingSimpleQueryHandler<H> {
+    /// Run `handler` with `TokioBlockingExecutor`.
+    pub fn new(handler: Arc<H>) -> BlockingSimpleQueryHandler<H> {
+        BlockingSimpleQueryHandler {
+            handler,
+            executor: Arc::new(TokioBlockingExecutor),
+            capacity: 64,
+        }
+    }
+
+    /// Run handler with a user supplied executor.
+    pub fn with_executor(mut self, executor: Arc<dyn BlockingExecutor>) -> Self {
+        self.executor = executor;
+        self
+    }
+
+    /// Number of responses or rows buffered before the handler blocks, 64 by
+    /// default.
+    pub fn with_channel_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    fn spawn<C: ClientInfo>(
+        &self,
+        client: &C,
+        query: String,
+    ) -> Receiver<PgWireResult<Response<'static>>> {
+        let (sender, receiver) = channel(self.capacity);
+        let handler = self.handler.clone();
+        let capacity = self.capacity;
+        let metadata = client.metadata().clone();
+        let token = client.cancellation_token();
+        self.executor.execute(Box::new(move || {
+            let mut writer = ResponseWriter {
+                sender,
+                capacity,
+                token,
+            };
+            let result = catch_unwind(AssertUnwindSafe(|| {
+                handler.do_query(&metadata, &query, &mut writer)
+            }))
+            .unwrap_or_else(|_| {
+                Err(PgWireError::ApiError(
+                    "blocking query handler panicked".into(),
+                ))
+            });
+            if let Err(e) = result {
+                let _ = block_on(writer.sender.send(Err(e)));
+            }
+        }));
+        receiver
+    }
+}
+
+#[async_trait]
+impl<H: BlockingQueryHandler> SimpleQueryHandler for BlockingSimpleQueryHandler<H> {
+    /// Same as the default implementation, except that each response is sent
+    /// as soon as the handler produces it.
+    async fn on_query<C>(&self, client: &mut C, query: Query) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        client.set_state(PgWireConnectionState::QueryInProgress);
+        if is_empty_query(&query.query) {
+            client
+                .feed(PgWireBackendMessage::EmptyQueryResponse(EmptyQueryResponse))
+                .await?;
+        } else {
+            let mut responses = self.spawn(client, query.query);
+            while let Some(response) = responses.next().await {
+                if send_simple_query_response(client, response?).await? {
+                    // `ReadyForQuery` is sent when copy is finished
+                    client.set_state(PgWireConnectionState::CopyInProgress(false));
+                    return Ok(());
+                }
+            }
+        }
+
+        client
+            .feed(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+                client.transaction_status(),
+            )))
+            .await?;
+        client.flush().await?;
+        client.set_state(PgWireConnectionState::ReadyForQuery);
+        Ok(())
+    }
+
+    /// Collect all responses. Rows are buffered in memory, `on_query` streams
+    /// them instead.
+    async fn do_query<'a, C>(
+        &self,
+        client: &mut C,
+        query: &'a str,
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + Unpin + Send + Sync,
+    {
+        let mut responses = self.spawn(client, query.to_owned());
+        let mut results = Vec::new();
+        while let Some(response) = responses.next().await {
+            match response? {
+                Response::Query(query) => {
+                    let fields = query.row_schema();
+                    let tag = query.command_tag().to_owned();
+                    let rows = query.data_rows().collect::<Vec<_>>().await;
+                    let mut query = QueryResponse::new(fields, futures::stream::iter(rows));
+                    query.set_command_tag(&tag);
+                    results.push(Response::Query(query));
+                }
+                response => results.push(response),
+            }
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::mock::MockClient;
+    use crate::api::results::{DataRowEncoder, FieldFormat, Tag};
+    use crate::api::Type;
+
+    struct CountHandler;
+
+    impl BlockingQueryHandler for CountHandler {
+        fn do_query(
+            &self,
+            _metadata: &HashMap<String, String>,
+            query: &str,
+            writer: &mut ResponseWriter,
+        ) -> PgWireResult<()> {
+            let count: i32 = query
+                .parse()
+                .map_err(|e| PgWireError::ApiError(Box::new(e)))?;
+            let fields = Arc::new(vec![FieldInfo::new(
+                "n".to_owned(),
+                None,
+                None,
+                Type::INT4,
+                FieldFormat::Text,
+            )]);
+            {
+                let mut rows = writer.send_query(fields.clone())?;
+                let mut encoder = DataRowEncoder::new(fields);
+                for i in 0..count {
+                    encoder.encode_field(&i)?;
+                    rows.send(encoder.take_row()?)?;
+                }
+            }
+            writer.send(Response::Execution(Tag::new("DONE")))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blocking_query_handler() {
+        // capacity smaller than row count, the handler blocks on the client
+        let handler =
+            BlockingSimpleQueryHandler::new(Arc::new(CountHandler)).with_channel_capacity(1);
+        let mut client = MockClient::new();
+        handler
+            .on_query(&mut client, Query::new("10".to_owned()))
+            .await
+            .unwrap();
+
+        let rows = client
+            .sent
+            .iter()
+            .filter(|m| matches!(m, PgWireBackendMessage::DataRow(_)))
+            .count();
+        assert_eq!(rows, 10);
+        assert!(matches!(
+            client.sent[..],
+            [
+                PgWireBackendMessage::RowDescription(_),
+                ..,
+                PgWireBackendMessage::CommandComplete(_),
+                PgWireBackendMessage::CommandComplete(_),
+                PgWireBackendMessage::ReadyForQuery(_)
+            ]
+        ));
+
+        let responses = handler.do_query(&mut client, "3").await.unwrap();
+        assert_eq!(responses.len(), 2);
+
+        let mut client = MockClient::new();
+        assert!(handler
+            .on_query(&mut client, Query::new("x".to_owned()))
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,506 @@
+//! Caching results of read-only statements.
+//!
+//! `CachingQueryHandler` wraps a query handler and serves repeated executions
+//! of statements chosen by `ResultCache`'s policy from memory, without
+//! calling the wrapped handler. Entries expire after their ttl, and can be
+//! invalidated explicitly, for example after a write to the underlying
+//! tables.
+//!
+//! The cache is shared by connections, but results are only served to
+//! sessions with the same key as the session that produced them. By default
+//! the key is the user, database and `options` of startup parameters.
+//! Settings changed in the session, like `SET ROLE` or `search_path`, are not
+//! part of it, use `ResultCache::with_session_key` when they affect results.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::sink::Sink;
+use futures::stream::{self, StreamExt};
+
+use super::portal::{Format, Portal};
+use super::query::{row_stream_error, ExtendedQueryHandler, SimpleQueryHandler};
+use super::results::{
+    DescribePortalResponse, DescribeStatementResponse, FieldInfo, QueryResponse, Response,
+};
+use super::stmt::StoredStatement;
+use super::store::{PortalStore, PortalStoreListener};
+use super::{ClientInfo, ClientPortalStore, METADATA_DATABASE, METADATA_OPTIONS, METADATA_USER};
+use crate::error::{PgWireError, PgWireResult};
+use crate::messages::data::DataRow;
+use crate::messages::PgWireBackendMessage;
+
+type CachePolicy = dyn Fn(&str) -> Option<Duration> + Send + Sync;
+
+type SessionKey = dyn Fn(&dyn ClientInfo) -> String + Send + Sync;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    session: String,
+    query: String,
+    parameter_formats: Vec<i16>,
+    parameters: Vec<Option<Bytes>>,
+    result_formats: Vec<i16>,
+}
+
+#[derive(Debug, Clone)]
+struct CachedResult {
+    schema: Arc<Vec<FieldInfo>>,
+    command_tag: String,
+    rows: Arc<Vec<DataRow>>,
+    expires_at: Instant,
+}
+
+impl CachedResult {
+    fn to_response<'a>(&self) -> Response<'a> {
+        let rows = self.rows.clone();
+        let row_stream = stream::iter((0..rows.len()).map(move |i| Ok(rows[i].clone())));
+        let mut response = QueryResponse::new(self.schema.clone(), row_stream);
+        response.set_command_tag(&self.command_tag);
+        Response::Query(response)
+    }
+}
+
+/// Storage of cached results, shared by handlers and the code that
+/// invalidates it.
+pub struct ResultCache {
+    entries: Mutex<HashMap<CacheKey, CachedResult>>,
+    policy: Box<CachePolicy>,
+    session_key: Box<SessionKey>,
+    max_entries: usize,
+    max_entry_bytes: usize,
+}
+
+impl Debug for ResultCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResultCache")
+            .field("entries", &self.len())
+            .field("max_entries", &self.max_entries)
+            .field("max_entry_bytes", &self.max_entry_bytes)
+            .finish()
+    }
+}
+
+impl ResultCache {
+    /// Create a cache with a policy that designates cacheable statements.
+    ///
+    /// The policy is called with query text and returns the ttl of its
+    /// results, or `None` if the statement must not be cached. Only
+    /// read-only statements should be designated.
+    pub fn new<F>(policy: F) -> ResultCache
+    where
+        F: Fn(&str) -> Option<Duration> + Send + Sync + 'static,
+    {
+        ResultCache {
+            entries: Mutex::new(HashMap::new()),
+            policy: Box::new(policy),
+            session_key: Box::new(default_session_key),
+            max_entries: 1024,
+            max_entry_bytes: 1024 * 1024,
+        }
+    }
+
+    /// Key of sessions that may share results, for example user and role
+    /// of the session. Results are only served to sessions of the same key.
+    pub fn with_session_key<F>(mut self, session_key: F) -> ResultCache
+    where
+        F: Fn(&dyn ClientInfo) -> String + Send + Sync + 'static,
+    {
+        self.session_key = Box::new(session_key);
+        self
+    }
+
+    /// Maximum number of cached results, default to 1024.
+    pub fn with_max_entries(mut self, max_entries: usize) -> ResultCache {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Results larger than this are not cached, default to 1MiB.
+    pub fn with_max_entry_bytes(mut self, max_entry_bytes: usize) -> ResultCache {
+        self.max_entry_bytes = max_entry_bytes;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drop all cached results.
+    pub fn invalidate_all(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Drop cached results of the query, with any parameters.
+    pub fn invalidate(&self, query: &str) {
+        self.invalidate_if(|q| q == query);
+    }
+
+    /// Drop cached results of queries matching the predicate, for example
+    /// queries referring a table that was just written.
+    pub fn invalidate_if<F>(&self, predicate: F)
+    where
+        F: Fn(&str) -> bool,
+    {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|key, _| !predicate(&key.query));
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<CachedResult> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn put(&self, key: CacheKey, result: CachedResult) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let now = Instant::now();
+            entries.retain(|_, e| e.expires_at > now);
+            // still full, evict the one expiring first
+            if entries.len() >= self.max_entries {
+                let victim = entries
+                    .iter()
+                    .min_by_key(|(_, e)| e.expires_at)
+                    .map(|(k, _)| k.clone());
+                if let Some(victim) = victim {
+                    entries.remove(&victim);
+                }
+            }
+        }
+        if self.max_entries > 0 {
+            entries.insert(key, result);
+        }
+    }
+
+    /// Collect rows of the response into cache and return a response
+    /// replaying them. Results larger than `max_entry_bytes` are passed
+    /// through without caching.
+    async fn store<'a>(
+        &self,
+        key: CacheKey,
+        ttl: Duration,
+        response: QueryResponse<'a>,
+    ) -> PgWireResult<Response<'a>> {
+        let schema = response.row_schema();
+        let command_tag = response.command_tag().to_owned();
+        let mut row_stream = response.data_rows();
+
+        let mut rows = Vec::new();
+        let mut size = 0;
+        while let Some(row) = row_stream.next().await {
+            let row = row.map_err(row_stream_error)?;
+            size += row.data.len();
+            rows.push(row);
+            if size > self.max_entry_bytes {
+                let rows = stream::iter(rows.into_iter().map(Ok)).chain(row_stream);
+                let mut response = QueryResponse::new(schema, rows);
+                response.set_command_tag(&command_tag);
+                return Ok(Response::Query(response));
+            }
+        }
+
+        let cached = CachedResult {
+            schema,
+            command_tag,
+            rows: Arc::new(rows),
+            expires_at: Instant::now() + ttl,
+        };
+        self.put(key, cached.clone());
+        Ok(cached.to_response())
+    }
+}
+
+/// User, database and startup `options` of the session.
+fn default_session_key(client: &dyn ClientInfo) -> String {
+    let metadata = client.metadata();
+    [METADATA_USER, METADATA_DATABASE, METADATA_OPTIONS]
+        .iter()
+        .map(|key| metadata.get(*key).map(String::as_str).unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("\0")
+}
+
+fn format_codes(format: &Format) -> Vec<i16> {
+    match format {
+        Format::UnifiedText => vec![],
+        Format::UnifiedBinary => vec![1],
+        Format::Individual(codes) => codes.clone(),
+    }
+}
+
+/// A query handler that caches results of the wrapped handler.
+///
+/// Simple queries are cached by their query string. Extended queries are
+/// cached by statement text, parameters and formats, both within the key of
+/// the session, this requires the
+/// statement type to expose its text through `AsRef<str>`. Executions with
+/// a row limit are never cached.
+///
+/// The wrapper implements `do_*` methods by delegating to the wrapped
+/// handler, and uses default implementations of `on_*` methods.
+#[derive(Debug)]
+pub struct CachingQueryHandler<H> {
+    inner: Arc<H>,
+    cache: Arc<ResultCache>,
+}
+
+impl<H> CachingQueryHandler<H> {
+    pub fn new(inner: Arc<H>, cache: Arc<ResultCache>) -> CachingQueryHandler<H> {
+        CachingQueryHandler { inner, cache }
+    }
+
+    pub fn cache(&self) -> &Arc<ResultCache> {
+        &self.cache
+    }
+}
+
+#[async_trait]
+impl<H> SimpleQueryHandler for CachingQueryHandler<H>
+where
+    H: SimpleQueryHandler,
+{
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        query: &'a str,
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let Some(ttl) = (self.cache.policy)(query) else {
+            return self.inner.do_query(client, query).await;
+        };
+        let key = CacheKey {
+            session: (self.cache.session_key)(client),
+            query: query.to_owned(),
+            parameter_formats: vec![],
+            parameters: vec![],
+            result_formats: vec![],
+        };
+        if let Some(cached) = self.cache.get(&key) {
+            return Ok(vec![cached.to_response()]);
+        }
+
+        let mut responses = self.inner.do_query(client, query).await?;
+        // only cache queries that return a single result
+        if responses.len() != 1 {
+            return Ok(responses);
+        }
+        match responses.pop() {
+            Some(Response::Query(response)) => {
+                Ok(vec![self.cache.store(key, ttl, response).await?])
+            }
+            response => Ok(response.into_iter().collect()),
+        }
+    }
+}
+
+#[async_trait]
+impl<H> ExtendedQueryHandler for CachingQueryHandler<H>
+where
+    H: ExtendedQueryHandler,
+    H::Statement: AsRef<str>,
+{
+    type Statement = H::Statement;
+    type QueryParser = H::QueryParser;
+
+    fn query_parser(&self) -> Arc<Self::QueryParser> {
+        self.inner.query_parser()
+    }
+
+    fn portal_store_listener(&self) -> Option<Arc<dyn PortalStoreListener<Self::Statement>>> {
+        self.inner.portal_store_listener()
+    }
+
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        portal: &'a Portal<Self::Statement>,
+        max_rows: usize,
+    ) -> PgWireResult<Response<'a>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let query = portal.statement.statement.as_ref();
+        let ttl = match (self.cache.policy)(query) {
+            Some(ttl) if max_rows == 0 => ttl,
+            _ => return self.inner.do_query(client, portal, max_rows).await,
+        };
+        let key = CacheKey {
+            session: (self.cache.session_key)(client),
+            query: query.to_owned(),
+            parameter_formats: format_codes(&portal.parameter_format),
+            parameters: portal.parameters.clone(),
+            result_formats: format_codes(&portal.result_column_format),
+        };
+        if let Some(cached) = self.cache.get(&key) {
+            return Ok(cached.to_response());
+        }
+
+        match self.inner.do_query(client, portal, max_rows).await? {
+            Response::Query(response) => self.cache.store(key, ttl, response).await,
+            response => Ok(response),
+        }
+    }
+
+    async fn do_describe_statement<C>(
+        &self,
+        client: &mut C,
+        target: &StoredStatement<Self::Statement>,
+    ) -> PgWireResult<DescribeStatementResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.inner.do_describe_statement(client, target).await
+    }
+
+    async fn do_describe_portal<C>(
+        &self,
+        client: &mut C,
+        target: &Portal<Self::Statement>,
+    ) -> PgWireResult<DescribePortalResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.inner.do_describe_portal(client, target).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::api::mock::MockClient;
+    use crate::api::results::{DataRowEncoder, FieldFormat};
+    use crate::api::Type;
+
+    #[derive(Default)]
+    struct CountingHandler {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl SimpleQueryHandler for CountingHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            let calls = self.calls.fetch_add(1, Ordering::SeqCst) as i32;
+            let schema = Arc::new(vec![FieldInfo::new(
+                "calls".to_owned(),
+                None,
+                None,
+                Type::INT4,
+                FieldFormat::Text,
+            )]);
+            let mut encoder = DataRowEncoder::new(schema.clone());
+            encoder.encode_field(&calls)?;
+            let row = encoder.finish();
+            Ok(vec![Response::Query(QueryResponse::new(
+                schema,
+                stream::iter(vec![row]),
+            ))])
+        }
+    }
+
+    async fn query(handler: &CachingQueryHandler<CountingHandler>, sql: &str) -> DataRow {
+        query_as(handler, "alice", sql).await
+    }
+
+    async fn query_as(
+        handler: &CachingQueryHandler<CountingHandler>,
+        user: &str,
+        sql: &str,
+    ) -> DataRow {
+        let mut client = MockClient::new();
+        client
+            .metadata_mut()
+            .insert(METADATA_USER.to_owned(), user.to_owned());
+        let mut responses = handler.do_query(&mut client, sql).await.unwrap();
+        let Some(Response::Query(response)) = responses.pop() else {
+            panic!("expect query response");
+        };
+        let mut rows = response.data_rows();
+        let row = rows.next().await.unwrap().unwrap();
+        assert!(rows.next().await.is_none());
+        row
+    }
+
+    #[tokio::test]
+    async fn test_result_cache() {
+        let cache = Arc::new(ResultCache::new(|q| {
+            q.starts_with("SELECT").then_some(Duration::from_secs(60))
+        }));
+        let handler = CachingQueryHandler::new(Arc::new(CountingHandler::default()), cache.clone());
+
+        let first = query(&handler, "SELECT calls").await;
+        assert_eq!(first, query(&handler, "SELECT calls").await);
+        assert_eq!(1, handler.inner.calls.load(Ordering::SeqCst));
+        assert_eq!(1, cache.len());
+
+        // not designated by policy
+        query(&handler, "SHOW calls").await;
+        query(&handler, "SHOW calls").await;
+        assert_eq!(3, handler.inner.calls.load(Ordering::SeqCst));
+
+        cache.invalidate("SELECT calls");
+        assert_ne!(first, query(&handler, "SELECT calls").await);
+        assert_eq!(4, handler.inner.calls.load(Ordering::SeqCst));
+    }
+    #[tokio::test]
+    async fn test_result_cache_sessions() {
+        let cache = Arc::new(ResultCache::new(|_| Some(Duration::from_secs(60))));
+        let handler = CachingQueryHandler::new(Arc::new(CountingHandler::default()), cache.clone());
+
+        let alice = query_as(&handler, "alice", "SELECT calls").await;
+        let bob = query_as(&handler, "bob", "SELECT calls").await;
+        assert_ne!(alice, bob);
+        assert_eq!(2, cache.len());
+        assert_eq!(alice, query_as(&handler, "alice", "SELECT calls").await);
+        assert_eq!(bob, query_as(&handler, "bob", "SELECT calls").await);
+        assert_eq!(2, handler.inner.calls.load(Ordering::SeqCst));
+
+        // shared by all sessions
+        let cache = Arc::new(
+            ResultCache::new(|_| Some(Duration::from_secs(60))).with_session_key(|_| String::new()),
+        );
+        let handler = CachingQueryHandler::new(Arc::new(CountingHandler::default()), cache);
+        let alice = query_as(&handler, "alice", "SELECT calls").await;
+        assert_eq!(alice, query_as(&handler, "bob", "SELECT calls").await);
+    }
+}
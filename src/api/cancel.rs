@@ -0,0 +1,175 @@
+//! Query cancellation with `CancelRequest`.
+//!
+//! Each session is registered in a `CancelRegistry` with a key, which is sent
+//! to client in `BackendKeyData`. To cancel the running query, client opens
+//! a new connection and sends the key in `CancelRequest`, which triggers the
+//! cancellation token of that session.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use tokio_util::sync::CancellationToken;
+
+use crate::error::{ErrorInfo, SqlState};
+use crate::messages::startup::{BackendKeyData, CancelRequest};
+
+#[derive(Debug)]
+struct Session {
+    secret_key: i32,
+    token: Arc<Mutex<CancellationToken>>,
+}
+
+/// Sessions that can be cancelled, by their process id in `BackendKeyData`.
+#[derive(Debug, Default, Clone)]
+pub struct CancelRegistry {
+    sessions: Arc<Mutex<HashMap<i32, Session>>>,
+}
+
+impl CancelRegistry {
+    pub fn new() -> CancelRegistry {
+        CancelRegistry::default()
+    }
+
+    /// The process-wide registry used by `process_socket`.
+    pub fn global() -> &'static CancelRegistry {
+        static GLOBAL: OnceLock<CancelRegistry> = OnceLock::new();
+        GLOBAL.get_or_init(CancelRegistry::new)
+    }
+
+    /// Register a new session with random key. The session is removed from
+    /// registry when the returned handle is dropped.
+    pub fn register(&self) -> CancelHandle {
+        let token = Arc::new(Mutex::new(CancellationToken::new()));
+        let secret_key = rand::random::<i32>();
+        let mut sessions = self.sessions.lock().unwrap();
+        // process id is unique among sessions, and positive like a real one
+        let pid = loop {
+            let pid = rand::random::<i32>() & i32::MAX;
+            if pid != 0 && !sessions.contains_key(&pid) {
+                break pid;
+            }
+        };
+        sessions.insert(
+            pid,
+            Session {
+                secret_key,
+                token: token.clone(),
+            },
+        );
+
+        CancelHandle {
+            pid,
+            secret_key,
+            token,
+            registry: self.clone(),
+        }
+    }
+
+    /// Cancel the query of session with the key. Returns false if no session
+    /// matches, which is not reported to client like postgres.
+    pub fn cancel(&self, request: &CancelRequest) -> bool {
+        let sessions = self.sessions.lock().unwrap();
+        match sessions.get(&request.pid) {
+            Some(session) if session.secret_key == request.secret_key => {
+                session.token.lock().unwrap().cancel();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Number of registered sessions.
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Cancellation state of a session.
+#[derive(Debug)]
+pub struct CancelHandle {
+    pid: i32,
+    secret_key: i32,
+    token: Arc<Mutex<CancellationToken>>,
+    registry: CancelRegistry,
+}
+
+impl CancelHandle {
+    /// Key of this session, sent to client after authentication.
+    pub fn backend_key_data(&self) -> BackendKeyData {
+        BackendKeyData::new(self.pid, self.secret_key)
+    }
+
+    /// Token of the current query, cancelled when client sends
+    /// `CancelRequest`. Long running handlers can check it or wait for it to
+    /// stop early.
+    pub fn token(&self) -> CancellationToken {
+        self.token.lock().unwrap().clone()
+    }
+
+    /// Start a new query. A cancelled token is replaced, because a
+    /// `CancelRequest` only affects the query running at that moment.
+    pub fn reset(&self) -> CancellationToken {
+        let mut token = self.token.lock().unwrap();
+        if token.is_cancelled() {
+            *token = CancellationToken::new();
+        }
+        token.clone()
+    }
+}
+
+impl Drop for CancelHandle {
+    fn drop(&mut self) {
+        self.registry.sessions.lock().unwrap().remove(&self.pid);
+    }
+}
+
+/// Error returned for a cancelled query.
+pub fn query_canceled_error() -> ErrorInfo {
+    ErrorInfo::new(
+        "ERROR".to_owned(),
+        SqlState::QUERY_CANCELED.into(),
+        "canceling statement due to user request".to_owned(),
+    )
+}
+
+/// Error returned for a query exceeding `ClientInfo::statement_timeout`.
+pub fn statement_timeout_error() -> ErrorInfo {
+    ErrorInfo::new(
+        "ERROR".to_owned(),
+        SqlState::QUERY_CANCELED.into(),
+        "canceling statement due to statement timeout".to_owned(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_registry() {
+        let registry = CancelRegistry::new();
+        let handle = registry.register();
+        let other = registry.register();
+        let key = handle.backend_key_data();
+        let token = handle.reset();
+
+        assert!(!registry.cancel(&CancelRequest::new(key.pid, key.secret_key.wrapping_add(1))));
+        assert!(!token.is_cancelled());
+
+        assert!(registry.cancel(&CancelRequest::new(key.pid, key.secret_key)));
+        assert!(token.is_cancelled());
+        assert!(handle.token().is_cancelled());
+        assert!(!other.token().is_cancelled());
+
+        // the next query is not affected
+        assert!(!handle.reset().is_cancelled());
+
+        drop(handle);
+        assert_eq!(1, registry.len());
+        assert!(!registry.cancel(&CancelRequest::new(key.pid, key.secret_key)));
+    }
+}
@@ -0,0 +1,1205 @@
+//! Emulation of postgres catalogs for introspection queries of tools.
+//!
+//! Clients like psql, DBeaver, Metabase and SQLAlchemy query `version()`,
+//! `pg_catalog` and `information_schema` to discover the server and its
+//! tables. `PgCatalog` answers common ones from a description of the tables,
+//! and `CatalogQueryHandler` wraps query handlers to answer them before the
+//! wrapped handler is called, so handlers only deal with their own queries.
+//!
+//! Recognized queries are:
+//!
+//! * `SELECT` of `version()`, `current_schema()`, `current_database()`,
+//!   `current_user` and `session_user`, like the version probe of SQLAlchemy
+//! * `SELECT` of columns from a single catalog table, filtered by `=`
+//!   conditions joined with `AND` and ordered by columns, like
+//!   `SELECT oid, typname FROM pg_type WHERE typname = 'int4'`. Tables are
+//!   `pg_namespace`, `pg_database`, `pg_tables`, `pg_type`, and `schemata`,
+//!   `tables` and `columns` of `information_schema`. Conditions can compare
+//!   with parameters of extended query.
+//! * listing of relations by psql `\d` and `\dt`
+//!
+//! Other queries are passed to the wrapped handler. For extended query, the
+//! `QueryParser` of wrapped handler must accept catalog queries, like
+//! `NoopQueryParser` which keeps the query text.
+//!
+//! ```ignore
+//! let catalog = PgCatalog::new()
+//!     .with_server_version("16.2")
+//!     .with_table(CatalogTable::new(
+//!         "users".to_owned(),
+//!         vec![CatalogColumn::new("id".to_owned(), Type::INT4).with_nullable(false)],
+//!     ));
+//! let handler = Arc::new(CatalogQueryHandler::new(handler, Arc::new(catalog)));
+//! ```
+
+use std::cmp::Ordering;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::sink::Sink;
+use futures::stream;
+
+use super::portal::{Format, Portal};
+use super::query::{ExtendedQueryHandler, SimpleQueryHandler};
+use super::results::{
+    DataRowEncoder, DescribePortalResponse, DescribeStatementResponse, FieldInfo, QueryResponse,
+    Response,
+};
+use super::stmt::StoredStatement;
+use super::store::{PortalStore, PortalStoreListener};
+use super::{ClientInfo, ClientPortalStore, Type};
+use crate::error::{PgWireError, PgWireResult};
+use crate::messages::PgWireBackendMessage;
+
+const PG_CATALOG_OID: u32 = 11;
+const PUBLIC_OID: u32 = 2200;
+const INFORMATION_SCHEMA_OID: u32 = 13000;
+// first oid of user objects in postgres
+const FIRST_USER_OID: u32 = 16384;
+const DATABASE_OID: u32 = 5;
+
+/// Column of a table described by `PgCatalog`.
+#[non_exhaustive]
+#[derive(Debug, Clone, new)]
+pub struct CatalogColumn {
+    pub name: String,
+    pub data_type: Type,
+    #[new(value = "true")]
+    pub nullable: bool,
+}
+
+impl CatalogColumn {
+    pub fn with_nullable(mut self, nullable: bool) -> CatalogColumn {
+        self.nullable = nullable;
+        self
+    }
+}
+
+/// Table described by `PgCatalog`, in schema `public` by default.
+#[non_exhaustive]
+#[derive(Debug, Clone, new)]
+pub struct CatalogTable {
+    pub name: String,
+    pub columns: Vec<CatalogColumn>,
+    #[new(value = "\"public\".to_owned()")]
+    pub schema: String,
+}
+
+impl CatalogTable {
+    pub fn with_schema(mut self, schema: impl Into<String>) -> CatalogTable {
+        self.schema = schema.into();
+        self
+    }
+}
+
+/// Catalog answering introspection queries.
+#[derive(Debug, Clone)]
+pub struct PgCatalog {
+    server_version: String,
+    database: Option<String>,
+    tables: Vec<CatalogTable>,
+}
+
+impl Default for PgCatalog {
+    fn default() -> PgCatalog {
+        PgCatalog {
+            server_version: env!("CARGO_PKG_VERSION").to_owned(),
+            database: None,
+            tables: Vec::new(),
+        }
+    }
+}
+
+impl PgCatalog {
+    pub fn new() -> PgCatalog {
+        PgCatalog::default()
+    }
+
+    /// Version reported by `version()`, it should be the same as
+    /// `server_version` of `DefaultServerParameterProvider`.
+    pub fn with_server_version(mut self, server_version: impl Into<String>) -> PgCatalog {
+        self.server_version = server_version.into();
+        self
+    }
+
+    /// Name of the database, the database of session by default.
+    pub fn with_database(mut self, database: impl Into<String>) -> PgCatalog {
+        self.database = Some(database.into());
+        self
+    }
+
+    pub fn with_table(mut self, table: CatalogTable) -> PgCatalog {
+        self.tables.push(table);
+        self
+    }
+
+    /// Answer `query` if it's a recognized introspection query. Columns are
+    /// in `format`, and `$n` in conditions are compared with text of
+    /// `parameters`.
+    pub fn answer(
+        &self,
+        client: &dyn ClientInfo,
+        query: &str,
+        parameters: &[Option<Bytes>],
+        format: &Format,
+    ) -> Option<Response<'static>> {
+        let result = self.evaluate(client, query, parameters)?;
+        let fields = Arc::new(result.fields(format));
+        let rows = result
+            .rows
+            .into_iter()
+            .map(|row| {
+                let mut encoder = DataRowEncoder::new(fields.clone());
+                for value in row {
+                    match value {
+                        Value::Text(text) => encoder.encode_field(&text)?,
+                        Value::Oid(oid) => encoder.encode_field(&oid)?,
+                        Value::Int(int) => encoder.encode_field(&int)?,
+                    }
+                }
+                encoder.finish()
+            })
+            .collect::<Vec<_>>();
+        Some(Response::Query(QueryResponse::new(
+            fields,
+            stream::iter(rows),
+        )))
+    }
+
+    /// Columns of `query` if it's a recognized introspection query, for
+    /// `Describe`.
+    pub fn describe(
+        &self,
+        client: &dyn ClientInfo,
+        query: &str,
+        format: &Format,
+    ) -> Option<Vec<FieldInfo>> {
+        self.evaluate(client, query, &[])
+            .map(|result| result.fields(format))
+    }
+
+    fn evaluate(
+        &self,
+        client: &dyn ClientInfo,
+        query: &str,
+        parameters: &[Option<Bytes>],
+    ) -> Option<CatalogResult> {
+        if let Some(result) = self.list_relations(client, query) {
+            return Some(result);
+        }
+        let select = Select::parse(query)?;
+        match select.from {
+            None => self.select_functions(client, &select.items),
+            Some((ref schema, ref name)) => {
+                let table = self.table(client, schema.as_deref(), name)?;
+                table.select(&select, parameters)
+            }
+        }
+    }
+
+    fn database(&self, client: &dyn ClientInfo) -> String {
+        self.database
+            .as_deref()
+            .or(client.database())
+            .unwrap_or("postgres")
+            .to_owned()
+    }
+
+    fn select_functions(&self, client: &dyn ClientInfo, items: &[Item]) -> Option<CatalogResult> {
+        let user = client.user().unwrap_or("postgres").to_owned();
+        let mut columns = Vec::new();
+        let mut row = Vec::new();
+        for item in items {
+            let Item::Function { name, alias } = item else {
+                return None;
+            };
+            let (data_type, value) = match name.as_str() {
+                "version" => (
+                    Type::TEXT,
+                    format!("PostgreSQL {} on pgwire", self.server_version),
+                ),
+                "current_schema" => (Type::NAME, "public".to_owned()),
+                "current_database" => (Type::NAME, self.database(client)),
+                "current_user" | "session_user" => (Type::NAME, user.clone()),
+                _ => return None,
+            };
+            columns.push((alias.clone().unwrap_or_else(|| name.clone()), data_type));
+            row.push(Value::Text(value));
+        }
+        Some(CatalogResult {
+            columns,
+            rows: vec![row],
+        })
+    }
+
+    /// Schemas with their oids, system schemas first.
+    fn schemas(&self) -> Vec<(String, u32)> {
+        let mut schemas = vec![
+            ("pg_catalog".to_owned(), PG_CATALOG_OID),
+            ("information_schema".to_owned(), INFORMATION_SCHEMA_OID),
+            ("public".to_owned(), PUBLIC_OID),
+        ];
+        for table in &self.tables {
+            if !schemas.iter().any(|(name, _)| *name == table.schema) {
+                let oid = FIRST_USER_OID + schemas.len() as u32;
+                schemas.push((table.schema.clone(), oid));
+            }
+        }
+        schemas
+    }
+
+    fn types(&self) -> Vec<Type> {
+        let mut types = vec![
+            Type::BOOL,
+            Type::BYTEA,
+            Type::CHAR,
+            Type::NAME,
+            Type::INT8,
+            Type::INT2,
+            Type::INT4,
+            Type::TEXT,
+            Type::OID,
+            Type::JSON,
+            Type::FLOAT4,
+            Type::FLOAT8,
+            Type::BPCHAR,
+            Type::VARCHAR,
+            Type::DATE,
+            Type::TIME,
+            Type::TIMESTAMP,
+            Type::TIMESTAMPTZ,
+            Type::INTERVAL,
+            Type::NUMERIC,
+            Type::UUID,
+            Type::JSONB,
+        ];
+        for column in self.tables.iter().flat_map(|table| &table.columns) {
+            if !types.contains(&column.data_type) {
+                types.push(column.data_type.clone());
+            }
+        }
+        types
+    }
+
+    fn table(&self, client: &dyn ClientInfo, schema: Option<&str>, name: &str) -> Option<Table> {
+        let owner = client.user().unwrap_or("postgres").to_owned();
+        let text = |value: &str| Value::Text(value.to_owned());
+        let table = match (schema, name) {
+            (None | Some("pg_catalog"), "pg_namespace") => Table {
+                columns: vec![("oid", Type::OID), ("nspname", Type::NAME)],
+                rows: self
+                    .schemas()
+                    .into_iter()
+                    .map(|(name, oid)| vec![Value::Oid(oid), Value::Text(name)])
+                    .collect(),
+            },
+            (None | Some("pg_catalog"), "pg_database") => Table {
+                columns: vec![("oid", Type::OID), ("datname", Type::NAME)],
+                rows: vec![vec![
+                    Value::Oid(DATABASE_OID),
+                    Value::Text(self.database(client)),
+                ]],
+            },
+            (None | Some("pg_catalog"), "pg_tables") => Table {
+                columns: vec![
+                    ("schemaname", Type::NAME),
+                    ("tablename", Type::NAME),
+                    ("tableowner", Type::NAME),
+                ],
+                rows: self
+                    .tables
+                    .iter()
+                    .map(|table| vec![text(&table.schema), text(&table.name), text(&owner)])
+                    .collect(),
+            },
+            (None | Some("pg_catalog"), "pg_type") => Table {
+                columns: vec![
+                    ("oid", Type::OID),
+                    ("typname", Type::NAME),
+                    ("typnamespace", Type::OID),
+                ],
+                rows: self
+                    .types()
+                    .into_iter()
+                    .map(|ty| {
+                        vec![
+                            Value::Oid(ty.oid()),
+                            text(ty.name()),
+                            Value::Oid(PG_CATALOG_OID),
+                        ]
+                    })
+                    .collect(),
+            },
+            (Some("information_schema"), "schemata") => Table {
+                columns: vec![
+                    ("catalog_name", Type::VARCHAR),
+                    ("schema_name", Type::VARCHAR),
+                    ("schema_owner", Type::VARCHAR),
+                ],
+                rows: self
+                    .schemas()
+                    .into_iter()
+                    .map(|(name, _)| {
+                        vec![
+                            text(&self.database(client)),
+                            Value::Text(name),
+                            text(&owner),
+                        ]
+                    })
+                    .collect(),
+            },
+            (Some("information_schema"), "tables") => Table {
+                columns: vec![
+                    ("table_catalog", Type::VARCHAR),
+                    ("table_schema", Type::VARCHAR),
+                    ("table_name", Type::VARCHAR),
+                    ("table_type", Type::VARCHAR),
+                ],
+                rows: self
+                    .tables
+                    .iter()
+                    .map(|table| {
+                        vec![
+                            text(&self.database(client)),
+                            text(&table.schema),
+                            text(&table.name),
+                            text("BASE TABLE"),
+                        ]
+                    })
+                    .collect(),
+            },
+            (Some("information_schema"), "columns") => Table {
+                columns: vec![
+                    ("table_catalog", Type::VARCHAR),
+                    ("table_schema", Type::VARCHAR),
+                    ("table_name", Type::VARCHAR),
+                    ("column_name", Type::VARCHAR),
+                    ("ordinal_position", Type::INT4),
+                    ("is_nullable", Type::VARCHAR),
+                    ("data_type", Type::VARCHAR),
+                    ("udt_name", Type::VARCHAR),
+                ],
+                rows: self
+                    .tables
+                    .iter()
+                    .flat_map(|table| {
+                        table.columns.iter().enumerate().map(|(idx, column)| {
+                            vec![
+                                text(&self.database(client)),
+                                text(&table.schema),
+                                text(&table.name),
+                                text(&column.name),
+                                Value::Int(idx as i32 + 1),
+                                text(if column.nullable { "YES" } else { "NO" }),
+                                text(sql_type_name(&column.data_type)),
+                                text(column.data_type.name()),
+                            ]
+                        })
+                    })
+                    .collect(),
+            },
+            _ => return None,
+        };
+        Some(table)
+    }
+
+    /// Relations listed by psql `\d` and `\dt`, with an optional name
+    /// pattern.
+    fn list_relations(&self, client: &dyn ClientInfo, query: &str) -> Option<CatalogResult> {
+        let query = query
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase();
+        let is_listing = query.contains("from pg_catalog.pg_class c")
+            && query.contains("c.relkind in ('r'")
+            && ["\"schema\"", "\"name\"", "\"type\"", "\"owner\""]
+                .iter()
+                .all(|label| query.contains(&format!("as {label}")));
+        if !is_listing {
+            return None;
+        }
+        // a name is matched with regex `^(name)$`
+        let pattern = query
+            .split_once("c.relname operator(pg_catalog.~) '^(")
+            .and_then(|(_, rest)| rest.split_once(")$'"))
+            .map(|(name, _)| name.to_owned());
+
+        let owner = client.user().unwrap_or("postgres");
+        let mut tables = self
+            .tables
+            .iter()
+            .filter(|table| {
+                pattern
+                    .as_ref()
+                    .map_or(true, |p| table.name.to_lowercase() == *p)
+            })
+            .collect::<Vec<_>>();
+        tables.sort_by(|a, b| (&a.schema, &a.name).cmp(&(&b.schema, &b.name)));
+        Some(CatalogResult {
+            columns: vec![
+                ("Schema".to_owned(), Type::NAME),
+                ("Name".to_owned(), Type::NAME),
+                ("Type".to_owned(), Type::TEXT),
+                ("Owner".to_owned(), Type::NAME),
+            ],
+            rows: tables
+                .into_iter()
+                .map(|table| {
+                    vec![
+                        Value::Text(table.schema.clone()),
+                        Value::Text(table.name.clone()),
+                        Value::Text("table".to_owned()),
+                        Value::Text(owner.to_owned()),
+                    ]
+                })
+                .collect(),
+        })
+    }
+}
+
+// name of type in `information_schema`, which uses standard names
+fn sql_type_name(data_type: &Type) -> &str {
+    match *data_type {
+        Type::BOOL => "boolean",
+        Type::INT2 => "smallint",
+        Type::INT4 => "integer",
+        Type::INT8 => "bigint",
+        Type::FLOAT4 => "real",
+        Type::FLOAT8 => "double precision",
+        Type::BPCHAR => "character",
+        Type::VARCHAR => "character varying",
+        Type::TIMESTAMP => "timestamp without time zone",
+        Type::TIMESTAMPTZ => "timestamp with time zone",
+        Type::TIME => "time without time zone",
+        _ => data_type.name(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    Text(String),
+    Oid(u32),
+    Int(i32),
+}
+
+impl Value {
+    fn matches(&self, literal: &str) -> bool {
+        match self {
+            Value::Text(text) => text == literal,
+            Value::Oid(oid) => literal.parse() == Ok(*oid),
+            Value::Int(int) => literal.parse() == Ok(*int),
+        }
+    }
+
+    fn compare(&self, other: &Value) -> Ordering {
+        match (self, other) {
+            (Value::Text(a), Value::Text(b)) => a.cmp(b),
+            (Value::Oid(a), Value::Oid(b)) => a.cmp(b),
+            (Value::Int(a), Value::Int(b)) => a.cmp(b),
+            _ => Ordering::Equal,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CatalogResult {
+    columns: Vec<(String, Type)>,
+    rows: Vec<Vec<Value>>,
+}
+
+impl CatalogResult {
+    fn fields(&self, format: &Format) -> Vec<FieldInfo> {
+        self.columns
+            .iter()
+            .enumerate()
+            .map(|(idx, (name, data_type))| {
+                FieldInfo::new(
+                    name.clone(),
+                    None,
+                    None,
+                    data_type.clone(),
+                    format.format_for(idx),
+                )
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct Table {
+    columns: Vec<(&'static str, Type)>,
+    rows: Vec<Vec<Value>>,
+}
+
+impl Table {
+    fn column(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|(column, _)| *column == name)
+    }
+
+    fn select(self, select: &Select, parameters: &[Option<Bytes>]) -> Option<CatalogResult> {
+        // index of table column, and label of each result column
+        let mut projection = Vec::new();
+        for item in &select.items {
+            match item {
+                Item::Star => projection.extend(
+                    self.columns
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, (name, _))| (idx, name.to_string())),
+                ),
+                Item::Column { name, alias } => {
+                    let idx = self.column(name)?;
+                    projection.push((idx, alias.clone().unwrap_or_else(|| name.clone())));
+                }
+                Item::Function { .. } => return None,
+            }
+        }
+        let mut filters = Vec::new();
+        for (column, literal) in &select.filters {
+            let idx = self.column(column)?;
+            let value = match literal {
+                Literal::Value(value) => Some(value.clone()),
+                Literal::Parameter(n) => parameters
+                    .get(n.wrapping_sub(1))
+                    .cloned()
+                    .flatten()
+                    .map(|bytes| String::from_utf8_lossy(&bytes).into_owned()),
+            };
+            filters.push((idx, value));
+        }
+        // position in select list, or a column of table
+        let mut order = Vec::new();
+        for (key, desc) in &select.order {
+            let idx = match key {
+                OrderKey::Position(position) => projection.get(position.checked_sub(1)?)?.0,
+                OrderKey::Column(name) => self.column(name)?,
+            };
+            order.push((idx, *desc));
+        }
+
+        let mut rows = self
+            .rows
+            .into_iter()
+            .filter(|row| {
+                filters.iter().all(|(idx, value)| {
+                    value
+                        .as_deref()
+                        .is_some_and(|value| row[*idx].matches(value))
+                })
+            })
+            .collect::<Vec<_>>();
+        rows.sort_by(|a, b| {
+            order.iter().fold(Ordering::Equal, |ordering, (idx, desc)| {
+                ordering.then_with(|| {
+                    let ordering = a[*idx].compare(&b[*idx]);
+                    if *desc {
+                        ordering.reverse()
+                    } else {
+                        ordering
+                    }
+                })
+            })
+        });
+        Some(CatalogResult {
+            columns: projection
+                .iter()
+                .map(|(idx, label)| (label.clone(), self.columns[*idx].1.clone()))
+                .collect(),
+            rows: rows
+                .into_iter()
+                .map(|row| {
+                    projection
+                        .iter()
+                        .map(|(idx, _)| row[*idx].clone())
+                        .collect()
+                })
+                .collect(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    // unquoted identifier or keyword, in lower case
+    Word(String),
+    Quoted(String),
+    String(String),
+    Number(String),
+    Parameter(usize),
+    Symbol(char),
+}
+
+const KEYWORDS: [&str; 9] = [
+    "select", "from", "where", "and", "order", "by", "as", "asc", "desc",
+];
+
+/// Split `query` into tokens, `None` if it has anything beyond what the
+/// catalog understands, like operators other than `=`.
+fn tokenize(query: &str) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = query.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            ',' | '.' | '=' | '*' | '(' | ')' | ';' => tokens.push(Token::Symbol(c)),
+            '\'' | '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next()? {
+                        // quote is escaped by doubling it
+                        q if q == c && chars.peek() == Some(&c) => {
+                            chars.next();
+                            text.push(c);
+                        }
+                        q if q == c => break,
+                        other => text.push(other),
+                    }
+                }
+                tokens.push(if c == '\'' {
+                    Token::String(text)
+                } else {
+                    Token::Quoted(text)
+                });
+            }
+            '$' => {
+                let mut digits = String::new();
+                while let Some(d) = chars.next_if(char::is_ascii_digit) {
+                    digits.push(d);
+                }
+                tokens.push(Token::Parameter(digits.parse().ok()?));
+            }
+            c if c.is_ascii_digit() => {
+                let mut digits = c.to_string();
+                while let Some(d) = chars.next_if(char::is_ascii_digit) {
+                    digits.push(d);
+                }
+                tokens.push(Token::Number(digits));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut word = c.to_string();
+                while let Some(w) = chars.next_if(|w| w.is_alphanumeric() || *w == '_') {
+                    word.push(w);
+                }
+                tokens.push(Token::Word(word.to_lowercase()));
+            }
+            _ => return None,
+        }
+    }
+    Some(tokens)
+}
+
+#[derive(Debug, PartialEq)]
+enum Item {
+    Star,
+    Column { name: String, alias: Option<String> },
+    Function { name: String, alias: Option<String> },
+}
+
+#[derive(Debug, PartialEq)]
+enum Literal {
+    Value(String),
+    Parameter(usize),
+}
+
+#[derive(Debug, PartialEq)]
+enum OrderKey {
+    Position(usize),
+    Column(String),
+}
+
+/// `SELECT items [FROM table [WHERE column = literal [AND ...]] [ORDER BY key
+/// [ASC | DESC], ...]]`
+#[derive(Debug, PartialEq)]
+struct Select {
+    items: Vec<Item>,
+    from: Option<(Option<String>, String)>,
+    filters: Vec<(String, Literal)>,
+    order: Vec<(OrderKey, bool)>,
+}
+
+impl Select {
+    fn parse(query: &str) -> Option<Select> {
+        let mut parser = Parser {
+            tokens: tokenize(query)?,
+            pos: 0,
+        };
+        parser.select()
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Word(word)) if word == keyword);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn symbol(&mut self, symbol: char) -> bool {
+        let found = self.peek() == Some(&Token::Symbol(symbol));
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn ident(&mut self) -> Option<String> {
+        match self.peek()? {
+            Token::Word(word) if !KEYWORDS.contains(&word.as_str()) => {}
+            Token::Quoted(_) => {}
+            _ => return None,
+        }
+        match self.next()? {
+            Token::Word(word) | Token::Quoted(word) => Some(word),
+            _ => None,
+        }
+    }
+
+    fn alias(&mut self) -> Option<Option<String>> {
+        if self.keyword("as") {
+            return self.ident().map(Some);
+        }
+        Some(self.ident())
+    }
+
+    /// Column name, without its table qualifier.
+    fn column(&mut self) -> Option<String> {
+        let name = self.ident()?;
+        if self.symbol('.') {
+            return self.ident();
+        }
+        Some(name)
+    }
+
+    fn item(&mut self) -> Option<Item> {
+        if self.symbol('*') {
+            return Some(Item::Star);
+        }
+        let mut name = self.ident()?;
+        if self.symbol('.') {
+            if self.symbol('*') {
+                return Some(Item::Star);
+            }
+            // only functions of `pg_catalog` are qualified
+            let qualifier = std::mem::replace(&mut name, self.ident()?);
+            if self.peek() != Some(&Token::Symbol('(')) {
+                return Some(Item::Column {
+                    name,
+                    alias: self.alias()?,
+                });
+            }
+            if qualifier != "pg_catalog" {
+                return None;
+            }
+        }
+        if self.symbol('(') {
+            if !self.symbol(')') {
+                return None;
+            }
+        } else if !matches!(
+            name.as_str(),
+            "current_schema" | "current_user" | "session_user"
+        ) {
+            return Some(Item::Column {
+                name,
+                alias: self.alias()?,
+            });
+        }
+        Some(Item::Function {
+            name,
+            alias: self.alias()?,
+        })
+    }
+
+    fn select(&mut self) -> Option<Select> {
+        if !self.keyword("select") {
+            return None;
+        }
+        let mut select = Select {
+            items: vec![self.item()?],
+            from: None,
+            filters: Vec::new(),
+            order: Vec::new(),
+        };
+        while self.symbol(',') {
+            select.items.push(self.item()?);
+        }
+        if self.keyword("from") {
+            let name = self.ident()?;
+            select.from = Some(if self.symbol('.') {
+                (Some(name), self.ident()?)
+            } else {
+                (None, name)
+            });
+            // alias of table
+            self.alias()?;
+            if self.keyword("where") {
+                loop {
+                    let column = self.column()?;
+                    if !self.symbol('=') {
+                        return None;
+                    }
+                    let literal = match self.next()? {
+                        Token::String(value) | Token::Number(value) => Literal::Value(value),
+                        Token::Parameter(n) => Literal::Parameter(n),
+                        _ => return None,
+                    };
+                    select.filters.push((column, literal));
+                    if !self.keyword("and") {
+                        break;
+                    }
+                }
+            }
+            if self.keyword("order") {
+                if !self.keyword("by") {
+                    return None;
+                }
+                loop {
+                    let key = match self.peek()? {
+                        Token::Number(n) => {
+                            let position = n.parse().ok()?;
+                            self.pos += 1;
+                            OrderKey::Position(position)
+                        }
+                        _ => OrderKey::Column(self.column()?),
+                    };
+                    let desc = self.keyword("desc");
+                    if !desc {
+                        self.keyword("asc");
+                    }
+                    select.order.push((key, desc));
+                    if !self.symbol(',') {
+                        break;
+                    }
+                }
+            }
+        }
+        self.symbol(';');
+        (self.pos == self.tokens.len()).then_some(select)
+    }
+}
+
+/// Query handler answering introspection queries with `PgCatalog`, and
+/// passing other queries to the wrapped handler.
+#[derive(Debug)]
+pub struct CatalogQueryHandler<H> {
+    inner: Arc<H>,
+    catalog: Arc<PgCatalog>,
+}
+
+impl<H> CatalogQueryHandler<H> {
+    pub fn new(inner: Arc<H>, catalog: Arc<PgCatalog>) -> CatalogQueryHandler<H> {
+        CatalogQueryHandler { inner, catalog }
+    }
+
+    pub fn catalog(&self) -> &Arc<PgCatalog> {
+        &self.catalog
+    }
+}
+
+#[async_trait]
+impl<H> SimpleQueryHandler for CatalogQueryHandler<H>
+where
+    H: SimpleQueryHandler,
+{
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        query: &'a str,
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        match self
+            .catalog
+            .answer(client, query, &[], &Format::UnifiedText)
+        {
+            Some(response) => Ok(vec![response]),
+            None => self.inner.do_query(client, query).await,
+        }
+    }
+}
+
+#[async_trait]
+impl<H> ExtendedQueryHandler for CatalogQueryHandler<H>
+where
+    H: ExtendedQueryHandler,
+{
+    type Statement = H::Statement;
+    type QueryParser = H::QueryParser;
+
+    fn query_parser(&self) -> Arc<Self::QueryParser> {
+        self.inner.query_parser()
+    }
+
+    fn portal_store_listener(&self) -> Option<Arc<dyn PortalStoreListener<Self::Statement>>> {
+        self.inner.portal_store_listener()
+    }
+
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        portal: &'a Portal<Self::Statement>,
+        max_rows: usize,
+    ) -> PgWireResult<Response<'a>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        match self.catalog.answer(
+            client,
+            &portal.statement.query,
+            &portal.parameters,
+            &portal.result_column_format,
+        ) {
+            Some(response) => Ok(response),
+            None => self.inner.do_query(client, portal, max_rows).await,
+        }
+    }
+
+    async fn do_describe_statement<C>(
+        &self,
+        client: &mut C,
+        target: &StoredStatement<Self::Statement>,
+    ) -> PgWireResult<DescribeStatementResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        match self
+            .catalog
+            .describe(client, &target.query, &Format::UnifiedText)
+        {
+            Some(fields) => {
+                // parameters of catalog queries are compared as text
+                let parameters = target
+                    .parameter_types
+                    .iter()
+                    .map(|ty| {
+                        if *ty == Type::UNKNOWN {
+                            Type::TEXT
+                        } else {
+                            ty.clone()
+                        }
+                    })
+                    .collect();
+                Ok(DescribeStatementResponse::new(parameters, fields))
+            }
+            None => self.inner.do_describe_statement(client, target).await,
+        }
+    }
+
+    async fn do_describe_portal<C>(
+        &self,
+        client: &mut C,
+        target: &Portal<Self::Statement>,
+    ) -> PgWireResult<DescribePortalResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        match self.catalog.describe(
+            client,
+            &target.statement.query,
+            &target.result_column_format,
+        ) {
+            Some(fields) => Ok(DescribePortalResponse::new(fields)),
+            None => self.inner.do_describe_portal(client, target).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::api::mock::MockClient;
+    use crate::api::METADATA_USER;
+
+    fn catalog() -> PgCatalog {
+        PgCatalog::new()
+            .with_server_version("16.2")
+            .with_database("shop")
+            .with_table(CatalogTable::new(
+                "users".to_owned(),
+                vec![
+                    CatalogColumn::new("id".to_owned(), Type::INT4).with_nullable(false),
+                    CatalogColumn::new("name".to_owned(), Type::VARCHAR),
+                ],
+            ))
+            .with_table(
+                CatalogTable::new(
+                    "events".to_owned(),
+                    vec![CatalogColumn::new("at".to_owned(), Type::TIMESTAMPTZ)],
+                )
+                .with_schema("audit"),
+            )
+    }
+
+    async fn rows(catalog: &PgCatalog, query: &str, parameters: &[&str]) -> Vec<Vec<String>> {
+        let mut client = MockClient::new();
+        client
+            .metadata_mut()
+            .insert(METADATA_USER.to_owned(), "alice".to_owned());
+        let parameters = parameters
+            .iter()
+            .map(|p| Some(Bytes::copy_from_slice(p.as_bytes())))
+            .collect::<Vec<_>>();
+        let Some(Response::Query(response)) =
+            catalog.answer(&client, query, &parameters, &Format::UnifiedText)
+        else {
+            panic!("query not answered: {query}");
+        };
+        let mut rows = Vec::new();
+        let mut data_rows = response.data_rows();
+        while let Some(row) = data_rows.next().await {
+            let row = row.unwrap();
+            let fields = row.fields().unwrap();
+            rows.push(
+                fields
+                    .into_iter()
+                    .map(|field| String::from_utf8(field.unwrap().to_vec()).unwrap())
+                    .collect(),
+            );
+        }
+        rows
+    }
+
+    #[tokio::test]
+    async fn test_catalog_functions() {
+        let catalog = catalog();
+        assert_eq!(
+            vec![vec!["PostgreSQL 16.2 on pgwire".to_owned()]],
+            rows(&catalog, "select pg_catalog.version()", &[]).await
+        );
+        assert_eq!(
+            vec![vec!["public", "alice", "shop"]],
+            rows(
+                &catalog,
+                "SELECT current_schema(), session_user, current_database() AS db;",
+                &[]
+            )
+            .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_catalog_tables() {
+        let catalog = catalog();
+        assert_eq!(
+            vec![vec!["23", "int4"]],
+            rows(
+                &catalog,
+                "SELECT t.oid, t.typname FROM pg_catalog.pg_type t WHERE typname = 'int4'",
+                &[]
+            )
+            .await
+        );
+        assert_eq!(
+            vec![vec!["audit", "events"], vec!["public", "users"]],
+            rows(
+                &catalog,
+                "select table_schema, table_name from information_schema.tables order by 1, 2",
+                &[]
+            )
+            .await
+        );
+        assert_eq!(
+            vec![
+                vec!["name", "character varying", "YES"],
+                vec!["id", "integer", "NO"]
+            ],
+            rows(
+                &catalog,
+                "SELECT column_name, data_type, is_nullable FROM information_schema.columns \
+                 WHERE table_name = $1 ORDER BY ordinal_position DESC",
+                &["users"]
+            )
+            .await
+        );
+        assert_eq!(
+            vec![vec!["5", "shop"]],
+            rows(
+                &catalog,
+                "SELECT db.oid, db.* FROM pg_catalog.pg_database db WHERE datname = $1",
+                &["shop"]
+            )
+            .await
+            .into_iter()
+            .map(|row| row[1..].to_vec())
+            .collect::<Vec<_>>()
+        );
+
+        // joins and other operators are left to handlers
+        let client = MockClient::new();
+        for query in [
+            "SELECT t.oid FROM pg_type t JOIN pg_namespace n ON t.typnamespace = n.oid",
+            "SELECT typname FROM pg_type WHERE oid > 20",
+            "SELECT id FROM users",
+        ] {
+            assert!(catalog
+                .answer(&client, query, &[], &Format::UnifiedText)
+                .is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_psql_list_relations() {
+        let catalog = catalog();
+        let query = r#"SELECT n.nspname as "Schema",
+  c.relname as "Name",
+  CASE c.relkind WHEN 'r' THEN 'table' WHEN 'v' THEN 'view' END as "Type",
+  pg_catalog.pg_get_userbyid(c.relowner) as "Owner"
+FROM pg_catalog.pg_class c
+     LEFT JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+WHERE c.relkind IN ('r','p','')
+      AND n.nspname <> 'pg_catalog'
+      AND n.nspname !~ '^pg_toast'
+      AND n.nspname <> 'information_schema'
+  AND pg_catalog.pg_table_is_visible(c.oid)
+ORDER BY 1,2;"#;
+        assert_eq!(
+            vec![
+                vec!["audit", "events", "table", "alice"],
+                vec!["public", "users", "table", "alice"]
+            ],
+            rows(&catalog, query, &[]).await
+        );
+        let query = query.replace(
+            "WHERE c.relkind IN ('r','p','')",
+            "WHERE c.relkind IN ('r','p','') AND c.relname OPERATOR(pg_catalog.~) '^(users)$' COLLATE pg_catalog.default",
+        );
+        assert_eq!(
+            vec![vec!["public", "users", "table", "alice"]],
+            rows(&catalog, &query, &[]).await
+        );
+    }
+}
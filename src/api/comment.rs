@@ -0,0 +1,168 @@
+//! Metadata in sqlcommenter style comments.
+//!
+//! Application frameworks following the
+//! [sqlcommenter](https://google.github.io/sqlcommenter/spec/) spec append a
+//! comment like `/*action='list',traceparent='00-5bd6...-01'*/` to each query,
+//! which carries tracing and application context.
+//!
+//! The comment of the running query is available to handlers with
+//! `ClientInfo::sql_comment`, for both simple and extended query, and is
+//! recorded in statement spans of the `tracing` feature.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::sql::Lexer;
+
+/// Key-value pairs from a trailing sqlcommenter comment of a query.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SqlComment {
+    fields: BTreeMap<String, String>,
+}
+
+impl SqlComment {
+    /// Parse the last comment after the final statement of a query. Returns
+    /// `None` if there is no such comment or it's not in sqlcommenter format.
+    pub fn parse(query: &str) -> Option<SqlComment> {
+        let mut lexer = Lexer::new(query);
+        let mut end = 0;
+        while let Some(token) = lexer.next() {
+            if !token.is_symbol(';') {
+                end = lexer.offset();
+            }
+        }
+
+        let tail = &query[end..];
+        let start = tail.rfind("/*")? + 2;
+        let len = tail[start..].find("*/")?;
+        let comment = tail[start..start + len].trim();
+
+        parse_fields(comment).map(|fields| SqlComment { fields })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields.get(key).map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// The W3C trace context, if the comment carries one.
+    pub fn traceparent(&self) -> Option<&str> {
+        self.get("traceparent")
+    }
+}
+
+impl fmt::Display for SqlComment {
+    /// Fields in sqlcommenter format, without percent encoding.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, (key, value)) in self.iter().enumerate() {
+            if idx > 0 {
+                f.write_str(",")?;
+            }
+            write!(
+                f,
+                "{key}='{}'",
+                value.replace('\\', "\\\\").replace('\'', "\\'")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_fields(comment: &str) -> Option<BTreeMap<String, String>> {
+    let mut fields = BTreeMap::new();
+    let mut rest = comment;
+    while !rest.is_empty() {
+        let (key, after_key) = rest.split_once("='")?;
+
+        // value ends at the first unescaped quote
+        let mut value = String::new();
+        let mut chars = after_key.char_indices();
+        let mut value_end = None;
+        while let Some((idx, c)) = chars.next() {
+            match c {
+                '\\' => value.push(chars.next()?.1),
+                '\'' => {
+                    value_end = Some(idx);
+                    break;
+                }
+                c => value.push(c),
+            }
+        }
+        let value_end = value_end?;
+
+        fields.insert(percent_decode(key.trim())?, percent_decode(&value)?);
+
+        rest = after_key[value_end + 1..].trim_start();
+        if let Some(next) = rest.strip_prefix(',') {
+            rest = next.trim_start();
+        } else if !rest.is_empty() {
+            return None;
+        }
+    }
+
+    if fields.is_empty() {
+        None
+    } else {
+        Some(fields)
+    }
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'%' {
+            let hex = s.get(idx + 1..idx + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            idx += 3;
+        } else {
+            decoded.push(bytes[idx]);
+            idx += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sql_comment() {
+        let comment = SqlComment::parse(
+            "SELECT * FROM users /*action='%2Fparam*d',controller='index',framework='spring',\
+             traceparent='00-5bd66ef5095369c7b0d1f8f4bd33716a-c532cb4098ac3dd2-01'*/;",
+        )
+        .unwrap();
+        assert_eq!(4, comment.len());
+        assert_eq!(Some("/param*d"), comment.get("action"));
+        assert_eq!(
+            Some("00-5bd66ef5095369c7b0d1f8f4bd33716a-c532cb4098ac3dd2-01"),
+            comment.traceparent()
+        );
+
+        let comment = SqlComment::parse("SELECT 1 /*name='O\\'Brien'*/").unwrap();
+        assert_eq!(Some("O'Brien"), comment.get("name"));
+        assert_eq!("name='O\\'Brien'", comment.to_string());
+
+        assert!(SqlComment::parse("SELECT 1").is_none());
+        assert!(SqlComment::parse("SELECT 1 /* just a comment */").is_none());
+        assert!(SqlComment::parse("/*a='b'*/ SELECT 1").is_none());
+        assert!(SqlComment::parse("SELECT '/*a=''b''*/'").is_none());
+        assert!(SqlComment::parse("SELECT 1 /*/ x */").is_none());
+        assert!(SqlComment::parse("SELECT 1 /*/").is_none());
+        assert!(SqlComment::parse("SELECT 1 /**/").is_none());
+    }
+}
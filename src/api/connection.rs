@@ -0,0 +1,441 @@
+//! Stateful handlers made for each connection.
+//!
+//! Handlers passed to `process_socket` are usually shared by connections,
+//! and made by `MakeHandler` without knowing the connection. A
+//! `ConnectionHandler` instead makes its handler with
+//! `MakeConnectionHandler` once startup of the connection is finished, when
+//! peer address, tls info and startup parameters are all known. The handler
+//! is owned by the connection, so it can keep session state like a
+//! transaction or a storage handle of the user without locking by session.
+//!
+//! A `ConnectionHandler` is created for each connection, and passed as both
+//! the startup handler and the query handlers:
+//!
+//! ```ignore
+//! let handler = Arc::new(ConnectionHandler::new(authenticator.clone(), factory.clone()));
+//! process_socket(socket, None, handler.clone(), handler.clone(), handler).await
+//! ```
+
+use std::fmt::Debug;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::sink::Sink;
+
+use super::auth::StartupHandler;
+use super::copy::CopyHandler;
+use super::portal::Portal;
+use super::query::{ExtendedQueryHandler, SimpleQueryHandler};
+use super::results::{DescribePortalResponse, DescribeStatementResponse, Response};
+use super::stmt::StoredStatement;
+use super::store::PortalStore;
+use super::{ClientInfo, ClientPortalStore, MakeHandler, PgWireConnectionState};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, SqlState};
+use crate::messages::copy::{CopyData, CopyDone, CopyFail};
+use crate::messages::extendedquery::{
+    Bind, Close, Describe, Execute, Flush, Parse, Sync as PgSync,
+};
+use crate::messages::simplequery::Query;
+use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+
+/// Makes the handler of a connection, with information of the connection.
+///
+/// Every `MakeHandler` is a `MakeConnectionHandler` ignoring the connection.
+pub trait MakeConnectionHandler: Send + Sync {
+    type Handler: Send + Sync;
+
+    /// Called once for each connection, after startup is finished, so
+    /// `client` has the peer address, tls info and startup parameters of the
+    /// connection.
+    fn make_for_connection<C: ClientInfo>(&self, client: &C) -> Self::Handler;
+}
+
+impl<M> MakeConnectionHandler for M
+where
+    M: MakeHandler + Send + Sync,
+    M::Handler: Send + Sync,
+{
+    type Handler = M::Handler;
+
+    fn make_for_connection<C: ClientInfo>(&self, _client: &C) -> Self::Handler {
+        self.make()
+    }
+}
+
+/// Handlers of a connection, running startup with a shared
+/// `StartupHandler`, then queries with a handler made for the connection.
+pub struct ConnectionHandler<A, M: MakeConnectionHandler> {
+    startup_handler: Arc<A>,
+    make_handler: Arc<M>,
+    handler: OnceLock<M::Handler>,
+}
+
+impl<A, M: MakeConnectionHandler> ConnectionHandler<A, M> {
+    pub fn new(startup_handler: Arc<A>, make_handler: Arc<M>) -> ConnectionHandler<A, M> {
+        ConnectionHandler {
+            startup_handler,
+            make_handler,
+            handler: OnceLock::new(),
+        }
+    }
+
+    /// Handler of the connection, `None` before startup is finished.
+    pub fn handler(&self) -> Option<&M::Handler> {
+        self.handler.get()
+    }
+
+    fn started_handler(&self) -> PgWireResult<&M::Handler> {
+        self.handler.get().ok_or_else(|| {
+            PgWireError::UserError(Box::new(ErrorInfo::new(
+                "FATAL".to_owned(),
+                SqlState::PROTOCOL_VIOLATION.into(),
+                "connection is not started up".to_owned(),
+            )))
+        })
+    }
+}
+
+#[async_trait]
+impl<A, M> StartupHandler for ConnectionHandler<A, M>
+where
+    A: StartupHandler,
+    M: MakeConnectionHandler,
+{
+    async fn on_startup<C>(
+        &self,
+        client: &mut C,
+        message: PgWireFrontendMessage,
+    ) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.startup_handler.on_startup(client, message).await?;
+        if client.state() == PgWireConnectionState::ReadyForQuery && self.handler.get().is_none() {
+            let _ = self
+                .handler
+                .set(self.make_handler.make_for_connection(client));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<A, M> SimpleQueryHandler for ConnectionHandler<A, M>
+where
+    A: Send + Sync,
+    M: MakeConnectionHandler,
+    M::Handler: SimpleQueryHandler,
+{
+    async fn on_query<C>(&self, client: &mut C, query: Query) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.started_handler()?.on_query(client, query).await
+    }
+
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        query: &'a str,
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.started_handler()?.do_query(client, query).await
+    }
+
+    fn statement_timeout(&self, query: &str, timeout: Option<Duration>) -> Option<Duration> {
+        match self.handler.get() {
+            Some(handler) => SimpleQueryHandler::statement_timeout(handler, query, timeout),
+            None => timeout,
+        }
+    }
+}
+
+#[async_trait]
+impl<A, M> ExtendedQueryHandler for ConnectionHandler<A, M>
+where
+    A: Send + Sync,
+    M: MakeConnectionHandler,
+    M::Handler: ExtendedQueryHandler,
+{
+    type Statement = <M::Handler as ExtendedQueryHandler>::Statement;
+    type QueryParser = <M::Handler as ExtendedQueryHandler>::QueryParser;
+
+    /// Query parser of the handler.
+    ///
+    /// # Panics
+    ///
+    /// If called before startup is finished, the handler is not made yet.
+    fn query_parser(&self) -> Arc<Self::QueryParser> {
+        self.handler
+            .get()
+            .expect("query parser is used before startup")
+            .query_parser()
+    }
+
+    fn statement_timeout(
+        &self,
+        statement: &StoredStatement<Self::Statement>,
+        timeout: Option<Duration>,
+    ) -> Option<Duration> {
+        match self.handler.get() {
+            Some(handler) => ExtendedQueryHandler::statement_timeout(handler, statement, timeout),
+            None => timeout,
+        }
+    }
+
+    async fn on_parse<C>(&self, client: &mut C, message: Parse) -> PgWireResult<()>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.started_handler()?.on_parse(client, message).await
+    }
+
+    async fn on_bind<C>(&self, client: &mut C, message: Bind) -> PgWireResult<()>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.started_handler()?.on_bind(client, message).await
+    }
+
+    async fn on_execute<C>(&self, client: &mut C, message: Execute) -> PgWireResult<()>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.started_handler()?.on_execute(client, message).await
+    }
+
+    async fn on_describe<C>(&self, client: &mut C, message: Describe) -> PgWireResult<()>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.started_handler()?.on_describe(client, message).await
+    }
+
+    async fn on_flush<C>(&self, client: &mut C, message: Flush) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.started_handler()?.on_flush(client, message).await
+    }
+
+    async fn on_sync<C>(&self, client: &mut C, message: PgSync) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.started_handler()?.on_sync(client, message).await
+    }
+
+    async fn on_close<C>(&self, client: &mut C, message: Close) -> PgWireResult<()>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.started_handler()?.on_close(client, message).await
+    }
+
+    async fn do_describe_statement<C>(
+        &self,
+        client: &mut C,
+        target: &StoredStatement<Self::Statement>,
+    ) -> PgWireResult<DescribeStatementResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.started_handler()?
+            .do_describe_statement(client, target)
+            .await
+    }
+
+    async fn do_describe_portal<C>(
+        &self,
+        client: &mut C,
+        target: &Portal<Self::Statement>,
+    ) -> PgWireResult<DescribePortalResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.started_handler()?
+            .do_describe_portal(client, target)
+            .await
+    }
+
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        portal: &'a Portal<Self::Statement>,
+        max_rows: usize,
+    ) -> PgWireResult<Response<'a>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.started_handler()?
+            .do_query(client, portal, max_rows)
+            .await
+    }
+}
+
+#[async_trait]
+impl<A, M> CopyHandler for ConnectionHandler<A, M>
+where
+    A: Send + Sync,
+    M: MakeConnectionHandler,
+    M::Handler: CopyHandler,
+{
+    async fn on_copy_data<C>(&self, client: &mut C, copy_data: CopyData) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.started_handler()?
+            .on_copy_data(client, copy_data)
+            .await
+    }
+
+    async fn on_copy_done<C>(&self, client: &mut C, done: CopyDone) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.started_handler()?.on_copy_done(client, done).await
+    }
+
+    async fn on_copy_fail<C>(&self, client: &mut C, fail: CopyFail) -> PgWireError
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        match self.started_handler() {
+            Ok(handler) => handler.on_copy_fail(client, fail).await,
+            Err(e) => e,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::api::auth::noop::NoopStartupHandler;
+    use crate::api::mock::MockClient;
+    use crate::api::results::Tag;
+    use crate::messages::startup::Startup;
+
+    // a session that counts its queries
+    struct Session {
+        user: String,
+        queries: Mutex<usize>,
+    }
+
+    #[async_trait]
+    impl SimpleQueryHandler for Session {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            let mut queries = self.queries.lock().unwrap();
+            *queries += 1;
+            Ok(vec![Response::Execution(
+                Tag::new(&self.user).with_rows(*queries),
+            )])
+        }
+    }
+
+    #[derive(Default)]
+    struct MakeSession {
+        made: AtomicUsize,
+    }
+
+    impl MakeConnectionHandler for MakeSession {
+        type Handler = Session;
+
+        fn make_for_connection<C: ClientInfo>(&self, client: &C) -> Session {
+            self.made.fetch_add(1, Ordering::SeqCst);
+            Session {
+                user: client.user().unwrap_or_default().to_owned(),
+                queries: Mutex::new(0),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_handler() {
+        let make = Arc::new(MakeSession::default());
+        let handler = ConnectionHandler::new(Arc::new(NoopStartupHandler), make.clone());
+        let mut client = MockClient::new();
+
+        let query = || Query::new("SELECT 1".to_owned());
+        assert!(handler.on_query(&mut client, query()).await.is_err());
+
+        let mut startup = Startup::new();
+        startup
+            .parameters
+            .insert("user".to_owned(), "alice".to_owned());
+        handler
+            .on_startup(&mut client, PgWireFrontendMessage::Startup(startup))
+            .await
+            .unwrap();
+        assert_eq!(1, make.made.load(Ordering::SeqCst));
+        assert_eq!("alice", handler.handler().unwrap().user);
+
+        client.sent.clear();
+        handler.on_query(&mut client, query()).await.unwrap();
+        handler.on_query(&mut client, query()).await.unwrap();
+        let tags: Vec<_> = client
+            .sent
+            .iter()
+            .filter_map(|message| match message {
+                PgWireBackendMessage::CommandComplete(complete) => Some(complete.tag.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec!["alice 1", "alice 2"], tags);
+        assert_eq!(1, make.made.load(Ordering::SeqCst));
+    }
+}
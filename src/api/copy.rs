@@ -0,0 +1,1256 @@
+//! Handling data of `COPY FROM STDIN`, and helpers for copy data in text, csv
+//! and binary format.
+//!
+//! Query handlers start the copy by returning `Response::CopyIn`, client then
+//! sends a stream of `CopyData`, terminated by `CopyDone` or `CopyFail`.
+
+use std::fmt::Debug;
+
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::sink::Sink;
+use postgres_types::{FromSql, Type};
+
+use super::results::{CopyResponse, FieldFormat};
+use super::ClientInfo;
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, SqlState};
+use crate::messages::copy::{CopyData, CopyDone, CopyFail};
+use crate::messages::data::DataRow;
+use crate::messages::PgWireBackendMessage;
+use crate::sql::{tokenize, Token, TokenKind};
+use crate::types::FromSqlText;
+
+/// Handler for data sent by client in `COPY FROM STDIN`. By default the
+/// copy is rejected as not supported.
+#[async_trait]
+pub trait CopyHandler: Send + Sync {
+    /// Called for each `CopyData` message. Messages may not align with rows,
+    /// a row can be split across messages.
+    async fn on_copy_data<C>(&self, _client: &mut C, _copy_data: CopyData) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        Err(copy_not_supported())
+    }
+
+    /// Called when client has sent all data. The implementation should send
+    /// `CommandComplete` with tag like `COPY 10` on success. `ReadyForQuery`
+    /// is handled by the caller.
+    async fn on_copy_done<C>(&self, _client: &mut C, _done: CopyDone) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        Err(copy_not_supported())
+    }
+
+    /// Called when client aborts the copy, data received should be
+    /// discarded. The returned error is sent to client.
+    async fn on_copy_fail<C>(&self, _client: &mut C, fail: CopyFail) -> PgWireError
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        PgWireError::UserError(Box::new(ErrorInfo::new(
+            "ERROR".to_owned(),
+            SqlState::QUERY_CANCELED.into(),
+            format!("COPY from stdin failed: {}", fail.message),
+        )))
+    }
+}
+
+/// A `CopyHandler` for servers that don't support `COPY FROM STDIN`.
+#[derive(Debug, Clone, Default)]
+pub struct NoopCopyHandler;
+
+fn copy_not_supported() -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        SqlState::FEATURE_NOT_SUPPORTED.into(),
+        "COPY FROM STDIN is not supported".to_owned(),
+    )))
+}
+
+impl CopyHandler for NoopCopyHandler {}
+
+/// Signature at the start of binary copy data.
+pub const BINARY_COPY_SIGNATURE: &[u8] = b"PGCOPY\n\xff\r\n\0";
+
+/// Header of binary copy data, with no flags and header extension.
+pub fn binary_copy_header() -> Bytes {
+    let mut buf = BytesMut::with_capacity(BINARY_COPY_SIGNATURE.len() + 8);
+    buf.put_slice(BINARY_COPY_SIGNATURE);
+    // flags
+    buf.put_i32(0);
+    // length of header extension
+    buf.put_i32(0);
+    buf.freeze()
+}
+
+/// Trailer of binary copy data.
+pub fn binary_copy_trailer() -> Bytes {
+    Bytes::from_static(&[0xff, 0xff])
+}
+
+/// Encode a row in binary copy format. The row should be encoded by
+/// `DataRowEncoder` with binary format, whose layout is the same as a tuple
+/// in binary copy format.
+pub fn binary_copy_row(row: &DataRow) -> Bytes {
+    let mut buf = BytesMut::with_capacity(2 + row.data.len());
+    buf.put_i16(row.field_count);
+    buf.put_slice(&row.data);
+    buf.freeze()
+}
+
+fn bad_copy_format(message: &str) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        SqlState::BAD_COPY_FILE_FORMAT.into(),
+        message.to_owned(),
+    )))
+}
+
+/// A row of binary copy data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BinaryCopyRow {
+    fields: Vec<Option<Bytes>>,
+}
+
+impl BinaryCopyRow {
+    /// Number of fields in the row
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Raw binary value of the field, `None` for null.
+    pub fn raw(&self, index: usize) -> Option<&[u8]> {
+        self.fields.get(index).and_then(|f| f.as_deref())
+    }
+
+    /// Decode the field as given type.
+    pub fn get<'a, T>(&'a self, index: usize, data_type: &Type) -> PgWireResult<T>
+    where
+        T: FromSql<'a>,
+    {
+        let field = self
+            .fields
+            .get(index)
+            .ok_or(PgWireError::ParameterIndexOutOfBound(index))?;
+        T::from_sql_nullable(data_type, field.as_deref())
+            .map_err(PgWireError::FailedToParseParameter)
+    }
+}
+
+/// Decoder of binary copy data sent by client in `COPY FROM STDIN`.
+///
+/// Data of `CopyData` messages are appended with `extend`, which don't
+/// necessarily align with rows, then complete rows are read with `next_row`.
+#[derive(Debug, Default)]
+pub struct BinaryCopyDecoder {
+    buf: BytesMut,
+    header_read: bool,
+    finished: bool,
+}
+
+impl BinaryCopyDecoder {
+    pub fn new() -> BinaryCopyDecoder {
+        BinaryCopyDecoder::default()
+    }
+
+    /// Append data from a `CopyData` message.
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Returns true if trailer of the data has been read.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Check the decoder is finished when client sends `CopyDone`.
+    pub fn finish(&self) -> PgWireResult<()> {
+        if self.finished {
+            Ok(())
+        } else {
+            Err(bad_copy_format("unexpected EOF in COPY data"))
+        }
+    }
+
+    fn read_header(&mut self) -> PgWireResult<bool> {
+        let fixed_len = BINARY_COPY_SIGNATURE.len() + 8;
+        if self.buf.len() < fixed_len {
+            return Ok(false);
+        }
+        if &self.buf[..BINARY_COPY_SIGNATURE.len()] != BINARY_COPY_SIGNATURE {
+            return Err(bad_copy_format("COPY file signature not recognized"));
+        }
+        let mut fields = &self.buf[BINARY_COPY_SIGNATURE.len()..fixed_len];
+        let flags = fields.get_i32();
+        // bits 16-31 are critical flags, none are defined
+        if flags & !0xffff != 0 {
+            return Err(bad_copy_format(
+                "unrecognized critical flags in COPY file header",
+            ));
+        }
+        let extension_len = fields.get_i32();
+        if extension_len < 0 {
+            return Err(bad_copy_format("invalid COPY file header (missing length)"));
+        }
+        let header_len = fixed_len + extension_len as usize;
+        if self.buf.len() < header_len {
+            return Ok(false);
+        }
+        self.buf.advance(header_len);
+        self.header_read = true;
+        Ok(true)
+    }
+
+    /// Read next row, returns `None` if more data is required or all rows
+    /// are read.
+    pub fn next_row(&mut self) -> PgWireResult<Option<BinaryCopyRow>> {
+        if self.finished || (!self.header_read && !self.read_header()?) {
+            return Ok(None);
+        }
+        if self.buf.len() < 2 {
+            return Ok(None);
+        }
+
+        let field_count = (&self.buf[..2]).get_i16();
+        if field_count == -1 {
+            self.buf.advance(2);
+            self.finished = true;
+            return Ok(None);
+        }
+        if field_count < 0 {
+            return Err(bad_copy_format("invalid field count in COPY data"));
+        }
+
+        // find the end of row before consuming it
+        let mut offset = 2;
+        let mut ranges = Vec::with_capacity(field_count as usize);
+        for _ in 0..field_count {
+            if self.buf.len() < offset + 4 {
+                return Ok(None);
+            }
+            let len = (&self.buf[offset..offset + 4]).get_i32();
+            offset += 4;
+            if len == -1 {
+                ranges.push(None);
+            } else if len < 0 {
+                return Err(bad_copy_format("invalid field size in COPY data"));
+            } else {
+                let len = len as usize;
+                if self.buf.len() < offset + len {
+                    return Ok(None);
+                }
+                ranges.push(Some((offset, len)));
+                offset += len;
+            }
+        }
+
+        let row = self.buf.split_to(offset).freeze();
+        let fields = ranges
+            .into_iter()
+            .map(|r| r.map(|(start, len)| row.slice(start..start + len)))
+            .collect();
+        Ok(Some(BinaryCopyRow { fields }))
+    }
+}
+
+/// Format of copy data.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CopyFormat {
+    #[default]
+    Text,
+    Csv,
+    Binary,
+}
+
+/// Options of a `COPY` statement, deciding how data in text and csv format
+/// is parsed by `TextCopyDecoder` and written by `encode_row`.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CopyOptions {
+    pub format: CopyFormat,
+    /// Separator of columns, tab in text format and `,` in csv.
+    pub delimiter: u8,
+    /// Text of null values, `\N` in text format and unquoted empty string
+    /// in csv.
+    pub null: String,
+    /// The first line is names of columns.
+    pub header: bool,
+    /// Quote of csv values.
+    pub quote: u8,
+    /// Escape of quotes in quoted csv values, the quote itself by default.
+    pub escape: u8,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        CopyOptions::new(CopyFormat::Text)
+    }
+}
+
+impl CopyOptions {
+    /// Create options with defaults of the format.
+    pub fn new(format: CopyFormat) -> CopyOptions {
+        let (delimiter, null) = match format {
+            CopyFormat::Csv => (b',', ""),
+            _ => (b'\t', "\\N"),
+        };
+        CopyOptions {
+            format,
+            delimiter,
+            null: null.to_owned(),
+            header: false,
+            quote: b'"',
+            escape: b'"',
+        }
+    }
+
+    pub fn with_delimiter(mut self, delimiter: u8) -> CopyOptions {
+        self.delimiter = delimiter;
+        self
+    }
+
+    pub fn with_null(mut self, null: impl Into<String>) -> CopyOptions {
+        self.null = null.into();
+        self
+    }
+
+    pub fn with_header(mut self, header: bool) -> CopyOptions {
+        self.header = header;
+        self
+    }
+
+    pub fn with_quote(mut self, quote: u8) -> CopyOptions {
+        self.quote = quote;
+        self
+    }
+
+    pub fn with_escape(mut self, escape: u8) -> CopyOptions {
+        self.escape = escape;
+        self
+    }
+
+    /// Parse options of a `COPY` statement, in the syntax of postgres like
+    /// `COPY t FROM STDIN WITH (FORMAT csv, HEADER)`, or the legacy syntax
+    /// like `COPY t FROM STDIN CSV HEADER` sent by psql `\copy`.
+    ///
+    /// Options not affecting the data format, like `FORCE_QUOTE` or
+    /// `ENCODING`, are accepted and ignored.
+    pub fn parse(statement: &str) -> PgWireResult<CopyOptions> {
+        let tokens = tokenize(statement);
+        let invalid = || copy_syntax_error(&format!("invalid COPY statement: {statement}"));
+
+        // options are after the source or target of the copy, skipping the
+        // query in `COPY (SELECT ... FROM ...) TO STDOUT`
+        let mut depth = 0;
+        let mut pos = None;
+        for (idx, token) in tokens.iter().enumerate() {
+            if token.is_symbol('(') {
+                depth += 1;
+            } else if token.is_symbol(')') {
+                depth -= 1;
+            } else if depth == 0 && (token.is_word("from") || token.is_word("to")) {
+                pos = Some(idx + 1);
+                break;
+            }
+        }
+        let mut pos = pos.ok_or_else(invalid)?;
+        if tokens.get(pos).is_some_and(|t| t.is_word("program")) {
+            pos += 1;
+        }
+        // STDIN, STDOUT or file name
+        pos += 1;
+        if tokens.get(pos).is_some_and(|t| t.is_word("with")) {
+            pos += 1;
+        }
+        let rest = tokens.get(pos..).unwrap_or_default();
+
+        let options = if rest.first().is_some_and(|t| t.is_symbol('(')) {
+            parse_option_list(&rest[1..]).ok_or_else(invalid)?
+        } else {
+            parse_legacy_options(rest).ok_or_else(invalid)?
+        };
+        CopyOptions::from_options(options)
+    }
+
+    fn from_options(options: Vec<(String, Option<String>)>) -> PgWireResult<CopyOptions> {
+        let mut format = CopyFormat::Text;
+        for (name, value) in &options {
+            if name == "format" {
+                format = match value.as_deref().map(str::to_ascii_lowercase).as_deref() {
+                    Some("text") => CopyFormat::Text,
+                    Some("csv") => CopyFormat::Csv,
+                    Some("binary") => CopyFormat::Binary,
+                    _ => {
+                        return Err(invalid_copy_option(&format!(
+                            "COPY format \"{}\" not recognized",
+                            value.as_deref().unwrap_or_default()
+                        )))
+                    }
+                };
+            }
+        }
+
+        let mut copy_options = CopyOptions::new(format);
+        let mut escape = None;
+        for (name, value) in options {
+            let csv_only = || {
+                if format == CopyFormat::Csv {
+                    Ok(())
+                } else {
+                    Err(invalid_copy_option(&format!(
+                        "COPY {} requires CSV mode",
+                        name.to_ascii_uppercase()
+                    )))
+                }
+            };
+            match name.as_str() {
+                "format" => {}
+                "delimiter" => copy_options.delimiter = single_byte(&name, value)?,
+                "null" => {
+                    copy_options.null = value.ok_or_else(|| missing_value(&name))?;
+                }
+                "header" => {
+                    copy_options.header = match value.map(|v| v.to_ascii_lowercase()).as_deref() {
+                        None | Some("true" | "on" | "1" | "match") => true,
+                        Some("false" | "off" | "0") => false,
+                        Some(other) => {
+                            return Err(invalid_copy_option(&format!(
+                                "header requires a Boolean value or \"match\", got \"{other}\""
+                            )))
+                        }
+                    }
+                }
+                "quote" => {
+                    csv_only()?;
+                    copy_options.quote = single_byte(&name, value)?;
+                }
+                "escape" => {
+                    csv_only()?;
+                    escape = Some(single_byte(&name, value)?);
+                }
+                "force_quote" | "force_not_null" | "force_null" => csv_only()?,
+                "freeze" | "encoding" | "on_error" | "default" | "log_verbosity" => {}
+                _ => {
+                    return Err(copy_syntax_error(&format!(
+                        "option \"{name}\" not recognized"
+                    )))
+                }
+            }
+        }
+        copy_options.escape = escape.unwrap_or(copy_options.quote);
+        copy_options.validate()?;
+        Ok(copy_options)
+    }
+
+    fn validate(&self) -> PgWireResult<()> {
+        let invalid = |message: &str| Err(invalid_copy_option(message));
+        match self.format {
+            CopyFormat::Binary if self.header => invalid("cannot specify HEADER in BINARY mode"),
+            CopyFormat::Binary if self.delimiter != b'\t' => {
+                invalid("cannot specify DELIMITER in BINARY mode")
+            }
+            _ if matches!(self.delimiter, b'\r' | b'\n') || !self.delimiter.is_ascii() => {
+                invalid("COPY delimiter must be a single one-byte character")
+            }
+            _ if self.null.contains(['\r', '\n']) => {
+                invalid("COPY null representation cannot use newline or carriage return")
+            }
+            _ if self.null.as_bytes().contains(&self.delimiter) => {
+                invalid("COPY delimiter must not appear in the NULL specification")
+            }
+            CopyFormat::Text if self.delimiter == b'\\' => {
+                invalid("COPY delimiter cannot be \"\\\"")
+            }
+            CopyFormat::Csv if self.delimiter == self.quote => {
+                invalid("COPY delimiter and quote must be different")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// `CopyResponse` of data with these options.
+    pub fn copy_response(&self, columns: usize) -> CopyResponse {
+        let format = if self.format == CopyFormat::Binary {
+            FieldFormat::Binary
+        } else {
+            FieldFormat::Text
+        };
+        CopyResponse::new(format, columns)
+    }
+
+    /// Decoder of text or csv data sent by client in `COPY FROM STDIN`.
+    pub fn decoder(&self) -> TextCopyDecoder {
+        TextCopyDecoder::new(self.clone())
+    }
+
+    /// Encode a row in text or csv format, ending with a newline. Values
+    /// are quoted or escaped when they contain special characters.
+    pub fn encode_row<S: AsRef<str>>(&self, fields: &[Option<S>]) -> Bytes {
+        let mut buf = BytesMut::new();
+        for (idx, field) in fields.iter().enumerate() {
+            if idx > 0 {
+                buf.put_u8(self.delimiter);
+            }
+            match field {
+                None => buf.put_slice(self.null.as_bytes()),
+                Some(value) if self.format == CopyFormat::Csv => {
+                    self.put_csv_value(value.as_ref(), &mut buf)
+                }
+                Some(value) => self.put_text_value(value.as_ref(), &mut buf),
+            }
+        }
+        buf.put_u8(b'\n');
+        buf.freeze()
+    }
+
+    /// Encode a row encoded by `DataRowEncoder` with text format.
+    pub fn encode_data_row(&self, row: &DataRow) -> PgWireResult<Bytes> {
+        let fields = row
+            .fields()?
+            .into_iter()
+            .map(|field| field.map(std::str::from_utf8).transpose())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| PgWireError::ApiError(Box::new(e)))?;
+        Ok(self.encode_row(&fields))
+    }
+
+    fn put_text_value(&self, value: &str, buf: &mut BytesMut) {
+        for b in value.bytes() {
+            let escaped = match b {
+                b'\\' => b'\\',
+                b'\n' => b'n',
+                b'\r' => b'r',
+                b'\t' => b't',
+                0x08 => b'b',
+                0x0c => b'f',
+                0x0b => b'v',
+                _ if b == self.delimiter => b,
+                _ => {
+                    buf.put_u8(b);
+                    continue;
+                }
+            };
+            buf.put_u8(b'\\');
+            buf.put_u8(escaped);
+        }
+    }
+
+    fn put_csv_value(&self, value: &str, buf: &mut BytesMut) {
+        // values equal to null or the end marker are quoted to tell them apart
+        let needs_quote = value == self.null
+            || value == "\\."
+            || value
+                .bytes()
+                .any(|b| matches!(b, b'\r' | b'\n') || b == self.delimiter || b == self.quote);
+        if !needs_quote {
+            buf.put_slice(value.as_bytes());
+            return;
+        }
+        buf.put_u8(self.quote);
+        for b in value.bytes() {
+            if b == self.quote || b == self.escape {
+                buf.put_u8(self.escape);
+            }
+            buf.put_u8(b);
+        }
+        buf.put_u8(self.quote);
+    }
+}
+
+fn copy_syntax_error(message: &str) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        SqlState::SYNTAX_ERROR.into(),
+        message.to_owned(),
+    )))
+}
+
+fn invalid_copy_option(message: &str) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        SqlState::INVALID_PARAMETER_VALUE.into(),
+        message.to_owned(),
+    )))
+}
+
+fn missing_value(name: &str) -> PgWireError {
+    copy_syntax_error(&format!("option \"{name}\" requires a value"))
+}
+
+fn single_byte(name: &str, value: Option<String>) -> PgWireResult<u8> {
+    match value.ok_or_else(|| missing_value(name))?.as_bytes() {
+        [b] if b.is_ascii() => Ok(*b),
+        _ => Err(invalid_copy_option(&format!(
+            "COPY {} must be a single one-byte character",
+            name
+        ))),
+    }
+}
+
+/// Value of an option, a string literal, identifier or number.
+fn option_value(token: &Token) -> Option<String> {
+    let text = token.text;
+    match token.kind {
+        TokenKind::Word => Some(text.to_owned()),
+        TokenKind::QuotedIdent => Some(text[1..text.len() - 1].replace("\"\"", "\"")),
+        TokenKind::Literal => {
+            if let Some(escaped) = text
+                .strip_prefix(['e', 'E'])
+                .and_then(|t| t.strip_prefix('\''))
+            {
+                Some(unescape_string(escaped.strip_suffix('\'')?))
+            } else if let Some(quoted) = text.strip_prefix('\'') {
+                Some(quoted.strip_suffix('\'')?.replace("''", "'"))
+            } else if let Some(dollar_quoted) = text.strip_prefix('$') {
+                let tag_len = dollar_quoted.find('$')? + 2;
+                text.get(tag_len..text.len() - tag_len)
+                    .map(ToOwned::to_owned)
+            } else {
+                Some(text.to_owned())
+            }
+        }
+        TokenKind::Param | TokenKind::Symbol => None,
+    }
+}
+
+/// Content of `E'...'` string.
+fn unescape_string(escaped: &str) -> String {
+    let mut out = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('t') => out.push('\t'),
+                Some('n') => out.push('\n'),
+                Some('r') => out.push('\r'),
+                Some('b') => out.push('\x08'),
+                Some('f') => out.push('\x0c'),
+                Some(other) => out.push(other),
+                None => {}
+            },
+            '\'' if chars.as_str().starts_with('\'') => {
+                chars.next();
+                out.push('\'');
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Options like `(FORMAT csv, HEADER true, FORCE_QUOTE (a, b))`, after the
+/// opening parenthesis.
+fn parse_option_list(tokens: &[Token]) -> Option<Vec<(String, Option<String>)>> {
+    let mut options = Vec::new();
+    let mut iter = tokens.iter().peekable();
+    loop {
+        let name = iter.next()?;
+        if name.kind != TokenKind::Word {
+            return None;
+        }
+        let mut value = None;
+        loop {
+            let token = iter.next()?;
+            if token.is_symbol(',') || token.is_symbol(')') {
+                options.push((name.text.to_ascii_lowercase(), value));
+                if token.is_symbol(')') {
+                    return Some(options);
+                }
+                break;
+            } else if token.is_symbol('(') {
+                // column list
+                while !iter.next()?.is_symbol(')') {}
+            } else if value.is_none() {
+                value = Some(option_value(token).unwrap_or_else(|| token.text.to_owned()));
+            } else {
+                return None;
+            }
+        }
+    }
+}
+
+/// Options like `CSV HEADER DELIMITER ';'` before postgres 9.0.
+fn parse_legacy_options(tokens: &[Token]) -> Option<Vec<(String, Option<String>)>> {
+    let mut options = Vec::new();
+    let mut iter = tokens.iter().peekable();
+    while let Some(token) = iter.next() {
+        let name = token.text.to_ascii_lowercase();
+        match name.as_str() {
+            "binary" | "csv" => options.push(("format".to_owned(), Some(name))),
+            "header" => options.push((name, None)),
+            "delimiter" | "null" | "quote" | "escape" => {
+                if iter.peek()?.is_word("as") {
+                    iter.next();
+                }
+                options.push((name, Some(option_value(iter.next()?)?)));
+            }
+            // FORCE QUOTE, FORCE NOT NULL with `*` or a list of columns
+            "force" => {
+                if iter.peek()?.is_word("not") {
+                    iter.next();
+                }
+                let option = iter.next()?;
+                if !option.is_word("quote") && !option.is_word("null") {
+                    return None;
+                }
+                options.push((format!("force_{}", option.text.to_ascii_lowercase()), None));
+                loop {
+                    iter.next()?;
+                    if !iter.peek().is_some_and(|t| t.is_symbol(',')) {
+                        break;
+                    }
+                    iter.next();
+                }
+            }
+            "where" => break,
+            _ if token.is_symbol(';') => break,
+            _ => return None,
+        }
+    }
+    Some(options)
+}
+
+/// A row of text or csv copy data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextCopyRow {
+    fields: Vec<Option<String>>,
+}
+
+impl TextCopyRow {
+    /// Number of fields in the row
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Text of the field, `None` for null.
+    pub fn value(&self, index: usize) -> Option<&str> {
+        self.fields.get(index).and_then(|f| f.as_deref())
+    }
+
+    /// Parse the field as given type.
+    pub fn get<T>(&self, index: usize, data_type: &Type) -> PgWireResult<T>
+    where
+        T: FromSqlText,
+    {
+        let field = self
+            .fields
+            .get(index)
+            .ok_or(PgWireError::ParameterIndexOutOfBound(index))?;
+        match field {
+            Some(value) => T::from_sql_text(data_type, value.as_bytes()),
+            None => T::from_sql_null(data_type),
+        }
+        .map_err(PgWireError::FailedToParseParameter)
+    }
+
+    pub fn into_fields(self) -> Vec<Option<String>> {
+        self.fields
+    }
+}
+
+/// Decoder of text or csv copy data sent by client in `COPY FROM STDIN`.
+///
+/// Like `BinaryCopyDecoder`, data of `CopyData` messages are appended with
+/// `extend` and complete rows are read with `next_row`. The header line is
+/// skipped if `header` is set, and reading stops at the end marker `\.`.
+#[derive(Debug)]
+pub struct TextCopyDecoder {
+    options: CopyOptions,
+    buf: BytesMut,
+    header_read: bool,
+    finished: bool,
+}
+
+impl TextCopyDecoder {
+    pub fn new(options: CopyOptions) -> TextCopyDecoder {
+        TextCopyDecoder {
+            header_read: !options.header,
+            options,
+            buf: BytesMut::new(),
+            finished: false,
+        }
+    }
+
+    /// Append data from a `CopyData` message.
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Returns true if the end marker `\.` has been read.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Read next row, returns `None` if more data is required or all rows
+    /// are read.
+    pub fn next_row(&mut self) -> PgWireResult<Option<TextCopyRow>> {
+        while !self.finished {
+            let Some(end) = self.line_end()? else {
+                return Ok(None);
+            };
+            let line = self.buf.split_to(end + 1);
+            if let Some(row) = self.read_line(&line[..end])? {
+                return Ok(Some(row));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read the last row without a newline when client sends `CopyDone`.
+    pub fn finish(&mut self) -> PgWireResult<Option<TextCopyRow>> {
+        if self.finished || self.buf.is_empty() {
+            self.finished = true;
+            return Ok(None);
+        }
+        if self.options.format == CopyFormat::Csv && self.csv_line_end(true)?.is_none() {
+            return Err(bad_copy_format("unterminated CSV quoted field"));
+        }
+        let line = self.buf.split().freeze();
+        let row = self.read_line(&line)?;
+        self.finished = true;
+        Ok(row)
+    }
+
+    fn line_end(&self) -> PgWireResult<Option<usize>> {
+        if self.options.format == CopyFormat::Csv {
+            self.csv_line_end(false)
+        } else {
+            Ok(self.buf.iter().position(|b| *b == b'\n'))
+        }
+    }
+
+    // newline outside of quotes, or the end of data at eof if all quotes
+    // are closed
+    fn csv_line_end(&self, eof: bool) -> PgWireResult<Option<usize>> {
+        let CopyOptions { quote, escape, .. } = self.options;
+        let mut in_quote = false;
+        let mut idx = 0;
+        while idx < self.buf.len() {
+            let b = self.buf[idx];
+            if in_quote && b == escape && escape != quote {
+                idx += 2;
+                continue;
+            }
+            if b == quote {
+                in_quote = !in_quote;
+            } else if b == b'\n' && !in_quote {
+                return Ok(Some(idx));
+            }
+            idx += 1;
+        }
+        Ok((eof && !in_quote).then_some(self.buf.len()))
+    }
+
+    // parse a line without the newline, `None` for the header and end marker
+    fn read_line(&mut self, line: &[u8]) -> PgWireResult<Option<TextCopyRow>> {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line == b"\\." {
+            self.finished = true;
+            return Ok(None);
+        }
+        if !self.header_read {
+            self.header_read = true;
+            return Ok(None);
+        }
+        let fields = if self.options.format == CopyFormat::Csv {
+            self.parse_csv(line)
+        } else {
+            self.parse_text(line)
+        }?;
+        Ok(Some(TextCopyRow { fields }))
+    }
+
+    fn parse_text(&self, line: &[u8]) -> PgWireResult<Vec<Option<String>>> {
+        let mut fields = Vec::new();
+        let mut start = 0;
+        let mut idx = 0;
+        loop {
+            if idx < line.len() && line[idx] == b'\\' {
+                idx += 2;
+                continue;
+            }
+            if idx >= line.len() || line[idx] == self.options.delimiter {
+                let raw = &line[start..idx.min(line.len())];
+                fields.push(if raw == self.options.null.as_bytes() {
+                    None
+                } else {
+                    Some(to_string(unescape_text(raw))?)
+                });
+                if idx >= line.len() {
+                    return Ok(fields);
+                }
+                start = idx + 1;
+            }
+            idx += 1;
+        }
+    }
+
+    fn parse_csv(&self, line: &[u8]) -> PgWireResult<Vec<Option<String>>> {
+        let CopyOptions {
+            delimiter,
+            quote,
+            escape,
+            ..
+        } = self.options;
+        let mut fields = Vec::new();
+        let mut value = Vec::new();
+        let mut quoted = false;
+        let mut in_quote = false;
+        let mut idx = 0;
+        loop {
+            let b = line.get(idx).copied();
+            match b {
+                Some(b) if in_quote && b == escape && escape != quote => match line.get(idx + 1) {
+                    Some(&next) if next == quote || next == escape => {
+                        value.push(next);
+                        idx += 1;
+                    }
+                    _ => value.push(b),
+                },
+                Some(b) if in_quote && b == quote && line.get(idx + 1) == Some(&quote) => {
+                    // doubled quote
+                    value.push(b);
+                    idx += 1;
+                }
+                Some(b) if b == quote => {
+                    in_quote = !in_quote;
+                    quoted = true;
+                }
+                Some(b) if in_quote || b != delimiter => value.push(b),
+                _ => {
+                    fields.push(
+                        if !quoted && value.as_slice() == self.options.null.as_bytes() {
+                            None
+                        } else {
+                            Some(to_string(std::mem::take(&mut value))?)
+                        },
+                    );
+                    value.clear();
+                    quoted = false;
+                    if b.is_none() {
+                        return Ok(fields);
+                    }
+                }
+            }
+            idx += 1;
+        }
+    }
+}
+
+fn to_string(value: Vec<u8>) -> PgWireResult<String> {
+    String::from_utf8(value).map_err(|_| bad_copy_format("invalid byte sequence in COPY data"))
+}
+
+/// Resolve backslash escapes of text format, like `\t` or `\011`.
+fn unescape_text(raw: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(raw.len());
+    let mut idx = 0;
+    while idx < raw.len() {
+        let b = raw[idx];
+        idx += 1;
+        if b != b'\\' || idx == raw.len() {
+            out.push(b);
+            continue;
+        }
+        let c = raw[idx];
+        idx += 1;
+        match c {
+            b'b' => out.push(0x08),
+            b'f' => out.push(0x0c),
+            b'n' => out.push(b'\n'),
+            b'r' => out.push(b'\r'),
+            b't' => out.push(b'\t'),
+            b'v' => out.push(0x0b),
+            b'0'..=b'7' => {
+                let mut value = (c - b'0') as u32;
+                for _ in 0..2 {
+                    match raw.get(idx) {
+                        Some(d @ b'0'..=b'7') => {
+                            value = value * 8 + (d - b'0') as u32;
+                            idx += 1;
+                        }
+                        _ => break,
+                    }
+                }
+                out.push(value as u8);
+            }
+            b'x' if raw.get(idx).is_some_and(u8::is_ascii_hexdigit) => {
+                let mut value = 0u8;
+                for _ in 0..2 {
+                    match raw.get(idx).and_then(|d| (*d as char).to_digit(16)) {
+                        Some(d) => {
+                            value = value * 16 + d as u8;
+                            idx += 1;
+                        }
+                        None => break,
+                    }
+                }
+                out.push(value);
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::api::results::{DataRowEncoder, FieldFormat, FieldInfo};
+
+    #[test]
+    fn test_binary_copy_roundtrip() {
+        let schema = Arc::new(vec![
+            FieldInfo::new("id".into(), None, None, Type::INT4, FieldFormat::Binary),
+            FieldInfo::new(
+                "name".into(),
+                None,
+                None,
+                Type::VARCHAR,
+                FieldFormat::Binary,
+            ),
+        ]);
+        let mut data = BytesMut::new();
+        data.extend_from_slice(&binary_copy_header());
+        for (id, name) in [(1, Some("tom")), (2, None)] {
+            let mut encoder = DataRowEncoder::new(schema.clone());
+            encoder.encode_field(&id).unwrap();
+            encoder.encode_field(&name).unwrap();
+            data.extend_from_slice(&binary_copy_row(&encoder.finish().unwrap()));
+        }
+        data.extend_from_slice(&binary_copy_trailer());
+
+        // data is split at arbitrary positions
+        let mut decoder = BinaryCopyDecoder::new();
+        let mut rows = Vec::new();
+        for chunk in data.chunks(5) {
+            decoder.extend(chunk);
+            while let Some(row) = decoder.next_row().unwrap() {
+                rows.push(row);
+            }
+        }
+        assert!(decoder.finish().is_ok());
+
+        assert_eq!(2, rows.len());
+        assert_eq!(1, rows[0].get::<i32>(0, &Type::INT4).unwrap());
+        assert_eq!(
+            Some("tom"),
+            rows[0].get::<Option<&str>>(1, &Type::VARCHAR).unwrap()
+        );
+        assert_eq!(2, rows[1].get::<i32>(0, &Type::INT4).unwrap());
+        assert_eq!(None, rows[1].raw(1));
+    }
+
+    #[test]
+    fn test_binary_copy_bad_signature() {
+        let mut decoder = BinaryCopyDecoder::new();
+        decoder.extend(b"1\ttom\n2\tjerry\n3\tspike\n");
+        assert!(decoder.next_row().is_err());
+
+        let mut decoder = BinaryCopyDecoder::new();
+        decoder.extend(&binary_copy_header());
+        assert!(decoder.next_row().unwrap().is_none());
+        assert!(decoder.finish().is_err());
+    }
+
+    #[test]
+    fn test_parse_copy_options() {
+        let options = CopyOptions::parse("COPY t FROM STDIN").unwrap();
+        assert_eq!(CopyOptions::default(), options);
+
+        // psql `\copy t from 'f.csv' with csv header`
+        let options = CopyOptions::parse("COPY  t FROM STDIN with csv header").unwrap();
+        assert_eq!(CopyOptions::new(CopyFormat::Csv).with_header(true), options);
+
+        let options = CopyOptions::parse(
+            "copy (select a from t) to stdout with (format csv, header match, delimiter ';', \
+             null 'NULL', quote '''', force_quote (a, b))",
+        )
+        .unwrap();
+        assert_eq!(
+            CopyOptions::new(CopyFormat::Csv)
+                .with_header(true)
+                .with_delimiter(b';')
+                .with_null("NULL")
+                .with_quote(b'\'')
+                .with_escape(b'\''),
+            options
+        );
+
+        let options = CopyOptions::parse(
+            "COPY t (a, b) FROM STDIN DELIMITER AS '|' NULL AS '' CSV ESCAPE E'\\\\' \
+             FORCE NOT NULL a, b WHERE a > 1",
+        )
+        .unwrap();
+        assert_eq!(
+            CopyOptions::new(CopyFormat::Csv)
+                .with_delimiter(b'|')
+                .with_escape(b'\\'),
+            options
+        );
+
+        let options = CopyOptions::parse("COPY t TO STDOUT (FORMAT binary)").unwrap();
+        assert_eq!(CopyFormat::Binary, options.format);
+
+        for invalid in [
+            "SELECT 1",
+            "COPY t FROM STDIN (FORMAT json)",
+            "COPY t FROM STDIN (FORMAT binary, HEADER)",
+            "COPY t FROM STDIN (DELIMITER 'ab')",
+            "COPY t FROM STDIN (QUOTE '\"')",
+            "COPY t FROM STDIN (FORMAT csv, DELIMITER '\"')",
+            "COPY t FROM STDIN (COLOR 'red')",
+            "COPY t FROM STDIN CSV COLOR",
+        ] {
+            assert!(CopyOptions::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    fn decode(options: &CopyOptions, data: &[u8]) -> PgWireResult<Vec<Vec<Option<String>>>> {
+        // data is split at arbitrary positions
+        let mut decoder = options.decoder();
+        let mut rows = Vec::new();
+        for chunk in data.chunks(3) {
+            decoder.extend(chunk);
+            while let Some(row) = decoder.next_row()? {
+                rows.push(row.into_fields());
+            }
+        }
+        rows.extend(decoder.finish()?.map(TextCopyRow::into_fields));
+        Ok(rows)
+    }
+
+    #[test]
+    fn test_text_copy_decoder() {
+        let options = CopyOptions::default();
+        let rows = decode(
+            &options,
+            b"1\ttom\\N\n2\t\\N\n3\ta\\tb\\\\c\\101\\x41\n\\.\nignored\n",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                vec![Some("1".to_owned()), Some("tomN".to_owned())],
+                vec![Some("2".to_owned()), None],
+                vec![Some("3".to_owned()), Some("a\tb\\cAA".to_owned())],
+            ],
+            rows
+        );
+
+        // last line without newline, and header
+        let options = CopyOptions::default()
+            .with_delimiter(b',')
+            .with_header(true);
+        let rows = decode(&options, b"id,name\r\n1,tom\r\n2,").unwrap();
+        assert_eq!(
+            vec![
+                vec![Some("1".to_owned()), Some("tom".to_owned())],
+                vec![Some("2".to_owned()), Some(String::new())],
+            ],
+            rows
+        );
+
+        let mut decoder = options.decoder();
+        decoder.extend(b"id\n\xff\n");
+        assert!(decoder.next_row().is_err());
+    }
+
+    #[test]
+    fn test_csv_copy_decoder() {
+        let options = CopyOptions::new(CopyFormat::Csv).with_header(true);
+        let rows = decode(
+            &options,
+            b"id,name,note\n1,\"tom, \"\"cat\"\"\",\"line\nbreak\"\n2,,\"\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                vec![
+                    Some("1".to_owned()),
+                    Some("tom, \"cat\"".to_owned()),
+                    Some("line\nbreak".to_owned())
+                ],
+                vec![Some("2".to_owned()), None, Some(String::new())],
+            ],
+            rows
+        );
+
+        let options = CopyOptions::new(CopyFormat::Csv).with_escape(b'\\');
+        let rows = decode(&options, b"\"a\\\"b\\\\\",c\n\\.\n").unwrap();
+        assert_eq!(
+            vec![vec![Some("a\"b\\".to_owned()), Some("c".to_owned())]],
+            rows
+        );
+
+        assert!(decode(&options, b"1,\"open\n").is_err());
+    }
+
+    #[test]
+    fn test_encode_copy_row() {
+        let row = [Some("a,b"), None, Some(""), Some("x\ty\"z"), Some("\\.")];
+
+        let csv = CopyOptions::new(CopyFormat::Csv);
+        let encoded = csv.encode_row(&row);
+        assert_eq!(&b"\"a,b\",,\"\",\"x\ty\"\"z\",\"\\.\"\n"[..], &encoded[..]);
+        assert_eq!(
+            vec![row
+                .iter()
+                .map(|v| v.map(ToOwned::to_owned))
+                .collect::<Vec<_>>()],
+            decode(&csv, &encoded).unwrap()
+        );
+
+        let text = CopyOptions::default();
+        let encoded = text.encode_row(&row);
+        assert_eq!(&b"a,b\t\\N\t\tx\\ty\"z\t\\\\.\n"[..], &encoded[..]);
+        assert_eq!(
+            vec![row
+                .iter()
+                .map(|v| v.map(ToOwned::to_owned))
+                .collect::<Vec<_>>()],
+            decode(&text, &encoded).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_copy_out_from_rows() {
+        use futures::StreamExt;
+
+        use crate::api::results::CopyOutStream;
+
+        let schema = Arc::new(vec![
+            FieldInfo::new("id".into(), None, None, Type::INT4, FieldFormat::Text),
+            FieldInfo::new("name".into(), None, None, Type::TEXT, FieldFormat::Text),
+        ]);
+        let mut encoder = DataRowEncoder::new(schema.clone());
+        encoder.encode_field(&1).unwrap();
+        encoder.encode_field(&"tom, jr").unwrap();
+        let rows = futures::stream::iter(vec![encoder.finish()]);
+
+        let options = CopyOptions::new(CopyFormat::Csv).with_header(true);
+        let mut copy = CopyOutStream::from_rows(options, &schema, rows);
+        assert_eq!(FieldFormat::Text, copy.copy_response().format);
+        assert_eq!(Some(Bytes::from_static(b"id,name\n")), copy.header());
+        let (_, data) = copy.into_parts();
+        let data = data.collect::<Vec<_>>().await;
+        assert_eq!(&b"1,\"tom, jr\"\n"[..], &data[0].as_ref().unwrap()[..]);
+    }
+}
@@ -0,0 +1,877 @@
+//! Emulation of cursors in simple query.
+//!
+//! BI tools page through results with `DECLARE ... CURSOR`, `FETCH`, `MOVE`
+//! and `CLOSE`, even against backends without cursors. `CursorQueryHandler`
+//! wraps a query handler to handle them like postgres, and passes other
+//! statements to the wrapped handler:
+//!
+//! * `DECLARE name [NO SCROLL | SCROLL] CURSOR [WITH | WITHOUT HOLD] FOR
+//!   query` runs `query` with the wrapped handler, and keeps its rows in
+//!   the cursor. `BINARY` cursors are not supported.
+//! * `FETCH` and `MOVE` with any direction of postgres, like `FETCH 10 FROM
+//!   name`, `FETCH PRIOR name` or `MOVE ABSOLUTE 5 IN name`. Cursors
+//!   declared with `NO SCROLL` only move forward.
+//! * `CLOSE name` and `CLOSE ALL`.
+//!
+//! Like a portal suspended by `Execute` with a row limit, rows of a cursor
+//! are buffered in memory, within the result limits of session, and
+//! accounted in `ClientInfo::send_memory` until the cursor is closed. Cursors
+//! declared in a transaction without `WITH HOLD` are closed when the
+//! transaction ends, others are kept until they are closed.
+//!
+//! Cursors are kept in the handler, so a `CursorQueryHandler` is made for
+//! each connection, for example with `ConnectionHandler`. Extended query is
+//! passed to the wrapped handler as is.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::sink::Sink;
+use futures::stream::{self, StreamExt};
+
+use super::memory::{SendMemory, SendMemoryReservation};
+use super::portal::Portal;
+use super::query::{row_stream_error, split_statements, ExtendedQueryHandler, SimpleQueryHandler};
+use super::results::{
+    DescribePortalResponse, DescribeStatementResponse, FieldInfo, QueryResponse, Response, Tag,
+};
+use super::stmt::StoredStatement;
+use super::store::{PortalStore, PortalStoreListener};
+use super::{ClientInfo, ClientPortalStore};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, SqlState};
+use crate::messages::data::DataRow;
+use crate::messages::response::TransactionStatus;
+use crate::messages::PgWireBackendMessage;
+use crate::sql::{tokenize, Lexer, Token, TokenKind};
+
+/// Direction of `FETCH` and `MOVE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    /// rows after the current one, or before it for a negative count, and
+    /// the current row for zero
+    Count(i64),
+    /// the row at a position, counted from the end if negative
+    Absolute(i64),
+    /// the row at a position relative to the current one
+    Relative(i64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum CursorStatement<'a> {
+    Declare {
+        name: String,
+        binary: bool,
+        scroll: bool,
+        hold: bool,
+        query: &'a str,
+    },
+    Fetch(String, Direction),
+    Move(String, Direction),
+    Close(String),
+    CloseAll,
+}
+
+#[derive(Debug)]
+struct Cursor {
+    schema: Arc<Vec<FieldInfo>>,
+    rows: Vec<DataRow>,
+    // 0 is before the first row, and `rows.len() + 1` after the last
+    position: usize,
+    scroll: bool,
+    // closed when the transaction declaring it ends
+    in_transaction: bool,
+    // memory of `rows`, if the session accounts it
+    _reservation: Option<SendMemoryReservation>,
+}
+
+impl Cursor {
+    /// Move the cursor in `direction`, returning range of rows passed.
+    fn seek(&mut self, direction: Direction) -> PgWireResult<std::ops::Range<usize>> {
+        let len = self.rows.len() as i64;
+        let position = self.position as i64;
+        let clamp = |target: i64| target.clamp(0, len + 1);
+        let (target, rows) = match direction {
+            Direction::Count(count) if count > 0 => {
+                let target = clamp(position.saturating_add(count));
+                (target, position.min(len) as usize..target.min(len) as usize)
+            }
+            Direction::Count(count) if count < 0 => {
+                let target = clamp(position.saturating_add(count));
+                let end = (position - 1).min(len).max(0);
+                (target, target.max(1) as usize - 1..end as usize)
+            }
+            Direction::Count(_) => (position, self.row_range(position)),
+            Direction::Absolute(n) => {
+                let target = clamp(if n < 0 { len + 1 + n } else { n });
+                (target, self.row_range(target))
+            }
+            Direction::Relative(n) => {
+                let target = clamp(position.saturating_add(n));
+                (target, self.row_range(target))
+            }
+        };
+        if !self.scroll && target < position {
+            return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                "ERROR".to_owned(),
+                SqlState::OBJECT_NOT_IN_PREREQUISITE_STATE.into(),
+                "cursor can only scan forward".to_owned(),
+            ))));
+        }
+        self.position = target as usize;
+        Ok(rows)
+    }
+
+    // range of the row at `position`, empty if it's before or after rows
+    fn row_range(&self, position: i64) -> std::ops::Range<usize> {
+        if position >= 1 && position as usize <= self.rows.len() {
+            position as usize - 1..position as usize
+        } else {
+            0..0
+        }
+    }
+}
+
+// name of a cursor, case insensitive unless quoted
+fn cursor_name(token: &Token) -> Option<String> {
+    match token.kind {
+        TokenKind::Word => Some(token.text.to_lowercase()),
+        TokenKind::QuotedIdent => Some(token.text[1..token.text.len() - 1].replace("\"\"", "\"")),
+        _ => None,
+    }
+}
+
+fn parse_declare(statement: &str) -> Option<CursorStatement<'_>> {
+    let mut lexer = Lexer::new(statement);
+    lexer.next().filter(|t| t.is_word("declare"))?;
+    let name = cursor_name(&lexer.next()?)?;
+    let mut binary = false;
+    let mut scroll = true;
+    let mut hold = false;
+    let mut token = lexer.next()?;
+    while !token.is_word("cursor") {
+        if token.is_word("binary") {
+            binary = true;
+        } else if token.is_word("no") {
+            lexer.next().filter(|t| t.is_word("scroll"))?;
+            scroll = false;
+        } else if !["asensitive", "insensitive", "scroll"]
+            .iter()
+            .any(|word| token.is_word(word))
+        {
+            return None;
+        }
+        token = lexer.next()?;
+    }
+    token = lexer.next()?;
+    if token.is_word("with") || token.is_word("without") {
+        hold = token.is_word("with");
+        lexer.next().filter(|t| t.is_word("hold"))?;
+        token = lexer.next()?;
+    }
+    if !token.is_word("for") {
+        return None;
+    }
+    let query = statement[lexer.offset()..].trim();
+    (!query.is_empty()).then_some(CursorStatement::Declare {
+        name,
+        binary,
+        scroll,
+        hold,
+        query,
+    })
+}
+
+// a count like `5` or `-5`
+fn parse_count(tokens: &[Token]) -> Option<(i64, usize)> {
+    let (sign, rest, used) = match tokens.first()? {
+        t if t.is_symbol('-') => (-1, &tokens[1..], 1),
+        t if t.is_symbol('+') => (1, &tokens[1..], 1),
+        _ => (1, tokens, 0),
+    };
+    let token = rest.first().filter(|t| t.kind == TokenKind::Literal)?;
+    let count = token.text.parse::<i64>().ok()?;
+    Some((sign * count, used + 1))
+}
+
+fn parse_direction(tokens: &[Token]) -> Option<(Direction, usize)> {
+    let first = tokens.first()?;
+    let simple = [
+        ("next", Direction::Count(1)),
+        ("prior", Direction::Count(-1)),
+        ("first", Direction::Absolute(1)),
+        ("last", Direction::Absolute(-1)),
+        ("all", Direction::Count(i64::MAX)),
+    ];
+    if let Some((_, direction)) = simple.iter().find(|(word, _)| first.is_word(word)) {
+        return Some((*direction, 1));
+    }
+    if first.is_word("absolute") || first.is_word("relative") {
+        let (count, used) = parse_count(&tokens[1..])?;
+        let direction = if first.is_word("absolute") {
+            Direction::Absolute(count)
+        } else {
+            Direction::Relative(count)
+        };
+        return Some((direction, used + 1));
+    }
+    if first.is_word("forward") || first.is_word("backward") {
+        let sign = if first.is_word("forward") { 1 } else { -1 };
+        return match tokens.get(1) {
+            Some(t) if t.is_word("all") => Some((Direction::Count(sign * i64::MAX), 2)),
+            _ => match parse_count(&tokens[1..]) {
+                Some((count, used)) => Some((Direction::Count(sign * count), used + 1)),
+                None => Some((Direction::Count(sign), 1)),
+            },
+        };
+    }
+    parse_count(tokens).map(|(count, used)| (Direction::Count(count), used))
+}
+
+// `[direction] [FROM | IN] name` of `FETCH` and `MOVE`
+fn parse_fetch(tokens: &[Token]) -> Option<(String, Direction)> {
+    let (direction, mut pos) = match tokens.len() {
+        // a name only
+        1 => (Direction::Count(1), 0),
+        _ => parse_direction(tokens).unwrap_or((Direction::Count(1), 0)),
+    };
+    if tokens
+        .get(pos)
+        .is_some_and(|t| t.is_word("from") || t.is_word("in"))
+    {
+        pos += 1;
+    }
+    match &tokens[pos..] {
+        [name] => Some((cursor_name(name)?, direction)),
+        _ => None,
+    }
+}
+
+fn parse_statement(statement: &str) -> Option<CursorStatement<'_>> {
+    let statement = statement.trim().trim_end_matches(';').trim_end();
+    let tokens = tokenize(statement);
+    let command = tokens.first()?;
+    if command.is_word("declare") {
+        parse_declare(statement)
+    } else if command.is_word("fetch") {
+        let (name, direction) = parse_fetch(&tokens[1..])?;
+        Some(CursorStatement::Fetch(name, direction))
+    } else if command.is_word("move") {
+        let (name, direction) = parse_fetch(&tokens[1..])?;
+        Some(CursorStatement::Move(name, direction))
+    } else if command.is_word("close") {
+        match &tokens[1..] {
+            [all] if all.is_word("all") => Some(CursorStatement::CloseAll),
+            [name] => Some(CursorStatement::Close(cursor_name(name)?)),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+fn cursor_error(code: SqlState, message: String) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        code.into(),
+        message,
+    )))
+}
+
+fn no_cursor(name: &str) -> PgWireError {
+    cursor_error(
+        SqlState::INVALID_CURSOR_NAME,
+        format!("cursor \"{name}\" does not exist"),
+    )
+}
+
+/// Query handler emulating cursors of a session, and passing other
+/// statements to the wrapped handler.
+#[derive(Debug)]
+pub struct CursorQueryHandler<H> {
+    inner: Arc<H>,
+    cursors: Mutex<HashMap<String, Cursor>>,
+}
+
+impl<H> CursorQueryHandler<H> {
+    pub fn new(inner: Arc<H>) -> CursorQueryHandler<H> {
+        CursorQueryHandler {
+            inner,
+            cursors: Mutex::default(),
+        }
+    }
+
+    /// Names of open cursors.
+    pub fn cursor_names(&self) -> Vec<String> {
+        let mut names = self
+            .cursors
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+}
+
+impl<H: SimpleQueryHandler> CursorQueryHandler<H> {
+    async fn execute<'a, C>(
+        &self,
+        client: &mut C,
+        statement: CursorStatement<'a>,
+    ) -> PgWireResult<Response<'a>>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        match statement {
+            CursorStatement::Declare {
+                name,
+                binary,
+                scroll,
+                hold,
+                query,
+            } => {
+                if binary {
+                    return Err(cursor_error(
+                        SqlState::FEATURE_NOT_SUPPORTED,
+                        "binary cursors are not supported".to_owned(),
+                    ));
+                }
+                if self.cursors.lock().unwrap().contains_key(&name) {
+                    return Err(cursor_error(
+                        SqlState::DUPLICATE_CURSOR,
+                        format!("cursor \"{name}\" already exists"),
+                    ));
+                }
+                let in_transaction =
+                    !hold && client.transaction_status() != TransactionStatus::Idle;
+                let (schema, rows, reservation) = self.run_query(client, query).await?;
+                let cursor = Cursor {
+                    schema,
+                    rows,
+                    position: 0,
+                    scroll,
+                    in_transaction,
+                    _reservation: reservation,
+                };
+                self.cursors.lock().unwrap().insert(name, cursor);
+                Ok(Response::Execution(Tag::new("DECLARE CURSOR")))
+            }
+            CursorStatement::Fetch(name, direction) => {
+                let mut cursors = self.cursors.lock().unwrap();
+                let cursor = cursors.get_mut(&name).ok_or_else(|| no_cursor(&name))?;
+                let range = cursor.seek(direction)?;
+                let mut rows = cursor.rows[range].to_vec();
+                if matches!(direction, Direction::Count(count) if count < 0) {
+                    rows.reverse();
+                }
+                let mut response = QueryResponse::new(
+                    cursor.schema.clone(),
+                    stream::iter(rows.into_iter().map(Ok)),
+                );
+                response.set_command_tag("FETCH");
+                Ok(Response::Query(response))
+            }
+            CursorStatement::Move(name, direction) => {
+                let mut cursors = self.cursors.lock().unwrap();
+                let cursor = cursors.get_mut(&name).ok_or_else(|| no_cursor(&name))?;
+                let range = cursor.seek(direction)?;
+                Ok(Response::Execution(Tag::new("MOVE").with_rows(range.len())))
+            }
+            CursorStatement::Close(name) => {
+                self.cursors
+                    .lock()
+                    .unwrap()
+                    .remove(&name)
+                    .ok_or_else(|| no_cursor(&name))?;
+                Ok(Response::Execution(Tag::new("CLOSE CURSOR")))
+            }
+            CursorStatement::CloseAll => {
+                self.cursors.lock().unwrap().clear();
+                Ok(Response::Execution(Tag::new("CLOSE CURSOR")))
+            }
+        }
+    }
+
+    /// Run the query of a cursor with the wrapped handler, and collect its
+    /// rows.
+    async fn run_query<C>(
+        &self,
+        client: &mut C,
+        query: &str,
+    ) -> PgWireResult<(
+        Arc<Vec<FieldInfo>>,
+        Vec<DataRow>,
+        Option<SendMemoryReservation>,
+    )>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let mut responses = self.inner.do_query(client, query).await?;
+        let response = match (responses.pop(), responses.is_empty()) {
+            (Some(Response::Query(response)), true) => response,
+            (Some(Response::Error(e)), _) => return Err(PgWireError::UserError(e)),
+            _ => {
+                return Err(cursor_error(
+                    SqlState::INVALID_CURSOR_DEFINITION,
+                    "cursor query must be a single statement returning rows".to_owned(),
+                ))
+            }
+        };
+
+        let limits = response
+            .result_limits()
+            .cloned()
+            .unwrap_or_else(|| client.result_limits());
+        let schema = response.row_schema();
+        let mut data_rows = response.data_rows();
+        let mut reservation = client.send_memory().map(SendMemory::reservation);
+        let mut rows = Vec::new();
+        let mut bytes = 0;
+        while let Some(row) = data_rows.next().await {
+            let row = row.map_err(row_stream_error)?;
+            bytes += row.data.len();
+            if let Some(reservation) = &mut reservation {
+                reservation.grow(row.data.len())?;
+            }
+            rows.push(row);
+            limits.check(rows.len(), bytes)?;
+        }
+        Ok((schema, rows, reservation))
+    }
+}
+
+#[async_trait]
+impl<H> SimpleQueryHandler for CursorQueryHandler<H>
+where
+    H: SimpleQueryHandler,
+{
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        query: &'a str,
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        // cursors of an ended transaction
+        if client.transaction_status() == TransactionStatus::Idle {
+            self.cursors
+                .lock()
+                .unwrap()
+                .retain(|_, cursor| !cursor.in_transaction);
+        }
+
+        let statements = split_statements(query)
+            .into_iter()
+            .map(|statement| (statement, parse_statement(statement)))
+            .collect::<Vec<_>>();
+        if statements.iter().all(|(_, parsed)| parsed.is_none()) {
+            return self.inner.do_query(client, query).await;
+        }
+        // other statements of the query are run one by one with the wrapped
+        // handler, like `MultiStatementQueryHandler`
+        let mut responses = Vec::new();
+        for (statement, parsed) in statements {
+            let result = match parsed {
+                Some(parsed) => self.execute(client, parsed).await.map(|r| vec![r]),
+                None => self.inner.do_query(client, statement).await,
+            };
+            match result {
+                Ok(statement_responses) => {
+                    let failed = statement_responses
+                        .iter()
+                        .any(|response| matches!(response, Response::Error(_)));
+                    responses.extend(statement_responses);
+                    if failed {
+                        break;
+                    }
+                }
+                Err(e) if responses.is_empty() => return Err(e),
+                Err(e) => {
+                    responses.push(Response::Error(Box::new(e.to_error_info())));
+                    break;
+                }
+            }
+        }
+        Ok(responses)
+    }
+
+    fn statement_timeout(&self, query: &str, timeout: Option<Duration>) -> Option<Duration> {
+        self.inner.statement_timeout(query, timeout)
+    }
+}
+
+#[async_trait]
+impl<H> ExtendedQueryHandler for CursorQueryHandler<H>
+where
+    H: ExtendedQueryHandler,
+{
+    type Statement = H::Statement;
+    type QueryParser = H::QueryParser;
+
+    fn query_parser(&self) -> Arc<Self::QueryParser> {
+        self.inner.query_parser()
+    }
+
+    fn portal_store_listener(&self) -> Option<Arc<dyn PortalStoreListener<Self::Statement>>> {
+        self.inner.portal_store_listener()
+    }
+
+    fn statement_timeout(
+        &self,
+        statement: &StoredStatement<Self::Statement>,
+        timeout: Option<Duration>,
+    ) -> Option<Duration> {
+        ExtendedQueryHandler::statement_timeout(self.inner.as_ref(), statement, timeout)
+    }
+
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        portal: &'a Portal<Self::Statement>,
+        max_rows: usize,
+    ) -> PgWireResult<Response<'a>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.inner.do_query(client, portal, max_rows).await
+    }
+
+    async fn do_describe_statement<C>(
+        &self,
+        client: &mut C,
+        target: &StoredStatement<Self::Statement>,
+    ) -> PgWireResult<DescribeStatementResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.inner.do_describe_statement(client, target).await
+    }
+
+    async fn do_describe_portal<C>(
+        &self,
+        client: &mut C,
+        target: &Portal<Self::Statement>,
+    ) -> PgWireResult<DescribePortalResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.inner.do_describe_portal(client, target).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::mock::MockClient;
+    use crate::api::results::{DataRowEncoder, FieldFormat};
+    use crate::api::Type;
+
+    // `SELECT` returns numbers 1 to 5, other statements complete with `OK`
+    struct NumbersHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for NumbersHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            if !query.starts_with("SELECT") {
+                return Ok(vec![Response::Execution(Tag::new("OK"))]);
+            }
+            let fields = Arc::new(vec![FieldInfo::new(
+                "n".to_owned(),
+                None,
+                None,
+                Type::INT4,
+                FieldFormat::Text,
+            )]);
+            let mut encoder = DataRowEncoder::new(fields.clone());
+            let rows = (1..=5)
+                .map(|n| {
+                    encoder.encode_field(&n)?;
+                    encoder.take_row()
+                })
+                .collect::<Vec<_>>();
+            Ok(vec![Response::Query(QueryResponse::new(
+                fields,
+                stream::iter(rows),
+            ))])
+        }
+    }
+
+    async fn fetch(
+        handler: &CursorQueryHandler<NumbersHandler>,
+        client: &mut MockClient,
+        query: &str,
+    ) -> Vec<String> {
+        let mut responses = handler.do_query(client, query).await.unwrap();
+        let Some(Response::Query(response)) = responses.pop() else {
+            panic!("expect query response");
+        };
+        assert_eq!("FETCH", response.command_tag());
+        response
+            .data_rows()
+            .map(|row| {
+                let row = row.unwrap();
+                String::from_utf8(row.fields().unwrap()[0].unwrap().to_vec()).unwrap()
+            })
+            .collect()
+            .await
+    }
+
+    async fn execute(
+        handler: &CursorQueryHandler<NumbersHandler>,
+        client: &mut MockClient,
+        query: &str,
+    ) -> PgWireResult<Tag> {
+        match handler.do_query(client, query).await?.pop() {
+            Some(Response::Execution(tag)) => Ok(tag),
+            Some(Response::Error(e)) => Err(PgWireError::UserError(e)),
+            _ => panic!("expect execution response"),
+        }
+    }
+
+    fn error_code(result: PgWireResult<Tag>) -> String {
+        match result {
+            Err(PgWireError::UserError(e)) => e.code,
+            result => panic!("expect error, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_statement() {
+        assert_eq!(
+            Some(CursorStatement::Declare {
+                name: "my cursor".to_owned(),
+                binary: false,
+                scroll: false,
+                hold: true,
+                query: "SELECT * FROM t WHERE a = 'for'",
+            }),
+            parse_statement(
+                "declare \"my cursor\" no scroll cursor with hold for SELECT * FROM t WHERE a = 'for';"
+            )
+        );
+        let fetch = |direction| Some(CursorStatement::Fetch("c".to_owned(), direction));
+        assert_eq!(fetch(Direction::Count(1)), parse_statement("FETCH c"));
+        assert_eq!(fetch(Direction::Count(1)), parse_statement("FETCH FROM C"));
+        assert_eq!(
+            fetch(Direction::Count(10)),
+            parse_statement("FETCH 10 IN c")
+        );
+        assert_eq!(fetch(Direction::Count(-2)), parse_statement("FETCH -2 c"));
+        assert_eq!(
+            fetch(Direction::Count(-i64::MAX)),
+            parse_statement("FETCH BACKWARD ALL FROM c")
+        );
+        assert_eq!(
+            fetch(Direction::Count(3)),
+            parse_statement("fetch forward 3 from c")
+        );
+        assert_eq!(
+            fetch(Direction::Count(-1)),
+            parse_statement("FETCH PRIOR c")
+        );
+        assert_eq!(
+            fetch(Direction::Absolute(-1)),
+            parse_statement("FETCH LAST FROM c")
+        );
+        assert_eq!(
+            fetch(Direction::Relative(-3)),
+            parse_statement("FETCH RELATIVE -3 FROM c")
+        );
+        assert_eq!(
+            Some(CursorStatement::Move(
+                "c".to_owned(),
+                Direction::Absolute(5)
+            )),
+            parse_statement("MOVE ABSOLUTE 5 IN c")
+        );
+        assert_eq!(
+            Some(CursorStatement::Close("c".to_owned())),
+            parse_statement("CLOSE c")
+        );
+        assert_eq!(
+            Some(CursorStatement::CloseAll),
+            parse_statement("close all")
+        );
+
+        for statement in [
+            "SELECT 1",
+            "DECLARE c CURSOR",
+            "DECLARE c CURSOR FOR ",
+            "DECLARE c LOCAL CURSOR FOR SELECT 1",
+            "FETCH",
+            "FETCH 1 FROM",
+            "FETCH c d",
+            "CLOSE",
+        ] {
+            assert_eq!(None, parse_statement(statement), "{statement}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let handler = CursorQueryHandler::new(Arc::new(NumbersHandler));
+        let mut client = MockClient::new();
+        let memory = SendMemory::new(None);
+        client.info.send_memory = Some(memory.clone());
+
+        let tag = execute(&handler, &mut client, "DECLARE c CURSOR FOR SELECT n")
+            .await
+            .unwrap();
+        assert_eq!(Tag::new("DECLARE CURSOR"), tag);
+        // 5 rows of 5 bytes
+        assert_eq!(25, memory.used());
+        assert_eq!(
+            vec!["1", "2"],
+            fetch(&handler, &mut client, "FETCH 2 FROM c").await
+        );
+        assert_eq!(vec!["3"], fetch(&handler, &mut client, "FETCH c").await);
+        assert_eq!(
+            vec!["2"],
+            fetch(&handler, &mut client, "FETCH PRIOR c").await
+        );
+        assert_eq!(
+            vec!["2"],
+            fetch(&handler, &mut client, "FETCH RELATIVE 0 c").await
+        );
+        assert_eq!(
+            vec!["3", "4", "5"],
+            fetch(&handler, &mut client, "FETCH ALL c").await
+        );
+        assert!(fetch(&handler, &mut client, "FETCH NEXT c")
+            .await
+            .is_empty());
+        assert_eq!(
+            vec!["5", "4"],
+            fetch(&handler, &mut client, "FETCH BACKWARD 2 c").await
+        );
+        assert_eq!(
+            vec!["5"],
+            fetch(&handler, &mut client, "FETCH LAST c").await
+        );
+        assert!(fetch(&handler, &mut client, "FETCH ABSOLUTE 9 c")
+            .await
+            .is_empty());
+        assert_eq!(
+            Tag::new("MOVE").with_rows(5),
+            execute(&handler, &mut client, "MOVE BACKWARD ALL IN c")
+                .await
+                .unwrap()
+        );
+        assert_eq!(vec!["1"], fetch(&handler, &mut client, "FETCH c").await);
+
+        // postgres codes of cursor errors
+        assert_eq!(
+            "42P03",
+            error_code(execute(&handler, &mut client, "DECLARE c CURSOR FOR SELECT n").await)
+        );
+        assert_eq!(
+            "42P11",
+            error_code(execute(&handler, &mut client, "DECLARE d CURSOR FOR UPDATE t").await)
+        );
+        assert_eq!(
+            "0A000",
+            error_code(
+                execute(
+                    &handler,
+                    &mut client,
+                    "DECLARE d BINARY CURSOR FOR SELECT n"
+                )
+                .await
+            )
+        );
+        assert_eq!(
+            "34000",
+            error_code(execute(&handler, &mut client, "MOVE d").await)
+        );
+
+        execute(
+            &handler,
+            &mut client,
+            "DECLARE d NO SCROLL CURSOR FOR SELECT n",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            vec!["1", "2"],
+            fetch(&handler, &mut client, "FETCH 2 d").await
+        );
+        assert_eq!(
+            "55000",
+            error_code(execute(&handler, &mut client, "MOVE PRIOR d").await)
+        );
+        assert_eq!(vec!["c", "d"], handler.cursor_names());
+
+        assert_eq!(
+            Tag::new("CLOSE CURSOR"),
+            execute(&handler, &mut client, "CLOSE c").await.unwrap()
+        );
+        assert_eq!(
+            "34000",
+            error_code(execute(&handler, &mut client, "CLOSE c").await)
+        );
+        execute(&handler, &mut client, "CLOSE ALL").await.unwrap();
+        assert!(handler.cursor_names().is_empty());
+        assert_eq!(0, memory.used());
+    }
+
+    #[tokio::test]
+    async fn test_transaction_cursors() {
+        let handler = CursorQueryHandler::new(Arc::new(NumbersHandler));
+        let mut client = MockClient::new();
+
+        // statements other than cursors are run by the wrapped handler
+        let responses = handler
+            .do_query(&mut client, "BEGIN; DECLARE c CURSOR FOR SELECT n; FETCH c")
+            .await
+            .unwrap();
+        assert_eq!(3, responses.len());
+        assert!(matches!(&responses[0], Response::Execution(tag) if *tag == Tag::new("OK")));
+
+        client.info.transaction_status = TransactionStatus::Transaction;
+        execute(&handler, &mut client, "DECLARE t CURSOR FOR SELECT n")
+            .await
+            .unwrap();
+        execute(
+            &handler,
+            &mut client,
+            "DECLARE h CURSOR WITH HOLD FOR SELECT n",
+        )
+        .await
+        .unwrap();
+        assert_eq!(vec!["c", "h", "t"], handler.cursor_names());
+
+        // the transaction ended
+        client.info.transaction_status = TransactionStatus::Idle;
+        assert_eq!(vec!["1"], fetch(&handler, &mut client, "FETCH h").await);
+        assert_eq!(vec!["c", "h"], handler.cursor_names());
+    }
+}
@@ -0,0 +1,495 @@
+//! Client encoding of text.
+//!
+//! Strings in this library are UTF-8. A client that sets `client_encoding`
+//! in startup parameters, or a handler that calls
+//! `ClientInfo::set_client_encoding` for `SET client_encoding`, has text
+//! transcoded by the connection: query strings and text parameters from
+//! client are decoded to UTF-8, and text fields of rows, column names,
+//! errors, notices and notifications are encoded to the client encoding.
+//! Binary values and `COPY` data are not transcoded. Characters that the
+//! client encoding can't represent are sent as `?`.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::api::PgWireConnectionState;
+use crate::error::PgWireResult;
+use crate::messages::codec;
+use crate::messages::extendedquery::TARGET_TYPE_BYTE_PORTAL;
+use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+use crate::sansio::{decode_frontend_message_with_limits, MessageLimits};
+
+/// Encodings of `client_encoding` supported by this library.
+#[non_exhaustive]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ClientEncoding {
+    #[default]
+    Utf8,
+    /// ISO 8859-1
+    Latin1,
+    /// Windows CP1252
+    Win1252,
+    /// No conversion, bytes are passed through as is.
+    SqlAscii,
+}
+
+// characters of 0x80 to 0x9f in WIN1252, `None` for undefined bytes
+const WIN1252_HIGH: [Option<char>; 32] = [
+    Some('\u{20ac}'),
+    None,
+    Some('\u{201a}'),
+    Some('\u{0192}'),
+    Some('\u{201e}'),
+    Some('\u{2026}'),
+    Some('\u{2020}'),
+    Some('\u{2021}'),
+    Some('\u{02c6}'),
+    Some('\u{2030}'),
+    Some('\u{0160}'),
+    Some('\u{2039}'),
+    Some('\u{0152}'),
+    None,
+    Some('\u{017d}'),
+    None,
+    None,
+    Some('\u{2018}'),
+    Some('\u{2019}'),
+    Some('\u{201c}'),
+    Some('\u{201d}'),
+    Some('\u{2022}'),
+    Some('\u{2013}'),
+    Some('\u{2014}'),
+    Some('\u{02dc}'),
+    Some('\u{2122}'),
+    Some('\u{0161}'),
+    Some('\u{203a}'),
+    Some('\u{0153}'),
+    None,
+    Some('\u{017e}'),
+    Some('\u{0178}'),
+];
+
+impl ClientEncoding {
+    /// Parse encoding name like postgres, case insensitive and ignoring
+    /// punctuation, so `latin1`, `ISO_8859_1` and `ISO-8859-1` are all
+    /// `Latin1`.
+    pub fn from_name(name: &str) -> Option<ClientEncoding> {
+        let name: String = name
+            .chars()
+            .filter(char::is_ascii_alphanumeric)
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        match name.as_str() {
+            "utf8" | "unicode" => Some(ClientEncoding::Utf8),
+            "latin1" | "iso88591" => Some(ClientEncoding::Latin1),
+            "win1252" | "windows1252" => Some(ClientEncoding::Win1252),
+            "sqlascii" => Some(ClientEncoding::SqlAscii),
+            _ => None,
+        }
+    }
+
+    /// Name reported in `client_encoding` parameter.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientEncoding::Utf8 => "UTF8",
+            ClientEncoding::Latin1 => "LATIN1",
+            ClientEncoding::Win1252 => "WIN1252",
+            ClientEncoding::SqlAscii => "SQL_ASCII",
+        }
+    }
+
+    /// Whether text is exchanged without conversion.
+    pub fn is_passthrough(&self) -> bool {
+        matches!(self, ClientEncoding::Utf8 | ClientEncoding::SqlAscii)
+    }
+
+    /// Decode text from client. Invalid bytes are replaced by U+FFFD.
+    pub fn decode<'a>(&self, bytes: &'a [u8]) -> Cow<'a, str> {
+        if self.is_passthrough() || bytes.is_ascii() {
+            return String::from_utf8_lossy(bytes);
+        }
+        let chars = bytes.iter().map(|b| match (self, b) {
+            (ClientEncoding::Win1252, 0x80..=0x9f) => {
+                WIN1252_HIGH[(b - 0x80) as usize].unwrap_or(char::REPLACEMENT_CHARACTER)
+            }
+            _ => *b as char,
+        });
+        Cow::Owned(chars.collect())
+    }
+
+    /// Encode text for client. Characters not in the encoding are replaced
+    /// by `?`.
+    pub fn encode<'a>(&self, text: &'a str) -> Cow<'a, [u8]> {
+        if self.is_passthrough() || text.is_ascii() {
+            return Cow::Borrowed(text.as_bytes());
+        }
+        let bytes = text.chars().map(|c| match c as u32 {
+            0..=0x7f | 0xa0..=0xff => c as u8,
+            0x80..=0x9f if *self == ClientEncoding::Latin1 => c as u8,
+            _ if *self == ClientEncoding::Win1252 => WIN1252_HIGH
+                .iter()
+                .position(|high| *high == Some(c))
+                .map_or(b'?', |i| 0x80 + i as u8),
+            _ => b'?',
+        });
+        Cow::Owned(bytes.collect())
+    }
+
+    fn put_encoded(&self, dst: &mut BytesMut, utf8: &[u8]) {
+        dst.put_slice(&self.encode(&String::from_utf8_lossy(utf8)));
+    }
+}
+
+// whether column `i` is in text format, by format codes of `Bind` or
+// `RowDescription`
+fn is_text(formats: &[i16], i: usize) -> bool {
+    match formats {
+        [] => true,
+        [format] => *format == 0,
+        _ => formats.get(i).map_or(true, |format| *format == 0),
+    }
+}
+
+/// Transcoding state of a connection, kept in its codec. It tracks the
+/// result formats of portals, to transcode only text fields of rows.
+#[derive(Debug, Default)]
+pub(crate) struct Transcoder {
+    portal_formats: HashMap<String, Vec<i16>>,
+    // formats of rows being sent
+    result_formats: Vec<i16>,
+}
+
+impl Transcoder {
+    /// Like `decode_frontend_message_with_limits`, decoding text of
+    /// messages from `encoding`.
+    pub(crate) fn decode(
+        &mut self,
+        encoding: ClientEncoding,
+        state: PgWireConnectionState,
+        buf: &mut BytesMut,
+        limits: &MessageLimits,
+    ) -> PgWireResult<Option<PgWireFrontendMessage>> {
+        if encoding.is_passthrough() || state == PgWireConnectionState::AwaitingStartup {
+            return decode_frontend_message_with_limits(state, buf, limits);
+        }
+
+        limits.check_length(state, buf)?;
+        // strings are decoded as UTF-8 by message decoders, so query strings
+        // are transcoded before
+        let message = match (buf.first(), codec::get_length(buf, 1)) {
+            (Some(b'Q'), Some(len)) if buf.len() > len => {
+                let frame = buf.split_to(len + 1);
+                PgWireFrontendMessage::decode(&mut transcode_frame(encoding, &frame, 1))?
+            }
+            (Some(b'P'), Some(len)) if buf.len() > len => {
+                let frame = buf.split_to(len + 1);
+                PgWireFrontendMessage::decode(&mut transcode_frame(encoding, &frame, 2))?
+            }
+            _ => PgWireFrontendMessage::decode(buf)?,
+        };
+
+        let Some(mut message) = message else {
+            return Ok(None);
+        };
+        match &mut message {
+            PgWireFrontendMessage::Query(_) => self.result_formats.clear(),
+            PgWireFrontendMessage::Bind(bind) => {
+                for (i, parameter) in bind.parameters.iter_mut().enumerate() {
+                    if let Some(value) = parameter {
+                        if is_text(&bind.parameter_format_codes, i) && !value.is_ascii() {
+                            *value = Bytes::from(encoding.decode(value).into_owned());
+                        }
+                    }
+                }
+                self.portal_formats.insert(
+                    bind.portal_name.clone().unwrap_or_default(),
+                    bind.result_column_format_codes.clone(),
+                );
+            }
+            PgWireFrontendMessage::Execute(execute) => {
+                let portal = execute.name.as_deref().unwrap_or_default();
+                self.result_formats = self.portal_formats.get(portal).cloned().unwrap_or_default();
+            }
+            PgWireFrontendMessage::Close(close) if close.target_type == TARGET_TYPE_BYTE_PORTAL => {
+                self.portal_formats
+                    .remove(close.name.as_deref().unwrap_or_default());
+            }
+            _ => {}
+        }
+        Ok(Some(message))
+    }
+
+    /// Encode text of `message`, which is encoded in `dst` from `start`, to
+    /// `encoding`.
+    pub(crate) fn encode(
+        &mut self,
+        encoding: ClientEncoding,
+        message: &PgWireBackendMessage,
+        dst: &mut BytesMut,
+        start: usize,
+    ) {
+        if let PgWireBackendMessage::RowDescription(description) = message {
+            self.result_formats = description.fields.iter().map(|f| f.format_code).collect();
+        }
+        if encoding.is_passthrough()
+            || !matches!(
+                message,
+                PgWireBackendMessage::ErrorResponse(_)
+                    | PgWireBackendMessage::NoticeResponse(_)
+                    | PgWireBackendMessage::ParameterStatus(_)
+                    | PgWireBackendMessage::NotificationResponse(_)
+                    | PgWireBackendMessage::RowDescription(_)
+                    | PgWireBackendMessage::DataRow(_)
+            )
+        {
+            return;
+        }
+
+        let encoded = dst.split_off(start);
+        let mut body = &encoded[5..];
+        dst.put_u8(encoded[0]);
+        dst.put_i32(0);
+        match message {
+            // all text but the field codes
+            PgWireBackendMessage::ErrorResponse(_)
+            | PgWireBackendMessage::NoticeResponse(_)
+            | PgWireBackendMessage::ParameterStatus(_) => encoding.put_encoded(dst, body),
+            PgWireBackendMessage::NotificationResponse(_) => {
+                dst.put_slice(&body[..4]);
+                encoding.put_encoded(dst, &body[4..]);
+            }
+            PgWireBackendMessage::RowDescription(_) => {
+                dst.put_slice(&body[..2]);
+                body.advance(2);
+                while let Some(end) = body.iter().position(|b| *b == 0) {
+                    encoding.put_encoded(dst, &body[..end]);
+                    // nul and the fixed size attributes
+                    dst.put_slice(&body[end..end + 19]);
+                    body.advance(end + 19);
+                }
+            }
+            PgWireBackendMessage::DataRow(_) => {
+                let fields = body.get_i16();
+                dst.put_i16(fields);
+                for i in 0..fields as usize {
+                    let len = body.get_i32();
+                    if len < 0 {
+                        dst.put_i32(len);
+                        continue;
+                    }
+                    let (value, rest) = body.split_at(len as usize);
+                    if is_text(&self.result_formats, i) && !value.is_ascii() {
+                        let value = encoding
+                            .encode(&String::from_utf8_lossy(value))
+                            .into_owned();
+                        dst.put_i32(value.len() as i32);
+                        dst.put_slice(&value);
+                    } else {
+                        dst.put_i32(len);
+                        dst.put_slice(value);
+                    }
+                    body = rest;
+                }
+            }
+            _ => dst.put_slice(body),
+        }
+        let len = (dst.len() - start - 1) as i32;
+        dst[start + 1..start + 5].copy_from_slice(&len.to_be_bytes());
+    }
+}
+
+// decode first `strings` strings of a frontend message frame
+fn transcode_frame(encoding: ClientEncoding, frame: &[u8], strings: usize) -> BytesMut {
+    let mut transcoded = BytesMut::with_capacity(frame.len());
+    transcoded.put_u8(frame[0]);
+    transcoded.put_i32(0);
+    let mut body = &frame[5..];
+    for _ in 0..strings {
+        let end = body.iter().position(|b| *b == 0).unwrap_or(body.len());
+        transcoded.put_slice(encoding.decode(&body[..end]).as_bytes());
+        body = &body[end..];
+        if !body.is_empty() {
+            transcoded.put_u8(0);
+            body = &body[1..];
+        }
+    }
+    transcoded.put_slice(body);
+    let len = (transcoded.len() - 1) as i32;
+    transcoded[1..5].copy_from_slice(&len.to_be_bytes());
+    transcoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorInfo;
+    use crate::messages::data::{DataRow, FieldDescription, RowDescription};
+    use crate::messages::extendedquery::{Bind, Execute, Parse};
+    use crate::messages::simplequery::Query;
+    use crate::messages::Message;
+
+    #[test]
+    fn test_encoding_names() {
+        assert_eq!(
+            Some(ClientEncoding::Latin1),
+            ClientEncoding::from_name("latin1")
+        );
+        assert_eq!(
+            Some(ClientEncoding::Latin1),
+            ClientEncoding::from_name("ISO_8859_1")
+        );
+        assert_eq!(
+            Some(ClientEncoding::Utf8),
+            ClientEncoding::from_name("UNICODE")
+        );
+        assert_eq!(
+            Some(ClientEncoding::SqlAscii),
+            ClientEncoding::from_name("sql_ascii")
+        );
+        assert_eq!(None, ClientEncoding::from_name("EUC_JP"));
+        assert_eq!("WIN1252", ClientEncoding::Win1252.name());
+    }
+
+    #[test]
+    fn test_transcode_text() {
+        let latin1 = ClientEncoding::Latin1;
+        assert_eq!(b"caf\xe9".as_slice(), latin1.encode("café").as_ref());
+        assert_eq!("café", latin1.decode(b"caf\xe9"));
+        assert_eq!(b"\x80?".as_slice(), latin1.encode("\u{80}€").as_ref());
+
+        let win1252 = ClientEncoding::Win1252;
+        assert_eq!(b"\x80 \xe9 ?".as_slice(), win1252.encode("€ é 日").as_ref());
+        assert_eq!("€ é \u{fffd}", win1252.decode(b"\x80 \xe9 \x81"));
+
+        assert!(matches!(latin1.encode("ascii"), Cow::Borrowed(_)));
+        assert_eq!(b"\xe9".as_slice(), latin1.encode("é").as_ref());
+        assert_eq!("\u{fffd}", ClientEncoding::Utf8.decode(b"\xe9"));
+    }
+
+    fn frontend(message: impl Message, query: &[u8]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        message.encode(&mut buf).unwrap();
+        // replace the placeholder query with raw bytes
+        let at = buf.windows(5).position(|w| w == b"QUERY").unwrap();
+        let mut raw = BytesMut::from(&buf[..at]);
+        raw.put_slice(query);
+        raw.put_slice(&buf[at + 5..]);
+        let len = (raw.len() - 1) as i32;
+        raw[1..5].copy_from_slice(&len.to_be_bytes());
+        raw
+    }
+
+    #[test]
+    fn test_decode_frontend() {
+        let mut transcoder = Transcoder::default();
+        let state = PgWireConnectionState::ReadyForQuery;
+        let limits = MessageLimits::default();
+
+        let mut buf = frontend(Query::new("QUERY".to_owned()), b"SELECT '\xe9'");
+        buf.extend_from_slice(&frontend(
+            Parse::new(Some("s".to_owned()), "QUERY".to_owned(), vec![25]),
+            b"SELECT \x80",
+        ));
+        let Some(PgWireFrontendMessage::Query(query)) = transcoder
+            .decode(ClientEncoding::Latin1, state, &mut buf, &limits)
+            .unwrap()
+        else {
+            panic!("expected query");
+        };
+        assert_eq!("SELECT 'é'", query.query);
+        let Some(PgWireFrontendMessage::Parse(parse)) = transcoder
+            .decode(ClientEncoding::Win1252, state, &mut buf, &limits)
+            .unwrap()
+        else {
+            panic!("expected parse");
+        };
+        assert_eq!("SELECT €", parse.query);
+        assert_eq!(vec![25], parse.type_oids);
+        assert!(buf.is_empty());
+
+        let bind = Bind::new(
+            None,
+            Some("s".to_owned()),
+            vec![0, 1],
+            vec![
+                Some(Bytes::from_static(b"\xe9")),
+                Some(Bytes::from_static(b"\xe9")),
+            ],
+            vec![1],
+        );
+        bind.encode(&mut buf).unwrap();
+        let Some(PgWireFrontendMessage::Bind(bind)) = transcoder
+            .decode(ClientEncoding::Latin1, state, &mut buf, &limits)
+            .unwrap()
+        else {
+            panic!("expected bind");
+        };
+        assert_eq!(Some(Bytes::from("é")), bind.parameters[0]);
+        assert_eq!(Some(Bytes::from_static(b"\xe9")), bind.parameters[1]);
+
+        Execute::new(None, 0).encode(&mut buf).unwrap();
+        transcoder
+            .decode(ClientEncoding::Latin1, state, &mut buf, &limits)
+            .unwrap();
+        assert_eq!(vec![1], transcoder.result_formats);
+    }
+
+    fn backend(transcoder: &mut Transcoder, message: PgWireBackendMessage) -> BytesMut {
+        let mut buf = BytesMut::from("prefix");
+        message.encode(&mut buf).unwrap();
+        transcoder.encode(ClientEncoding::Latin1, &message, &mut buf, 6);
+        assert_eq!(b"prefix", &buf[..6]);
+        buf.split_off(6)
+    }
+
+    #[test]
+    fn test_encode_backend() {
+        let mut transcoder = Transcoder::default();
+
+        let field = |name: &str, format_code| {
+            FieldDescription::new(name.to_owned(), 0, 0, 25, -1, -1, format_code)
+        };
+        let description = RowDescription::new(vec![field("é", 0), field("b", 1)]);
+        let mut buf = backend(
+            &mut transcoder,
+            PgWireBackendMessage::RowDescription(description),
+        );
+        assert!(buf.windows(2).any(|w| w == b"\xe9\0"));
+        let Some(PgWireBackendMessage::RowDescription(description)) =
+            PgWireBackendMessage::decode(&mut buf).unwrap()
+        else {
+            panic!("expected row description");
+        };
+        assert_eq!("\u{fffd}", description.fields[0].name);
+        assert_eq!(1, description.fields[1].format_code);
+
+        let mut data = BytesMut::new();
+        for value in ["é", "é"] {
+            data.put_i32(value.len() as i32);
+            data.put_slice(value.as_bytes());
+        }
+        data.put_i32(-1);
+        let mut buf = backend(
+            &mut transcoder,
+            PgWireBackendMessage::DataRow(DataRow::new(data, 3)),
+        );
+        let Some(PgWireBackendMessage::DataRow(row)) =
+            PgWireBackendMessage::decode(&mut buf).unwrap()
+        else {
+            panic!("expected data row");
+        };
+        assert_eq!(
+            vec![Some(b"\xe9".as_slice()), Some("é".as_bytes()), None],
+            row.fields().unwrap()
+        );
+
+        let error = ErrorInfo::new("ERROR".to_owned(), "XX000".to_owned(), "é".to_owned());
+        let buf = backend(
+            &mut transcoder,
+            PgWireBackendMessage::ErrorResponse(error.into()),
+        );
+        assert!(buf.windows(2).any(|w| w == b"\xe9\0"));
+    }
+}
@@ -0,0 +1,137 @@
+//! Health check and readiness probes of load balancers.
+//!
+//! Load balancers and orchestrators check postgres compatible endpoints by
+//! connecting and disconnecting, like a TCP check or `pg_isready`, or by a
+//! trivial query like `SELECT 1`. With `ServerOptions::with_health_check`,
+//! these probe queries are answered by the connection itself, before
+//! middleware, query rewriter, auditor and handlers, so they don't pollute
+//! query paths of the application. Both kinds of probes are reported to
+//! `Metrics::on_health_probe`.
+//!
+//! Probe queries fail with `57P03` (cannot_connect_now) when the server is
+//! not ready, like during warmup with `HealthCheck::set_ready(false)`, or
+//! once graceful shutdown is requested, so load balancers stop routing new
+//! clients to it.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use futures::stream;
+use postgres_types::Type;
+
+use super::results::{DataRowEncoder, FieldFormat, FieldInfo, QueryResponse, Response};
+use crate::error::{ErrorInfo, PgWireResult, SqlState};
+
+/// Probe queries answered by the server, `SELECT 1` by default, and the
+/// readiness reported by them. Clones share the readiness.
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    // normalized by `normalize`
+    queries: Vec<String>,
+    ready: Arc<AtomicBool>,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            queries: vec![normalize("SELECT 1")],
+            ready: Arc::new(AtomicBool::new(true)),
+        }
+    }
+}
+
+impl HealthCheck {
+    pub fn new() -> HealthCheck {
+        HealthCheck::default()
+    }
+
+    /// Answer simple query `query` as a probe too. All probe queries are
+    /// answered like `SELECT 1`, with a single `int4` column of value 1.
+    pub fn with_query(mut self, query: &str) -> HealthCheck {
+        self.queries.push(normalize(query));
+        self
+    }
+
+    /// Set whether the server is ready, probe queries fail when it isn't.
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Relaxed);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    /// Whether `query` is a probe query, ignoring case, extra whitespace and
+    /// trailing semicolons.
+    pub fn is_probe(&self, query: &str) -> bool {
+        self.queries.contains(&normalize(query))
+    }
+
+    /// Answer of a probe query.
+    pub(crate) fn response(&self) -> PgWireResult<Response<'static>> {
+        let schema = Arc::new(vec![FieldInfo::new(
+            "?column?".to_owned(),
+            None,
+            None,
+            Type::INT4,
+            FieldFormat::Text,
+        )]);
+        let mut encoder = DataRowEncoder::new(schema.clone());
+        encoder.encode_field(&1i32)?;
+        let row = encoder.finish();
+        Ok(Response::Query(QueryResponse::new(
+            schema,
+            stream::iter(vec![row]),
+        )))
+    }
+}
+
+/// A probe recognized by the server, see `Metrics::on_health_probe`.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthProbe {
+    /// A connection is closed by client without running any query.
+    Connect,
+    /// A probe query is answered, `ready` is false when it failed as the
+    /// server isn't ready.
+    Query { ready: bool },
+}
+
+/// Error of a probe query when the server isn't ready.
+pub(crate) fn not_ready_error() -> ErrorInfo {
+    ErrorInfo::new(
+        "ERROR".to_owned(),
+        SqlState::CANNOT_CONNECT_NOW.into(),
+        "the server is not ready to accept connections".to_owned(),
+    )
+}
+
+fn normalize(query: &str) -> String {
+    query
+        .trim()
+        .trim_end_matches(|c: char| c == ';' || c.is_whitespace())
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_probe() {
+        let check = HealthCheck::new().with_query("SELECT 'ok'");
+        assert!(check.is_probe("SELECT 1"));
+        assert!(check.is_probe("  select\n 1 ;; "));
+        assert!(check.is_probe("select 'OK';"));
+        assert!(!check.is_probe("SELECT 2"));
+        assert!(!check.is_probe("SELECT 1; DROP TABLE t"));
+
+        let shared = check.clone();
+        assert!(check.is_ready());
+        shared.set_ready(false);
+        assert!(!check.is_ready());
+    }
+}
@@ -0,0 +1,197 @@
+//! Accounting of memory buffered for responses of a connection.
+//!
+//! Rows of a query response are streamed to client as fast as they are
+//! written to socket, and the write buffer is bounded by
+//! `ServerOptions::write_buffer_size`. Some responses are buffered in memory
+//! though, like the rest of a portal suspended by `Execute` with a row
+//! limit, which can be a giant result a client never reads. Each connection
+//! accounts these bytes in its `SendMemory`, and fails the query buffering
+//! them with `53200` (out_of_memory) when they'd exceed
+//! `ServerOptions::max_send_memory`. Handlers buffering responses of a
+//! session, like `CursorQueryHandler`, reserve their bytes as well.
+//!
+//! Changes are reported to `Metrics::on_send_memory_reserved` and
+//! `Metrics::on_send_memory_released`.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use super::metrics::Metrics;
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, SqlState};
+
+/// Memory buffered for responses of a connection, with an optional limit.
+/// Clones share the same account.
+#[derive(Clone, Default)]
+pub struct SendMemory {
+    inner: Arc<SendMemoryInner>,
+}
+
+#[derive(Default)]
+struct SendMemoryInner {
+    used: AtomicUsize,
+    limit: Option<usize>,
+    metrics: Option<Arc<dyn Metrics>>,
+}
+
+impl std::fmt::Debug for SendMemory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SendMemory")
+            .field("used", &self.used())
+            .field("limit", &self.limit())
+            .finish()
+    }
+}
+
+impl SendMemory {
+    /// Account of at most `limit` bytes, `None` for unlimited.
+    pub fn new(limit: Option<usize>) -> SendMemory {
+        SendMemory::with_metrics(limit, None)
+    }
+
+    pub(crate) fn with_metrics(
+        limit: Option<usize>,
+        metrics: Option<Arc<dyn Metrics>>,
+    ) -> SendMemory {
+        SendMemory {
+            inner: Arc::new(SendMemoryInner {
+                used: AtomicUsize::new(0),
+                limit,
+                metrics,
+            }),
+        }
+    }
+
+    /// Bytes reserved now.
+    pub fn used(&self) -> usize {
+        self.inner.used.load(Ordering::Relaxed)
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.inner.limit
+    }
+
+    /// An empty reservation, which grows as responses are buffered.
+    pub fn reservation(&self) -> SendMemoryReservation {
+        SendMemoryReservation {
+            memory: self.clone(),
+            bytes: 0,
+        }
+    }
+
+    fn acquire(&self, bytes: usize) -> PgWireResult<()> {
+        let used = self.inner.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if let Some(limit) = self.inner.limit {
+            if used > limit {
+                self.inner.used.fetch_sub(bytes, Ordering::Relaxed);
+                return Err(PgWireError::UserError(Box::new(
+                    ErrorInfo::new(
+                        "ERROR".to_owned(),
+                        SqlState::OUT_OF_MEMORY.into(),
+                        "out of memory".to_owned(),
+                    )
+                    .with_detail(format!(
+                        "Responses buffered for the connection exceed the limit of {limit} bytes."
+                    )),
+                )));
+            }
+        }
+        if let Some(metrics) = &self.inner.metrics {
+            metrics.on_send_memory_reserved(bytes);
+        }
+        Ok(())
+    }
+
+    fn release(&self, bytes: usize) {
+        self.inner.used.fetch_sub(bytes, Ordering::Relaxed);
+        if let Some(metrics) = &self.inner.metrics {
+            metrics.on_send_memory_released(bytes);
+        }
+    }
+}
+
+/// Bytes reserved for a buffered response, released when it's dropped.
+#[derive(Debug)]
+pub struct SendMemoryReservation {
+    memory: SendMemory,
+    bytes: usize,
+}
+
+impl SendMemoryReservation {
+    /// Reserve `bytes` more, failing with `53200` over the limit of
+    /// connection.
+    pub fn grow(&mut self, bytes: usize) -> PgWireResult<()> {
+        self.memory.acquire(bytes)?;
+        self.bytes += bytes;
+        Ok(())
+    }
+
+    /// Release `bytes` of the reservation.
+    pub fn shrink(&mut self, bytes: usize) {
+        let bytes = bytes.min(self.bytes);
+        self.memory.release(bytes);
+        self.bytes -= bytes;
+    }
+
+    /// Bytes reserved.
+    pub fn size(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl Drop for SendMemoryReservation {
+    fn drop(&mut self) {
+        if self.bytes > 0 {
+            self.memory.release(self.bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Gauge(AtomicUsize);
+
+    impl Metrics for Gauge {
+        fn on_send_memory_reserved(&self, bytes: usize) {
+            self.0.fetch_add(bytes, Ordering::Relaxed);
+        }
+
+        fn on_send_memory_released(&self, bytes: usize) {
+            self.0.fetch_sub(bytes, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_reservation() {
+        let gauge = Arc::new(Gauge::default());
+        let memory = SendMemory::with_metrics(Some(100), Some(gauge.clone()));
+
+        let mut first = memory.reservation();
+        first.grow(60).unwrap();
+        let mut second = memory.reservation();
+        second.grow(40).unwrap();
+        assert_eq!(100, memory.used());
+
+        let Err(PgWireError::UserError(error)) = second.grow(1) else {
+            panic!("expect out of memory");
+        };
+        assert_eq!("53200", error.code);
+        assert_eq!(40, second.size());
+
+        first.shrink(20);
+        second.grow(1).unwrap();
+        assert_eq!(81, memory.used());
+        assert_eq!(81, gauge.0.load(Ordering::Relaxed));
+
+        drop(first);
+        drop(second);
+        assert_eq!(0, memory.used());
+        assert_eq!(0, gauge.0.load(Ordering::Relaxed));
+
+        // unlimited
+        let mut reservation = SendMemory::default().reservation();
+        reservation.grow(usize::MAX / 2).unwrap();
+    }
+}
@@ -0,0 +1,173 @@
+//! Observer of connection and query events, for exporting metrics.
+//!
+//! Implement `Metrics` and set it with `ServerOptions::with_metrics`, to wire
+//! counters of a metrics library like prometheus to the server. Events are
+//! recorded by the connection itself, from the messages it decodes and
+//! encodes, so they cover any handler implementation. Without metrics
+//! configured nothing is recorded.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use super::health::HealthProbe;
+use crate::messages::PgWireBackendMessage;
+
+/// Receives events of all connections. All methods do nothing by default,
+/// implement the ones of interest. They are called on the connection task,
+/// and should be cheap and non-blocking.
+pub trait Metrics: Send + Sync {
+    /// A client connected, before tls and startup.
+    fn on_connection_opened(&self, _addr: SocketAddr) {}
+
+    /// A client connection is closed, for any reason.
+    fn on_connection_closed(&self, _addr: SocketAddr) {}
+
+    /// Bytes of a message decoded from client.
+    fn on_bytes_received(&self, _bytes: usize) {}
+
+    /// Bytes of a message encoded for client.
+    fn on_bytes_sent(&self, _bytes: usize) {}
+
+    /// A statement has completed, `tag` is the tag of `CommandComplete` like
+    /// `SELECT 5` or `INSERT 0 1`.
+    fn on_query_completed(&self, _tag: &str) {}
+
+    /// `rows` rows are sent as result of a statement, or a portion of it.
+    fn on_rows_sent(&self, _rows: usize) {}
+
+    /// An `ErrorResponse` is sent to client.
+    fn on_error(&self, _severity: &str, _code: &str) {}
+
+    /// Bytes of responses buffered in memory by a connection, like rows of a
+    /// suspended portal, see `memory::SendMemory`.
+    fn on_send_memory_reserved(&self, _bytes: usize) {}
+
+    /// Bytes of buffered responses are sent or dropped.
+    fn on_send_memory_released(&self, _bytes: usize) {}
+
+    /// A health check probe, with `ServerOptions::health_check` set. Probe
+    /// connections are counted as connections as well, but probe queries are
+    /// not counted as queries.
+    fn on_health_probe(&self, _probe: HealthProbe) {}
+}
+
+/// Metrics state of a connection, kept in its codec.
+pub(crate) struct ConnectionMetrics {
+    metrics: Arc<dyn Metrics>,
+    // rows sent since last completion
+    rows: usize,
+}
+
+impl ConnectionMetrics {
+    pub(crate) fn new(metrics: Arc<dyn Metrics>) -> ConnectionMetrics {
+        ConnectionMetrics { metrics, rows: 0 }
+    }
+
+    pub(crate) fn on_received(&self, bytes: usize) {
+        self.metrics.on_bytes_received(bytes);
+    }
+
+    pub(crate) fn on_sent(&mut self, message: &PgWireBackendMessage, bytes: usize) {
+        self.metrics.on_bytes_sent(bytes);
+        match message {
+            PgWireBackendMessage::DataRow(_) => self.rows += 1,
+            PgWireBackendMessage::CommandComplete(complete) => {
+                self.flush_rows();
+                self.metrics.on_query_completed(&complete.tag);
+            }
+            PgWireBackendMessage::PortalSuspended(_) => self.flush_rows(),
+            PgWireBackendMessage::ErrorResponse(error) => {
+                self.flush_rows();
+                let field = |code| {
+                    error
+                        .fields
+                        .iter()
+                        .find(|(c, _)| *c == code)
+                        .map_or("", |(_, value)| value.as_str())
+                };
+                self.metrics.on_error(field(b'S'), field(b'C'));
+            }
+            _ => {}
+        }
+    }
+
+    fn flush_rows(&mut self) {
+        if self.rows > 0 {
+            self.metrics.on_rows_sent(self.rows);
+            self.rows = 0;
+        }
+    }
+}
+
+/// Records opening of a connection, and its closing when dropped.
+pub(crate) struct ConnectionGuard {
+    metrics: Arc<dyn Metrics>,
+    addr: SocketAddr,
+}
+
+impl ConnectionGuard {
+    pub(crate) fn new(metrics: Arc<dyn Metrics>, addr: SocketAddr) -> ConnectionGuard {
+        metrics.on_connection_opened(addr);
+        ConnectionGuard { metrics, addr }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.metrics.on_connection_closed(self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::error::ErrorInfo;
+    use crate::messages::data::DataRow;
+    use crate::messages::response::CommandComplete;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl Metrics for Recorder {
+        fn on_query_completed(&self, tag: &str) {
+            self.events.lock().unwrap().push(format!("complete {tag}"));
+        }
+
+        fn on_rows_sent(&self, rows: usize) {
+            self.events.lock().unwrap().push(format!("rows {rows}"));
+        }
+
+        fn on_error(&self, severity: &str, code: &str) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("error {severity} {code}"));
+        }
+    }
+
+    #[test]
+    fn test_connection_metrics() {
+        let recorder = Arc::new(Recorder::default());
+        let mut metrics = ConnectionMetrics::new(recorder.clone());
+
+        for _ in 0..3 {
+            metrics.on_sent(&PgWireBackendMessage::DataRow(DataRow::default()), 10);
+        }
+        metrics.on_sent(
+            &PgWireBackendMessage::CommandComplete(CommandComplete::new("SELECT 3".to_owned())),
+            10,
+        );
+        metrics.on_sent(&PgWireBackendMessage::DataRow(DataRow::default()), 10);
+        let error = ErrorInfo::new("ERROR".to_owned(), "57014".to_owned(), "cancel".to_owned());
+        metrics.on_sent(&PgWireBackendMessage::ErrorResponse(error.into()), 10);
+
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            vec!["rows 3", "complete SELECT 3", "rows 1", "error ERROR 57014"]
+        );
+    }
+}
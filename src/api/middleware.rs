@@ -0,0 +1,51 @@
+//! Middleware of protocol messages.
+//!
+//! A `Middleware` added with `ServerOptions::with_middleware` sees each
+//! message decoded from client before it's dispatched to handlers, and each
+//! message from handlers before it's encoded. It can pass a message on,
+//! replace it, or drop it, for logging, rewriting, throttling or gating
+//! features of the protocol without touching handlers.
+//!
+//! Middleware are layered in the order they are added: the first added is the
+//! outermost layer, it sees frontend messages first and backend messages
+//! last.
+//!
+//! ```ignore
+//! let options = ServerOptions::new()
+//!     .with_middleware(Arc::new(Logger))
+//!     .with_middleware(Arc::new(RejectCopy));
+//! ```
+
+use async_trait::async_trait;
+
+use super::ClientInfo;
+use crate::error::PgWireResult;
+use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+
+/// Intercepts messages of a connection. Messages are passed on unchanged by
+/// default, implement the methods of interest.
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    /// Called with each message from client, before it's handled. Return
+    /// `Ok(None)` to drop the message. An error rejects the message, and is
+    /// sent to client like an error of handlers, so further messages of an
+    /// extended query are skipped until `Sync`.
+    async fn on_frontend_message(
+        &self,
+        _client: &(dyn ClientInfo + Sync),
+        message: PgWireFrontendMessage,
+    ) -> PgWireResult<Option<PgWireFrontendMessage>> {
+        Ok(Some(message))
+    }
+
+    /// Called with each message to client, before it's encoded. Return `None`
+    /// to drop the message. It's called on the connection task when messages
+    /// are written, and should be cheap and non-blocking.
+    fn on_backend_message(
+        &self,
+        _client: &dyn ClientInfo,
+        message: PgWireBackendMessage,
+    ) -> Option<PgWireBackendMessage> {
+        Some(message)
+    }
+}
@@ -0,0 +1,171 @@
+//! A client for testing handlers, it records messages sent to it.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::Sink;
+
+use super::cancel::CancelHandle;
+use super::comment::SqlComment;
+use super::encoding::ClientEncoding;
+use super::memory::SendMemory;
+use super::notification::NotificationSink;
+use super::results::{FlushPolicy, ResultLimits};
+use super::{ClientInfo, ClientPortalStore, DefaultClient, PgWireConnectionState, TlsInfo};
+use crate::error::{PgWireError, PgWireResult};
+use crate::messages::response::TransactionStatus;
+use crate::messages::PgWireBackendMessage;
+
+pub(crate) struct MockClient {
+    pub(crate) info: DefaultClient<String>,
+    pub(crate) sent: Vec<PgWireBackendMessage>,
+    // number of sent messages at each flush
+    pub(crate) flushed: Vec<usize>,
+}
+
+impl MockClient {
+    pub(crate) fn new() -> MockClient {
+        MockClient {
+            info: DefaultClient::new("127.0.0.1:5432".parse().unwrap(), false),
+            sent: Vec::new(),
+            flushed: Vec::new(),
+        }
+    }
+}
+
+impl ClientInfo for MockClient {
+    fn socket_addr(&self) -> std::net::SocketAddr {
+        self.info.socket_addr()
+    }
+
+    fn is_secure(&self) -> bool {
+        self.info.is_secure()
+    }
+
+    fn state(&self) -> PgWireConnectionState {
+        self.info.state()
+    }
+
+    fn set_state(&mut self, new_state: PgWireConnectionState) {
+        self.info.set_state(new_state);
+    }
+
+    fn metadata(&self) -> &HashMap<String, String> {
+        self.info.metadata()
+    }
+
+    fn metadata_mut(&mut self) -> &mut HashMap<String, String> {
+        self.info.metadata_mut()
+    }
+
+    fn result_limits(&self) -> ResultLimits {
+        self.info.result_limits()
+    }
+
+    fn set_result_limits(&mut self, limits: ResultLimits) {
+        self.info.set_result_limits(limits);
+    }
+
+    fn flush_policy(&self) -> FlushPolicy {
+        self.info.flush_policy()
+    }
+
+    fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        self.info.set_flush_policy(policy);
+    }
+
+    fn statement_timeout(&self) -> Option<Duration> {
+        self.info.statement_timeout()
+    }
+
+    fn set_statement_timeout(&mut self, timeout: Option<Duration>) {
+        self.info.set_statement_timeout(timeout);
+    }
+
+    fn transaction_status(&self) -> TransactionStatus {
+        self.info.transaction_status()
+    }
+
+    fn set_transaction_status(&mut self, status: TransactionStatus) {
+        self.info.set_transaction_status(status);
+    }
+
+    fn client_encoding(&self) -> ClientEncoding {
+        self.info.client_encoding()
+    }
+
+    fn set_client_encoding(&mut self, encoding: ClientEncoding) {
+        self.info.set_client_encoding(encoding);
+    }
+
+    fn protocol_minor_version(&self) -> u16 {
+        self.info.protocol_minor_version()
+    }
+
+    fn set_protocol_minor_version(&mut self, minor: u16) {
+        self.info.set_protocol_minor_version(minor);
+    }
+
+    fn client_certificates(&self) -> Option<&[Vec<u8>]> {
+        self.info.client_certificates()
+    }
+
+    fn tls_info(&self) -> Option<&TlsInfo> {
+        self.info.tls_info()
+    }
+
+    fn cancel_handle(&self) -> Option<&CancelHandle> {
+        self.info.cancel_handle()
+    }
+
+    fn notification_sink(&self) -> Option<&NotificationSink> {
+        self.info.notification_sink()
+    }
+
+    fn send_memory(&self) -> Option<&SendMemory> {
+        self.info.send_memory()
+    }
+
+    fn sql_comment(&self) -> Option<&SqlComment> {
+        self.info.sql_comment()
+    }
+
+    fn set_sql_comment(&mut self, comment: Option<SqlComment>) {
+        self.info.set_sql_comment(comment);
+    }
+}
+
+impl ClientPortalStore for MockClient {
+    type PortalStore = <DefaultClient<String> as ClientPortalStore>::PortalStore;
+
+    fn portal_store(&self) -> &Self::PortalStore {
+        self.info.portal_store()
+    }
+}
+
+impl Sink<PgWireBackendMessage> for MockClient {
+    type Error = PgWireError;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<PgWireResult<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: PgWireBackendMessage) -> PgWireResult<()> {
+        let status = self.info.transaction_status.after_sent(&item);
+        self.info.transaction_status = status;
+        self.sent.push(item);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<PgWireResult<()>> {
+        let sent = self.sent.len();
+        self.flushed.push(sent);
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<PgWireResult<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
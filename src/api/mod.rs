@@ -3,27 +3,50 @@
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
 pub use postgres_types::Type;
+use tokio_util::sync::CancellationToken;
 
+use crate::messages::response::TransactionStatus;
+
+pub mod admission;
+pub mod audit;
 pub mod auth;
+pub mod blocking;
+pub mod cache;
+pub mod cancel;
+#[cfg(feature = "pg-catalog")]
+pub mod catalog;
+pub mod comment;
+pub mod connection;
+pub mod copy;
+pub mod cursor;
+pub mod encoding;
+pub mod health;
+pub mod memory;
+pub mod metrics;
+pub mod middleware;
+#[cfg(test)]
+pub(crate) mod mock;
+pub mod notification;
 pub mod portal;
 pub mod query;
+pub mod replication;
 pub mod results;
+pub mod rewrite;
+pub mod router;
+#[cfg(feature = "serde")]
+pub mod serde;
+pub mod session;
+pub mod shutdown;
 pub mod stmt;
 pub mod store;
+pub mod wiretap;
 
 pub const DEFAULT_NAME: &str = "POSTGRESQL_DEFAULT_NAME";
 
-#[derive(Debug, Clone, Copy, Default)]
-pub enum PgWireConnectionState {
-    #[default]
-    AwaitingStartup,
-    AuthenticationInProgress,
-    ReadyForQuery,
-    QueryInProgress,
-    AwaitingSync,
-}
+pub use crate::sansio::PgWireConnectionState;
 
 /// Describe a client information holder
 pub trait ClientInfo {
@@ -38,6 +61,166 @@ pub trait ClientInfo {
     fn metadata(&self) -> &HashMap<String, String>;
 
     fn metadata_mut(&mut self) -> &mut HashMap<String, String>;
+
+    /// User name of startup parameters.
+    fn user(&self) -> Option<&str> {
+        self.metadata().get(METADATA_USER).map(String::as_str)
+    }
+
+    /// Database name of startup parameters. Unlike postgres, it doesn't
+    /// default to the user name when client doesn't send it.
+    fn database(&self) -> Option<&str> {
+        self.metadata().get(METADATA_DATABASE).map(String::as_str)
+    }
+
+    /// `application_name` of startup parameters, or as set by handlers.
+    fn application_name(&self) -> Option<&str> {
+        self.metadata()
+            .get(METADATA_APPLICATION_NAME)
+            .map(String::as_str)
+    }
+
+    /// Settings passed in `options` of startup parameters, as `-c name=value`
+    /// or `--name=value` like postgres command line, in order. Other
+    /// arguments are ignored.
+    fn options(&self) -> Vec<(String, String)> {
+        self.metadata()
+            .get(METADATA_OPTIONS)
+            .map(|options| parse_options(options))
+            .unwrap_or_default()
+    }
+
+    /// Whether client requested a replication connection with `replication`
+    /// of startup parameters, either physical or logical (`database`).
+    fn is_replication(&self) -> bool {
+        self.metadata()
+            .get(METADATA_REPLICATION)
+            .is_some_and(|value| {
+                !matches!(
+                    value.to_ascii_lowercase().as_str(),
+                    "false" | "off" | "no" | "0"
+                )
+            })
+    }
+
+    /// Limits on the result of each statement in this session. Unlimited by
+    /// default, for clients not keeping them.
+    fn result_limits(&self) -> results::ResultLimits {
+        results::ResultLimits::default()
+    }
+
+    fn set_result_limits(&mut self, _limits: results::ResultLimits) {}
+
+    /// When rows of responses are flushed in this session. The default
+    /// policy for clients not keeping one.
+    fn flush_policy(&self) -> results::FlushPolicy {
+        results::FlushPolicy::default()
+    }
+
+    fn set_flush_policy(&mut self, _policy: results::FlushPolicy) {}
+
+    /// Time budget of each statement in this session, like `statement_timeout`
+    /// of postgres. Statements running longer are cancelled with `57014`.
+    /// Changes apply from the next statement. No timeout by default.
+    fn statement_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    fn set_statement_timeout(&mut self, _timeout: Option<Duration>) {}
+
+    /// Status of transaction sent in `ReadyForQuery`. It's tracked from the
+    /// messages sent to client, see `TransactionStatus::after_sent`, and
+    /// handlers can set it for statements not covered there. Clients not
+    /// tracking it are always idle.
+    fn transaction_status(&self) -> TransactionStatus {
+        TransactionStatus::Idle
+    }
+
+    fn set_transaction_status(&mut self, _status: TransactionStatus) {}
+
+    /// Encoding of text exchanged with client, from `client_encoding` of
+    /// startup parameters. Handlers of `SET client_encoding` should set it,
+    /// and report the change with `query::send_parameter_status`. `UTF8` for
+    /// clients not keeping it.
+    fn client_encoding(&self) -> encoding::ClientEncoding {
+        encoding::ClientEncoding::default()
+    }
+
+    fn set_client_encoding(&mut self, _encoding: encoding::ClientEncoding) {}
+
+    /// Minor version of protocol 3 negotiated at startup, see
+    /// `auth::negotiate_protocol_version`. `3.0` for clients not keeping it.
+    fn protocol_minor_version(&self) -> u16 {
+        0
+    }
+
+    fn set_protocol_minor_version(&mut self, _minor: u16) {}
+
+    /// DER encoded certificate chain presented by client during tls
+    /// handshake, end-entity certificate first. `None` if the connection is
+    /// not secure or client didn't send a certificate.
+    fn client_certificates(&self) -> Option<&[Vec<u8>]> {
+        None
+    }
+
+    /// Details of the tls session, `None` if the connection is not secure or
+    /// the tls library doesn't provide them.
+    fn tls_info(&self) -> Option<&TlsInfo> {
+        None
+    }
+
+    /// Cancellation state of this session, `None` if the session can't be
+    /// cancelled.
+    fn cancel_handle(&self) -> Option<&cancel::CancelHandle> {
+        None
+    }
+
+    /// Token of the running query. It's cancelled when client sends
+    /// `CancelRequest` for the query, disconnects during the query, or the
+    /// server is shut down before the query completes. Long running
+    /// handlers, or work spawned by them, should stop when it's cancelled.
+    fn cancellation_token(&self) -> CancellationToken {
+        self.cancel_handle()
+            .map(cancel::CancelHandle::token)
+            .unwrap_or_default()
+    }
+
+    /// Sink to push `NotificationResponse` to this client at any time,
+    /// `None` if the connection doesn't deliver notifications.
+    fn notification_sink(&self) -> Option<&notification::NotificationSink> {
+        None
+    }
+
+    /// Memory buffered for responses of this session, `None` if the
+    /// connection doesn't account it.
+    fn send_memory(&self) -> Option<&memory::SendMemory> {
+        None
+    }
+
+    /// sqlcommenter metadata of the running query, from the simple query or
+    /// the prepared statement run by `Execute`. `None` if the query has no
+    /// such comment or the client doesn't keep it.
+    fn sql_comment(&self) -> Option<&comment::SqlComment> {
+        None
+    }
+
+    fn set_sql_comment(&mut self, _comment: Option<comment::SqlComment>) {}
+}
+
+/// Details of a tls session, like `pg_stat_ssl` of postgres.
+#[non_exhaustive]
+#[derive(Debug, Clone, Default, PartialEq, Eq, new)]
+pub struct TlsInfo {
+    /// protocol version, like `TLSv1.3`
+    pub version: String,
+    /// name of cipher suite
+    pub cipher: String,
+    /// server name requested by client with SNI
+    #[new(default)]
+    pub server_name: Option<String>,
+    /// protocol negotiated by ALPN
+    #[new(default)]
+    pub alpn_protocol: Option<Vec<u8>>,
 }
 
 /// Client Portal Store
@@ -49,6 +232,48 @@ pub trait ClientPortalStore {
 
 pub const METADATA_USER: &str = "user";
 pub const METADATA_DATABASE: &str = "database";
+pub const METADATA_APPLICATION_NAME: &str = "application_name";
+pub const METADATA_OPTIONS: &str = "options";
+pub const METADATA_REPLICATION: &str = "replication";
+
+// split `options` into arguments by unescaped whitespace, and collect
+// settings of `-c name=value` and `--name=value`
+fn parse_options(options: &str) -> Vec<(String, String)> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut chars = options.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => arg.extend(chars.next()),
+            c if c.is_ascii_whitespace() => {
+                if !arg.is_empty() {
+                    args.push(std::mem::take(&mut arg));
+                }
+            }
+            c => arg.push(c),
+        }
+    }
+    if !arg.is_empty() {
+        args.push(arg);
+    }
+
+    let mut settings = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let setting = if arg == "-c" {
+            args.next()
+        } else {
+            arg.strip_prefix("--")
+                .or(arg.strip_prefix("-c"))
+                .map(str::to_owned)
+        };
+        if let Some((name, value)) = setting.as_deref().and_then(|s| s.split_once('=')) {
+            // postgres accepts dashes in place of underscores
+            settings.push((name.replace('-', "_"), value.to_owned()));
+        }
+    }
+    settings
+}
 
 #[non_exhaustive]
 #[derive(Debug)]
@@ -57,6 +282,18 @@ pub struct DefaultClient<S> {
     pub is_secure: bool,
     pub state: PgWireConnectionState,
     pub metadata: HashMap<String, String>,
+    pub result_limits: results::ResultLimits,
+    pub flush_policy: results::FlushPolicy,
+    pub statement_timeout: Option<Duration>,
+    pub transaction_status: TransactionStatus,
+    pub client_encoding: encoding::ClientEncoding,
+    pub protocol_minor_version: u16,
+    pub client_certificates: Option<Vec<Vec<u8>>>,
+    pub tls_info: Option<TlsInfo>,
+    pub cancel_handle: Option<cancel::CancelHandle>,
+    pub notification_sink: Option<notification::NotificationSink>,
+    pub send_memory: Option<memory::SendMemory>,
+    pub sql_comment: Option<comment::SqlComment>,
     pub portal_store: store::MemPortalStore<S>,
 }
 
@@ -84,6 +321,82 @@ impl<S> ClientInfo for DefaultClient<S> {
     fn metadata_mut(&mut self) -> &mut HashMap<String, String> {
         &mut self.metadata
     }
+
+    fn result_limits(&self) -> results::ResultLimits {
+        self.result_limits.clone()
+    }
+
+    fn set_result_limits(&mut self, limits: results::ResultLimits) {
+        self.result_limits = limits;
+    }
+
+    fn flush_policy(&self) -> results::FlushPolicy {
+        self.flush_policy
+    }
+
+    fn set_flush_policy(&mut self, policy: results::FlushPolicy) {
+        self.flush_policy = policy;
+    }
+
+    fn statement_timeout(&self) -> Option<Duration> {
+        self.statement_timeout
+    }
+
+    fn set_statement_timeout(&mut self, timeout: Option<Duration>) {
+        self.statement_timeout = timeout;
+    }
+
+    fn transaction_status(&self) -> TransactionStatus {
+        self.transaction_status
+    }
+
+    fn set_transaction_status(&mut self, status: TransactionStatus) {
+        self.transaction_status = status;
+    }
+
+    fn client_encoding(&self) -> encoding::ClientEncoding {
+        self.client_encoding
+    }
+
+    fn set_client_encoding(&mut self, encoding: encoding::ClientEncoding) {
+        self.client_encoding = encoding;
+    }
+
+    fn protocol_minor_version(&self) -> u16 {
+        self.protocol_minor_version
+    }
+
+    fn set_protocol_minor_version(&mut self, minor: u16) {
+        self.protocol_minor_version = minor;
+    }
+
+    fn client_certificates(&self) -> Option<&[Vec<u8>]> {
+        self.client_certificates.as_deref()
+    }
+
+    fn tls_info(&self) -> Option<&TlsInfo> {
+        self.tls_info.as_ref()
+    }
+
+    fn cancel_handle(&self) -> Option<&cancel::CancelHandle> {
+        self.cancel_handle.as_ref()
+    }
+
+    fn notification_sink(&self) -> Option<&notification::NotificationSink> {
+        self.notification_sink.as_ref()
+    }
+
+    fn send_memory(&self) -> Option<&memory::SendMemory> {
+        self.send_memory.as_ref()
+    }
+
+    fn sql_comment(&self) -> Option<&comment::SqlComment> {
+        self.sql_comment.as_ref()
+    }
+
+    fn set_sql_comment(&mut self, comment: Option<comment::SqlComment>) {
+        self.sql_comment = comment;
+    }
 }
 
 impl<S> DefaultClient<S> {
@@ -93,6 +406,18 @@ impl<S> DefaultClient<S> {
             is_secure,
             state: PgWireConnectionState::default(),
             metadata: HashMap::new(),
+            result_limits: results::ResultLimits::default(),
+            flush_policy: results::FlushPolicy::default(),
+            statement_timeout: None,
+            transaction_status: TransactionStatus::Idle,
+            client_encoding: encoding::ClientEncoding::default(),
+            protocol_minor_version: 0,
+            client_certificates: None,
+            tls_info: None,
+            cancel_handle: None,
+            notification_sink: None,
+            send_memory: None,
+            sql_comment: None,
             portal_store: store::MemPortalStore::new(),
         }
     }
@@ -106,6 +431,27 @@ impl<S> ClientPortalStore for DefaultClient<S> {
     }
 }
 
+/// All handlers of a connection implemented by one type, so they share state
+/// without wiring separate handlers through `Arc`s. It's implemented for any
+/// type implementing the four handler traits, for which only `on_startup` and
+/// the `do_query` methods are required. Serve it with
+/// `tokio::process_socket_with_handler`.
+pub trait PgWireHandler:
+    auth::StartupHandler + query::SimpleQueryHandler + query::ExtendedQueryHandler + copy::CopyHandler
+{
+}
+
+impl<H> PgWireHandler for H where
+    H: auth::StartupHandler
+        + query::SimpleQueryHandler
+        + query::ExtendedQueryHandler
+        + copy::CopyHandler
+{
+}
+
+/// Makes handler for each connection, without knowing the connection. Use
+/// `connection::MakeConnectionHandler` to make handlers with peer address,
+/// tls info and startup parameters of the connection.
 pub trait MakeHandler {
     type Handler;
 
@@ -122,3 +468,93 @@ impl<H> MakeHandler for StatelessMakeHandler<H> {
         self.0.clone()
     }
 }
+
+impl<M: MakeHandler> MakeHandler for Arc<M> {
+    type Handler = M::Handler;
+
+    fn make(&self) -> Self::Handler {
+        (**self).make()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_startup_parameters() {
+        let mut client = DefaultClient::<()>::new("127.0.0.1:5432".parse().unwrap(), false);
+        assert_eq!(None, client.user());
+        assert!(!client.is_replication());
+
+        let metadata = client.metadata_mut();
+        metadata.insert(METADATA_USER.to_owned(), "alice".to_owned());
+        metadata.insert(METADATA_APPLICATION_NAME.to_owned(), "psql".to_owned());
+        metadata.insert(METADATA_REPLICATION.to_owned(), "database".to_owned());
+        metadata.insert(
+            METADATA_OPTIONS.to_owned(),
+            r"-c search_path=a\ b --statement-timeout=5s -csearch_path=c -v ignored".to_owned(),
+        );
+        assert_eq!(Some("alice"), client.user());
+        assert_eq!(None, client.database());
+        assert_eq!(Some("psql"), client.application_name());
+        assert!(client.is_replication());
+        assert_eq!(
+            vec![
+                ("search_path".to_owned(), "a b".to_owned()),
+                ("statement_timeout".to_owned(), "5s".to_owned()),
+                ("search_path".to_owned(), "c".to_owned()),
+            ],
+            client.options()
+        );
+    }
+
+    // implementations written before session settings were added
+    struct MinimalClient {
+        metadata: HashMap<String, String>,
+    }
+
+    impl ClientInfo for MinimalClient {
+        fn socket_addr(&self) -> SocketAddr {
+            "127.0.0.1:5432".parse().unwrap()
+        }
+
+        fn is_secure(&self) -> bool {
+            false
+        }
+
+        fn state(&self) -> PgWireConnectionState {
+            PgWireConnectionState::ReadyForQuery
+        }
+
+        fn set_state(&mut self, _new_state: PgWireConnectionState) {}
+
+        fn metadata(&self) -> &HashMap<String, String> {
+            &self.metadata
+        }
+
+        fn metadata_mut(&mut self) -> &mut HashMap<String, String> {
+            &mut self.metadata
+        }
+    }
+
+    #[test]
+    fn test_default_client_info() {
+        let mut client = MinimalClient {
+            metadata: HashMap::new(),
+        };
+        client.set_statement_timeout(Some(Duration::from_secs(1)));
+        assert_eq!(None, client.statement_timeout());
+        assert_eq!(TransactionStatus::Idle, client.transaction_status());
+        assert_eq!(encoding::ClientEncoding::Utf8, client.client_encoding());
+        assert_eq!(0, client.protocol_minor_version());
+        assert_eq!(results::FlushPolicy::default(), client.flush_policy());
+        assert!(client.cancel_handle().is_none());
+        assert!(!client.cancellation_token().is_cancelled());
+        assert!(client.notification_sink().is_none());
+        assert!(client.send_memory().is_none());
+        assert!(client.sql_comment().is_none());
+        assert!(client.tls_info().is_none());
+        assert!(client.client_certificates().is_none());
+    }
+}
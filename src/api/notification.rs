@@ -0,0 +1,79 @@
+//! Asynchronous `NotificationResponse`, for implementing `LISTEN`/`NOTIFY`.
+//!
+//! Each connection has a `NotificationSink`, available from
+//! `ClientInfo::notification_sink`. Server code keeps a clone of the sink,
+//! for example in a map from channel name to listening sessions when handling
+//! `LISTEN`, and pushes notifications to it at any time. Like postgres,
+//! notifications are delivered to client when the session is idle, those
+//! arrived during a query are held until the query completes.
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+
+use crate::messages::response::NotificationResponse;
+
+/// Handle to push notifications to a connected client.
+#[derive(Debug, Clone)]
+pub struct NotificationSink {
+    sender: UnboundedSender<NotificationResponse>,
+}
+
+/// Receiving side of a `NotificationSink`, polled by the connection.
+pub type NotificationReceiver = UnboundedReceiver<NotificationResponse>;
+
+impl NotificationSink {
+    /// Create a sink and the receiver to be polled by the connection.
+    pub fn channel() -> (NotificationSink, NotificationReceiver) {
+        let (sender, receiver) = unbounded();
+        (NotificationSink { sender }, receiver)
+    }
+
+    /// Queue a notification for the client. Returns false if the client has
+    /// disconnected, the sink can be dropped then.
+    pub fn send(&self, notification: NotificationResponse) -> bool {
+        self.sender.unbounded_send(notification).is_ok()
+    }
+
+    /// Queue a notification on `channel`, `pid` is the process id of the
+    /// notifying session.
+    pub fn notify(&self, pid: i32, channel: &str, payload: &str) -> bool {
+        self.send(NotificationResponse::new(
+            pid,
+            channel.to_owned(),
+            payload.to_owned(),
+        ))
+    }
+
+    /// Returns true if the client has disconnected.
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_notification_sink() {
+        let (sink, mut receiver) = NotificationSink::channel();
+        let other = sink.clone();
+        assert!(sink.notify(1, "jobs", "1"));
+        assert!(other.notify(2, "jobs", "2"));
+
+        assert_eq!(
+            Some(NotificationResponse::new(
+                1,
+                "jobs".to_owned(),
+                "1".to_owned()
+            )),
+            receiver.next().await
+        );
+        assert_eq!("2", receiver.next().await.unwrap().payload);
+
+        drop(receiver);
+        assert!(sink.is_closed());
+        assert!(!sink.notify(1, "jobs", "3"));
+    }
+}
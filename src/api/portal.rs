@@ -1,15 +1,25 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use bytes::Bytes;
-use postgres_types::FromSqlOwned;
+use bytes::{Bytes, BytesMut};
+use futures::stream::BoxStream;
+use postgres_types::{FromSqlOwned, Kind};
 
 use crate::{
     api::Type,
-    error::{PgWireError, PgWireResult},
-    messages::{data::FORMAT_CODE_BINARY, extendedquery::Bind},
+    error::{ErrorInfo, PgWireError, PgWireResult, SqlState},
+    messages::{
+        data::{DataRow, FORMAT_CODE_BINARY, FORMAT_CODE_TEXT},
+        extendedquery::Bind,
+    },
+    types::{binary::binary_to_text, FromSqlText},
 };
 
-use super::{results::FieldFormat, stmt::StoredStatement, DEFAULT_NAME};
+use super::{
+    memory::SendMemoryReservation,
+    results::{FieldFormat, FieldInfo, Flusher, ResultLimits},
+    stmt::{QueryParser, StoredStatement},
+    DEFAULT_NAME,
+};
 
 /// Represent a prepared sql statement and its parameters bound by a `Bind`
 /// request.
@@ -21,9 +31,38 @@ pub struct Portal<S> {
     pub parameter_format: Format,
     pub parameters: Vec<Option<Bytes>>,
     pub result_column_format: Format,
+    suspended: Arc<Mutex<Option<SuspendedResult>>>,
+}
+
+/// Rest of the result of a portal suspended by `Execute` with row limit.
+pub(crate) struct SuspendedResult {
+    pub(crate) command_tag: String,
+    pub(crate) data_rows: BoxStream<'static, PgWireResult<DataRow>>,
+    pub(crate) flusher: Flusher,
+    pub(crate) limits: ResultLimits,
+    // rows and bytes sent by previous `Execute`s, checked against `limits`
+    pub(crate) rows: usize,
+    pub(crate) bytes: usize,
+    // memory of rows buffered from a stream borrowing the handler, if the
+    // session accounts it
+    pub(crate) reservation: Option<SendMemoryReservation>,
+}
+
+impl std::fmt::Debug for SuspendedResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SuspendedResult")
+            .field("command_tag", &self.command_tag)
+            .field("rows", &self.rows)
+            .field("bytes", &self.bytes)
+            .finish_non_exhaustive()
+    }
 }
 
-#[derive(Debug, Clone, Default)]
+/// Formats of parameters or result columns requested by `Bind`.
+///
+/// Like postgres, no format code means text for all, a single code applies
+/// to all, and otherwise there is a code for each one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub enum Format {
     #[default]
     UnifiedText,
@@ -42,12 +81,15 @@ impl From<i16> for Format {
 }
 
 impl Format {
-    /// Get format code for given index
+    /// Get format code for given index, text for an index without code.
     pub fn format_for(&self, idx: usize) -> FieldFormat {
         match self {
             Format::UnifiedText => FieldFormat::Text,
             Format::UnifiedBinary => FieldFormat::Binary,
-            Format::Individual(ref fv) => FieldFormat::from(fv[idx]),
+            Format::Individual(fv) if fv.len() == 1 => FieldFormat::from(fv[0]),
+            Format::Individual(fv) => fv
+                .get(idx)
+                .map_or(FieldFormat::Text, |f| FieldFormat::from(*f)),
         }
     }
 
@@ -61,6 +103,42 @@ impl Format {
         self.format_for(idx) == FieldFormat::Binary
     }
 
+    /// Check there is a code for each of `columns` result columns, unless a
+    /// single code applies to all.
+    pub fn check_columns(&self, columns: usize) -> PgWireResult<()> {
+        match self {
+            Format::Individual(codes) if codes.len() > 1 && codes.len() != columns => {
+                Err(format_error(format!(
+                    "bind message has {} result formats but query has {columns} columns",
+                    codes.len()
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // format of `codes` for `count` values, failing on unknown codes or a
+    // count mismatch
+    fn try_from_codes(codes: &[i16], count: Option<usize>, what: &str) -> PgWireResult<Self> {
+        if let Some(code) = codes
+            .iter()
+            .find(|c| **c != FORMAT_CODE_TEXT && **c != FORMAT_CODE_BINARY)
+        {
+            return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                "ERROR".to_owned(),
+                SqlState::INVALID_PARAMETER_VALUE.into(),
+                format!("unsupported format code: {code}"),
+            ))));
+        }
+        match count {
+            Some(count) if codes.len() > 1 && codes.len() != count => Err(format_error(format!(
+                "bind message has {} {what} formats but {count} {what}s",
+                codes.len()
+            ))),
+            _ => Ok(Format::from_codes(codes)),
+        }
+    }
+
     fn from_codes(codes: &[i16]) -> Self {
         if codes.is_empty() {
             Format::UnifiedText
@@ -72,19 +150,59 @@ impl Format {
     }
 }
 
+fn format_error(message: String) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        SqlState::PROTOCOL_VIOLATION.into(),
+        message,
+    )))
+}
+
+impl<S> Portal<S> {
+    /// Returns true if the portal has rows left from a previous `Execute`
+    /// with row limit.
+    pub fn is_suspended(&self) -> bool {
+        self.suspended.lock().unwrap().is_some()
+    }
+
+    /// Result schema of the statement in result formats of the portal,
+    /// cached in the statement, see `StoredStatement::result_schema`.
+    pub fn result_schema<P>(&self, parser: &P) -> PgWireResult<Arc<Vec<FieldInfo>>>
+    where
+        P: QueryParser<Statement = S> + ?Sized,
+    {
+        self.statement
+            .result_schema(parser, Some(&self.result_column_format))
+    }
+
+    pub(crate) fn suspend(&self, result: SuspendedResult) {
+        *self.suspended.lock().unwrap() = Some(result);
+    }
+
+    pub(crate) fn take_suspended(&self) -> Option<SuspendedResult> {
+        self.suspended.lock().unwrap().take()
+    }
+}
+
 impl<S: Clone> Portal<S> {
-    /// Try to create portal from bind command and current client state
+    /// Try to create portal from bind command and current client state.
+    ///
+    /// Fails like postgres if format codes are unknown, or parameter format
+    /// codes don't match parameters. Result format codes are checked against
+    /// columns when the portal is described or executed.
     pub fn try_new(bind: &Bind, statement: Arc<StoredStatement<S>>) -> PgWireResult<Self> {
         let portal_name = bind
             .portal_name
             .clone()
             .unwrap_or_else(|| DEFAULT_NAME.to_owned());
 
-        // param format
-        let param_format = Format::from_codes(&bind.parameter_format_codes);
-
-        // format
-        let result_format = Format::from_codes(&bind.result_column_format_codes);
+        let param_format = Format::try_from_codes(
+            &bind.parameter_format_codes,
+            Some(bind.parameters.len()),
+            "parameter",
+        )?;
+        let result_format =
+            Format::try_from_codes(&bind.result_column_format_codes, None, "result")?;
 
         Ok(Portal {
             name: portal_name,
@@ -92,6 +210,7 @@ impl<S: Clone> Portal<S> {
             parameter_format: param_format,
             parameters: bind.parameters.clone(),
             result_column_format: result_format,
+            suspended: Arc::default(),
         })
     }
 
@@ -100,34 +219,48 @@ impl<S: Clone> Portal<S> {
         self.parameters.len()
     }
 
-    /// Attempt to get parameter at given index as type `T`.
+    /// Attempt to get parameter at given index as type `T`, `None` for null.
     ///
+    /// The parameter is decoded by its format code in `Bind`, with `FromSql`
+    /// for binary format and `FromSqlText` for text format. Arrays are
+    /// decoded into `Vec<T>`, or nested `Vec` for multi-dimensional arrays.
     pub fn parameter<T>(&self, idx: usize, pg_type: &Type) -> PgWireResult<Option<T>>
     where
-        T: FromSqlOwned,
+        T: FromSqlOwned + FromSqlText,
     {
-        if !T::accepts(pg_type) {
-            return Err(PgWireError::InvalidRustTypeForParameter(
-                pg_type.name().to_owned(),
-            ));
-        }
-
         let param = self
             .parameters
             .get(idx)
             .ok_or_else(|| PgWireError::ParameterIndexOutOfBound(idx))?;
+        let Some(param) = param else {
+            return Ok(None);
+        };
 
-        let _format = self.parameter_format.format_for(idx);
-
-        if let Some(ref param) = param {
-            // TODO: from_sql only works with binary format
-            // here we need to check format code first and seek to support text
-            T::from_sql(pg_type, param)
-                .map(|v| Some(v))
+        if self.parameter_format.is_binary(idx) {
+            if T::accepts(pg_type) {
+                return T::from_sql(pg_type, param)
+                    .map(Some)
+                    .map_err(PgWireError::FailedToParseParameter);
+            }
+            // `FromSql` only decodes one-dimensional arrays, parse nested
+            // `Vec` of multi-dimensional arrays from text instead
+            let mut text = BytesMut::new();
+            if !matches!(pg_type.kind(), Kind::Array(_))
+                || binary_to_text(pg_type, param, &mut text).is_err()
+            {
+                return Err(PgWireError::InvalidRustTypeForParameter(
+                    pg_type.name().to_owned(),
+                ));
+            }
+            T::from_sql_text(pg_type, &text)
+                .map(Some)
                 .map_err(PgWireError::FailedToParseParameter)
         } else {
-            // Null
-            Ok(None)
+            // text is parsed regardless of the type, which may be unknown if
+            // client asks for type inference
+            T::from_sql_text(pg_type, param)
+                .map(Some)
+                .map_err(PgWireError::FailedToParseParameter)
         }
     }
 }
@@ -145,4 +278,120 @@ mod tests {
             String::from_sql(&Type::UNKNOWN, "helloworld".as_bytes()).unwrap()
         )
     }
+
+    #[test]
+    fn test_parameter_formats() {
+        let statement = Arc::new(StoredStatement::new(
+            "s".to_owned(),
+            "SELECT $1, $2, $3".to_owned(),
+            vec![Type::INT4, Type::INT4, Type::TEXT],
+        ));
+        let bind = Bind::new(
+            None,
+            None,
+            vec![0, 1, 0],
+            vec![
+                Some(Bytes::from_static(b"42")),
+                Some(Bytes::copy_from_slice(&7i32.to_be_bytes())),
+                None,
+            ],
+            vec![],
+        );
+        let portal = Portal::try_new(&bind, statement).unwrap();
+        assert_eq!(Some(42), portal.parameter::<i32>(0, &Type::INT4).unwrap());
+        assert_eq!(Some(7), portal.parameter::<i32>(1, &Type::INT4).unwrap());
+        assert_eq!(None, portal.parameter::<String>(2, &Type::TEXT).unwrap());
+        assert!(portal.parameter::<String>(1, &Type::INT4).is_err());
+        assert!(portal.parameter::<i32>(3, &Type::INT4).is_err());
+    }
+
+    #[test]
+    fn test_result_formats() {
+        // a single code applies to all columns
+        let format = Format::from_codes(&[1]);
+        assert_eq!(FieldFormat::Binary, format.format_for(0));
+        assert_eq!(FieldFormat::Binary, format.format_for(3));
+        assert!(format.check_columns(4).is_ok());
+
+        let format = Format::from_codes(&[0, 1]);
+        assert_eq!(FieldFormat::Text, format.format_for(0));
+        assert_eq!(FieldFormat::Binary, format.format_for(1));
+        assert_eq!(FieldFormat::Text, format.format_for(2));
+        assert!(format.check_columns(2).is_ok());
+        let Err(PgWireError::UserError(error)) = format.check_columns(3) else {
+            panic!("expect format count mismatch");
+        };
+        assert_eq!("08P01", error.code);
+
+        let statement = Arc::new(StoredStatement::new(
+            "s".to_owned(),
+            "SELECT $1, $2".to_owned(),
+            vec![Type::INT4, Type::INT4],
+        ));
+        let parameters = vec![Some(Bytes::from_static(b"1")), None];
+        let bind = |parameter_formats, result_formats| {
+            Bind::new(
+                None,
+                None,
+                parameter_formats,
+                parameters.clone(),
+                result_formats,
+            )
+        };
+        let error = |bind| match Portal::try_new(&bind, statement.clone()) {
+            Err(PgWireError::UserError(error)) => error.code,
+            _ => panic!("expect bind error"),
+        };
+        assert_eq!("08P01", error(bind(vec![0, 0, 0], vec![])));
+        assert_eq!("22023", error(bind(vec![2], vec![])));
+        assert_eq!("22023", error(bind(vec![], vec![0, 3])));
+        let portal = Portal::try_new(&bind(vec![0], vec![0, 1, 1]), statement.clone()).unwrap();
+        assert_eq!(
+            Format::Individual(vec![0, 1, 1]),
+            portal.result_column_format
+        );
+    }
+
+    #[test]
+    fn test_array_parameters() {
+        let statement = Arc::new(StoredStatement::new(
+            "s".to_owned(),
+            "SELECT $1, $2, $3".to_owned(),
+            vec![Type::INT4_ARRAY, Type::INT4_ARRAY, Type::TEXT_ARRAY],
+        ));
+        let mut binary = BytesMut::new();
+        crate::types::binary::text_to_binary(&Type::INT4_ARRAY, b"{{1,2},{3,4}}", &mut binary)
+            .unwrap();
+        let bind = Bind::new(
+            None,
+            None,
+            vec![0, 1, 0],
+            vec![
+                Some(Bytes::from_static(b"{1,NULL,3}")),
+                Some(binary.freeze()),
+                Some(Bytes::from_static(br#"{"a,b",c}"#)),
+            ],
+            vec![],
+        );
+        let portal = Portal::try_new(&bind, statement).unwrap();
+        assert_eq!(
+            Some(vec![Some(1), None, Some(3)]),
+            portal
+                .parameter::<Vec<Option<i32>>>(0, &Type::INT4_ARRAY)
+                .unwrap()
+        );
+        assert_eq!(
+            Some(vec![vec![1, 2], vec![3, 4]]),
+            portal
+                .parameter::<Vec<Vec<i32>>>(1, &Type::INT4_ARRAY)
+                .unwrap()
+        );
+        assert_eq!(
+            Some(vec!["a,b".to_owned(), "c".to_owned()]),
+            portal
+                .parameter::<Vec<String>>(2, &Type::TEXT_ARRAY)
+                .unwrap()
+        );
+        assert!(portal.parameter::<Vec<i32>>(1, &Type::INT4_ARRAY).is_err());
+    }
 }
@@ -1,31 +1,39 @@
 use std::fmt::Debug;
 use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use futures::sink::{Sink, SinkExt};
-use futures::stream::StreamExt;
+use futures::stream::{self, StreamExt};
 
-use super::portal::Portal;
-use super::results::{into_row_description, Tag};
+use super::memory::SendMemory;
+use super::portal::{Portal, SuspendedResult};
+use super::results::{into_row_description, DataRows, Flusher, Tag};
 use super::stmt::{NoopQueryParser, QueryParser, StoredStatement};
-use super::store::PortalStore;
+use super::store::{PortalStore, PortalStoreListener};
+use super::Type;
 use super::{ClientInfo, ClientPortalStore, DEFAULT_NAME};
 use crate::api::results::{
-    DescribePortalResponse, DescribeResponse, DescribeStatementResponse, QueryResponse, Response,
+    CopyOutStream, CopyResponse, DescribePortalResponse, DescribeResponse,
+    DescribeStatementResponse, FieldFormat, QueryResponse, Response,
 };
-use crate::error::{PgWireError, PgWireResult};
-use crate::messages::data::{NoData, ParameterDescription};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, SqlState};
+use crate::messages::copy::{CopyData, CopyDone};
+use crate::messages::data::{DataRow, NoData, ParameterDescription, RowDescription};
 use crate::messages::extendedquery::{
-    Bind, BindComplete, Close, CloseComplete, Describe, Execute, Parse, ParseComplete,
-    Sync as PgSync, TARGET_TYPE_BYTE_PORTAL, TARGET_TYPE_BYTE_STATEMENT,
+    Bind, BindComplete, Close, CloseComplete, Describe, Execute, Flush, Parse, ParseComplete,
+    PortalSuspended, Sync as PgSync, TARGET_TYPE_BYTE_PORTAL, TARGET_TYPE_BYTE_STATEMENT,
 };
-use crate::messages::response::{EmptyQueryResponse, ReadyForQuery, TransactionStatus};
+use crate::messages::response::{EmptyQueryResponse, ReadyForQuery};
 use crate::messages::simplequery::Query;
+use crate::messages::startup::ParameterStatus;
 use crate::messages::PgWireBackendMessage;
+use crate::sql::Lexer;
 
-fn is_empty_query(q: &str) -> bool {
-    let trimmed_query = q.trim();
-    trimmed_query == ";" || trimmed_query.is_empty()
+/// Whether a query has no statement, only whitespaces, comments or `;`.
+/// Postgres responds `EmptyQueryResponse` to such query.
+pub(crate) fn is_empty_query(q: &str) -> bool {
+    Lexer::new(q).all(|token| token.is_symbol(';'))
 }
 
 /// handler for processing simple query.
@@ -35,8 +43,11 @@ pub trait SimpleQueryHandler: Send + Sync {
     /// simple query. The default implementation calls `do_query` with the
     /// incoming query string.
     ///
-    /// This handle checks empty query by default, if the query string is empty
-    /// or `;`, it returns `EmptyQueryResponse` and does not call `self.do_query`.
+    /// This handle checks empty query by default, if the query string has no
+    /// statement, like an empty string, `;` or only comments, it returns
+    /// `EmptyQueryResponse` and does not call `self.do_query`. Connections
+    /// served by `tokio::process_socket` respond to empty queries before
+    /// calling this.
     async fn on_query<C>(&self, client: &mut C, query: Query) -> PgWireResult<()>
     where
         C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
@@ -52,30 +63,17 @@ pub trait SimpleQueryHandler: Send + Sync {
         } else {
             let resp = self.do_query(client, &query_string).await?;
             for r in resp {
-                match r {
-                    Response::EmptyQuery => {
-                        client
-                            .feed(PgWireBackendMessage::EmptyQueryResponse(EmptyQueryResponse))
-                            .await?;
-                    }
-                    Response::Query(results) => {
-                        send_query_response(client, results, true).await?;
-                    }
-                    Response::Execution(tag) => {
-                        send_execution_response(client, tag).await?;
-                    }
-                    Response::Error(e) => {
-                        client
-                            .feed(PgWireBackendMessage::ErrorResponse((*e).into()))
-                            .await?;
-                    }
+                if send_simple_query_response(client, r).await? {
+                    // `ReadyForQuery` is sent when copy is finished
+                    client.set_state(super::PgWireConnectionState::CopyInProgress(false));
+                    return Ok(());
                 }
             }
         }
 
         client
             .feed(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
-                TransactionStatus::Idle,
+                client.transaction_status(),
             )))
             .await?;
         client.flush().await?;
@@ -93,6 +91,11 @@ pub trait SimpleQueryHandler: Send + Sync {
         C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
         C::Error: Debug,
         PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>;
+
+    /// Statement timeout of `query`, overriding `timeout` of the session.
+    fn statement_timeout(&self, _query: &str, timeout: Option<Duration>) -> Option<Duration> {
+        timeout
+    }
 }
 
 #[async_trait]
@@ -103,6 +106,23 @@ pub trait ExtendedQueryHandler: Send + Sync {
     /// Get a reference to associated `QueryParser` implementation
     fn query_parser(&self) -> Arc<Self::QueryParser>;
 
+    /// Hooks on statements and portals removed from the store of each
+    /// session, by `Close` or eviction. Connections of `tokio` call it when
+    /// they are opened.
+    fn portal_store_listener(&self) -> Option<Arc<dyn PortalStoreListener<Self::Statement>>> {
+        None
+    }
+
+    /// Statement timeout of executing a portal of `statement`, overriding
+    /// `timeout` of the session.
+    fn statement_timeout(
+        &self,
+        _statement: &StoredStatement<Self::Statement>,
+        timeout: Option<Duration>,
+    ) -> Option<Duration> {
+        timeout
+    }
+
     /// Called when client sends `parse` command.
     ///
     /// The default implementation parsed query with `Self::QueryParser` and
@@ -118,7 +138,7 @@ pub trait ExtendedQueryHandler: Send + Sync {
         let stmt = StoredStatement::parse(&message, parser).await?;
         client.portal_store().put_statement(Arc::new(stmt));
         client
-            .send(PgWireBackendMessage::ParseComplete(ParseComplete::new()))
+            .feed(PgWireBackendMessage::ParseComplete(ParseComplete::new()))
             .await?;
 
         Ok(())
@@ -141,7 +161,7 @@ pub trait ExtendedQueryHandler: Send + Sync {
             let portal = Portal::try_new(&message, statement)?;
             client.portal_store().put_portal(Arc::new(portal));
             client
-                .send(PgWireBackendMessage::BindComplete(BindComplete::new()))
+                .feed(PgWireBackendMessage::BindComplete(BindComplete::new()))
                 .await?;
             Ok(())
         } else {
@@ -153,6 +173,9 @@ pub trait ExtendedQueryHandler: Send + Sync {
     ///
     /// The default implementation delegates the query to `self::do_query` and
     /// sends response messages according to `Response` from `self::do_query`.
+    /// When `max_rows` of `Execute` is reached, the portal is suspended and
+    /// the next `Execute` of it continues with the rest of rows, without
+    /// calling `self::do_query` again.
     ///
     /// Note that, different from `SimpleQueryHandler`, this implementation
     /// won't check empty query because it cannot understand parsed
@@ -166,25 +189,31 @@ pub trait ExtendedQueryHandler: Send + Sync {
     {
         let portal_name = message.name.as_deref().unwrap_or(DEFAULT_NAME);
         if let Some(portal) = client.portal_store().get_portal(portal_name) {
-            match self
-                .do_query(client, portal.as_ref(), message.max_rows as usize)
-                .await?
-            {
+            let max_rows = message.max_rows.max(0) as usize;
+            // continue the portal suspended by previous `Execute`
+            if let Some(suspended) = portal.take_suspended() {
+                return resume_portal(client, &portal, suspended, max_rows).await;
+            }
+            match self.do_query(client, portal.as_ref(), max_rows).await? {
                 Response::EmptyQuery => {
                     client
                         .feed(PgWireBackendMessage::EmptyQueryResponse(EmptyQueryResponse))
                         .await?;
                 }
                 Response::Query(results) => {
-                    send_query_response(client, results, false).await?;
+                    send_portal_query_response(client, &portal, results, max_rows).await?;
                 }
                 Response::Execution(tag) => {
                     send_execution_response(client, tag).await?;
                 }
-                Response::Error(err) => {
-                    client
-                        .send(PgWireBackendMessage::ErrorResponse((*err).into()))
-                        .await?;
+                // returned as error, so messages until `Sync` are skipped
+                Response::Error(err) => return Err(PgWireError::UserError(err)),
+                Response::CopyOut(copy) => {
+                    send_copy_out_response(client, copy).await?;
+                }
+                Response::CopyIn(copy) => {
+                    send_copy_in_response(client, copy).await?;
+                    client.set_state(super::PgWireConnectionState::CopyInProgress(true));
                 }
             }
 
@@ -196,7 +225,11 @@ pub trait ExtendedQueryHandler: Send + Sync {
 
     /// Called when client sends `describe` command.
     ///
-    /// The default implementation delegates the call to `self::do_describe`.
+    /// The default implementation delegates the call to
+    /// `self::do_describe_statement` or `self::do_describe_portal`. A
+    /// statement is described with `ParameterDescription` and columns in
+    /// text format, as result formats are unknown before `Bind`. A portal is
+    /// described with columns only, in result formats requested by `Bind`.
     async fn on_describe<C>(&self, client: &mut C, message: Describe) -> PgWireResult<()>
     where
         C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
@@ -209,7 +242,7 @@ pub trait ExtendedQueryHandler: Send + Sync {
             TARGET_TYPE_BYTE_STATEMENT => {
                 if let Some(stmt) = client.portal_store().get_statement(name) {
                     let describe_response = self.do_describe_statement(client, &stmt).await?;
-                    send_describe_response(client, &describe_response).await?;
+                    send_describe_statement_response(client, &describe_response).await?;
                 } else {
                     return Err(PgWireError::StatementNotFound(name.to_owned()));
                 }
@@ -217,7 +250,7 @@ pub trait ExtendedQueryHandler: Send + Sync {
             TARGET_TYPE_BYTE_PORTAL => {
                 if let Some(portal) = client.portal_store().get_portal(name) {
                     let describe_response = self.do_describe_portal(client, &portal).await?;
-                    send_describe_response(client, &describe_response).await?;
+                    send_describe_portal_response(client, &portal, &describe_response).await?;
                 } else {
                     return Err(PgWireError::PortalNotFound(name.to_owned()));
                 }
@@ -228,10 +261,26 @@ pub trait ExtendedQueryHandler: Send + Sync {
         Ok(())
     }
 
+    /// Called when client sends `flush` command.
+    ///
+    /// Responses of extended query messages are buffered until `Sync` or
+    /// `Flush`, so pipelining clients receive them in batches. The default
+    /// implementation flushes client buffer, without ending the implicit
+    /// transaction like `Sync` does.
+    async fn on_flush<C>(&self, client: &mut C, _message: Flush) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        client.flush().await?;
+        Ok(())
+    }
+
     /// Called when client sends `sync` command.
     ///
-    /// The default implementation flushes client buffer and sends
-    /// `READY_FOR_QUERY` response to client
+    /// The default implementation sends `READY_FOR_QUERY` response to
+    /// client and flushes client buffer
     async fn on_sync<C>(&self, client: &mut C, _message: PgSync) -> PgWireResult<()>
     where
         C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
@@ -240,7 +289,7 @@ pub trait ExtendedQueryHandler: Send + Sync {
     {
         client
             .send(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
-                TransactionStatus::Idle,
+                client.transaction_status(),
             )))
             .await?;
         client.flush().await?;
@@ -268,34 +317,52 @@ pub trait ExtendedQueryHandler: Send + Sync {
             _ => {}
         }
         client
-            .send(PgWireBackendMessage::CloseComplete(CloseComplete))
+            .feed(PgWireBackendMessage::CloseComplete(CloseComplete))
             .await?;
         Ok(())
     }
 
     /// Return resultset metadata without actually executing statement
+    ///
+    /// The default implementation gets parameter types and result columns
+    /// from `Self::QueryParser`, columns are cached in the statement.
     async fn do_describe_statement<C>(
         &self,
-        client: &mut C,
+        _client: &mut C,
         target: &StoredStatement<Self::Statement>,
     ) -> PgWireResult<DescribeStatementResponse>
     where
         C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
         C::PortalStore: PortalStore<Statement = Self::Statement>,
         C::Error: Debug,
-        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>;
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let parser = self.query_parser();
+        let parameters = parser.get_parameter_types(&target.statement, &target.parameter_types)?;
+        let fields = target.result_schema(parser.as_ref(), None)?;
+        Ok(DescribeStatementResponse::new(parameters, fields.to_vec()))
+    }
 
     /// Return resultset metadata without actually executing portal
+    ///
+    /// The default implementation gets result columns from
+    /// `Self::QueryParser`, in formats requested by `Bind`, cached in the
+    /// statement for portals of the same formats. `do_query` can share them
+    /// with `Portal::result_schema`.
     async fn do_describe_portal<C>(
         &self,
-        client: &mut C,
+        _client: &mut C,
         target: &Portal<Self::Statement>,
     ) -> PgWireResult<DescribePortalResponse>
     where
         C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
         C::PortalStore: PortalStore<Statement = Self::Statement>,
         C::Error: Debug,
-        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>;
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let fields = target.result_schema(self.query_parser().as_ref())?;
+        Ok(DescribePortalResponse::new(fields.to_vec()))
+    }
 
     /// This is the main implementation for query execution. Context has
     /// been provided:
@@ -316,6 +383,45 @@ pub trait ExtendedQueryHandler: Send + Sync {
         PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>;
 }
 
+/// Send one response of a simple query. Returns true if `COPY FROM STDIN` is
+/// started, which must be the last response.
+pub(crate) async fn send_simple_query_response<'a, C>(
+    client: &mut C,
+    response: Response<'a>,
+) -> PgWireResult<bool>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    match response {
+        Response::EmptyQuery => {
+            client
+                .feed(PgWireBackendMessage::EmptyQueryResponse(EmptyQueryResponse))
+                .await?;
+        }
+        Response::Query(results) => {
+            send_query_response(client, results, true).await?;
+        }
+        Response::Execution(tag) => {
+            send_execution_response(client, tag).await?;
+        }
+        Response::Error(e) => {
+            client
+                .feed(PgWireBackendMessage::ErrorResponse((*e).into()))
+                .await?;
+        }
+        Response::CopyOut(copy) => {
+            send_copy_out_response(client, copy).await?;
+        }
+        Response::CopyIn(copy) => {
+            send_copy_in_response(client, copy).await?;
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
 /// Helper function to send `QueryResponse` and optional `RowDescription` to client
 ///
 /// For most cases in extended query implementation, `send_describe` is set to
@@ -333,6 +439,14 @@ where
 {
     let command_tag = results.command_tag().to_owned();
     let row_schema = results.row_schema();
+    let mut flusher = Flusher::new(
+        results.flush_policy().unwrap_or(client.flush_policy()),
+        results.flush_handle(),
+    );
+    let limits = results
+        .result_limits()
+        .cloned()
+        .unwrap_or_else(|| client.result_limits());
     let mut data_rows = results.data_rows();
 
     // Simple query has row_schema in query response. For extended query,
@@ -340,20 +454,204 @@ where
     if send_describe {
         let row_desc = into_row_description(&row_schema);
         client
-            .send(PgWireBackendMessage::RowDescription(row_desc))
+            .feed(PgWireBackendMessage::RowDescription(row_desc))
             .await?;
     }
 
     let mut rows = 0;
+    let mut bytes = 0;
     while let Some(row) = data_rows.next().await {
-        let row = row?;
+        let row = row.map_err(row_stream_error)?;
         rows += 1;
-        client.feed(PgWireBackendMessage::DataRow(row)).await?;
+        bytes += row.data.len();
+        // the rest of the stream is dropped when limit exceeded
+        limits.check(rows, bytes)?;
+        feed_row(client, row, &mut flusher).await?;
     }
 
     let tag = Tag::new(&command_tag).with_rows(rows);
     client
-        .send(PgWireBackendMessage::CommandComplete(tag.into()))
+        .feed(PgWireBackendMessage::CommandComplete(tag.into()))
+        .await?;
+
+    Ok(())
+}
+
+/// Error of a row stream, which fails the query rather than the connection.
+///
+/// Rows sent before the error stand, and the result ends with the error
+/// instead of `CommandComplete`, like postgres. Errors other than
+/// `UserError` and `ApiError` are fatal when returned by handlers, they are
+/// sent as `ERROR` here so the session goes on.
+pub(crate) fn row_stream_error(error: PgWireError) -> PgWireError {
+    match error {
+        PgWireError::UserError(_) | PgWireError::ApiError(_) => error,
+        PgWireError::IoError(e) => PgWireError::UserError(Box::new(ErrorInfo::new(
+            "ERROR".to_owned(),
+            SqlState::IO_ERROR.into(),
+            e.to_string(),
+        ))),
+        error => {
+            let mut info = error.to_error_info();
+            info.severity = "ERROR".to_owned();
+            PgWireError::UserError(Box::new(info))
+        }
+    }
+}
+
+/// Send a `DataRow`, and flush when `flusher` decides to. Sending waits when
+/// write buffer is full, so the row stream is polled at the pace of client.
+async fn feed_row<C>(client: &mut C, row: DataRow, flusher: &mut Flusher) -> PgWireResult<()>
+where
+    C: Sink<PgWireBackendMessage> + Unpin,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    let len = row.data.len();
+    client.feed(PgWireBackendMessage::DataRow(row)).await?;
+    if flusher.on_row(len) {
+        client.flush().await?;
+    }
+    Ok(())
+}
+
+/// Helper function to send `QueryResponse` for `Execute` with row limit.
+///
+/// At most `max_rows` rows are sent, zero for no limit. If the limit is
+/// reached, `PortalSuspended` is sent instead of `CommandComplete`, and the
+/// row stream is kept in the portal, to be polled for more rows by the next
+/// `Execute`. As the stream of `QueryResponse::new` may borrow the handler,
+/// its rows after the first `max_rows` are buffered in memory instead, and
+/// accounted in `ClientInfo::send_memory`. Use `QueryResponse::new_owned`
+/// to avoid that.
+///
+/// Columns are converted to result formats requested by `Bind` of `portal`,
+/// if rows are encoded in other formats of the row schema.
+pub async fn send_portal_query_response<'a, C, S>(
+    client: &mut C,
+    portal: &Portal<S>,
+    results: QueryResponse<'a>,
+    max_rows: usize,
+) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    let columns = results.row_schema().len();
+    if columns > 0 {
+        portal.result_column_format.check_columns(columns)?;
+    }
+    let results = results.into_formats(&portal.result_column_format);
+    if max_rows == 0 {
+        return send_query_response(client, results, false).await;
+    }
+
+    let command_tag = results.command_tag().to_owned();
+    let mut flusher = Flusher::new(
+        results.flush_policy().unwrap_or(client.flush_policy()),
+        results.flush_handle(),
+    );
+    let limits = results
+        .result_limits()
+        .cloned()
+        .unwrap_or_else(|| client.result_limits());
+    let mut data_rows = match results.into_data_rows() {
+        DataRows::Owned(data_rows) => {
+            let suspended = SuspendedResult {
+                command_tag,
+                data_rows,
+                flusher,
+                limits,
+                rows: 0,
+                bytes: 0,
+                reservation: None,
+            };
+            return resume_portal(client, portal, suspended, max_rows).await;
+        }
+        DataRows::Borrowed(data_rows) => data_rows,
+    };
+
+    let mut rows = 0;
+    let mut bytes = 0;
+    let mut sent_bytes = 0;
+    let mut remaining = Vec::new();
+    let mut reservation = client.send_memory().map(SendMemory::reservation);
+    while let Some(row) = data_rows.next().await {
+        let row = row.map_err(row_stream_error)?;
+        rows += 1;
+        bytes += row.data.len();
+        limits.check(rows, bytes)?;
+        if rows <= max_rows {
+            sent_bytes = bytes;
+            feed_row(client, row, &mut flusher).await?;
+        } else {
+            if let Some(reservation) = &mut reservation {
+                reservation.grow(row.data.len())?;
+            }
+            remaining.push(Ok(row));
+        }
+    }
+
+    if remaining.is_empty() {
+        let tag = Tag::new(&command_tag).with_rows(rows);
+        client
+            .feed(PgWireBackendMessage::CommandComplete(tag.into()))
+            .await?;
+    } else {
+        portal.suspend(SuspendedResult {
+            command_tag,
+            data_rows: stream::iter(remaining).boxed(),
+            flusher,
+            limits,
+            rows: max_rows,
+            bytes: sent_bytes,
+            reservation,
+        });
+        client
+            .feed(PgWireBackendMessage::PortalSuspended(PortalSuspended))
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Send at most `max_rows` rows of a suspended portal, zero for no limit,
+/// and keep it suspended if the limit is reached before the end of rows.
+async fn resume_portal<C, S>(
+    client: &mut C,
+    portal: &Portal<S>,
+    mut suspended: SuspendedResult,
+    max_rows: usize,
+) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    let mut count = 0;
+    while max_rows == 0 || count < max_rows {
+        let Some(row) = suspended.data_rows.next().await else {
+            // like postgres, the tag has number of rows sent by this `Execute`
+            let tag = Tag::new(&suspended.command_tag).with_rows(count);
+            client
+                .feed(PgWireBackendMessage::CommandComplete(tag.into()))
+                .await?;
+            return Ok(());
+        };
+        let row = row.map_err(row_stream_error)?;
+        if let Some(reservation) = &mut suspended.reservation {
+            reservation.shrink(row.data.len());
+        }
+        suspended.rows += 1;
+        suspended.bytes += row.data.len();
+        suspended.limits.check(suspended.rows, suspended.bytes)?;
+        feed_row(client, row, &mut suspended.flusher).await?;
+        count += 1;
+    }
+
+    portal.suspend(suspended);
+    client
+        .feed(PgWireBackendMessage::PortalSuspended(PortalSuspended))
         .await?;
 
     Ok(())
@@ -366,6 +664,117 @@ where
     C::Error: Debug,
     PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
 {
+    client
+        .feed(PgWireBackendMessage::CommandComplete(tag.into()))
+        .await?;
+
+    Ok(())
+}
+
+/// Helper function to send a `NoticeResponse`, like `RAISE NOTICE` in
+/// postgres. Handlers can call it at any time during the query, for example
+/// in `do_query` before returning results. Notices don't affect the query,
+/// use `ErrorInfo::notice` or `ErrorInfo::warning` to create one.
+pub async fn send_notice<C>(client: &mut C, notice: ErrorInfo) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    // buffered with the results, which are flushed at the end of query
+    client
+        .feed(PgWireBackendMessage::NoticeResponse(notice.into()))
+        .await?;
+
+    Ok(())
+}
+
+/// Helper function to send a `ParameterStatus`, reporting the new value of a
+/// parameter that drivers track, like `TimeZone` or `client_encoding`. Call
+/// it when a session parameter changes, for example when handling `SET`.
+/// Like notices, it can be sent at any time during the query.
+pub async fn send_parameter_status<C>(client: &mut C, name: &str, value: &str) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    client
+        .feed(PgWireBackendMessage::ParameterStatus(ParameterStatus::new(
+            name.to_owned(),
+            value.to_owned(),
+        )))
+        .await?;
+
+    Ok(())
+}
+
+/// Helper function to start `COPY FROM STDIN`.
+pub async fn send_copy_in_response<C>(client: &mut C, copy: CopyResponse) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    // client waits for this response before sending data
+    client
+        .send(PgWireBackendMessage::CopyInResponse(
+            copy.into_copy_in_response(),
+        ))
+        .await?;
+
+    Ok(())
+}
+
+/// Helper function to send data of `COPY TO STDOUT`, followed by `CopyDone`
+/// and `CommandComplete`.
+pub async fn send_copy_out_response<C>(
+    client: &mut C,
+    mut copy: CopyOutStream<'_>,
+) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    let header = copy.header();
+    let trailer = copy.trailer();
+    let (copy, mut data) = copy.into_parts();
+    client
+        .feed(PgWireBackendMessage::CopyOutResponse(
+            copy.into_copy_out_response(),
+        ))
+        .await?;
+    if let Some(header) = header {
+        client
+            .feed(PgWireBackendMessage::CopyData(CopyData::new(header)))
+            .await?;
+    }
+
+    let limits = client.result_limits();
+    let mut rows = 0;
+    let mut bytes = 0;
+    while let Some(chunk) = data.next().await {
+        let chunk = chunk?;
+        rows += 1;
+        bytes += chunk.len();
+        limits.check(rows, bytes)?;
+        // feed flushes when the write buffer is full, so a slow client
+        // slows down the stream
+        client
+            .feed(PgWireBackendMessage::CopyData(CopyData::new(chunk)))
+            .await?;
+    }
+
+    if let Some(trailer) = trailer {
+        client
+            .feed(PgWireBackendMessage::CopyData(CopyData::new(trailer)))
+            .await?;
+    }
+    client
+        .feed(PgWireBackendMessage::CopyDone(CopyDone))
+        .await?;
+    let tag = Tag::copy(rows);
     client
         .send(PgWireBackendMessage::CommandComplete(tag.into()))
         .await?;
@@ -384,20 +793,88 @@ where
     PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
     DR: DescribeResponse,
 {
-    if let Some(parameter_types) = describe_response.parameters() {
+    let row_desc = into_row_description(describe_response.fields());
+    feed_describe_response(
+        client,
+        describe_response.parameters(),
+        describe_response.is_no_data(),
+        row_desc,
+    )
+    .await
+}
+
+/// Helper function to send response for `Describe` of a prepared statement,
+/// `ParameterDescription` and `RowDescription`. Result formats are not known
+/// until `Bind`, so columns are described in text format like postgres.
+pub async fn send_describe_statement_response<C>(
+    client: &mut C,
+    describe_response: &DescribeStatementResponse,
+) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    let mut row_desc = into_row_description(&describe_response.fields);
+    for field in &mut row_desc.fields {
+        field.format_code = FieldFormat::Text.value();
+    }
+    feed_describe_response(
+        client,
+        describe_response.parameters(),
+        describe_response.is_no_data(),
+        row_desc,
+    )
+    .await
+}
+
+/// Helper function to send response for `Describe` of a portal, only
+/// `RowDescription`, in result formats requested by `Bind` of `portal`.
+pub async fn send_describe_portal_response<C, S>(
+    client: &mut C,
+    portal: &Portal<S>,
+    describe_response: &DescribePortalResponse,
+) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    if !describe_response.fields.is_empty() {
+        portal
+            .result_column_format
+            .check_columns(describe_response.fields.len())?;
+    }
+    let mut row_desc = into_row_description(&describe_response.fields);
+    for (idx, field) in row_desc.fields.iter_mut().enumerate() {
+        field.format_code = portal.result_column_format.format_for(idx).value();
+    }
+    feed_describe_response(client, None, describe_response.is_no_data(), row_desc).await
+}
+
+async fn feed_describe_response<C>(
+    client: &mut C,
+    parameter_types: Option<&[Type]>,
+    no_data: bool,
+    row_desc: RowDescription,
+) -> PgWireResult<()>
+where
+    C: Sink<PgWireBackendMessage> + Unpin,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    if let Some(parameter_types) = parameter_types {
         // parameter type inference
         client
-            .send(PgWireBackendMessage::ParameterDescription(
+            .feed(PgWireBackendMessage::ParameterDescription(
                 ParameterDescription::new(parameter_types.iter().map(|t| t.oid()).collect()),
             ))
             .await?;
     }
-    if describe_response.is_no_data() {
-        client.send(PgWireBackendMessage::NoData(NoData)).await?;
+    if no_data {
+        client.feed(PgWireBackendMessage::NoData(NoData)).await?;
     } else {
-        let row_desc = into_row_description(describe_response.fields());
         client
-            .send(PgWireBackendMessage::RowDescription(row_desc))
+            .feed(PgWireBackendMessage::RowDescription(row_desc))
             .await?;
     }
 
@@ -453,3 +930,1277 @@ impl ExtendedQueryHandler for PlaceholderExtendedQueryHandler {
         unimplemented!("Extended Query is not implemented on this server.")
     }
 }
+
+/// Extended query for servers whose statements are SQL strings, which is
+/// easier to implement than `ExtendedQueryHandler`.
+///
+/// Wrap the implementation in `DefaultExtendedQueryHandler`, which manages
+/// named statements and portals in the client's `PortalStore`, and handles
+/// `Parse`, `Bind`, `Describe`, `Execute`, `Close` and empty queries.
+#[async_trait]
+pub trait PreparedQueryHandler: Send + Sync {
+    /// Return parameter types and result columns of a query without
+    /// executing it. `parameter_types` are types specified by client in
+    /// `Parse`, it can be shorter than number of parameters in the query,
+    /// or contain `Type::UNKNOWN` for parameters to be inferred.
+    async fn do_describe<C>(
+        &self,
+        client: &mut C,
+        query: &str,
+        parameter_types: &[Type],
+    ) -> PgWireResult<DescribeStatementResponse>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>;
+
+    /// Execute the query of a portal. Parameters and requested result
+    /// formats are available in `portal`, with
+    /// `portal.result_column_format.format_for(idx)` for each column. Rows
+    /// encoded in other formats of the row schema are converted before they
+    /// are sent.
+    async fn do_query_extended<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        portal: &'a Portal<String>,
+        max_rows: usize,
+    ) -> PgWireResult<Response<'a>>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>;
+}
+
+/// `ExtendedQueryHandler` built on a `PreparedQueryHandler`.
+#[derive(Debug, new)]
+pub struct DefaultExtendedQueryHandler<H> {
+    handler: Arc<H>,
+    #[new(value = "Arc::new(NoopQueryParser)")]
+    parser: Arc<NoopQueryParser>,
+}
+
+#[async_trait]
+impl<H> ExtendedQueryHandler for DefaultExtendedQueryHandler<H>
+where
+    H: PreparedQueryHandler,
+{
+    type Statement = String;
+    type QueryParser = NoopQueryParser;
+
+    fn query_parser(&self) -> Arc<Self::QueryParser> {
+        self.parser.clone()
+    }
+
+    async fn do_describe_statement<C>(
+        &self,
+        client: &mut C,
+        target: &StoredStatement<Self::Statement>,
+    ) -> PgWireResult<DescribeStatementResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        if is_empty_query(&target.statement) {
+            return Ok(DescribeStatementResponse::no_data());
+        }
+        let mut response = self
+            .handler
+            .do_describe(client, &target.statement, &target.parameter_types)
+            .await?;
+        // parameters must be described, even if the handler doesn't infer them
+        if response.parameters.is_empty() {
+            response.parameters = target.parameter_types.clone();
+        }
+        Ok(response)
+    }
+
+    async fn do_describe_portal<C>(
+        &self,
+        client: &mut C,
+        target: &Portal<Self::Statement>,
+    ) -> PgWireResult<DescribePortalResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let statement = &target.statement;
+        if is_empty_query(&statement.statement) {
+            return Ok(DescribePortalResponse::no_data());
+        }
+        let response = self
+            .handler
+            .do_describe(client, &statement.statement, &statement.parameter_types)
+            .await?;
+        // columns are returned in formats requested by `Bind`
+        let fields = response
+            .fields
+            .into_iter()
+            .enumerate()
+            .map(|(idx, field)| {
+                let format = target.result_column_format.format_for(idx);
+                field.with_format(format)
+            })
+            .collect();
+        Ok(DescribePortalResponse::new(fields))
+    }
+
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        portal: &'a Portal<Self::Statement>,
+        max_rows: usize,
+    ) -> PgWireResult<Response<'a>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        if is_empty_query(&portal.statement.statement) {
+            return Ok(Response::EmptyQuery);
+        }
+        self.handler
+            .do_query_extended(client, portal, max_rows)
+            .await
+    }
+}
+
+/// Split a query string into statements by `;`, aware of string literals,
+/// quoted identifiers, dollar-quoted strings and comments. Statements are
+/// trimmed, and empty ones are skipped.
+pub fn split_statements(query: &str) -> Vec<&str> {
+    crate::sql::split_statements(query)
+}
+
+/// `SimpleQueryHandler` running each statement of a multi-statement query
+/// with `do_query` of the wrapped handler, which sees a single statement
+/// at a time.
+///
+/// Like postgres, statements after a failed one are not run: the error is
+/// sent after the responses of statements before it. Unlike postgres, the
+/// statements are not run in an implicit transaction. `on_query` of the
+/// wrapped handler is not called.
+#[derive(Debug, new)]
+pub struct MultiStatementQueryHandler<H> {
+    handler: Arc<H>,
+}
+
+#[async_trait]
+impl<H> SimpleQueryHandler for MultiStatementQueryHandler<H>
+where
+    H: SimpleQueryHandler,
+{
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        query: &'a str,
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let mut responses = Vec::new();
+        for statement in split_statements(query) {
+            match self.handler.do_query(client, statement).await {
+                Ok(statement_responses) => {
+                    let failed = statement_responses
+                        .iter()
+                        .any(|response| matches!(response, Response::Error(_)));
+                    responses.extend(statement_responses);
+                    if failed {
+                        break;
+                    }
+                }
+                // nothing is sent yet, the error is handled as usual
+                Err(e) if responses.is_empty() => return Err(e),
+                Err(e) => {
+                    responses.push(Response::Error(Box::new(e.to_error_info())));
+                    break;
+                }
+            }
+        }
+        Ok(responses)
+    }
+
+    fn statement_timeout(&self, query: &str, timeout: Option<Duration>) -> Option<Duration> {
+        self.handler.statement_timeout(query, timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use bytes::Bytes;
+    use futures::stream;
+
+    use super::*;
+    use crate::api::mock::MockClient;
+    use crate::api::portal::Format;
+    use crate::api::results::{
+        DataRowEncoder, FieldFormat, FieldInfo, FlushHandle, FlushPolicy, ResultLimits,
+    };
+    use crate::api::Type;
+    use crate::messages::response::TransactionStatus;
+
+    fn query_response(rows: usize) -> QueryResponse<'static> {
+        let schema = Arc::new(vec![FieldInfo::new(
+            "id".to_owned(),
+            None,
+            None,
+            Type::INT4,
+            FieldFormat::Text,
+        )]);
+        let data = (0..rows).map(|i| {
+            let mut encoder = DataRowEncoder::new(schema.clone());
+            encoder.encode_field(&(i as i32))?;
+            encoder.finish()
+        });
+        QueryResponse::new(schema.clone(), stream::iter(data.collect::<Vec<_>>()))
+    }
+
+    fn count_rows(client: &MockClient) -> usize {
+        client
+            .sent
+            .iter()
+            .filter(|m| matches!(m, PgWireBackendMessage::DataRow(_)))
+            .count()
+    }
+
+    struct EchoQueryHandler;
+
+    #[async_trait]
+    impl PreparedQueryHandler for EchoQueryHandler {
+        async fn do_describe<C>(
+            &self,
+            _client: &mut C,
+            _query: &str,
+            _parameter_types: &[Type],
+        ) -> PgWireResult<DescribeStatementResponse>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            Ok(DescribeStatementResponse::new(
+                vec![Type::INT4],
+                vec![FieldInfo::new(
+                    "id".to_owned(),
+                    None,
+                    None,
+                    Type::INT4,
+                    FieldFormat::Text,
+                )],
+            ))
+        }
+
+        async fn do_query_extended<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            portal: &'a Portal<String>,
+            _max_rows: usize,
+        ) -> PgWireResult<Response<'a>>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            let id = portal.parameter::<i32>(0, &Type::INT4)?;
+            let schema = Arc::new(vec![FieldInfo::new(
+                "id".to_owned(),
+                None,
+                None,
+                Type::INT4,
+                portal.result_column_format.format_for(0),
+            )]);
+            let mut encoder = DataRowEncoder::new(schema.clone());
+            encoder.encode_field(&id)?;
+            let rows = vec![encoder.finish()];
+            Ok(Response::Query(QueryResponse::new(
+                schema,
+                stream::iter(rows),
+            )))
+        }
+    }
+
+    struct StatementQueryHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for StatementQueryHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            match query {
+                "fail" => Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                    "ERROR".to_owned(),
+                    "42601".to_owned(),
+                    "syntax error".to_owned(),
+                )))),
+                query => Ok(vec![Response::Execution(Tag::new(query))]),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multi_statement_query_handler() {
+        let handler = MultiStatementQueryHandler::new(Arc::new(StatementQueryHandler));
+        let mut client = MockClient::new();
+        let query = Query::new("BEGIN; SELECT ';'; fail; COMMIT".to_owned());
+        handler.on_query(&mut client, query).await.unwrap();
+
+        let sent: Vec<_> = client
+            .sent
+            .iter()
+            .map(|message| match message {
+                PgWireBackendMessage::CommandComplete(complete) => complete.tag.clone(),
+                PgWireBackendMessage::ErrorResponse(_) => "error".to_owned(),
+                PgWireBackendMessage::ReadyForQuery(_) => "ready".to_owned(),
+                message => panic!("unexpected {message:?}"),
+            })
+            .collect();
+        assert_eq!(vec!["BEGIN", "SELECT ';'", "error", "ready"], sent);
+
+        // an error of the first statement is returned as is
+        let query = Query::new("fail; SELECT 1".to_owned());
+        assert!(handler.on_query(&mut client, query).await.is_err());
+    }
+
+    #[test]
+    fn test_is_empty_query() {
+        for query in ["", "  \n", ";", " ; ;", "-- comment", "/* a */ ; -- b\n"] {
+            assert!(is_empty_query(query), "{query:?}");
+        }
+        for query in ["SELECT 1", "; SELECT 1", "'--'"] {
+            assert!(!is_empty_query(query), "{query:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_default_extended_query_handler() {
+        let handler = DefaultExtendedQueryHandler::new(Arc::new(EchoQueryHandler));
+        let mut client = MockClient::new();
+
+        handler
+            .on_parse(
+                &mut client,
+                Parse::new(Some("s1".to_owned()), "SELECT $1".to_owned(), vec![]),
+            )
+            .await
+            .unwrap();
+        handler
+            .on_describe(
+                &mut client,
+                Describe::new(TARGET_TYPE_BYTE_STATEMENT, Some("s1".to_owned())),
+            )
+            .await
+            .unwrap();
+        handler
+            .on_bind(
+                &mut client,
+                Bind::new(
+                    Some("p1".to_owned()),
+                    Some("s1".to_owned()),
+                    vec![1],
+                    vec![Some(Bytes::copy_from_slice(&42i32.to_be_bytes()))],
+                    vec![1],
+                ),
+            )
+            .await
+            .unwrap();
+        handler
+            .on_describe(
+                &mut client,
+                Describe::new(TARGET_TYPE_BYTE_PORTAL, Some("p1".to_owned())),
+            )
+            .await
+            .unwrap();
+        handler
+            .on_execute(&mut client, Execute::new(Some("p1".to_owned()), 0))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            client.sent[0],
+            PgWireBackendMessage::ParseComplete(_)
+        ));
+        assert!(matches!(
+            client.sent[1],
+            PgWireBackendMessage::ParameterDescription(ref desc) if desc.types == vec![Type::INT4.oid()]
+        ));
+        assert!(matches!(
+            client.sent[2],
+            PgWireBackendMessage::RowDescription(_)
+        ));
+        assert!(matches!(
+            client.sent[3],
+            PgWireBackendMessage::BindComplete(_)
+        ));
+        // binary format is requested in bind
+        let PgWireBackendMessage::RowDescription(ref desc) = client.sent[4] else {
+            panic!("expected RowDescription");
+        };
+        assert_eq!(1, desc.fields[0].format_code);
+        let PgWireBackendMessage::DataRow(ref row) = client.sent[5] else {
+            panic!("expected DataRow");
+        };
+        assert_eq!(&42i32.to_be_bytes()[..], &row.data[4..]);
+
+        // empty query is handled without calling the handler
+        handler
+            .on_parse(&mut client, Parse::new(None, " ".to_owned(), vec![]))
+            .await
+            .unwrap();
+        handler
+            .on_bind(&mut client, Bind::new(None, None, vec![], vec![], vec![]))
+            .await
+            .unwrap();
+        handler
+            .on_execute(&mut client, Execute::new(None, 0))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.sent.last(),
+            Some(PgWireBackendMessage::EmptyQueryResponse(_))
+        ));
+    }
+
+    struct RowsQueryHandler(usize);
+
+    #[async_trait]
+    impl PreparedQueryHandler for RowsQueryHandler {
+        async fn do_describe<C>(
+            &self,
+            _client: &mut C,
+            _query: &str,
+            _parameter_types: &[Type],
+        ) -> PgWireResult<DescribeStatementResponse>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            Ok(DescribeStatementResponse::new(
+                vec![],
+                query_response(0).row_schema().to_vec(),
+            ))
+        }
+
+        async fn do_query_extended<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            _portal: &'a Portal<String>,
+            _max_rows: usize,
+        ) -> PgWireResult<Response<'a>>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            Ok(Response::Query(query_response(self.0)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_portal_suspended() {
+        let handler = DefaultExtendedQueryHandler::new(Arc::new(RowsQueryHandler(5)));
+        let mut client = MockClient::new();
+        handler
+            .on_parse(
+                &mut client,
+                Parse::new(None, "SELECT id".to_owned(), vec![]),
+            )
+            .await
+            .unwrap();
+        handler
+            .on_bind(&mut client, Bind::new(None, None, vec![], vec![], vec![]))
+            .await
+            .unwrap();
+        client.sent.clear();
+
+        for _ in 0..3 {
+            handler
+                .on_execute(&mut client, Execute::new(None, 2))
+                .await
+                .unwrap();
+        }
+        let complete = |message: &PgWireBackendMessage| match message {
+            PgWireBackendMessage::CommandComplete(complete) => Some(complete.tag.clone()),
+            _ => None,
+        };
+        // 2 rows, suspended, 2 rows, suspended, the last row
+        assert_eq!(8, client.sent.len());
+        assert_eq!(5, count_rows(&client));
+        assert!(matches!(
+            client.sent[2],
+            PgWireBackendMessage::PortalSuspended(_)
+        ));
+        assert!(matches!(
+            client.sent[5],
+            PgWireBackendMessage::PortalSuspended(_)
+        ));
+        assert_eq!(Some("SELECT 1".to_owned()), complete(&client.sent[7]));
+        let portal = client.portal_store().get_portal(DEFAULT_NAME).unwrap();
+        assert!(!portal.is_suspended());
+
+        // the query is executed again after the portal is completed, and all
+        // rows are sent without row limit
+        client.sent.clear();
+        handler
+            .on_execute(&mut client, Execute::new(None, 0))
+            .await
+            .unwrap();
+        assert_eq!(5, count_rows(&client));
+        assert_eq!(Some("SELECT 5".to_owned()), complete(&client.sent[5]));
+    }
+
+    #[tokio::test]
+    async fn test_portal_suspended_memory() {
+        let handler = DefaultExtendedQueryHandler::new(Arc::new(RowsQueryHandler(5)));
+        let mut client = MockClient::new();
+        // each row is 5 bytes
+        let memory = SendMemory::new(Some(15));
+        client.info.send_memory = Some(memory.clone());
+        handler
+            .on_parse(
+                &mut client,
+                Parse::new(None, "SELECT id".to_owned(), vec![]),
+            )
+            .await
+            .unwrap();
+        let bind = || Bind::new(None, None, vec![], vec![], vec![]);
+        handler.on_bind(&mut client, bind()).await.unwrap();
+
+        handler
+            .on_execute(&mut client, Execute::new(None, 2))
+            .await
+            .unwrap();
+        assert_eq!(15, memory.used());
+        handler
+            .on_execute(&mut client, Execute::new(None, 2))
+            .await
+            .unwrap();
+        assert_eq!(5, memory.used());
+        // rebinding drops the suspended rows
+        handler.on_bind(&mut client, bind()).await.unwrap();
+        assert_eq!(0, memory.used());
+
+        // rest of the result exceeds the limit
+        let result = handler.on_execute(&mut client, Execute::new(None, 1)).await;
+        let Err(PgWireError::UserError(error)) = result else {
+            panic!("expect out of memory");
+        };
+        assert_eq!("53200", error.code);
+        assert_eq!(0, memory.used());
+    }
+
+    // rows of an owned stream, counting rows polled from it
+    struct LazyRowsQueryHandler {
+        rows: usize,
+        polled: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl PreparedQueryHandler for LazyRowsQueryHandler {
+        async fn do_describe<C>(
+            &self,
+            _client: &mut C,
+            _query: &str,
+            _parameter_types: &[Type],
+        ) -> PgWireResult<DescribeStatementResponse>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            Ok(DescribeStatementResponse::new(
+                vec![],
+                query_response(0).row_schema().to_vec(),
+            ))
+        }
+
+        async fn do_query_extended<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            _portal: &'a Portal<String>,
+            _max_rows: usize,
+        ) -> PgWireResult<Response<'a>>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            let schema = query_response(0).row_schema();
+            let polled = self.polled.clone();
+            let encoder_schema = schema.clone();
+            let data = stream::iter(0..self.rows).map(move |i| {
+                polled.fetch_add(1, Ordering::SeqCst);
+                let mut encoder = DataRowEncoder::new(encoder_schema.clone());
+                encoder.encode_field(&(i as i32))?;
+                encoder.finish()
+            });
+            Ok(Response::Query(QueryResponse::new_owned(schema, data)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_portal_suspended_lazy() {
+        let polled = Arc::new(AtomicUsize::new(0));
+        let handler = DefaultExtendedQueryHandler::new(Arc::new(LazyRowsQueryHandler {
+            rows: 10,
+            polled: polled.clone(),
+        }));
+        let mut client = MockClient::new();
+        client.set_result_limits(ResultLimits::new().with_max_rows(8));
+        handler
+            .on_parse(
+                &mut client,
+                Parse::new(None, "SELECT id".to_owned(), vec![]),
+            )
+            .await
+            .unwrap();
+        handler
+            .on_bind(&mut client, Bind::new(None, None, vec![], vec![], vec![]))
+            .await
+            .unwrap();
+
+        // the stream is not polled past `max_rows` of each `Execute`
+        for executed in [2, 4] {
+            handler
+                .on_execute(&mut client, Execute::new(None, 2))
+                .await
+                .unwrap();
+            assert_eq!(executed, polled.load(Ordering::SeqCst));
+            assert_eq!(executed, count_rows(&client));
+            assert!(matches!(
+                client.sent.last(),
+                Some(PgWireBackendMessage::PortalSuspended(_))
+            ));
+        }
+
+        // limits apply to rows of all `Execute`s of the portal
+        let result = handler.on_execute(&mut client, Execute::new(None, 0)).await;
+        let Err(PgWireError::UserError(error)) = result else {
+            panic!("expect limit exceeded");
+        };
+        assert_eq!("54000", error.code);
+        assert_eq!(9, polled.load(Ordering::SeqCst));
+        assert_eq!(8, count_rows(&client));
+        let portal = client.portal_store().get_portal(DEFAULT_NAME).unwrap();
+        assert!(!portal.is_suspended());
+    }
+
+    #[tokio::test]
+    async fn test_result_formats() {
+        let handler = DefaultExtendedQueryHandler::new(Arc::new(RowsQueryHandler(2)));
+        let mut client = MockClient::new();
+        handler
+            .on_parse(
+                &mut client,
+                Parse::new(None, "SELECT id".to_owned(), vec![]),
+            )
+            .await
+            .unwrap();
+
+        // rows of handler in text are converted to binary requested by
+        // the single code of `Bind`
+        for max_rows in [0, 1] {
+            handler
+                .on_bind(&mut client, Bind::new(None, None, vec![], vec![], vec![1]))
+                .await
+                .unwrap();
+            client.sent.clear();
+            handler
+                .on_execute(&mut client, Execute::new(None, max_rows))
+                .await
+                .unwrap();
+            let PgWireBackendMessage::DataRow(ref row) = client.sent[0] else {
+                panic!("expected DataRow");
+            };
+            assert_eq!(vec![Some(&0i32.to_be_bytes()[..])], row.fields().unwrap());
+        }
+
+        // more codes than columns
+        handler
+            .on_bind(
+                &mut client,
+                Bind::new(None, None, vec![], vec![], vec![0, 1]),
+            )
+            .await
+            .unwrap();
+        for result in [
+            handler
+                .on_describe(&mut client, Describe::new(TARGET_TYPE_BYTE_PORTAL, None))
+                .await,
+            handler.on_execute(&mut client, Execute::new(None, 0)).await,
+        ] {
+            let Err(PgWireError::UserError(error)) = result else {
+                panic!("expect format count mismatch");
+            };
+            assert_eq!("08P01", error.code);
+        }
+    }
+
+    struct InferringParser;
+
+    #[async_trait]
+    impl QueryParser for InferringParser {
+        type Statement = String;
+
+        async fn parse_sql(&self, sql: &str, _types: &[Type]) -> PgWireResult<Self::Statement> {
+            Ok(sql.to_owned())
+        }
+
+        fn get_parameter_types(
+            &self,
+            _stmt: &Self::Statement,
+            types: &[Type],
+        ) -> PgWireResult<Vec<Type>> {
+            Ok(types
+                .iter()
+                .map(|t| {
+                    if *t == Type::UNKNOWN {
+                        Type::INT4
+                    } else {
+                        t.clone()
+                    }
+                })
+                .collect())
+        }
+
+        fn get_result_schema(
+            &self,
+            _stmt: &Self::Statement,
+            format: Option<&Format>,
+        ) -> PgWireResult<Vec<FieldInfo>> {
+            let format = format.map(|f| f.format_for(0)).unwrap_or(FieldFormat::Text);
+            Ok(vec![FieldInfo::new(
+                "id".to_owned(),
+                None,
+                None,
+                Type::INT4,
+                format,
+            )])
+        }
+    }
+
+    struct ParserQueryHandler;
+
+    #[async_trait]
+    impl ExtendedQueryHandler for ParserQueryHandler {
+        type Statement = String;
+        type QueryParser = InferringParser;
+
+        fn query_parser(&self) -> Arc<Self::QueryParser> {
+            Arc::new(InferringParser)
+        }
+
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            _portal: &'a Portal<Self::Statement>,
+            _max_rows: usize,
+        ) -> PgWireResult<Response<'a>>
+        where
+            C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::PortalStore: PortalStore<Statement = Self::Statement>,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            Ok(Response::Execution(Tag::new("OK")))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_describe_with_query_parser() {
+        let handler = ParserQueryHandler;
+        let mut client = MockClient::new();
+        handler
+            .on_parse(
+                &mut client,
+                Parse::new(None, "SELECT $1, $2".to_owned(), vec![Type::TEXT.oid()]),
+            )
+            .await
+            .unwrap();
+        handler
+            .on_describe(&mut client, Describe::new(TARGET_TYPE_BYTE_STATEMENT, None))
+            .await
+            .unwrap();
+        handler
+            .on_bind(
+                &mut client,
+                Bind::new(None, None, vec![], vec![None, None], vec![1]),
+            )
+            .await
+            .unwrap();
+        handler
+            .on_describe(&mut client, Describe::new(TARGET_TYPE_BYTE_PORTAL, None))
+            .await
+            .unwrap();
+
+        // the parameter not in `Parse` is inferred by the parser
+        let PgWireBackendMessage::ParameterDescription(ref params) = client.sent[1] else {
+            panic!("expected ParameterDescription");
+        };
+        assert_eq!(vec![Type::TEXT.oid(), Type::INT4.oid()], params.types);
+        let PgWireBackendMessage::RowDescription(ref desc) = client.sent[2] else {
+            panic!("expected RowDescription");
+        };
+        assert_eq!(0, desc.fields[0].format_code);
+        let PgWireBackendMessage::RowDescription(ref desc) = client.sent[4] else {
+            panic!("expected RowDescription");
+        };
+        assert_eq!(1, desc.fields[0].format_code);
+    }
+
+    // describes columns in formats different from what client expects
+    struct BinaryDescribeHandler;
+
+    #[async_trait]
+    impl ExtendedQueryHandler for BinaryDescribeHandler {
+        type Statement = String;
+        type QueryParser = NoopQueryParser;
+
+        fn query_parser(&self) -> Arc<Self::QueryParser> {
+            Arc::new(NoopQueryParser)
+        }
+
+        async fn do_describe_statement<C>(
+            &self,
+            _client: &mut C,
+            _target: &StoredStatement<Self::Statement>,
+        ) -> PgWireResult<DescribeStatementResponse>
+        where
+            C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::PortalStore: PortalStore<Statement = Self::Statement>,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            let field =
+                FieldInfo::new("id".to_owned(), None, None, Type::INT4, FieldFormat::Binary);
+            Ok(DescribeStatementResponse::new(
+                vec![Type::INT4],
+                vec![field],
+            ))
+        }
+
+        async fn do_describe_portal<C>(
+            &self,
+            _client: &mut C,
+            _target: &Portal<Self::Statement>,
+        ) -> PgWireResult<DescribePortalResponse>
+        where
+            C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::PortalStore: PortalStore<Statement = Self::Statement>,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            let field = FieldInfo::new("id".to_owned(), None, None, Type::INT4, FieldFormat::Text);
+            Ok(DescribePortalResponse::new(vec![field]))
+        }
+
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            _portal: &'a Portal<Self::Statement>,
+            _max_rows: usize,
+        ) -> PgWireResult<Response<'a>>
+        where
+            C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::PortalStore: PortalStore<Statement = Self::Statement>,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            Ok(Response::Execution(Tag::new("OK")))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_describe_statement_and_portal() {
+        let handler = BinaryDescribeHandler;
+        let mut client = MockClient::new();
+        handler
+            .on_parse(
+                &mut client,
+                Parse::new(None, "SELECT $1".to_owned(), vec![]),
+            )
+            .await
+            .unwrap();
+        handler
+            .on_describe(&mut client, Describe::new(TARGET_TYPE_BYTE_STATEMENT, None))
+            .await
+            .unwrap();
+        handler
+            .on_bind(
+                &mut client,
+                Bind::new(None, None, vec![], vec![None], vec![1]),
+            )
+            .await
+            .unwrap();
+        handler
+            .on_describe(&mut client, Describe::new(TARGET_TYPE_BYTE_PORTAL, None))
+            .await
+            .unwrap();
+
+        // statement: parameters and columns in text format
+        assert!(matches!(
+            client.sent[1],
+            PgWireBackendMessage::ParameterDescription(_)
+        ));
+        let PgWireBackendMessage::RowDescription(ref desc) = client.sent[2] else {
+            panic!("expected RowDescription");
+        };
+        assert_eq!(0, desc.fields[0].format_code);
+        // portal: columns only, in format of `Bind`
+        assert!(matches!(
+            client.sent[3],
+            PgWireBackendMessage::BindComplete(_)
+        ));
+        let PgWireBackendMessage::RowDescription(ref desc) = client.sent[4] else {
+            panic!("expected RowDescription");
+        };
+        assert_eq!(1, desc.fields[0].format_code);
+        assert_eq!(5, client.sent.len());
+    }
+
+    #[tokio::test]
+    async fn test_describe_no_data() {
+        // a statement without rows still describes its parameters
+        let mut client = MockClient::new();
+        let response = DescribeStatementResponse::new(vec![Type::INT4], vec![]);
+        send_describe_statement_response(&mut client, &response)
+            .await
+            .unwrap();
+        let PgWireBackendMessage::ParameterDescription(ref params) = client.sent[0] else {
+            panic!("expected ParameterDescription");
+        };
+        assert_eq!(vec![Type::INT4.oid()], params.types);
+        assert!(matches!(client.sent[1], PgWireBackendMessage::NoData(_)));
+
+        let mut client = MockClient::new();
+        let response = DescribePortalResponse::new(vec![]);
+        send_describe_response(&mut client, &response)
+            .await
+            .unwrap();
+        assert!(matches!(client.sent[..], [PgWireBackendMessage::NoData(_)]));
+    }
+
+    #[tokio::test]
+    async fn test_copy_out() {
+        let mut client = MockClient::new();
+        let data = stream::iter(vec![
+            Ok(Bytes::from_static(b"1\ta\n")),
+            Ok(Bytes::from_static(b"2\tb\n")),
+        ]);
+        let copy = CopyOutStream::new(CopyResponse::new(FieldFormat::Text, 2), data);
+        send_copy_out_response(&mut client, copy).await.unwrap();
+
+        assert_eq!(5, client.sent.len());
+        assert!(matches!(
+            client.sent[0],
+            PgWireBackendMessage::CopyOutResponse(ref resp)
+                if resp.format == 0 && resp.column_formats == vec![0, 0]
+        ));
+        assert!(matches!(
+            client.sent[2],
+            PgWireBackendMessage::CopyData(ref data) if data.data.as_ref() == b"2\tb\n"
+        ));
+        assert!(matches!(client.sent[3], PgWireBackendMessage::CopyDone(_)));
+        let PgWireBackendMessage::CommandComplete(ref complete) = client.sent[4] else {
+            panic!("expected CommandComplete");
+        };
+        assert_eq!("COPY 2", complete.tag);
+
+        // header and trailer of binary format are not counted as rows
+        let mut client = MockClient::new();
+        let rows = query_response(1).data_rows();
+        send_copy_out_response(&mut client, CopyOutStream::binary(1, rows))
+            .await
+            .unwrap();
+        assert_eq!(6, client.sent.len());
+        assert!(matches!(
+            client.sent[0],
+            PgWireBackendMessage::CopyOutResponse(ref resp) if resp.format == 1
+        ));
+        assert!(matches!(
+            client.sent[1],
+            PgWireBackendMessage::CopyData(ref data) if data.data.starts_with(b"PGCOPY")
+        ));
+        let PgWireBackendMessage::CommandComplete(ref complete) = client.sent[5] else {
+            panic!("expected CommandComplete");
+        };
+        assert_eq!("COPY 1", complete.tag);
+    }
+
+    #[tokio::test]
+    async fn test_send_notice() {
+        let mut client = MockClient::new();
+        send_notice(&mut client, ErrorInfo::warning("deprecated".to_owned()))
+            .await
+            .unwrap();
+        send_execution_response(&mut client, Tag::new("DROP TABLE"))
+            .await
+            .unwrap();
+
+        let PgWireBackendMessage::NoticeResponse(ref notice) = client.sent[0] else {
+            panic!("expected NoticeResponse");
+        };
+        assert!(notice.fields.contains(&(b'S', "WARNING".to_owned())));
+        assert!(notice.fields.contains(&(b'M', "deprecated".to_owned())));
+        assert!(matches!(
+            client.sent[1],
+            PgWireBackendMessage::CommandComplete(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_send_parameter_status() {
+        let mut client = MockClient::new();
+        send_parameter_status(&mut client, "TimeZone", "Asia/Tokyo")
+            .await
+            .unwrap();
+        send_execution_response(&mut client, Tag::new("SET"))
+            .await
+            .unwrap();
+
+        let PgWireBackendMessage::ParameterStatus(ref status) = client.sent[0] else {
+            panic!("expected ParameterStatus");
+        };
+        assert_eq!("TimeZone", status.name);
+        assert_eq!("Asia/Tokyo", status.value);
+    }
+
+    #[tokio::test]
+    async fn test_result_limits() {
+        let mut client = MockClient::new();
+        client.set_result_limits(ResultLimits::new().with_max_rows(3));
+        send_query_response(&mut client, query_response(3), true)
+            .await
+            .unwrap();
+        assert_eq!(3, count_rows(&client));
+
+        let mut client = MockClient::new();
+        client.set_result_limits(ResultLimits::new().with_max_rows(3));
+        let err = send_query_response(&mut client, query_response(5), true)
+            .await
+            .unwrap_err();
+        let PgWireError::UserError(info) = err else {
+            panic!("expect user error");
+        };
+        assert_eq!("54000", info.code);
+        assert_eq!(3, count_rows(&client));
+
+        let mut client = MockClient::new();
+        client.set_result_limits(ResultLimits::new().with_max_bytes(10));
+        assert!(send_query_response(&mut client, query_response(5), true)
+            .await
+            .is_err());
+        assert!(count_rows(&client) < 5);
+
+        // limits of response override session, with a custom error
+        let mut client = MockClient::new();
+        client.set_result_limits(ResultLimits::new().with_max_rows(10));
+        let mut response = query_response(5);
+        response.set_result_limits(ResultLimits::new().with_max_rows(2).with_error(
+            ErrorInfo::new(
+                "ERROR".to_owned(),
+                "53400".to_owned(),
+                "too many".to_owned(),
+            ),
+        ));
+        let err = send_query_response(&mut client, response, true)
+            .await
+            .unwrap_err();
+        let PgWireError::UserError(info) = err else {
+            panic!("expect user error");
+        };
+        assert_eq!("53400", info.code);
+        assert_eq!("too many", info.message);
+        assert_eq!(2, count_rows(&client));
+
+        let mut client = MockClient::new();
+        client.set_result_limits(ResultLimits::new().with_max_rows(3));
+        let mut response = query_response(5);
+        response.set_result_limits(ResultLimits::default());
+        send_query_response(&mut client, response, true)
+            .await
+            .unwrap();
+        assert_eq!(5, count_rows(&client));
+    }
+
+    #[tokio::test]
+    async fn test_flush_policy() {
+        // RowDescription and 5 rows, flushed every 2 rows, CommandComplete
+        // waits for ReadyForQuery or Flush
+        let mut client = MockClient::new();
+        client.set_flush_policy(FlushPolicy::new().with_every_rows(2));
+        send_query_response(&mut client, query_response(5), true)
+            .await
+            .unwrap();
+        assert_eq!(vec![3, 5], client.flushed);
+
+        // policy of response overrides session
+        let mut client = MockClient::new();
+        let mut response = query_response(5);
+        response.set_flush_policy(FlushPolicy::new());
+        client.set_flush_policy(FlushPolicy::new().with_every_rows(1));
+        send_query_response(&mut client, response, false)
+            .await
+            .unwrap();
+        assert!(client.flushed.is_empty());
+
+        // flush requested by the row stream
+        let mut client = MockClient::new();
+        let response = query_response(5);
+        let handle = FlushHandle::default();
+        let stream_handle = handle.clone();
+        let schema = response.row_schema();
+        let rows = response.data_rows().enumerate().map(move |(i, row)| {
+            if i == 1 {
+                stream_handle.request();
+            }
+            row
+        });
+        let mut response = QueryResponse::new(schema, rows);
+        response.set_flush_handle(handle);
+        send_query_response(&mut client, response, false)
+            .await
+            .unwrap();
+        assert_eq!(vec![2], client.flushed);
+    }
+
+    #[tokio::test]
+    async fn test_transaction_status() {
+        struct TransactionQueryHandler;
+
+        #[async_trait]
+        impl SimpleQueryHandler for TransactionQueryHandler {
+            async fn do_query<'a, 'b: 'a, C>(
+                &'b self,
+                _client: &mut C,
+                query: &'a str,
+            ) -> PgWireResult<Vec<Response<'a>>>
+            where
+                C: ClientInfo + Unpin + Send + Sync,
+            {
+                if query == "SELECT 1/0" {
+                    Ok(vec![Response::Error(Box::new(ErrorInfo::new(
+                        "ERROR".to_owned(),
+                        "22012".to_owned(),
+                        "division by zero".to_owned(),
+                    )))])
+                } else {
+                    Ok(vec![Response::Execution(Tag::new(query))])
+                }
+            }
+        }
+
+        let mut client = MockClient::new();
+        let mut statuses = Vec::new();
+        for query in ["BEGIN", "SELECT 1/0", "ROLLBACK", "SELECT 1/0"] {
+            TransactionQueryHandler
+                .on_query(&mut client, Query::new(query.to_owned()))
+                .await
+                .unwrap();
+            let Some(PgWireBackendMessage::ReadyForQuery(ready)) = client.sent.last() else {
+                panic!("expected ReadyForQuery");
+            };
+            statuses.push(ready.status);
+        }
+        assert_eq!(
+            vec![
+                TransactionStatus::Transaction,
+                TransactionStatus::Error,
+                TransactionStatus::Idle,
+                TransactionStatus::Idle
+            ],
+            statuses
+        );
+    }
+
+    #[tokio::test]
+    async fn test_row_stream_error() {
+        let schema = query_response(0).row_schema();
+        let failure = PgWireError::IoError(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "upstream timed out",
+        ));
+        let rows = query_response(2)
+            .data_rows()
+            .chain(stream::iter(vec![Err(failure)]))
+            .chain(query_response(1).data_rows());
+        let mut client = MockClient::new();
+        let error = send_query_response(&mut client, QueryResponse::new(schema, rows), true)
+            .await
+            .unwrap_err();
+        // rows before the error are sent, the result is not completed
+        assert_eq!(2, count_rows(&client));
+        assert!(!client
+            .sent
+            .iter()
+            .any(|m| matches!(m, PgWireBackendMessage::CommandComplete(_))));
+        let PgWireError::UserError(info) = error else {
+            panic!("expected user error");
+        };
+        assert_eq!("ERROR", info.severity);
+        assert_eq!("58030", info.code);
+    }
+
+    #[tokio::test]
+    async fn test_row_stream_error_keeps_session() {
+        use crate::api::auth::noop::NoopStartupHandler;
+        use crate::testing::TestClientBuilder;
+
+        // rows of `SELECT` fail after the first one
+        struct FailingRowsHandler;
+
+        #[async_trait]
+        impl SimpleQueryHandler for FailingRowsHandler {
+            async fn do_query<'a, 'b: 'a, C>(
+                &'b self,
+                _client: &mut C,
+                query: &'a str,
+            ) -> PgWireResult<Vec<Response<'a>>>
+            where
+                C: ClientInfo + Unpin + Send + Sync,
+            {
+                if query == "SELECT" {
+                    let response = query_response(1);
+                    let schema = response.row_schema();
+                    let rows = response
+                        .data_rows()
+                        .chain(stream::iter(vec![Err(PgWireError::InvalidDataRow)]));
+                    Ok(vec![Response::Query(QueryResponse::new(schema, rows))])
+                } else {
+                    Ok(vec![Response::Execution(Tag::new(query))])
+                }
+            }
+        }
+
+        let mut client = TestClientBuilder::new()
+            .connect(
+                Arc::new(NoopStartupHandler),
+                Arc::new(FailingRowsHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+            )
+            .await
+            .unwrap();
+        client.simple_query("BEGIN").await.unwrap();
+        let error = client.simple_query("SELECT").await.unwrap_err();
+        assert_eq!("ERROR", error.to_error_info().severity);
+        assert_eq!(TransactionStatus::Error, client.transaction_status());
+
+        client.simple_query("ROLLBACK").await.unwrap();
+        assert_eq!(TransactionStatus::Idle, client.transaction_status());
+        client.close().await.unwrap();
+    }
+}
@@ -0,0 +1,217 @@
+//! Streaming replication, for logical replication clients like
+//! `pg_recvlogical` or Debezium.
+//!
+//! Clients connect with `replication=database` startup parameter. Commands
+//! like `IDENTIFY_SYSTEM` and `CREATE_REPLICATION_SLOT` are sent to
+//! `SimpleQueryHandler` as normal queries, while `START_REPLICATION` is
+//! handled by `ReplicationHandler`, configured in `ServerOptions`. WAL data
+//! from the handler is streamed to client in copy-both mode, with keepalive
+//! messages, until the stream ends or client sends `CopyDone`.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+
+use crate::error::PgWireResult;
+use crate::messages::replication::{parse_lsn, HotStandbyFeedback, StandbyStatusUpdate, XLogData};
+
+/// `START_REPLICATION` command sent by client.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct StartReplication {
+    pub slot: Option<String>,
+    /// true for logical replication, false for physical replication
+    pub logical: bool,
+    /// WAL location to start streaming from
+    pub start_lsn: u64,
+    pub timeline: Option<u32>,
+    /// Options of logical decoding plugin, values are optional
+    pub options: Vec<(String, Option<String>)>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Literal(String),
+    Punct(char),
+}
+
+// split command into words, identifiers in double quotes and string
+// literals in single quotes
+fn tokenize(command: &str) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = command.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' | ')' | ',' | ';' => tokens.push(Token::Punct(c)),
+            '"' | '\'' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next()? {
+                        q if q == c && chars.peek() == Some(&c) => {
+                            chars.next();
+                            value.push(c);
+                        }
+                        q if q == c => break,
+                        other => value.push(other),
+                    }
+                }
+                tokens.push(if c == '"' {
+                    Token::Word(value)
+                } else {
+                    Token::Literal(value)
+                });
+            }
+            _ => {
+                let mut word = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || "(),;\"'".contains(next) {
+                        break;
+                    }
+                    word.push(next);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Some(tokens)
+}
+
+impl StartReplication {
+    /// Parse `START_REPLICATION` command, returns `None` for other queries or
+    /// invalid syntax.
+    pub fn parse(command: &str) -> Option<StartReplication> {
+        let tokens = tokenize(command)?;
+        let mut tokens = tokens.into_iter().peekable();
+        let is_keyword = |token: &Token, keyword: &str| matches!(token, Token::Word(w) if w.eq_ignore_ascii_case(keyword));
+
+        if !is_keyword(&tokens.next()?, "START_REPLICATION") {
+            return None;
+        }
+        let mut command = StartReplication::default();
+        if tokens.peek().is_some_and(|t| is_keyword(t, "SLOT")) {
+            tokens.next();
+            let Token::Word(slot) = tokens.next()? else {
+                return None;
+            };
+            command.slot = Some(slot);
+        }
+        if tokens.peek().is_some_and(|t| is_keyword(t, "LOGICAL")) {
+            tokens.next();
+            command.logical = true;
+        } else if tokens.peek().is_some_and(|t| is_keyword(t, "PHYSICAL")) {
+            tokens.next();
+        }
+        let Token::Word(lsn) = tokens.next()? else {
+            return None;
+        };
+        command.start_lsn = parse_lsn(&lsn)?;
+
+        if !command.logical && tokens.peek().is_some_and(|t| is_keyword(t, "TIMELINE")) {
+            tokens.next();
+            let Token::Word(timeline) = tokens.next()? else {
+                return None;
+            };
+            command.timeline = Some(timeline.parse().ok()?);
+        }
+
+        if command.logical && tokens.peek() == Some(&Token::Punct('(')) {
+            tokens.next();
+            loop {
+                let Token::Word(name) = tokens.next()? else {
+                    return None;
+                };
+                let value = match tokens.peek() {
+                    Some(Token::Literal(_)) => match tokens.next() {
+                        Some(Token::Literal(value)) => Some(value),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                command.options.push((name, value));
+                match tokens.next()? {
+                    Token::Punct(',') => {}
+                    Token::Punct(')') => break,
+                    _ => return None,
+                }
+            }
+        }
+
+        match tokens.next() {
+            None => Some(command),
+            Some(Token::Punct(';')) if tokens.next().is_none() => Some(command),
+            _ => None,
+        }
+    }
+}
+
+/// Handler of `START_REPLICATION` command.
+#[async_trait]
+pub trait ReplicationHandler: Send + Sync {
+    /// Start streaming WAL data. `metadata` holds startup parameters of the
+    /// client. Return an error to reject the command, like missing slot.
+    async fn start_replication(
+        &self,
+        metadata: &HashMap<String, String>,
+        command: &StartReplication,
+    ) -> PgWireResult<BoxStream<'static, PgWireResult<XLogData>>>;
+
+    /// Called when client reports its progress, WAL before `flush_lsn` can
+    /// be discarded for the slot in logical replication.
+    async fn on_standby_status_update(
+        &self,
+        command: &StartReplication,
+        update: StandbyStatusUpdate,
+    ) -> PgWireResult<()>;
+
+    /// Called when physical replica sends its xmin.
+    async fn on_hot_standby_feedback(
+        &self,
+        _command: &StartReplication,
+        _feedback: HotStandbyFeedback,
+    ) -> PgWireResult<()> {
+        Ok(())
+    }
+
+    /// Interval of keepalive messages when no WAL data is sent.
+    fn keepalive_interval(&self) -> Duration {
+        Duration::from_secs(10)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_start_replication() {
+        let command = StartReplication::parse(
+            "START_REPLICATION SLOT \"test_slot\" LOGICAL 16/B374D848 (\"proto_version\" '1', \"publication_names\" 'pub1,pub2', binary)",
+        )
+        .unwrap();
+        assert_eq!(Some("test_slot"), command.slot.as_deref());
+        assert!(command.logical);
+        assert_eq!(0x16_B374_D848, command.start_lsn);
+        assert_eq!(
+            vec![
+                ("proto_version".to_owned(), Some("1".to_owned())),
+                ("publication_names".to_owned(), Some("pub1,pub2".to_owned())),
+                ("binary".to_owned(), None),
+            ],
+            command.options
+        );
+
+        let command = StartReplication::parse("start_replication 0/0 timeline 2;").unwrap();
+        assert!(!command.logical);
+        assert_eq!(None, command.slot);
+        assert_eq!(Some(2), command.timeline);
+
+        assert!(StartReplication::parse("SELECT 1").is_none());
+        assert!(StartReplication::parse("START_REPLICATION SLOT s LOGICAL").is_none());
+        assert!(StartReplication::parse("START_REPLICATION SLOT s LOGICAL 0/0 (a '1'").is_none());
+    }
+}
@@ -1,7 +1,8 @@
 use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use futures::{
     stream::{BoxStream, StreamExt},
     Stream,
@@ -9,14 +10,26 @@ use futures::{
 use postgres_types::{IsNull, Oid, ToSql, Type};
 
 use crate::{
-    error::{ErrorInfo, PgWireResult},
+    api::{
+        copy::{binary_copy_header, binary_copy_row, binary_copy_trailer, CopyFormat, CopyOptions},
+        portal::Format,
+    },
+    error::{ErrorInfo, PgWireError, PgWireResult, SqlState},
     messages::{
+        copy::{CopyInResponse, CopyOutResponse},
         data::{DataRow, FieldDescription, RowDescription, FORMAT_CODE_BINARY, FORMAT_CODE_TEXT},
         response::CommandComplete,
     },
-    types::ToSqlText,
+    types::{
+        binary::{binary_to_text, text_to_binary},
+        ToSqlText,
+    },
 };
 
+/// Tag of `CommandComplete`, like `SELECT 5` or `INSERT 0 1`.
+///
+/// Drivers parse the row count out of the tag, so prefer the constructors of
+/// commands with a row count, which always produce the form postgres sends.
 #[derive(Debug, Eq, PartialEq)]
 pub struct Tag {
     command: String,
@@ -25,6 +38,7 @@ pub struct Tag {
 }
 
 impl Tag {
+    /// Tag of `command`, sent as is. See `Tag::custom` for a checked version.
     pub fn new(command: &str) -> Tag {
         Tag {
             command: command.to_owned(),
@@ -33,6 +47,50 @@ impl Tag {
         }
     }
 
+    /// Tag of a custom command like `CREATE TABLE`. It must be words of
+    /// uppercase ascii letters and underscores, separated by single spaces.
+    pub fn custom(command: &str) -> PgWireResult<Tag> {
+        let valid = !command.is_empty()
+            && command.split(' ').all(|word| {
+                !word.is_empty() && word.bytes().all(|b| b.is_ascii_uppercase() || b == b'_')
+            });
+        if valid {
+            Ok(Tag::new(command))
+        } else {
+            Err(PgWireError::InvalidCommandTag(command.to_owned()))
+        }
+    }
+
+    /// `SELECT rows`, also used for `CREATE TABLE AS`.
+    pub fn select(rows: usize) -> Tag {
+        Tag::new("SELECT").with_rows(rows)
+    }
+
+    /// `INSERT 0 rows`, set the oid of a single inserted row with `with_oid`.
+    pub fn insert(rows: usize) -> Tag {
+        Tag::new("INSERT").with_oid(0).with_rows(rows)
+    }
+
+    /// `UPDATE rows`
+    pub fn update(rows: usize) -> Tag {
+        Tag::new("UPDATE").with_rows(rows)
+    }
+
+    /// `DELETE rows`
+    pub fn delete(rows: usize) -> Tag {
+        Tag::new("DELETE").with_rows(rows)
+    }
+
+    /// `COPY rows`
+    pub fn copy(rows: usize) -> Tag {
+        Tag::new("COPY").with_rows(rows)
+    }
+
+    /// `FETCH rows`
+    pub fn fetch(rows: usize) -> Tag {
+        Tag::new("FETCH").with_rows(rows)
+    }
+
     pub fn with_rows(mut self, rows: usize) -> Tag {
         self.rows = Some(rows);
         self
@@ -46,15 +104,156 @@ impl Tag {
 
 impl From<Tag> for CommandComplete {
     fn from(tag: Tag) -> CommandComplete {
-        let tag_string = if let Some(rows) = tag.rows {
-            format!("{} {rows}", tag.command)
-        } else {
-            tag.command
+        // INSERT is the only tag with an oid, which clients expect even
+        // when it's 0
+        let oid = tag
+            .oid
+            .or_else(|| tag.command.eq_ignore_ascii_case("INSERT").then_some(0));
+        let tag_string = match (oid, tag.rows) {
+            (Some(oid), Some(rows)) => format!("{} {oid} {rows}", tag.command),
+            (None, Some(rows)) => format!("{} {rows}", tag.command),
+            _ => tag.command,
         };
         CommandComplete::new(tag_string)
     }
 }
 
+/// Limits on the result of a single statement, `None` for unlimited.
+///
+/// When a limit is exceeded, the result is truncated and the statement fails
+/// with SQLSTATE `54000` (program_limit_exceeded), or the error set with
+/// `with_error`.
+#[non_exhaustive]
+#[derive(Debug, Default, Clone, new)]
+pub struct ResultLimits {
+    /// maximum number of rows
+    #[new(default)]
+    pub max_rows: Option<usize>,
+    /// maximum size of all `DataRow` messages in bytes
+    #[new(default)]
+    pub max_bytes: Option<usize>,
+    /// error to fail the statement with, instead of `54000`
+    #[new(default)]
+    pub error: Option<Box<ErrorInfo>>,
+}
+
+impl ResultLimits {
+    pub fn with_max_rows(mut self, max_rows: usize) -> ResultLimits {
+        self.max_rows = Some(max_rows);
+        self
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> ResultLimits {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    pub fn with_error(mut self, error: ErrorInfo) -> ResultLimits {
+        self.error = Some(Box::new(error));
+        self
+    }
+
+    pub(crate) fn check(&self, rows: usize, bytes: usize) -> PgWireResult<()> {
+        let exceeded = match (self.max_rows, self.max_bytes) {
+            (Some(max_rows), _) if rows > max_rows => format!("{max_rows} rows"),
+            (_, Some(max_bytes)) if bytes > max_bytes => format!("{max_bytes} bytes"),
+            _ => return Ok(()),
+        };
+        if let Some(error) = &self.error {
+            return Err(PgWireError::UserError(error.clone()));
+        }
+        Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+            "ERROR".to_owned(),
+            SqlState::PROGRAM_LIMIT_EXCEEDED.into(),
+            format!("query result exceeds the limit of {exceeded}"),
+        ))))
+    }
+}
+
+/// When data rows of a query response are flushed to client, in addition to
+/// when the write buffer of connection is full, and when `ReadyForQuery` is
+/// sent or client sends `Flush`. `None` to not flush on that condition.
+///
+/// Rows are pulled from the stream only as fast as they are written to the
+/// socket, so a slow client can't cause unbounded buffering.
+#[non_exhaustive]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, new)]
+pub struct FlushPolicy {
+    /// flush after every number of rows
+    #[new(default)]
+    pub every_rows: Option<usize>,
+    /// flush after every number of bytes of `DataRow` messages
+    #[new(default)]
+    pub every_bytes: Option<usize>,
+}
+
+impl FlushPolicy {
+    pub fn with_every_rows(mut self, rows: usize) -> FlushPolicy {
+        self.every_rows = Some(rows);
+        self
+    }
+
+    pub fn with_every_bytes(mut self, bytes: usize) -> FlushPolicy {
+        self.every_bytes = Some(bytes);
+        self
+    }
+}
+
+/// Handle for a row stream to request flushing rows sent so far. Create one
+/// and move a clone into the stream, then set it with
+/// `QueryResponse::set_flush_handle`.
+///
+/// The flush happens after the row being yielded by the stream is sent.
+#[derive(Debug, Clone, Default)]
+pub struct FlushHandle(Arc<AtomicBool>);
+
+impl FlushHandle {
+    /// Request a flush.
+    pub fn request(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    fn take(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// Decide when to flush rows, by `FlushPolicy` and requests from
+/// `FlushHandle`.
+#[derive(Debug)]
+pub(crate) struct Flusher {
+    policy: FlushPolicy,
+    handle: FlushHandle,
+    rows: usize,
+    bytes: usize,
+}
+
+impl Flusher {
+    pub(crate) fn new(policy: FlushPolicy, handle: FlushHandle) -> Flusher {
+        Flusher {
+            policy,
+            handle,
+            rows: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Record a row of `len` bytes being sent, returns true if rows should
+    /// be flushed.
+    pub(crate) fn on_row(&mut self, len: usize) -> bool {
+        self.rows += 1;
+        self.bytes += len;
+        let flush = self.handle.take()
+            || self.policy.every_rows.is_some_and(|n| self.rows >= n)
+            || self.policy.every_bytes.is_some_and(|n| self.bytes >= n);
+        if flush {
+            self.rows = 0;
+            self.bytes = 0;
+        }
+        flush
+    }
+}
+
 /// Describe encoding of a data field.
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 pub enum FieldFormat {
@@ -113,6 +312,13 @@ impl FieldInfo {
     pub fn format(&self) -> FieldFormat {
         self.format
     }
+
+    /// Set the format of the column, for example to the format requested by
+    /// client in `Bind`.
+    pub fn with_format(mut self, format: FieldFormat) -> FieldInfo {
+        self.format = format;
+        self
+    }
 }
 
 impl From<&FieldInfo> for FieldDescription {
@@ -134,15 +340,41 @@ pub(crate) fn into_row_description(fields: &[FieldInfo]) -> RowDescription {
     RowDescription::new(fields.iter().map(Into::into).collect())
 }
 
+/// Row stream of a `QueryResponse`, `Owned` if it doesn't borrow, so that a
+/// portal suspended by row limit can keep it for the next `Execute`.
+pub(crate) enum DataRows<'a> {
+    Borrowed(BoxStream<'a, PgWireResult<DataRow>>),
+    Owned(BoxStream<'static, PgWireResult<DataRow>>),
+}
+
+impl<'a> DataRows<'a> {
+    fn map<F>(self, f: F) -> DataRows<'a>
+    where
+        F: FnMut(PgWireResult<DataRow>) -> PgWireResult<DataRow> + Send + 'static,
+    {
+        match self {
+            DataRows::Borrowed(rows) => DataRows::Borrowed(rows.map(f).boxed()),
+            DataRows::Owned(rows) => DataRows::Owned(rows.map(f).boxed()),
+        }
+    }
+}
+
 pub struct QueryResponse<'a> {
     command_tag: String,
     row_schema: Arc<Vec<FieldInfo>>,
-    data_rows: BoxStream<'a, PgWireResult<DataRow>>,
+    data_rows: DataRows<'a>,
+    flush_policy: Option<FlushPolicy>,
+    flush_handle: FlushHandle,
+    result_limits: Option<ResultLimits>,
 }
 
 impl<'a> QueryResponse<'a> {
     /// Create `QueryResponse` from column schemas and stream of data row.
     /// Sets "SELECT" as the command tag.
+    ///
+    /// An error of the stream ends the result: rows before it are sent,
+    /// followed by `ErrorResponse` instead of `CommandComplete`, and the
+    /// session goes on.
     pub fn new<S>(field_defs: Arc<Vec<FieldInfo>>, row_stream: S) -> QueryResponse<'a>
     where
         S: Stream<Item = PgWireResult<DataRow>> + Send + Unpin + 'a,
@@ -150,7 +382,29 @@ impl<'a> QueryResponse<'a> {
         QueryResponse {
             command_tag: "SELECT".to_owned(),
             row_schema: field_defs,
-            data_rows: row_stream.boxed(),
+            data_rows: DataRows::Borrowed(row_stream.boxed()),
+            flush_policy: None,
+            flush_handle: FlushHandle::default(),
+            result_limits: None,
+        }
+    }
+
+    /// Like `new`, for a stream that doesn't borrow from the handler or the
+    /// portal. When `Execute` has a row limit, the portal keeps the stream
+    /// and rows are fetched from it as the client executes the portal again,
+    /// while rows of other streams are buffered at the first `Execute`.
+    /// Prefer this for large results, like cursors of JDBC `setFetchSize`.
+    pub fn new_owned<S>(field_defs: Arc<Vec<FieldInfo>>, row_stream: S) -> QueryResponse<'a>
+    where
+        S: Stream<Item = PgWireResult<DataRow>> + Send + 'static,
+    {
+        QueryResponse {
+            command_tag: "SELECT".to_owned(),
+            row_schema: field_defs,
+            data_rows: DataRows::Owned(row_stream.boxed()),
+            flush_policy: None,
+            flush_handle: FlushHandle::default(),
+            result_limits: None,
         }
     }
 
@@ -169,24 +423,130 @@ impl<'a> QueryResponse<'a> {
         self.row_schema.clone()
     }
 
+    /// Get flush policy of this response, `None` for the policy of session.
+    pub fn flush_policy(&self) -> Option<FlushPolicy> {
+        self.flush_policy
+    }
+
+    /// Set flush policy of this response, instead of the policy of session.
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        self.flush_policy = Some(policy);
+    }
+
+    /// Get result limits of this response, `None` for the limits of session.
+    pub fn result_limits(&self) -> Option<&ResultLimits> {
+        self.result_limits.as_ref()
+    }
+
+    /// Set result limits of this response, instead of the limits of session.
+    /// Lifts the limits of session with `ResultLimits::default()`.
+    pub fn set_result_limits(&mut self, limits: ResultLimits) {
+        self.result_limits = Some(limits);
+    }
+
+    /// Set the handle for the row stream to request flush.
+    pub fn set_flush_handle(&mut self, handle: FlushHandle) {
+        self.flush_handle = handle;
+    }
+
+    /// Get the handle for the row stream to request flush.
+    pub fn flush_handle(&self) -> FlushHandle {
+        self.flush_handle.clone()
+    }
+
     /// Get owned `BoxStream` of data rows
     pub fn data_rows(self) -> BoxStream<'a, PgWireResult<DataRow>> {
+        match self.data_rows {
+            DataRows::Borrowed(rows) => rows,
+            DataRows::Owned(rows) => rows,
+        }
+    }
+
+    pub(crate) fn into_data_rows(self) -> DataRows<'a> {
         self.data_rows
     }
+
+    /// Convert columns to formats requested by `Bind`, when rows are encoded
+    /// in other formats of `row_schema`.
+    pub(crate) fn into_formats(mut self, format: &Format) -> QueryResponse<'a> {
+        let target = self
+            .row_schema
+            .iter()
+            .enumerate()
+            .map(|(idx, field)| field.clone().with_format(format.format_for(idx)))
+            .collect::<Vec<_>>();
+        if target == *self.row_schema {
+            return self;
+        }
+
+        let source = std::mem::replace(&mut self.row_schema, Arc::new(target));
+        let target = self.row_schema.clone();
+        let rows = std::mem::replace(
+            &mut self.data_rows,
+            DataRows::Owned(futures::stream::empty().boxed()),
+        );
+        self.data_rows = rows.map(move |row| convert_row(&source, &target, row?));
+        self
+    }
 }
 
+// re-encode fields of `row` from formats of `source` to formats of `target`
+fn convert_row(source: &[FieldInfo], target: &[FieldInfo], row: DataRow) -> PgWireResult<DataRow> {
+    let mut data = BytesMut::with_capacity(row.data.len());
+    for (idx, value) in row.fields()?.into_iter().enumerate() {
+        let Some(value) = value else {
+            data.put_i32(-1);
+            continue;
+        };
+        let (Some(from), Some(to)) = (source.get(idx), target.get(idx)) else {
+            data.put_i32(value.len() as i32);
+            data.put_slice(value);
+            continue;
+        };
+
+        let start = data.len();
+        data.put_i32(0);
+        match (from.format(), to.format()) {
+            (FieldFormat::Text, FieldFormat::Binary) => {
+                text_to_binary(from.datatype(), value, &mut data)?
+            }
+            (FieldFormat::Binary, FieldFormat::Text) => {
+                binary_to_text(from.datatype(), value, &mut data)?
+            }
+            _ => data.put_slice(value),
+        }
+        let length = (data.len() - start - 4) as i32;
+        data[start..start + 4].copy_from_slice(&length.to_be_bytes());
+    }
+    Ok(DataRow::new(data, row.field_count))
+}
+
+/// Encoder of `DataRow`s for a schema.
+///
+/// An encoder can be reused for all rows of a result set with `take_row`.
+/// Rows taken are split from a shared buffer, so its allocation is reused
+/// once previous rows are sent and dropped, instead of allocating per row.
 pub struct DataRowEncoder {
     schema: Arc<Vec<FieldInfo>>,
     row_buffer: BytesMut,
+    // scratch buffer for values converted from text to binary format
+    text_buffer: BytesMut,
     col_index: usize,
 }
 
 impl DataRowEncoder {
     /// New DataRowEncoder from schema of column
     pub fn new(fields: Arc<Vec<FieldInfo>>) -> DataRowEncoder {
+        Self::with_capacity(fields, 128)
+    }
+
+    /// New DataRowEncoder with `capacity` bytes of buffer, which can be sized
+    /// to hold many rows when the encoder is reused.
+    pub fn with_capacity(fields: Arc<Vec<FieldInfo>>, capacity: usize) -> DataRowEncoder {
         Self {
             schema: fields,
-            row_buffer: BytesMut::with_capacity(128),
+            row_buffer: BytesMut::with_capacity(capacity),
+            text_buffer: BytesMut::new(),
             col_index: 0,
         }
     }
@@ -211,8 +571,18 @@ impl DataRowEncoder {
 
         let is_null = if format == FieldFormat::Text {
             value.to_sql_text(data_type, &mut self.row_buffer)?
-        } else {
+        } else if T::accepts(data_type) {
             value.to_sql(data_type, &mut self.row_buffer)?
+        } else {
+            // rust type has no binary encoding for the column type, like
+            // `String` for `NUMERIC` or `i32` for `INT8`. Convert from text
+            // format so the value matches postgres binary representation.
+            self.text_buffer.clear();
+            let is_null = value.to_sql_text(data_type, &mut self.text_buffer)?;
+            if let IsNull::No = is_null {
+                text_to_binary(data_type, &self.text_buffer, &mut self.row_buffer)?;
+            }
+            is_null
         };
 
         if let IsNull::No = is_null {
@@ -239,6 +609,19 @@ impl DataRowEncoder {
         self.encode_field_with_type_and_format(value, &data_type, format)
     }
 
+    /// Take the encoded row, and reset the encoder for the next row.
+    pub fn take_row(&mut self) -> PgWireResult<DataRow> {
+        let row = DataRow::new(self.row_buffer.split(), self.col_index as i16);
+        self.col_index = 0;
+        Ok(row)
+    }
+
+    /// Discard fields encoded for current row, for example after an error.
+    pub fn reset(&mut self) {
+        self.row_buffer.clear();
+        self.col_index = 0;
+    }
+
     pub fn finish(self) -> PgWireResult<DataRow> {
         Ok(DataRow::new(self.row_buffer, self.col_index as i16))
     }
@@ -254,11 +637,16 @@ pub trait DescribeResponse {
     /// when client tries to describe an empty query.
     fn no_data() -> Self;
 
-    /// Return true if the `DescribeResponse` is empty/nodata
+    /// Return true if the described statement returns no rows, which is
+    /// answered with `NoData` instead of `RowDescription`.
     fn is_no_data(&self) -> bool;
 }
 
 /// Response for frontend describe statement requests.
+///
+/// Statements returning no rows, like DDL or `INSERT` without `RETURNING`,
+/// have no `fields`, and are described with `ParameterDescription` and
+/// `NoData`.
 #[non_exhaustive]
 #[derive(Debug, new)]
 pub struct DescribeStatementResponse {
@@ -284,9 +672,10 @@ impl DescribeResponse for DescribeStatementResponse {
         }
     }
 
-    /// Return true if the `DescribeStatementResponse` is empty/nodata
+    /// Return true if the statement returns no rows. Parameters are
+    /// described regardless.
     fn is_no_data(&self) -> bool {
-        self.parameters.is_empty() && self.fields.is_empty()
+        self.fields.is_empty()
     }
 }
 
@@ -318,16 +707,149 @@ impl DescribeResponse for DescribePortalResponse {
     }
 }
 
+/// Response of `COPY ... FROM STDIN`. Client starts to send `CopyData` after
+/// receiving it, which is processed by `CopyHandler`.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, new)]
+pub struct CopyResponse {
+    /// Format of the data, text format includes csv.
+    pub format: FieldFormat,
+    /// Number of columns in the data.
+    pub columns: usize,
+}
+
+impl CopyResponse {
+    // format of each column must be the same as overall format
+    fn column_formats(&self) -> Vec<i16> {
+        vec![self.format.value(); self.columns]
+    }
+
+    pub(crate) fn into_copy_in_response(self) -> CopyInResponse {
+        CopyInResponse::new(
+            (self.format == FieldFormat::Binary).into(),
+            self.columns as i16,
+            self.column_formats(),
+        )
+    }
+
+    pub(crate) fn into_copy_out_response(self) -> CopyOutResponse {
+        CopyOutResponse::new(
+            (self.format == FieldFormat::Binary).into(),
+            self.columns as i16,
+            self.column_formats(),
+        )
+    }
+}
+
+/// Data of `COPY ... TO STDOUT`.
+///
+/// Each item of the stream is a row, sent to client in a `CopyData` message
+/// like postgres does.
+pub struct CopyOutStream<'a> {
+    copy: CopyResponse,
+    header: Option<Bytes>,
+    trailer: Option<Bytes>,
+    data: BoxStream<'a, PgWireResult<Bytes>>,
+}
+
+impl<'a> CopyOutStream<'a> {
+    /// Each item of `data` must be exactly one row, as the number of items
+    /// is reported as rows in the command tag and checked against
+    /// `ResultLimits`. Header and trailer are not counted.
+    pub fn new<S>(copy: CopyResponse, data: S) -> CopyOutStream<'a>
+    where
+        S: Stream<Item = PgWireResult<Bytes>> + Send + 'a,
+    {
+        CopyOutStream {
+            copy,
+            header: None,
+            trailer: None,
+            data: data.boxed(),
+        }
+    }
+
+    /// Data in binary copy format, from rows encoded by `DataRowEncoder`
+    /// with binary format.
+    pub fn binary<S>(columns: usize, rows: S) -> CopyOutStream<'a>
+    where
+        S: Stream<Item = PgWireResult<DataRow>> + Send + 'a,
+    {
+        CopyOutStream::new(
+            CopyResponse::new(FieldFormat::Binary, columns),
+            rows.map(|row| row.map(|row| binary_copy_row(&row))),
+        )
+        .with_header(binary_copy_header())
+        .with_trailer(binary_copy_trailer())
+    }
+
+    /// Data in format of `options`, from rows encoded by `DataRowEncoder`
+    /// with text format, or binary format if `options` is binary. Names of
+    /// `fields` are sent as header if `options.header` is set.
+    pub fn from_rows<S>(options: CopyOptions, fields: &[FieldInfo], rows: S) -> CopyOutStream<'a>
+    where
+        S: Stream<Item = PgWireResult<DataRow>> + Send + 'a,
+    {
+        if options.format == CopyFormat::Binary {
+            return CopyOutStream::binary(fields.len(), rows);
+        }
+        let header = options.header.then(|| {
+            options.encode_row(&fields.iter().map(|f| Some(f.name())).collect::<Vec<_>>())
+        });
+        let mut stream = CopyOutStream::new(
+            options.copy_response(fields.len()),
+            rows.map(move |row| row.and_then(|row| options.encode_data_row(&row))),
+        );
+        stream.header = header;
+        stream
+    }
+
+    /// Data sent before the stream, it's not counted as a row.
+    pub fn with_header(mut self, header: Bytes) -> CopyOutStream<'a> {
+        self.header = Some(header);
+        self
+    }
+
+    /// Data sent after the stream, it's not counted as a row.
+    pub fn with_trailer(mut self, trailer: Bytes) -> CopyOutStream<'a> {
+        self.trailer = Some(trailer);
+        self
+    }
+
+    /// Get format and columns of the data
+    pub fn copy_response(&self) -> &CopyResponse {
+        &self.copy
+    }
+
+    pub(crate) fn header(&mut self) -> Option<Bytes> {
+        self.header.take()
+    }
+
+    pub(crate) fn trailer(&mut self) -> Option<Bytes> {
+        self.trailer.take()
+    }
+
+    /// Split into format of the data and the data stream
+    pub fn into_parts(self) -> (CopyResponse, BoxStream<'a, PgWireResult<Bytes>>) {
+        (self.copy, self.data)
+    }
+}
+
 /// Query response types:
 ///
 /// * Query: the response contains data rows
 /// * Execution: response for ddl/dml execution
 /// * Error: error response
+/// * CopyIn: start of `COPY FROM STDIN`, it must be the last response of a
+///   query
+/// * CopyOut: data of `COPY TO STDOUT`
+#[non_exhaustive]
 pub enum Response<'a> {
     EmptyQuery,
     Query(QueryResponse<'a>),
     Execution(Tag),
     Error(Box<ErrorInfo>),
+    CopyIn(CopyResponse),
+    CopyOut(CopyOutStream<'a>),
 }
 
 #[cfg(test)]
@@ -341,7 +863,33 @@ mod test {
         let tag = Tag::new("INSERT").with_oid(0).with_rows(100);
         let cc = CommandComplete::from(tag);
 
-        assert_eq!(cc.tag, "INSERT 100");
+        assert_eq!(cc.tag, "INSERT 0 100");
+
+        let tags = [
+            (Tag::select(5), "SELECT 5"),
+            (Tag::insert(3), "INSERT 0 3"),
+            (Tag::insert(1).with_oid(16384), "INSERT 16384 1"),
+            (Tag::new("INSERT").with_rows(2), "INSERT 0 2"),
+            (Tag::update(0), "UPDATE 0"),
+            (Tag::delete(7), "DELETE 7"),
+            (Tag::copy(10), "COPY 10"),
+            (Tag::fetch(4), "FETCH 4"),
+            (Tag::custom("CREATE TABLE").unwrap(), "CREATE TABLE"),
+            (
+                Tag::custom("START_REPLICATION").unwrap(),
+                "START_REPLICATION",
+            ),
+        ];
+        for (tag, expected) in tags {
+            assert_eq!(expected, CommandComplete::from(tag).tag);
+        }
+
+        for invalid in ["", "Insert", "INSERT 3", "CREATE  TABLE", " SET"] {
+            assert!(matches!(
+                Tag::custom(invalid),
+                Err(PgWireError::InvalidCommandTag(_))
+            ));
+        }
     }
 
     #[test]
@@ -370,4 +918,102 @@ mod test {
         let _ = now.to_sql_text(&Type::TIMESTAMP, &mut expected);
         assert_eq!(row.data, expected);
     }
+
+    #[test]
+    fn test_data_row_encoder_reuse() {
+        let schema = Arc::new(vec![
+            FieldInfo::new("id".into(), None, None, Type::INT4, FieldFormat::Text),
+            FieldInfo::new("n".into(), None, None, Type::INT8, FieldFormat::Binary),
+        ]);
+        let mut encoder = DataRowEncoder::with_capacity(schema, 1024);
+        let rows = (0..3)
+            .map(|i| {
+                encoder.encode_field(&i).unwrap();
+                // text to binary conversion reuses scratch buffer
+                encoder.encode_field(&i).unwrap();
+                encoder.take_row().unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        for (i, row) in rows.iter().enumerate() {
+            let mut expected = BytesMut::new();
+            expected.put_i32(1);
+            expected.put_slice(i.to_string().as_bytes());
+            expected.put_i32(8);
+            expected.put_i64(i as i64);
+            assert_eq!(2, row.field_count);
+            assert_eq!(expected, row.data);
+        }
+
+        encoder.encode_field(&1).unwrap();
+        encoder.reset();
+        let row = encoder.take_row().unwrap();
+        assert_eq!(0, row.field_count);
+        assert!(row.data.is_empty());
+    }
+
+    #[test]
+    fn test_data_row_encoder_binary() {
+        let schema = Arc::new(vec![
+            FieldInfo::new("id".into(), None, None, Type::INT8, FieldFormat::Binary),
+            FieldInfo::new("ok".into(), None, None, Type::BOOL, FieldFormat::Binary),
+            FieldInfo::new(
+                "price".into(),
+                None,
+                None,
+                Type::NUMERIC,
+                FieldFormat::Binary,
+            ),
+            FieldInfo::new(
+                "ratio".into(),
+                None,
+                None,
+                Type::FLOAT8,
+                FieldFormat::Binary,
+            ),
+            FieldInfo::new("data".into(), None, None, Type::BYTEA, FieldFormat::Binary),
+            FieldInfo::new("day".into(), None, None, Type::DATE, FieldFormat::Binary),
+            FieldInfo::new("none".into(), None, None, Type::INT4, FieldFormat::Binary),
+        ]);
+        let mut encoder = DataRowEncoder::new(schema);
+        // i32 in INT8 column is widened
+        encoder.encode_field(&42i32).unwrap();
+        encoder.encode_field(&true).unwrap();
+        encoder.encode_field(&"-1.5").unwrap();
+        encoder.encode_field(&0.5f32).unwrap();
+        encoder.encode_field(&b"\x01\x02".as_slice()).unwrap();
+        encoder
+            .encode_field(&chrono::NaiveDate::from_ymd_opt(2000, 1, 2).unwrap())
+            .unwrap();
+        encoder.encode_field(&None::<i32>).unwrap();
+        let row = encoder.finish().unwrap();
+
+        let mut expected = BytesMut::new();
+        expected.put_i32(8);
+        expected.put_i64(42);
+        expected.put_i32(1);
+        expected.put_u8(1);
+        expected.put_i32(12);
+        for digit in [2i16, 0, 0x4000, 1, 1, 5000] {
+            expected.put_i16(digit);
+        }
+        expected.put_i32(8);
+        expected.put_f64(0.5);
+        expected.put_i32(2);
+        expected.put_slice(b"\x01\x02");
+        expected.put_i32(4);
+        expected.put_i32(1);
+        expected.put_i32(-1);
+        assert_eq!(row.data, expected);
+
+        let schema = Arc::new(vec![FieldInfo::new(
+            "id".into(),
+            None,
+            None,
+            Type::INT4,
+            FieldFormat::Binary,
+        )]);
+        let mut encoder = DataRowEncoder::new(schema);
+        assert!(encoder.encode_field(&"abc").is_err());
+    }
 }
@@ -0,0 +1,32 @@
+//! Rewriting queries before they are dispatched to handlers.
+//!
+//! A `QueryRewriter` set with `ServerOptions::with_query_rewriter` sees the
+//! text of each simple query and each `Parse` of extended query. It can
+//! rewrite the text, for example to shim another SQL dialect or inject tenant
+//! filters, answer the query itself, or reject it, like blocking dangerous
+//! statements. Handlers and `QueryAuditor` only see rewritten queries.
+
+use super::results::Response;
+use super::ClientInfo;
+use crate::error::ErrorInfo;
+
+/// Outcome of `QueryRewriter::rewrite`.
+pub enum QueryRewrite {
+    /// pass the query to handlers as is
+    Unchanged,
+    /// pass this query to handlers instead
+    Rewrite(String),
+    /// answer the query with these responses, without calling handlers. Only
+    /// simple queries can be answered, a `Parse` answered with them is
+    /// rejected with `0A000` (feature_not_supported).
+    Respond(Vec<Response<'static>>),
+    /// reject the query with this error
+    Reject(Box<ErrorInfo>),
+}
+
+/// Rewrites or vetoes query text before handlers see it.
+pub trait QueryRewriter: Send + Sync {
+    /// `extended` is true if `query` is from `Parse`. It's called on the
+    /// connection task, and should not block.
+    fn rewrite(&self, client: &dyn ClientInfo, query: &str, extended: bool) -> QueryRewrite;
+}
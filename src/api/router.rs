@@ -0,0 +1,309 @@
+//! Routing of connections by database and user of startup parameters.
+//!
+//! A `StartupRouter` selects the startup handler and the
+//! `MakeConnectionHandler` of a connection from its startup message, so a
+//! single listener serves multiple logical databases or tenants with
+//! different backends. Serve it as both parts of a `ConnectionHandler`:
+//!
+//! ```ignore
+//! let router = Arc::new(
+//!     StartupRouter::new()
+//!         .with_database("sales", authenticator.clone(), Arc::new(sales))
+//!         .with_route(Some("hr"), Some("admin"), admin_authenticator, Arc::new(hr_admin))
+//!         .with_database("hr", authenticator, Arc::new(hr)),
+//! );
+//! let handler = Arc::new(ConnectionHandler::new(router.clone(), router));
+//! ```
+//!
+//! Like postgres, database defaults to the user name when client doesn't
+//! send it. Routes are matched in the order they are added, and a connection
+//! matching none of them fails with `3D000` (invalid_catalog_name), unless
+//! there is a fallback. All routes have the same handler types, backends of
+//! different types can be wrapped in an enum.
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::sink::Sink;
+
+use super::auth::{save_startup_parameters_to_metadata, StartupHandler};
+use super::connection::MakeConnectionHandler;
+use super::ClientInfo;
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, SqlState};
+use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+
+struct Route<A, M> {
+    database: Option<String>,
+    user: Option<String>,
+    startup_handler: Arc<A>,
+    make_handler: Arc<M>,
+}
+
+/// Startup handler and `MakeConnectionHandler` delegating to the route of
+/// each connection.
+pub struct StartupRouter<A, M> {
+    routes: Vec<Route<A, M>>,
+    fallback: Option<(Arc<A>, Arc<M>)>,
+}
+
+impl<A, M> Default for StartupRouter<A, M> {
+    fn default() -> Self {
+        StartupRouter {
+            routes: Vec::new(),
+            fallback: None,
+        }
+    }
+}
+
+impl<A, M> StartupRouter<A, M> {
+    pub fn new() -> StartupRouter<A, M> {
+        StartupRouter::default()
+    }
+
+    /// Route connections to `database` of any user.
+    pub fn with_database(
+        self,
+        database: &str,
+        startup_handler: Arc<A>,
+        make_handler: Arc<M>,
+    ) -> StartupRouter<A, M> {
+        self.with_route(Some(database), None, startup_handler, make_handler)
+    }
+
+    /// Route connections matching `database` and `user`, `None` for any.
+    pub fn with_route(
+        mut self,
+        database: Option<&str>,
+        user: Option<&str>,
+        startup_handler: Arc<A>,
+        make_handler: Arc<M>,
+    ) -> StartupRouter<A, M> {
+        self.routes.push(Route {
+            database: database.map(str::to_owned),
+            user: user.map(str::to_owned),
+            startup_handler,
+            make_handler,
+        });
+        self
+    }
+
+    /// Route connections matching no route, instead of failing them.
+    pub fn with_fallback(
+        mut self,
+        startup_handler: Arc<A>,
+        make_handler: Arc<M>,
+    ) -> StartupRouter<A, M> {
+        self.fallback = Some((startup_handler, make_handler));
+        self
+    }
+
+    /// Handlers of the first route matching `database` and `user` of startup
+    /// parameters. Database defaults to the user.
+    pub fn route(
+        &self,
+        database: Option<&str>,
+        user: Option<&str>,
+    ) -> PgWireResult<(&Arc<A>, &Arc<M>)> {
+        let database = database.or(user);
+        let matches = |expected: &Option<String>, actual: Option<&str>| {
+            expected.is_none() || expected.as_deref() == actual
+        };
+        self.routes
+            .iter()
+            .find(|route| matches(&route.database, database) && matches(&route.user, user))
+            .map(|route| (&route.startup_handler, &route.make_handler))
+            .or_else(|| self.fallback.as_ref().map(|(a, m)| (a, m)))
+            .ok_or_else(|| {
+                PgWireError::UserError(Box::new(ErrorInfo::new(
+                    "FATAL".to_owned(),
+                    SqlState::INVALID_CATALOG_NAME.into(),
+                    format!(
+                        "database \"{}\" does not exist",
+                        database.unwrap_or_default()
+                    ),
+                )))
+            })
+    }
+
+    fn route_client<C: ClientInfo>(&self, client: &C) -> PgWireResult<(&Arc<A>, &Arc<M>)> {
+        self.route(client.database(), client.user())
+    }
+}
+
+#[async_trait]
+impl<A, M> StartupHandler for StartupRouter<A, M>
+where
+    A: StartupHandler,
+    M: Send + Sync,
+{
+    async fn on_startup<C>(
+        &self,
+        client: &mut C,
+        message: PgWireFrontendMessage,
+    ) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        // saved before routing, so later messages of startup and
+        // `make_for_connection` take the same route
+        if let PgWireFrontendMessage::Startup(ref startup) = message {
+            save_startup_parameters_to_metadata(client, startup);
+        }
+        let (startup_handler, _) = self.route_client(client)?;
+        startup_handler.on_startup(client, message).await
+    }
+}
+
+impl<A, M> MakeConnectionHandler for StartupRouter<A, M>
+where
+    A: Send + Sync,
+    M: MakeConnectionHandler,
+{
+    type Handler = M::Handler;
+
+    /// # Panics
+    ///
+    /// If the connection matches no route, which fails its startup already.
+    fn make_for_connection<C: ClientInfo>(&self, client: &C) -> Self::Handler {
+        let (_, make_handler) = self
+            .route_client(client)
+            .expect("connection is routed at startup");
+        make_handler.make_for_connection(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::api::auth::noop::NoopStartupHandler;
+    use crate::api::connection::ConnectionHandler;
+    use crate::api::mock::MockClient;
+    use crate::api::query::SimpleQueryHandler;
+    use crate::api::results::{Response, Tag};
+    use crate::messages::simplequery::Query;
+    use crate::messages::startup::Startup;
+
+    // a backend answering queries with its name
+    struct Backend(&'static str);
+
+    #[async_trait]
+    impl SimpleQueryHandler for Backend {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            Ok(vec![Response::Execution(Tag::new(self.0))])
+        }
+    }
+
+    struct MakeBackend {
+        name: &'static str,
+        made: AtomicUsize,
+    }
+
+    impl MakeBackend {
+        fn new(name: &'static str) -> Arc<MakeBackend> {
+            Arc::new(MakeBackend {
+                name,
+                made: AtomicUsize::new(0),
+            })
+        }
+    }
+
+    impl MakeConnectionHandler for MakeBackend {
+        type Handler = Backend;
+
+        fn make_for_connection<C: ClientInfo>(&self, _client: &C) -> Backend {
+            self.made.fetch_add(1, Ordering::SeqCst);
+            Backend(self.name)
+        }
+    }
+
+    async fn connect(
+        router: &Arc<StartupRouter<NoopStartupHandler, MakeBackend>>,
+        parameters: &[(&str, &str)],
+    ) -> PgWireResult<String> {
+        let handler = ConnectionHandler::new(router.clone(), router.clone());
+        let mut client = MockClient::new();
+        let mut startup = Startup::new();
+        for (name, value) in parameters {
+            startup
+                .parameters
+                .insert((*name).to_owned(), (*value).to_owned());
+        }
+        handler
+            .on_startup(&mut client, PgWireFrontendMessage::Startup(startup))
+            .await?;
+        client.sent.clear();
+        handler
+            .on_query(&mut client, Query::new("SELECT 1".to_owned()))
+            .await?;
+        match &client.sent[0] {
+            PgWireBackendMessage::CommandComplete(complete) => Ok(complete.tag.clone()),
+            message => panic!("unexpected {message:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_startup_router() {
+        let auth = Arc::new(NoopStartupHandler);
+        let (sales, hr, hr_admin) = (
+            MakeBackend::new("sales"),
+            MakeBackend::new("hr"),
+            MakeBackend::new("hr admin"),
+        );
+        let router = Arc::new(
+            StartupRouter::new()
+                .with_database("sales", auth.clone(), sales.clone())
+                .with_route(Some("hr"), Some("admin"), auth.clone(), hr_admin.clone())
+                .with_database("hr", auth.clone(), hr.clone()),
+        );
+
+        let tag = |parameters| connect(&router, parameters);
+        assert_eq!(
+            "sales",
+            tag(&[("user", "alice"), ("database", "sales")])
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            "hr",
+            tag(&[("user", "alice"), ("database", "hr")]).await.unwrap()
+        );
+        assert_eq!(
+            "hr admin",
+            tag(&[("user", "admin"), ("database", "hr")]).await.unwrap()
+        );
+        // database defaults to user
+        assert_eq!("sales", tag(&[("user", "sales")]).await.unwrap());
+        assert_eq!(1, hr.made.load(Ordering::SeqCst));
+
+        let Err(PgWireError::UserError(error)) =
+            tag(&[("user", "alice"), ("database", "crm")]).await
+        else {
+            panic!("expect unknown database");
+        };
+        assert_eq!("3D000", error.code);
+        assert_eq!("database \"crm\" does not exist", error.message);
+
+        let crm = MakeBackend::new("crm");
+        let router = Arc::new(StartupRouter::new().with_fallback(auth, crm));
+        assert_eq!(
+            "crm",
+            connect(&router, &[("user", "alice"), ("database", "crm")])
+                .await
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,643 @@
+//! Encode rows of any `Serialize` type into a `QueryResponse`, enabled by
+//! feature `serde`.
+//!
+//! Column names are taken from struct fields or map keys, and column types
+//! are inferred from values: integers map to `INT2`/`INT4`/`INT8`, floats to
+//! `FLOAT4`/`FLOAT8`, strings, chars and unit enum variants to `VARCHAR`,
+//! bytes to `BYTEA`. `None` is encoded as null. Nested values like sequences
+//! or structs are not supported.
+
+use std::fmt;
+use std::sync::Arc;
+
+use ::serde::ser::{
+    self, Impossible, Serialize, SerializeMap, SerializeStruct, SerializeTuple,
+    SerializeTupleStruct, Serializer,
+};
+use futures::stream;
+
+use super::portal::Format;
+use super::results::{DataRowEncoder, FieldFormat, FieldInfo, QueryResponse};
+use super::Type;
+use crate::error::{PgWireError, PgWireResult};
+
+/// Error of serializing a row.
+#[derive(Debug)]
+pub struct SerializeError(String);
+
+impl fmt::Display for SerializeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for SerializeError {}
+
+impl ser::Error for SerializeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        SerializeError(msg.to_string())
+    }
+}
+
+impl From<SerializeError> for PgWireError {
+    fn from(e: SerializeError) -> Self {
+        PgWireError::ApiError(Box::new(e))
+    }
+}
+
+fn unsupported(what: &str) -> SerializeError {
+    SerializeError(format!("{what} is not supported as a column value"))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Cell {
+    Null,
+    Bool(bool),
+    Int2(i16),
+    Int4(i32),
+    Int8(i64),
+    Float4(f32),
+    Float8(f64),
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl Cell {
+    fn datatype(&self) -> Option<Type> {
+        match self {
+            Cell::Null => None,
+            Cell::Bool(_) => Some(Type::BOOL),
+            Cell::Int2(_) => Some(Type::INT2),
+            Cell::Int4(_) => Some(Type::INT4),
+            Cell::Int8(_) => Some(Type::INT8),
+            Cell::Float4(_) => Some(Type::FLOAT4),
+            Cell::Float8(_) => Some(Type::FLOAT8),
+            Cell::Text(_) => Some(Type::VARCHAR),
+            Cell::Bytes(_) => Some(Type::BYTEA),
+        }
+    }
+
+    fn encode(&self, encoder: &mut DataRowEncoder) -> PgWireResult<()> {
+        match self {
+            Cell::Null => encoder.encode_field(&None::<&str>),
+            Cell::Bool(v) => encoder.encode_field(v),
+            Cell::Int2(v) => encoder.encode_field(v),
+            Cell::Int4(v) => encoder.encode_field(v),
+            Cell::Int8(v) => encoder.encode_field(v),
+            Cell::Float4(v) => encoder.encode_field(v),
+            Cell::Float8(v) => encoder.encode_field(v),
+            Cell::Text(v) => encoder.encode_field(v),
+            Cell::Bytes(v) => encoder.encode_field(v),
+        }
+    }
+}
+
+// serializer of a single column value
+struct CellSerializer;
+
+impl Serializer for CellSerializer {
+    type Ok = Cell;
+    type Error = SerializeError;
+    type SerializeSeq = Impossible<Cell, SerializeError>;
+    type SerializeTuple = Impossible<Cell, SerializeError>;
+    type SerializeTupleStruct = Impossible<Cell, SerializeError>;
+    type SerializeTupleVariant = Impossible<Cell, SerializeError>;
+    type SerializeMap = Impossible<Cell, SerializeError>;
+    type SerializeStruct = Impossible<Cell, SerializeError>;
+    type SerializeStructVariant = Impossible<Cell, SerializeError>;
+
+    fn serialize_bool(self, v: bool) -> Result<Cell, SerializeError> {
+        Ok(Cell::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Cell, SerializeError> {
+        Ok(Cell::Int2(v.into()))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Cell, SerializeError> {
+        Ok(Cell::Int2(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Cell, SerializeError> {
+        Ok(Cell::Int4(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Cell, SerializeError> {
+        Ok(Cell::Int8(v))
+    }
+
+    // unsigned integers use the next larger type, as postgres has no
+    // unsigned types
+    fn serialize_u8(self, v: u8) -> Result<Cell, SerializeError> {
+        Ok(Cell::Int2(v.into()))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Cell, SerializeError> {
+        Ok(Cell::Int4(v.into()))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Cell, SerializeError> {
+        Ok(Cell::Int8(v.into()))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Cell, SerializeError> {
+        i64::try_from(v)
+            .map(Cell::Int8)
+            .map_err(|_| SerializeError(format!("{v} is out of range for type bigint")))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Cell, SerializeError> {
+        Ok(Cell::Float4(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Cell, SerializeError> {
+        Ok(Cell::Float8(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Cell, SerializeError> {
+        Ok(Cell::Text(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Cell, SerializeError> {
+        Ok(Cell::Text(v.to_owned()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Cell, SerializeError> {
+        Ok(Cell::Bytes(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<Cell, SerializeError> {
+        Ok(Cell::Null)
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Cell, SerializeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Cell, SerializeError> {
+        Ok(Cell::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Cell, SerializeError> {
+        Ok(Cell::Null)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Cell, SerializeError> {
+        Ok(Cell::Text(variant.to_owned()))
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Cell, SerializeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<Cell, SerializeError> {
+        Err(unsupported("enum variant with data"))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, SerializeError> {
+        Err(unsupported("sequence"))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, SerializeError> {
+        Err(unsupported("tuple"))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, SerializeError> {
+        Err(unsupported("tuple struct"))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, SerializeError> {
+        Err(unsupported("enum variant with data"))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, SerializeError> {
+        Err(unsupported("map"))
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, SerializeError> {
+        Err(unsupported("struct"))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, SerializeError> {
+        Err(unsupported("enum variant with data"))
+    }
+}
+
+type Row = Vec<(String, Cell)>;
+
+// serializer of a row, from a struct, a map or a tuple
+struct RowSerializer;
+
+// collects columns of a row
+#[derive(Default)]
+struct RowCollector {
+    columns: Row,
+    key: Option<String>,
+}
+
+impl RowCollector {
+    fn push<T: ?Sized + Serialize>(
+        &mut self,
+        name: String,
+        value: &T,
+    ) -> Result<(), SerializeError> {
+        self.columns.push((name, value.serialize(CellSerializer)?));
+        Ok(())
+    }
+}
+
+impl SerializeStruct for RowCollector {
+    type Ok = Row;
+    type Error = SerializeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerializeError> {
+        self.push(key.to_owned(), value)
+    }
+
+    fn end(self) -> Result<Row, SerializeError> {
+        Ok(self.columns)
+    }
+}
+
+impl SerializeMap for RowCollector {
+    type Ok = Row;
+    type Error = SerializeError;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), SerializeError> {
+        match key.serialize(CellSerializer)? {
+            Cell::Text(key) => {
+                self.key = Some(key);
+                Ok(())
+            }
+            _ => Err(SerializeError("column name must be a string".to_owned())),
+        }
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), SerializeError> {
+        let key = self.key.take().unwrap_or_default();
+        self.push(key, value)
+    }
+
+    fn end(self) -> Result<Row, SerializeError> {
+        Ok(self.columns)
+    }
+}
+
+// tuple columns are named like postgres names columns of `ROW`
+impl SerializeTuple for RowCollector {
+    type Ok = Row;
+    type Error = SerializeError;
+
+    fn serialize_element<T: ?Sized + Serialize>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), SerializeError> {
+        let name = format!("f{}", self.columns.len() + 1);
+        self.push(name, value)
+    }
+
+    fn end(self) -> Result<Row, SerializeError> {
+        Ok(self.columns)
+    }
+}
+
+impl SerializeTupleStruct for RowCollector {
+    type Ok = Row;
+    type Error = SerializeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), SerializeError> {
+        SerializeTuple::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Row, SerializeError> {
+        Ok(self.columns)
+    }
+}
+
+// a single value is a row of one column
+macro_rules! serialize_single {
+    ($($method:ident($ty:ty)),* $(,)?) => {
+        $(
+            fn $method(self, v: $ty) -> Result<Row, SerializeError> {
+                Ok(vec![("?column?".to_owned(), CellSerializer.$method(v)?)])
+            }
+        )*
+    };
+}
+
+impl Serializer for RowSerializer {
+    type Ok = Row;
+    type Error = SerializeError;
+    type SerializeSeq = Impossible<Row, SerializeError>;
+    type SerializeTuple = RowCollector;
+    type SerializeTupleStruct = RowCollector;
+    type SerializeTupleVariant = Impossible<Row, SerializeError>;
+    type SerializeMap = RowCollector;
+    type SerializeStruct = RowCollector;
+    type SerializeStructVariant = Impossible<Row, SerializeError>;
+
+    serialize_single!(
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_str(&str),
+        serialize_bytes(&[u8]),
+    );
+
+    fn serialize_none(self) -> Result<Row, SerializeError> {
+        Err(unsupported("null row"))
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Row, SerializeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Row, SerializeError> {
+        Ok(Row::new())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Row, SerializeError> {
+        Ok(Row::new())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Row, SerializeError> {
+        let cell = CellSerializer.serialize_unit_variant(name, variant_index, variant)?;
+        Ok(vec![("?column?".to_owned(), cell)])
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Row, SerializeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<Row, SerializeError> {
+        Err(unsupported("enum variant with data"))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, SerializeError> {
+        Err(unsupported("sequence"))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<RowCollector, SerializeError> {
+        Ok(RowCollector::default())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<RowCollector, SerializeError> {
+        Ok(RowCollector::default())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, SerializeError> {
+        Err(unsupported("enum variant with data"))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<RowCollector, SerializeError> {
+        Ok(RowCollector::default())
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<RowCollector, SerializeError> {
+        Ok(RowCollector::default())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, SerializeError> {
+        Err(unsupported("enum variant with data"))
+    }
+}
+
+fn serialize_rows<T: Serialize>(rows: &[T]) -> Result<Vec<Row>, SerializeError> {
+    let rows = rows
+        .iter()
+        .map(|row| row.serialize(RowSerializer))
+        .collect::<Result<Vec<Row>, SerializeError>>()?;
+
+    if let Some(first) = rows.first() {
+        let names_match =
+            |row: &Row| row.len() == first.len() && row.iter().zip(first).all(|(a, b)| a.0 == b.0);
+        if !rows.iter().all(names_match) {
+            return Err(SerializeError("rows have different columns".to_owned()));
+        }
+    }
+    Ok(rows)
+}
+
+fn schema_of(rows: &[Row], format: Option<&Format>) -> Vec<FieldInfo> {
+    let Some(first) = rows.first() else {
+        return Vec::new();
+    };
+    first
+        .iter()
+        .enumerate()
+        .map(|(idx, (name, _))| {
+            // columns with only nulls are text
+            let datatype = rows
+                .iter()
+                .find_map(|row| row[idx].1.datatype())
+                .unwrap_or(Type::VARCHAR);
+            let format = format
+                .map(|f| f.format_for(idx))
+                .unwrap_or(FieldFormat::Text);
+            FieldInfo::new(name.clone(), None, None, datatype, format)
+        })
+        .collect()
+}
+
+/// Infer the schema of `rows`, with result formats requested by client in
+/// `format`. Column types are from the first non-null value of each column.
+///
+/// There are no columns for empty `rows`, as names are only known from
+/// values.
+pub fn infer_schema<T: Serialize>(
+    rows: &[T],
+    format: Option<&Format>,
+) -> PgWireResult<Vec<FieldInfo>> {
+    Ok(schema_of(&serialize_rows(rows)?, format))
+}
+
+/// Encode `rows` into a `QueryResponse`, with schema from `infer_schema`.
+pub fn encode_rows<T: Serialize>(
+    rows: &[T],
+    format: Option<&Format>,
+) -> PgWireResult<QueryResponse<'static>> {
+    let rows = serialize_rows(rows)?;
+    let schema = Arc::new(schema_of(&rows, format));
+
+    let mut encoder = DataRowEncoder::new(schema.clone());
+    let data_rows = rows
+        .iter()
+        .map(|row| {
+            for (_, cell) in row {
+                cell.encode(&mut encoder)?;
+            }
+            encoder.take_row()
+        })
+        .collect::<Vec<_>>();
+    Ok(QueryResponse::new(schema, stream::iter(data_rows)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use bytes::{BufMut, BytesMut};
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[derive(::serde::Serialize)]
+    enum Status {
+        Active,
+    }
+
+    #[derive(::serde::Serialize)]
+    struct User {
+        id: u32,
+        name: &'static str,
+        score: Option<f64>,
+        status: Status,
+    }
+
+    #[tokio::test]
+    async fn test_encode_rows() {
+        let users = vec![
+            User {
+                id: 1,
+                name: "alice",
+                score: None,
+                status: Status::Active,
+            },
+            User {
+                id: 2,
+                name: "bob",
+                score: Some(0.5),
+                status: Status::Active,
+            },
+        ];
+        let response = encode_rows(&users, Some(&Format::UnifiedBinary)).unwrap();
+        let schema = response.row_schema();
+        assert_eq!(
+            vec!["id", "name", "score", "status"],
+            schema.iter().map(|f| f.name()).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![Type::INT8, Type::VARCHAR, Type::FLOAT8, Type::VARCHAR],
+            schema
+                .iter()
+                .map(|f| f.datatype().clone())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(FieldFormat::Binary, schema[0].format());
+
+        let rows = response.data_rows().collect::<Vec<_>>().await;
+        let first = rows[0].as_ref().unwrap();
+        let mut expected = BytesMut::new();
+        expected.put_i32(8);
+        expected.put_i64(1);
+        expected.put_i32(5);
+        expected.put_slice(b"alice");
+        expected.put_i32(-1);
+        expected.put_i32(6);
+        expected.put_slice(b"Active");
+        assert_eq!(expected, first.data);
+    }
+
+    #[test]
+    fn test_infer_schema() {
+        let rows = vec![BTreeMap::from([("a", 1i16), ("b", 2)])];
+        let schema = infer_schema(&rows, None).unwrap();
+        assert_eq!("b", schema[1].name());
+        assert_eq!(&Type::INT2, schema[1].datatype());
+        assert_eq!(FieldFormat::Text, schema[1].format());
+
+        let schema = infer_schema(&[(true, "x")], None).unwrap();
+        assert_eq!("f2", schema[1].name());
+
+        assert!(infer_schema::<User>(&[], None).unwrap().is_empty());
+        assert!(infer_schema(&[vec![1]], None).is_err());
+        assert!(infer_schema(
+            &[BTreeMap::from([("a", 1)]), BTreeMap::from([("b", 1)])],
+            None
+        )
+        .is_err());
+    }
+}
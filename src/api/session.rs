@@ -0,0 +1,915 @@
+//! Absorbing session management statements.
+//!
+//! Drivers and tools send `SET`, `SHOW`, `BEGIN`, `COMMIT`, `ROLLBACK` and
+//! `DISCARD ALL` on their own, for example `SET extra_float_digits = 3` on
+//! connect, which backends that are not postgres usually don't understand.
+//! `SessionQueryHandler` wraps query handlers to handle them like postgres,
+//! and passes other statements to the wrapped handler:
+//!
+//! * `SET` and `RESET` update parameters of the session, kept in
+//!   `ClientInfo::metadata` so handlers can read them. Changes of parameters
+//!   reported by postgres, like `TimeZone`, are sent as `ParameterStatus`.
+//!   `SET LOCAL` is handled like `SET`, and `SET TRANSACTION` is ignored.
+//! * `SHOW` answers with the parameter of session, or the default given to
+//!   the handler.
+//! * `BEGIN`, `COMMIT` and `ROLLBACK` complete with their tags, which update
+//!   transaction status of the session, with warnings like postgres when
+//!   there is already or no transaction.
+//! * `DISCARD ALL` resets all parameters. Prepared statements are kept, as
+//!   `PortalStore` doesn't support removing all of them.
+//!
+//! For extended query, the `QueryParser` of wrapped handler must accept these
+//! statements, like `NoopQueryParser` which keeps the query text.
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::sink::Sink;
+use futures::stream;
+
+use super::auth::DefaultServerParameterProvider;
+use super::encoding::ClientEncoding;
+use super::portal::Portal;
+use super::query::{
+    send_notice, send_parameter_status, split_statements, ExtendedQueryHandler, SimpleQueryHandler,
+};
+use super::results::{
+    DataRowEncoder, DescribePortalResponse, DescribeStatementResponse, FieldFormat, FieldInfo,
+    QueryResponse, Response, Tag,
+};
+use super::stmt::StoredStatement;
+use super::store::{PortalStore, PortalStoreListener};
+use super::{
+    ClientInfo, ClientPortalStore, Type, METADATA_DATABASE, METADATA_OPTIONS, METADATA_REPLICATION,
+    METADATA_USER,
+};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, SqlState};
+use crate::messages::response::TransactionStatus;
+use crate::messages::PgWireBackendMessage;
+
+/// Prefix of metadata keys keeping the value of a parameter before it's
+/// changed by `SET`, restored by `RESET`.
+pub const METADATA_RESET_PREFIX: &str = "pgwire.reset.";
+
+// parameters reported with `ParameterStatus` by postgres, in the case of
+// their names in it
+const REPORTED_PARAMETERS: [&str; 14] = [
+    "application_name",
+    "client_encoding",
+    "DateStyle",
+    "default_transaction_read_only",
+    "in_hot_standby",
+    "integer_datetimes",
+    "IntervalStyle",
+    "is_superuser",
+    "scram_iterations",
+    "server_encoding",
+    "server_version",
+    "session_authorization",
+    "standard_conforming_strings",
+    "TimeZone",
+];
+
+fn reported_name(name: &str) -> Option<&'static str> {
+    REPORTED_PARAMETERS
+        .iter()
+        .find(|reported| reported.eq_ignore_ascii_case(name))
+        .copied()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum SessionStatement {
+    /// `None` value is `DEFAULT`
+    Set(String, Option<String>),
+    /// `SET TRANSACTION` and others without effect
+    SetIgnored,
+    Show(String),
+    ShowAll,
+    Reset(String),
+    ResetAll,
+    Begin(&'static str),
+    Commit,
+    Rollback,
+    Discard(String),
+}
+
+// split the first word, an identifier maybe quoted, from `text`
+fn next_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    let end = if let Some(quoted) = text.strip_prefix('"') {
+        quoted.find('"').map_or(text.len(), |end| end + 2)
+    } else {
+        text.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(text.len())
+    };
+    (&text[..end], &text[end..])
+}
+
+// name of a parameter, case insensitive unless quoted
+fn parameter_name(word: &str) -> Option<String> {
+    if word.len() >= 2 && word.starts_with('"') && word.ends_with('"') {
+        return Some(word[1..word.len() - 1].to_owned());
+    }
+    (!word.is_empty()).then(|| word.to_lowercase())
+}
+
+// the rest of statement is `words`, case insensitive
+fn is_words(text: &str, words: &[&str]) -> bool {
+    text.split_whitespace()
+        .map(str::to_lowercase)
+        .eq(words.iter().map(|word| word.to_string()))
+}
+
+// value of `SET`, a list of literals and identifiers, `None` for `DEFAULT`
+fn parse_value(text: &str) -> Option<Option<String>> {
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
+    if text.eq_ignore_ascii_case("default") {
+        return Some(None);
+    }
+    let mut items = Vec::new();
+    let mut item = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => loop {
+                match chars.next()? {
+                    '\'' if chars.peek() == Some(&'\'') => {
+                        chars.next();
+                        item.push('\'');
+                    }
+                    '\'' => break,
+                    c => item.push(c),
+                }
+            },
+            ',' => items.push(std::mem::take(&mut item).trim().to_owned()),
+            c => item.push(c),
+        }
+    }
+    items.push(item.trim().to_owned());
+    Some(Some(items.join(", ")))
+}
+
+fn parse_set(text: &str) -> Option<SessionStatement> {
+    let (mut word, mut rest) = next_word(text);
+    if word.eq_ignore_ascii_case("session") || word.eq_ignore_ascii_case("local") {
+        let session = word.eq_ignore_ascii_case("session");
+        (word, rest) = next_word(rest);
+        if session && word.eq_ignore_ascii_case("characteristics") {
+            return Some(SessionStatement::SetIgnored);
+        }
+        if session && word.eq_ignore_ascii_case("authorization") {
+            let value = parse_value(rest)?;
+            return Some(SessionStatement::Set(
+                "session_authorization".to_owned(),
+                value,
+            ));
+        }
+    }
+    let name = match word.to_lowercase().as_str() {
+        "transaction" | "constraints" => return Some(SessionStatement::SetIgnored),
+        "time" => {
+            let (zone, value) = next_word(rest);
+            if !zone.eq_ignore_ascii_case("zone") {
+                return None;
+            }
+            let value = if value.trim().eq_ignore_ascii_case("local") {
+                None
+            } else {
+                parse_value(value)?
+            };
+            return Some(SessionStatement::Set("timezone".to_owned(), value));
+        }
+        "names" => {
+            let value = parse_value(rest)?;
+            return Some(SessionStatement::Set("client_encoding".to_owned(), value));
+        }
+        _ => parameter_name(word)?,
+    };
+    let rest = rest.trim_start();
+    let value = if let Some(value) = rest.strip_prefix('=') {
+        value
+    } else {
+        let (to, value) = next_word(rest);
+        if !to.eq_ignore_ascii_case("to") {
+            return None;
+        }
+        value
+    };
+    Some(SessionStatement::Set(name, parse_value(value)?))
+}
+
+// name of parameter in `SHOW` and `RESET`, `None` for `ALL`
+fn parse_target(text: &str) -> Option<Option<String>> {
+    if is_words(text, &["all"]) {
+        Some(None)
+    } else if is_words(text, &["time", "zone"]) {
+        Some(Some("timezone".to_owned()))
+    } else if is_words(text, &["transaction", "isolation", "level"]) {
+        Some(Some("transaction_isolation".to_owned()))
+    } else if is_words(text, &["session", "authorization"]) {
+        Some(Some("session_authorization".to_owned()))
+    } else {
+        let (word, rest) = next_word(text);
+        if !rest.trim().is_empty() {
+            return None;
+        }
+        parameter_name(word).map(Some)
+    }
+}
+
+// end of transaction, with optional `WORK`, `TRANSACTION` or `AND NO CHAIN`
+fn is_transaction_end(text: &str) -> bool {
+    let words = text
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>();
+    let words = match words.as_slice() {
+        [rest @ .., and, no, chain] if and == "and" && no == "no" && chain == "chain" => rest,
+        words => words,
+    };
+    matches!(words, [] | [_]) && words.iter().all(|w| w == "work" || w == "transaction")
+}
+
+fn parse_statement(statement: &str) -> Option<SessionStatement> {
+    let statement = statement.trim().trim_end_matches(';').trim_end();
+    let (command, rest) = next_word(statement);
+    match command.to_lowercase().as_str() {
+        "set" => parse_set(rest),
+        "show" => Some(match parse_target(rest)? {
+            Some(name) => SessionStatement::Show(name),
+            None => SessionStatement::ShowAll,
+        }),
+        "reset" => Some(match parse_target(rest)? {
+            Some(name) => SessionStatement::Reset(name),
+            None => SessionStatement::ResetAll,
+        }),
+        // transaction modes are ignored
+        "begin" => Some(SessionStatement::Begin("BEGIN")),
+        "start" => {
+            let (transaction, _) = next_word(rest);
+            transaction
+                .eq_ignore_ascii_case("transaction")
+                .then_some(SessionStatement::Begin("START TRANSACTION"))
+        }
+        "commit" | "end" if is_transaction_end(rest) => Some(SessionStatement::Commit),
+        "rollback" | "abort" if is_transaction_end(rest) => Some(SessionStatement::Rollback),
+        "discard" => {
+            let (target, extra) = next_word(rest);
+            let target = target.to_uppercase();
+            let known = matches!(
+                target.as_str(),
+                "ALL" | "PLANS" | "SEQUENCES" | "TEMP" | "TEMPORARY"
+            );
+            (known && extra.trim().is_empty()).then_some(SessionStatement::Discard(target))
+        }
+        _ => None,
+    }
+}
+
+/// Query handler answering session management statements, and passing
+/// other statements to the wrapped handler.
+#[derive(Debug)]
+pub struct SessionQueryHandler<H> {
+    inner: Arc<H>,
+    // default values of parameters, by lower case name
+    defaults: BTreeMap<String, String>,
+}
+
+impl<H> SessionQueryHandler<H> {
+    /// Handler with defaults of parameters from
+    /// `DefaultServerParameterProvider`.
+    pub fn new(inner: Arc<H>) -> SessionQueryHandler<H> {
+        let provider = DefaultServerParameterProvider::default();
+        let defaults = [
+            ("application_name", ""),
+            ("client_encoding", &provider.client_encoding),
+            ("datestyle", &provider.date_style),
+            ("extra_float_digits", "1"),
+            ("integer_datetimes", &provider.integer_datetimes),
+            ("intervalstyle", "postgres"),
+            ("search_path", "\"$user\", public"),
+            ("server_encoding", &provider.server_encoding),
+            ("server_version", &provider.server_version),
+            (
+                "standard_conforming_strings",
+                &provider.standard_conforming_strings,
+            ),
+            ("timezone", &provider.time_zone),
+            ("transaction_isolation", "read committed"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .collect();
+        SessionQueryHandler { inner, defaults }
+    }
+
+    /// Set default value of a parameter, shown when the session didn't set
+    /// it. Parameters without value are unknown to `SHOW`.
+    pub fn with_parameter(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> SessionQueryHandler<H> {
+        self.defaults
+            .insert(name.into().to_lowercase(), value.into());
+        self
+    }
+
+    /// Value of parameter `name` in session of `client`, or its default.
+    pub fn parameter<C: ClientInfo>(&self, client: &C, name: &str) -> Option<String> {
+        let name = name.to_lowercase();
+        metadata_key(client, &name)
+            .and_then(|key| client.metadata().get(&key).cloned())
+            .or_else(|| self.defaults.get(&name).cloned())
+    }
+
+    async fn execute<'a, C>(
+        &self,
+        client: &mut C,
+        statement: SessionStatement,
+    ) -> PgWireResult<Response<'a>>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let tag = match statement {
+            SessionStatement::Set(name, value) => {
+                let value = match value {
+                    Some(value) if name == "client_encoding" => {
+                        let encoding = ClientEncoding::from_name(&value)
+                            .ok_or_else(|| invalid_value(&name, &value))?;
+                        Some(encoding.name().to_owned())
+                    }
+                    value => value,
+                };
+                self.change_parameter(client, &name, value).await?;
+                "SET"
+            }
+            SessionStatement::SetIgnored => "SET",
+            SessionStatement::Reset(name) => {
+                self.change_parameter(client, &name, None).await?;
+                "RESET"
+            }
+            SessionStatement::ResetAll => {
+                self.reset_all(client).await?;
+                "RESET"
+            }
+            SessionStatement::Show(name) => {
+                let value = self.parameter(client, &name).ok_or_else(|| {
+                    PgWireError::UserError(Box::new(ErrorInfo::new(
+                        "ERROR".to_owned(),
+                        SqlState::UNDEFINED_OBJECT.into(),
+                        format!("unrecognized configuration parameter \"{name}\""),
+                    )))
+                })?;
+                let label = reported_name(&name).map_or(name, str::to_owned);
+                return show_response(vec![label], vec![vec![value]]);
+            }
+            SessionStatement::ShowAll => {
+                let rows = self
+                    .parameter_names(client)
+                    .into_iter()
+                    .filter_map(|name| {
+                        let value = self.parameter(client, &name)?;
+                        Some(vec![name, value, String::new()])
+                    })
+                    .collect();
+                let labels = ["name", "setting", "description"].map(str::to_owned);
+                return show_response(labels.to_vec(), rows);
+            }
+            SessionStatement::Begin(tag) => {
+                if client.transaction_status() != TransactionStatus::Idle {
+                    let warning = transaction_warning(
+                        SqlState::ACTIVE_SQL_TRANSACTION,
+                        "there is already a transaction in progress",
+                    );
+                    send_notice(client, warning).await?;
+                }
+                tag
+            }
+            SessionStatement::Commit | SessionStatement::Rollback => {
+                match client.transaction_status() {
+                    TransactionStatus::Idle => {
+                        let warning = transaction_warning(
+                            SqlState::NO_ACTIVE_SQL_TRANSACTION,
+                            "there is no transaction in progress",
+                        );
+                        send_notice(client, warning).await?;
+                    }
+                    // a failed transaction is rolled back by `COMMIT`
+                    TransactionStatus::Error => {
+                        return Ok(Response::Execution(Tag::new("ROLLBACK")))
+                    }
+                    TransactionStatus::Transaction => {}
+                }
+                if statement == SessionStatement::Commit {
+                    "COMMIT"
+                } else {
+                    "ROLLBACK"
+                }
+            }
+            SessionStatement::Discard(target) => {
+                if target == "ALL" {
+                    if client.transaction_status() != TransactionStatus::Idle {
+                        return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                            "ERROR".to_owned(),
+                            SqlState::ACTIVE_SQL_TRANSACTION.into(),
+                            "DISCARD ALL cannot run inside a transaction block".to_owned(),
+                        ))));
+                    }
+                    self.reset_all(client).await?;
+                }
+                return Ok(Response::Execution(Tag::new(&format!("DISCARD {target}"))));
+            }
+        };
+        Ok(Response::Execution(Tag::new(tag)))
+    }
+
+    /// Set parameter `name` to `value`, or restore the value before it's
+    /// set for `None`. The change is reported if postgres reports it.
+    async fn change_parameter<C>(
+        &self,
+        client: &mut C,
+        name: &str,
+        value: Option<String>,
+    ) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let key = metadata_key(client, name).unwrap_or_else(|| name.to_owned());
+        let reset_key = format!("{METADATA_RESET_PREFIX}{name}");
+        let metadata = client.metadata_mut();
+        match value {
+            Some(value) => {
+                if !metadata.contains_key(&reset_key) {
+                    // `=` marks a value, the parameter was unset without it
+                    let original = metadata
+                        .get(&key)
+                        .map(|value| format!("={value}"))
+                        .unwrap_or_default();
+                    metadata.insert(reset_key, original);
+                }
+                metadata.insert(key, value);
+            }
+            None => match metadata.remove(&reset_key) {
+                Some(original) => match original.strip_prefix('=') {
+                    Some(original) => {
+                        metadata.insert(key, original.to_owned());
+                    }
+                    None => {
+                        metadata.remove(&key);
+                    }
+                },
+                // never set
+                None => return Ok(()),
+            },
+        }
+
+        let current = self.parameter(client, name).unwrap_or_default();
+        if name == "client_encoding" {
+            if let Some(encoding) = ClientEncoding::from_name(&current) {
+                client.set_client_encoding(encoding);
+            }
+        }
+        if let Some(reported) = reported_name(name) {
+            send_parameter_status(client, reported, &current).await?;
+        }
+        Ok(())
+    }
+
+    async fn reset_all<C>(&self, client: &mut C) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let names = client
+            .metadata()
+            .keys()
+            .filter_map(|key| key.strip_prefix(METADATA_RESET_PREFIX))
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        for name in names {
+            self.change_parameter(client, &name, None).await?;
+        }
+        Ok(())
+    }
+
+    /// Names of parameters with a value, in lower case.
+    fn parameter_names<C: ClientInfo>(&self, client: &C) -> Vec<String> {
+        let mut names = self.defaults.keys().cloned().collect::<Vec<_>>();
+        for key in client.metadata().keys() {
+            let is_parameter = ![
+                METADATA_USER,
+                METADATA_DATABASE,
+                METADATA_OPTIONS,
+                METADATA_REPLICATION,
+            ]
+            .contains(&key.as_str())
+                && !key.starts_with(METADATA_RESET_PREFIX);
+            if is_parameter {
+                names.push(key.to_lowercase());
+            }
+        }
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+// key of parameter `name` in metadata, which keeps the case of startup
+// parameters like `DateStyle`
+fn metadata_key<C: ClientInfo>(client: &C, name: &str) -> Option<String> {
+    client
+        .metadata()
+        .keys()
+        .find(|key| key.eq_ignore_ascii_case(name))
+        .cloned()
+}
+
+fn invalid_value(name: &str, value: &str) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        SqlState::INVALID_PARAMETER_VALUE.into(),
+        format!("invalid value for parameter \"{name}\": \"{value}\""),
+    )))
+}
+
+fn transaction_warning(code: SqlState, message: &str) -> ErrorInfo {
+    ErrorInfo::new("WARNING".to_owned(), code.into(), message.to_owned())
+}
+
+fn show_fields(labels: Vec<String>) -> Vec<FieldInfo> {
+    labels
+        .into_iter()
+        .map(|label| FieldInfo::new(label, None, None, Type::TEXT, FieldFormat::Text))
+        .collect()
+}
+
+fn show_response<'a>(labels: Vec<String>, rows: Vec<Vec<String>>) -> PgWireResult<Response<'a>> {
+    let fields = Arc::new(show_fields(labels));
+    let rows = rows
+        .into_iter()
+        .map(|row| {
+            let mut encoder = DataRowEncoder::new(fields.clone());
+            for value in row {
+                encoder.encode_field(&value)?;
+            }
+            encoder.finish()
+        })
+        .collect::<Vec<_>>();
+    let mut response = QueryResponse::new(fields, stream::iter(rows));
+    response.set_command_tag("SHOW");
+    Ok(Response::Query(response))
+}
+
+// columns of `statement`, empty if it returns no rows
+fn describe_fields(statement: &SessionStatement) -> Vec<FieldInfo> {
+    match statement {
+        SessionStatement::Show(name) => {
+            let label = reported_name(name).map_or_else(|| name.clone(), str::to_owned);
+            show_fields(vec![label])
+        }
+        SessionStatement::ShowAll => show_fields(
+            ["name", "setting", "description"]
+                .map(str::to_owned)
+                .to_vec(),
+        ),
+        _ => Vec::new(),
+    }
+}
+
+#[async_trait]
+impl<H> SimpleQueryHandler for SessionQueryHandler<H>
+where
+    H: SimpleQueryHandler,
+{
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        query: &'a str,
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        // queries mixing session statements with others are left to the
+        // wrapped handler
+        let statements = split_statements(query)
+            .into_iter()
+            .map(parse_statement)
+            .collect::<Option<Vec<_>>>();
+        let statements = match statements {
+            Some(statements) if !statements.is_empty() => statements,
+            _ => return self.inner.do_query(client, query).await,
+        };
+        let mut responses = Vec::new();
+        for statement in statements {
+            match self.execute(client, statement).await {
+                Ok(response) => responses.push(response),
+                Err(e) if responses.is_empty() => return Err(e),
+                Err(e) => {
+                    responses.push(Response::Error(Box::new(e.to_error_info())));
+                    break;
+                }
+            }
+        }
+        Ok(responses)
+    }
+}
+
+#[async_trait]
+impl<H> ExtendedQueryHandler for SessionQueryHandler<H>
+where
+    H: ExtendedQueryHandler,
+{
+    type Statement = H::Statement;
+    type QueryParser = H::QueryParser;
+
+    fn query_parser(&self) -> Arc<Self::QueryParser> {
+        self.inner.query_parser()
+    }
+
+    fn portal_store_listener(&self) -> Option<Arc<dyn PortalStoreListener<Self::Statement>>> {
+        self.inner.portal_store_listener()
+    }
+
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        portal: &'a Portal<Self::Statement>,
+        max_rows: usize,
+    ) -> PgWireResult<Response<'a>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        match parse_statement(&portal.statement.query) {
+            Some(statement) => self.execute(client, statement).await,
+            None => self.inner.do_query(client, portal, max_rows).await,
+        }
+    }
+
+    async fn do_describe_statement<C>(
+        &self,
+        client: &mut C,
+        target: &StoredStatement<Self::Statement>,
+    ) -> PgWireResult<DescribeStatementResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        match parse_statement(&target.query) {
+            Some(statement) => Ok(DescribeStatementResponse::new(
+                vec![],
+                describe_fields(&statement),
+            )),
+            None => self.inner.do_describe_statement(client, target).await,
+        }
+    }
+
+    async fn do_describe_portal<C>(
+        &self,
+        client: &mut C,
+        target: &Portal<Self::Statement>,
+    ) -> PgWireResult<DescribePortalResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        match parse_statement(&target.statement.query) {
+            Some(statement) => {
+                let fields = describe_fields(&statement)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(idx, field)| {
+                        field.with_format(target.result_column_format.format_for(idx))
+                    })
+                    .collect();
+                Ok(DescribePortalResponse::new(fields))
+            }
+            None => self.inner.do_describe_portal(client, target).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::api::mock::MockClient;
+    use crate::messages::response::CommandComplete;
+
+    struct FailingHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for FailingHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            Err(PgWireError::ApiError(
+                format!("unsupported: {query}").into(),
+            ))
+        }
+    }
+
+    #[test]
+    fn test_parse_statement() {
+        let set = |name: &str, value: Option<&str>| {
+            Some(SessionStatement::Set(
+                name.to_owned(),
+                value.map(str::to_owned),
+            ))
+        };
+        assert_eq!(
+            set("extra_float_digits", Some("3")),
+            parse_statement("SET extra_float_digits = 3")
+        );
+        assert_eq!(
+            set("search_path", Some("a, b c")),
+            parse_statement("set session search_path to 'a', 'b c';")
+        );
+        assert_eq!(
+            set("timezone", Some("UTC")),
+            parse_statement("SET TIME ZONE 'UTC'")
+        );
+        assert_eq!(
+            set("DateStyle", None),
+            parse_statement("SET \"DateStyle\" TO DEFAULT")
+        );
+        assert_eq!(
+            Some(SessionStatement::SetIgnored),
+            parse_statement("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE")
+        );
+        assert_eq!(
+            Some(SessionStatement::Show("transaction_isolation".to_owned())),
+            parse_statement("SHOW TRANSACTION ISOLATION LEVEL")
+        );
+        assert_eq!(
+            Some(SessionStatement::ResetAll),
+            parse_statement("RESET ALL")
+        );
+        assert_eq!(
+            Some(SessionStatement::Begin("START TRANSACTION")),
+            parse_statement("START TRANSACTION READ ONLY")
+        );
+        assert_eq!(
+            Some(SessionStatement::Commit),
+            parse_statement("commit and no chain")
+        );
+        assert_eq!(Some(SessionStatement::Rollback), parse_statement("ABORT"));
+        assert_eq!(
+            Some(SessionStatement::Discard("ALL".to_owned())),
+            parse_statement("DISCARD ALL")
+        );
+
+        for statement in [
+            "SELECT 1",
+            "ROLLBACK TO SAVEPOINT a",
+            "COMMIT PREPARED 'x'",
+            "SET a b",
+            "SHOW a b",
+        ] {
+            assert_eq!(None, parse_statement(statement), "{statement}");
+        }
+    }
+
+    async fn show(
+        handler: &SessionQueryHandler<FailingHandler>,
+        client: &mut MockClient,
+        query: &str,
+    ) -> String {
+        let mut responses = handler.do_query(client, query).await.unwrap();
+        let Some(Response::Query(response)) = responses.pop() else {
+            panic!("expect query response");
+        };
+        let row = response.data_rows().next().await.unwrap().unwrap();
+        let fields = row.fields().unwrap();
+        String::from_utf8(fields[0].unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_session_parameters() {
+        let handler = SessionQueryHandler::new(Arc::new(FailingHandler)).with_parameter("x.y", "z");
+        let mut client = MockClient::new();
+        client
+            .metadata_mut()
+            .insert("DateStyle".to_owned(), "ISO, DMY".to_owned());
+
+        assert_eq!(
+            "ISO, DMY",
+            show(&handler, &mut client, "SHOW datestyle").await
+        );
+        assert_eq!("z", show(&handler, &mut client, "SHOW x.y").await);
+        handler
+            .do_query(
+                &mut client,
+                "SET datestyle = 'ISO, MDY'; SET TIME ZONE 'UTC'",
+            )
+            .await
+            .unwrap();
+        assert_eq!("ISO, MDY", client.metadata()["DateStyle"]);
+        assert_eq!(
+            "ISO, MDY",
+            show(&handler, &mut client, "SHOW DateStyle").await
+        );
+        let reported = client
+            .sent
+            .iter()
+            .filter_map(|message| match message {
+                PgWireBackendMessage::ParameterStatus(status) => {
+                    Some((status.name.as_str(), status.value.as_str()))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![("DateStyle", "ISO, MDY"), ("TimeZone", "UTC")],
+            reported
+        );
+
+        handler.do_query(&mut client, "RESET ALL").await.unwrap();
+        assert_eq!(
+            "ISO, DMY",
+            show(&handler, &mut client, "SHOW datestyle").await
+        );
+        assert!(!client.metadata().contains_key("timezone"));
+
+        // unknown parameters and other statements
+        assert!(handler.do_query(&mut client, "SHOW nothing").await.is_err());
+        assert!(handler
+            .do_query(&mut client, "SET a = 1; SELECT 1")
+            .await
+            .is_err());
+
+        handler
+            .do_query(&mut client, "SET client_encoding TO 'latin1'")
+            .await
+            .unwrap();
+        assert_eq!("LATIN1", client.client_encoding().name());
+        assert!(handler
+            .do_query(&mut client, "SET NAMES 'klingon'")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_transaction_statements() {
+        let handler = SessionQueryHandler::new(Arc::new(FailingHandler));
+        let mut client = MockClient::new();
+
+        let tag = |mut responses: Vec<Response>| match responses.pop() {
+            Some(Response::Execution(tag)) if responses.is_empty() => {
+                CommandComplete::from(tag).tag
+            }
+            _ => panic!("expect execution response"),
+        };
+        assert_eq!(
+            "BEGIN",
+            tag(handler.do_query(&mut client, "BEGIN").await.unwrap())
+        );
+        client.set_transaction_status(TransactionStatus::Error);
+        assert_eq!(
+            "ROLLBACK",
+            tag(handler.do_query(&mut client, "COMMIT").await.unwrap())
+        );
+        client.set_transaction_status(TransactionStatus::Idle);
+        assert!(client.sent.is_empty());
+
+        // warning without transaction
+        assert_eq!(
+            "ROLLBACK",
+            tag(handler.do_query(&mut client, "ROLLBACK").await.unwrap())
+        );
+        assert!(matches!(
+            client.sent[..],
+            [PgWireBackendMessage::NoticeResponse(_)]
+        ));
+
+        client.set_transaction_status(TransactionStatus::Transaction);
+        assert!(handler.do_query(&mut client, "DISCARD ALL").await.is_err());
+    }
+}
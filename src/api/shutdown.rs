@@ -0,0 +1,177 @@
+//! Graceful shutdown of a server.
+//!
+//! Connections processed with a `GracefulShutdown` in `ServerOptions` are
+//! tracked by it. When shutdown is requested, idle sessions are terminated
+//! with `57P01 admin_shutdown` like postgres, and sessions running a query
+//! are terminated as soon as the query completes. Queries still running at
+//! the deadline are interrupted.
+//!
+//! The accept loop should stop accepting when `requested` resolves, then wait
+//! for `shutdown` to drain the connections:
+//!
+//! ```ignore
+//! let shutdown = GracefulShutdown::new();
+//! let options = Arc::new(ServerOptions::new().with_graceful_shutdown(shutdown.clone()));
+//! loop {
+//!     tokio::select! {
+//!         accepted = listener.accept() => { /* spawn process_socket_with_options */ }
+//!         _ = shutdown.requested() => break,
+//!     }
+//! }
+//! shutdown.shutdown(Duration::from_secs(30)).await;
+//! ```
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::{ErrorInfo, SqlState};
+use crate::sansio::PgWireConnectionState;
+
+#[derive(Debug, Default)]
+struct Inner {
+    requested: CancellationToken,
+    forced: CancellationToken,
+    connections: AtomicUsize,
+    drained: Notify,
+}
+
+/// Coordinates shutdown of connections, cheap to clone.
+#[derive(Debug, Default, Clone)]
+pub struct GracefulShutdown {
+    inner: Arc<Inner>,
+}
+
+impl GracefulShutdown {
+    pub fn new() -> GracefulShutdown {
+        GracefulShutdown::default()
+    }
+
+    /// Request shutdown, without waiting for connections.
+    pub fn request(&self) {
+        self.inner.requested.cancel();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.inner.requested.is_cancelled()
+    }
+
+    /// Resolves when shutdown is requested.
+    pub async fn requested(&self) {
+        self.inner.requested.cancelled().await
+    }
+
+    /// Resolves when queries should be interrupted, after the deadline of
+    /// `shutdown`.
+    pub(crate) async fn forced(&self) {
+        self.inner.forced.cancelled().await
+    }
+
+    /// Number of open connections.
+    pub fn connections(&self) -> usize {
+        self.inner.connections.load(Ordering::SeqCst)
+    }
+
+    /// Request shutdown and wait until all connections are closed. Queries
+    /// still running after `timeout` are interrupted. Returns false if any
+    /// query was interrupted.
+    pub async fn shutdown(&self, timeout: Duration) -> bool {
+        self.request();
+        let drained = tokio::time::timeout(timeout, self.drained()).await.is_ok();
+        if !drained {
+            self.inner.forced.cancel();
+            self.drained().await;
+        }
+        drained
+    }
+
+    async fn drained(&self) {
+        loop {
+            // registered before checking, so a wakeup is not missed
+            let notified = self.inner.drained.notified();
+            if self.connections() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Track a connection until the returned guard is dropped.
+    pub(crate) fn track(&self) -> ConnectionTracker {
+        self.inner.connections.fetch_add(1, Ordering::SeqCst);
+        ConnectionTracker {
+            shutdown: self.clone(),
+        }
+    }
+}
+
+pub(crate) struct ConnectionTracker {
+    shutdown: GracefulShutdown,
+}
+
+impl Drop for ConnectionTracker {
+    fn drop(&mut self) {
+        let inner = &self.shutdown.inner;
+        if inner.connections.fetch_sub(1, Ordering::SeqCst) == 1 {
+            inner.drained.notify_waiters();
+        }
+    }
+}
+
+/// Whether a session in `state` can be terminated without interrupting a
+/// query.
+pub(crate) fn is_idle(state: PgWireConnectionState) -> bool {
+    matches!(
+        state,
+        PgWireConnectionState::AwaitingStartup
+            | PgWireConnectionState::AuthenticationInProgress
+            | PgWireConnectionState::ReadyForQuery
+    )
+}
+
+/// Error sent to sessions terminated by shutdown.
+pub fn admin_shutdown_error() -> ErrorInfo {
+    ErrorInfo::new(
+        "FATAL".to_owned(),
+        SqlState::ADMIN_SHUTDOWN.into(),
+        "terminating connection due to administrator command".to_owned(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_drains_connections() {
+        let shutdown = GracefulShutdown::new();
+        let tracker = shutdown.track();
+        assert_eq!(1, shutdown.connections());
+
+        let closer = {
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                shutdown.requested().await;
+                drop(tracker);
+            })
+        };
+        assert!(shutdown.shutdown(Duration::from_secs(10)).await);
+        closer.await.unwrap();
+        assert_eq!(0, shutdown.connections());
+
+        // a connection that doesn't close in time is forced
+        let tracker = shutdown.track();
+        let forced = {
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                shutdown.forced().await;
+                drop(tracker);
+            })
+        };
+        assert!(!shutdown.shutdown(Duration::from_millis(10)).await);
+        forced.await.unwrap();
+    }
+}
@@ -1,11 +1,16 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
 
 use async_trait::async_trait;
-use postgres_types::Type;
+use postgres_types::{Oid, Type};
 
 use crate::error::PgWireResult;
 use crate::messages::extendedquery::Parse;
+use crate::sql;
 
+use super::comment::SqlComment;
+use super::portal::Format;
+use super::results::FieldInfo;
 use super::DEFAULT_NAME;
 
 #[non_exhaustive]
@@ -15,9 +20,61 @@ pub struct StoredStatement<S> {
     pub id: String,
     /// parsed query statement
     pub statement: S,
-    /// type ids of query parameters, can be empty if frontend asks backend for
-    /// type inference
+    /// types of query parameters, `Type::UNKNOWN` for those not specified by
+    /// frontend, which asks backend for type inference
     pub parameter_types: Vec<Type>,
+    /// metadata from sqlcommenter style comment of the query
+    #[new(default)]
+    pub comment: Option<SqlComment>,
+    /// source text of the query
+    #[new(default)]
+    pub query: String,
+    #[new(default)]
+    schema_cache: SchemaCache,
+}
+
+/// Result schemas of a statement from `QueryParser::get_result_schema`, in
+/// text format and in the result format of the last portal.
+#[derive(Debug, Default)]
+struct SchemaCache {
+    text: OnceLock<Arc<Vec<FieldInfo>>>,
+    last_format: Mutex<Option<(Format, Arc<Vec<FieldInfo>>)>>,
+}
+
+impl<S> StoredStatement<S> {
+    /// Result schema of the statement from `parser`, cached so portals
+    /// executed repeatedly from the statement share the schema, instead of
+    /// describing it again. `format` is like in
+    /// `QueryParser::get_result_schema`.
+    ///
+    /// The schema of a prepared statement is assumed not to change, like in
+    /// postgres.
+    pub fn result_schema<P>(
+        &self,
+        parser: &P,
+        format: Option<&Format>,
+    ) -> PgWireResult<Arc<Vec<FieldInfo>>>
+    where
+        P: QueryParser<Statement = S> + ?Sized,
+    {
+        let Some(format) = format else {
+            if let Some(schema) = self.schema_cache.text.get() {
+                return Ok(schema.clone());
+            }
+            let schema = Arc::new(parser.get_result_schema(&self.statement, None)?);
+            return Ok(self.schema_cache.text.get_or_init(|| schema).clone());
+        };
+
+        if let Some((last_format, schema)) = self.schema_cache.last_format.lock().unwrap().as_ref()
+        {
+            if last_format == format {
+                return Ok(schema.clone());
+            }
+        }
+        let schema = Arc::new(parser.get_result_schema(&self.statement, Some(format))?);
+        *self.schema_cache.last_format.lock().unwrap() = Some((format.clone(), schema.clone()));
+        Ok(schema)
+    }
 }
 
 impl<S> StoredStatement<S> {
@@ -31,6 +88,12 @@ impl<S> StoredStatement<S> {
             .map(|oid| Type::from_oid(*oid).unwrap_or(Type::UNKNOWN))
             .collect::<Vec<Type>>();
         let statement = parser.parse_sql(&parse.query, &types).await?;
+        // frontend can leave parameters out of `Parse`
+        let mut types = types;
+        let count = sql::parameter_count(&parse.query);
+        if types.len() < count {
+            types.resize(count, Type::UNKNOWN);
+        }
         Ok(StoredStatement {
             id: parse
                 .name
@@ -38,17 +101,47 @@ impl<S> StoredStatement<S> {
                 .unwrap_or_else(|| DEFAULT_NAME.to_owned()),
             statement,
             parameter_types: types,
+            comment: SqlComment::parse(&parse.query),
+            query: parse.query.clone(),
+            schema_cache: SchemaCache::default(),
         })
     }
 }
 
 /// Trait for sql parser. The parser transforms string query into its statement
 /// type.
+///
+/// It's also used by default implementations of `ExtendedQueryHandler` to
+/// describe statements and portals before execution.
 #[async_trait]
 pub trait QueryParser {
     type Statement;
 
     async fn parse_sql(&self, sql: &str, types: &[Type]) -> PgWireResult<Self::Statement>;
+
+    /// Types of parameters in the statement, sent in `ParameterDescription`.
+    /// `types` are those from `Parse`, `Type::UNKNOWN` for ones left to
+    /// inference. The default implementation returns `types` as is.
+    fn get_parameter_types(
+        &self,
+        _stmt: &Self::Statement,
+        types: &[Type],
+    ) -> PgWireResult<Vec<Type>> {
+        Ok(types.to_vec())
+    }
+
+    /// Columns of the statement result, sent in `RowDescription`. `format` is
+    /// the result format requested in `Bind` when describing a portal, and
+    /// `None` for a statement, whose result is described in text format. The
+    /// default implementation returns no columns, like a statement returning
+    /// no rows.
+    fn get_result_schema(
+        &self,
+        _stmt: &Self::Statement,
+        _format: Option<&Format>,
+    ) -> PgWireResult<Vec<FieldInfo>> {
+        Ok(vec![])
+    }
 }
 
 #[async_trait]
@@ -61,6 +154,144 @@ where
     async fn parse_sql(&self, sql: &str, types: &[Type]) -> PgWireResult<Self::Statement> {
         (**self).parse_sql(sql, types).await
     }
+
+    fn get_parameter_types(
+        &self,
+        stmt: &Self::Statement,
+        types: &[Type],
+    ) -> PgWireResult<Vec<Type>> {
+        (**self).get_parameter_types(stmt, types)
+    }
+
+    fn get_result_schema(
+        &self,
+        stmt: &Self::Statement,
+        format: Option<&Format>,
+    ) -> PgWireResult<Vec<FieldInfo>> {
+        (**self).get_result_schema(stmt, format)
+    }
+}
+
+/// A `QueryParser` wrapper that caches parsed statements, so a statement
+/// prepared by many clients is only parsed once.
+///
+/// Statements are keyed by parameter types and normalized query text, with
+/// whitespace and comments ignored, so the statement returned may be parsed
+/// from a query that differs in those. Least recently used entries are
+/// evicted when `capacity` is reached.
+#[derive(Debug)]
+pub struct CachedQueryParser<P: QueryParser> {
+    inner: P,
+    capacity: usize,
+    cache: Mutex<ParserCache<P::Statement>>,
+}
+
+#[derive(Debug)]
+struct ParserCache<S> {
+    entries: HashMap<(String, Vec<Oid>), (S, u64)>,
+    tick: u64,
+}
+
+impl<P: QueryParser> CachedQueryParser<P> {
+    pub fn new(inner: P, capacity: usize) -> CachedQueryParser<P> {
+        CachedQueryParser {
+            inner,
+            capacity,
+            cache: Mutex::new(ParserCache {
+                entries: HashMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+
+    /// Number of cached statements.
+    pub fn len(&self) -> usize {
+        self.cache.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drop all cached statements, for example after schema changes.
+    pub fn clear(&self) {
+        self.cache.lock().unwrap().entries.clear();
+    }
+}
+
+/// Normalize query text for caching: whitespace and comments between tokens
+/// are replaced by single spaces, so they don't make a difference. Adjacent
+/// tokens are kept adjacent, as `>=` is not the same as `> =`.
+fn normalize_sql(sql: &str) -> String {
+    let mut normalized = String::with_capacity(sql.len());
+    let mut lexer = sql::Lexer::new(sql);
+    let mut last_end = 0;
+    while let Some(token) = lexer.next() {
+        let end = lexer.offset();
+        if !normalized.is_empty() && end - token.text.len() > last_end {
+            normalized.push(' ');
+        }
+        normalized.push_str(token.text);
+        last_end = end;
+    }
+    normalized
+}
+
+#[async_trait]
+impl<P> QueryParser for CachedQueryParser<P>
+where
+    P: QueryParser + Send + Sync,
+    P::Statement: Clone + Send,
+{
+    type Statement = P::Statement;
+
+    async fn parse_sql(&self, sql: &str, types: &[Type]) -> PgWireResult<Self::Statement> {
+        let key = (normalize_sql(sql), types.iter().map(Type::oid).collect());
+        {
+            let mut cache = self.cache.lock().unwrap();
+            cache.tick += 1;
+            let tick = cache.tick;
+            if let Some((statement, last_used)) = cache.entries.get_mut(&key) {
+                *last_used = tick;
+                return Ok(statement.clone());
+            }
+        }
+
+        let statement = self.inner.parse_sql(sql, types).await?;
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.entries.len() >= self.capacity {
+            let victim = cache
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(k, _)| k.clone());
+            if let Some(victim) = victim {
+                cache.entries.remove(&victim);
+            }
+        }
+        if self.capacity > 0 {
+            let tick = cache.tick;
+            cache.entries.insert(key, (statement.clone(), tick));
+        }
+        Ok(statement)
+    }
+
+    fn get_parameter_types(
+        &self,
+        stmt: &Self::Statement,
+        types: &[Type],
+    ) -> PgWireResult<Vec<Type>> {
+        self.inner.get_parameter_types(stmt, types)
+    }
+
+    fn get_result_schema(
+        &self,
+        stmt: &Self::Statement,
+        format: Option<&Format>,
+    ) -> PgWireResult<Vec<FieldInfo>> {
+        self.inner.get_result_schema(stmt, format)
+    }
 }
 
 /// A demo parser implementation. Never use it in serious application.
@@ -75,3 +306,125 @@ impl QueryParser for NoopQueryParser {
         Ok(sql.to_owned())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::api::results::FieldFormat;
+
+    #[derive(Default)]
+    struct CountingParser {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl QueryParser for CountingParser {
+        type Statement = String;
+
+        async fn parse_sql(&self, sql: &str, _types: &[Type]) -> PgWireResult<Self::Statement> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(sql.to_owned())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_query_parser() {
+        let parser = CachedQueryParser::new(CountingParser::default(), 2);
+
+        parser.parse_sql("SELECT $1", &[Type::INT4]).await.unwrap();
+        parser
+            .parse_sql("SELECT  $1 /* comment */", &[Type::INT4])
+            .await
+            .unwrap();
+        assert_eq!(1, parser.inner.calls.load(Ordering::SeqCst));
+
+        // different types
+        parser.parse_sql("SELECT $1", &[Type::TEXT]).await.unwrap();
+        assert_eq!(2, parser.inner.calls.load(Ordering::SeqCst));
+
+        // evicts least recently used SELECT $1::INT4
+        parser.parse_sql("SELECT 1", &[]).await.unwrap();
+        assert_eq!(2, parser.len());
+        parser.parse_sql("SELECT $1", &[Type::TEXT]).await.unwrap();
+        assert_eq!(3, parser.inner.calls.load(Ordering::SeqCst));
+        parser.parse_sql("SELECT $1", &[Type::INT4]).await.unwrap();
+        assert_eq!(4, parser.inner.calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_normalize_sql() {
+        assert_eq!(
+            "SELECT a FROM t WHERE a >= $1",
+            normalize_sql(" SELECT a\n  FROM t /* c */ WHERE a >= $1 -- c")
+        );
+        assert_eq!("a>=1", normalize_sql("a>=1"));
+        assert_eq!("a > = 1", normalize_sql("a >/**/= 1"));
+        assert_ne!(normalize_sql("a >= 1"), normalize_sql("a > = 1"));
+        assert_ne!(
+            normalize_sql("SELECT 'a  b'"),
+            normalize_sql("SELECT 'a b'")
+        );
+    }
+
+    #[derive(Default)]
+    struct SchemaParser {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl QueryParser for SchemaParser {
+        type Statement = String;
+
+        async fn parse_sql(&self, sql: &str, _types: &[Type]) -> PgWireResult<Self::Statement> {
+            Ok(sql.to_owned())
+        }
+
+        fn get_result_schema(
+            &self,
+            _stmt: &Self::Statement,
+            format: Option<&Format>,
+        ) -> PgWireResult<Vec<FieldInfo>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let format = format.map_or(FieldFormat::Text, |f| f.format_for(0));
+            Ok(vec![FieldInfo::new(
+                "id".to_owned(),
+                None,
+                None,
+                Type::INT4,
+                format,
+            )])
+        }
+    }
+
+    #[test]
+    fn test_result_schema_cache() {
+        let parser = SchemaParser::default();
+        let stmt = StoredStatement::new("stmt".to_owned(), "SELECT id".to_owned(), vec![]);
+
+        let text = stmt.result_schema(&parser, None).unwrap();
+        assert!(Arc::ptr_eq(
+            &text,
+            &stmt.result_schema(&parser, None).unwrap()
+        ));
+        assert_eq!(1, parser.calls.load(Ordering::SeqCst));
+
+        let binary = stmt
+            .result_schema(&parser, Some(&Format::UnifiedBinary))
+            .unwrap();
+        assert_eq!(FieldFormat::Binary, binary[0].format());
+        let again = stmt
+            .result_schema(&parser, Some(&Format::UnifiedBinary))
+            .unwrap();
+        assert!(Arc::ptr_eq(&binary, &again));
+        assert_eq!(2, parser.calls.load(Ordering::SeqCst));
+
+        // the last format is cached
+        stmt.result_schema(&parser, Some(&Format::UnifiedText))
+            .unwrap();
+        stmt.result_schema(&parser, Some(&Format::UnifiedBinary))
+            .unwrap();
+        assert_eq!(4, parser.calls.load(Ordering::SeqCst));
+    }
+}
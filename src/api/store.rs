@@ -1,8 +1,10 @@
 use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, RwLock};
 
 use super::portal::Portal;
 use super::stmt::StoredStatement;
+use super::DEFAULT_NAME;
 
 pub trait PortalStore: Send + Sync {
     type Statement;
@@ -20,44 +22,238 @@ pub trait PortalStore: Send + Sync {
     fn get_portal(&self, name: &str) -> Option<Arc<Portal<Self::Statement>>>;
 }
 
-#[derive(Debug, Default, new)]
+/// Why a statement or portal is removed from `MemPortalStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovalCause {
+    /// closed by client with `Close`
+    Closed,
+    /// replaced by a statement or portal of the same name
+    Replaced,
+    /// evicted as least recently used, when the store is full
+    Evicted,
+}
+
+/// Hooks called when statements and portals are removed from
+/// `MemPortalStore`, to release resources held for them, like prepared
+/// statements of an upstream database.
+pub trait PortalStoreListener<S>: Send + Sync {
+    fn on_statement_removed(&self, _statement: &StoredStatement<S>, _cause: RemovalCause) {}
+
+    fn on_portal_removed(&self, _portal: &Portal<S>, _cause: RemovalCause) {}
+}
+
+#[derive(Debug)]
+struct Entry<T> {
+    value: Arc<T>,
+    // tick of the store clock when last used
+    last_used: AtomicU64,
+}
+
+/// Map of entries evicted in least recently used order. The unnamed entry
+/// doesn't count towards capacity and is never evicted, as it's replaced by
+/// each unnamed `Parse` or `Bind` anyway.
+#[derive(Debug)]
+struct LruMap<T> {
+    entries: RwLock<BTreeMap<String, Entry<T>>>,
+    capacity: Option<usize>,
+}
+
+impl<T> Default for LruMap<T> {
+    fn default() -> LruMap<T> {
+        LruMap {
+            entries: RwLock::default(),
+            capacity: None,
+        }
+    }
+}
+
+impl<T> LruMap<T> {
+    fn get(&self, name: &str, tick: u64) -> Option<Arc<T>> {
+        let guard = self.entries.read().unwrap();
+        guard.get(name).map(|entry| {
+            entry.last_used.store(tick, Ordering::Relaxed);
+            entry.value.clone()
+        })
+    }
+
+    /// Insert `value`, returns entries removed by it.
+    fn insert(&self, name: String, value: Arc<T>, tick: u64) -> Vec<(Arc<T>, RemovalCause)> {
+        let mut removed = Vec::new();
+        let mut guard = self.entries.write().unwrap();
+        let entry = Entry {
+            value,
+            last_used: AtomicU64::new(tick),
+        };
+        let named = name != DEFAULT_NAME;
+        if let Some(replaced) = guard.insert(name, entry) {
+            removed.push((replaced.value, RemovalCause::Replaced));
+        } else if let (true, Some(capacity)) = (named, self.capacity) {
+            let count = guard.len() - usize::from(guard.contains_key(DEFAULT_NAME));
+            if count > capacity {
+                let lru = guard
+                    .iter()
+                    .filter(|(name, _)| name.as_str() != DEFAULT_NAME)
+                    .min_by_key(|(_, entry)| entry.last_used.load(Ordering::Relaxed))
+                    .map(|(name, _)| name.clone());
+                if let Some(evicted) = lru.and_then(|name| guard.remove(&name)) {
+                    removed.push((evicted.value, RemovalCause::Evicted));
+                }
+            }
+        }
+        removed
+    }
+
+    fn remove(&self, name: &str) -> Option<Arc<T>> {
+        let mut guard = self.entries.write().unwrap();
+        guard.remove(name).map(|entry| entry.value)
+    }
+}
+
+/// In-memory `PortalStore`, unbounded by default. With a capacity, the least
+/// recently used statement or portal is evicted when it's full, and client
+/// gets an error like postgres if it refers to an evicted one.
+#[derive(Default, new)]
 pub struct MemPortalStore<S> {
     #[new(default)]
-    statements: RwLock<BTreeMap<String, Arc<StoredStatement<S>>>>,
+    statements: LruMap<StoredStatement<S>>,
+    #[new(default)]
+    portals: LruMap<Portal<S>>,
+    #[new(default)]
+    clock: AtomicU64,
     #[new(default)]
-    portals: RwLock<BTreeMap<String, Arc<Portal<S>>>>,
+    listener: Option<Arc<dyn PortalStoreListener<S>>>,
+}
+
+impl<S: std::fmt::Debug> std::fmt::Debug for MemPortalStore<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemPortalStore")
+            .field("statements", &self.statements)
+            .field("portals", &self.portals)
+            .field("listener", &self.listener.is_some())
+            .finish()
+    }
+}
+
+impl<S> MemPortalStore<S> {
+    /// Maximum number of named statements.
+    pub fn with_max_statements(mut self, max: usize) -> MemPortalStore<S> {
+        self.statements.capacity = Some(max);
+        self
+    }
+
+    /// Maximum number of named portals.
+    pub fn with_max_portals(mut self, max: usize) -> MemPortalStore<S> {
+        self.portals.capacity = Some(max);
+        self
+    }
+
+    pub fn with_listener(mut self, listener: Arc<dyn PortalStoreListener<S>>) -> MemPortalStore<S> {
+        self.listener = Some(listener);
+        self
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
 }
 
 impl<S: Clone + Send + Sync> PortalStore for MemPortalStore<S> {
     type Statement = S;
 
     fn put_statement(&self, statement: Arc<StoredStatement<Self::Statement>>) {
-        let mut guard = self.statements.write().unwrap();
-        guard.insert(statement.id.to_owned(), statement);
+        let removed = self
+            .statements
+            .insert(statement.id.to_owned(), statement, self.tick());
+        if let Some(listener) = &self.listener {
+            for (statement, cause) in removed {
+                listener.on_statement_removed(&statement, cause);
+            }
+        }
     }
 
     fn rm_statement(&self, name: &str) {
-        let mut guard = self.statements.write().unwrap();
-        guard.remove(name);
+        let removed = self.statements.remove(name);
+        if let (Some(listener), Some(statement)) = (&self.listener, removed) {
+            listener.on_statement_removed(&statement, RemovalCause::Closed);
+        }
     }
 
     fn get_statement(&self, name: &str) -> Option<Arc<StoredStatement<Self::Statement>>> {
-        let guard = self.statements.read().unwrap();
-        guard.get(name).cloned()
+        self.statements.get(name, self.tick())
     }
 
     fn put_portal(&self, portal: Arc<Portal<Self::Statement>>) {
-        let mut guard = self.portals.write().unwrap();
-        guard.insert(portal.name.to_owned(), portal);
+        let removed = self
+            .portals
+            .insert(portal.name.to_owned(), portal, self.tick());
+        if let Some(listener) = &self.listener {
+            for (portal, cause) in removed {
+                listener.on_portal_removed(&portal, cause);
+            }
+        }
     }
 
     fn rm_portal(&self, name: &str) {
-        let mut guard = self.portals.write().unwrap();
-        guard.remove(name);
+        let removed = self.portals.remove(name);
+        if let (Some(listener), Some(portal)) = (&self.listener, removed) {
+            listener.on_portal_removed(&portal, RemovalCause::Closed);
+        }
     }
 
     fn get_portal(&self, name: &str) -> Option<Arc<Portal<Self::Statement>>> {
-        let guard = self.portals.read().unwrap();
-        guard.get(name).cloned()
+        self.portals.get(name, self.tick())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct RemovalLog(Mutex<Vec<(String, RemovalCause)>>);
+
+    impl PortalStoreListener<String> for RemovalLog {
+        fn on_statement_removed(&self, statement: &StoredStatement<String>, cause: RemovalCause) {
+            self.0.lock().unwrap().push((statement.id.clone(), cause));
+        }
+    }
+
+    fn statement(name: &str) -> Arc<StoredStatement<String>> {
+        Arc::new(StoredStatement::new(
+            name.to_owned(),
+            "SELECT 1".to_owned(),
+            vec![],
+        ))
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let log = Arc::new(RemovalLog::default());
+        let store = MemPortalStore::new()
+            .with_max_statements(2)
+            .with_listener(log.clone());
+
+        store.put_statement(statement("a"));
+        store.put_statement(statement("b"));
+        // unnamed statement doesn't count
+        store.put_statement(statement(DEFAULT_NAME));
+        assert!(store.get_statement("a").is_some());
+        store.put_statement(statement("c"));
+        assert!(store.get_statement("b").is_none());
+        assert!(store.get_statement("a").is_some());
+        assert!(store.get_statement(DEFAULT_NAME).is_some());
+
+        store.put_statement(statement(DEFAULT_NAME));
+        store.rm_statement("a");
+        assert_eq!(
+            vec![
+                ("b".to_owned(), RemovalCause::Evicted),
+                (DEFAULT_NAME.to_owned(), RemovalCause::Replaced),
+                ("a".to_owned(), RemovalCause::Closed),
+            ],
+            *log.0.lock().unwrap()
+        );
     }
 }
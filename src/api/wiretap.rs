@@ -0,0 +1,443 @@
+//! Wire tap, recording protocol messages of connections for diagnosing
+//! driver incompatibilities without packet captures.
+//!
+//! Set a `WireTap` with `ServerOptions::with_wire_tap`, and every message
+//! decoded from client and encoded for client is logged as a `tracing` event
+//! of target `pgwire::wire` at debug level, with the `tracing` feature
+//! enabled. Logged messages are truncated to `max_payload` bytes, and
+//! parameters of `Bind` are redacted unless disabled. Passwords and SASL
+//! messages are always redacted.
+//!
+//! With a `WireDump`, raw frames are also written to a binary dump, which
+//! can be read back with `WireDumpReader`. The dump starts with
+//! `WIRE_DUMP_MAGIC`, followed by records of:
+//!
+//! - kind, one byte, `C` for connected, `F` for frontend and `B` for backend
+//! - connection id, u64
+//! - timestamp, microseconds since unix epoch as u64
+//! - length of frame, u32
+//! - frame, the address of peer for `C`, or the message as sent on wire
+//!
+//! all integers in network byte order. Frames of passwords have their body
+//! dropped, and frames are dumped regardless of `max_payload` and
+//! `redact_parameters`.
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+
+use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+
+/// Magic bytes at the start of a wire dump, the last one is the version of
+/// its format.
+pub const WIRE_DUMP_MAGIC: &[u8; 8] = b"PGWDUMP\x01";
+
+const DEFAULT_MAX_PAYLOAD: usize = 256;
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Options of the wire tap, shared by all connections.
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub struct WireTap {
+    /// Whether messages are logged with `tracing`.
+    pub log: bool,
+    /// Maximum bytes of a logged message, longer ones are truncated.
+    pub max_payload: usize,
+    /// Whether parameter values of `Bind` are redacted in log.
+    pub redact_parameters: bool,
+    /// Dump of raw frames.
+    pub dump: Option<WireDump>,
+}
+
+impl Default for WireTap {
+    fn default() -> WireTap {
+        WireTap {
+            log: true,
+            max_payload: DEFAULT_MAX_PAYLOAD,
+            redact_parameters: true,
+            dump: None,
+        }
+    }
+}
+
+impl WireTap {
+    pub fn new() -> WireTap {
+        WireTap::default()
+    }
+
+    pub fn with_log(mut self, log: bool) -> WireTap {
+        self.log = log;
+        self
+    }
+
+    pub fn with_max_payload(mut self, max_payload: usize) -> WireTap {
+        self.max_payload = max_payload;
+        self
+    }
+
+    pub fn with_redact_parameters(mut self, redact: bool) -> WireTap {
+        self.redact_parameters = redact;
+        self
+    }
+
+    pub fn with_dump(mut self, dump: WireDump) -> WireTap {
+        self.dump = Some(dump);
+        self
+    }
+
+    /// Text of a frontend message as logged.
+    pub fn describe_frontend(&self, message: &PgWireFrontendMessage) -> String {
+        let text = match message {
+            PgWireFrontendMessage::PasswordMessageFamily(_) => {
+                return "PasswordMessageFamily(<redacted>)".to_owned();
+            }
+            PgWireFrontendMessage::Bind(bind) if self.redact_parameters => format!(
+                "Bind {{ portal_name: {:?}, statement_name: {:?}, parameter_format_codes: {:?}, \
+                 parameters: <{} redacted>, result_column_format_codes: {:?} }}",
+                bind.portal_name,
+                bind.statement_name,
+                bind.parameter_format_codes,
+                bind.parameters.len(),
+                bind.result_column_format_codes
+            ),
+            message => format!("{message:?}"),
+        };
+        truncate(text, self.max_payload)
+    }
+
+    /// Text of a backend message as logged.
+    pub fn describe_backend(&self, message: &PgWireBackendMessage) -> String {
+        truncate(format!("{message:?}"), self.max_payload)
+    }
+}
+
+fn truncate(mut text: String, max: usize) -> String {
+    if text.len() <= max {
+        return text;
+    }
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let len = text.len();
+    text.truncate(end);
+    let _ = write!(text, "...({len} bytes)");
+    text
+}
+
+/// Kind of a record in wire dump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireRecordKind {
+    /// A connection is tapped, the frame is address of peer.
+    Connected,
+    /// A message decoded from client.
+    Frontend,
+    /// A message encoded for client.
+    Backend,
+}
+
+impl WireRecordKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            WireRecordKind::Connected => b'C',
+            WireRecordKind::Frontend => b'F',
+            WireRecordKind::Backend => b'B',
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<WireRecordKind> {
+        match byte {
+            b'C' => Some(WireRecordKind::Connected),
+            b'F' => Some(WireRecordKind::Frontend),
+            b'B' => Some(WireRecordKind::Backend),
+            _ => None,
+        }
+    }
+}
+
+/// A record of wire dump.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WireRecord {
+    pub kind: WireRecordKind,
+    pub connection: u64,
+    pub timestamp: SystemTime,
+    pub frame: Bytes,
+}
+
+/// Sink of raw frames, shared by all connections. Records are written with
+/// the sink locked, so a `BufWriter` is recommended, which is flushed by
+/// `flush` or when all clones of the dump are dropped.
+#[derive(Clone)]
+pub struct WireDump {
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl std::fmt::Debug for WireDump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WireDump").finish_non_exhaustive()
+    }
+}
+
+impl WireDump {
+    /// Dump to `writer`, writing `WIRE_DUMP_MAGIC` to it first.
+    pub fn new<W: Write + Send + 'static>(mut writer: W) -> io::Result<WireDump> {
+        writer.write_all(WIRE_DUMP_MAGIC)?;
+        Ok(WireDump {
+            writer: Arc::new(Mutex::new(Box::new(writer))),
+        })
+    }
+
+    /// Flush the underlying writer.
+    pub fn flush(&self) -> io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+
+    fn write(&self, kind: WireRecordKind, connection: u64, frame: &[u8]) {
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64);
+        let mut record = Vec::with_capacity(21 + frame.len());
+        record.push(kind.to_byte());
+        record.extend_from_slice(&connection.to_be_bytes());
+        record.extend_from_slice(&micros.to_be_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        record.extend_from_slice(frame);
+        // a failing dump must not fail the connection
+        if let Err(_e) = self.writer.lock().unwrap().write_all(&record) {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(error = %_e, "failed to write wire dump");
+        }
+    }
+}
+
+/// Reader of records from a wire dump.
+#[derive(Debug)]
+pub struct WireDumpReader<R> {
+    reader: R,
+}
+
+impl<R: Read> WireDumpReader<R> {
+    /// Read a dump from `reader`, failing if it doesn't start with
+    /// `WIRE_DUMP_MAGIC`.
+    pub fn new(mut reader: R) -> io::Result<WireDumpReader<R>> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != WIRE_DUMP_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a wire dump",
+            ));
+        }
+        Ok(WireDumpReader { reader })
+    }
+
+    /// Read next record, `None` at the end of dump.
+    pub fn next_record(&mut self) -> io::Result<Option<WireRecord>> {
+        let mut header = [0u8; 21];
+        match self.reader.read(&mut header[..1])? {
+            0 => return Ok(None),
+            _ => self.reader.read_exact(&mut header[1..])?,
+        }
+        let kind = WireRecordKind::from_byte(header[0]).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "unknown wire record kind")
+        })?;
+        let connection = u64::from_be_bytes(header[1..9].try_into().unwrap());
+        let micros = u64::from_be_bytes(header[9..17].try_into().unwrap());
+        let len = u32::from_be_bytes(header[17..21].try_into().unwrap()) as usize;
+        let mut frame = vec![0u8; len];
+        self.reader.read_exact(&mut frame)?;
+        Ok(Some(WireRecord {
+            kind,
+            connection,
+            timestamp: UNIX_EPOCH + Duration::from_micros(micros),
+            frame: frame.into(),
+        }))
+    }
+}
+
+impl<R: Read> Iterator for WireDumpReader<R> {
+    type Item = io::Result<WireRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+/// Wire tap state of a connection, kept in its codec.
+#[derive(Debug)]
+pub(crate) struct ConnectionTap {
+    tap: WireTap,
+    connection: u64,
+}
+
+impl ConnectionTap {
+    pub(crate) fn new(tap: WireTap, addr: SocketAddr) -> ConnectionTap {
+        let connection = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        if let Some(dump) = &tap.dump {
+            dump.write(
+                WireRecordKind::Connected,
+                connection,
+                addr.to_string().as_bytes(),
+            );
+        }
+        ConnectionTap { tap, connection }
+    }
+
+    /// Whether raw frames are needed.
+    pub(crate) fn is_dumping(&self) -> bool {
+        self.tap.dump.is_some()
+    }
+
+    pub(crate) fn on_frontend(&self, message: &PgWireFrontendMessage, frame: Option<&[u8]>) {
+        if let (Some(dump), Some(frame)) = (&self.tap.dump, frame) {
+            match message {
+                // keep only the type and a length of empty body
+                PgWireFrontendMessage::PasswordMessageFamily(_) => {
+                    dump.write(WireRecordKind::Frontend, self.connection, b"p\0\0\0\x04")
+                }
+                _ => dump.write(WireRecordKind::Frontend, self.connection, frame),
+            }
+        }
+        #[cfg(feature = "tracing")]
+        if self.tap.log {
+            tracing::debug!(
+                target: "pgwire::wire",
+                connection = self.connection,
+                "<- {}",
+                self.tap.describe_frontend(message)
+            );
+        }
+    }
+
+    pub(crate) fn on_backend(&self, message: &PgWireBackendMessage, frame: &[u8]) {
+        if let Some(dump) = &self.tap.dump {
+            dump.write(WireRecordKind::Backend, self.connection, frame);
+        }
+        #[cfg(feature = "tracing")]
+        if self.tap.log {
+            tracing::debug!(
+                target: "pgwire::wire",
+                connection = self.connection,
+                "-> {}",
+                self.tap.describe_backend(message)
+            );
+        }
+        #[cfg(not(feature = "tracing"))]
+        let _ = message;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::extendedquery::Bind;
+    use crate::messages::simplequery::Query;
+    use crate::messages::startup::{Password, PasswordMessageFamily};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_describe() {
+        let tap = WireTap::new().with_max_payload(20);
+        let query = PgWireFrontendMessage::Query(Query::new("SELECT 1".repeat(10)));
+        let text = tap.describe_frontend(&query);
+        assert!(text.starts_with("Query(Query { query"));
+        assert!(text.ends_with("...(106 bytes)"), "{text}");
+
+        let tap = WireTap::new();
+        let bind = PgWireFrontendMessage::Bind(Bind::new(
+            None,
+            Some("s".to_owned()),
+            vec![],
+            vec![Some(Bytes::from_static(b"secret")), None],
+            vec![],
+        ));
+        let text = tap.describe_frontend(&bind);
+        assert!(text.contains("<2 redacted>"));
+        assert!(!text.contains("secret"));
+        let text = tap
+            .clone()
+            .with_redact_parameters(false)
+            .describe_frontend(&bind);
+        assert!(text.contains("Some(b\"secret\")"), "{text}");
+
+        let password = PgWireFrontendMessage::PasswordMessageFamily(
+            PasswordMessageFamily::Password(Password::new("secret".to_owned())),
+        );
+        assert!(!tap
+            .with_redact_parameters(false)
+            .describe_frontend(&password)
+            .contains("secret"));
+    }
+
+    #[test]
+    fn test_dump() {
+        let sink = Shared::default();
+        let tap = WireTap::new().with_dump(WireDump::new(sink.clone()).unwrap());
+        let conn = ConnectionTap::new(tap, "127.0.0.1:5432".parse().unwrap());
+        assert!(conn.is_dumping());
+
+        let query = PgWireFrontendMessage::Query(Query::new("SELECT 1".to_owned()));
+        conn.on_frontend(&query, Some(b"Q\0\0\0\x0dSELECT 1\0"));
+        let password = PgWireFrontendMessage::PasswordMessageFamily(
+            PasswordMessageFamily::Password(Password::new("secret".to_owned())),
+        );
+        conn.on_frontend(&password, Some(b"p\0\0\0\x0bsecret\0"));
+        let ready =
+            PgWireBackendMessage::ReadyForQuery(crate::messages::response::ReadyForQuery::new(
+                crate::messages::response::TransactionStatus::Idle,
+            ));
+        conn.on_backend(&ready, b"Z\0\0\0\x05I");
+
+        let dump = sink.0.lock().unwrap().clone();
+        let records = WireDumpReader::new(&dump[..])
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        let frames = records
+            .iter()
+            .map(|r| {
+                assert_eq!(conn.connection, r.connection);
+                (r.kind, r.frame.clone())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (
+                    WireRecordKind::Connected,
+                    Bytes::from_static(b"127.0.0.1:5432")
+                ),
+                (
+                    WireRecordKind::Frontend,
+                    Bytes::from_static(b"Q\0\0\0\x0dSELECT 1\0")
+                ),
+                (WireRecordKind::Frontend, Bytes::from_static(b"p\0\0\0\x04")),
+                (WireRecordKind::Backend, Bytes::from_static(b"Z\0\0\0\x05I")),
+            ],
+            frames
+        );
+
+        assert!(WireDumpReader::new(&b"PGDUMP\x01\x02"[..]).is_err());
+        // truncated record
+        assert!(WireDumpReader::new(&dump[..dump.len() - 1])
+            .unwrap()
+            .any(|r| r.is_err()));
+    }
+}
@@ -0,0 +1,168 @@
+//! Codec for the frontend side of the protocol.
+//!
+//! `PgWireMessageClientCodec` encodes `PgWireFrontendMessage` and decodes
+//! `PgWireBackendMessage`, for building proxies, load balancers and test
+//! clients on top of `tokio_util::codec::Framed`. Rows in `DataRow` can be
+//! split into fields with `DataRow::fields`.
+
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::error::{PgWireError, PgWireResult};
+use crate::messages::response::SslResponse;
+use crate::messages::{Message, PgWireBackendMessage, PgWireFrontendMessage};
+
+/// Decode next backend message from `buf`. `ssl_requested` is true if
+/// client has sent `SslRequest` and waits for the single byte response.
+pub fn decode_backend_message(
+    ssl_requested: bool,
+    buf: &mut BytesMut,
+) -> PgWireResult<Option<PgWireBackendMessage>> {
+    if ssl_requested {
+        if let Some(response) = SslResponse::decode(buf)? {
+            return Ok(Some(PgWireBackendMessage::SslResponse(response)));
+        }
+    }
+    PgWireBackendMessage::decode(buf)
+}
+
+#[derive(Debug, Default)]
+pub struct PgWireMessageClientCodec {
+    ssl_requested: bool,
+}
+
+impl PgWireMessageClientCodec {
+    pub fn new() -> PgWireMessageClientCodec {
+        PgWireMessageClientCodec::default()
+    }
+}
+
+impl Decoder for PgWireMessageClientCodec {
+    type Item = PgWireBackendMessage;
+    type Error = PgWireError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let message = decode_backend_message(self.ssl_requested, src)?;
+        if message.is_some() {
+            // servers that don't know SslRequest respond with an error
+            self.ssl_requested = false;
+        }
+        Ok(message)
+    }
+}
+
+impl Encoder<PgWireFrontendMessage> for PgWireMessageClientCodec {
+    type Error = PgWireError;
+
+    fn encode(&mut self, item: PgWireFrontendMessage, dst: &mut BytesMut) -> PgWireResult<()> {
+        if let PgWireFrontendMessage::SslRequest(_) = item {
+            self.ssl_requested = true;
+        }
+        item.encode(dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::data::{DataRow, FieldDescription, RowDescription};
+    use crate::messages::response::{ReadyForQuery, TransactionStatus};
+    use crate::messages::simplequery::Query;
+    use crate::messages::startup::{Authentication, ParameterStatus, SslRequest, Startup};
+
+    fn backend_bytes(messages: Vec<PgWireBackendMessage>) -> BytesMut {
+        let mut buf = BytesMut::new();
+        for message in messages {
+            message.encode(&mut buf).unwrap();
+        }
+        buf
+    }
+
+    #[test]
+    fn test_client_codec() {
+        let mut codec = PgWireMessageClientCodec::new();
+        let mut out = BytesMut::new();
+
+        codec
+            .encode(
+                PgWireFrontendMessage::SslRequest(SslRequest::new()),
+                &mut out,
+            )
+            .unwrap();
+        // 'S' is SslResponse here, not ParameterStatus
+        let mut buf = backend_bytes(vec![PgWireBackendMessage::SslResponse(SslResponse::Accept)]);
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(PgWireBackendMessage::SslResponse(SslResponse::Accept))
+        ));
+
+        let mut startup = Startup::new();
+        startup
+            .parameters
+            .insert("user".to_owned(), "tomcat".to_owned());
+        codec
+            .encode(PgWireFrontendMessage::Startup(startup), &mut out)
+            .unwrap();
+        codec
+            .encode(
+                PgWireFrontendMessage::Query(Query::new("SELECT 1".to_owned())),
+                &mut out,
+            )
+            .unwrap();
+        assert!(matches!(
+            SslRequest::decode(&mut out).unwrap(),
+            Some(SslRequest { .. })
+        ));
+        assert!(matches!(
+            Startup::decode(&mut out).unwrap(),
+            Some(s) if s.parameters["user"] == "tomcat"
+        ));
+        assert!(matches!(
+            PgWireFrontendMessage::decode(&mut out).unwrap(),
+            Some(PgWireFrontendMessage::Query(_))
+        ));
+
+        let mut data = BytesMut::new();
+        data.extend_from_slice(&1i32.to_be_bytes());
+        data.extend_from_slice(b"1");
+        data.extend_from_slice(&(-1i32).to_be_bytes());
+        let mut buf = backend_bytes(vec![
+            PgWireBackendMessage::Authentication(Authentication::Ok),
+            PgWireBackendMessage::ParameterStatus(ParameterStatus::new(
+                "server_version".to_owned(),
+                "16".to_owned(),
+            )),
+            PgWireBackendMessage::RowDescription(RowDescription::new(vec![
+                FieldDescription::new("a".to_owned(), 0, 0, 23, 4, -1, 0),
+                FieldDescription::new("b".to_owned(), 0, 0, 23, 4, -1, 0),
+            ])),
+            PgWireBackendMessage::DataRow(DataRow::new(data, 2)),
+            PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(TransactionStatus::Idle)),
+        ]);
+
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(PgWireBackendMessage::Authentication(Authentication::Ok))
+        ));
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(PgWireBackendMessage::ParameterStatus(_))
+        ));
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(PgWireBackendMessage::RowDescription(desc)) if desc.fields.len() == 2
+        ));
+        let Some(PgWireBackendMessage::DataRow(row)) = codec.decode(&mut buf).unwrap() else {
+            panic!("expect DataRow");
+        };
+        assert_eq!(row.fields().unwrap(), vec![Some(&b"1"[..]), None]);
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(PgWireBackendMessage::ReadyForQuery(_))
+        ));
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        let truncated = DataRow::new(BytesMut::from(&[0u8, 0, 0, 5, b'x'][..]), 1);
+        assert!(truncated.fields().is_err());
+    }
+}
@@ -2,6 +2,10 @@ use crate::messages::response::{ErrorResponse, NoticeResponse};
 use std::io::{Error as IOError, ErrorKind};
 use thiserror::Error;
 
+mod sqlstate;
+
+pub use sqlstate::{class_of, SqlState};
+
 #[derive(Error, Debug)]
 pub enum PgWireError {
     #[error("Invalid protocol version, received {0}")]
@@ -12,6 +16,16 @@ pub enum PgWireError {
     InvalidTargetType(u8),
     #[error("Invalid transaction status, received {0}")]
     InvalidTransactionStatus(u8),
+    #[error("Invalid authentication type, received {0}")]
+    InvalidAuthenticationType(i32),
+    #[error("Invalid message length, received {0}")]
+    InvalidMessageLength(i32),
+    #[error("Message body is truncated, {0} more bytes expected")]
+    TruncatedMessageBody(usize),
+    #[error("Message is incomplete, {0} more bytes expected")]
+    IncompleteMessage(usize),
+    #[error("Message body has {0} unexpected trailing bytes")]
+    TrailingMessageBytes(usize),
     #[error("Invalid startup message")]
     InvalidStartupMessage,
     #[error(transparent)]
@@ -30,8 +44,18 @@ pub enum PgWireError {
     InvalidScramMessage(String),
     #[error("Certificate algorithm is not supported")]
     UnsupportedCertificateSignatureAlgorithm,
+    #[error("Invalid pg_hba rule: {0}")]
+    InvalidHbaRule(String),
+    #[error("Invalid streaming replication message")]
+    InvalidReplicationMessage,
+    #[error("Invalid data row")]
+    InvalidDataRow,
     #[error("Username is required")]
     UserNameRequired,
+    #[error("Message of {0} bytes exceeds the limit of {1} bytes")]
+    MessageTooLarge(usize, usize),
+    #[error("Invalid command tag: {0:?}")]
+    InvalidCommandTag(String),
 
     #[error(transparent)]
     ApiError(#[from] Box<dyn std::error::Error + 'static + Send + Sync>),
@@ -40,6 +64,69 @@ pub enum PgWireError {
     UserError(Box<ErrorInfo>),
 }
 
+impl PgWireError {
+    /// `ApiError` of an application error, sent to client as its
+    /// `ToErrorInfo::to_error_info` instead of an internal error. The
+    /// original error is the `source` of the `ApiError`.
+    ///
+    /// ```ignore
+    /// let rows = db.query(sql).await.map_err(PgWireError::api)?;
+    /// ```
+    pub fn api<E: ToErrorInfo>(error: E) -> PgWireError {
+        let info = error.to_error_info();
+        PgWireError::ApiError(Box::new(StructuredError {
+            info,
+            error: Box::new(error),
+        }))
+    }
+
+    /// The error sent to client for this error. Errors other than `UserError`
+    /// and `ApiError` are fatal to the connection.
+    pub fn to_error_info(&self) -> ErrorInfo {
+        match self {
+            PgWireError::UserError(error_info) => (**error_info).clone(),
+            PgWireError::ApiError(e) => match e.downcast_ref::<StructuredError>() {
+                Some(structured) => structured.info.clone(),
+                None => ErrorInfo::new(
+                    "ERROR".to_owned(),
+                    SqlState::INTERNAL_ERROR.into(),
+                    e.to_string(),
+                ),
+            },
+            _ => ErrorInfo::new(
+                "FATAL".to_owned(),
+                SqlState::INTERNAL_ERROR.into(),
+                self.to_string(),
+            ),
+        }
+    }
+}
+
+/// Application errors with a postgres error, so clients can handle them by
+/// SQLSTATE. Wrap them with `PgWireError::api`.
+pub trait ToErrorInfo: std::error::Error + Send + Sync + 'static {
+    fn to_error_info(&self) -> ErrorInfo;
+}
+
+/// `ApiError` created by `PgWireError::api`.
+#[derive(Debug)]
+struct StructuredError {
+    info: ErrorInfo,
+    error: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl std::fmt::Display for StructuredError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for StructuredError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.error.as_ref())
+    }
+}
+
 impl From<PgWireError> for IOError {
     fn from(e: PgWireError) -> Self {
         IOError::new(ErrorKind::Other, e)
@@ -52,7 +139,7 @@ pub type PgWireResult<T> = Result<T, PgWireError>;
 // This part of protocol is defined in
 // https://www.postgresql.org/docs/8.2/protocol-error-fields.html
 #[non_exhaustive]
-#[derive(new, Debug)]
+#[derive(new, Debug, Clone)]
 pub struct ErrorInfo {
     // severity can be one of `ERROR`, `FATAL`, or `PANIC` (in an error
     // message), or `WARNING`, `NOTICE`, `DEBUG`, `INFO`, or `LOG` (in a notice
@@ -84,6 +171,26 @@ pub struct ErrorInfo {
     // Where: an indication of the context in which the error occurred.
     #[new(default)]
     pub where_context: Option<String>,
+    // Schema name: if the error was associated with a specific database
+    // object, the name of the schema containing that object, if any.
+    #[new(default)]
+    pub schema_name: Option<String>,
+    // Table name: if the error was associated with a specific table, the name
+    // of the table.
+    #[new(default)]
+    pub table_name: Option<String>,
+    // Column name: if the error was associated with a specific table column,
+    // the name of the column.
+    #[new(default)]
+    pub column_name: Option<String>,
+    // Data type name: if the error was associated with a specific data type,
+    // the name of the data type.
+    #[new(default)]
+    pub datatype_name: Option<String>,
+    // Constraint name: if the error was associated with a specific constraint,
+    // the name of the constraint.
+    #[new(default)]
+    pub constraint_name: Option<String>,
     // File: the file name of the source-code location where the error was
     // reported.
     #[new(default)]
@@ -98,8 +205,103 @@ pub struct ErrorInfo {
 }
 
 impl ErrorInfo {
+    /// A `NOTICE` with code `00000`, sent to client as `NoticeResponse`.
+    pub fn notice(message: String) -> ErrorInfo {
+        ErrorInfo::new(
+            "NOTICE".to_owned(),
+            SqlState::SUCCESSFUL_COMPLETION.into(),
+            message,
+        )
+    }
+
+    /// A `WARNING` with code `01000`, sent to client as `NoticeResponse`.
+    pub fn warning(message: String) -> ErrorInfo {
+        ErrorInfo::new("WARNING".to_owned(), SqlState::WARNING.into(), message)
+    }
+
+    /// Known SQLSTATE of `code`.
+    pub fn sqlstate(&self) -> Option<SqlState> {
+        SqlState::from_code(&self.code)
+    }
+
+    pub fn with_severity(mut self, severity: String) -> ErrorInfo {
+        self.severity = severity;
+        self
+    }
+
+    pub fn with_detail(mut self, detail: String) -> ErrorInfo {
+        self.detail = Some(detail);
+        self
+    }
+
+    pub fn with_hint(mut self, hint: String) -> ErrorInfo {
+        self.hint = Some(hint);
+        self
+    }
+
+    /// Cursor position in the query, starting from 1 and counted in
+    /// characters.
+    pub fn with_position(mut self, position: usize) -> ErrorInfo {
+        self.position = Some(position.to_string());
+        self
+    }
+
+    /// Cursor position in `internal_query`.
+    pub fn with_internal_position(mut self, position: usize) -> ErrorInfo {
+        self.internal_position = Some(position.to_string());
+        self
+    }
+
+    pub fn with_internal_query(mut self, query: String) -> ErrorInfo {
+        self.internal_query = Some(query);
+        self
+    }
+
+    pub fn with_where_context(mut self, context: String) -> ErrorInfo {
+        self.where_context = Some(context);
+        self
+    }
+
+    pub fn with_schema_name(mut self, name: String) -> ErrorInfo {
+        self.schema_name = Some(name);
+        self
+    }
+
+    pub fn with_table_name(mut self, name: String) -> ErrorInfo {
+        self.table_name = Some(name);
+        self
+    }
+
+    pub fn with_column_name(mut self, name: String) -> ErrorInfo {
+        self.column_name = Some(name);
+        self
+    }
+
+    pub fn with_datatype_name(mut self, name: String) -> ErrorInfo {
+        self.datatype_name = Some(name);
+        self
+    }
+
+    pub fn with_constraint_name(mut self, name: String) -> ErrorInfo {
+        self.constraint_name = Some(name);
+        self
+    }
+
+    /// Location in source code reporting the error.
+    pub fn with_source_location(
+        mut self,
+        file_name: String,
+        line: usize,
+        routine: String,
+    ) -> ErrorInfo {
+        self.file_name = Some(file_name);
+        self.line = Some(line);
+        self.routine = Some(routine);
+        self
+    }
+
     fn into_fields(self) -> Vec<(u8, String)> {
-        let mut fields = Vec::with_capacity(11);
+        let mut fields = Vec::with_capacity(16);
 
         fields.push((b'S', self.severity));
         fields.push((b'C', self.code));
@@ -122,6 +324,21 @@ impl ErrorInfo {
         if let Some(value) = self.where_context {
             fields.push((b'W', value));
         }
+        if let Some(value) = self.schema_name {
+            fields.push((b's', value));
+        }
+        if let Some(value) = self.table_name {
+            fields.push((b't', value));
+        }
+        if let Some(value) = self.column_name {
+            fields.push((b'c', value));
+        }
+        if let Some(value) = self.datatype_name {
+            fields.push((b'd', value));
+        }
+        if let Some(value) = self.constraint_name {
+            fields.push((b'n', value));
+        }
         if let Some(value) = self.file_name {
             fields.push((b'F', value));
         }
@@ -134,6 +351,34 @@ impl ErrorInfo {
 
         fields
     }
+
+    fn from_fields(fields: Vec<(u8, String)>) -> ErrorInfo {
+        let mut info = ErrorInfo::new(String::new(), String::new(), String::new());
+        for (code, value) in fields {
+            match code {
+                b'S' => info.severity = value,
+                b'C' => info.code = value,
+                b'M' => info.message = value,
+                b'D' => info.detail = Some(value),
+                b'H' => info.hint = Some(value),
+                b'P' => info.position = Some(value),
+                b'p' => info.internal_position = Some(value),
+                b'q' => info.internal_query = Some(value),
+                b'W' => info.where_context = Some(value),
+                b's' => info.schema_name = Some(value),
+                b't' => info.table_name = Some(value),
+                b'c' => info.column_name = Some(value),
+                b'd' => info.datatype_name = Some(value),
+                b'n' => info.constraint_name = Some(value),
+                b'F' => info.file_name = Some(value),
+                b'L' => info.line = value.parse().ok(),
+                b'R' => info.routine = Some(value),
+                // `V`, the non-localized severity, and unknown fields
+                _ => {}
+            }
+        }
+        info
+    }
 }
 
 impl From<ErrorInfo> for ErrorResponse {
@@ -148,6 +393,18 @@ impl From<ErrorInfo> for NoticeResponse {
     }
 }
 
+impl From<ErrorResponse> for ErrorInfo {
+    fn from(response: ErrorResponse) -> ErrorInfo {
+        ErrorInfo::from_fields(response.fields)
+    }
+}
+
+impl From<NoticeResponse> for ErrorInfo {
+    fn from(response: NoticeResponse) -> ErrorInfo {
+        ErrorInfo::from_fields(response.fields)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -163,5 +420,96 @@ mod test {
         assert_eq!("28P01", error_info.code);
         assert_eq!("Password authentication failed", error_info.message);
         assert!(error_info.file_name.is_none());
+
+        let notice = ErrorInfo::notice("table \"t\" does not exist, skipping".to_owned());
+        assert_eq!("NOTICE", notice.severity);
+        assert_eq!("00000", notice.code);
+        assert_eq!("01000", ErrorInfo::warning("w".to_owned()).code);
+    }
+
+    #[test]
+    fn test_error_info_fields() {
+        let error_info = ErrorInfo::new(
+            "ERROR".to_owned(),
+            "23505".to_owned(),
+            "duplicate key value violates unique constraint \"users_pkey\"".to_owned(),
+        )
+        .with_detail("Key (id)=(1) already exists.".to_owned())
+        .with_position(13)
+        .with_schema_name("public".to_owned())
+        .with_table_name("users".to_owned())
+        .with_constraint_name("users_pkey".to_owned())
+        .with_source_location("nbtinsert.c".to_owned(), 666, "_bt_check_unique".to_owned());
+
+        let ErrorResponse { fields } = error_info.into();
+        assert_eq!(
+            vec![
+                (b'S', "ERROR".to_owned()),
+                (b'C', "23505".to_owned()),
+                (
+                    b'M',
+                    "duplicate key value violates unique constraint \"users_pkey\"".to_owned()
+                ),
+                (b'D', "Key (id)=(1) already exists.".to_owned()),
+                (b'P', "13".to_owned()),
+                (b's', "public".to_owned()),
+                (b't', "users".to_owned()),
+                (b'n', "users_pkey".to_owned()),
+                (b'F', "nbtinsert.c".to_owned()),
+                (b'L', "666".to_owned()),
+                (b'R', "_bt_check_unique".to_owned()),
+            ],
+            fields
+        );
+
+        let error_info = ErrorInfo::from(ErrorResponse::new(fields));
+        assert_eq!("23505", error_info.code);
+        assert_eq!(Some("13"), error_info.position.as_deref());
+        assert_eq!(Some("users_pkey"), error_info.constraint_name.as_deref());
+        assert_eq!(Some(666), error_info.line);
+    }
+
+    #[test]
+    fn test_api_error_info() {
+        #[derive(Debug)]
+        struct NotFound(String);
+
+        impl std::fmt::Display for NotFound {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "relation \"{}\" does not exist", self.0)
+            }
+        }
+
+        impl std::error::Error for NotFound {}
+
+        impl ToErrorInfo for NotFound {
+            fn to_error_info(&self) -> ErrorInfo {
+                ErrorInfo::new("ERROR".to_owned(), "42P01".to_owned(), self.to_string())
+                    .with_table_name(self.0.clone())
+            }
+        }
+
+        let error = PgWireError::api(NotFound("users".to_owned()));
+        let info = error.to_error_info();
+        assert_eq!("42P01", info.code);
+        assert_eq!("relation \"users\" does not exist", info.message);
+        assert_eq!(Some("users".to_owned()), info.table_name);
+        assert_eq!("relation \"users\" does not exist", error.to_string());
+
+        // the original error is kept as source
+        let PgWireError::ApiError(e) = &error else {
+            panic!("expected ApiError");
+        };
+        assert!(e
+            .source()
+            .and_then(|source| source.downcast_ref::<NotFound>())
+            .is_some());
+
+        let error = PgWireError::ApiError(Box::new(NotFound("users".to_owned())));
+        assert_eq!("XX000", error.to_error_info().code);
+        assert_eq!(
+            "FATAL",
+            PgWireError::InvalidDataRow.to_error_info().severity
+        );
     }
 }
@@ -0,0 +1,343 @@
+//! SQLSTATE error codes of postgres.
+//!
+//! Codes are listed in
+//! <https://www.postgresql.org/docs/current/errcodes-appendix.html>. The first
+//! two characters of a code are its class, clients often handle errors by
+//! class, like retrying transactions of class `40`.
+
+use std::fmt;
+
+/// A SQLSTATE code, use it as `code` of `ErrorInfo`:
+///
+/// ```
+/// use pgwire::error::{ErrorInfo, SqlState};
+///
+/// let error = ErrorInfo::new(
+///     "ERROR".to_owned(),
+///     SqlState::UNIQUE_VIOLATION.into(),
+///     "duplicate key value violates unique constraint".to_owned(),
+/// );
+/// assert_eq!(Some(SqlState::UNIQUE_VIOLATION), error.sqlstate());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SqlState(&'static str);
+
+macro_rules! sqlstates {
+    ($($(#[$attr:meta])* $name:ident = $code:literal,)*) => {
+        impl SqlState {
+            $(
+                $(#[$attr])*
+                pub const $name: SqlState = SqlState($code);
+            )*
+        }
+
+        const ALL: &[SqlState] = &[$(SqlState::$name),*];
+    };
+}
+
+sqlstates! {
+    // Class 00 - Successful Completion
+    SUCCESSFUL_COMPLETION = "00000",
+
+    // Class 01 - Warning
+    WARNING = "01000",
+    WARNING_DYNAMIC_RESULT_SETS_RETURNED = "0100C",
+    WARNING_IMPLICIT_ZERO_BIT_PADDING = "01008",
+    WARNING_NULL_VALUE_ELIMINATED_IN_SET_FUNCTION = "01003",
+    WARNING_PRIVILEGE_NOT_GRANTED = "01007",
+    WARNING_PRIVILEGE_NOT_REVOKED = "01006",
+    WARNING_STRING_DATA_RIGHT_TRUNCATION = "01004",
+    WARNING_DEPRECATED_FEATURE = "01P01",
+
+    // Class 02 - No Data
+    NO_DATA = "02000",
+    NO_ADDITIONAL_DYNAMIC_RESULT_SETS_RETURNED = "02001",
+
+    // Class 03 - SQL Statement Not Yet Complete
+    SQL_STATEMENT_NOT_YET_COMPLETE = "03000",
+
+    // Class 08 - Connection Exception
+    CONNECTION_EXCEPTION = "08000",
+    CONNECTION_DOES_NOT_EXIST = "08003",
+    CONNECTION_FAILURE = "08006",
+    SQLCLIENT_UNABLE_TO_ESTABLISH_SQLCONNECTION = "08001",
+    SQLSERVER_REJECTED_ESTABLISHMENT_OF_SQLCONNECTION = "08004",
+    TRANSACTION_RESOLUTION_UNKNOWN = "08007",
+    PROTOCOL_VIOLATION = "08P01",
+
+    // Class 09 - Triggered Action Exception
+    TRIGGERED_ACTION_EXCEPTION = "09000",
+
+    // Class 0A - Feature Not Supported
+    FEATURE_NOT_SUPPORTED = "0A000",
+
+    // Class 0B - Invalid Transaction Initiation
+    INVALID_TRANSACTION_INITIATION = "0B000",
+
+    // Class 0L - Invalid Grantor
+    INVALID_GRANTOR = "0L000",
+
+    // Class 0P - Invalid Role Specification
+    INVALID_ROLE_SPECIFICATION = "0P000",
+
+    // Class 21 - Cardinality Violation
+    CARDINALITY_VIOLATION = "21000",
+
+    // Class 22 - Data Exception
+    DATA_EXCEPTION = "22000",
+    ARRAY_SUBSCRIPT_ERROR = "2202E",
+    CHARACTER_NOT_IN_REPERTOIRE = "22021",
+    DATETIME_FIELD_OVERFLOW = "22008",
+    DIVISION_BY_ZERO = "22012",
+    ERROR_IN_ASSIGNMENT = "22005",
+    INDICATOR_OVERFLOW = "22022",
+    INTERVAL_FIELD_OVERFLOW = "22015",
+    INVALID_TEXT_REPRESENTATION = "22P02",
+    INVALID_BINARY_REPRESENTATION = "22P03",
+    BAD_COPY_FILE_FORMAT = "22P04",
+    UNTRANSLATABLE_CHARACTER = "22P05",
+    INVALID_DATETIME_FORMAT = "22007",
+    INVALID_ESCAPE_CHARACTER = "22019",
+    INVALID_PARAMETER_VALUE = "22023",
+    INVALID_REGULAR_EXPRESSION = "2201B",
+    INVALID_TIME_ZONE_DISPLACEMENT_VALUE = "22009",
+    NULL_VALUE_NOT_ALLOWED = "22004",
+    NUMERIC_VALUE_OUT_OF_RANGE = "22003",
+    STRING_DATA_RIGHT_TRUNCATION = "22001",
+    SUBSTRING_ERROR = "22011",
+    TRIM_ERROR = "22027",
+    UNTERMINATED_C_STRING = "22024",
+    ZERO_LENGTH_CHARACTER_STRING = "2200F",
+    INVALID_JSON_TEXT = "22032",
+
+    // Class 23 - Integrity Constraint Violation
+    INTEGRITY_CONSTRAINT_VIOLATION = "23000",
+    RESTRICT_VIOLATION = "23001",
+    NOT_NULL_VIOLATION = "23502",
+    FOREIGN_KEY_VIOLATION = "23503",
+    UNIQUE_VIOLATION = "23505",
+    CHECK_VIOLATION = "23514",
+    EXCLUSION_VIOLATION = "23P01",
+
+    // Class 24 - Invalid Cursor State
+    INVALID_CURSOR_STATE = "24000",
+
+    // Class 25 - Invalid Transaction State
+    INVALID_TRANSACTION_STATE = "25000",
+    ACTIVE_SQL_TRANSACTION = "25001",
+    READ_ONLY_SQL_TRANSACTION = "25006",
+    NO_ACTIVE_SQL_TRANSACTION = "25P01",
+    IN_FAILED_SQL_TRANSACTION = "25P02",
+    IDLE_IN_TRANSACTION_SESSION_TIMEOUT = "25P03",
+
+    // Class 26 - Invalid SQL Statement Name
+    INVALID_SQL_STATEMENT_NAME = "26000",
+
+    // Class 28 - Invalid Authorization Specification
+    INVALID_AUTHORIZATION_SPECIFICATION = "28000",
+    INVALID_PASSWORD = "28P01",
+
+    // Class 2D - Invalid Transaction Termination
+    INVALID_TRANSACTION_TERMINATION = "2D000",
+
+    // Class 34 - Invalid Cursor Name
+    INVALID_CURSOR_NAME = "34000",
+
+    // Class 3D - Invalid Catalog Name
+    INVALID_CATALOG_NAME = "3D000",
+
+    // Class 3F - Invalid Schema Name
+    INVALID_SCHEMA_NAME = "3F000",
+
+    // Class 40 - Transaction Rollback
+    TRANSACTION_ROLLBACK = "40000",
+    TRANSACTION_INTEGRITY_CONSTRAINT_VIOLATION = "40002",
+    SERIALIZATION_FAILURE = "40001",
+    STATEMENT_COMPLETION_UNKNOWN = "40003",
+    DEADLOCK_DETECTED = "40P01",
+
+    // Class 42 - Syntax Error or Access Rule Violation
+    SYNTAX_ERROR_OR_ACCESS_RULE_VIOLATION = "42000",
+    SYNTAX_ERROR = "42601",
+    INSUFFICIENT_PRIVILEGE = "42501",
+    CANNOT_COERCE = "42846",
+    GROUPING_ERROR = "42803",
+    WINDOWING_ERROR = "42P20",
+    INVALID_FOREIGN_KEY = "42830",
+    INVALID_NAME = "42602",
+    NAME_TOO_LONG = "42622",
+    RESERVED_NAME = "42939",
+    DATATYPE_MISMATCH = "42804",
+    INDETERMINATE_DATATYPE = "42P18",
+    WRONG_OBJECT_TYPE = "42809",
+    UNDEFINED_COLUMN = "42703",
+    UNDEFINED_FUNCTION = "42883",
+    UNDEFINED_TABLE = "42P01",
+    UNDEFINED_PARAMETER = "42P02",
+    UNDEFINED_OBJECT = "42704",
+    DUPLICATE_COLUMN = "42701",
+    DUPLICATE_CURSOR = "42P03",
+    DUPLICATE_DATABASE = "42P04",
+    DUPLICATE_FUNCTION = "42723",
+    DUPLICATE_PREPARED_STATEMENT = "42P05",
+    DUPLICATE_SCHEMA = "42P06",
+    DUPLICATE_TABLE = "42P07",
+    DUPLICATE_ALIAS = "42712",
+    DUPLICATE_OBJECT = "42710",
+    AMBIGUOUS_COLUMN = "42702",
+    AMBIGUOUS_FUNCTION = "42725",
+    AMBIGUOUS_PARAMETER = "42P08",
+    AMBIGUOUS_ALIAS = "42P09",
+    INVALID_COLUMN_REFERENCE = "42P10",
+    INVALID_COLUMN_DEFINITION = "42611",
+    INVALID_CURSOR_DEFINITION = "42P11",
+    INVALID_DATABASE_DEFINITION = "42P12",
+    INVALID_FUNCTION_DEFINITION = "42P13",
+    INVALID_PREPARED_STATEMENT_DEFINITION = "42P14",
+    INVALID_SCHEMA_DEFINITION = "42P15",
+    INVALID_TABLE_DEFINITION = "42P16",
+    INVALID_OBJECT_DEFINITION = "42P17",
+
+    // Class 44 - WITH CHECK OPTION Violation
+    WITH_CHECK_OPTION_VIOLATION = "44000",
+
+    // Class 53 - Insufficient Resources
+    INSUFFICIENT_RESOURCES = "53000",
+    DISK_FULL = "53100",
+    OUT_OF_MEMORY = "53200",
+    TOO_MANY_CONNECTIONS = "53300",
+    CONFIGURATION_LIMIT_EXCEEDED = "53400",
+
+    // Class 54 - Program Limit Exceeded
+    PROGRAM_LIMIT_EXCEEDED = "54000",
+    STATEMENT_TOO_COMPLEX = "54001",
+    TOO_MANY_COLUMNS = "54011",
+    TOO_MANY_ARGUMENTS = "54023",
+
+    // Class 55 - Object Not In Prerequisite State
+    OBJECT_NOT_IN_PREREQUISITE_STATE = "55000",
+    OBJECT_IN_USE = "55006",
+    LOCK_NOT_AVAILABLE = "55P03",
+
+    // Class 57 - Operator Intervention
+    OPERATOR_INTERVENTION = "57000",
+    QUERY_CANCELED = "57014",
+    ADMIN_SHUTDOWN = "57P01",
+    CRASH_SHUTDOWN = "57P02",
+    CANNOT_CONNECT_NOW = "57P03",
+    DATABASE_DROPPED = "57P04",
+    IDLE_SESSION_TIMEOUT = "57P05",
+
+    // Class 58 - System Error
+    SYSTEM_ERROR = "58000",
+    IO_ERROR = "58030",
+
+    // Class XX - Internal Error
+    INTERNAL_ERROR = "XX000",
+    DATA_CORRUPTED = "XX001",
+    INDEX_CORRUPTED = "XX002",
+}
+
+impl SqlState {
+    /// The five characters of this code.
+    pub const fn code(&self) -> &'static str {
+        self.0
+    }
+
+    /// Known code of `code`, `None` if it's not listed here.
+    pub fn from_code(code: &str) -> Option<SqlState> {
+        ALL.iter().find(|state| state.0 == code).copied()
+    }
+
+    /// The first two characters of this code.
+    pub fn class(&self) -> &'static str {
+        class_of(self.0)
+    }
+
+    /// Class `00`, sent in notices.
+    pub fn is_success(&self) -> bool {
+        self.class() == "00"
+    }
+
+    /// Class `01`.
+    pub fn is_warning(&self) -> bool {
+        self.class() == "01"
+    }
+
+    /// Class `02`.
+    pub fn is_no_data(&self) -> bool {
+        self.class() == "02"
+    }
+
+    /// Codes of errors, not in class `00`, `01` or `02`.
+    pub fn is_error(&self) -> bool {
+        !matches!(self.class(), "00" | "01" | "02")
+    }
+
+    /// Class `08`, the connection is unusable.
+    pub fn is_connection_exception(&self) -> bool {
+        self.class() == "08"
+    }
+
+    /// Class `23`.
+    pub fn is_integrity_constraint_violation(&self) -> bool {
+        self.class() == "23"
+    }
+
+    /// Class `40`, like serialization failure and deadlock. The transaction
+    /// can be retried.
+    pub fn is_transaction_rollback(&self) -> bool {
+        self.class() == "40"
+    }
+}
+
+/// Class of any SQLSTATE code, the first two characters.
+pub fn class_of(code: &str) -> &str {
+    code.get(..2).unwrap_or(code)
+}
+
+impl fmt::Display for SqlState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl From<SqlState> for String {
+    fn from(state: SqlState) -> String {
+        state.0.to_owned()
+    }
+}
+
+impl PartialEq<str> for SqlState {
+    fn eq(&self, code: &str) -> bool {
+        self.0 == code
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sqlstate() {
+        assert_eq!("40001", SqlState::SERIALIZATION_FAILURE.code());
+        assert!(SqlState::SERIALIZATION_FAILURE.is_transaction_rollback());
+        assert!(SqlState::DEADLOCK_DETECTED.is_transaction_rollback());
+        assert!(SqlState::UNIQUE_VIOLATION.is_integrity_constraint_violation());
+        assert!(SqlState::WARNING_DEPRECATED_FEATURE.is_warning());
+        assert!(!SqlState::WARNING.is_error());
+        assert!(SqlState::PROTOCOL_VIOLATION.is_connection_exception());
+        assert!(SqlState::INTERNAL_ERROR.is_error());
+
+        assert_eq!(Some(SqlState::QUERY_CANCELED), SqlState::from_code("57014"));
+        assert_eq!(None, SqlState::from_code("99999"));
+        assert_eq!("22", class_of("22P02"));
+        assert!(SqlState::SYNTAX_ERROR == *"42601");
+
+        // codes are unique
+        for (i, state) in ALL.iter().enumerate() {
+            assert_eq!(5, state.code().len());
+            assert!(!ALL[i + 1..].contains(state), "{state}");
+        }
+    }
+}
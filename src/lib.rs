@@ -37,7 +37,8 @@
 //! application from any level of abstraction. They are:
 //!
 //! - Protocol layer: Just use message definitions and codecs in `messages`
-//!   module.
+//!   module, or drive a connection with the runtime-agnostic
+//!   `sansio::ServerConnection`.
 //! - Message handler layer: Implement `on_` prefixed methods in traits:
 //!   - `StartupHandler`
 //!   - `SimpleQueryHandler`
@@ -46,6 +47,7 @@
 //!   - `AuthSource` and various authentication mechanisms
 //!   - `do_` prefixed methods in handler traits
 //!   - `QueryParser`/`PortalStore` for extended query support
+//!   - `PgWireServerBuilder` to serve handlers on a tcp address
 //!
 //! ## Features
 //!
@@ -54,6 +56,14 @@
 //! - `server-api-ring` is almost same to `server-api-aws-lc-rs` except for it's
 //!   using `ring` as crypto backend.
 //! - `scram` for the SASL/SCRAM authenticator.
+//! - `client-cert` for authentication by tls client certificate.
+//! - `ldap` for authentication against LDAP server.
+//! - `gssapi` for Kerberos authentication with system GSSAPI library.
+//! - `client-api` for the frontend codec, to build proxies and clients.
+//! - `tracing` for spans of connections and statements, and events of
+//!   protocol errors, with the `tracing` crate.
+//! - `pg-catalog` for answering introspection queries of tools from a
+//!   description of tables.
 //! - Turn off default features if you just use our Protocol layer.
 //!
 //! ## Examples
@@ -68,13 +78,33 @@ extern crate derive_new;
 /// handler layer and high-level API layer.
 #[cfg(feature = "server-api")]
 pub mod api;
+/// frontend codec.
+#[cfg(feature = "client-api")]
+pub mod client;
 /// error types.
 pub mod error;
 /// the protocol layer.
 pub mod messages;
+/// components for building proxies and poolers.
+#[cfg(feature = "server-api")]
+pub mod proxy;
+pub mod sansio;
+#[cfg(feature = "server-api")]
+pub mod server;
+#[cfg(feature = "server-api")]
+mod sql;
+/// in-memory client for testing handlers.
+#[cfg(feature = "server-api")]
+pub mod testing;
+/// tls acceptor from PEM certificates and key, with rustls.
+#[cfg(feature = "rustls")]
+pub mod tls;
 /// server entry-point for tokio based application.
 #[cfg(feature = "server-api")]
 pub mod tokio;
 /// types and encoding related helper
 #[cfg(feature = "server-api")]
 pub mod types;
+/// io_uring transport with tokio-uring, on linux.
+#[cfg(all(feature = "tokio-uring", target_os = "linux"))]
+pub mod uring;
@@ -2,32 +2,60 @@ use std::str;
 
 use bytes::{Buf, BufMut, BytesMut};
 
-use crate::error::PgWireResult;
+use crate::error::{PgWireError, PgWireResult};
 
 /// Get null-terminated string, returns None when empty cstring read.
 ///
 /// Note that this implementation will also advance cursor by 1 after reading
 /// empty cstring. This behaviour works for how postgres wire protocol handling
 /// key-value pairs, which is ended by a single `\0`
-pub(crate) fn get_cstring(buf: &mut BytesMut) -> Option<String> {
-    let mut i = 0;
-
-    // with bound check to prevent invalid format
-    while i < buf.remaining() && buf[i] != b'\0' {
-        i += 1;
-    }
+pub(crate) fn get_cstring(buf: &mut BytesMut) -> PgWireResult<Option<String>> {
+    // a string without terminator is cut off by end of message
+    let i = buf
+        .iter()
+        .position(|b| *b == b'\0')
+        .ok_or(PgWireError::TruncatedMessageBody(1))?;
 
     // i+1: include the '\0'
     // move cursor to the end of cstring
     let string_buf = buf.split_to(i + 1);
 
     if i == 0 {
-        None
+        Ok(None)
     } else {
-        Some(String::from_utf8_lossy(&string_buf[..i]).into_owned())
+        Ok(Some(String::from_utf8_lossy(&string_buf[..i]).into_owned()))
     }
 }
 
+fn check_remaining(buf: &BytesMut, len: usize) -> PgWireResult<()> {
+    if buf.remaining() < len {
+        Err(PgWireError::TruncatedMessageBody(len - buf.remaining()))
+    } else {
+        Ok(())
+    }
+}
+
+macro_rules! checked_get {
+    ($($name:ident: $t:ty),*) => {
+        $(
+            pub(crate) fn $name(buf: &mut BytesMut) -> PgWireResult<$t> {
+                check_remaining(buf, std::mem::size_of::<$t>())?;
+                Ok(buf.$name())
+            }
+        )*
+    };
+}
+
+// integers read from message body, failing instead of panicking when the
+// body is too short
+checked_get!(get_u8: u8, get_i8: i8, get_i16: i16, get_u16: u16, get_i32: i32, get_u32: u32);
+
+/// Get `len` bytes from message body.
+pub(crate) fn get_bytes(buf: &mut BytesMut, len: usize) -> PgWireResult<BytesMut> {
+    check_remaining(buf, len)?;
+    Ok(buf.split_to(len))
+}
+
 /// Put null-termianted string
 ///
 /// You can put empty string by giving `""` as input.
@@ -55,6 +83,10 @@ pub(crate) fn get_length(buf: &BytesMut, offset: usize) -> Option<usize> {
 
 /// Check if message_length matches and move the cursor to right position then
 /// call the `decode_fn` for the body
+///
+/// The body is split off from `buf` before decoding, so a malformed body never
+/// reads into next message, and bytes left unread by `decode_fn` are an
+/// error.
 pub(crate) fn decode_packet<T, F>(
     buf: &mut BytesMut,
     offset: usize,
@@ -64,15 +96,37 @@ where
     F: Fn(&mut BytesMut, usize) -> PgWireResult<T>,
 {
     if let Some(msg_len) = get_length(buf, offset) {
+        // the length includes itself
+        if msg_len < 4 || msg_len > i32::MAX as usize {
+            return Err(PgWireError::InvalidMessageLength(msg_len as i32));
+        }
         if buf.remaining() >= msg_len + offset {
-            buf.advance(offset + 4);
-            return decode_fn(buf, msg_len).map(|r| Some(r));
+            let mut body = buf.split_to(msg_len + offset);
+            body.advance(offset + 4);
+            return decode_body_exact(body, msg_len, decode_fn).map(Some);
         }
     }
 
     Ok(None)
 }
 
+/// Decode a message body split off from its frame, failing if `decode_fn`
+/// doesn't consume the whole body.
+pub(crate) fn decode_body_exact<T, F>(
+    mut body: BytesMut,
+    msg_len: usize,
+    decode_fn: F,
+) -> PgWireResult<T>
+where
+    F: Fn(&mut BytesMut, usize) -> PgWireResult<T>,
+{
+    let message = decode_fn(&mut body, msg_len)?;
+    if body.has_remaining() {
+        return Err(PgWireError::TrailingMessageBytes(body.remaining()));
+    }
+    Ok(message)
+}
+
 // pub(crate) fn get_and_ensure_message_type(buf: &mut BytesMut, t: u8) -> PgWireResult<()> {
 //     let msg_type = buf[0];
 //     // ensure the type is corrent
@@ -84,5 +138,5 @@ where
 // }
 
 pub(crate) fn option_string_len(s: &Option<String>) -> usize {
-    1 + s.as_ref().map(|s| s.as_bytes().len()).unwrap_or(0)
+    1 + s.as_ref().map(|s| s.len()).unwrap_or(0)
 }
@@ -1,4 +1,4 @@
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 
 use super::codec;
 use super::Message;
@@ -27,8 +27,8 @@ impl Message for CopyData {
         Ok(())
     }
 
-    fn decode_body(buf: &mut BytesMut, len: usize) -> PgWireResult<Self> {
-        let data = buf.split_to(len - 4).freeze();
+    fn decode_body(buf: &mut BytesMut, _len: usize) -> PgWireResult<Self> {
+        let data = buf.split().freeze();
         Ok(Self::new(data))
     }
 }
@@ -69,11 +69,11 @@ pub struct CopyFail {
 impl Message for CopyFail {
     #[inline]
     fn message_type() -> Option<u8> {
-        Some(MESSAGE_TYPE_BYTE_COPY_DONE)
+        Some(MESSAGE_TYPE_BYTE_COPY_FAIL)
     }
 
     fn message_length(&self) -> usize {
-        4 + self.message.as_bytes().len() + 1
+        4 + self.message.len() + 1
     }
 
     fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
@@ -82,7 +82,7 @@ impl Message for CopyFail {
     }
 
     fn decode_body(buf: &mut BytesMut, _len: usize) -> PgWireResult<Self> {
-        let msg = codec::get_cstring(buf).unwrap_or_else(|| "".to_owned());
+        let msg = codec::get_cstring(buf)?.unwrap_or_else(|| "".to_owned());
         Ok(Self::new(msg))
     }
 }
@@ -117,11 +117,11 @@ impl Message for CopyInResponse {
     }
 
     fn decode_body(buf: &mut BytesMut, _len: usize) -> PgWireResult<Self> {
-        let format = buf.get_i8();
-        let columns = buf.get_i16();
-        let mut column_formats = Vec::with_capacity(columns as usize);
+        let format = codec::get_i8(buf)?;
+        let columns = codec::get_i16(buf)?;
+        let mut column_formats = Vec::with_capacity(columns.max(0) as usize);
         for _ in 0..columns {
-            column_formats.push(buf.get_i16());
+            column_formats.push(codec::get_i16(buf)?);
         }
 
         Ok(Self::new(format, columns, column_formats))
@@ -158,11 +158,11 @@ impl Message for CopyOutResponse {
     }
 
     fn decode_body(buf: &mut BytesMut, _len: usize) -> PgWireResult<Self> {
-        let format = buf.get_i8();
-        let columns = buf.get_i16();
-        let mut column_formats = Vec::with_capacity(columns as usize);
+        let format = codec::get_i8(buf)?;
+        let columns = codec::get_i16(buf)?;
+        let mut column_formats = Vec::with_capacity(columns.max(0) as usize);
         for _ in 0..columns {
-            column_formats.push(buf.get_i16());
+            column_formats.push(codec::get_i16(buf)?);
         }
 
         Ok(Self::new(format, columns, column_formats))
@@ -199,11 +199,11 @@ impl Message for CopyBothResponse {
     }
 
     fn decode_body(buf: &mut BytesMut, _len: usize) -> PgWireResult<Self> {
-        let format = buf.get_i8();
-        let columns = buf.get_i16();
-        let mut column_formats = Vec::with_capacity(columns as usize);
+        let format = codec::get_i8(buf)?;
+        let columns = codec::get_i16(buf)?;
+        let mut column_formats = Vec::with_capacity(columns.max(0) as usize);
         for _ in 0..columns {
-            column_formats.push(buf.get_i16());
+            column_formats.push(codec::get_i16(buf)?);
         }
 
         Ok(Self::new(format, columns, column_formats))
@@ -0,0 +1,330 @@
+//! Conformance corpus of protocol framing.
+//!
+//! Each `CorpusCase` is a byte stream with how it must decode: into a number
+//! of messages, as cut off in the middle of a message, or failing at the
+//! offset of a malformed message. The cases check decoders of
+//! `pgwire::sansio`, and make a seed corpus for fuzzers.
+
+/// Which side sends the bytes, and in which phase of connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// client before startup, sending untagged packets until `Startup`
+    Startup,
+    /// client after startup
+    Frontend,
+    /// server
+    Backend,
+}
+
+/// How a case decodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expected {
+    /// into this number of messages
+    Messages(usize),
+    /// the last message is incomplete
+    Incomplete,
+    /// the message at this offset is malformed
+    Malformed(usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CorpusCase {
+    pub name: &'static str,
+    pub framing: Framing,
+    pub bytes: &'static [u8],
+    pub expected: Expected,
+}
+
+const fn case(
+    name: &'static str,
+    framing: Framing,
+    bytes: &'static [u8],
+    expected: Expected,
+) -> CorpusCase {
+    CorpusCase {
+        name,
+        framing,
+        bytes,
+        expected,
+    }
+}
+
+/// The corpus.
+pub const CORPUS: &[CorpusCase] = &[
+    // startup packets
+    case(
+        "ssl_request",
+        Framing::Startup,
+        b"\0\0\0\x08\x04\xd2\x16\x2f",
+        Expected::Messages(1),
+    ),
+    case(
+        "gssenc_request_then_startup",
+        Framing::Startup,
+        b"\0\0\0\x08\x04\xd2\x16\x30\0\0\0\x14\0\x03\0\0user\0alice\0\0",
+        Expected::Messages(2),
+    ),
+    case(
+        "startup",
+        Framing::Startup,
+        b"\0\0\0\x14\0\x03\0\0user\0alice\0\0",
+        Expected::Messages(1),
+    ),
+    case(
+        "ssl_request_then_startup",
+        Framing::Startup,
+        b"\0\0\0\x08\x04\xd2\x16\x2f\0\0\0\x14\0\x03\0\0user\0alice\0\0",
+        Expected::Messages(2),
+    ),
+    case(
+        "startup_then_query",
+        Framing::Startup,
+        b"\0\0\0\x14\0\x03\0\0user\0alice\0\0Q\0\0\0\x0dSELECT 1\0",
+        Expected::Messages(2),
+    ),
+    case(
+        "cancel_request",
+        Framing::Startup,
+        b"\0\0\0\x10\x04\xd2\x16\x2e\0\0\0\x01\0\0\0\x02",
+        Expected::Messages(1),
+    ),
+    case(
+        "startup_truncated",
+        Framing::Startup,
+        b"\0\0\0\x14\0\x03\0\0user",
+        Expected::Incomplete,
+    ),
+    case(
+        "startup_protocol_2",
+        Framing::Startup,
+        b"\0\0\0\x14\0\x02\0\0user\0alice\0\0",
+        Expected::Malformed(0),
+    ),
+    case(
+        "startup_value_without_terminator",
+        Framing::Startup,
+        b"\0\0\0\x0e\0\x03\0\0user\0a",
+        Expected::Malformed(0),
+    ),
+    case(
+        "startup_length_below_header",
+        Framing::Startup,
+        b"\0\0\0\x02\0\x03\0\0",
+        Expected::Malformed(0),
+    ),
+    // frontend messages
+    case(
+        "query",
+        Framing::Frontend,
+        b"Q\0\0\0\x0dSELECT 1\0",
+        Expected::Messages(1),
+    ),
+    case(
+        "extended_query",
+        Framing::Frontend,
+        b"P\0\0\0\x15\0SELECT $1\0\0\x01\0\0\0\x17\
+          B\0\0\0\x11\0\0\0\0\0\x01\0\0\0\x017\0\0\
+          D\0\0\0\x06P\0\
+          E\0\0\0\x09\0\0\0\0\0\
+          S\0\0\0\x04",
+        Expected::Messages(5),
+    ),
+    case(
+        "copy_data",
+        Framing::Frontend,
+        b"d\0\0\0\x06a\nc\0\0\0\x04",
+        Expected::Messages(2),
+    ),
+    case(
+        "terminate",
+        Framing::Frontend,
+        b"X\0\0\0\x04",
+        Expected::Messages(1),
+    ),
+    case(
+        "query_truncated",
+        Framing::Frontend,
+        b"Q\0\0\0\x0dSELECT",
+        Expected::Incomplete,
+    ),
+    case(
+        "unknown_message_type",
+        Framing::Frontend,
+        b"z\0\0\0\x04",
+        Expected::Malformed(0),
+    ),
+    case(
+        "negative_length",
+        Framing::Frontend,
+        b"Q\xff\xff\xff\xff",
+        Expected::Malformed(0),
+    ),
+    case(
+        "length_below_header",
+        Framing::Frontend,
+        b"S\0\0\0\x03",
+        Expected::Malformed(0),
+    ),
+    case(
+        "query_without_terminator",
+        Framing::Frontend,
+        b"Q\0\0\0\x08SELE",
+        Expected::Malformed(0),
+    ),
+    case(
+        "describe_without_target",
+        Framing::Frontend,
+        b"D\0\0\0\x04",
+        Expected::Malformed(0),
+    ),
+    case(
+        "bind_parameter_overruns_message",
+        Framing::Frontend,
+        b"B\0\0\0\x12\0\0\0\0\0\x01\0\0\0\x64ab\0\0",
+        Expected::Malformed(0),
+    ),
+    case(
+        "sync_with_trailing_bytes",
+        Framing::Frontend,
+        b"S\0\0\0\x05\0",
+        Expected::Malformed(0),
+    ),
+    case(
+        "second_message_malformed",
+        Framing::Frontend,
+        b"Q\0\0\0\x0dSELECT 1\0S\0\0\0\x05\0",
+        Expected::Malformed(14),
+    ),
+    // backend messages
+    case(
+        "authentication_ok",
+        Framing::Backend,
+        b"R\0\0\0\x08\0\0\0\0",
+        Expected::Messages(1),
+    ),
+    case(
+        "authentication_md5",
+        Framing::Backend,
+        b"R\0\0\0\x0c\0\0\0\x05salt",
+        Expected::Messages(1),
+    ),
+    case(
+        "query_result",
+        Framing::Backend,
+        b"T\0\0\0\x1a\0\x01a\0\0\0\0\0\0\0\0\0\0\x17\0\x04\xff\xff\xff\xff\0\0\
+          D\0\0\0\x0b\0\x01\0\0\0\x017\
+          C\0\0\0\x0dSELECT 1\0\
+          Z\0\0\0\x05I",
+        Expected::Messages(4),
+    ),
+    case(
+        "error_response",
+        Framing::Backend,
+        b"E\0\0\0\x1bSERROR\0C42601\0Msyntax\0\0",
+        Expected::Messages(1),
+    ),
+    case(
+        "authentication_unknown_type",
+        Framing::Backend,
+        b"R\0\0\0\x08\0\0\0\x63",
+        Expected::Malformed(0),
+    ),
+    case(
+        "authentication_md5_without_salt",
+        Framing::Backend,
+        b"R\0\0\0\x08\0\0\0\x05",
+        Expected::Malformed(0),
+    ),
+    case(
+        "row_description_missing_field",
+        Framing::Backend,
+        b"T\0\0\0\x1a\0\x02a\0\0\0\0\0\0\0\0\0\0\x17\0\x04\xff\xff\xff\xff\0\0",
+        Expected::Malformed(0),
+    ),
+    case(
+        "data_row_without_count",
+        Framing::Backend,
+        b"D\0\0\0\x05\0",
+        Expected::Malformed(0),
+    ),
+    case(
+        "ready_for_query_invalid_status",
+        Framing::Backend,
+        b"Z\0\0\0\x05x",
+        Expected::Malformed(0),
+    ),
+    case(
+        "error_response_without_terminator",
+        Framing::Backend,
+        b"E\0\0\0\x0bSERROR\0",
+        Expected::Malformed(0),
+    ),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::PgWireError;
+    use crate::sansio::{
+        decode_backend_stream, decode_frontend_stream, DecodeError, PgWireConnectionState,
+    };
+
+    fn decode(framing: Framing, bytes: &[u8]) -> Result<usize, DecodeError> {
+        match framing {
+            Framing::Startup => {
+                decode_frontend_stream(PgWireConnectionState::AwaitingStartup, bytes)
+                    .map(|messages| messages.len())
+            }
+            Framing::Frontend => {
+                decode_frontend_stream(PgWireConnectionState::ReadyForQuery, bytes)
+                    .map(|messages| messages.len())
+            }
+            Framing::Backend => decode_backend_stream(bytes).map(|messages| messages.len()),
+        }
+    }
+
+    #[test]
+    fn test_corpus() {
+        for case in CORPUS {
+            let result = decode(case.framing, case.bytes);
+            match (case.expected, result) {
+                (Expected::Messages(n), Ok(decoded)) => assert_eq!(n, decoded, "{}", case.name),
+                (
+                    Expected::Incomplete,
+                    Err(DecodeError {
+                        error: PgWireError::IncompleteMessage(_),
+                        ..
+                    }),
+                ) => {}
+                (Expected::Malformed(offset), Err(error))
+                    if !matches!(error.error, PgWireError::IncompleteMessage(_)) =>
+                {
+                    assert_eq!(offset, error.offset, "{}: {error}", case.name);
+                }
+                (expected, result) => {
+                    panic!("{}: expected {expected:?}, got {result:?}", case.name)
+                }
+            }
+        }
+    }
+
+    // truncated and corrupted cases decode to messages or errors, without
+    // panicking
+    #[test]
+    fn test_corpus_mutations() {
+        for case in CORPUS {
+            for len in 0..case.bytes.len() {
+                let _ = decode(case.framing, &case.bytes[..len]);
+            }
+            let mut bytes = case.bytes.to_vec();
+            for i in 0..bytes.len() {
+                for value in [0x00, 0x01, 0x7f, 0x80, 0xff] {
+                    let original = bytes[i];
+                    bytes[i] = value;
+                    let _ = decode(case.framing, &bytes);
+                    bytes[i] = original;
+                }
+            }
+        }
+    }
+}
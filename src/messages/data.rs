@@ -2,7 +2,7 @@ use bytes::{Buf, BufMut, BytesMut};
 
 use super::codec;
 use super::Message;
-use crate::error::PgWireResult;
+use crate::error::{PgWireError, PgWireResult};
 
 pub const FORMAT_CODE_TEXT: i16 = 0;
 pub const FORMAT_CODE_BINARY: i16 = 1;
@@ -44,7 +44,7 @@ impl Message for RowDescription {
             + self
                 .fields
                 .iter()
-                .map(|f| f.name.as_bytes().len() + 1 + 4 + 2 + 4 + 2 + 4 + 2)
+                .map(|f| f.name.len() + 1 + 4 + 2 + 4 + 2 + 4 + 2)
                 .sum::<usize>()
     }
 
@@ -65,18 +65,18 @@ impl Message for RowDescription {
     }
 
     fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
-        let fields_len = buf.get_i16();
-        let mut fields = Vec::with_capacity(fields_len as usize);
+        let fields_len = codec::get_i16(buf)?;
+        let mut fields = Vec::with_capacity(fields_len.max(0) as usize);
 
         for _ in 0..fields_len {
             let field = FieldDescription {
-                name: codec::get_cstring(buf).unwrap_or_else(|| "".to_owned()),
-                table_id: buf.get_i32(),
-                column_id: buf.get_i16(),
-                type_id: buf.get_u32(),
-                type_size: buf.get_i16(),
-                type_modifier: buf.get_i32(),
-                format_code: buf.get_i16(),
+                name: codec::get_cstring(buf)?.unwrap_or_else(|| "".to_owned()),
+                table_id: codec::get_i32(buf)?,
+                column_id: codec::get_i16(buf)?,
+                type_id: codec::get_u32(buf)?,
+                type_size: codec::get_i16(buf)?,
+                type_modifier: codec::get_i32(buf)?,
+                format_code: codec::get_i16(buf)?,
             };
 
             fields.push(field);
@@ -116,11 +116,11 @@ impl Message for ParameterDescription {
     }
 
     fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
-        let types_len = buf.get_i16();
-        let mut types = Vec::with_capacity(types_len as usize);
+        let types_len = codec::get_i16(buf)?;
+        let mut types = Vec::with_capacity(types_len.max(0) as usize);
 
         for _ in 0..types_len {
-            types.push(buf.get_i32() as u32);
+            types.push(codec::get_i32(buf)? as u32);
         }
 
         Ok(ParameterDescription { types })
@@ -138,7 +138,30 @@ pub struct DataRow {
     pub field_count: i16,
 }
 
-impl DataRow {}
+impl DataRow {
+    /// Split row data into fields, `None` for NULL. Fields are in the format
+    /// given by `RowDescription`.
+    pub fn fields(&self) -> PgWireResult<Vec<Option<&[u8]>>> {
+        let mut fields = Vec::with_capacity(self.field_count.max(0) as usize);
+        let mut data = &self.data[..];
+        for _ in 0..self.field_count {
+            if data.len() < 4 {
+                return Err(PgWireError::InvalidDataRow);
+            }
+            let len = data.get_i32();
+            if len < 0 {
+                fields.push(None);
+            } else if data.len() < len as usize {
+                return Err(PgWireError::InvalidDataRow);
+            } else {
+                let (field, rest) = data.split_at(len as usize);
+                fields.push(Some(field));
+                data = rest;
+            }
+        }
+        Ok(fields)
+    }
+}
 
 pub const MESSAGE_TYPE_BYTE_DATA_ROW: u8 = b'D';
 
@@ -160,10 +183,9 @@ impl Message for DataRow {
         Ok(())
     }
 
-    fn decode_body(buf: &mut BytesMut, msg_len: usize) -> PgWireResult<Self> {
-        let field_count = buf.get_i16();
-        // get body size from packet
-        let data = buf.split_to(msg_len - 4 - 2);
+    fn decode_body(buf: &mut BytesMut, _msg_len: usize) -> PgWireResult<Self> {
+        let field_count = codec::get_i16(buf)?;
+        let data = buf.split();
 
         Ok(DataRow { data, field_count })
     }
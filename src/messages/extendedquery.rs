@@ -1,4 +1,4 @@
-use bytes::{Buf, BufMut, Bytes};
+use bytes::{BufMut, Bytes};
 
 use super::{codec, Message};
 use crate::error::PgWireResult;
@@ -22,8 +22,8 @@ impl Message for Parse {
 
     fn message_length(&self) -> usize {
         4 + codec::option_string_len(&self.name) // name
-            + (1 + self.query.as_bytes().len()) // query
-            + (4 * self.type_oids.len()) // type oids
+            + (1 + self.query.len()) // query
+            + 2 + (4 * self.type_oids.len()) // type oids
     }
 
     fn encode_body(&self, buf: &mut bytes::BytesMut) -> PgWireResult<()> {
@@ -39,13 +39,13 @@ impl Message for Parse {
     }
 
     fn decode_body(buf: &mut bytes::BytesMut, _: usize) -> PgWireResult<Self> {
-        let name = codec::get_cstring(buf);
-        let query = codec::get_cstring(buf).unwrap_or_else(|| "".to_owned());
-        let type_oid_count = buf.get_i16();
+        let name = codec::get_cstring(buf)?;
+        let query = codec::get_cstring(buf)?.unwrap_or_else(|| "".to_owned());
+        let type_oid_count = codec::get_i16(buf)?;
 
-        let mut type_oids = Vec::with_capacity(type_oid_count as usize);
+        let mut type_oids = Vec::with_capacity(type_oid_count.max(0) as usize);
         for _ in 0..type_oid_count {
-            type_oids.push(buf.get_u32());
+            type_oids.push(codec::get_u32(buf)?);
         }
 
         Ok(Parse {
@@ -115,8 +115,8 @@ impl Message for Close {
     }
 
     fn decode_body(buf: &mut bytes::BytesMut, _: usize) -> PgWireResult<Self> {
-        let target_type = buf.get_u8();
-        let name = codec::get_cstring(buf);
+        let target_type = codec::get_u8(buf)?;
+        let name = codec::get_cstring(buf)?;
 
         Ok(Close { target_type, name })
     }
@@ -211,33 +211,34 @@ impl Message for Bind {
     }
 
     fn decode_body(buf: &mut bytes::BytesMut, _: usize) -> PgWireResult<Self> {
-        let portal_name = codec::get_cstring(buf);
-        let statement_name = codec::get_cstring(buf);
+        let portal_name = codec::get_cstring(buf)?;
+        let statement_name = codec::get_cstring(buf)?;
 
-        let parameter_format_code_len = buf.get_i16();
-        let mut parameter_format_codes = Vec::with_capacity(parameter_format_code_len as usize);
+        let parameter_format_code_len = codec::get_i16(buf)?;
+        let mut parameter_format_codes =
+            Vec::with_capacity(parameter_format_code_len.max(0) as usize);
 
         for _ in 0..parameter_format_code_len {
-            parameter_format_codes.push(buf.get_i16());
+            parameter_format_codes.push(codec::get_i16(buf)?);
         }
 
-        let parameter_len = buf.get_i16();
-        let mut parameters = Vec::with_capacity(parameter_len as usize);
+        let parameter_len = codec::get_i16(buf)?;
+        let mut parameters = Vec::with_capacity(parameter_len.max(0) as usize);
         for _ in 0..parameter_len {
-            let data_len = buf.get_i32();
+            let data_len = codec::get_i32(buf)?;
 
             if data_len >= 0 {
-                parameters.push(Some(buf.split_to(data_len as usize).freeze()));
+                parameters.push(Some(codec::get_bytes(buf, data_len as usize)?.freeze()));
             } else {
                 parameters.push(None);
             }
         }
 
-        let result_column_format_code_len = buf.get_i16();
+        let result_column_format_code_len = codec::get_i16(buf)?;
         let mut result_column_format_codes =
-            Vec::with_capacity(result_column_format_code_len as usize);
+            Vec::with_capacity(result_column_format_code_len.max(0) as usize);
         for _ in 0..result_column_format_code_len {
-            result_column_format_codes.push(buf.get_i16());
+            result_column_format_codes.push(codec::get_i16(buf)?);
         }
 
         Ok(Bind {
@@ -309,8 +310,8 @@ impl Message for Describe {
     }
 
     fn decode_body(buf: &mut bytes::BytesMut, _: usize) -> PgWireResult<Self> {
-        let target_type = buf.get_u8();
-        let name = codec::get_cstring(buf);
+        let target_type = codec::get_u8(buf)?;
+        let name = codec::get_cstring(buf)?;
 
         Ok(Describe { target_type, name })
     }
@@ -343,8 +344,8 @@ impl Message for Execute {
     }
 
     fn decode_body(buf: &mut bytes::BytesMut, _: usize) -> PgWireResult<Self> {
-        let name = codec::get_cstring(buf);
-        let max_rows = buf.get_i32();
+        let name = codec::get_cstring(buf)?;
+        let max_rows = codec::get_i32(buf)?;
 
         Ok(Execute { name, max_rows })
     }
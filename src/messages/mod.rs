@@ -54,13 +54,17 @@ pub trait Message: Sized {
     }
 }
 
-mod codec;
+pub(crate) mod codec;
 /// Copy messages
 pub mod copy;
+/// Conformance corpus of message framing
+pub mod corpus;
 /// Data related messages
 pub mod data;
 /// Extended query messages, including request/response for parse, bind and etc.
 pub mod extendedquery;
+/// Streaming replication messages, sent in `CopyData`
+pub mod replication;
 /// General response messages
 pub mod response;
 /// Simple query messages, including descriptions
@@ -75,6 +79,8 @@ pub mod terminate;
 pub enum PgWireFrontendMessage {
     Startup(startup::Startup),
     SslRequest(startup::SslRequest),
+    GssEncRequest(startup::GssEncRequest),
+    CancelRequest(startup::CancelRequest),
     PasswordMessageFamily(startup::PasswordMessageFamily),
 
     Query(simplequery::Query),
@@ -112,6 +118,8 @@ impl PgWireFrontendMessage {
         match self {
             Self::Startup(msg) => msg.encode(buf),
             Self::SslRequest(msg) => msg.encode(buf),
+            Self::GssEncRequest(msg) => msg.encode(buf),
+            Self::CancelRequest(msg) => msg.encode(buf),
             Self::PasswordMessageFamily(msg) => msg.encode(buf),
 
             Self::Query(msg) => msg.encode(buf),
@@ -197,6 +205,7 @@ pub enum PgWireBackendMessage {
     Authentication(startup::Authentication),
     ParameterStatus(startup::ParameterStatus),
     BackendKeyData(startup::BackendKeyData),
+    NegotiateProtocolVersion(startup::NegotiateProtocolVersion),
 
     // extended query
     ParseComplete(extendedquery::ParseComplete),
@@ -234,6 +243,7 @@ impl PgWireBackendMessage {
             Self::Authentication(msg) => msg.encode(buf),
             Self::ParameterStatus(msg) => msg.encode(buf),
             Self::BackendKeyData(msg) => msg.encode(buf),
+            Self::NegotiateProtocolVersion(msg) => msg.encode(buf),
 
             Self::ParseComplete(msg) => msg.encode(buf),
             Self::BindComplete(msg) => msg.encode(buf),
@@ -275,6 +285,10 @@ impl PgWireBackendMessage {
                 startup::MESSAGE_TYPE_BYTE_BACKEND_KEY_DATA => {
                     startup::BackendKeyData::decode(buf).map(|v| v.map(Self::BackendKeyData))
                 }
+                startup::MESSAGE_TYPE_BYTE_NEGOTIATE_PROTOCOL_VERSION => {
+                    startup::NegotiateProtocolVersion::decode(buf)
+                        .map(|v| v.map(Self::NegotiateProtocolVersion))
+                }
 
                 extendedquery::MESSAGE_TYPE_BYTE_PARSE_COMPLETE => {
                     extendedquery::ParseComplete::decode(buf).map(|v| v.map(Self::ParseComplete))
@@ -352,15 +366,18 @@ impl PgWireBackendMessage {
 }
 
 #[cfg(test)]
+#[allow(clippy::field_reassign_with_default)]
 mod test {
     use super::copy::*;
     use super::data::*;
     use super::extendedquery::*;
+    use super::replication::*;
     use super::response::*;
     use super::simplequery::*;
     use super::startup::*;
     use super::terminate::*;
-    use super::Message;
+    use super::{Message, PgWireBackendMessage};
+    use crate::error::PgWireError;
     use bytes::{Buf, BufMut, Bytes, BytesMut};
 
     macro_rules! roundtrip {
@@ -385,6 +402,24 @@ mod test {
         s.parameters.insert("user".to_owned(), "tomcat".to_owned());
 
         roundtrip!(s, Startup);
+
+        // newer minor versions are negotiated after decoding
+        s.protocol_number_minor = 2;
+        s.parameters
+            .insert("_pq_.compression".to_owned(), "on".to_owned());
+        assert_eq!(
+            vec!["_pq_.compression"],
+            s.protocol_options().collect::<Vec<_>>()
+        );
+        roundtrip!(s, Startup);
+
+        s.protocol_number_major = 4;
+        let mut buffer = BytesMut::new();
+        s.encode(&mut buffer).unwrap();
+        assert!(matches!(
+            Startup::decode(&mut buffer),
+            Err(PgWireError::InvalidProtocolVersion(_))
+        ));
     }
 
     #[test]
@@ -393,6 +428,9 @@ mod test {
             Authentication::Ok,
             Authentication::CleartextPassword,
             Authentication::KerberosV5,
+            Authentication::GSS,
+            Authentication::GSSContinue(Bytes::from("token")),
+            Authentication::SSPI,
             Authentication::SASLContinue(Bytes::from("hello")),
             Authentication::SASLFinal(Bytes::from("world")),
         ];
@@ -402,6 +440,29 @@ mod test {
 
         let md5pass = Authentication::MD5Password(vec![b'p', b's', b't', b'g']);
         roundtrip!(md5pass, Authentication);
+
+        // variable length body doesn't consume the next message
+        let mut buffer = BytesMut::new();
+        Authentication::SASLFinal(Bytes::from("world"))
+            .encode(&mut buffer)
+            .unwrap();
+        Authentication::Ok.encode(&mut buffer).unwrap();
+        assert_eq!(
+            Authentication::decode(&mut buffer).unwrap(),
+            Some(Authentication::SASLFinal(Bytes::from("world")))
+        );
+        assert_eq!(
+            Authentication::decode(&mut buffer).unwrap(),
+            Some(Authentication::Ok)
+        );
+    }
+
+    #[test]
+    fn test_negotiate_protocol_version() {
+        let npv = NegotiateProtocolVersion::new(0, vec!["_pq_.compression".to_owned()]);
+        roundtrip!(npv, NegotiateProtocolVersion);
+        let npv = NegotiateProtocolVersion::new(0, vec![]);
+        roundtrip!(npv, NegotiateProtocolVersion);
     }
 
     #[test]
@@ -436,6 +497,23 @@ mod test {
         roundtrip!(r4q, ReadyForQuery);
         let r4q = ReadyForQuery::new(TransactionStatus::Error);
         roundtrip!(r4q, ReadyForQuery);
+
+        let begin = PgWireBackendMessage::CommandComplete(CommandComplete::new("BEGIN".to_owned()));
+        let error = PgWireBackendMessage::ErrorResponse(ErrorResponse::new(vec![(
+            b'S',
+            "ERROR".to_owned(),
+        )]));
+        let commit =
+            PgWireBackendMessage::CommandComplete(CommandComplete::new("COMMIT".to_owned()));
+        let status = TransactionStatus::Idle.after_sent(&begin);
+        assert_eq!(TransactionStatus::Transaction, status);
+        assert_eq!(TransactionStatus::Error, status.after_sent(&error));
+        assert_eq!(TransactionStatus::Idle, status.after_sent(&commit));
+        // errors outside of transaction don't start one
+        assert_eq!(
+            TransactionStatus::Idle,
+            TransactionStatus::Idle.after_sent(&error)
+        );
     }
 
     #[test]
@@ -550,6 +628,18 @@ mod test {
         roundtrip!(sslreq, SslRequest);
     }
 
+    #[test]
+    fn test_gssencrequest() {
+        let gssencreq = GssEncRequest::new();
+        roundtrip!(gssencreq, GssEncRequest);
+    }
+
+    #[test]
+    fn test_cancelrequest() {
+        let cancel = CancelRequest::new(1234, -5678);
+        roundtrip!(cancel, CancelRequest);
+    }
+
     #[test]
     fn test_sslresponse() {
         let sslaccept = SslResponse::Accept;
@@ -595,6 +685,14 @@ mod test {
         let item2 = PasswordMessageFamily::decode(&mut buffer).unwrap().unwrap();
         assert_eq!(buffer.remaining(), 0);
         assert_eq!(saslinitialresp, item2.into_sasl_initial_response().unwrap());
+
+        let gssresp = GSSResponse::new(Bytes::from_static(b"token"));
+        let mut buffer = BytesMut::new();
+        gssresp.encode(&mut buffer).unwrap();
+
+        let item2 = PasswordMessageFamily::decode(&mut buffer).unwrap().unwrap();
+        assert_eq!(buffer.remaining(), 0);
+        assert_eq!(gssresp, item2.into_gss_response().unwrap());
     }
 
     #[test]
@@ -633,6 +731,49 @@ mod test {
         roundtrip!(copyresponse, CopyBothResponse);
     }
 
+    #[test]
+    fn test_replication_messages() {
+        let messages = vec![
+            ReplicationBackendMessage::XLogData(XLogData::new(
+                0x16_B374_D848,
+                0x16_B374_D900,
+                current_timestamp(),
+                Bytes::from_static(b"BEGIN 1234"),
+            )),
+            ReplicationBackendMessage::PrimaryKeepalive(PrimaryKeepalive::new(
+                0x16_B374_D900,
+                current_timestamp(),
+                true,
+            )),
+        ];
+        for msg in messages {
+            let copy_data = msg.clone().into_copy_data();
+            assert_eq!(
+                msg,
+                ReplicationBackendMessage::decode(copy_data.data).unwrap()
+            );
+        }
+
+        let messages = vec![
+            ReplicationFrontendMessage::StandbyStatusUpdate(StandbyStatusUpdate::new(
+                1, 2, 3, 4, false,
+            )),
+            ReplicationFrontendMessage::HotStandbyFeedback(HotStandbyFeedback::new(1, 2, 3, 4, 5)),
+        ];
+        for msg in messages {
+            let copy_data = msg.clone().into_copy_data();
+            assert_eq!(
+                msg,
+                ReplicationFrontendMessage::decode(copy_data.data).unwrap()
+            );
+        }
+        assert!(ReplicationFrontendMessage::decode(Bytes::from_static(b"r\0\0")).is_err());
+
+        assert_eq!(Some(0x16_B374_D848), parse_lsn("16/B374D848"));
+        assert_eq!("16/B374D848", format_lsn(0x16_B374_D848));
+        assert_eq!(None, parse_lsn("16B374D848"));
+    }
+
     #[test]
     fn test_notification_response() {
         let notification_response =
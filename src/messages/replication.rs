@@ -0,0 +1,256 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use super::copy::CopyData;
+use crate::error::{PgWireError, PgWireResult};
+
+// Messages of streaming replication are sent in `CopyData` after
+// `CopyBothResponse`. They have a type byte but no length.
+
+pub const MESSAGE_TYPE_BYTE_XLOG_DATA: u8 = b'w';
+pub const MESSAGE_TYPE_BYTE_PRIMARY_KEEPALIVE: u8 = b'k';
+pub const MESSAGE_TYPE_BYTE_STANDBY_STATUS_UPDATE: u8 = b'r';
+pub const MESSAGE_TYPE_BYTE_HOT_STANDBY_FEEDBACK: u8 = b'h';
+
+// microseconds between unix epoch and postgres epoch 2000-01-01
+const POSTGRES_EPOCH_MICROS: i64 = 946_684_800_000_000;
+
+/// Current time in replication messages, microseconds since 2000-01-01.
+pub fn current_timestamp() -> i64 {
+    let micros = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as i64)
+        .unwrap_or_default();
+    micros - POSTGRES_EPOCH_MICROS
+}
+
+/// Parse LSN in `16/B374D848` format.
+pub fn parse_lsn(s: &str) -> Option<u64> {
+    let (high, low) = s.split_once('/')?;
+    let high = u32::from_str_radix(high, 16).ok()?;
+    let low = u32::from_str_radix(low, 16).ok()?;
+    Some(((high as u64) << 32) | low as u64)
+}
+
+/// Format LSN like postgres, `16/B374D848`.
+pub fn format_lsn(lsn: u64) -> String {
+    format!("{:X}/{:X}", lsn >> 32, lsn as u32)
+}
+
+fn check_remaining(buf: &Bytes, len: usize) -> PgWireResult<()> {
+    if buf.remaining() < len {
+        Err(PgWireError::InvalidReplicationMessage)
+    } else {
+        Ok(())
+    }
+}
+
+/// WAL data sent to client.
+#[non_exhaustive]
+#[derive(PartialEq, Eq, Debug, Clone, new)]
+pub struct XLogData {
+    pub start_lsn: u64,
+    pub end_lsn: u64,
+    pub timestamp: i64,
+    pub data: Bytes,
+}
+
+impl XLogData {
+    fn encode_body(&self, buf: &mut BytesMut) {
+        buf.put_u64(self.start_lsn);
+        buf.put_u64(self.end_lsn);
+        buf.put_i64(self.timestamp);
+        buf.put_slice(&self.data);
+    }
+
+    fn decode_body(buf: &mut Bytes) -> PgWireResult<Self> {
+        check_remaining(buf, 24)?;
+        let start_lsn = buf.get_u64();
+        let end_lsn = buf.get_u64();
+        let timestamp = buf.get_i64();
+        let data = buf.split_off(0);
+        Ok(XLogData::new(start_lsn, end_lsn, timestamp, data))
+    }
+}
+
+/// Keepalive sent to client, optionally asking for `StandbyStatusUpdate`.
+#[non_exhaustive]
+#[derive(PartialEq, Eq, Debug, Clone, new)]
+pub struct PrimaryKeepalive {
+    pub wal_end: u64,
+    pub timestamp: i64,
+    pub reply: bool,
+}
+
+impl PrimaryKeepalive {
+    fn encode_body(&self, buf: &mut BytesMut) {
+        buf.put_u64(self.wal_end);
+        buf.put_i64(self.timestamp);
+        buf.put_u8(self.reply.into());
+    }
+
+    fn decode_body(buf: &mut Bytes) -> PgWireResult<Self> {
+        check_remaining(buf, 17)?;
+        let wal_end = buf.get_u64();
+        let timestamp = buf.get_i64();
+        let reply = buf.get_u8() != 0;
+        Ok(PrimaryKeepalive::new(wal_end, timestamp, reply))
+    }
+}
+
+/// Progress reported by client.
+#[non_exhaustive]
+#[derive(PartialEq, Eq, Debug, Clone, new)]
+pub struct StandbyStatusUpdate {
+    /// The location of the last WAL byte + 1 received and written to disk
+    pub write_lsn: u64,
+    /// The location of the last WAL byte + 1 flushed to disk
+    pub flush_lsn: u64,
+    /// The location of the last WAL byte + 1 applied
+    pub apply_lsn: u64,
+    pub timestamp: i64,
+    /// Client asks for a keepalive immediately
+    pub reply: bool,
+}
+
+impl StandbyStatusUpdate {
+    fn encode_body(&self, buf: &mut BytesMut) {
+        buf.put_u64(self.write_lsn);
+        buf.put_u64(self.flush_lsn);
+        buf.put_u64(self.apply_lsn);
+        buf.put_i64(self.timestamp);
+        buf.put_u8(self.reply.into());
+    }
+
+    fn decode_body(buf: &mut Bytes) -> PgWireResult<Self> {
+        check_remaining(buf, 33)?;
+        let write_lsn = buf.get_u64();
+        let flush_lsn = buf.get_u64();
+        let apply_lsn = buf.get_u64();
+        let timestamp = buf.get_i64();
+        let reply = buf.get_u8() != 0;
+        Ok(StandbyStatusUpdate::new(
+            write_lsn, flush_lsn, apply_lsn, timestamp, reply,
+        ))
+    }
+}
+
+/// Transaction ids that client still needs, sent by physical replicas.
+#[non_exhaustive]
+#[derive(PartialEq, Eq, Debug, Clone, new)]
+pub struct HotStandbyFeedback {
+    pub timestamp: i64,
+    pub xmin: u32,
+    pub xmin_epoch: u32,
+    pub catalog_xmin: u32,
+    pub catalog_xmin_epoch: u32,
+}
+
+impl HotStandbyFeedback {
+    fn encode_body(&self, buf: &mut BytesMut) {
+        buf.put_i64(self.timestamp);
+        buf.put_u32(self.xmin);
+        buf.put_u32(self.xmin_epoch);
+        buf.put_u32(self.catalog_xmin);
+        buf.put_u32(self.catalog_xmin_epoch);
+    }
+
+    fn decode_body(buf: &mut Bytes) -> PgWireResult<Self> {
+        check_remaining(buf, 24)?;
+        let timestamp = buf.get_i64();
+        let xmin = buf.get_u32();
+        let xmin_epoch = buf.get_u32();
+        let catalog_xmin = buf.get_u32();
+        let catalog_xmin_epoch = buf.get_u32();
+        Ok(HotStandbyFeedback::new(
+            timestamp,
+            xmin,
+            xmin_epoch,
+            catalog_xmin,
+            catalog_xmin_epoch,
+        ))
+    }
+}
+
+/// Replication messages sent from backend
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum ReplicationBackendMessage {
+    XLogData(XLogData),
+    PrimaryKeepalive(PrimaryKeepalive),
+}
+
+impl ReplicationBackendMessage {
+    pub fn encode(&self, buf: &mut BytesMut) {
+        match self {
+            Self::XLogData(msg) => {
+                buf.put_u8(MESSAGE_TYPE_BYTE_XLOG_DATA);
+                msg.encode_body(buf);
+            }
+            Self::PrimaryKeepalive(msg) => {
+                buf.put_u8(MESSAGE_TYPE_BYTE_PRIMARY_KEEPALIVE);
+                msg.encode_body(buf);
+            }
+        }
+    }
+
+    /// Decode from data of a `CopyData` message.
+    pub fn decode(mut buf: Bytes) -> PgWireResult<Self> {
+        check_remaining(&buf, 1)?;
+        match buf.get_u8() {
+            MESSAGE_TYPE_BYTE_XLOG_DATA => XLogData::decode_body(&mut buf).map(Self::XLogData),
+            MESSAGE_TYPE_BYTE_PRIMARY_KEEPALIVE => {
+                PrimaryKeepalive::decode_body(&mut buf).map(Self::PrimaryKeepalive)
+            }
+            _ => Err(PgWireError::InvalidReplicationMessage),
+        }
+    }
+
+    pub fn into_copy_data(self) -> CopyData {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf);
+        CopyData::new(buf.freeze())
+    }
+}
+
+/// Replication messages sent from frontend
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum ReplicationFrontendMessage {
+    StandbyStatusUpdate(StandbyStatusUpdate),
+    HotStandbyFeedback(HotStandbyFeedback),
+}
+
+impl ReplicationFrontendMessage {
+    pub fn encode(&self, buf: &mut BytesMut) {
+        match self {
+            Self::StandbyStatusUpdate(msg) => {
+                buf.put_u8(MESSAGE_TYPE_BYTE_STANDBY_STATUS_UPDATE);
+                msg.encode_body(buf);
+            }
+            Self::HotStandbyFeedback(msg) => {
+                buf.put_u8(MESSAGE_TYPE_BYTE_HOT_STANDBY_FEEDBACK);
+                msg.encode_body(buf);
+            }
+        }
+    }
+
+    /// Decode from data of a `CopyData` message.
+    pub fn decode(mut buf: Bytes) -> PgWireResult<Self> {
+        check_remaining(&buf, 1)?;
+        match buf.get_u8() {
+            MESSAGE_TYPE_BYTE_STANDBY_STATUS_UPDATE => {
+                StandbyStatusUpdate::decode_body(&mut buf).map(Self::StandbyStatusUpdate)
+            }
+            MESSAGE_TYPE_BYTE_HOT_STANDBY_FEEDBACK => {
+                HotStandbyFeedback::decode_body(&mut buf).map(Self::HotStandbyFeedback)
+            }
+            _ => Err(PgWireError::InvalidReplicationMessage),
+        }
+    }
+
+    pub fn into_copy_data(self) -> CopyData {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf);
+        CopyData::new(buf.freeze())
+    }
+}
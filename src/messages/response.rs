@@ -1,7 +1,7 @@
 use bytes::{Buf, BufMut, BytesMut};
 
 use super::codec;
-use super::Message;
+use super::{Message, PgWireBackendMessage};
 use crate::error::{PgWireError, PgWireResult};
 
 #[non_exhaustive]
@@ -19,7 +19,7 @@ impl Message for CommandComplete {
     }
 
     fn message_length(&self) -> usize {
-        5 + self.tag.as_bytes().len()
+        5 + self.tag.len()
     }
 
     fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
@@ -29,7 +29,7 @@ impl Message for CommandComplete {
     }
 
     fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
-        let tag = codec::get_cstring(buf).unwrap_or_else(|| "".to_owned());
+        let tag = codec::get_cstring(buf)?.unwrap_or_else(|| "".to_owned());
 
         Ok(CommandComplete::new(tag))
     }
@@ -65,9 +65,10 @@ pub struct ReadyForQuery {
     pub status: TransactionStatus,
 }
 
-#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
 #[repr(u8)]
 pub enum TransactionStatus {
+    #[default]
     Idle = READY_STATUS_IDLE,
     Transaction = READY_STATUS_TRANSACTION_BLOCK,
     Error = READY_STATUS_FAILED_TRANSACTION_BLOCK,
@@ -97,7 +98,7 @@ impl Message for ReadyForQuery {
     }
 
     fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
-        let status = TransactionStatus::try_from(buf.get_u8())?;
+        let status = TransactionStatus::try_from(codec::get_u8(buf)?)?;
         Ok(ReadyForQuery::new(status))
     }
 }
@@ -114,6 +115,32 @@ impl TryFrom<u8> for TransactionStatus {
     }
 }
 
+impl TransactionStatus {
+    /// Status after `message` is sent to client. A transaction is started by
+    /// `BEGIN` or `START TRANSACTION`, fails with an `ERROR` and ends with
+    /// `COMMIT`, `ROLLBACK` or `PREPARE TRANSACTION`, according to the tag
+    /// of `CommandComplete`.
+    ///
+    /// `ROLLBACK TO SAVEPOINT` has the same tag as `ROLLBACK`, handlers
+    /// supporting savepoints should set the status themselves.
+    pub fn after_sent(self, message: &PgWireBackendMessage) -> TransactionStatus {
+        match message {
+            PgWireBackendMessage::CommandComplete(complete) => match complete.tag.as_str() {
+                "BEGIN" | "START TRANSACTION" => TransactionStatus::Transaction,
+                "COMMIT" | "ROLLBACK" | "PREPARE TRANSACTION" => TransactionStatus::Idle,
+                _ => self,
+            },
+            PgWireBackendMessage::ErrorResponse(error)
+                if self == TransactionStatus::Transaction
+                    && error.fields.contains(&(b'S', "ERROR".to_owned())) =>
+            {
+                TransactionStatus::Error
+            }
+            _ => self,
+        }
+    }
+}
+
 /// postgres error response, sent from backend to frontend
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, Default, new)]
@@ -130,12 +157,7 @@ impl Message for ErrorResponse {
     }
 
     fn message_length(&self) -> usize {
-        4 + self
-            .fields
-            .iter()
-            .map(|f| 1 + f.1.as_bytes().len() + 1)
-            .sum::<usize>()
-            + 1
+        4 + self.fields.iter().map(|f| 1 + f.1.len() + 1).sum::<usize>() + 1
     }
 
     fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
@@ -152,12 +174,12 @@ impl Message for ErrorResponse {
     fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
         let mut fields = Vec::new();
         loop {
-            let code = buf.get_u8();
+            let code = codec::get_u8(buf)?;
 
             if code == b'\0' {
                 return Ok(ErrorResponse { fields });
             } else {
-                let value = codec::get_cstring(buf).unwrap_or_else(|| "".to_owned());
+                let value = codec::get_cstring(buf)?.unwrap_or_else(|| "".to_owned());
                 fields.push((code, value));
             }
         }
@@ -180,12 +202,7 @@ impl Message for NoticeResponse {
     }
 
     fn message_length(&self) -> usize {
-        4 + self
-            .fields
-            .iter()
-            .map(|f| 1 + f.1.as_bytes().len() + 1)
-            .sum::<usize>()
-            + 1
+        4 + self.fields.iter().map(|f| 1 + f.1.len() + 1).sum::<usize>() + 1
     }
 
     fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
@@ -202,12 +219,12 @@ impl Message for NoticeResponse {
     fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
         let mut fields = Vec::new();
         loop {
-            let code = buf.get_u8();
+            let code = codec::get_u8(buf)?;
 
             if code == b'\0' {
                 return Ok(NoticeResponse { fields });
             } else {
-                let value = codec::get_cstring(buf).unwrap_or_else(|| "".to_owned());
+                let value = codec::get_cstring(buf)?.unwrap_or_else(|| "".to_owned());
                 fields.push((code, value));
             }
         }
@@ -291,7 +308,7 @@ impl Message for NotificationResponse {
     }
 
     fn message_length(&self) -> usize {
-        8 + self.channel.as_bytes().len() + 1 + self.payload.as_bytes().len() + 1
+        8 + self.channel.len() + 1 + self.payload.len() + 1
     }
 
     fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
@@ -303,9 +320,9 @@ impl Message for NotificationResponse {
     }
 
     fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
-        let pid = buf.get_i32();
-        let channel = codec::get_cstring(buf).unwrap_or_else(|| "".to_owned());
-        let payload = codec::get_cstring(buf).unwrap_or_else(|| "".to_owned());
+        let pid = codec::get_i32(buf)?;
+        let channel = codec::get_cstring(buf)?.unwrap_or_else(|| "".to_owned());
+        let payload = codec::get_cstring(buf)?.unwrap_or_else(|| "".to_owned());
 
         Ok(NotificationResponse {
             pid,
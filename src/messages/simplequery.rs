@@ -20,7 +20,7 @@ impl Message for Query {
     }
 
     fn message_length(&self) -> usize {
-        5 + self.query.as_bytes().len()
+        5 + self.query.len()
     }
 
     fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
@@ -30,7 +30,7 @@ impl Message for Query {
     }
 
     fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
-        let query = codec::get_cstring(buf).unwrap_or_else(|| "".to_owned());
+        let query = codec::get_cstring(buf)?.unwrap_or_else(|| "".to_owned());
 
         Ok(Query::new(query))
     }
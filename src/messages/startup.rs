@@ -31,8 +31,20 @@ impl Default for Startup {
 impl Startup {
     const MINIMUM_STARTUP_MESSAGE_LEN: usize = 8;
 
+    /// Prefix of protocol options in startup parameters.
+    pub const PROTOCOL_OPTION_PREFIX: &'static str = "_pq_.";
+
+    // any minor version of 3.x, newer minor versions are negotiated down
     fn is_protocol_version_supported(version: i32) -> bool {
-        version == 196608
+        version >> 16 == 3
+    }
+
+    /// Names of protocol options, the `_pq_.` parameters, requested by client.
+    pub fn protocol_options(&self) -> impl Iterator<Item = &str> {
+        self.parameters
+            .keys()
+            .filter(|k| k.starts_with(Self::PROTOCOL_OPTION_PREFIX))
+            .map(String::as_str)
     }
 }
 
@@ -41,7 +53,7 @@ impl Message for Startup {
         let param_length = self
             .parameters
             .iter()
-            .map(|(k, v)| k.as_bytes().len() + v.as_bytes().len() + 2)
+            .map(|(k, v)| k.len() + v.len() + 2)
             .sum::<usize>();
         // length:4 + protocol_number:4 + param.len + nullbyte:1
         9 + param_length
@@ -86,13 +98,13 @@ impl Message for Startup {
         }
 
         // parse
-        let protocol_number_major = buf.get_u16();
-        let protocol_number_minor = buf.get_u16();
+        let protocol_number_major = codec::get_u16(buf)?;
+        let protocol_number_minor = codec::get_u16(buf)?;
 
         // end by reading the last \0
         let mut parameters = BTreeMap::new();
-        while let Some(key) = codec::get_cstring(buf) {
-            let value = codec::get_cstring(buf).unwrap_or_else(|| "".to_owned());
+        while let Some(key) = codec::get_cstring(buf)? {
+            let value = codec::get_cstring(buf)?.unwrap_or_else(|| "".to_owned());
             parameters.insert(key, value);
         }
 
@@ -113,16 +125,15 @@ pub enum Authentication {
     KerberosV5,           // code 2
     MD5Password(Vec<u8>), // code 5, with 4 bytes of md5 salt
 
+    GSS,                 // code 7
+    GSSContinue(Bytes),  // code 8, with GSSAPI or SSPI authentication data
+    SSPI,                // code 9
     SASL(Vec<String>),   // code 10, with server supported sasl mechanisms
     SASLContinue(Bytes), // code 11, with authentication data
     SASLFinal(Bytes),    // code 12, with additional authentication data
 
                          // TODO: more types
                          // AuthenticationSCMCredential
-                         //
-                         // AuthenticationGSS
-                         // AuthenticationGSSContinue
-                         // AuthenticationSSPI
 }
 
 pub const MESSAGE_TYPE_BYTE_AUTHENTICATION: u8 = b'R';
@@ -136,10 +147,13 @@ impl Message for Authentication {
     #[inline]
     fn message_length(&self) -> usize {
         match self {
-            Authentication::Ok | Authentication::CleartextPassword | Authentication::KerberosV5 => {
-                8
-            }
+            Authentication::Ok
+            | Authentication::CleartextPassword
+            | Authentication::KerberosV5
+            | Authentication::GSS
+            | Authentication::SSPI => 8,
             Authentication::MD5Password(_) => 12,
+            Authentication::GSSContinue(data) => 8 + data.len(),
             Authentication::SASL(methods) => {
                 8 + methods.iter().map(|v| v.len() + 1).sum::<usize>() + 1
             }
@@ -157,6 +171,12 @@ impl Message for Authentication {
                 buf.put_i32(5);
                 buf.put_slice(salt.as_ref());
             }
+            Authentication::GSS => buf.put_i32(7),
+            Authentication::GSSContinue(data) => {
+                buf.put_i32(8);
+                buf.put_slice(data.as_ref());
+            }
+            Authentication::SSPI => buf.put_i32(9),
             Authentication::SASL(methods) => {
                 buf.put_i32(10);
                 for method in methods {
@@ -176,27 +196,26 @@ impl Message for Authentication {
         Ok(())
     }
 
-    fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
-        let code = buf.get_i32();
+    fn decode_body(buf: &mut BytesMut, _msg_len: usize) -> PgWireResult<Self> {
+        let code = codec::get_i32(buf)?;
         let msg = match code {
             0 => Authentication::Ok,
             2 => Authentication::KerberosV5,
             3 => Authentication::CleartextPassword,
-            5 => {
-                let mut salt_vec = vec![0; 4];
-                buf.copy_to_slice(&mut salt_vec);
-                Authentication::MD5Password(salt_vec)
-            }
+            5 => Authentication::MD5Password(codec::get_bytes(buf, 4)?.to_vec()),
+            7 => Authentication::GSS,
+            8 => Authentication::GSSContinue(buf.split().freeze()),
+            9 => Authentication::SSPI,
             10 => {
                 let mut methods = Vec::new();
-                while let Some(method) = codec::get_cstring(buf) {
+                while let Some(method) = codec::get_cstring(buf)? {
                     methods.push(method);
                 }
                 Authentication::SASL(methods)
             }
             11 => Authentication::SASLContinue(buf.split().freeze()),
             12 => Authentication::SASLFinal(buf.split().freeze()),
-            _ => unreachable!(),
+            _ => return Err(PgWireError::InvalidAuthenticationType(code)),
         };
 
         Ok(msg)
@@ -230,6 +249,8 @@ pub enum PasswordMessageFamily {
     SASLInitialResponse(SASLInitialResponse),
     /// SASLResponse
     SASLResponse(SASLResponse),
+    /// GSSResponse
+    GSSResponse(GSSResponse),
 }
 
 impl Message for PasswordMessageFamily {
@@ -243,6 +264,7 @@ impl Message for PasswordMessageFamily {
             PasswordMessageFamily::Password(inner) => inner.message_length(),
             PasswordMessageFamily::SASLInitialResponse(inner) => inner.message_length(),
             PasswordMessageFamily::SASLResponse(inner) => inner.message_length(),
+            PasswordMessageFamily::GSSResponse(inner) => inner.message_length(),
         }
     }
 
@@ -255,11 +277,12 @@ impl Message for PasswordMessageFamily {
             PasswordMessageFamily::Password(inner) => inner.encode_body(buf),
             PasswordMessageFamily::SASLInitialResponse(inner) => inner.encode_body(buf),
             PasswordMessageFamily::SASLResponse(inner) => inner.encode_body(buf),
+            PasswordMessageFamily::GSSResponse(inner) => inner.encode_body(buf),
         }
     }
 
-    fn decode_body(buf: &mut BytesMut, full_len: usize) -> PgWireResult<Self> {
-        let body = buf.split_to(full_len - 4);
+    fn decode_body(buf: &mut BytesMut, _full_len: usize) -> PgWireResult<Self> {
+        let body = buf.split();
         Ok(PasswordMessageFamily::Raw(body))
     }
 }
@@ -271,9 +294,9 @@ impl PasswordMessageFamily {
     ///
     /// Panic when the message is already coerced into concrete type.
     pub fn into_password(self) -> PgWireResult<Password> {
-        if let PasswordMessageFamily::Raw(mut body) = self {
+        if let PasswordMessageFamily::Raw(body) = self {
             let len = body.len() + 4;
-            Password::decode_body(&mut body, len)
+            codec::decode_body_exact(body, len, Password::decode_body)
         } else {
             unreachable!(
                 "Do not coerce password message when it has a concrete type {:?}",
@@ -288,9 +311,9 @@ impl PasswordMessageFamily {
     ///
     /// Panic when the message is already coerced into concrete type.
     pub fn into_sasl_initial_response(self) -> PgWireResult<SASLInitialResponse> {
-        if let PasswordMessageFamily::Raw(mut body) = self {
+        if let PasswordMessageFamily::Raw(body) = self {
             let len = body.len() + 4;
-            SASLInitialResponse::decode_body(&mut body, len)
+            codec::decode_body_exact(body, len, SASLInitialResponse::decode_body)
         } else {
             unreachable!(
                 "Do not coerce password message when it has a concrete type {:?}",
@@ -305,9 +328,26 @@ impl PasswordMessageFamily {
     ///
     /// Panic when the message is already coerced into concrete type.
     pub fn into_sasl_response(self) -> PgWireResult<SASLResponse> {
-        if let PasswordMessageFamily::Raw(mut body) = self {
+        if let PasswordMessageFamily::Raw(body) = self {
             let len = body.len() + 4;
-            SASLResponse::decode_body(&mut body, len)
+            codec::decode_body_exact(body, len, SASLResponse::decode_body)
+        } else {
+            unreachable!(
+                "Do not coerce password message when it has a concrete type {:?}",
+                self
+            )
+        }
+    }
+
+    /// Coerce the raw message into `GSSResponse`
+    ///
+    /// # Panics
+    ///
+    /// Panic when the message is already coerced into concrete type.
+    pub fn into_gss_response(self) -> PgWireResult<GSSResponse> {
+        if let PasswordMessageFamily::Raw(body) = self {
+            let len = body.len() + 4;
+            codec::decode_body_exact(body, len, GSSResponse::decode_body)
         } else {
             unreachable!(
                 "Do not coerce password message when it has a concrete type {:?}",
@@ -331,7 +371,7 @@ impl Message for Password {
     }
 
     fn message_length(&self) -> usize {
-        5 + self.password.as_bytes().len()
+        5 + self.password.len()
     }
 
     fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
@@ -341,7 +381,7 @@ impl Message for Password {
     }
 
     fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
-        let pass = codec::get_cstring(buf).unwrap_or_else(|| "".to_owned());
+        let pass = codec::get_cstring(buf)?.unwrap_or_else(|| "".to_owned());
 
         Ok(Password::new(pass))
     }
@@ -364,7 +404,7 @@ impl Message for ParameterStatus {
     }
 
     fn message_length(&self) -> usize {
-        4 + 2 + self.name.as_bytes().len() + self.value.as_bytes().len()
+        4 + 2 + self.name.len() + self.value.len()
     }
 
     fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
@@ -375,8 +415,8 @@ impl Message for ParameterStatus {
     }
 
     fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
-        let name = codec::get_cstring(buf).unwrap_or_else(|| "".to_owned());
-        let value = codec::get_cstring(buf).unwrap_or_else(|| "".to_owned());
+        let name = codec::get_cstring(buf)?.unwrap_or_else(|| "".to_owned());
+        let value = codec::get_cstring(buf)?.unwrap_or_else(|| "".to_owned());
 
         Ok(ParameterStatus::new(name, value))
     }
@@ -412,13 +452,64 @@ impl Message for BackendKeyData {
     }
 
     fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
-        let pid = buf.get_i32();
-        let secret_key = buf.get_i32();
+        let pid = codec::get_i32(buf)?;
+        let secret_key = codec::get_i32(buf)?;
 
         Ok(BackendKeyData { pid, secret_key })
     }
 }
 
+/// `NegotiateProtocolVersion`, sent from backend when client asks for a newer
+/// minor protocol version, or protocol options the backend doesn't support.
+#[non_exhaustive]
+#[derive(PartialEq, Eq, Debug, new)]
+pub struct NegotiateProtocolVersion {
+    pub newest_minor_version: i32,
+    pub unsupported_options: Vec<String>,
+}
+
+pub const MESSAGE_TYPE_BYTE_NEGOTIATE_PROTOCOL_VERSION: u8 = b'v';
+
+impl Message for NegotiateProtocolVersion {
+    #[inline]
+    fn message_type() -> Option<u8> {
+        Some(MESSAGE_TYPE_BYTE_NEGOTIATE_PROTOCOL_VERSION)
+    }
+
+    fn message_length(&self) -> usize {
+        12 + self
+            .unsupported_options
+            .iter()
+            .map(|option| option.len() + 1)
+            .sum::<usize>()
+    }
+
+    fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
+        buf.put_i32(self.newest_minor_version);
+        buf.put_i32(self.unsupported_options.len() as i32);
+        for option in &self.unsupported_options {
+            codec::put_cstring(buf, option);
+        }
+
+        Ok(())
+    }
+
+    fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
+        let newest_minor_version = codec::get_i32(buf)?;
+        let count = codec::get_i32(buf)?;
+        let mut unsupported_options =
+            Vec::with_capacity((count.max(0) as usize).min(buf.remaining()));
+        for _ in 0..count {
+            unsupported_options.push(codec::get_cstring(buf)?.unwrap_or_else(|| "".to_owned()));
+        }
+
+        Ok(NegotiateProtocolVersion {
+            newest_minor_version,
+            unsupported_options,
+        })
+    }
+}
+
 /// `Sslrequest` sent from frontend to negotiate with backend to check if the
 /// backend supports secure connection. The packet has no message type and
 /// contains only a length(4) and an i32 value.
@@ -466,6 +557,106 @@ impl Message for SslRequest {
     }
 }
 
+/// `GssEncRequest` sent from frontend to negotiate GSSAPI encryption of the
+/// connection. Like `SslRequest`, the packet has no message type and contains
+/// only a length(4) and an i32 value.
+///
+/// The backend answers with a single byte 'G' to start GSSAPI encryption, or
+/// 'N' after which the frontend may go on with `SslRequest` or `Startup` on
+/// the same connection.
+#[non_exhaustive]
+#[derive(PartialEq, Eq, Debug, new)]
+pub struct GssEncRequest;
+
+impl GssEncRequest {
+    pub const BODY_MAGIC_NUMBER: i32 = 80877104;
+    pub const BODY_SIZE: usize = 8;
+}
+
+impl Message for GssEncRequest {
+    #[inline]
+    fn message_type() -> Option<u8> {
+        None
+    }
+
+    #[inline]
+    fn message_length(&self) -> usize {
+        Self::BODY_SIZE
+    }
+
+    fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
+        buf.put_i32(Self::BODY_MAGIC_NUMBER);
+        Ok(())
+    }
+
+    fn decode_body(_buf: &mut BytesMut, _full_len: usize) -> PgWireResult<Self> {
+        unreachable!();
+    }
+
+    /// Try to decode and check if the packet is a `GssEncRequest`.
+    fn decode(buf: &mut BytesMut) -> PgWireResult<Option<Self>> {
+        if buf.remaining() >= 8 && (&buf[4..8]).get_i32() == Self::BODY_MAGIC_NUMBER {
+            buf.advance(8);
+            Ok(Some(GssEncRequest))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// `CancelRequest` sent from frontend on a new connection to cancel the query
+/// running in another session, identified by the `BackendKeyData` of that
+/// session. Like `SslRequest`, the packet has no message type.
+#[non_exhaustive]
+#[derive(PartialEq, Eq, Debug, new)]
+pub struct CancelRequest {
+    pub pid: i32,
+    pub secret_key: i32,
+}
+
+impl CancelRequest {
+    pub const BODY_MAGIC_NUMBER: i32 = 80877102;
+    pub const BODY_SIZE: usize = 16;
+}
+
+impl Message for CancelRequest {
+    #[inline]
+    fn message_type() -> Option<u8> {
+        None
+    }
+
+    #[inline]
+    fn message_length(&self) -> usize {
+        Self::BODY_SIZE
+    }
+
+    fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
+        buf.put_i32(Self::BODY_MAGIC_NUMBER);
+        buf.put_i32(self.pid);
+        buf.put_i32(self.secret_key);
+        Ok(())
+    }
+
+    fn decode_body(buf: &mut BytesMut, full_len: usize) -> PgWireResult<Self> {
+        if full_len != Self::BODY_SIZE {
+            return Err(PgWireError::InvalidStartupMessage);
+        }
+        buf.advance(4);
+        let pid = codec::get_i32(buf)?;
+        let secret_key = codec::get_i32(buf)?;
+        Ok(CancelRequest { pid, secret_key })
+    }
+
+    /// Try to decode and check if the packet is a `CancelRequest`.
+    fn decode(buf: &mut BytesMut) -> PgWireResult<Option<Self>> {
+        if buf.remaining() >= 8 && (&buf[4..8]).get_i32() == Self::BODY_MAGIC_NUMBER {
+            codec::decode_packet(buf, 0, Self::decode_body)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
 pub struct SASLInitialResponse {
@@ -481,10 +672,7 @@ impl Message for SASLInitialResponse {
 
     #[inline]
     fn message_length(&self) -> usize {
-        4 + self.auth_method.as_bytes().len()
-            + 1
-            + 4
-            + self.data.as_ref().map(|b| b.len()).unwrap_or(0)
+        4 + self.auth_method.len() + 1 + 4 + self.data.as_ref().map(|b| b.len()).unwrap_or(0)
     }
 
     fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
@@ -499,12 +687,12 @@ impl Message for SASLInitialResponse {
     }
 
     fn decode_body(buf: &mut BytesMut, _full_len: usize) -> PgWireResult<Self> {
-        let auth_method = codec::get_cstring(buf).unwrap_or_else(|| "".to_owned());
-        let data_len = buf.get_i32();
+        let auth_method = codec::get_cstring(buf)?.unwrap_or_else(|| "".to_owned());
+        let data_len = codec::get_i32(buf)?;
         let data = if data_len == -1 {
             None
         } else {
-            Some(buf.split_to(data_len as usize).freeze())
+            Some(codec::get_bytes(buf, data_len as usize)?.freeze())
         };
 
         Ok(SASLInitialResponse { auth_method, data })
@@ -533,8 +721,37 @@ impl Message for SASLResponse {
         Ok(())
     }
 
-    fn decode_body(buf: &mut BytesMut, full_len: usize) -> PgWireResult<Self> {
-        let data = buf.split_to(full_len - 4).freeze();
+    fn decode_body(buf: &mut BytesMut, _full_len: usize) -> PgWireResult<Self> {
+        let data = buf.split().freeze();
         Ok(SASLResponse { data })
     }
 }
+
+/// GSSAPI or SSPI authentication data, sent by frontend
+#[non_exhaustive]
+#[derive(PartialEq, Eq, Debug, new)]
+pub struct GSSResponse {
+    pub data: Bytes,
+}
+
+impl Message for GSSResponse {
+    #[inline]
+    fn message_type() -> Option<u8> {
+        Some(MESSAGE_TYPE_BYTE_PASWORD_MESSAGE_FAMILY)
+    }
+
+    #[inline]
+    fn message_length(&self) -> usize {
+        4 + self.data.len()
+    }
+
+    fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
+        buf.put_slice(self.data.as_ref());
+        Ok(())
+    }
+
+    fn decode_body(buf: &mut BytesMut, _full_len: usize) -> PgWireResult<Self> {
+        let data = buf.split().freeze();
+        Ok(GSSResponse { data })
+    }
+}
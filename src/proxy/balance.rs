@@ -0,0 +1,616 @@
+use std::io::{Error as IOError, ErrorKind};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use rand::Rng;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+
+use crate::error::PgWireResult;
+use crate::messages::startup::SslRequest;
+use crate::messages::Message;
+
+/// An upstream postgres server.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, new)]
+pub struct Endpoint {
+    /// address in `host:port` form
+    pub addr: String,
+    /// relative weight in selection, endpoints with weight 0 are only used
+    /// when no other endpoint is healthy
+    #[new(value = "1")]
+    pub weight: u32,
+}
+
+impl Endpoint {
+    pub fn with_weight(mut self, weight: u32) -> Endpoint {
+        self.weight = weight;
+        self
+    }
+}
+
+/// Probe an upstream endpoint, returns error if the endpoint is not usable.
+#[async_trait]
+pub trait HealthCheck: Send + Sync {
+    async fn check(&self, endpoint: &Endpoint) -> PgWireResult<()>;
+}
+
+/// Health check that connects to the endpoint and sends a `SSLRequest`.
+///
+/// A postgres server answers it with a single byte before any
+/// authentication, so this verifies the server is accepting connections
+/// without requiring credentials. It doesn't verify the server accepts
+/// startup or serves queries, for example during crash recovery, use
+/// `QueryHealthCheck` for that.
+#[derive(Debug, Clone, new)]
+pub struct TcpHealthCheck {
+    timeout: Duration,
+}
+
+impl Default for TcpHealthCheck {
+    fn default() -> Self {
+        TcpHealthCheck::new(Duration::from_secs(3))
+    }
+}
+
+impl TcpHealthCheck {
+    async fn probe(&self, endpoint: &Endpoint) -> PgWireResult<()> {
+        let mut socket = TcpStream::connect(&endpoint.addr).await?;
+
+        let mut buf = BytesMut::with_capacity(SslRequest::BODY_SIZE);
+        SslRequest.encode(&mut buf)?;
+        socket.write_all(&buf).await?;
+
+        match socket.read_u8().await? {
+            b'S' | b'N' => Ok(()),
+            _ => Err(
+                IOError::new(ErrorKind::InvalidData, "unexpected response to SSLRequest").into(),
+            ),
+        }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for TcpHealthCheck {
+    async fn check(&self, endpoint: &Endpoint) -> PgWireResult<()> {
+        tokio::time::timeout(self.timeout, self.probe(endpoint))
+            .await
+            .map_err(|_| IOError::new(ErrorKind::TimedOut, "health check timed out"))?
+    }
+}
+
+#[cfg(feature = "client-api")]
+pub use query_check::QueryHealthCheck;
+
+#[cfg(feature = "client-api")]
+mod query_check {
+    use std::io::{Error as IOError, ErrorKind};
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::TcpStream;
+    use tokio_util::codec::Framed;
+
+    use super::{Endpoint, HealthCheck};
+    use crate::api::auth::passthrough::login;
+    use crate::client::PgWireMessageClientCodec;
+    use crate::error::{PgWireError, PgWireResult};
+    use crate::messages::simplequery::Query;
+    use crate::messages::startup::Startup;
+    use crate::messages::terminate::Terminate;
+    use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+
+    /// Health check that logs in to the endpoint like a client and runs a query,
+    /// `SELECT 1` by default.
+    ///
+    /// Cleartext, md5 and, with the `scram` feature, SCRAM-SHA-256
+    /// authentication are supported. TLS is not used.
+    #[derive(Debug, Clone)]
+    pub struct QueryHealthCheck {
+        user: String,
+        password: String,
+        database: Option<String>,
+        query: String,
+        timeout: Duration,
+    }
+
+    impl QueryHealthCheck {
+        pub fn new(user: &str) -> QueryHealthCheck {
+            QueryHealthCheck {
+                user: user.to_owned(),
+                password: String::new(),
+                database: None,
+                query: "SELECT 1".to_owned(),
+                timeout: Duration::from_secs(3),
+            }
+        }
+
+        pub fn with_password(mut self, password: &str) -> QueryHealthCheck {
+            self.password = password.to_owned();
+            self
+        }
+
+        pub fn with_database(mut self, database: &str) -> QueryHealthCheck {
+            self.database = Some(database.to_owned());
+            self
+        }
+
+        pub fn with_query(mut self, query: &str) -> QueryHealthCheck {
+            self.query = query.to_owned();
+            self
+        }
+
+        pub fn with_timeout(mut self, timeout: Duration) -> QueryHealthCheck {
+            self.timeout = timeout;
+            self
+        }
+
+        async fn probe(&self, endpoint: &Endpoint) -> PgWireResult<()> {
+            let socket = TcpStream::connect(&endpoint.addr).await?;
+            let mut upstream = Framed::new(socket, PgWireMessageClientCodec::new());
+
+            let mut startup = Startup::new();
+            startup
+                .parameters
+                .insert("user".to_owned(), self.user.clone());
+            if let Some(database) = &self.database {
+                startup
+                    .parameters
+                    .insert("database".to_owned(), database.clone());
+            }
+            upstream
+                .send(PgWireFrontendMessage::Startup(startup))
+                .await?;
+            if let Some(error) = login(&mut upstream, &self.user, &self.password).await? {
+                return Err(PgWireError::UserError(Box::new(error.into())));
+            }
+            Self::ready_for_query(&mut upstream).await?;
+
+            upstream
+                .send(PgWireFrontendMessage::Query(Query::new(self.query.clone())))
+                .await?;
+            Self::ready_for_query(&mut upstream).await?;
+            upstream
+                .send(PgWireFrontendMessage::Terminate(Terminate::new()))
+                .await?;
+            Ok(())
+        }
+
+        /// Read messages until `ReadyForQuery`, fails on `ErrorResponse`.
+        async fn ready_for_query(
+            upstream: &mut Framed<TcpStream, PgWireMessageClientCodec>,
+        ) -> PgWireResult<()> {
+            let mut error = None;
+            loop {
+                match upstream.next().await {
+                    Some(Ok(PgWireBackendMessage::ErrorResponse(response))) => {
+                        error.get_or_insert(response);
+                    }
+                    Some(Ok(PgWireBackendMessage::ReadyForQuery(_))) => break,
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => return Err(e),
+                    None => {
+                        return Err(IOError::new(
+                            ErrorKind::UnexpectedEof,
+                            "connection closed by endpoint",
+                        )
+                        .into())
+                    }
+                }
+            }
+            match error {
+                Some(error) => Err(PgWireError::UserError(Box::new(error.into()))),
+                None => Ok(()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl HealthCheck for QueryHealthCheck {
+        async fn check(&self, endpoint: &Endpoint) -> PgWireResult<()> {
+            tokio::time::timeout(self.timeout, self.probe(endpoint))
+                .await
+                .map_err(|_| IOError::new(ErrorKind::TimedOut, "health check timed out"))?
+        }
+    }
+}
+
+/// How an endpoint is chosen from healthy ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BalanceStrategy {
+    /// rotate through endpoints, each endpoint gets `weight` turns in a row
+    #[default]
+    RoundRobin,
+    /// random choice with probability proportional to `weight` divided by
+    /// the latency measured by health checks
+    LatencyWeighted,
+}
+
+/// Options of `LoadBalancer`.
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub struct LoadBalancerConfig {
+    pub strategy: BalanceStrategy,
+    /// interval between health checks of all endpoints
+    pub check_interval: Duration,
+    /// consecutive failures, of checks or reported by caller, to eject an
+    /// endpoint
+    pub unhealthy_threshold: u32,
+    /// consecutive successful checks to bring an ejected endpoint back
+    pub healthy_threshold: u32,
+}
+
+impl Default for LoadBalancerConfig {
+    fn default() -> Self {
+        LoadBalancerConfig {
+            strategy: BalanceStrategy::default(),
+            check_interval: Duration::from_secs(5),
+            unhealthy_threshold: 3,
+            healthy_threshold: 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct EndpointState {
+    endpoint: Endpoint,
+    healthy: AtomicBool,
+    failures: AtomicU32,
+    successes: AtomicU32,
+    // moving average of check latency, 0 if not measured yet
+    latency_micros: AtomicU64,
+    active: AtomicUsize,
+}
+
+/// Spread sessions or transactions across upstream endpoints.
+///
+/// Endpoints start healthy. They are ejected after `unhealthy_threshold`
+/// consecutive failures and brought back after `healthy_threshold`
+/// consecutive successful health checks. Use `spawn_health_checks` to run
+/// checks in background periodically.
+#[derive(Debug)]
+pub struct LoadBalancer {
+    endpoints: Vec<EndpointState>,
+    config: LoadBalancerConfig,
+    counter: AtomicUsize,
+}
+
+/// An endpoint selected by `LoadBalancer`. It's counted as active until
+/// dropped.
+#[derive(Debug)]
+pub struct SelectedEndpoint {
+    balancer: Arc<LoadBalancer>,
+    index: usize,
+}
+
+impl SelectedEndpoint {
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.balancer.endpoints[self.index].endpoint
+    }
+
+    /// Report a failure, like failing to connect, of this endpoint.
+    pub fn report_failure(&self) {
+        self.balancer.record_failure(self.index);
+    }
+}
+
+impl Drop for SelectedEndpoint {
+    fn drop(&mut self) {
+        self.balancer.endpoints[self.index]
+            .active
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl LoadBalancer {
+    pub fn new(endpoints: Vec<Endpoint>, config: LoadBalancerConfig) -> LoadBalancer {
+        LoadBalancer {
+            endpoints: endpoints
+                .into_iter()
+                .map(|endpoint| EndpointState {
+                    endpoint,
+                    healthy: AtomicBool::new(true),
+                    failures: AtomicU32::new(0),
+                    successes: AtomicU32::new(0),
+                    latency_micros: AtomicU64::new(0),
+                    active: AtomicUsize::new(0),
+                })
+                .collect(),
+            config,
+            counter: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn endpoints(&self) -> impl Iterator<Item = &Endpoint> {
+        self.endpoints.iter().map(|s| &s.endpoint)
+    }
+
+    /// Whether the endpoint at `index` is healthy, `None` if there is no
+    /// such endpoint.
+    pub fn is_healthy(&self, index: usize) -> Option<bool> {
+        let state = self.endpoints.get(index)?;
+        Some(state.healthy.load(Ordering::Relaxed))
+    }
+
+    /// Number of selections of the endpoint that are not dropped yet, `None`
+    /// if there is no such endpoint.
+    pub fn active(&self, index: usize) -> Option<usize> {
+        let state = self.endpoints.get(index)?;
+        Some(state.active.load(Ordering::Relaxed))
+    }
+
+    /// Latency of the endpoint measured by health checks, `None` if there is
+    /// no such endpoint or it's not measured yet.
+    pub fn latency(&self, index: usize) -> Option<Duration> {
+        match self
+            .endpoints
+            .get(index)?
+            .latency_micros
+            .load(Ordering::Relaxed)
+        {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    /// Choose an endpoint. Returns `None` when every endpoint is ejected.
+    pub fn select(self: &Arc<Self>) -> Option<SelectedEndpoint> {
+        let healthy = (0..self.endpoints.len())
+            .filter(|i| self.endpoints[*i].healthy.load(Ordering::Relaxed))
+            .collect::<Vec<_>>();
+        let mut weights = healthy.iter().map(|i| self.weight(*i)).collect::<Vec<_>>();
+        if weights.iter().all(|w| *w == 0) {
+            weights.iter_mut().for_each(|w| *w = 1);
+        }
+        let total = weights.iter().sum::<u64>();
+        if total == 0 {
+            return None;
+        }
+
+        let mut point = match self.config.strategy {
+            BalanceStrategy::RoundRobin => {
+                self.counter.fetch_add(1, Ordering::Relaxed) as u64 % total
+            }
+            BalanceStrategy::LatencyWeighted => rand::thread_rng().gen_range(0..total),
+        };
+        let index = healthy
+            .iter()
+            .zip(weights)
+            .find(|(_, w)| {
+                if point < *w {
+                    true
+                } else {
+                    point -= w;
+                    false
+                }
+            })
+            .map(|(i, _)| *i)?;
+
+        self.endpoints[index].active.fetch_add(1, Ordering::Relaxed);
+        Some(SelectedEndpoint {
+            balancer: self.clone(),
+            index,
+        })
+    }
+
+    fn weight(&self, index: usize) -> u64 {
+        let state = &self.endpoints[index];
+        let weight = state.endpoint.weight as u64;
+        match self.config.strategy {
+            BalanceStrategy::RoundRobin => weight,
+            BalanceStrategy::LatencyWeighted => {
+                // endpoints not measured yet are treated as having 1ms latency
+                let latency = match state.latency_micros.load(Ordering::Relaxed) {
+                    0 => 1000,
+                    micros => micros,
+                };
+                (weight as f64 * 1_000_000.0 / latency as f64) as u64
+            }
+        }
+    }
+
+    fn record_failure(&self, index: usize) {
+        let state = &self.endpoints[index];
+        state.successes.store(0, Ordering::Relaxed);
+        let failures = state.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= self.config.unhealthy_threshold {
+            state.healthy.store(false, Ordering::Relaxed);
+        }
+    }
+
+    fn record_success(&self, index: usize, latency: Duration) {
+        let state = &self.endpoints[index];
+        state.failures.store(0, Ordering::Relaxed);
+        let successes = state.successes.fetch_add(1, Ordering::Relaxed) + 1;
+        if successes >= self.config.healthy_threshold {
+            state.healthy.store(true, Ordering::Relaxed);
+        }
+
+        let sample = (latency.as_micros() as u64).max(1);
+        let previous = state.latency_micros.load(Ordering::Relaxed);
+        let average = if previous == 0 {
+            sample
+        } else {
+            (previous * 7 + sample * 3) / 10
+        };
+        state
+            .latency_micros
+            .store(average.max(1), Ordering::Relaxed);
+    }
+
+    /// Run health check on all endpoints once.
+    pub async fn check_all<H: HealthCheck + ?Sized>(&self, health_check: &H) {
+        let checks = self.endpoints.iter().map(|state| async {
+            let start = Instant::now();
+            let result = health_check.check(&state.endpoint).await;
+            (result, start.elapsed())
+        });
+        let results = futures::future::join_all(checks).await;
+        for (index, (result, latency)) in results.into_iter().enumerate() {
+            match result {
+                Ok(()) => self.record_success(index, latency),
+                Err(_) => self.record_failure(index),
+            }
+        }
+    }
+
+    /// Run health checks periodically in a tokio task, until the returned
+    /// handle is aborted.
+    pub fn spawn_health_checks<H>(self: &Arc<Self>, health_check: Arc<H>) -> JoinHandle<()>
+    where
+        H: HealthCheck + ?Sized + 'static,
+    {
+        let balancer = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(balancer.config.check_interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                balancer.check_all(health_check.as_ref()).await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FailingCheck(&'static str);
+
+    #[async_trait]
+    impl HealthCheck for FailingCheck {
+        async fn check(&self, endpoint: &Endpoint) -> PgWireResult<()> {
+            if endpoint.addr == self.0 {
+                Err(IOError::new(ErrorKind::ConnectionRefused, "down").into())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn balancer(strategy: BalanceStrategy) -> Arc<LoadBalancer> {
+        let config = LoadBalancerConfig {
+            strategy,
+            ..Default::default()
+        };
+        Arc::new(LoadBalancer::new(
+            vec![
+                Endpoint::new("a:5432".to_owned()),
+                Endpoint::new("b:5432".to_owned()).with_weight(2),
+            ],
+            config,
+        ))
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let balancer = balancer(BalanceStrategy::RoundRobin);
+        let picks = (0..6)
+            .map(|_| balancer.select().unwrap().endpoint().addr.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(2, picks.iter().filter(|a| *a == "a:5432").count());
+        assert_eq!(4, picks.iter().filter(|a| *a == "b:5432").count());
+
+        let selected = balancer.select().unwrap();
+        assert_eq!(1, balancer.active(0).unwrap() + balancer.active(1).unwrap());
+        drop(selected);
+        assert_eq!(0, balancer.active(0).unwrap() + balancer.active(1).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_ejection() {
+        let balancer = balancer(BalanceStrategy::LatencyWeighted);
+        let check = FailingCheck("b:5432");
+        for _ in 0..3 {
+            balancer.check_all(&check).await;
+        }
+        assert_eq!(Some(true), balancer.is_healthy(0));
+        assert_eq!(Some(false), balancer.is_healthy(1));
+        assert_eq!(None, balancer.is_healthy(2));
+        assert_eq!(None, balancer.active(2));
+        assert_eq!(None, balancer.latency(2));
+        assert!(balancer.latency(0).is_some());
+        for _ in 0..10 {
+            assert_eq!("a:5432", balancer.select().unwrap().endpoint().addr);
+        }
+
+        let selected = balancer.select().unwrap();
+        for _ in 0..3 {
+            selected.report_failure();
+        }
+        assert!(balancer.select().is_none());
+
+        let check = FailingCheck("");
+        balancer.check_all(&check).await;
+        assert_eq!(Some(false), balancer.is_healthy(0));
+        balancer.check_all(&check).await;
+        assert_eq!(Some(true), balancer.is_healthy(0));
+        assert_eq!(Some(true), balancer.is_healthy(1));
+    }
+
+    #[cfg(feature = "client-api")]
+    #[tokio::test]
+    async fn test_query_health_check() {
+        use tokio::net::TcpListener;
+
+        use crate::api::auth::noop::NoopStartupHandler;
+        use crate::api::query::{PlaceholderExtendedQueryHandler, SimpleQueryHandler};
+        use crate::api::results::{Response, Tag};
+        use crate::api::ClientInfo;
+        use crate::error::{ErrorInfo, PgWireError};
+        use crate::tokio::process_socket;
+
+        struct Backend;
+
+        #[async_trait]
+        impl SimpleQueryHandler for Backend {
+            async fn do_query<'a, C>(
+                &self,
+                _client: &mut C,
+                query: &'a str,
+            ) -> PgWireResult<Vec<Response<'a>>>
+            where
+                C: ClientInfo + Unpin + Send + Sync,
+            {
+                if query == "SELECT 1" {
+                    Ok(vec![Response::Execution(Tag::new("SELECT").with_rows(1))])
+                } else {
+                    Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                        "ERROR".to_owned(),
+                        "57P03".to_owned(),
+                        "starting up".to_owned(),
+                    ))))
+                }
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = Endpoint::new(listener.local_addr().unwrap().to_string());
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(process_socket(
+                    socket,
+                    None,
+                    Arc::new(NoopStartupHandler),
+                    Arc::new(Backend),
+                    Arc::new(PlaceholderExtendedQueryHandler),
+                ));
+            }
+        });
+
+        let check = QueryHealthCheck::new("postgres");
+        check.check(&endpoint).await.unwrap();
+
+        let check = check.with_query("SELECT 2");
+        let Err(PgWireError::UserError(error)) = check.check(&endpoint).await else {
+            panic!("expect query failure");
+        };
+        assert_eq!("57P03", error.code);
+    }
+}
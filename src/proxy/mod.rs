@@ -0,0 +1,8 @@
+//! Building blocks for proxies and connection poolers that sit between
+//! postgres clients and upstream postgres servers.
+
+pub mod balance;
+pub mod prepared;
+#[cfg(feature = "client-api")]
+pub mod relay;
+pub mod session;
@@ -0,0 +1,593 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, SqlState};
+use crate::messages::extendedquery::{Close, Parse, TARGET_TYPE_BYTE_STATEMENT};
+use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+use crate::sql;
+
+/// A named statement prepared by client, and the name it's known as on
+/// upstream connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MappedStatement {
+    pub upstream_name: String,
+    pub query: String,
+    pub type_oids: Vec<u32>,
+}
+
+impl MappedStatement {
+    fn from_parse(parse: &Parse) -> MappedStatement {
+        MappedStatement {
+            upstream_name: upstream_statement_name(&parse.query, &parse.type_oids),
+            query: parse.query.clone(),
+            type_oids: parse.type_oids.clone(),
+        }
+    }
+
+    fn to_parse(&self) -> Parse {
+        Parse::new(
+            Some(self.upstream_name.clone()),
+            self.query.clone(),
+            self.type_oids.clone(),
+        )
+    }
+}
+
+/// Derive upstream statement name from its content, so identical statements
+/// from different clients share one prepared statement on upstream.
+fn upstream_statement_name(query: &str, type_oids: &[u32]) -> String {
+    let mut key = Vec::with_capacity(query.len() + 1 + 4 * type_oids.len());
+    key.extend_from_slice(query.as_bytes());
+    key.push(0);
+    for oid in type_oids {
+        key.extend_from_slice(&oid.to_be_bytes());
+    }
+    format!("pgwire_{:x}", md5::compute(key))
+}
+
+/// Named statements prepared by a client, which outlive the upstream
+/// connection they were created on in transaction pooling mode.
+///
+/// Every message from client goes through `rewrite` before it's sent to the
+/// upstream serving current transaction, and every message from that upstream
+/// goes through `filter_backend_message` before it's sent to client. Named
+/// statements are renamed to content derived names, and re-prepared on
+/// upstreams that don't have them yet. Replies to the messages injected for
+/// re-preparing are hidden from client.
+///
+/// Unnamed statements and portals only live within a transaction and are
+/// passed through as is. The upstream must not be switched before
+/// `ReadyForQuery` is received.
+///
+/// Like postgres, parsing a named statement that already exists fails with
+/// `42P05` (duplicate_prepared_statement). The error should be sent to client
+/// after replies of the messages before it, and following messages are
+/// dropped until `Sync`.
+#[derive(Debug, Default, Clone)]
+pub struct ClientStatements {
+    statements: HashMap<String, MappedStatement>,
+    // skipping messages until Sync after an error
+    failed: bool,
+}
+
+impl ClientStatements {
+    pub fn new() -> ClientStatements {
+        ClientStatements::default()
+    }
+
+    /// Get statement by the name client used.
+    pub fn get(&self, name: &str) -> Option<&MappedStatement> {
+        self.statements.get(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.statements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.statements.is_empty()
+    }
+
+    /// Forget all statements, for example when client runs `DISCARD ALL`.
+    pub fn clear(&mut self) {
+        self.statements.clear();
+    }
+
+    /// Rewrite a client message for given upstream. The returned messages
+    /// should be sent to upstream in order.
+    pub fn rewrite(
+        &mut self,
+        upstream: &mut UpstreamStatements,
+        message: PgWireFrontendMessage,
+    ) -> PgWireResult<Vec<PgWireFrontendMessage>> {
+        let mut out = Vec::with_capacity(1);
+        if self.failed && !matches!(message, PgWireFrontendMessage::Sync(_)) {
+            return Ok(out);
+        }
+        match message {
+            PgWireFrontendMessage::Parse(mut parse) => {
+                if let Some(name) = named(&parse.name) {
+                    if self.statements.contains_key(name) {
+                        self.failed = true;
+                        return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                            "ERROR".to_owned(),
+                            SqlState::DUPLICATE_PREPARED_STATEMENT.into(),
+                            format!("prepared statement \"{name}\" already exists"),
+                        ))));
+                    }
+                    let name = name.to_owned();
+                    let statement = MappedStatement::from_parse(&parse);
+                    upstream.prepare(&statement, &mut out);
+                    parse.name = Some(statement.upstream_name.clone());
+                    upstream.pending.push_back(Pending::parse(
+                        statement.upstream_name.clone(),
+                        Some(name.clone()),
+                        true,
+                    ));
+                    self.statements.insert(name, statement);
+                } else {
+                    upstream.pending.push_back(Pending::passthrough());
+                }
+                out.push(PgWireFrontendMessage::Parse(parse));
+            }
+            PgWireFrontendMessage::Bind(mut bind) => {
+                if let Some(statement) = named(&bind.statement_name).and_then(|n| self.get(n)) {
+                    upstream.ensure_prepared(statement, &mut out);
+                    bind.statement_name = Some(statement.upstream_name.clone());
+                }
+                out.push(PgWireFrontendMessage::Bind(bind));
+            }
+            PgWireFrontendMessage::Describe(mut describe) => {
+                if describe.target_type == TARGET_TYPE_BYTE_STATEMENT {
+                    if let Some(statement) = named(&describe.name).and_then(|n| self.get(n)) {
+                        upstream.ensure_prepared(statement, &mut out);
+                        describe.name = Some(statement.upstream_name.clone());
+                    }
+                }
+                out.push(PgWireFrontendMessage::Describe(describe));
+            }
+            PgWireFrontendMessage::Close(close) => {
+                // The upstream statement may be shared with other clients, so
+                // it's kept. Closing the client's own name is a no-op on
+                // upstream and still gets the CloseComplete client expects.
+                let restore = if close.target_type == TARGET_TYPE_BYTE_STATEMENT {
+                    named(&close.name).and_then(|n| self.statements.remove_entry(n))
+                } else {
+                    None
+                };
+                upstream.pending.push_back(Pending {
+                    forward: true,
+                    upstream_name: None,
+                    client_name: None,
+                    restore,
+                });
+                out.push(PgWireFrontendMessage::Close(close));
+            }
+            PgWireFrontendMessage::Sync(sync) => {
+                self.failed = false;
+                upstream.generation += 1;
+                out.push(PgWireFrontendMessage::Sync(sync));
+            }
+            PgWireFrontendMessage::Query(query) => {
+                // these also drop all protocol level statements
+                if sql::split_statements(&query.query).into_iter().any(|s| {
+                    let tokens = sql::tokenize(s);
+                    tokens.len() == 2
+                        && (tokens[0].is_word("discard") || tokens[0].is_word("deallocate"))
+                        && tokens[1].is_word("all")
+                }) {
+                    self.clear();
+                    upstream.clear();
+                }
+                out.push(PgWireFrontendMessage::Query(query));
+            }
+            message => out.push(message),
+        }
+        Ok(out)
+    }
+
+    /// Process a message from upstream, returns false if the message is a
+    /// reply to an injected message and should not be sent to client.
+    pub fn filter_backend_message(
+        &mut self,
+        upstream: &mut UpstreamStatements,
+        message: &PgWireBackendMessage,
+    ) -> bool {
+        match message {
+            PgWireBackendMessage::ParseComplete(_) | PgWireBackendMessage::CloseComplete(_) => {
+                upstream.pending.pop_front().map_or(true, |p| p.forward)
+            }
+            PgWireBackendMessage::ErrorResponse(_) => {
+                // upstream skips everything until Sync, undo the bookkeeping of
+                // messages that are not going to take effect
+                for pending in upstream.pending.drain(..) {
+                    if let Some(upstream_name) = pending.upstream_name {
+                        upstream.prepared.remove(&upstream_name);
+                        if let Some(client_name) = pending.client_name {
+                            if self
+                                .statements
+                                .get(&client_name)
+                                .is_some_and(|s| s.upstream_name == upstream_name)
+                            {
+                                self.statements.remove(&client_name);
+                            }
+                        }
+                    }
+                    if let Some((name, statement)) = pending.restore {
+                        self.statements.entry(name).or_insert(statement);
+                    }
+                }
+                true
+            }
+            PgWireBackendMessage::ReadyForQuery(_) => {
+                upstream.pending.clear();
+                true
+            }
+            _ => true,
+        }
+    }
+}
+
+fn named(name: &Option<String>) -> Option<&str> {
+    name.as_deref().filter(|n| !n.is_empty())
+}
+
+/// A Parse or Close sent to upstream and waiting for its completion.
+#[derive(Debug, Clone)]
+struct Pending {
+    forward: bool,
+    upstream_name: Option<String>,
+    client_name: Option<String>,
+    restore: Option<(String, MappedStatement)>,
+}
+
+impl Pending {
+    fn parse(upstream_name: String, client_name: Option<String>, forward: bool) -> Pending {
+        Pending {
+            forward,
+            upstream_name: Some(upstream_name),
+            client_name,
+            restore: None,
+        }
+    }
+
+    fn passthrough() -> Pending {
+        Pending {
+            forward: true,
+            upstream_name: None,
+            client_name: None,
+            restore: None,
+        }
+    }
+
+    fn injected_close() -> Pending {
+        Pending {
+            forward: false,
+            upstream_name: None,
+            client_name: None,
+            restore: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct UpstreamStatement {
+    query: String,
+    type_oids: Vec<u32>,
+    last_used: u64,
+    generation: u64,
+}
+
+/// Statements prepared on one upstream connection, shared by all clients it
+/// serves.
+///
+/// Optionally the number of statements can be limited, least recently used
+/// ones are closed when the limit is reached. Statements used since last
+/// `Sync` are never evicted.
+#[derive(Debug, Default, Clone)]
+pub struct UpstreamStatements {
+    prepared: HashMap<String, UpstreamStatement>,
+    max_statements: Option<usize>,
+    tick: u64,
+    generation: u64,
+    pending: VecDeque<Pending>,
+}
+
+impl UpstreamStatements {
+    pub fn new() -> UpstreamStatements {
+        UpstreamStatements::default()
+    }
+
+    /// Limit the number of prepared statements kept on upstream.
+    pub fn with_max_statements(max_statements: usize) -> UpstreamStatements {
+        UpstreamStatements {
+            max_statements: Some(max_statements),
+            ..Default::default()
+        }
+    }
+
+    /// Test if a statement is prepared on upstream, by its upstream name.
+    pub fn contains(&self, upstream_name: &str) -> bool {
+        self.prepared.contains_key(upstream_name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.prepared.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prepared.is_empty()
+    }
+
+    /// Forget all statements, when upstream is reset or reconnected.
+    pub fn clear(&mut self) {
+        self.prepared.clear();
+    }
+
+    fn touch(&mut self, upstream_name: &str) {
+        self.tick += 1;
+        if let Some(stmt) = self.prepared.get_mut(upstream_name) {
+            stmt.last_used = self.tick;
+            stmt.generation = self.generation;
+        }
+    }
+
+    fn ensure_prepared(
+        &mut self,
+        statement: &MappedStatement,
+        out: &mut Vec<PgWireFrontendMessage>,
+    ) {
+        let prepared = self
+            .prepared
+            .get(&statement.upstream_name)
+            .is_some_and(|s| s.query == statement.query && s.type_oids == statement.type_oids);
+        if prepared {
+            self.touch(&statement.upstream_name);
+        } else {
+            self.prepare(statement, out);
+            out.push(PgWireFrontendMessage::Parse(statement.to_parse()));
+            self.pending
+                .push_back(Pending::parse(statement.upstream_name.clone(), None, false));
+        }
+    }
+
+    /// Make room for the statement and record it as prepared. Caller sends
+    /// the Parse itself after messages pushed into `out`.
+    fn prepare(&mut self, statement: &MappedStatement, out: &mut Vec<PgWireFrontendMessage>) {
+        if !self.prepared.contains_key(&statement.upstream_name) {
+            self.evict(out);
+        }
+
+        // A statement with this name may exist on upstream, or we are not sure
+        // about it after an error. Closing a missing statement is not an error.
+        out.push(PgWireFrontendMessage::Close(Close::new(
+            TARGET_TYPE_BYTE_STATEMENT,
+            Some(statement.upstream_name.clone()),
+        )));
+        self.pending.push_back(Pending::injected_close());
+
+        self.prepared.insert(
+            statement.upstream_name.clone(),
+            UpstreamStatement {
+                query: statement.query.clone(),
+                type_oids: statement.type_oids.clone(),
+                last_used: 0,
+                generation: self.generation,
+            },
+        );
+        self.touch(&statement.upstream_name);
+    }
+
+    fn evict(&mut self, out: &mut Vec<PgWireFrontendMessage>) {
+        let Some(max_statements) = self.max_statements else {
+            return;
+        };
+        while self.prepared.len() >= max_statements.max(1) {
+            let victim = self
+                .prepared
+                .iter()
+                .filter(|(_, s)| s.generation < self.generation)
+                .min_by_key(|(_, s)| s.last_used)
+                .map(|(name, _)| name.clone());
+            let Some(victim) = victim else {
+                // everything is in use by current pipeline
+                return;
+            };
+            self.prepared.remove(&victim);
+            out.push(PgWireFrontendMessage::Close(Close::new(
+                TARGET_TYPE_BYTE_STATEMENT,
+                Some(victim),
+            )));
+            self.pending.push_back(Pending::injected_close());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::extendedquery::{Bind, CloseComplete, ParseComplete, Sync};
+    use crate::messages::response::{ErrorResponse, ReadyForQuery, TransactionStatus};
+
+    fn parse(name: &str, query: &str) -> PgWireFrontendMessage {
+        PgWireFrontendMessage::Parse(Parse::new(Some(name.to_owned()), query.to_owned(), vec![]))
+    }
+
+    fn bind(statement: &str) -> PgWireFrontendMessage {
+        PgWireFrontendMessage::Bind(Bind::new(
+            None,
+            Some(statement.to_owned()),
+            vec![],
+            vec![],
+            vec![],
+        ))
+    }
+
+    fn forwarded(
+        client: &mut ClientStatements,
+        upstream: &mut UpstreamStatements,
+        replies: Vec<PgWireBackendMessage>,
+    ) -> usize {
+        replies
+            .iter()
+            .filter(|m| client.filter_backend_message(upstream, m))
+            .count()
+    }
+
+    #[test]
+    fn test_reprepare_on_other_upstream() {
+        let mut client = ClientStatements::new();
+        let mut first = UpstreamStatements::new();
+        let mut second = UpstreamStatements::new();
+
+        let out = client
+            .rewrite(&mut first, parse("s1", "SELECT $1"))
+            .unwrap();
+        assert_eq!(2, out.len());
+        let upstream_name = client.get("s1").unwrap().upstream_name.clone();
+        assert!(upstream_name.starts_with("pgwire_"));
+        let PgWireFrontendMessage::Parse(ref p) = out[1] else {
+            panic!("expect parse");
+        };
+        assert_eq!(Some(&upstream_name), p.name.as_ref());
+        assert_eq!(
+            1,
+            forwarded(
+                &mut client,
+                &mut first,
+                vec![
+                    PgWireBackendMessage::CloseComplete(CloseComplete),
+                    PgWireBackendMessage::ParseComplete(ParseComplete),
+                ]
+            )
+        );
+
+        // already prepared on first upstream
+        let out = client.rewrite(&mut first, bind("s1")).unwrap();
+        assert_eq!(1, out.len());
+
+        // next transaction is served by another upstream
+        let out = client.rewrite(&mut second, bind("s1")).unwrap();
+        assert_eq!(3, out.len());
+        assert!(matches!(out[1], PgWireFrontendMessage::Parse(_)));
+        let PgWireFrontendMessage::Bind(ref b) = out[2] else {
+            panic!("expect bind");
+        };
+        assert_eq!(Some(&upstream_name), b.statement_name.as_ref());
+        assert_eq!(
+            0,
+            forwarded(
+                &mut client,
+                &mut second,
+                vec![
+                    PgWireBackendMessage::CloseComplete(CloseComplete),
+                    PgWireBackendMessage::ParseComplete(ParseComplete),
+                ]
+            )
+        );
+        assert!(second.contains(&upstream_name));
+
+        // close keeps upstream statement
+        let out = client
+            .rewrite(
+                &mut second,
+                PgWireFrontendMessage::Close(Close::new(
+                    TARGET_TYPE_BYTE_STATEMENT,
+                    Some("s1".to_owned()),
+                )),
+            )
+            .unwrap();
+        assert_eq!(1, out.len());
+        assert!(client.is_empty());
+        assert!(second.contains(&upstream_name));
+    }
+
+    #[test]
+    fn test_duplicate_statement() {
+        let mut client = ClientStatements::new();
+        let mut upstream = UpstreamStatements::new();
+
+        client
+            .rewrite(&mut upstream, parse("s1", "SELECT 1"))
+            .unwrap();
+        let Err(PgWireError::UserError(error)) =
+            client.rewrite(&mut upstream, parse("s1", "SELECT 2"))
+        else {
+            panic!("expect duplicate statement");
+        };
+        assert_eq!("42P05", error.code);
+        assert_eq!("SELECT 1", client.get("s1").unwrap().query);
+
+        // skipped until sync
+        assert!(client
+            .rewrite(&mut upstream, bind("s1"))
+            .unwrap()
+            .is_empty());
+        let out = client
+            .rewrite(&mut upstream, PgWireFrontendMessage::Sync(Sync::new()))
+            .unwrap();
+        assert_eq!(1, out.len());
+        assert_eq!(1, client.rewrite(&mut upstream, bind("s1")).unwrap().len());
+
+        // unnamed statements are replaced
+        client
+            .rewrite(&mut upstream, parse("", "SELECT 1"))
+            .unwrap();
+        client
+            .rewrite(&mut upstream, parse("", "SELECT 2"))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_error_rollback() {
+        let mut client = ClientStatements::new();
+        let mut upstream = UpstreamStatements::new();
+
+        client
+            .rewrite(&mut upstream, parse("s1", "SELECT 1"))
+            .unwrap();
+        client
+            .rewrite(&mut upstream, parse("s2", "SELEC 2"))
+            .unwrap();
+        client
+            .rewrite(&mut upstream, PgWireFrontendMessage::Sync(Sync::new()))
+            .unwrap();
+        assert_eq!(2, upstream.len());
+
+        let replies = vec![
+            PgWireBackendMessage::CloseComplete(CloseComplete),
+            PgWireBackendMessage::ParseComplete(ParseComplete),
+            PgWireBackendMessage::CloseComplete(CloseComplete),
+            PgWireBackendMessage::ErrorResponse(ErrorResponse::new(vec![])),
+            PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(TransactionStatus::Idle)),
+        ];
+        assert_eq!(3, forwarded(&mut client, &mut upstream, replies));
+        assert_eq!(1, client.len());
+        assert!(client.get("s2").is_none());
+        assert_eq!(1, upstream.len());
+    }
+
+    #[test]
+    fn test_eviction() {
+        let mut client = ClientStatements::new();
+        let mut upstream = UpstreamStatements::with_max_statements(1);
+
+        client
+            .rewrite(&mut upstream, parse("s1", "SELECT 1"))
+            .unwrap();
+        client
+            .rewrite(&mut upstream, PgWireFrontendMessage::Sync(Sync::new()))
+            .unwrap();
+        let out = client
+            .rewrite(&mut upstream, parse("s2", "SELECT 2"))
+            .unwrap();
+        // close evicted, close and parse s2
+        assert_eq!(3, out.len());
+        assert_eq!(1, upstream.len());
+        assert!(upstream.contains(&client.get("s2").unwrap().upstream_name));
+
+        // s1 is re-prepared on demand, s2 is in use by current pipeline
+        let out = client.rewrite(&mut upstream, bind("s1")).unwrap();
+        assert_eq!(3, out.len());
+        assert_eq!(2, upstream.len());
+    }
+}
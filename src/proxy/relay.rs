@@ -0,0 +1,400 @@
+//! Relay a client connection to an upstream postgres server.
+//!
+//! `relay` speaks the protocol on both sides: it decodes every message from
+//! client and upstream, passes it to a `RelayInterceptor` and forwards what the
+//! interceptor returns. Interceptors can rewrite or block statements, answer
+//! queries themselves, or just observe the traffic, for building query
+//! firewalls, poolers or shadow traffic tools.
+//!
+//! Startup and authentication are relayed as is, so the upstream
+//! authenticates the client. `SslRequest` from client is refused, and SCRAM
+//! channel binding (`SCRAM-SHA-256-PLUS`) doesn't work through the relay.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use crate::api::results::Tag;
+use crate::client::PgWireMessageClientCodec;
+use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+use crate::messages::data::{DataRow, RowDescription};
+use crate::messages::response::{ReadyForQuery, SslResponse, TransactionStatus};
+use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+use crate::sansio::{decode_frontend_message, PgWireConnectionState};
+use crate::tokio::Transport;
+
+/// What to do with a message from client.
+#[derive(Debug)]
+pub enum FrontendAction {
+    /// Forward the message, possibly rewritten, to upstream.
+    Forward(PgWireFrontendMessage),
+    /// Don't forward the message, and send these messages to client instead.
+    /// To answer a `Query`, the responses must end with `ReadyForQuery`.
+    Respond(Vec<PgWireBackendMessage>),
+    /// Discard the message.
+    Drop,
+}
+
+impl FrontendAction {
+    /// Reject a simple query with `error`.
+    pub fn reject(session: &RelaySession, error: ErrorInfo) -> FrontendAction {
+        FrontendAction::Respond(vec![
+            PgWireBackendMessage::ErrorResponse(error.into()),
+            PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(session.transaction_status)),
+        ])
+    }
+
+    /// Answer a simple query with `rows`.
+    pub fn rows(
+        session: &RelaySession,
+        row_description: RowDescription,
+        rows: Vec<DataRow>,
+    ) -> FrontendAction {
+        let tag = Tag::select(rows.len());
+        let mut messages = Vec::with_capacity(rows.len() + 3);
+        messages.push(PgWireBackendMessage::RowDescription(row_description));
+        messages.extend(rows.into_iter().map(PgWireBackendMessage::DataRow));
+        messages.push(PgWireBackendMessage::CommandComplete(tag.into()));
+        messages.push(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+            session.transaction_status,
+        )));
+        FrontendAction::Respond(messages)
+    }
+}
+
+/// State of a relayed connection, available to interceptors.
+#[derive(Debug, Clone)]
+pub struct RelaySession {
+    parameters: BTreeMap<String, String>,
+    transaction_status: TransactionStatus,
+}
+
+impl RelaySession {
+    /// Parameters of client's startup message, like `user` and `database`.
+    pub fn parameters(&self) -> &BTreeMap<String, String> {
+        &self.parameters
+    }
+
+    /// Transaction status reported by the last `ReadyForQuery` from upstream.
+    pub fn transaction_status(&self) -> TransactionStatus {
+        self.transaction_status
+    }
+}
+
+/// Hooks called for each relayed message.
+#[async_trait]
+pub trait RelayInterceptor: Send + Sync {
+    /// Called for each message from client, including `Startup`.
+    async fn on_frontend_message(
+        &self,
+        _session: &RelaySession,
+        message: PgWireFrontendMessage,
+    ) -> PgWireResult<FrontendAction> {
+        Ok(FrontendAction::Forward(message))
+    }
+
+    /// Called for each message from upstream, return `None` to hide it from
+    /// client.
+    async fn on_backend_message(
+        &self,
+        _session: &RelaySession,
+        message: PgWireBackendMessage,
+    ) -> PgWireResult<Option<PgWireBackendMessage>> {
+        Ok(Some(message))
+    }
+}
+
+/// Relay everything unchanged.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopRelayInterceptor;
+
+impl RelayInterceptor for NoopRelayInterceptor {}
+
+/// Server side codec of the relay, it doesn't track query state.
+#[derive(Debug, Default)]
+struct RelayServerCodec {
+    started: bool,
+}
+
+impl Decoder for RelayServerCodec {
+    type Item = PgWireFrontendMessage;
+    type Error = PgWireError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> PgWireResult<Option<Self::Item>> {
+        let state = if self.started {
+            PgWireConnectionState::ReadyForQuery
+        } else {
+            PgWireConnectionState::AwaitingStartup
+        };
+        let message = decode_frontend_message(state, src)?;
+        if let Some(PgWireFrontendMessage::Startup(_)) = message {
+            self.started = true;
+        }
+        Ok(message)
+    }
+}
+
+impl Encoder<PgWireBackendMessage> for RelayServerCodec {
+    type Error = PgWireError;
+
+    fn encode(&mut self, item: PgWireBackendMessage, dst: &mut BytesMut) -> PgWireResult<()> {
+        item.encode(dst)
+    }
+}
+
+/// Relay messages between `client` and `upstream` until either side closes
+/// the connection or client sends `Terminate`.
+pub async fn relay<S, U, I>(client: S, upstream: U, interceptor: Arc<I>) -> PgWireResult<()>
+where
+    S: Transport,
+    U: Transport,
+    I: RelayInterceptor + ?Sized,
+{
+    let mut client = Framed::new(client, RelayServerCodec::default());
+    let mut upstream = Framed::new(upstream, PgWireMessageClientCodec::new());
+    let mut session = RelaySession {
+        parameters: BTreeMap::new(),
+        transaction_status: TransactionStatus::Idle,
+    };
+
+    loop {
+        tokio::select! {
+            message = client.next() => {
+                let Some(message) = message else {
+                    return Ok(());
+                };
+                let message = message?;
+                match message {
+                    PgWireFrontendMessage::SslRequest(_) => {
+                        client
+                            .send(PgWireBackendMessage::SslResponse(SslResponse::Refuse))
+                            .await?;
+                        continue;
+                    }
+                    PgWireFrontendMessage::Startup(ref startup) => {
+                        session.parameters = startup.parameters.clone();
+                    }
+                    _ => {}
+                }
+                let terminate = matches!(
+                    message,
+                    PgWireFrontendMessage::Terminate(_) | PgWireFrontendMessage::CancelRequest(_)
+                );
+                match interceptor.on_frontend_message(&session, message).await? {
+                    FrontendAction::Forward(message) => upstream.send(message).await?,
+                    FrontendAction::Respond(messages) => {
+                        for message in messages {
+                            client.feed(message).await?;
+                        }
+                        client.flush().await?;
+                    }
+                    FrontendAction::Drop => {}
+                }
+                if terminate {
+                    return Ok(());
+                }
+            }
+            message = upstream.next() => {
+                let Some(message) = message else {
+                    return Ok(());
+                };
+                let message = message?;
+                if let PgWireBackendMessage::ReadyForQuery(ref ready) = message {
+                    session.transaction_status = ready.status;
+                }
+                if let Some(message) = interceptor.on_backend_message(&session, message).await? {
+                    // flush when upstream has nothing more for now
+                    client.feed(message).await?;
+                    if upstream.read_buffer().is_empty() {
+                        client.flush().await?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::Type;
+    use crate::messages::data::FieldDescription;
+    use crate::messages::response::CommandComplete;
+    use crate::messages::simplequery::Query;
+    use crate::messages::startup::{Authentication, SslRequest, Startup};
+    use crate::messages::terminate::Terminate;
+
+    struct Firewall;
+
+    #[async_trait]
+    impl RelayInterceptor for Firewall {
+        async fn on_frontend_message(
+            &self,
+            session: &RelaySession,
+            message: PgWireFrontendMessage,
+        ) -> PgWireResult<FrontendAction> {
+            match message {
+                PgWireFrontendMessage::Query(query) if query.query.starts_with("DROP") => {
+                    Ok(FrontendAction::reject(
+                        session,
+                        ErrorInfo::new(
+                            "ERROR".to_owned(),
+                            "42501".to_owned(),
+                            "blocked".to_owned(),
+                        ),
+                    ))
+                }
+                PgWireFrontendMessage::Query(query) if query.query == "SHOW proxy" => {
+                    let desc = RowDescription::new(vec![FieldDescription::new(
+                        "proxy".to_owned(),
+                        0,
+                        0,
+                        Type::TEXT.oid(),
+                        -1,
+                        -1,
+                        0,
+                    )]);
+                    let mut data = BytesMut::new();
+                    data.extend_from_slice(&2i32.to_be_bytes());
+                    data.extend_from_slice(b"on");
+                    Ok(FrontendAction::rows(
+                        session,
+                        desc,
+                        vec![DataRow::new(data, 1)],
+                    ))
+                }
+                PgWireFrontendMessage::Query(query) => Ok(FrontendAction::Forward(
+                    PgWireFrontendMessage::Query(Query::new(query.query.to_lowercase())),
+                )),
+                message => Ok(FrontendAction::Forward(message)),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        let (client, proxy_client) = tokio::io::duplex(4096);
+        let (proxy_upstream, upstream) = tokio::io::duplex(4096);
+        let relay = tokio::spawn(relay(proxy_client, proxy_upstream, Arc::new(Firewall)));
+
+        let mut client = Framed::new(client, PgWireMessageClientCodec::new());
+        let mut upstream = Framed::new(upstream, RelayServerCodec::default());
+
+        // ssl is refused by relay
+        client
+            .send(PgWireFrontendMessage::SslRequest(SslRequest::new()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.next().await.unwrap().unwrap(),
+            PgWireBackendMessage::SslResponse(SslResponse::Refuse)
+        ));
+
+        let mut startup = Startup::new();
+        startup
+            .parameters
+            .insert("user".to_owned(), "tomcat".to_owned());
+        client
+            .send(PgWireFrontendMessage::Startup(startup))
+            .await
+            .unwrap();
+        assert!(matches!(
+            upstream.next().await.unwrap().unwrap(),
+            PgWireFrontendMessage::Startup(s) if s.parameters["user"] == "tomcat"
+        ));
+        upstream
+            .send(PgWireBackendMessage::Authentication(Authentication::Ok))
+            .await
+            .unwrap();
+        upstream
+            .send(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+                TransactionStatus::Idle,
+            )))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.next().await.unwrap().unwrap(),
+            PgWireBackendMessage::Authentication(Authentication::Ok)
+        ));
+        assert!(matches!(
+            client.next().await.unwrap().unwrap(),
+            PgWireBackendMessage::ReadyForQuery(_)
+        ));
+
+        // blocked
+        client
+            .send(PgWireFrontendMessage::Query(Query::new(
+                "DROP TABLE t".to_owned(),
+            )))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.next().await.unwrap().unwrap(),
+            PgWireBackendMessage::ErrorResponse(_)
+        ));
+        assert!(matches!(
+            client.next().await.unwrap().unwrap(),
+            PgWireBackendMessage::ReadyForQuery(_)
+        ));
+
+        // answered by relay
+        client
+            .send(PgWireFrontendMessage::Query(Query::new(
+                "SHOW proxy".to_owned(),
+            )))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.next().await.unwrap().unwrap(),
+            PgWireBackendMessage::RowDescription(_)
+        ));
+        let Some(Ok(PgWireBackendMessage::DataRow(row))) = client.next().await else {
+            panic!("expect DataRow");
+        };
+        assert_eq!(row.fields().unwrap(), vec![Some(&b"on"[..])]);
+        assert!(matches!(
+            client.next().await.unwrap().unwrap(),
+            PgWireBackendMessage::CommandComplete(_)
+        ));
+        assert!(matches!(
+            client.next().await.unwrap().unwrap(),
+            PgWireBackendMessage::ReadyForQuery(_)
+        ));
+
+        // rewritten
+        client
+            .send(PgWireFrontendMessage::Query(Query::new(
+                "SELECT 1".to_owned(),
+            )))
+            .await
+            .unwrap();
+        assert!(matches!(
+            upstream.next().await.unwrap().unwrap(),
+            PgWireFrontendMessage::Query(q) if q.query == "select 1"
+        ));
+        upstream
+            .send(PgWireBackendMessage::CommandComplete(CommandComplete::new(
+                "SELECT 0".to_owned(),
+            )))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.next().await.unwrap().unwrap(),
+            PgWireBackendMessage::CommandComplete(_)
+        ));
+
+        client
+            .send(PgWireFrontendMessage::Terminate(Terminate::new()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            upstream.next().await.unwrap().unwrap(),
+            PgWireFrontendMessage::Terminate(_)
+        ));
+        relay.await.unwrap().unwrap();
+    }
+}
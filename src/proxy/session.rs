@@ -0,0 +1,368 @@
+use std::collections::BTreeSet;
+
+use crate::messages::extendedquery::{Close, Parse, TARGET_TYPE_BYTE_STATEMENT};
+use crate::messages::PgWireBackendMessage;
+use crate::sql::{self, Token, TokenKind};
+
+/// Kinds of session-level state a client can leave on an upstream connection.
+///
+/// All of these survive the end of a transaction, so an upstream carrying any
+/// of them can't be handed to another client without a reset, and the client
+/// that created them expects to see them again in its next transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum SessionStateKind {
+    /// `SET` (without `LOCAL`), `set_config(.., false)` or a server reported
+    /// `ParameterStatus` change
+    Parameter = 1,
+    /// `CREATE TEMP TABLE`, `SELECT .. INTO TEMP` and other temporary objects
+    TemporaryObject = 1 << 1,
+    /// statements prepared by sql `PREPARE`
+    SqlPreparedStatement = 1 << 2,
+    /// named statements prepared by the extended query `Parse` message
+    ProtocolPreparedStatement = 1 << 3,
+    /// session level advisory locks, `pg_advisory_lock` and friends
+    AdvisoryLock = 1 << 4,
+    /// `LISTEN` on a channel
+    Listen = 1 << 5,
+    /// cursors declared `WITH HOLD`
+    HoldableCursor = 1 << 6,
+}
+
+impl SessionStateKind {
+    pub const ALL: [SessionStateKind; 7] = [
+        SessionStateKind::Parameter,
+        SessionStateKind::TemporaryObject,
+        SessionStateKind::SqlPreparedStatement,
+        SessionStateKind::ProtocolPreparedStatement,
+        SessionStateKind::AdvisoryLock,
+        SessionStateKind::Listen,
+        SessionStateKind::HoldableCursor,
+    ];
+
+    #[inline]
+    fn bit(self) -> u8 {
+        self as u8
+    }
+}
+
+const ALL_KINDS: u8 = 0x7f;
+
+/// Tracks state-changing commands a pooled client sends through an upstream
+/// connection.
+///
+/// A pooler feeds every client `Query` and `Parse`/`Close` message, as well as
+/// backend messages after startup, into the tracker. Before the upstream is
+/// returned to the pool, `needs_reset` tells whether `reset_query` has to be
+/// run on it. In transaction pooling mode, `is_pinned` tells whether the client
+/// has to keep its upstream beyond current transaction because it created
+/// state it will rely on later.
+///
+/// By default every kind of state pins the client. Use `set_pinning` to relax
+/// this for kinds the pooler can handle itself, for example protocol level
+/// prepared statements that are re-prepared on demand.
+///
+/// The classification is done on sql text with a lightweight lexer, it
+/// recognizes common forms of these commands and is not a replacement for a
+/// real sql parser.
+#[derive(Debug, Clone)]
+pub struct SessionState {
+    observed: u8,
+    pinning: u8,
+    protocol_statements: BTreeSet<String>,
+}
+
+impl Default for SessionState {
+    fn default() -> Self {
+        SessionState {
+            observed: 0,
+            pinning: ALL_KINDS,
+            protocol_statements: BTreeSet::new(),
+        }
+    }
+}
+
+impl SessionState {
+    pub fn new() -> SessionState {
+        SessionState::default()
+    }
+
+    /// Configure whether state of `kind` pins the client to its upstream.
+    pub fn set_pinning(&mut self, kind: SessionStateKind, pinning: bool) {
+        if pinning {
+            self.pinning |= kind.bit();
+        } else {
+            self.pinning &= !kind.bit();
+        }
+    }
+
+    /// Test if state of given kind has been observed since last reset.
+    pub fn contains(&self, kind: SessionStateKind) -> bool {
+        self.observed & kind.bit() != 0
+    }
+
+    /// All kinds of state observed since last reset.
+    pub fn kinds(&self) -> Vec<SessionStateKind> {
+        SessionStateKind::ALL
+            .into_iter()
+            .filter(|k| self.contains(*k))
+            .collect()
+    }
+
+    /// Test if the upstream carries no session state.
+    pub fn is_clean(&self) -> bool {
+        self.observed == 0
+    }
+
+    /// Test if the upstream has to be reset before it's reused by another
+    /// client.
+    pub fn needs_reset(&self) -> bool {
+        !self.is_clean()
+    }
+
+    /// Test if the client has to stay on its current upstream.
+    pub fn is_pinned(&self) -> bool {
+        self.observed & self.pinning != 0
+    }
+
+    /// The query to clean up the upstream connection, if required.
+    ///
+    /// Note that `DISCARD ALL` cannot run inside a transaction block.
+    pub fn reset_query(&self) -> Option<&'static str> {
+        if self.needs_reset() {
+            Some("DISCARD ALL")
+        } else {
+            None
+        }
+    }
+
+    /// Mark the upstream as clean, after `reset_query` is executed or a fresh
+    /// upstream connection is assigned.
+    pub fn mark_reset(&mut self) {
+        self.observed = 0;
+        self.protocol_statements.clear();
+    }
+
+    fn add(&mut self, kind: SessionStateKind) {
+        self.observed |= kind.bit();
+    }
+
+    fn remove(&mut self, kind: SessionStateKind) {
+        self.observed &= !kind.bit();
+    }
+
+    /// Observe a query string sent by client, either from a simple `Query` or
+    /// the `Parse` of an extended query. Multiple statements are supported.
+    pub fn observe_query(&mut self, query: &str) {
+        for statement in sql::split_statements(query) {
+            self.observe_statement(&sql::tokenize(statement));
+        }
+    }
+
+    /// Observe a `Parse` message sent by client.
+    pub fn observe_parse(&mut self, parse: &Parse) {
+        if let Some(name) = parse.name.as_deref().filter(|n| !n.is_empty()) {
+            self.protocol_statements.insert(name.to_owned());
+            self.add(SessionStateKind::ProtocolPreparedStatement);
+        }
+        self.observe_query(&parse.query);
+    }
+
+    /// Observe a `Close` message sent by client.
+    pub fn observe_close(&mut self, close: &Close) {
+        if close.target_type != TARGET_TYPE_BYTE_STATEMENT {
+            return;
+        }
+        if let Some(name) = close.name.as_deref() {
+            self.protocol_statements.remove(name);
+            if self.protocol_statements.is_empty() {
+                self.remove(SessionStateKind::ProtocolPreparedStatement);
+            }
+        }
+    }
+
+    /// Observe a message sent by upstream. This should only be called after
+    /// startup phase is finished, because upstream reports all its parameters
+    /// during startup.
+    pub fn observe_backend_message(&mut self, message: &PgWireBackendMessage) {
+        if let PgWireBackendMessage::ParameterStatus(_) = message {
+            self.add(SessionStateKind::Parameter);
+        }
+    }
+
+    fn observe_statement(&mut self, tokens: &[Token]) {
+        let word = |idx: usize, w: &str| tokens.get(idx).is_some_and(|t| t.is_word(w));
+
+        if word(0, "set") {
+            // transaction scoped variants
+            if !(word(1, "local") || word(1, "transaction") || word(1, "constraints")) {
+                self.add(SessionStateKind::Parameter);
+            }
+        } else if word(0, "reset") {
+            if word(1, "all") {
+                self.remove(SessionStateKind::Parameter);
+            }
+        } else if word(0, "create") {
+            // CREATE [OR REPLACE] [GLOBAL|LOCAL] TEMP[ORARY] [UNLOGGED] TABLE ...
+            if tokens
+                .iter()
+                .skip(1)
+                .take(4)
+                .any(|t| t.is_word("temp") || t.is_word("temporary"))
+            {
+                self.add(SessionStateKind::TemporaryObject);
+            }
+        } else if word(0, "prepare") {
+            // PREPARE TRANSACTION is two-phase commit
+            if !word(1, "transaction") {
+                self.add(SessionStateKind::SqlPreparedStatement);
+            }
+        } else if word(0, "deallocate") {
+            if word(1, "all") || (word(1, "prepare") && word(2, "all")) {
+                self.remove(SessionStateKind::SqlPreparedStatement);
+            }
+        } else if word(0, "listen") {
+            self.add(SessionStateKind::Listen);
+        } else if word(0, "unlisten") {
+            if tokens.get(1).is_some_and(|t| t.is_symbol('*')) {
+                self.remove(SessionStateKind::Listen);
+            }
+        } else if word(0, "declare") {
+            if tokens
+                .windows(2)
+                .any(|w| w[0].is_word("with") && w[1].is_word("hold"))
+            {
+                self.add(SessionStateKind::HoldableCursor);
+            }
+        } else if word(0, "close") {
+            if word(1, "all") {
+                self.remove(SessionStateKind::HoldableCursor);
+            }
+        } else if word(0, "discard") {
+            if word(1, "all") {
+                self.mark_reset();
+            } else if word(1, "temp") || word(1, "temporary") {
+                self.remove(SessionStateKind::TemporaryObject);
+            }
+        }
+
+        // SELECT ... INTO TEMP
+        if tokens
+            .windows(2)
+            .any(|w| w[0].is_word("into") && (w[1].is_word("temp") || w[1].is_word("temporary")))
+        {
+            self.add(SessionStateKind::TemporaryObject);
+        }
+
+        self.observe_function_calls(tokens);
+    }
+
+    fn observe_function_calls(&mut self, tokens: &[Token]) {
+        for (idx, token) in tokens.iter().enumerate() {
+            if token.kind != TokenKind::Word
+                || !tokens.get(idx + 1).is_some_and(|t| t.is_symbol('('))
+            {
+                continue;
+            }
+
+            let name = token.text.to_ascii_lowercase();
+            match name.as_str() {
+                "pg_advisory_lock"
+                | "pg_advisory_lock_shared"
+                | "pg_try_advisory_lock"
+                | "pg_try_advisory_lock_shared" => self.add(SessionStateKind::AdvisoryLock),
+                "pg_advisory_unlock_all" => self.remove(SessionStateKind::AdvisoryLock),
+                // set_config(name, value, is_local)
+                "set_config" if !last_argument_is_true(&tokens[idx + 1..]) => {
+                    self.add(SessionStateKind::Parameter)
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Given tokens starting at an opening parenthesis, test if the last argument
+/// of the call is literally `true`.
+fn last_argument_is_true(tokens: &[Token]) -> bool {
+    let mut depth = 0;
+    for (idx, token) in tokens.iter().enumerate() {
+        if token.is_symbol('(') {
+            depth += 1;
+        } else if token.is_symbol(')') {
+            depth -= 1;
+            if depth == 0 {
+                return idx > 0 && tokens[idx - 1].is_word("true");
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observe(query: &str) -> SessionState {
+        let mut state = SessionState::new();
+        state.observe_query(query);
+        state
+    }
+
+    #[test]
+    fn test_parameters() {
+        assert!(observe("SET search_path TO myschema").contains(SessionStateKind::Parameter));
+        assert!(observe("set session timezone = 'UTC'").contains(SessionStateKind::Parameter));
+        assert!(observe("SET LOCAL statement_timeout = 100").is_clean());
+        assert!(observe("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE").is_clean());
+        assert!(observe("SELECT set_config('a.b', 'c', false)").is_pinned());
+        assert!(observe("SELECT set_config('a.b', 'c', true)").is_clean());
+        assert!(observe("SET a.b = 1; RESET ALL").is_clean());
+        assert!(observe("SELECT 'SET x = 1'").is_clean());
+    }
+
+    #[test]
+    fn test_objects() {
+        assert!(observe("CREATE TEMP TABLE t (id int)").contains(SessionStateKind::TemporaryObject));
+        assert!(observe("create global temporary table t (id int)")
+            .contains(SessionStateKind::TemporaryObject));
+        assert!(
+            observe("SELECT * INTO TEMP t FROM users").contains(SessionStateKind::TemporaryObject)
+        );
+        assert!(observe("CREATE TABLE t (id int)").is_clean());
+        assert!(observe("PREPARE q AS SELECT 1").contains(SessionStateKind::SqlPreparedStatement));
+        assert!(observe("PREPARE TRANSACTION 'tx'").is_clean());
+        assert!(observe("LISTEN ch; SELECT pg_advisory_lock(1)").is_pinned());
+        assert!(observe("SELECT pg_advisory_xact_lock(1)").is_clean());
+        assert!(observe("DECLARE c CURSOR WITH HOLD FOR SELECT 1")
+            .contains(SessionStateKind::HoldableCursor));
+        assert!(observe("DECLARE c CURSOR FOR SELECT 1").is_clean());
+        assert!(observe("LISTEN ch; CREATE TEMP TABLE t(); DISCARD ALL").is_clean());
+    }
+
+    #[test]
+    fn test_pinning() {
+        let mut state = SessionState::new();
+        state.set_pinning(SessionStateKind::ProtocolPreparedStatement, false);
+        state.observe_parse(&Parse::new(
+            Some("s1".to_owned()),
+            "SELECT 1".to_owned(),
+            vec![],
+        ));
+        assert!(!state.is_pinned());
+        assert!(state.needs_reset());
+        assert_eq!(Some("DISCARD ALL"), state.reset_query());
+
+        state.observe_close(&Close::new(
+            TARGET_TYPE_BYTE_STATEMENT,
+            Some("s1".to_owned()),
+        ));
+        assert!(state.is_clean());
+
+        state.observe_query("SET application_name = 'x'");
+        assert!(state.is_pinned());
+        state.mark_reset();
+        assert!(state.is_clean());
+        assert_eq!(None, state.reset_query());
+    }
+}
@@ -0,0 +1,728 @@
+//! Runtime-agnostic protocol core.
+//!
+//! `ServerConnection` holds the read and write buffers of a backend
+//! connection and tracks its protocol state, without doing any I/O. Bytes
+//! received from client are passed in with `feed`, decoded messages are pulled
+//! out with `poll_message`, responses are queued with `send` and the pending
+//! bytes in `output` are written to the transport by the caller. This allows
+//! pgwire to be driven by any async runtime, a blocking thread per connection,
+//! a custom event loop or a test, without tokio.
+//!
+//! The tokio server in `pgwire::tokio` uses the same framing rules through
+//! `decode_frontend_message`.
+//!
+//! `decode_frontend_bytes`, `decode_backend_bytes` and their `_stream`
+//! variants decode plain byte slices, reporting the offset and type of a
+//! malformed message in `DecodeError`. They validate framing without an async
+//! stack, and are safe to fuzz: malformed input fails, never panics.
+
+use bytes::{Buf, BytesMut};
+
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, SqlState};
+use crate::messages::response::TransactionStatus;
+use crate::messages::startup::{CancelRequest, GssEncRequest, SslRequest, Startup};
+use crate::messages::{Message, PgWireBackendMessage, PgWireFrontendMessage};
+
+/// Protocol state of a backend connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PgWireConnectionState {
+    #[default]
+    AwaitingStartup,
+    AuthenticationInProgress,
+    ReadyForQuery,
+    QueryInProgress,
+    AwaitingSync,
+    /// Receiving data of `COPY FROM STDIN`, the flag is true if the copy is
+    /// started by extended query.
+    CopyInProgress(bool),
+}
+
+/// Postgres rejects startup packets larger than this.
+pub const MAX_STARTUP_PACKET_SIZE: usize = 10000;
+/// Postgres rejects messages larger than this, the maximum size of an
+/// allocation.
+pub const MAX_MESSAGE_SIZE: usize = 0x3fff_ffff;
+
+/// Limits on messages from client, `None` for unlimited.
+///
+/// Sizes of startup packets and messages are checked against their length
+/// header, so oversized messages are rejected before they are buffered, and
+/// the connection is closed with `08P01` (protocol_violation). Queries and
+/// parameters exceeding their limits fail with `54000`
+/// (program_limit_exceeded), and the session continues.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, new)]
+pub struct MessageLimits {
+    /// maximum size of startup packet, 10000 bytes by default like postgres
+    #[new(value = "Some(MAX_STARTUP_PACKET_SIZE)")]
+    pub max_startup_packet_size: Option<usize>,
+    /// maximum size of any message, 1GiB by default like postgres
+    #[new(value = "Some(MAX_MESSAGE_SIZE)")]
+    pub max_message_size: Option<usize>,
+    /// maximum length of query text of `Query` and `Parse` in bytes
+    #[new(default)]
+    pub max_query_length: Option<usize>,
+    /// maximum size of each parameter of `Bind`
+    #[new(default)]
+    pub max_parameter_size: Option<usize>,
+}
+
+impl Default for MessageLimits {
+    fn default() -> MessageLimits {
+        MessageLimits::new()
+    }
+}
+
+impl MessageLimits {
+    /// No limit at all.
+    pub fn unlimited() -> MessageLimits {
+        MessageLimits {
+            max_startup_packet_size: None,
+            max_message_size: None,
+            max_query_length: None,
+            max_parameter_size: None,
+        }
+    }
+
+    pub fn with_max_startup_packet_size(mut self, size: usize) -> MessageLimits {
+        self.max_startup_packet_size = Some(size);
+        self
+    }
+
+    pub fn with_max_message_size(mut self, size: usize) -> MessageLimits {
+        self.max_message_size = Some(size);
+        self
+    }
+
+    pub fn with_max_query_length(mut self, length: usize) -> MessageLimits {
+        self.max_query_length = Some(length);
+        self
+    }
+
+    pub fn with_max_parameter_size(mut self, size: usize) -> MessageLimits {
+        self.max_parameter_size = Some(size);
+        self
+    }
+
+    /// Check length header of the next message in `buf`.
+    pub(crate) fn check_length(
+        &self,
+        state: PgWireConnectionState,
+        buf: &[u8],
+    ) -> PgWireResult<()> {
+        let (offset, limit) = match state {
+            PgWireConnectionState::AwaitingStartup => (0, self.max_startup_packet_size),
+            _ => (1, self.max_message_size),
+        };
+        let (Some(limit), Some(header)) = (limit, buf.get(offset..offset + 4)) else {
+            return Ok(());
+        };
+        // a negative length is too large as well
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        if len > limit {
+            return Err(PgWireError::MessageTooLarge(len, limit));
+        }
+        Ok(())
+    }
+
+    /// Check query text and parameters of a decoded message.
+    pub fn check(&self, message: &PgWireFrontendMessage) -> PgWireResult<()> {
+        let query = match message {
+            PgWireFrontendMessage::Query(query) => Some(&query.query),
+            PgWireFrontendMessage::Parse(parse) => Some(&parse.query),
+            _ => None,
+        };
+        if let (Some(query), Some(limit)) = (query, self.max_query_length) {
+            if query.len() > limit {
+                return Err(limit_exceeded(format!(
+                    "query of {} bytes exceeds the limit of {limit} bytes",
+                    query.len()
+                )));
+            }
+        }
+
+        if let (PgWireFrontendMessage::Bind(bind), Some(limit)) = (message, self.max_parameter_size)
+        {
+            let oversized =
+                bind.parameters.iter().enumerate().find_map(|(i, param)| {
+                    Some((i, param.as_ref()?.len())).filter(|p| p.1 > limit)
+                });
+            if let Some((i, len)) = oversized {
+                return Err(limit_exceeded(format!(
+                    "parameter ${} of {len} bytes exceeds the limit of {limit} bytes",
+                    i + 1
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn limit_exceeded(message: String) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        SqlState::PROGRAM_LIMIT_EXCEEDED.into(),
+        message,
+    )))
+}
+
+/// Decode next frontend message from `buf` according to connection state.
+///
+/// Before startup, client sends untagged `SslRequest`, `GssEncRequest`,
+/// `CancelRequest` or `Startup` packets. Tagged messages are expected after that. Returns `None`
+/// if the buffer doesn't contain a complete message yet.
+pub fn decode_frontend_message(
+    state: PgWireConnectionState,
+    buf: &mut BytesMut,
+) -> PgWireResult<Option<PgWireFrontendMessage>> {
+    decode_frontend_message_with_limits(state, buf, &MessageLimits::default())
+}
+
+/// Like `decode_frontend_message`, failing with `MessageTooLarge` as soon as
+/// the length of next message exceeds `limits`.
+pub fn decode_frontend_message_with_limits(
+    state: PgWireConnectionState,
+    buf: &mut BytesMut,
+    limits: &MessageLimits,
+) -> PgWireResult<Option<PgWireFrontendMessage>> {
+    limits.check_length(state, buf)?;
+    match state {
+        PgWireConnectionState::AwaitingStartup => {
+            if let Some(request) = SslRequest::decode(buf)? {
+                return Ok(Some(PgWireFrontendMessage::SslRequest(request)));
+            }
+
+            if let Some(request) = GssEncRequest::decode(buf)? {
+                return Ok(Some(PgWireFrontendMessage::GssEncRequest(request)));
+            }
+
+            if let Some(request) = CancelRequest::decode(buf)? {
+                return Ok(Some(PgWireFrontendMessage::CancelRequest(request)));
+            }
+
+            if let Some(startup) = Startup::decode(buf)? {
+                return Ok(Some(PgWireFrontendMessage::Startup(startup)));
+            }
+
+            Ok(None)
+        }
+        _ => PgWireFrontendMessage::decode(buf),
+    }
+}
+
+/// Error of decoding messages from a byte slice, with position of the
+/// malformed message.
+#[derive(Debug)]
+pub struct DecodeError {
+    /// offset of the message in input
+    pub offset: usize,
+    /// type byte of the message, `None` for untagged startup packets
+    pub message_type: Option<u8>,
+    pub error: PgWireError,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.message_type {
+            Some(t) if t.is_ascii_graphic() => write!(f, "message '{}'", t as char)?,
+            Some(t) => write!(f, "message 0x{t:02x}")?,
+            None => write!(f, "startup packet")?,
+        }
+        write!(f, " at offset {}: {}", self.offset, self.error)
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Decode the frontend message at the start of `bytes`, returns it with the
+/// number of bytes it takes, or `None` if `bytes` doesn't hold a complete
+/// message yet.
+///
+/// Decoding never panics on malformed input, which makes it a target for
+/// fuzzing. Framing is checked strictly: a message whose fields overrun its
+/// length, or leave bytes unread, is an error.
+pub fn decode_frontend_bytes(
+    state: PgWireConnectionState,
+    bytes: &[u8],
+) -> Result<Option<(PgWireFrontendMessage, usize)>, DecodeError> {
+    let mut buf = BytesMut::from(bytes);
+    decode_next(&mut buf, 0, is_tagged(state), |buf| {
+        decode_frontend_message(state, buf)
+    })
+    .map(|message| message.map(|message| (message, bytes.len() - buf.len())))
+}
+
+/// Decode the backend message at the start of `bytes`, like
+/// `decode_frontend_bytes`. The single byte response of `SslRequest` is not
+/// framed, and not decoded here.
+pub fn decode_backend_bytes(
+    bytes: &[u8],
+) -> Result<Option<(PgWireBackendMessage, usize)>, DecodeError> {
+    let mut buf = BytesMut::from(bytes);
+    decode_next(&mut buf, 0, true, PgWireBackendMessage::decode)
+        .map(|message| message.map(|message| (message, bytes.len() - buf.len())))
+}
+
+/// Decode all messages sent by client, starting in connection `state`.
+/// Connection moves from `AwaitingStartup` to `AuthenticationInProgress` on
+/// `Startup`. An incomplete message at the end is an error.
+pub fn decode_frontend_stream(
+    mut state: PgWireConnectionState,
+    bytes: &[u8],
+) -> Result<Vec<PgWireFrontendMessage>, DecodeError> {
+    let mut buf = BytesMut::from(bytes);
+    let mut messages = Vec::new();
+    while buf.has_remaining() {
+        let offset = bytes.len() - buf.len();
+        let tagged = is_tagged(state);
+        let Some(message) = decode_next(&mut buf, offset, tagged, |buf| {
+            decode_frontend_message(state, buf)
+        })?
+        else {
+            return Err(incomplete(&buf, offset, tagged));
+        };
+        if let PgWireFrontendMessage::Startup(_) = message {
+            state = PgWireConnectionState::AuthenticationInProgress;
+        }
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+/// Decode all messages sent by server. An incomplete message at the end is
+/// an error.
+pub fn decode_backend_stream(bytes: &[u8]) -> Result<Vec<PgWireBackendMessage>, DecodeError> {
+    let mut buf = BytesMut::from(bytes);
+    let mut messages = Vec::new();
+    while buf.has_remaining() {
+        let offset = bytes.len() - buf.len();
+        let Some(message) = decode_next(&mut buf, offset, true, PgWireBackendMessage::decode)?
+        else {
+            return Err(incomplete(&buf, offset, true));
+        };
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+fn is_tagged(state: PgWireConnectionState) -> bool {
+    state != PgWireConnectionState::AwaitingStartup
+}
+
+fn decode_next<M>(
+    buf: &mut BytesMut,
+    offset: usize,
+    tagged: bool,
+    decode: impl FnOnce(&mut BytesMut) -> PgWireResult<Option<M>>,
+) -> Result<Option<M>, DecodeError> {
+    let message_type = buf.first().copied().filter(|_| tagged);
+    decode(buf).map_err(|error| DecodeError {
+        offset,
+        message_type,
+        error,
+    })
+}
+
+// error of a message cut off by end of input
+fn incomplete(buf: &BytesMut, offset: usize, tagged: bool) -> DecodeError {
+    let header_len = usize::from(tagged) + 4;
+    let message_len = match buf.get(header_len - 4..header_len) {
+        Some(len) => {
+            usize::from(tagged) + u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize
+        }
+        None => header_len,
+    };
+    DecodeError {
+        offset,
+        message_type: buf.first().copied().filter(|_| tagged),
+        error: PgWireError::IncompleteMessage(message_len.saturating_sub(buf.len()).max(1)),
+    }
+}
+
+/// Server side of a postgres connection, without I/O.
+#[derive(Debug, Default)]
+pub struct ServerConnection {
+    state: PgWireConnectionState,
+    // whether current query cycle is started by extended query messages
+    extended_query: bool,
+    closed: bool,
+    transaction_status: TransactionStatus,
+    limits: MessageLimits,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+}
+
+impl ServerConnection {
+    pub fn new() -> ServerConnection {
+        ServerConnection::default()
+    }
+
+    /// Create a connection that has received `initial_bytes` already, for
+    /// example the bytes peeked to detect protocol.
+    pub fn with_initial_bytes(initial_bytes: &[u8]) -> ServerConnection {
+        let mut conn = ServerConnection::new();
+        conn.feed(initial_bytes);
+        conn
+    }
+
+    pub fn state(&self) -> PgWireConnectionState {
+        self.state
+    }
+
+    /// Override the state, for example to enter `ReadyForQuery` after an
+    /// authentication handled outside of this connection.
+    pub fn set_state(&mut self, state: PgWireConnectionState) {
+        self.state = state;
+    }
+
+    /// Status of transaction to send in `ReadyForQuery`, tracked from the
+    /// messages sent like `ClientInfo::transaction_status`.
+    pub fn transaction_status(&self) -> TransactionStatus {
+        self.transaction_status
+    }
+
+    pub fn set_transaction_status(&mut self, status: TransactionStatus) {
+        self.transaction_status = status;
+    }
+
+    /// Limits on size of messages decoded by `poll_message`.
+    pub fn set_message_limits(&mut self, limits: MessageLimits) {
+        self.limits = limits;
+    }
+
+    /// Whether client has sent `Terminate`, or startup failed with an error.
+    /// The transport should be closed after writing remaining output.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Append bytes received from client.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.read_buf.extend_from_slice(data);
+    }
+
+    /// The read buffer, for reading from transport without an extra copy.
+    pub fn read_buf_mut(&mut self) -> &mut BytesMut {
+        &mut self.read_buf
+    }
+
+    /// Decode next complete message from received bytes.
+    ///
+    /// Returns `None` if more bytes are needed. Messages that postgres
+    /// discards, like anything but `Sync` after an error in extended query, or
+    /// `Flush` and `Sync` during `COPY FROM STDIN`, are skipped here.
+    pub fn poll_message(&mut self) -> PgWireResult<Option<PgWireFrontendMessage>> {
+        loop {
+            let Some(message) =
+                decode_frontend_message_with_limits(self.state, &mut self.read_buf, &self.limits)?
+            else {
+                return Ok(None);
+            };
+            if self.on_received(&message) {
+                return Ok(Some(message));
+            }
+        }
+    }
+
+    // update state with received message, return false if message should be
+    // discarded
+    fn on_received(&mut self, message: &PgWireFrontendMessage) -> bool {
+        if let PgWireFrontendMessage::Terminate(_) = message {
+            self.closed = true;
+            return true;
+        }
+
+        match self.state {
+            PgWireConnectionState::AwaitingStartup => {
+                if let PgWireFrontendMessage::Startup(_) = message {
+                    self.state = PgWireConnectionState::AuthenticationInProgress;
+                }
+                true
+            }
+            PgWireConnectionState::AuthenticationInProgress => true,
+            PgWireConnectionState::AwaitingSync => {
+                if let PgWireFrontendMessage::Sync(_) = message {
+                    self.state = PgWireConnectionState::QueryInProgress;
+                    true
+                } else {
+                    false
+                }
+            }
+            PgWireConnectionState::CopyInProgress(_) => match message {
+                PgWireFrontendMessage::Flush(_) | PgWireFrontendMessage::Sync(_) => false,
+                PgWireFrontendMessage::CopyDone(_) | PgWireFrontendMessage::CopyFail(_) => {
+                    self.state = PgWireConnectionState::QueryInProgress;
+                    true
+                }
+                _ => true,
+            },
+            PgWireConnectionState::ReadyForQuery | PgWireConnectionState::QueryInProgress => {
+                if let PgWireFrontendMessage::Query(_) = message {
+                    self.extended_query = false;
+                } else if message.is_extended_query() {
+                    self.extended_query = true;
+                }
+                self.state = PgWireConnectionState::QueryInProgress;
+                true
+            }
+        }
+    }
+
+    /// Encode a message into output buffer.
+    pub fn send(&mut self, message: PgWireBackendMessage) -> PgWireResult<()> {
+        message.encode(&mut self.write_buf)?;
+        self.on_sent(&message);
+        Ok(())
+    }
+
+    fn on_sent(&mut self, message: &PgWireBackendMessage) {
+        self.transaction_status = self.transaction_status.after_sent(message);
+        match message {
+            PgWireBackendMessage::ReadyForQuery(_) => {
+                self.state = PgWireConnectionState::ReadyForQuery;
+                self.extended_query = false;
+            }
+            PgWireBackendMessage::CopyInResponse(_) | PgWireBackendMessage::CopyBothResponse(_) => {
+                self.state = PgWireConnectionState::CopyInProgress(self.extended_query);
+            }
+            PgWireBackendMessage::ErrorResponse(_) => match self.state {
+                // errors during startup are fatal
+                PgWireConnectionState::AwaitingStartup
+                | PgWireConnectionState::AuthenticationInProgress => {
+                    self.closed = true;
+                }
+                PgWireConnectionState::QueryInProgress
+                | PgWireConnectionState::CopyInProgress(_)
+                    if self.extended_query =>
+                {
+                    self.state = PgWireConnectionState::AwaitingSync;
+                }
+                PgWireConnectionState::CopyInProgress(_) => {
+                    self.state = PgWireConnectionState::QueryInProgress;
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    /// Encoded bytes waiting to be written to transport.
+    pub fn output(&self) -> &[u8] {
+        &self.write_buf
+    }
+
+    /// Mark `n` bytes of `output` as written.
+    pub fn advance_output(&mut self, n: usize) {
+        self.write_buf.advance(n);
+    }
+
+    /// Take all pending output.
+    pub fn take_output(&mut self) -> BytesMut {
+        self.write_buf.split()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::extendedquery::{Bind, Execute, Parse, ParseComplete, Sync};
+    use crate::messages::response::{ErrorResponse, ReadyForQuery, TransactionStatus};
+    use crate::messages::simplequery::Query;
+    use crate::messages::startup::Authentication;
+    use crate::messages::terminate::Terminate;
+
+    fn encode(messages: Vec<PgWireFrontendMessage>) -> BytesMut {
+        let mut buf = BytesMut::new();
+        for message in messages {
+            message.encode(&mut buf).unwrap();
+        }
+        buf
+    }
+
+    fn decode_output(conn: &mut ServerConnection) -> Vec<PgWireBackendMessage> {
+        let mut buf = conn.take_output();
+        let mut messages = Vec::new();
+        while let Some(message) = PgWireBackendMessage::decode(&mut buf).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    #[test]
+    fn test_decode_bytes() {
+        let bytes = b"Q\0\0\0\x0dSELECT 1\0S\0\0";
+        let state = PgWireConnectionState::ReadyForQuery;
+        let (message, len) = decode_frontend_bytes(state, bytes).unwrap().unwrap();
+        assert!(matches!(message, PgWireFrontendMessage::Query(_)));
+        assert_eq!(14, len);
+        assert!(decode_frontend_bytes(state, &bytes[len..])
+            .unwrap()
+            .is_none());
+
+        let error = decode_frontend_stream(state, bytes).unwrap_err();
+        assert_eq!(14, error.offset);
+        assert_eq!(Some(b'S'), error.message_type);
+        assert!(matches!(error.error, PgWireError::IncompleteMessage(2)));
+
+        let error = decode_backend_bytes(b"Z\0\0\0\x05x").unwrap_err();
+        assert_eq!(
+            "message 'Z' at offset 0: Invalid transaction status, received 120",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn test_simple_query_flow() {
+        let mut conn = ServerConnection::new();
+        let startup = encode(vec![PgWireFrontendMessage::Startup(Startup::new())]);
+
+        // partial packet
+        conn.feed(&startup[..5]);
+        assert!(conn.poll_message().unwrap().is_none());
+        conn.feed(&startup[5..]);
+        assert!(matches!(
+            conn.poll_message().unwrap(),
+            Some(PgWireFrontendMessage::Startup(_))
+        ));
+        assert_eq!(
+            conn.state(),
+            PgWireConnectionState::AuthenticationInProgress
+        );
+
+        conn.send(PgWireBackendMessage::Authentication(Authentication::Ok))
+            .unwrap();
+        conn.send(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+            TransactionStatus::Idle,
+        )))
+        .unwrap();
+        assert_eq!(conn.state(), PgWireConnectionState::ReadyForQuery);
+        let sent = decode_output(&mut conn);
+        assert!(matches!(
+            sent[..],
+            [
+                PgWireBackendMessage::Authentication(Authentication::Ok),
+                PgWireBackendMessage::ReadyForQuery(_)
+            ]
+        ));
+        assert!(conn.output().is_empty());
+
+        conn.feed(&encode(vec![
+            PgWireFrontendMessage::Query(Query::new("SELECT 1".to_owned())),
+            PgWireFrontendMessage::Terminate(Terminate::new()),
+        ]));
+        assert!(matches!(
+            conn.poll_message().unwrap(),
+            Some(PgWireFrontendMessage::Query(q)) if q.query == "SELECT 1"
+        ));
+        assert_eq!(conn.state(), PgWireConnectionState::QueryInProgress);
+        assert!(!conn.is_closed());
+        assert!(matches!(
+            conn.poll_message().unwrap(),
+            Some(PgWireFrontendMessage::Terminate(_))
+        ));
+        assert!(conn.is_closed());
+    }
+
+    #[test]
+    fn test_extended_query_error_awaits_sync() {
+        let mut conn = ServerConnection::new();
+        conn.set_state(PgWireConnectionState::ReadyForQuery);
+        conn.feed(&encode(vec![
+            PgWireFrontendMessage::Parse(Parse::new(None, "SELECT 1".to_owned(), vec![])),
+            PgWireFrontendMessage::Bind(Bind::new(None, None, vec![], vec![], vec![])),
+            PgWireFrontendMessage::Execute(Execute::new(None, 0)),
+            PgWireFrontendMessage::Sync(Sync::new()),
+        ]));
+
+        assert!(matches!(
+            conn.poll_message().unwrap(),
+            Some(PgWireFrontendMessage::Parse(_))
+        ));
+        conn.send(PgWireBackendMessage::ParseComplete(ParseComplete::new()))
+            .unwrap();
+        assert!(matches!(
+            conn.poll_message().unwrap(),
+            Some(PgWireFrontendMessage::Bind(_))
+        ));
+        conn.send(PgWireBackendMessage::ErrorResponse(ErrorResponse::default()))
+            .unwrap();
+        assert_eq!(conn.state(), PgWireConnectionState::AwaitingSync);
+
+        // Execute is discarded
+        assert!(matches!(
+            conn.poll_message().unwrap(),
+            Some(PgWireFrontendMessage::Sync(_))
+        ));
+        conn.send(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+            TransactionStatus::Idle,
+        )))
+        .unwrap();
+        assert_eq!(conn.state(), PgWireConnectionState::ReadyForQuery);
+        assert!(conn.poll_message().unwrap().is_none());
+        assert!(!conn.is_closed());
+    }
+
+    #[test]
+    fn test_message_limits() {
+        let mut conn = ServerConnection::new();
+        let mut startup = Startup::new();
+        startup
+            .parameters
+            .insert("options".to_owned(), "x".repeat(MAX_STARTUP_PACKET_SIZE));
+        // rejected as soon as the length is received
+        let startup = encode(vec![PgWireFrontendMessage::Startup(startup)]);
+        conn.feed(&startup[..4]);
+        assert!(matches!(
+            conn.poll_message(),
+            Err(PgWireError::MessageTooLarge(_, MAX_STARTUP_PACKET_SIZE))
+        ));
+
+        let limits = MessageLimits::new()
+            .with_max_message_size(64)
+            .with_max_query_length(8)
+            .with_max_parameter_size(4);
+        let mut conn = ServerConnection::new();
+        conn.set_message_limits(limits);
+        conn.set_state(PgWireConnectionState::ReadyForQuery);
+        conn.feed(&[b'Q', 0, 0, 1, 0]);
+        assert!(matches!(
+            conn.poll_message(),
+            Err(PgWireError::MessageTooLarge(256, 64))
+        ));
+
+        let Err(PgWireError::UserError(error)) = limits.check(&PgWireFrontendMessage::Query(
+            Query::new("SELECT 123".to_owned()),
+        )) else {
+            panic!("expected query to exceed limit");
+        };
+        assert_eq!("54000", error.code);
+        assert!(limits
+            .check(&PgWireFrontendMessage::Query(Query::new(
+                "SELECT 1".to_owned()
+            )))
+            .is_ok());
+
+        let bind = |param: &'static [u8]| {
+            PgWireFrontendMessage::Bind(Bind::new(
+                None,
+                None,
+                vec![],
+                vec![None, Some(param.into())],
+                vec![],
+            ))
+        };
+        let Err(PgWireError::UserError(error)) = limits.check(&bind(b"12345")) else {
+            panic!("expected parameter to exceed limit");
+        };
+        assert_eq!(
+            "parameter $2 of 5 bytes exceeds the limit of 4 bytes",
+            error.message
+        );
+        assert!(limits.check(&bind(b"1234")).is_ok());
+        assert!(MessageLimits::unlimited().check(&bind(b"12345")).is_ok());
+    }
+}
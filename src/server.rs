@@ -0,0 +1,359 @@
+//! High-level server, accepting tcp connections and serving them with
+//! handlers made for each connection.
+//!
+//! `PgWireServerBuilder` binds the listener, accepts connections until
+//! shutdown is requested, then waits for the open connections to close.
+//! Connection limits, timeouts and other settings are taken from
+//! `ServerOptions`:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use pgwire::api::auth::noop::NoopStartupHandler;
+//! use pgwire::api::query::PlaceholderExtendedQueryHandler;
+//! use pgwire::api::StatelessMakeHandler;
+//! use pgwire::server::PgWireServerBuilder;
+//! # use pgwire::api::query::SimpleQueryHandler;
+//!
+//! # async fn run<P: SimpleQueryHandler + 'static>(processor: Arc<P>) -> std::io::Result<()> {
+//! PgWireServerBuilder::new(
+//!     StatelessMakeHandler::new(Arc::new(NoopStartupHandler)),
+//!     StatelessMakeHandler::new(processor),
+//!     StatelessMakeHandler::new(Arc::new(PlaceholderExtendedQueryHandler)),
+//! )
+//! .with_address("127.0.0.1:5432")
+//! .serve()
+//! .await
+//! # }
+//! ```
+//!
+//! Use `pgwire::tokio::process_socket` and its variants to write the accept
+//! loop by hand.
+
+use std::fmt::Debug;
+use std::future::Future;
+use std::io::Error as IOError;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use futures::future::{select, Either};
+use futures::pin_mut;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+use crate::api::auth::StartupHandler;
+use crate::api::copy::{CopyHandler, NoopCopyHandler};
+use crate::api::query::{ExtendedQueryHandler, SimpleQueryHandler};
+use crate::api::shutdown::GracefulShutdown;
+use crate::api::{MakeHandler, StatelessMakeHandler};
+use crate::tokio::{process_socket_with_tls, Handlers, ServerOptions, TlsUpgrade};
+
+/// Address listened to when none is given.
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:5432";
+
+/// Time to wait for connections to close on shutdown, before interrupting
+/// their queries.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// pause after failing to accept, like running out of file descriptors
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+type ShutdownSignal = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Builder of a server, completed by `serve`.
+///
+/// Handlers are given as `MakeHandler` factories, called for each accepted
+/// connection.
+pub struct PgWireServerBuilder<
+    A,
+    Q,
+    EQ,
+    CH = StatelessMakeHandler<NoopCopyHandler>,
+    T = TlsAcceptor,
+> {
+    startup_handler: A,
+    query_handler: Q,
+    extended_query_handler: EQ,
+    copy_handler: CH,
+    address: String,
+    listener: Option<TcpListener>,
+    tls: Option<Arc<T>>,
+    options: ServerOptions,
+    shutdown_timeout: Duration,
+    shutdown_signal: Option<ShutdownSignal>,
+}
+
+impl<A, Q, EQ, CH, T> Debug for PgWireServerBuilder<A, Q, EQ, CH, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PgWireServerBuilder")
+            .field("address", &self.address)
+            .field("listener", &self.listener)
+            .field("tls", &self.tls.is_some())
+            .field("options", &self.options)
+            .field("shutdown_timeout", &self.shutdown_timeout)
+            .field("shutdown_signal", &self.shutdown_signal.is_some())
+            .finish()
+    }
+}
+
+impl<A, Q, EQ> PgWireServerBuilder<A, Q, EQ> {
+    /// Create a builder with handler factories of startup, simple query and
+    /// extended query. COPY is refused until `with_copy_handler`.
+    pub fn new(
+        startup_handler: A,
+        query_handler: Q,
+        extended_query_handler: EQ,
+    ) -> PgWireServerBuilder<A, Q, EQ> {
+        PgWireServerBuilder {
+            startup_handler,
+            query_handler,
+            extended_query_handler,
+            copy_handler: StatelessMakeHandler::new(Arc::new(NoopCopyHandler)),
+            address: DEFAULT_ADDRESS.to_owned(),
+            listener: None,
+            tls: None,
+            options: ServerOptions::default(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            shutdown_signal: None,
+        }
+    }
+}
+
+impl<A, Q, EQ, CH, T> PgWireServerBuilder<A, Q, EQ, CH, T> {
+    pub fn with_copy_handler<CH2>(
+        self,
+        copy_handler: CH2,
+    ) -> PgWireServerBuilder<A, Q, EQ, CH2, T> {
+        PgWireServerBuilder {
+            startup_handler: self.startup_handler,
+            query_handler: self.query_handler,
+            extended_query_handler: self.extended_query_handler,
+            copy_handler,
+            address: self.address,
+            listener: self.listener,
+            tls: self.tls,
+            options: self.options,
+            shutdown_timeout: self.shutdown_timeout,
+            shutdown_signal: self.shutdown_signal,
+        }
+    }
+
+    /// Upgrade connections requesting ssl with `tls`, like a
+    /// `TlsAcceptor` or a `ReloadableTlsAcceptor`.
+    pub fn with_tls<T2>(self, tls: Arc<T2>) -> PgWireServerBuilder<A, Q, EQ, CH, T2> {
+        PgWireServerBuilder {
+            startup_handler: self.startup_handler,
+            query_handler: self.query_handler,
+            extended_query_handler: self.extended_query_handler,
+            copy_handler: self.copy_handler,
+            address: self.address,
+            listener: self.listener,
+            tls: Some(tls),
+            options: self.options,
+            shutdown_timeout: self.shutdown_timeout,
+            shutdown_signal: self.shutdown_signal,
+        }
+    }
+
+    /// Address to bind, like `0.0.0.0:5432`.
+    pub fn with_address(mut self, address: impl Into<String>) -> Self {
+        self.address = address.into();
+        self
+    }
+
+    /// Accept from a bound listener, instead of binding the address.
+    pub fn with_listener(mut self, listener: TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    pub fn with_options(mut self, options: ServerOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Time to wait for connections to close after shutdown is requested,
+    /// before interrupting their queries.
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Request shutdown when `signal` resolves, like `tokio::signal::ctrl_c`.
+    /// Shutdown can also be requested with the `GracefulShutdown` of
+    /// `ServerOptions`.
+    pub fn with_shutdown_signal<F>(mut self, signal: F) -> Self
+    where
+        F: Future + Send + 'static,
+    {
+        self.shutdown_signal = Some(Box::pin(async move {
+            signal.await;
+        }));
+        self
+    }
+}
+
+impl<A, Q, EQ, CH, T, AH, QH, EQH, CHH> PgWireServerBuilder<A, Q, EQ, CH, T>
+where
+    A: MakeHandler<Handler = Arc<AH>>,
+    Q: MakeHandler<Handler = Arc<QH>>,
+    EQ: MakeHandler<Handler = Arc<EQH>>,
+    CH: MakeHandler<Handler = Arc<CHH>>,
+    T: TlsUpgrade + 'static,
+    AH: StartupHandler + 'static,
+    QH: SimpleQueryHandler + 'static,
+    EQH: ExtendedQueryHandler + 'static,
+    CHH: CopyHandler + 'static,
+{
+    /// Bind the listener and serve connections until shutdown is requested,
+    /// then wait for the connections to close. Returns an error if the
+    /// address can't be bound.
+    pub async fn serve(self) -> Result<(), IOError> {
+        let listener = match self.listener {
+            Some(listener) => listener,
+            None => TcpListener::bind(&self.address).await?,
+        };
+        #[cfg(feature = "tracing")]
+        if let Ok(addr) = listener.local_addr() {
+            tracing::info!(%addr, "listening");
+        }
+
+        let mut options = self.options;
+        let shutdown = options
+            .graceful_shutdown
+            .get_or_insert_with(GracefulShutdown::new)
+            .clone();
+        let options = Arc::new(options);
+
+        if let Some(signal) = self.shutdown_signal {
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                signal.await;
+                shutdown.request();
+            });
+        }
+
+        loop {
+            let requested = shutdown.requested();
+            let accept = listener.accept();
+            pin_mut!(requested);
+            pin_mut!(accept);
+            let accepted = match select(requested, accept).await {
+                Either::Left(_) => break,
+                Either::Right((accepted, _)) => accepted,
+            };
+
+            let socket = match accepted {
+                Ok((socket, _)) => socket,
+                Err(_e) => {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(error = %_e, "failed to accept connection");
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+
+            let serve = process_socket_with_tls(
+                socket,
+                BytesMut::new(),
+                self.tls.clone(),
+                options.clone(),
+                Handlers::new(
+                    self.startup_handler.make(),
+                    self.query_handler.make(),
+                    self.extended_query_handler.make(),
+                )
+                .with_copy_handler(self.copy_handler.make()),
+            );
+            tokio::spawn(async move {
+                if let Err(_e) = serve.await {
+                    #[cfg(feature = "tracing")]
+                    tracing::debug!(error = %_e, "connection closed with error");
+                }
+            });
+        }
+
+        drop(listener);
+        shutdown.shutdown(self.shutdown_timeout).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::auth::noop::NoopStartupHandler;
+    use crate::api::query::PlaceholderExtendedQueryHandler;
+    use crate::api::results::{Response, Tag};
+    use crate::api::ClientInfo;
+    use crate::error::{PgWireError, PgWireResult};
+    use crate::messages::PgWireBackendMessage;
+    use async_trait::async_trait;
+    use futures::Sink;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    struct EmptyQuery;
+
+    #[async_trait]
+    impl SimpleQueryHandler for EmptyQuery {
+        async fn do_query<'a, C>(
+            &self,
+            _client: &mut C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            Ok(vec![Response::Execution(Tag::new("OK"))])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = GracefulShutdown::new();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+
+        let server = PgWireServerBuilder::new(
+            StatelessMakeHandler::new(Arc::new(NoopStartupHandler)),
+            StatelessMakeHandler::new(Arc::new(EmptyQuery)),
+            StatelessMakeHandler::new(Arc::new(PlaceholderExtendedQueryHandler)),
+        )
+        .with_listener(listener)
+        .with_options(ServerOptions::new().with_graceful_shutdown(shutdown.clone()))
+        .with_shutdown_timeout(Duration::from_secs(5))
+        .with_shutdown_signal(stopped);
+        let server = tokio::spawn(server.serve());
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"\0\0\0\x14\0\x03\0\0user\0alice\0\0")
+            .await
+            .unwrap();
+        // read until ReadyForQuery
+        let mut received = Vec::new();
+        while !received.ends_with(b"Z\0\0\0\x05I") {
+            let mut buf = [0u8; 1024];
+            let n = client.read(&mut buf).await.unwrap();
+            assert!(n > 0);
+            received.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(1, shutdown.connections());
+
+        // idle session is terminated on shutdown
+        stop.send(()).unwrap();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.windows(5).any(|w| w == b"57P01"));
+
+        server.await.unwrap().unwrap();
+        assert_eq!(0, shutdown.connections());
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+}
@@ -0,0 +1,307 @@
+//! A tiny SQL lexer for recognizing statement boundaries and leading keywords.
+//!
+//! This is not a parser. It only understands enough of postgres lexical
+//! structure (comments, string literals, quoted identifiers and dollar-quoted
+//! strings) to split a query string into statements and look at the words in
+//! them without being fooled by a `;` or keyword inside a literal.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TokenKind {
+    /// keyword or unquoted identifier
+    Word,
+    /// double-quoted identifier, the text contains the quotes
+    QuotedIdent,
+    /// string literal, dollar-quoted string or number
+    Literal,
+    /// positional parameter like `$1`
+    Param,
+    /// any other single character
+    Symbol,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Token<'a> {
+    pub(crate) kind: TokenKind,
+    pub(crate) text: &'a str,
+}
+
+impl<'a> Token<'a> {
+    /// Test if this token is the given keyword, case-insensitively.
+    pub(crate) fn is_word(&self, word: &str) -> bool {
+        self.kind == TokenKind::Word && self.text.eq_ignore_ascii_case(word)
+    }
+
+    /// Test if this token is the given symbol.
+    pub(crate) fn is_symbol(&self, c: char) -> bool {
+        self.kind == TokenKind::Symbol
+            && self.text.len() == c.len_utf8()
+            && self.text.starts_with(c)
+    }
+}
+
+/// Iterator of tokens in a query string. Whitespaces and comments are skipped.
+#[derive(Debug)]
+pub(crate) struct Lexer<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Lexer<'a> {
+    pub(crate) fn new(input: &'a str) -> Lexer<'a> {
+        Lexer { input, pos: 0 }
+    }
+
+    /// Current byte offset of the lexer.
+    pub(crate) fn offset(&self) -> usize {
+        self.pos
+    }
+
+    fn peek_byte(&self, ahead: usize) -> Option<u8> {
+        self.input.as_bytes().get(self.pos + ahead).copied()
+    }
+
+    fn skip_whitespace_and_comments(&mut self) {
+        let bytes = self.input.as_bytes();
+        loop {
+            match self.peek_byte(0) {
+                Some(b) if b.is_ascii_whitespace() => self.pos += 1,
+                Some(b'-') if self.peek_byte(1) == Some(b'-') => {
+                    while self.pos < bytes.len() && bytes[self.pos] != b'\n' {
+                        self.pos += 1;
+                    }
+                }
+                Some(b'/') if self.peek_byte(1) == Some(b'*') => {
+                    // block comments can be nested in postgres
+                    let mut depth = 0;
+                    while self.pos < bytes.len() {
+                        if bytes[self.pos] == b'/' && self.peek_byte(1) == Some(b'*') {
+                            depth += 1;
+                            self.pos += 2;
+                        } else if bytes[self.pos] == b'*' && self.peek_byte(1) == Some(b'/') {
+                            depth -= 1;
+                            self.pos += 2;
+                            if depth == 0 {
+                                break;
+                            }
+                        } else {
+                            self.pos += 1;
+                        }
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Consume a quoted section starting at current position, `quote` is
+    /// escaped by doubling it. With `backslash` set, `\` escapes the next
+    /// character as in `E'...'` strings.
+    fn consume_quoted(&mut self, quote: u8, backslash: bool) {
+        let bytes = self.input.as_bytes();
+        // opening quote
+        self.pos += 1;
+        while self.pos < bytes.len() {
+            let b = bytes[self.pos];
+            if backslash && b == b'\\' {
+                self.pos += 2;
+            } else if b == quote {
+                if self.peek_byte(1) == Some(quote) {
+                    self.pos += 2;
+                } else {
+                    self.pos += 1;
+                    return;
+                }
+            } else {
+                self.pos += 1;
+            }
+        }
+        self.pos = bytes.len();
+    }
+
+    /// Try to consume a dollar-quoted string. Returns false if the `$` at
+    /// current position doesn't open one.
+    fn consume_dollar_quoted(&mut self) -> bool {
+        let rest = &self.input[self.pos..];
+        let tag_end = rest[1..]
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .map(|i| i + 1);
+        let Some(tag_end) = tag_end else {
+            return false;
+        };
+        if !rest[tag_end..].starts_with('$') || rest[1..].starts_with(|c: char| c.is_ascii_digit())
+        {
+            return false;
+        }
+        let tag = &rest[..=tag_end];
+        match rest[tag.len()..].find(tag) {
+            Some(end) => self.pos += tag.len() + end + tag.len(),
+            None => self.pos = self.input.len(),
+        }
+        true
+    }
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.skip_whitespace_and_comments();
+
+        let start = self.pos;
+        let c = self.input[start..].chars().next()?;
+
+        let kind = match c {
+            '\'' => {
+                self.consume_quoted(b'\'', false);
+                TokenKind::Literal
+            }
+            'e' | 'E' if self.peek_byte(1) == Some(b'\'') => {
+                self.pos += 1;
+                self.consume_quoted(b'\'', true);
+                TokenKind::Literal
+            }
+            '"' => {
+                self.consume_quoted(b'"', false);
+                TokenKind::QuotedIdent
+            }
+            '$' if self.peek_byte(1).is_some_and(|b| b.is_ascii_digit()) => {
+                self.pos += 1;
+                while self.peek_byte(0).is_some_and(|b| b.is_ascii_digit()) {
+                    self.pos += 1;
+                }
+                TokenKind::Param
+            }
+            '$' if self.consume_dollar_quoted() => TokenKind::Literal,
+            c if is_ident_start(c) => {
+                let len = self.input[start..]
+                    .find(|c: char| !is_ident_char(c))
+                    .unwrap_or(self.input.len() - start);
+                self.pos += len;
+                TokenKind::Word
+            }
+            c if c.is_ascii_digit() => {
+                while self
+                    .peek_byte(0)
+                    .is_some_and(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'_')
+                {
+                    self.pos += 1;
+                }
+                TokenKind::Literal
+            }
+            c => {
+                self.pos += c.len_utf8();
+                TokenKind::Symbol
+            }
+        };
+
+        Some(Token {
+            kind,
+            text: &self.input[start..self.pos],
+        })
+    }
+}
+
+/// Split query string into statements by `;`, ignoring those inside literals
+/// and comments. Returned statements are trimmed and empty ones are skipped.
+pub(crate) fn split_statements(query: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let mut lexer = Lexer::new(query);
+    let mut start = 0;
+
+    while let Some(token) = lexer.next() {
+        if token.is_symbol(';') {
+            let end = lexer.offset() - 1;
+            push_statement(&mut statements, &query[start..end]);
+            start = lexer.offset();
+        }
+    }
+    push_statement(&mut statements, &query[start..]);
+
+    statements
+}
+
+fn push_statement<'a>(statements: &mut Vec<&'a str>, statement: &'a str) {
+    let statement = statement.trim();
+    if Lexer::new(statement).next().is_some() {
+        statements.push(statement);
+    }
+}
+
+/// Number of positional parameters in a query, the largest `$n` in it.
+pub(crate) fn parameter_count(query: &str) -> usize {
+    Lexer::new(query)
+        .filter(|token| token.kind == TokenKind::Param)
+        .filter_map(|token| token.text[1..].parse::<usize>().ok())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Collect tokens of a statement.
+pub(crate) fn tokenize(statement: &str) -> Vec<Token<'_>> {
+    Lexer::new(statement).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_statements() {
+        assert_eq!(
+            vec!["SELECT 1", "SELECT 2"],
+            split_statements("SELECT 1; SELECT 2;")
+        );
+        assert_eq!(
+            vec!["SELECT ';'", "SELECT \"a;b\""],
+            split_statements("SELECT ';'; SELECT \"a;b\"")
+        );
+        assert_eq!(
+            vec!["SELECT $$a;b$$", "SELECT $fn$ $$; $fn$"],
+            split_statements("SELECT $$a;b$$; SELECT $fn$ $$; $fn$")
+        );
+        assert_eq!(vec!["SELECT E'\\';'"], split_statements("SELECT E'\\';'"));
+        assert_eq!(
+            vec!["SELECT 1 -- ;", "/* ; /* ; */ */ SELECT 2"],
+            split_statements("SELECT 1 -- ;\n; /* ; /* ; */ */ SELECT 2")
+        );
+        assert!(split_statements(" ; -- nothing\n;").is_empty());
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let tokens = tokenize("select pg_advisory_lock($1) /* x */ from \"T\" where a = 'b'");
+        let kinds = tokens.iter().map(|t| t.kind).collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                TokenKind::Word,
+                TokenKind::Word,
+                TokenKind::Symbol,
+                TokenKind::Param,
+                TokenKind::Symbol,
+                TokenKind::Word,
+                TokenKind::QuotedIdent,
+                TokenKind::Word,
+                TokenKind::Word,
+                TokenKind::Symbol,
+                TokenKind::Literal,
+            ],
+            kinds
+        );
+        assert!(tokens[0].is_word("SELECT"));
+        assert!(tokens[2].is_symbol('('));
+    }
+
+    #[test]
+    fn test_parameter_count() {
+        assert_eq!(0, parameter_count("SELECT '$1'"));
+        assert_eq!(3, parameter_count("SELECT $1, $3 -- $4\n"));
+    }
+}
@@ -0,0 +1,655 @@
+//! In-memory client for testing handlers.
+//!
+//! `TestClient` serves a connection with `process_stream` over an in-memory
+//! duplex stream, and speaks the protocol to it like a driver does: startup
+//! with cleartext or md5 password, simple query and extended query. Rows and
+//! errors are returned decoded, so handlers can be unit-tested without
+//! listening on ports or using external drivers.
+//!
+//! ```ignore
+//! let mut client = TestClientBuilder::new()
+//!     .with_user("alice")
+//!     .with_password("secret")
+//!     .connect(startup_handler, query_handler, extended_query_handler)
+//!     .await?;
+//! let results = client.simple_query("SELECT 1").await?;
+//! assert_eq!(vec![vec![Some("1".to_owned())]], results[0].text_rows());
+//! client.close().await?;
+//! ```
+//!
+//! SASL authentication and `COPY FROM STDIN` are not supported by the
+//! high-level methods, drive them with `send` and `receive` instead.
+
+use std::collections::BTreeMap;
+use std::io::{Error as IOError, ErrorKind};
+use std::mem;
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+
+use crate::api::auth::md5pass::hash_md5_password;
+use crate::api::auth::StartupHandler;
+use crate::api::copy::CopyHandler;
+use crate::api::query::{ExtendedQueryHandler, SimpleQueryHandler};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+use crate::messages::copy::CopyFail;
+use crate::messages::data::{DataRow, FieldDescription};
+use crate::messages::extendedquery::{Bind, Describe, Execute, Parse, Sync as PgSync};
+use crate::messages::response::{NotificationResponse, TransactionStatus};
+use crate::messages::simplequery::Query;
+use crate::messages::startup::{Authentication, Password, PasswordMessageFamily, Startup};
+use crate::messages::terminate::Terminate;
+use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+use crate::tokio::{process_stream, Handlers, ServerOptions};
+
+const DUPLEX_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Startup parameters, password and server options of a `TestClient`.
+#[derive(Debug)]
+pub struct TestClientBuilder {
+    parameters: BTreeMap<String, String>,
+    password: Option<String>,
+    options: ServerOptions,
+}
+
+impl Default for TestClientBuilder {
+    fn default() -> TestClientBuilder {
+        TestClientBuilder::new()
+    }
+}
+
+impl TestClientBuilder {
+    /// Builder of clients connecting as user `postgres`, with default server
+    /// options.
+    pub fn new() -> TestClientBuilder {
+        let mut parameters = BTreeMap::new();
+        parameters.insert("user".to_owned(), "postgres".to_owned());
+        TestClientBuilder {
+            parameters,
+            password: None,
+            options: ServerOptions::default(),
+        }
+    }
+
+    pub fn with_user(self, user: &str) -> TestClientBuilder {
+        self.with_parameter("user", user)
+    }
+
+    pub fn with_database(self, database: &str) -> TestClientBuilder {
+        self.with_parameter("database", database)
+    }
+
+    /// Startup parameter like `application_name` or `options`.
+    pub fn with_parameter(mut self, name: &str, value: &str) -> TestClientBuilder {
+        self.parameters.insert(name.to_owned(), value.to_owned());
+        self
+    }
+
+    /// Password sent when server asks for cleartext or md5 password.
+    pub fn with_password(mut self, password: &str) -> TestClientBuilder {
+        self.password = Some(password.to_owned());
+        self
+    }
+
+    /// Options of the server side of connection.
+    pub fn with_options(mut self, options: ServerOptions) -> TestClientBuilder {
+        self.options = options;
+        self
+    }
+
+    /// Serve a connection with handlers, and start it up. An error sent by
+    /// server during startup, like failed authentication, is returned as
+    /// `PgWireError::UserError`.
+    pub async fn connect<A, Q, EQ>(
+        self,
+        startup_handler: Arc<A>,
+        query_handler: Arc<Q>,
+        extended_query_handler: Arc<EQ>,
+    ) -> PgWireResult<TestClient>
+    where
+        A: StartupHandler + 'static,
+        Q: SimpleQueryHandler + 'static,
+        EQ: ExtendedQueryHandler + 'static,
+    {
+        self.connect_with_handlers(Handlers::new(
+            startup_handler,
+            query_handler,
+            extended_query_handler,
+        ))
+        .await
+    }
+
+    /// Like `connect`, with `Handlers` which may include a copy handler.
+    pub async fn connect_with_handlers<A, Q, EQ, CH>(
+        self,
+        handlers: Handlers<A, Q, EQ, CH>,
+    ) -> PgWireResult<TestClient>
+    where
+        A: StartupHandler + 'static,
+        Q: SimpleQueryHandler + 'static,
+        EQ: ExtendedQueryHandler + 'static,
+        CH: CopyHandler + 'static,
+    {
+        let (stream, server_stream) = tokio::io::duplex(DUPLEX_BUFFER_SIZE);
+        let server = tokio::spawn(process_stream(
+            server_stream,
+            "127.0.0.1:0".parse().unwrap(),
+            BytesMut::new(),
+            None::<Arc<TlsAcceptor>>,
+            Arc::new(self.options),
+            handlers,
+        ));
+
+        let mut client = TestClient {
+            stream,
+            buf: BytesMut::new(),
+            server,
+            parameters: BTreeMap::new(),
+            backend_key: None,
+            transaction_status: TransactionStatus::Idle,
+            notices: Vec::new(),
+            notifications: Vec::new(),
+        };
+
+        let user = self.parameters.get("user").cloned().unwrap_or_default();
+        let password = self.password.unwrap_or_default();
+        let mut startup = Startup::new();
+        startup.parameters = self.parameters;
+        client.send(PgWireFrontendMessage::Startup(startup)).await?;
+
+        loop {
+            match client.next_message().await? {
+                PgWireBackendMessage::Authentication(Authentication::Ok) => {}
+                PgWireBackendMessage::Authentication(Authentication::CleartextPassword) => {
+                    client.send_password(password.clone()).await?;
+                }
+                PgWireBackendMessage::Authentication(Authentication::MD5Password(salt)) => {
+                    let hashed = hash_md5_password(&user, &password, &salt);
+                    client.send_password(hashed).await?;
+                }
+                PgWireBackendMessage::Authentication(authentication) => {
+                    return Err(PgWireError::ApiError(
+                        format!("{authentication:?} is not supported by TestClient").into(),
+                    ));
+                }
+                PgWireBackendMessage::ErrorResponse(error) => {
+                    return Err(PgWireError::UserError(Box::new(error.into())));
+                }
+                PgWireBackendMessage::ReadyForQuery(ready) => {
+                    client.transaction_status = ready.status;
+                    return Ok(client);
+                }
+                message => client.observe(message)?,
+            }
+        }
+    }
+}
+
+/// Result of a statement.
+#[derive(Debug, Default)]
+pub struct QueryResult {
+    /// columns of rows, empty if the statement is described with `NoData`
+    pub fields: Vec<FieldDescription>,
+    /// values of columns, `None` for null
+    pub rows: Vec<Vec<Option<Bytes>>>,
+    /// command tag, like `SELECT 1`
+    pub tag: String,
+}
+
+impl QueryResult {
+    pub fn column_names(&self) -> Vec<&str> {
+        self.fields
+            .iter()
+            .map(|field| field.name.as_str())
+            .collect()
+    }
+
+    /// Values of rows as strings, for results in text format.
+    pub fn text_rows(&self) -> Vec<Vec<Option<String>>> {
+        self.rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|value| {
+                        value
+                            .as_ref()
+                            .map(|value| String::from_utf8_lossy(value).into_owned())
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Parameters and columns of a prepared statement.
+#[derive(Debug, Default)]
+pub struct StatementDescription {
+    /// oids of parameter types
+    pub parameter_types: Vec<u32>,
+    /// columns of rows, empty if the statement returns no rows
+    pub fields: Vec<FieldDescription>,
+}
+
+/// Client of a connection served in memory. See module docs.
+#[derive(Debug)]
+pub struct TestClient {
+    stream: DuplexStream,
+    buf: BytesMut,
+    server: JoinHandle<Result<(), IOError>>,
+    parameters: BTreeMap<String, String>,
+    backend_key: Option<(i32, i32)>,
+    transaction_status: TransactionStatus,
+    notices: Vec<ErrorInfo>,
+    notifications: Vec<NotificationResponse>,
+}
+
+impl TestClient {
+    /// Server parameters reported with `ParameterStatus`.
+    pub fn parameters(&self) -> &BTreeMap<String, String> {
+        &self.parameters
+    }
+
+    /// Process id and secret key from `BackendKeyData`.
+    pub fn backend_key(&self) -> Option<(i32, i32)> {
+        self.backend_key
+    }
+
+    /// Transaction status of the last `ReadyForQuery`.
+    pub fn transaction_status(&self) -> TransactionStatus {
+        self.transaction_status
+    }
+
+    /// Take notices and warnings received so far.
+    pub fn take_notices(&mut self) -> Vec<ErrorInfo> {
+        mem::take(&mut self.notices)
+    }
+
+    /// Take notifications received so far.
+    pub fn take_notifications(&mut self) -> Vec<NotificationResponse> {
+        mem::take(&mut self.notifications)
+    }
+
+    /// Run a simple query, returns results of its statements. If a statement
+    /// fails, its error is returned after `ReadyForQuery`, and results of
+    /// statements before it are dropped.
+    pub async fn simple_query(&mut self, query: &str) -> PgWireResult<Vec<QueryResult>> {
+        self.send(PgWireFrontendMessage::Query(Query::new(query.to_owned())))
+            .await?;
+        self.read_results().await
+    }
+
+    /// Run a query with extended query protocol, with parameters and
+    /// results in text format.
+    pub async fn extended_query(
+        &mut self,
+        query: &str,
+        parameters: &[Option<&str>],
+    ) -> PgWireResult<QueryResult> {
+        let parameters = parameters
+            .iter()
+            .map(|value| value.map(|value| Bytes::copy_from_slice(value.as_bytes())))
+            .collect();
+        for message in [
+            PgWireFrontendMessage::Parse(Parse::new(None, query.to_owned(), vec![])),
+            PgWireFrontendMessage::Bind(Bind::new(None, None, vec![], parameters, vec![])),
+            PgWireFrontendMessage::Describe(Describe::new(b'P', None)),
+            PgWireFrontendMessage::Execute(Execute::new(None, 0)),
+            PgWireFrontendMessage::Sync(PgSync::new()),
+        ] {
+            self.send(message).await?;
+        }
+        let mut results = self.read_results().await?;
+        Ok(results.pop().unwrap_or_default())
+    }
+
+    /// Parse and describe a statement with extended query protocol.
+    pub async fn prepare(&mut self, query: &str) -> PgWireResult<StatementDescription> {
+        for message in [
+            PgWireFrontendMessage::Parse(Parse::new(None, query.to_owned(), vec![])),
+            PgWireFrontendMessage::Describe(Describe::new(b'S', None)),
+            PgWireFrontendMessage::Sync(PgSync::new()),
+        ] {
+            self.send(message).await?;
+        }
+
+        let mut description = StatementDescription::default();
+        let mut error = None;
+        loop {
+            match self.next_message().await? {
+                PgWireBackendMessage::ParseComplete(_) | PgWireBackendMessage::NoData(_) => {}
+                PgWireBackendMessage::ParameterDescription(parameters) => {
+                    description.parameter_types = parameters.types;
+                }
+                PgWireBackendMessage::RowDescription(row_description) => {
+                    description.fields = row_description.fields;
+                }
+                PgWireBackendMessage::ErrorResponse(response) => error = Some(response),
+                PgWireBackendMessage::ReadyForQuery(ready) => {
+                    self.transaction_status = ready.status;
+                    return match error {
+                        Some(error) => Err(PgWireError::UserError(Box::new(error.into()))),
+                        None => Ok(description),
+                    };
+                }
+                message => self.observe(message)?,
+            }
+        }
+    }
+
+    /// Send a raw message.
+    pub async fn send(&mut self, message: PgWireFrontendMessage) -> PgWireResult<()> {
+        let mut buf = BytesMut::new();
+        message.encode(&mut buf)?;
+        self.stream.write_all(&buf).await?;
+        Ok(())
+    }
+
+    /// Receive a raw message, `None` if the connection is closed by server.
+    pub async fn receive(&mut self) -> PgWireResult<Option<PgWireBackendMessage>> {
+        loop {
+            if let Some(message) = PgWireBackendMessage::decode(&mut self.buf)? {
+                return Ok(Some(message));
+            }
+            if self.stream.read_buf(&mut self.buf).await? == 0 {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Terminate the connection, returns the result of serving it.
+    pub async fn close(mut self) -> Result<(), IOError> {
+        // server may have closed the connection already
+        let _ = self
+            .send(PgWireFrontendMessage::Terminate(Terminate::new()))
+            .await;
+        let _ = self.stream.shutdown().await;
+        self.server
+            .await
+            .map_err(|e| IOError::new(ErrorKind::Other, e))?
+    }
+
+    async fn send_password(&mut self, password: String) -> PgWireResult<()> {
+        self.send(PgWireFrontendMessage::PasswordMessageFamily(
+            PasswordMessageFamily::Password(Password::new(password)),
+        ))
+        .await
+    }
+
+    async fn next_message(&mut self) -> PgWireResult<PgWireBackendMessage> {
+        self.receive().await?.ok_or_else(|| {
+            IOError::new(ErrorKind::UnexpectedEof, "connection closed by server").into()
+        })
+    }
+
+    // read results of statements until `ReadyForQuery`
+    async fn read_results(&mut self) -> PgWireResult<Vec<QueryResult>> {
+        let mut results = Vec::new();
+        let mut current = QueryResult::default();
+        let mut error = None;
+        loop {
+            match self.next_message().await? {
+                PgWireBackendMessage::ParseComplete(_)
+                | PgWireBackendMessage::BindComplete(_)
+                | PgWireBackendMessage::NoData(_)
+                | PgWireBackendMessage::EmptyQueryResponse(_)
+                | PgWireBackendMessage::CopyOutResponse(_)
+                | PgWireBackendMessage::CopyData(_)
+                | PgWireBackendMessage::CopyDone(_) => {}
+                PgWireBackendMessage::RowDescription(row_description) => {
+                    current.fields = row_description.fields;
+                }
+                PgWireBackendMessage::DataRow(row) => current.rows.push(row_values(&row)?),
+                PgWireBackendMessage::CommandComplete(complete) => {
+                    current.tag = complete.tag;
+                    results.push(mem::take(&mut current));
+                }
+                PgWireBackendMessage::PortalSuspended(_) => {
+                    results.push(mem::take(&mut current));
+                }
+                PgWireBackendMessage::CopyInResponse(_) => {
+                    let fail = CopyFail::new("COPY FROM STDIN is not supported".to_owned());
+                    self.send(PgWireFrontendMessage::CopyFail(fail)).await?;
+                }
+                PgWireBackendMessage::ErrorResponse(response) => error = Some(response),
+                PgWireBackendMessage::ReadyForQuery(ready) => {
+                    self.transaction_status = ready.status;
+                    return match error {
+                        Some(error) => Err(PgWireError::UserError(Box::new(error.into()))),
+                        None => Ok(results),
+                    };
+                }
+                message => self.observe(message)?,
+            }
+        }
+    }
+
+    // keep messages which may be sent at any time
+    fn observe(&mut self, message: PgWireBackendMessage) -> PgWireResult<()> {
+        match message {
+            PgWireBackendMessage::ParameterStatus(status) => {
+                self.parameters.insert(status.name, status.value);
+            }
+            PgWireBackendMessage::BackendKeyData(key) => {
+                self.backend_key = Some((key.pid, key.secret_key));
+            }
+            PgWireBackendMessage::NoticeResponse(notice) => self.notices.push(notice.into()),
+            PgWireBackendMessage::NotificationResponse(notification) => {
+                self.notifications.push(notification);
+            }
+            message => {
+                return Err(PgWireError::ApiError(
+                    format!("unexpected message {message:?}").into(),
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+fn row_values(row: &DataRow) -> PgWireResult<Vec<Option<Bytes>>> {
+    Ok(row
+        .fields()?
+        .into_iter()
+        .map(|value| value.map(Bytes::copy_from_slice))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use futures::stream;
+
+    use super::*;
+    use crate::api::auth::md5pass::MakeMd5PasswordAuthStartupHandler;
+    use crate::api::auth::noop::NoopStartupHandler;
+    use crate::api::auth::{AuthSource, DefaultServerParameterProvider, LoginInfo, Verifier};
+    use crate::api::portal::{Format, Portal};
+    use crate::api::query::PlaceholderExtendedQueryHandler;
+    use crate::api::results::{
+        DataRowEncoder, FieldFormat, FieldInfo, QueryResponse, Response, Tag,
+    };
+    use crate::api::stmt::QueryParser;
+    use crate::api::{ClientInfo, MakeHandler, Type};
+    use crate::error::ErrorInfo;
+
+    // answers `SELECT <text>` with a row of the text, and fails other queries
+    struct EchoHandler;
+
+    fn echo_schema() -> Vec<FieldInfo> {
+        vec![FieldInfo::new(
+            "echo".to_owned(),
+            None,
+            None,
+            Type::TEXT,
+            FieldFormat::Text,
+        )]
+    }
+
+    fn echo(value: Option<String>) -> PgWireResult<Response<'static>> {
+        let schema = Arc::new(echo_schema());
+        let mut encoder = DataRowEncoder::new(schema.clone());
+        encoder.encode_field(&value)?;
+        let rows = vec![encoder.finish()];
+        Ok(Response::Query(QueryResponse::new(
+            schema,
+            stream::iter(rows),
+        )))
+    }
+
+    #[async_trait]
+    impl SimpleQueryHandler for EchoHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            query
+                .split(';')
+                .map(str::trim)
+                .filter(|statement| !statement.is_empty())
+                .map(|statement| match statement.strip_prefix("SELECT ") {
+                    Some(text) => echo(Some(text.to_owned())),
+                    None if statement == "BEGIN" => Ok(Response::Execution(Tag::new("BEGIN"))),
+                    None => Ok(Response::Error(Box::new(ErrorInfo::new(
+                        "ERROR".to_owned(),
+                        "42601".to_owned(),
+                        format!("syntax error at {statement:?}"),
+                    )))),
+                })
+                .collect()
+        }
+    }
+
+    struct EchoParser;
+
+    #[async_trait]
+    impl QueryParser for EchoParser {
+        type Statement = String;
+
+        async fn parse_sql(&self, sql: &str, _types: &[Type]) -> PgWireResult<String> {
+            Ok(sql.to_owned())
+        }
+
+        fn get_parameter_types(&self, _stmt: &String, _types: &[Type]) -> PgWireResult<Vec<Type>> {
+            Ok(vec![Type::TEXT])
+        }
+
+        fn get_result_schema(
+            &self,
+            _stmt: &String,
+            _format: Option<&Format>,
+        ) -> PgWireResult<Vec<FieldInfo>> {
+            Ok(echo_schema())
+        }
+    }
+
+    // answers with a row of the first parameter
+    #[async_trait]
+    impl ExtendedQueryHandler for EchoHandler {
+        type Statement = String;
+        type QueryParser = EchoParser;
+
+        fn query_parser(&self) -> Arc<Self::QueryParser> {
+            Arc::new(EchoParser)
+        }
+
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            portal: &'a Portal<Self::Statement>,
+            _max_rows: usize,
+        ) -> PgWireResult<Response<'a>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            echo(portal.parameter::<String>(0, &Type::TEXT)?)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queries() {
+        let mut client = TestClientBuilder::new()
+            .connect(
+                Arc::new(NoopStartupHandler),
+                Arc::new(EchoHandler),
+                Arc::new(EchoHandler),
+            )
+            .await
+            .unwrap();
+
+        let results = client.simple_query("SELECT 1; SELECT a").await.unwrap();
+        assert_eq!(2, results.len());
+        assert_eq!(vec!["echo"], results[0].column_names());
+        assert_eq!(vec![vec![Some("1".to_owned())]], results[0].text_rows());
+        assert_eq!(vec![vec![Some("a".to_owned())]], results[1].text_rows());
+        assert_eq!("SELECT 1", results[1].tag);
+
+        let error = client.simple_query("BEGIN; DROP t").await.unwrap_err();
+        assert_eq!("42601", error.to_error_info().code);
+        assert_eq!(TransactionStatus::Error, client.transaction_status());
+
+        let result = client
+            .extended_query("SELECT $1", &[Some("hello")])
+            .await
+            .unwrap();
+        assert_eq!(vec![vec![Some("hello".to_owned())]], result.text_rows());
+        let result = client.extended_query("SELECT $1", &[None]).await.unwrap();
+        assert_eq!(vec![vec![None]], result.text_rows());
+        assert_eq!("SELECT 1", result.tag);
+
+        let description = client.prepare("SELECT $1").await.unwrap();
+        assert_eq!(vec![Type::TEXT.oid()], description.parameter_types);
+        assert_eq!("echo", description.fields[0].name);
+
+        client.close().await.unwrap();
+    }
+
+    struct Users;
+
+    #[async_trait]
+    impl AuthSource for Users {
+        async fn get_verifier(&self, _login: &LoginInfo) -> PgWireResult<Option<Verifier>> {
+            Ok(Some(Verifier::Cleartext("secret".to_owned())))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_md5_authentication() {
+        let startup_handler = MakeMd5PasswordAuthStartupHandler::new(
+            Arc::new(Users),
+            Arc::new(DefaultServerParameterProvider::default()),
+        );
+
+        let mut client = TestClientBuilder::new()
+            .with_user("alice")
+            .with_password("secret")
+            .connect(
+                startup_handler.make(),
+                Arc::new(EchoHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+            )
+            .await
+            .unwrap();
+        assert!(client.parameters().contains_key("server_version"));
+        assert_eq!(1, client.simple_query("SELECT 1").await.unwrap().len());
+        client.close().await.unwrap();
+
+        let error = TestClientBuilder::new()
+            .with_user("alice")
+            .with_password("wrong")
+            .connect(
+                startup_handler.make(),
+                Arc::new(EchoHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!("28P01", error.to_error_info().code);
+    }
+}
@@ -0,0 +1,427 @@
+//! Build rustls `TlsAcceptor` from PEM encoded certificates and private key,
+//! enabled by feature `rustls`.
+//!
+//! Pass the acceptor to `pgwire::tokio::process_socket`, which answers
+//! `SSLRequest` of client, performs tls handshake and continues the protocol
+//! over the encrypted stream.
+
+use std::fs;
+use std::io::{Error as IOError, ErrorKind};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+pub use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
+pub use tokio_rustls::TlsAcceptor;
+
+use crate::tokio::{ReplayStream, TlsUpgrade, Transport, POSTGRESQL_ALPN};
+
+/// Load certificate chain from PEM, end-entity certificate first.
+pub fn load_certs(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>, IOError> {
+    let certs = rustls_pemfile::certs(&mut &pem[..]).collect::<Result<Vec<_>, IOError>>()?;
+    if certs.is_empty() {
+        return Err(IOError::new(
+            ErrorKind::InvalidInput,
+            "no certificate found in pem",
+        ));
+    }
+    Ok(certs)
+}
+
+/// Load the first PKCS#1, PKCS#8 or SEC1 private key from PEM.
+pub fn load_private_key(pem: &[u8]) -> Result<PrivateKeyDer<'static>, IOError> {
+    rustls_pemfile::private_key(&mut &pem[..])?
+        .ok_or_else(|| IOError::new(ErrorKind::InvalidInput, "no private key found in pem"))
+}
+
+/// Server config without client authentication, from PEM encoded
+/// certificate chain and private key. ALPN `postgresql` is offered for
+/// clients connecting with direct tls. Customize it for client certificates
+/// before creating `TlsAcceptor`.
+pub fn server_config_from_pem(cert_pem: &[u8], key_pem: &[u8]) -> Result<ServerConfig, IOError> {
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert_pem)?, load_private_key(key_pem)?)
+        .map_err(|e| IOError::new(ErrorKind::InvalidInput, e))?;
+    config.alpn_protocols = vec![POSTGRESQL_ALPN.to_vec()];
+    Ok(config)
+}
+
+/// Acceptor from PEM encoded certificate chain and private key.
+pub fn acceptor_from_pem(cert_pem: &[u8], key_pem: &[u8]) -> Result<TlsAcceptor, IOError> {
+    Ok(TlsAcceptor::from(Arc::new(server_config_from_pem(
+        cert_pem, key_pem,
+    )?)))
+}
+
+/// Acceptor from files of PEM encoded certificate chain and private key.
+pub fn acceptor_from_pem_files(
+    cert_path: impl AsRef<Path>,
+    key_path: impl AsRef<Path>,
+) -> Result<TlsAcceptor, IOError> {
+    acceptor_from_pem(&fs::read(cert_path)?, &fs::read(key_path)?)
+}
+
+/// Acceptor whose `ServerConfig` can be replaced at runtime, for example to
+/// pick up rotated certificates on `SIGHUP`. New connections use the latest
+/// config, established connections are not affected.
+///
+/// Use it with `pgwire::tokio::process_socket_with_tls`.
+#[derive(Debug)]
+pub struct ReloadableTlsAcceptor {
+    config: RwLock<Arc<ServerConfig>>,
+}
+
+impl ReloadableTlsAcceptor {
+    pub fn new(config: Arc<ServerConfig>) -> ReloadableTlsAcceptor {
+        ReloadableTlsAcceptor {
+            config: RwLock::new(config),
+        }
+    }
+
+    /// Acceptor from files of PEM encoded certificate chain and private key.
+    pub fn from_pem_files(
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+    ) -> Result<ReloadableTlsAcceptor, IOError> {
+        let config = server_config_from_pem(&fs::read(cert_path)?, &fs::read(key_path)?)?;
+        Ok(ReloadableTlsAcceptor::new(Arc::new(config)))
+    }
+
+    /// Current config.
+    pub fn config(&self) -> Arc<ServerConfig> {
+        self.config
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Replace config for new connections.
+    pub fn reload(&self, config: Arc<ServerConfig>) {
+        *self.config.write().unwrap_or_else(|e| e.into_inner()) = config;
+    }
+
+    /// Reload certificate chain and private key from files. The current
+    /// config is kept if the files are invalid.
+    pub fn reload_pem_files(
+        &self,
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+    ) -> Result<(), IOError> {
+        let config = server_config_from_pem(&fs::read(cert_path)?, &fs::read(key_path)?)?;
+        self.reload(Arc::new(config));
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TlsUpgrade for ReloadableTlsAcceptor {
+    type Stream<S: Transport> = TlsStream<ReplayStream<S>>;
+
+    async fn upgrade<S: Transport>(
+        &self,
+        socket: ReplayStream<S>,
+    ) -> Result<Self::Stream<S>, IOError> {
+        TlsAcceptor::from(self.config()).upgrade(socket).await
+    }
+
+    fn client_certificates<S: Transport>(stream: &Self::Stream<S>) -> Option<Vec<Vec<u8>>> {
+        TlsAcceptor::client_certificates::<S>(stream)
+    }
+
+    fn alpn_protocol<S: Transport>(stream: &Self::Stream<S>) -> Option<Vec<u8>> {
+        TlsAcceptor::alpn_protocol::<S>(stream)
+    }
+
+    fn tls_info<S: Transport>(stream: &Self::Stream<S>) -> Option<crate::api::TlsInfo> {
+        TlsAcceptor::tls_info::<S>(stream)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::fmt::Debug;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    use futures::Sink;
+
+    use bytes::BytesMut;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::task::JoinHandle;
+    use tokio_rustls::client::TlsStream;
+    use tokio_rustls::rustls::client::danger::{
+        HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+    };
+    use tokio_rustls::rustls::pki_types::{ServerName, UnixTime};
+    use tokio_rustls::rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+    use crate::api::auth::noop::NoopStartupHandler;
+    use crate::api::auth::StartupHandler;
+    use crate::api::query::{PlaceholderExtendedQueryHandler, SimpleQueryHandler};
+    use crate::api::results::Response;
+    use crate::api::{ClientInfo, TlsInfo};
+    use crate::error::{PgWireError, PgWireResult};
+    use crate::messages::startup::{SslRequest, Startup};
+    use crate::messages::{Message, PgWireBackendMessage, PgWireFrontendMessage};
+
+    pub(crate) const CERT: &[u8] = include_bytes!("../examples/ssl/server.crt");
+    pub(crate) const KEY: &[u8] = include_bytes!("../examples/ssl/server.key");
+
+    // the test certificate is self-signed
+    #[derive(Debug)]
+    pub(crate) struct AcceptAnyCert;
+
+    impl ServerCertVerifier for AcceptAnyCert {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer<'_>,
+            _dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer<'_>,
+            _dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            vec![
+                SignatureScheme::RSA_PSS_SHA256,
+                SignatureScheme::RSA_PKCS1_SHA256,
+                SignatureScheme::ECDSA_NISTP256_SHA256,
+                SignatureScheme::ED25519,
+            ]
+        }
+    }
+
+    pub(crate) fn connector(alpn_protocols: Vec<Vec<u8>>) -> TlsConnector {
+        let mut config = ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCert))
+            .with_no_client_auth();
+        config.alpn_protocols = alpn_protocols;
+        TlsConnector::from(Arc::new(config))
+    }
+
+    struct NoopQueryHandler;
+
+    #[async_trait::async_trait]
+    impl SimpleQueryHandler for NoopQueryHandler {
+        async fn do_query<'a, C>(
+            &self,
+            _client: &mut C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(vec![])
+        }
+    }
+
+    // records tls info of client during startup
+    #[derive(Default)]
+    struct TlsInfoStartupHandler(Mutex<Option<TlsInfo>>);
+
+    #[async_trait]
+    impl StartupHandler for TlsInfoStartupHandler {
+        async fn on_startup<C>(
+            &self,
+            client: &mut C,
+            message: PgWireFrontendMessage,
+        ) -> PgWireResult<()>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            *self.0.lock().unwrap() = client.tls_info().cloned();
+            NoopStartupHandler.on_startup(client, message).await
+        }
+    }
+
+    #[test]
+    fn test_load_pem() {
+        assert_eq!(1, load_certs(CERT).unwrap().len());
+        assert!(load_private_key(KEY).is_ok());
+        assert!(load_certs(KEY).is_err());
+        assert!(load_private_key(CERT).is_err());
+        assert!(acceptor_from_pem(CERT, KEY).is_ok());
+    }
+
+    async fn spawn_server() -> (SocketAddr, JoinHandle<Result<(), IOError>>) {
+        spawn_server_with(Arc::new(NoopStartupHandler)).await
+    }
+
+    async fn spawn_server_with<A: StartupHandler + 'static>(
+        startup_handler: Arc<A>,
+    ) -> (SocketAddr, JoinHandle<Result<(), IOError>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = Arc::new(acceptor_from_pem(CERT, KEY).unwrap());
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            crate::tokio::process_socket(
+                socket,
+                Some(acceptor),
+                startup_handler,
+                Arc::new(NoopQueryHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+            )
+            .await
+        });
+        (addr, server)
+    }
+
+    // send startup and read the first response
+    async fn startup(tls: &mut TlsStream<TcpStream>) -> PgWireBackendMessage {
+        let mut startup = Startup::new();
+        startup
+            .parameters
+            .insert("user".to_owned(), "pgwire".to_owned());
+        let mut buf = BytesMut::new();
+        startup.encode(&mut buf).unwrap();
+        tls.write_all(&buf).await.unwrap();
+
+        let mut buf = BytesMut::new();
+        loop {
+            tls.read_buf(&mut buf).await.unwrap();
+            if let Some(message) = PgWireBackendMessage::decode(&mut buf).unwrap() {
+                return message;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tls_handshake() {
+        let (addr, server) = spawn_server().await;
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let mut buf = BytesMut::new();
+        SslRequest::new().encode(&mut buf).unwrap();
+        socket.write_all(&buf).await.unwrap();
+        assert_eq!(b'S', socket.read_u8().await.unwrap());
+
+        let mut tls = connector(vec![])
+            .connect(ServerName::try_from("localhost").unwrap(), socket)
+            .await
+            .unwrap();
+        let message = startup(&mut tls).await;
+        assert!(matches!(message, PgWireBackendMessage::Authentication(_)));
+
+        drop(tls);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_direct_tls() {
+        let (addr, server) = spawn_server().await;
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut tls = connector(vec![POSTGRESQL_ALPN.to_vec()])
+            .connect(ServerName::try_from("localhost").unwrap(), socket)
+            .await
+            .unwrap();
+        let message = startup(&mut tls).await;
+        assert!(matches!(message, PgWireBackendMessage::Authentication(_)));
+        drop(tls);
+        server.await.unwrap().unwrap();
+
+        // ALPN is required for direct tls
+        let (addr, server) = spawn_server().await;
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let _tls = connector(vec![])
+            .connect(ServerName::try_from("localhost").unwrap(), socket)
+            .await;
+        assert!(server.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let acceptor = Arc::new(
+            ReloadableTlsAcceptor::from_pem_files(
+                "examples/ssl/server.crt",
+                "examples/ssl/server.key",
+            )
+            .unwrap(),
+        );
+        let config = Arc::new(server_config_from_pem(CERT, KEY).unwrap());
+        acceptor.reload(config.clone());
+        assert!(Arc::ptr_eq(&config, &acceptor.config()));
+
+        // invalid files keep the current config
+        assert!(acceptor
+            .reload_pem_files("examples/ssl/server.key", "examples/ssl/server.key")
+            .is_err());
+        assert!(Arc::ptr_eq(&config, &acceptor.config()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            crate::tokio::process_socket_with_tls(
+                socket,
+                BytesMut::new(),
+                Some(acceptor),
+                Arc::new(crate::tokio::ServerOptions::default()),
+                crate::tokio::Handlers::new(
+                    Arc::new(NoopStartupHandler),
+                    Arc::new(NoopQueryHandler),
+                    Arc::new(PlaceholderExtendedQueryHandler),
+                ),
+            )
+            .await
+        });
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut tls = connector(vec![POSTGRESQL_ALPN.to_vec()])
+            .connect(ServerName::try_from("localhost").unwrap(), socket)
+            .await
+            .unwrap();
+        let message = startup(&mut tls).await;
+        assert!(matches!(message, PgWireBackendMessage::Authentication(_)));
+        drop(tls);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tls_info() {
+        let handler = Arc::new(TlsInfoStartupHandler::default());
+        let (addr, server) = spawn_server_with(handler.clone()).await;
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut tls = connector(vec![POSTGRESQL_ALPN.to_vec()])
+            .connect(ServerName::try_from("localhost").unwrap(), socket)
+            .await
+            .unwrap();
+        startup(&mut tls).await;
+        drop(tls);
+        server.await.unwrap().unwrap();
+
+        let info = handler.0.lock().unwrap().clone().unwrap();
+        assert_eq!("TLSv1.3", info.version);
+        assert!(info.cipher.starts_with("TLS13_"));
+        assert_eq!(Some("localhost"), info.server_name.as_deref());
+        assert_eq!(Some(POSTGRESQL_ALPN), info.alpn_protocol.as_deref());
+    }
+}
@@ -1,28 +1,98 @@
-use std::io::Error as IOError;
-use std::sync::Arc;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::future::Future;
+use std::io::{Error as IOError, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
 
-use bytes::BytesMut;
-use futures::future::poll_fn;
-use futures::{SinkExt, StreamExt};
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::future::{poll_fn, select, Either};
+use futures::{pin_mut, SinkExt, StreamExt};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
 use tokio_rustls::TlsAcceptor;
-use tokio_util::codec::{Decoder, Encoder, Framed};
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedParts};
+use tokio_util::sync::CancellationToken;
 
-use crate::api::auth::StartupHandler;
+use crate::api::admission::ConnectionLimiter;
+use crate::api::audit::{AuditQuery, QueryAuditor, QueryOutcome};
+use crate::api::auth::hba::HbaAddress;
+use crate::api::auth::{negotiate_protocol_version, StartupHandler};
+use crate::api::cancel::{
+    query_canceled_error, statement_timeout_error, CancelHandle, CancelRegistry,
+};
+use crate::api::comment::SqlComment;
+use crate::api::copy::{CopyHandler, NoopCopyHandler};
+use crate::api::encoding::{ClientEncoding, Transcoder};
+use crate::api::health::{not_ready_error, HealthCheck, HealthProbe};
+use crate::api::memory::SendMemory;
+use crate::api::metrics::{ConnectionGuard, ConnectionMetrics, Metrics};
+use crate::api::middleware::Middleware;
+use crate::api::notification::{NotificationReceiver, NotificationSink};
 use crate::api::query::ExtendedQueryHandler;
-use crate::api::query::SimpleQueryHandler;
-use crate::api::{ClientInfo, ClientPortalStore, DefaultClient, PgWireConnectionState};
-use crate::error::{ErrorInfo, PgWireError, PgWireResult};
-use crate::messages::response::ReadyForQuery;
+use crate::api::query::{is_empty_query, send_simple_query_response, SimpleQueryHandler};
+use crate::api::replication::{ReplicationHandler, StartReplication};
+use crate::api::results::Tag;
+use crate::api::results::{FlushPolicy, Response, ResultLimits};
+use crate::api::rewrite::{QueryRewrite, QueryRewriter};
+use crate::api::shutdown::{admin_shutdown_error, is_idle, GracefulShutdown};
+use crate::api::store::{MemPortalStore, PortalStore, PortalStoreListener};
+use crate::api::wiretap::{ConnectionTap, WireTap};
+use crate::api::{
+    ClientInfo, ClientPortalStore, DefaultClient, PgWireConnectionState, PgWireHandler, TlsInfo,
+    DEFAULT_NAME,
+};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult, SqlState};
+use crate::messages::codec::get_length;
+use crate::messages::copy::{CopyBothResponse, CopyDone};
+use crate::messages::replication::{
+    current_timestamp, PrimaryKeepalive, ReplicationBackendMessage, ReplicationFrontendMessage,
+};
+use crate::messages::response::{EmptyQueryResponse, ReadyForQuery};
 use crate::messages::response::{SslResponse, TransactionStatus};
-use crate::messages::startup::{SslRequest, Startup};
+use crate::messages::startup::{GssEncRequest, ParameterStatus, SslRequest};
 use crate::messages::{Message, PgWireBackendMessage, PgWireFrontendMessage};
+use crate::sansio::MessageLimits;
 
 #[non_exhaustive]
-#[derive(Debug, new)]
+#[derive(new)]
 pub struct PgWireMessageServerCodec<S> {
     pub client_info: DefaultClient<S>,
+    #[new(default)]
+    metrics: Option<ConnectionMetrics>,
+    // count of `DataRow` sent
+    #[new(default)]
+    rows_sent: usize,
+    #[new(default)]
+    disconnect: Option<DisconnectWatcher>,
+    // encoded bytes are reported to `disconnect`, for heartbeats
+    #[new(default)]
+    report_encoded: bool,
+    #[new(default)]
+    limits: MessageLimits,
+    #[new(default)]
+    transcoder: Transcoder,
+    #[new(default)]
+    middleware: Vec<Arc<dyn Middleware>>,
+    #[new(default)]
+    wire_tap: Option<ConnectionTap>,
+}
+
+impl<S: std::fmt::Debug> std::fmt::Debug for PgWireMessageServerCodec<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PgWireMessageServerCodec")
+            .field("client_info", &self.client_info)
+            .field("metrics", &self.metrics.is_some())
+            .field("middleware", &self.middleware.len())
+            .field("wire_tap", &self.wire_tap)
+            .finish()
+    }
 }
 
 impl<S> Decoder for PgWireMessageServerCodec<S> {
@@ -30,20 +100,36 @@ impl<S> Decoder for PgWireMessageServerCodec<S> {
     type Error = PgWireError;
 
     fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        match self.client_info.state() {
-            PgWireConnectionState::AwaitingStartup => {
-                if let Some(request) = SslRequest::decode(src)? {
-                    return Ok(Some(PgWireFrontendMessage::SslRequest(request)));
-                }
-
-                if let Some(startup) = Startup::decode(src)? {
-                    return Ok(Some(PgWireFrontendMessage::Startup(startup)));
-                }
-
-                Ok(None)
-            }
-            _ => PgWireFrontendMessage::decode(src),
+        let len = src.len();
+        let frame = self
+            .wire_tap
+            .as_ref()
+            .filter(|tap| tap.is_dumping())
+            .and_then(|_| self.peek_frame(src));
+        let message = self.transcoder.decode(
+            self.client_info.client_encoding,
+            self.client_info.state(),
+            src,
+            &self.limits,
+        )?;
+        if let (Some(metrics), Some(_)) = (&self.metrics, &message) {
+            metrics.on_received(len - src.len());
         }
+        if let (Some(tap), Some(message)) = (&self.wire_tap, &message) {
+            tap.on_frontend(message, frame.as_deref());
+        }
+        Ok(message)
+    }
+}
+
+impl<S> PgWireMessageServerCodec<S> {
+    // copy of next frame in `src` if it's complete
+    fn peek_frame(&self, src: &bytes::BytesMut) -> Option<Vec<u8>> {
+        let len = match self.client_info.state() {
+            PgWireConnectionState::AwaitingStartup => get_length(src, 0)?,
+            _ => get_length(src, 1)? + 1,
+        };
+        src.get(..len).map(|frame| frame.to_vec())
     }
 }
 
@@ -55,7 +141,33 @@ impl<S> Encoder<PgWireBackendMessage> for PgWireMessageServerCodec<S> {
         item: PgWireBackendMessage,
         dst: &mut bytes::BytesMut,
     ) -> Result<(), Self::Error> {
-        item.encode(dst).map_err(Into::into)
+        // the outermost middleware sees the message last
+        let mut item = item;
+        for middleware in self.middleware.iter().rev() {
+            match middleware.on_backend_message(&self.client_info, item) {
+                Some(message) => item = message,
+                None => return Ok(()),
+            }
+        }
+        let len = dst.len();
+        item.encode(dst)?;
+        self.transcoder
+            .encode(self.client_info.client_encoding, &item, dst, len);
+        if let PgWireBackendMessage::DataRow(_) = item {
+            self.rows_sent += 1;
+        }
+        let status = &mut self.client_info.transaction_status;
+        *status = status.after_sent(&item);
+        if let Some(metrics) = &mut self.metrics {
+            metrics.on_sent(&item, dst.len() - len);
+        }
+        if let Some(tap) = &self.wire_tap {
+            tap.on_backend(&item, &dst[len..]);
+        }
+        if let Some(watcher) = self.disconnect.as_ref().filter(|_| self.report_encoded) {
+            watcher.on_encoded(dst.len() - len);
+        }
+        Ok(())
     }
 }
 
@@ -83,6 +195,84 @@ impl<T, S> ClientInfo for Framed<T, PgWireMessageServerCodec<S>> {
     fn metadata_mut(&mut self) -> &mut std::collections::HashMap<String, String> {
         self.codec_mut().client_info.metadata_mut()
     }
+
+    fn result_limits(&self) -> ResultLimits {
+        self.codec().client_info.result_limits()
+    }
+
+    fn set_result_limits(&mut self, limits: ResultLimits) {
+        self.codec_mut().client_info.set_result_limits(limits);
+    }
+
+    fn flush_policy(&self) -> FlushPolicy {
+        self.codec().client_info.flush_policy()
+    }
+
+    fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        self.codec_mut().client_info.set_flush_policy(policy);
+    }
+
+    fn statement_timeout(&self) -> Option<Duration> {
+        self.codec().client_info.statement_timeout()
+    }
+
+    fn set_statement_timeout(&mut self, timeout: Option<Duration>) {
+        self.codec_mut().client_info.set_statement_timeout(timeout);
+    }
+
+    fn transaction_status(&self) -> TransactionStatus {
+        self.codec().client_info.transaction_status()
+    }
+
+    fn set_transaction_status(&mut self, status: TransactionStatus) {
+        self.codec_mut().client_info.set_transaction_status(status);
+    }
+
+    fn client_encoding(&self) -> ClientEncoding {
+        self.codec().client_info.client_encoding()
+    }
+
+    fn set_client_encoding(&mut self, encoding: ClientEncoding) {
+        self.codec_mut().client_info.set_client_encoding(encoding);
+    }
+
+    fn protocol_minor_version(&self) -> u16 {
+        self.codec().client_info.protocol_minor_version()
+    }
+
+    fn set_protocol_minor_version(&mut self, minor: u16) {
+        self.codec_mut()
+            .client_info
+            .set_protocol_minor_version(minor);
+    }
+
+    fn client_certificates(&self) -> Option<&[Vec<u8>]> {
+        self.codec().client_info.client_certificates()
+    }
+
+    fn tls_info(&self) -> Option<&TlsInfo> {
+        self.codec().client_info.tls_info()
+    }
+
+    fn cancel_handle(&self) -> Option<&CancelHandle> {
+        self.codec().client_info.cancel_handle()
+    }
+
+    fn notification_sink(&self) -> Option<&NotificationSink> {
+        self.codec().client_info.notification_sink()
+    }
+
+    fn send_memory(&self) -> Option<&SendMemory> {
+        self.codec().client_info.send_memory()
+    }
+
+    fn sql_comment(&self) -> Option<&SqlComment> {
+        self.codec().client_info.sql_comment()
+    }
+
+    fn set_sql_comment(&mut self, comment: Option<SqlComment>) {
+        self.codec_mut().client_info.set_sql_comment(comment);
+    }
 }
 
 impl<T, S> ClientPortalStore for Framed<T, PgWireMessageServerCodec<S>> {
@@ -93,22 +283,132 @@ impl<T, S> ClientPortalStore for Framed<T, PgWireMessageServerCodec<S>> {
     }
 }
 
-async fn process_message<S, A, Q, EQ>(
+/// Run a query handler until it completes, or the query is cancelled by
+/// `CancelRequest` from client. If client disconnects during the query, or
+/// sends `Terminate` with `ServerOptions::terminate_cancels_query`, the
+/// token is cancelled, so work started by the handler can stop early.
+async fn cancellable<F>(
+    token: Option<CancellationToken>,
+    disconnect: Option<DisconnectWatcher>,
+    timeout: Option<Duration>,
+    query: F,
+) -> PgWireResult<()>
+where
+    F: Future<Output = PgWireResult<()>>,
+{
+    let Some(token) = token else {
+        return match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, query)
+                .await
+                .unwrap_or_else(|_| {
+                    Err(PgWireError::UserError(Box::new(statement_timeout_error())))
+                }),
+            None => query.await,
+        };
+    };
+    let cancelled = token.cancelled();
+    let disconnected = async {
+        match disconnect {
+            Some(watcher) => poll_fn(|cx| watcher.poll_disconnected(cx)).await,
+            None => futures::future::pending().await,
+        }
+    };
+    let timed_out = async {
+        match timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => futures::future::pending().await,
+        }
+    };
+    pin_mut!(query);
+    pin_mut!(cancelled);
+    pin_mut!(disconnected);
+    pin_mut!(timed_out);
+    match select(query, select(cancelled, select(disconnected, timed_out))).await {
+        Either::Left((result, _)) => result,
+        Either::Right((Either::Left(_), _)) => {
+            Err(PgWireError::UserError(Box::new(query_canceled_error())))
+        }
+        Either::Right((Either::Right((Either::Left((disconnect, _)), _)), _)) => {
+            token.cancel();
+            let message = match disconnect {
+                Disconnect::Closed => "client disconnected during query",
+                Disconnect::Terminated => "client terminated connection during query",
+            };
+            Err(PgWireError::IoError(IOError::new(
+                ErrorKind::ConnectionReset,
+                message,
+            )))
+        }
+        Either::Right((Either::Right((Either::Right(_), _)), _)) => {
+            // work spawned by handler is stopped as well
+            token.cancel();
+            Err(PgWireError::UserError(Box::new(statement_timeout_error())))
+        }
+    }
+}
+
+/// Run a query, sending heartbeats to client whenever it's silent for the
+/// interval of `heartbeat`.
+async fn with_heartbeat<F>(
+    heartbeat: Option<(QueryHeartbeat, DisconnectWatcher)>,
+    query: F,
+) -> PgWireResult<()>
+where
+    F: Future<Output = PgWireResult<()>>,
+{
+    let Some((heartbeat, watcher)) = heartbeat else {
+        return query.await;
+    };
+    let beats = send_heartbeats(heartbeat, watcher);
+    pin_mut!(query);
+    pin_mut!(beats);
+    match select(query, beats).await {
+        Either::Left((result, _)) => result,
+        Either::Right((never, _)) => match never {},
+    }
+}
+
+async fn send_heartbeats(heartbeat: QueryHeartbeat, watcher: DisconnectWatcher) -> Infallible {
+    let start = tokio::time::Instant::now() + heartbeat.interval;
+    let mut ticks = tokio::time::interval_at(start, heartbeat.interval);
+    loop {
+        ticks.tick().await;
+        watcher.inject(&heartbeat.frame, heartbeat.interval);
+        poll_fn(|cx| watcher.poll_injected(cx)).await;
+    }
+}
+
+/// Watcher of the stream for a query about to run.
+fn watch_query<S, ST>(
+    socket: &Framed<S, PgWireMessageServerCodec<ST>>,
+) -> Option<DisconnectWatcher> {
+    let watcher = socket.codec().disconnect.clone()?;
+    watcher.start_query(socket.read_buffer());
+    Some(watcher)
+}
+
+async fn process_message<S, A, Q, EQ, CH>(
     message: PgWireFrontendMessage,
     socket: &mut Framed<S, PgWireMessageServerCodec<EQ::Statement>>,
     authenticator: Arc<A>,
     query_handler: Arc<Q>,
     extended_query_handler: Arc<EQ>,
+    copy_handler: Arc<CH>,
+    options: &ServerOptions,
 ) -> PgWireResult<()>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
     A: StartupHandler,
     Q: SimpleQueryHandler,
     EQ: ExtendedQueryHandler,
+    CH: CopyHandler,
 {
     match socket.codec().client_info.state() {
         PgWireConnectionState::AwaitingStartup
         | PgWireConnectionState::AuthenticationInProgress => {
+            if let PgWireFrontendMessage::Startup(startup) = &message {
+                negotiate_protocol_version(socket, startup).await?;
+            }
             authenticator.on_startup(socket, message).await?;
         }
         // From Postgres docs:
@@ -116,40 +416,418 @@ where
         // message, the backend issues ErrorResponse, then reads and discards
         // messages until a Sync is reached, then issues ReadyForQuery and
         // returns to normal message processing.
+        //
+        // Messages are handled one at a time, so responses of a pipeline are
+        // in the order of its messages.
         PgWireConnectionState::AwaitingSync => {
             if let PgWireFrontendMessage::Sync(sync) = message {
                 extended_query_handler.on_sync(socket, sync).await?;
                 socket.set_state(PgWireConnectionState::ReadyForQuery);
             }
         }
+        PgWireConnectionState::CopyInProgress(is_extended_query) => match message {
+            PgWireFrontendMessage::CopyData(copy_data) => {
+                copy_handler.on_copy_data(socket, copy_data).await?;
+            }
+            PgWireFrontendMessage::CopyDone(copy_done) => {
+                copy_handler.on_copy_done(socket, copy_done).await?;
+                socket.set_state(PgWireConnectionState::ReadyForQuery);
+                // extended query waits for `Sync` from client
+                if !is_extended_query {
+                    socket
+                        .feed(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+                            socket.transaction_status(),
+                        )))
+                        .await?;
+                }
+                socket.flush().await?;
+            }
+            PgWireFrontendMessage::CopyFail(copy_fail) => {
+                return Err(copy_handler.on_copy_fail(socket, copy_fail).await);
+            }
+            // ignored in copy mode like postgres
+            PgWireFrontendMessage::Flush(_) | PgWireFrontendMessage::Sync(_) => {}
+            _ => {
+                return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                    "ERROR".to_owned(),
+                    SqlState::PROTOCOL_VIOLATION.into(),
+                    "unexpected message type during COPY from stdin".to_owned(),
+                ))));
+            }
+        },
         _ => {
-            // query or query in progress
-            match message {
-                PgWireFrontendMessage::Query(query) => {
-                    query_handler.on_query(socket, query).await?;
+            options.message_limits.check(&message)?;
+            let (message, answer) =
+                rewrite_query(options.query_rewriter.as_deref(), message, socket);
+            let audit = options
+                .query_auditor
+                .as_deref()
+                .and_then(|auditor| RunningAudit::start(auditor, &message, socket));
+            #[cfg(feature = "tracing")]
+            let span = trace::statement_span(&message, socket.portal_store());
+            let dispatch = async {
+                match answer {
+                    Some(answer) => send_rewriter_answer(socket, answer?).await,
+                    None => {
+                        dispatch_query(
+                            message,
+                            socket,
+                            query_handler,
+                            extended_query_handler,
+                            options,
+                        )
+                        .await
+                    }
                 }
-                PgWireFrontendMessage::Parse(parse) => {
-                    extended_query_handler.on_parse(socket, parse).await?;
+            };
+            #[cfg(feature = "tracing")]
+            let dispatch = tracing::Instrument::instrument(dispatch, span);
+            let result = dispatch.await;
+            if let Some(audit) = audit {
+                audit.finish(socket, &result);
+            }
+            result?;
+        }
+    }
+    Ok(())
+}
+
+/// Apply `QueryRewriter` to text of `Query` and `Parse`. Returns the message
+/// to dispatch, and the answer of rewriter if the query is not dispatched.
+#[allow(clippy::type_complexity)]
+fn rewrite_query<C: ClientInfo>(
+    rewriter: Option<&dyn QueryRewriter>,
+    message: PgWireFrontendMessage,
+    client: &C,
+) -> (
+    PgWireFrontendMessage,
+    Option<PgWireResult<Vec<Response<'static>>>>,
+) {
+    let Some(rewriter) = rewriter else {
+        return (message, None);
+    };
+    let (mut message, query, extended) = match message {
+        PgWireFrontendMessage::Query(query) => {
+            let text = query.query.clone();
+            (PgWireFrontendMessage::Query(query), text, false)
+        }
+        PgWireFrontendMessage::Parse(parse) => {
+            let text = parse.query.clone();
+            (PgWireFrontendMessage::Parse(parse), text, true)
+        }
+        message => return (message, None),
+    };
+    let answer = match rewriter.rewrite(client, &query, extended) {
+        QueryRewrite::Unchanged => None,
+        QueryRewrite::Rewrite(rewritten) => {
+            match &mut message {
+                PgWireFrontendMessage::Query(query) => query.query = rewritten,
+                PgWireFrontendMessage::Parse(parse) => parse.query = rewritten,
+                _ => unreachable!(),
+            }
+            None
+        }
+        QueryRewrite::Respond(_) if extended => {
+            Some(Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                "ERROR".to_owned(),
+                SqlState::FEATURE_NOT_SUPPORTED.into(),
+                "statement can't be prepared".to_owned(),
+            )))))
+        }
+        QueryRewrite::Respond(responses) => Some(Ok(responses)),
+        QueryRewrite::Reject(error) => Some(Err(PgWireError::UserError(error))),
+    };
+    (message, answer)
+}
+
+/// Send responses of `QueryRewriter` to a simple query, like
+/// `SimpleQueryHandler::on_query`.
+async fn send_rewriter_answer<S, ST>(
+    socket: &mut Framed<S, PgWireMessageServerCodec<ST>>,
+    responses: Vec<Response<'static>>,
+) -> PgWireResult<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    ST: Send + Sync,
+{
+    socket.set_state(PgWireConnectionState::QueryInProgress);
+    for response in responses {
+        if send_simple_query_response(socket, response).await? {
+            socket.set_state(PgWireConnectionState::CopyInProgress(false));
+            return Ok(());
+        }
+    }
+    socket
+        .feed(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+            socket.transaction_status(),
+        )))
+        .await?;
+    socket.flush().await?;
+    socket.set_state(PgWireConnectionState::ReadyForQuery);
+    Ok(())
+}
+
+/// A query being audited by `QueryAuditor`.
+struct RunningAudit<'a> {
+    auditor: &'a dyn QueryAuditor,
+    query: AuditQuery,
+    started: Instant,
+    rows_sent: usize,
+}
+
+impl<'a> RunningAudit<'a> {
+    /// Start audit of `Query` or `Execute`, other messages are not audited.
+    fn start<S, ST>(
+        auditor: &'a dyn QueryAuditor,
+        message: &PgWireFrontendMessage,
+        socket: &Framed<S, PgWireMessageServerCodec<ST>>,
+    ) -> Option<RunningAudit<'a>>
+    where
+        ST: Clone + Send + Sync,
+    {
+        let mut query = AuditQuery::new(socket.socket_addr(), socket.metadata().clone());
+        match message {
+            PgWireFrontendMessage::Query(q) => query.query = q.query.clone(),
+            PgWireFrontendMessage::Execute(execute) => {
+                let name = execute.name.as_deref().unwrap_or(DEFAULT_NAME);
+                if let Some(portal) = socket.portal_store().get_portal(name) {
+                    query.query = portal.statement.query.clone();
+                    if auditor.audit_parameters() {
+                        query.parameters = Some(portal.parameters.clone());
+                        query.parameter_format = portal.parameter_format.clone();
+                    }
                 }
-                PgWireFrontendMessage::Bind(bind) => {
-                    extended_query_handler.on_bind(socket, bind).await?;
+                query.portal = Some(execute.name.clone().unwrap_or_default());
+            }
+            _ => return None,
+        }
+        auditor.on_query_start(&query);
+        Some(RunningAudit {
+            auditor,
+            query,
+            started: Instant::now(),
+            rows_sent: socket.codec().rows_sent,
+        })
+    }
+
+    fn finish<S, ST>(
+        self,
+        socket: &Framed<S, PgWireMessageServerCodec<ST>>,
+        result: &PgWireResult<()>,
+    ) {
+        let outcome = QueryOutcome::new(
+            self.started.elapsed(),
+            socket.codec().rows_sent - self.rows_sent,
+            result.as_ref().err().map(PgWireError::to_error_info),
+        );
+        self.auditor.on_query_end(&self.query, &outcome);
+    }
+}
+
+/// Handle messages of query or query in progress state.
+async fn dispatch_query<S, Q, EQ>(
+    message: PgWireFrontendMessage,
+    socket: &mut Framed<S, PgWireMessageServerCodec<EQ::Statement>>,
+    query_handler: Arc<Q>,
+    extended_query_handler: Arc<EQ>,
+    options: &ServerOptions,
+) -> PgWireResult<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    Q: SimpleQueryHandler,
+    EQ: ExtendedQueryHandler,
+{
+    let cancel_token = socket.cancel_handle().map(CancelHandle::reset);
+    match message {
+        PgWireFrontendMessage::Query(query) => {
+            let replication = options
+                .replication_handler
+                .as_ref()
+                .filter(|_| socket.is_replication())
+                .and_then(|handler| {
+                    StartReplication::parse(&query.query).map(|cmd| (handler, cmd))
+                });
+            if let Some((handler, command)) = replication {
+                run_replication(socket, handler.as_ref(), command).await?;
+            } else if is_empty_query(&query.query) {
+                // handlers never see empty queries, as some fail on them
+                socket
+                    .feed(PgWireBackendMessage::EmptyQueryResponse(EmptyQueryResponse))
+                    .await?;
+                socket
+                    .feed(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+                        socket.transaction_status(),
+                    )))
+                    .await?;
+                socket.flush().await?;
+            } else {
+                let timeout =
+                    query_handler.statement_timeout(&query.query, socket.statement_timeout());
+                socket.set_sql_comment(SqlComment::parse(&query.query));
+                let disconnect = watch_query(socket);
+                let heartbeat = options.query_heartbeat.clone().zip(disconnect.clone());
+                cancellable(
+                    cancel_token,
+                    disconnect,
+                    timeout,
+                    with_heartbeat(heartbeat, query_handler.on_query(socket, query)),
+                )
+                .await?;
+            }
+        }
+        PgWireFrontendMessage::Parse(parse) => {
+            extended_query_handler.on_parse(socket, parse).await?;
+        }
+        PgWireFrontendMessage::Bind(bind) => {
+            extended_query_handler.on_bind(socket, bind).await?;
+        }
+        PgWireFrontendMessage::Execute(execute) => {
+            let name = execute.name.as_deref().unwrap_or(DEFAULT_NAME);
+            let (timeout, comment) = match socket.portal_store().get_portal(name) {
+                Some(portal) => (
+                    extended_query_handler
+                        .statement_timeout(&portal.statement, socket.statement_timeout()),
+                    portal.statement.comment.clone(),
+                ),
+                None => (socket.statement_timeout(), None),
+            };
+            socket.set_sql_comment(comment);
+            let disconnect = watch_query(socket);
+            let heartbeat = options.query_heartbeat.clone().zip(disconnect.clone());
+            cancellable(
+                cancel_token,
+                disconnect,
+                timeout,
+                with_heartbeat(
+                    heartbeat,
+                    extended_query_handler.on_execute(socket, execute),
+                ),
+            )
+            .await?;
+        }
+        PgWireFrontendMessage::Describe(describe) => {
+            extended_query_handler.on_describe(socket, describe).await?;
+        }
+        PgWireFrontendMessage::Flush(flush) => {
+            extended_query_handler.on_flush(socket, flush).await?;
+        }
+        PgWireFrontendMessage::Sync(sync) => {
+            extended_query_handler.on_sync(socket, sync).await?;
+        }
+        PgWireFrontendMessage::Close(close) => {
+            extended_query_handler.on_close(socket, close).await?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn keepalive_copy_data(wal_end: u64, reply: bool) -> PgWireBackendMessage {
+    let keepalive = PrimaryKeepalive::new(wal_end, current_timestamp(), reply);
+    PgWireBackendMessage::CopyData(
+        ReplicationBackendMessage::PrimaryKeepalive(keepalive).into_copy_data(),
+    )
+}
+
+/// Stream WAL data from `ReplicationHandler` in copy-both mode, until the
+/// stream ends or client sends `CopyDone`.
+async fn run_replication<S, ST>(
+    socket: &mut Framed<S, PgWireMessageServerCodec<ST>>,
+    handler: &dyn ReplicationHandler,
+    command: StartReplication,
+) -> PgWireResult<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    let mut wal = handler
+        .start_replication(socket.metadata(), &command)
+        .await?;
+    socket.set_state(PgWireConnectionState::QueryInProgress);
+    socket
+        .send(PgWireBackendMessage::CopyBothResponse(
+            CopyBothResponse::new(0, 0, vec![]),
+        ))
+        .await?;
+
+    let mut keepalive = tokio::time::interval(handler.keepalive_interval());
+    let mut wal_end = command.start_lsn;
+    let mut streaming = true;
+    // ask client for status if it hasn't sent one since last keepalive
+    let mut status_received = true;
+
+    loop {
+        tokio::select! {
+            data = wal.next(), if streaming => match data {
+                Some(data) => {
+                    let data = data?;
+                    wal_end = wal_end.max(data.end_lsn);
+                    socket
+                        .send(PgWireBackendMessage::CopyData(
+                            ReplicationBackendMessage::XLogData(data).into_copy_data(),
+                        ))
+                        .await?;
                 }
-                PgWireFrontendMessage::Execute(execute) => {
-                    extended_query_handler.on_execute(socket, execute).await?;
+                None => {
+                    streaming = false;
+                    socket
+                        .send(PgWireBackendMessage::CopyDone(CopyDone::new()))
+                        .await?;
                 }
-                PgWireFrontendMessage::Describe(describe) => {
-                    extended_query_handler.on_describe(socket, describe).await?;
+            },
+            _ = keepalive.tick(), if streaming => {
+                socket.send(keepalive_copy_data(wal_end, !status_received)).await?;
+                status_received = false;
+            }
+            message = socket.next() => match message {
+                Some(Ok(PgWireFrontendMessage::CopyData(data))) => {
+                    match ReplicationFrontendMessage::decode(data.data)? {
+                        ReplicationFrontendMessage::StandbyStatusUpdate(update) => {
+                            status_received = true;
+                            let reply = update.reply && streaming;
+                            handler.on_standby_status_update(&command, update).await?;
+                            if reply {
+                                socket.send(keepalive_copy_data(wal_end, false)).await?;
+                            }
+                        }
+                        ReplicationFrontendMessage::HotStandbyFeedback(feedback) => {
+                            handler.on_hot_standby_feedback(&command, feedback).await?;
+                        }
+                    }
                 }
-                PgWireFrontendMessage::Sync(sync) => {
-                    extended_query_handler.on_sync(socket, sync).await?;
+                Some(Ok(PgWireFrontendMessage::CopyDone(_))) => {
+                    if streaming {
+                        socket
+                            .send(PgWireBackendMessage::CopyDone(CopyDone::new()))
+                            .await?;
+                    }
+                    break;
                 }
-                PgWireFrontendMessage::Close(close) => {
-                    extended_query_handler.on_close(socket, close).await?;
+                Some(Ok(_)) => {
+                    return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                        "ERROR".to_owned(),
+                        SqlState::PROTOCOL_VIOLATION.into(),
+                        "unexpected message type during streaming replication".to_owned(),
+                    ))));
                 }
-                _ => {}
-            }
+                Some(Err(e)) => return Err(e),
+                // client has gone
+                None => return Ok(()),
+            },
         }
     }
+
+    socket
+        .feed(PgWireBackendMessage::CommandComplete(
+            Tag::new("START_REPLICATION").into(),
+        ))
+        .await?;
+    socket
+        .feed(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+            socket.transaction_status(),
+        )))
+        .await?;
+    socket.flush().await?;
+    socket.set_state(PgWireConnectionState::ReadyForQuery);
     Ok(())
 }
 
@@ -161,22 +839,24 @@ async fn process_error<S, ST>(
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
 {
+    #[cfg(feature = "tracing")]
+    trace::error_event(&error);
     match error {
         PgWireError::UserError(error_info) => {
             socket
                 .feed(PgWireBackendMessage::ErrorResponse((*error_info).into()))
                 .await?;
         }
-        PgWireError::ApiError(e) => {
-            let error_info = ErrorInfo::new("ERROR".to_owned(), "XX000".to_owned(), e.to_string());
+        PgWireError::ApiError(_) => {
             socket
-                .feed(PgWireBackendMessage::ErrorResponse(error_info.into()))
+                .feed(PgWireBackendMessage::ErrorResponse(
+                    error.to_error_info().into(),
+                ))
                 .await?;
         }
         _ => {
             // Internal error
-            let error_info =
-                ErrorInfo::new("FATAL".to_owned(), "XX000".to_owned(), error.to_string());
+            let error_info = error.to_error_info();
             socket
                 .send(PgWireBackendMessage::ErrorResponse(error_info.into()))
                 .await?;
@@ -187,9 +867,13 @@ where
     if wait_for_sync {
         socket.set_state(PgWireConnectionState::AwaitingSync);
     } else {
+        // further copy messages from client are dropped
+        if matches!(socket.state(), PgWireConnectionState::CopyInProgress(_)) {
+            socket.set_state(PgWireConnectionState::ReadyForQuery);
+        }
         socket
             .feed(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
-                TransactionStatus::Idle,
+                socket.transaction_status(),
             )))
             .await?;
     }
@@ -198,105 +882,4083 @@ where
     Ok(())
 }
 
-async fn is_sslrequest_pending(tcp_socket: &TcpStream) -> Result<bool, IOError> {
-    let mut buf = [0u8; SslRequest::BODY_SIZE];
-    let mut buf = ReadBuf::new(&mut buf);
-    while buf.filled().len() < SslRequest::BODY_SIZE {
-        if poll_fn(|cx| tcp_socket.poll_peek(cx, &mut buf)).await? == 0 {
-            // the tcp_stream has ended
-            return Ok(false);
+/// Read from socket until `buf` holds enough bytes to tell if the client
+/// starts with a `SslRequest`. Never reads past that, so a TLS handshake
+/// following the request stays in the socket.
+async fn read_sslrequest_prefix<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+) -> Result<(), IOError> {
+    let mut chunk = [0u8; SslRequest::BODY_SIZE];
+    while buf.len() < SslRequest::BODY_SIZE {
+        let n = stream
+            .read(&mut chunk[..SslRequest::BODY_SIZE - buf.len()])
+            .await?;
+        if n == 0 {
+            // the stream has ended
+            break;
         }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    Ok(())
+}
+
+/// Decline a pending `GssEncRequest`, since GSSAPI encryption is not
+/// supported, and read the prefix of next request. Like postgres, clients
+/// with `gssencmode=prefer` go on with `SslRequest` or `Startup`.
+async fn decline_gssenc<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+) -> Result<(), IOError> {
+    if GssEncRequest::decode(buf)?.is_none() {
+        return Ok(());
     }
 
-    let mut buf = BytesMut::from(buf.filled());
-    if let Ok(Some(_)) = SslRequest::decode(&mut buf) {
-        return Ok(true);
+    if !buf.is_empty() {
+        return Err(IOError::new(
+            ErrorKind::InvalidData,
+            "received unencrypted data after GSSAPI encryption request",
+        ));
     }
-    Ok(false)
+
+    // the refusal is the same single byte as of `SslRequest`
+    let mut response_buf = BytesMut::with_capacity(SslResponse::MESSAGE_LENGTH);
+    SslResponse::Refuse.encode(&mut response_buf)?;
+    stream.write_all(&response_buf).await?;
+
+    read_sslrequest_prefix(stream, buf).await
 }
 
-async fn peek_for_sslrequest<ST>(
-    socket: &mut Framed<TcpStream, PgWireMessageServerCodec<ST>>,
+/// Answer a pending `SslRequest`, returns true if the connection should be
+/// upgraded to tls.
+async fn negotiate_ssl<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    buf: &mut BytesMut,
     ssl_supported: bool,
 ) -> Result<bool, IOError> {
-    let mut ssl = false;
-    if is_sslrequest_pending(socket.get_ref()).await? {
-        // consume request
-        socket.next().await;
-
-        let response = if ssl_supported {
-            ssl = true;
-            PgWireBackendMessage::SslResponse(SslResponse::Accept)
-        } else {
-            PgWireBackendMessage::SslResponse(SslResponse::Refuse)
-        };
-        socket.send(response).await?;
+    if SslRequest::decode(buf)?.is_none() {
+        return Ok(false);
+    }
+
+    if !buf.is_empty() {
+        // data sent after SslRequest before our response could be injected
+        // by a man-in-the-middle, refuse to process it either way
+        return Err(IOError::new(
+            ErrorKind::InvalidData,
+            "received unencrypted data after SSL request",
+        ));
     }
-    Ok(ssl)
+
+    let response = if ssl_supported {
+        SslResponse::Accept
+    } else {
+        SslResponse::Refuse
+    };
+    let mut response_buf = BytesMut::with_capacity(SslResponse::MESSAGE_LENGTH);
+    response.encode(&mut response_buf)?;
+    stream.write_all(&response_buf).await?;
+
+    Ok(ssl_supported)
 }
 
-pub async fn process_socket<A, Q, EQ>(
-    tcp_socket: TcpStream,
-    tls_acceptor: Option<Arc<TlsAcceptor>>,
-    startup_handler: Arc<A>,
-    query_handler: Arc<Q>,
-    extended_query_handler: Arc<EQ>,
-) -> Result<(), IOError>
-where
-    A: StartupHandler,
-    Q: SimpleQueryHandler,
-    EQ: ExtendedQueryHandler,
-{
-    let addr = tcp_socket.peer_addr()?;
-    tcp_socket.set_nodelay(true)?;
+/// Whether connections use TLS.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SslMode {
+    /// TLS when client asks for it and a tls acceptor is given, plaintext
+    /// otherwise
+    #[default]
+    Prefer,
+    /// Plaintext connections are rejected with `28000`
+    /// (invalid_authorization_specification) before startup. Streams
+    /// processed with an unspecified address, like unix domain sockets, are
+    /// local and exempt.
+    Require,
+    /// `SslRequest` and direct TLS are refused, even if a tls acceptor is
+    /// given.
+    Disable,
+}
+
+/// Server side policy of TLS, by client address.
+#[non_exhaustive]
+#[derive(Debug, Default, Clone, PartialEq, Eq, new)]
+pub struct SslPolicy {
+    /// mode of clients not in `networks`
+    pub mode: SslMode,
+    /// modes of networks, the first network containing the client address
+    /// decides its mode
+    #[new(default)]
+    pub networks: Vec<(HbaAddress, SslMode)>,
+}
 
-    let client_info = DefaultClient::new(addr, false);
-    let mut tcp_socket = Framed::new(tcp_socket, PgWireMessageServerCodec::new(client_info));
-    let ssl = peek_for_sslrequest(&mut tcp_socket, tls_acceptor.is_some()).await?;
+impl SslPolicy {
+    /// Set mode of clients in network `addr/prefix_len`, like
+    /// `SslMode::Prefer` for local clients when TLS is required for others.
+    pub fn with_network(mut self, addr: IpAddr, prefix_len: u8, mode: SslMode) -> SslPolicy {
+        self.networks
+            .push((HbaAddress::Net { addr, prefix_len }, mode));
+        self
+    }
 
-    if !ssl {
-        // use an already configured socket.
-        let mut socket = tcp_socket;
+    /// Get mode of a client address.
+    pub fn mode_for(&self, client_addr: IpAddr) -> SslMode {
+        self.networks
+            .iter()
+            .find(|(network, _)| network.matches(client_addr))
+            .map_or(self.mode, |(_, mode)| *mode)
+    }
+}
 
-        while let Some(Ok(msg)) = socket.next().await {
-            let is_extended_query = msg.is_extended_query();
-            if let Err(e) = process_message(
-                msg,
-                &mut socket,
-                startup_handler.clone(),
-                query_handler.clone(),
-                extended_query_handler.clone(),
-            )
-            .await
-            {
-                process_error(&mut socket, e, is_extended_query).await?;
-            }
+/// Options of TCP sockets of client connections, applied by
+/// `process_socket` and its variants.
+///
+/// Keepalive probes keep idle connections from being dropped by middleboxes,
+/// and detect dead clients, like `tcp_keepalives_*` settings of postgres.
+/// Interval and count of probes are ignored on platforms not supporting them.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpOptions {
+    /// `TCP_NODELAY`, true by default
+    pub nodelay: bool,
+    /// idle time before the first keepalive probe, keepalive is enabled when
+    /// any of keepalive options is set
+    pub keepalive_idle: Option<Duration>,
+    /// time between keepalive probes
+    pub keepalive_interval: Option<Duration>,
+    /// number of unanswered keepalive probes before the connection is closed
+    pub keepalive_retries: Option<u32>,
+    /// `SO_SNDBUF` in bytes
+    pub send_buffer_size: Option<usize>,
+    /// `SO_RCVBUF` in bytes
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl Default for TcpOptions {
+    fn default() -> TcpOptions {
+        TcpOptions {
+            nodelay: true,
+            keepalive_idle: None,
+            keepalive_interval: None,
+            keepalive_retries: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
         }
-    } else {
-        // mention the use of ssl
-        let client_info = DefaultClient::new(addr, true);
-        // safe to unwrap tls_acceptor here
-        let ssl_socket = tls_acceptor
-            .unwrap()
-            .accept(tcp_socket.into_inner())
-            .await?;
-        let mut socket = Framed::new(ssl_socket, PgWireMessageServerCodec::new(client_info));
+    }
+}
 
-        while let Some(Ok(msg)) = socket.next().await {
-            let is_extended_query = msg.is_extended_query();
-            if let Err(e) = process_message(
-                msg,
-                &mut socket,
-                startup_handler.clone(),
-                query_handler.clone(),
-                extended_query_handler.clone(),
-            )
-            .await
-            {
-                process_error(&mut socket, e, is_extended_query).await?;
+impl TcpOptions {
+    pub fn new() -> TcpOptions {
+        TcpOptions::default()
+    }
+
+    pub fn with_nodelay(mut self, nodelay: bool) -> TcpOptions {
+        self.nodelay = nodelay;
+        self
+    }
+
+    pub fn with_keepalive_idle(mut self, idle: Duration) -> TcpOptions {
+        self.keepalive_idle = Some(idle);
+        self
+    }
+
+    pub fn with_keepalive_interval(mut self, interval: Duration) -> TcpOptions {
+        self.keepalive_interval = Some(interval);
+        self
+    }
+
+    pub fn with_keepalive_retries(mut self, retries: u32) -> TcpOptions {
+        self.keepalive_retries = Some(retries);
+        self
+    }
+
+    pub fn with_send_buffer_size(mut self, size: usize) -> TcpOptions {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    pub fn with_recv_buffer_size(mut self, size: usize) -> TcpOptions {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Apply the options to a socket.
+    pub fn apply(&self, socket: &TcpStream) -> Result<(), IOError> {
+        self.apply_to(SockRef::from(socket))
+    }
+
+    pub(crate) fn apply_to(&self, socket: SockRef<'_>) -> Result<(), IOError> {
+        socket.set_tcp_nodelay(self.nodelay)?;
+        if self.keepalive_idle.is_some()
+            || self.keepalive_interval.is_some()
+            || self.keepalive_retries.is_some()
+        {
+            let mut keepalive = TcpKeepalive::new();
+            if let Some(idle) = self.keepalive_idle {
+                keepalive = keepalive.with_time(idle);
+            }
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "ios",
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "windows",
+            ))]
+            if let Some(interval) = self.keepalive_interval {
+                keepalive = keepalive.with_interval(interval);
             }
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "ios",
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "windows",
+            ))]
+            if let Some(retries) = self.keepalive_retries {
+                keepalive = keepalive.with_retries(retries);
+            }
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
         }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
     }
+}
 
-    Ok(())
+/// Message sent to client while a query runs without sending anything, to
+/// keep connections of long queries from being closed by idle timeouts of
+/// clients, proxies and load balancers.
+///
+/// Heartbeats are written between frames of the response, so they never
+/// split a message. They bypass middleware, metrics and wire tap.
+#[derive(Debug, Clone)]
+pub struct QueryHeartbeat {
+    /// Time without sending anything after which a heartbeat is sent.
+    pub interval: Duration,
+    // encoded message
+    frame: Bytes,
+}
+
+impl QueryHeartbeat {
+    /// Heartbeat of a `NoticeResponse` with `message`, in severity `DEBUG`
+    /// which psql and most drivers don't display.
+    pub fn notice(interval: Duration, message: &str) -> QueryHeartbeat {
+        let notice = ErrorInfo::new(
+            "DEBUG".to_owned(),
+            SqlState::SUCCESSFUL_COMPLETION.into(),
+            message.to_owned(),
+        );
+        QueryHeartbeat::new(
+            interval,
+            PgWireBackendMessage::NoticeResponse(notice.into()),
+        )
+    }
+
+    /// Heartbeat of a `ParameterStatus` of `name` and `value`. Clients
+    /// update the parameter, so it's better a parameter they don't use.
+    pub fn parameter_status(interval: Duration, name: &str, value: &str) -> QueryHeartbeat {
+        let status = ParameterStatus::new(name.to_owned(), value.to_owned());
+        QueryHeartbeat::new(interval, PgWireBackendMessage::ParameterStatus(status))
+    }
+
+    fn new(interval: Duration, message: PgWireBackendMessage) -> QueryHeartbeat {
+        let mut frame = BytesMut::new();
+        message
+            .encode(&mut frame)
+            .expect("heartbeat message is encoded");
+        QueryHeartbeat {
+            interval,
+            frame: frame.freeze(),
+        }
+    }
+}
+
+/// Options for processing client connections.
+#[non_exhaustive]
+#[derive(Clone, Default)]
+pub struct ServerOptions {
+    /// Raw bytes sent to clients detected to speak another protocol, like
+    /// HTTP or direct TLS, before closing the connection. A postgres
+    /// `ErrorResponse` describing the problem is sent when unset.
+    pub foreign_protocol_response: Option<Bytes>,
+    /// Initial result limits of each session, handlers can change them with
+    /// `ClientInfo::set_result_limits`.
+    pub result_limits: ResultLimits,
+    /// Initial flush policy of each session, handlers can change it with
+    /// `ClientInfo::set_flush_policy` or per response.
+    pub flush_policy: FlushPolicy,
+    /// Initial statement timeout of each session, handlers can change it with
+    /// `ClientInfo::set_statement_timeout` or per query.
+    pub statement_timeout: Option<Duration>,
+    /// Handler of `START_REPLICATION` from replication connections.
+    pub replication_handler: Option<Arc<dyn ReplicationHandler>>,
+    /// Bytes of outgoing messages buffered before writing to socket. Small
+    /// messages like `DataRow` are coalesced into writes of this size, the
+    /// default is 8KiB. Buffered messages are also written when a response
+    /// is complete.
+    pub write_buffer_size: Option<usize>,
+    /// Observer of connection and query events.
+    pub metrics: Option<Arc<dyn Metrics>>,
+    /// Audit hook called before and after each query.
+    pub query_auditor: Option<Arc<dyn QueryAuditor>>,
+    /// Shutdown coordinator tracking connections.
+    pub graceful_shutdown: Option<GracefulShutdown>,
+    /// Time for a client to complete `SslRequest`, tls handshake, startup and
+    /// authentication, like `authentication_timeout` of postgres which is 1
+    /// minute by default. Connections not ready for query in time are closed.
+    pub authentication_timeout: Option<Duration>,
+    /// Time a session may be idle outside a transaction, like
+    /// `idle_session_timeout` of postgres. Sessions idle longer are
+    /// terminated with `57P05`.
+    pub idle_session_timeout: Option<Duration>,
+    /// Time a session may be idle in an open transaction, like
+    /// `idle_in_transaction_session_timeout` of postgres. Sessions idle longer
+    /// are terminated with `25P03`.
+    pub idle_in_transaction_session_timeout: Option<Duration>,
+    /// Limits on size of messages from client.
+    pub message_limits: MessageLimits,
+    /// Limits on concurrent connections.
+    pub connection_limiter: Option<ConnectionLimiter>,
+    /// Maximum number of named prepared statements of each session, least
+    /// recently used ones are evicted over it.
+    pub max_prepared_statements: Option<usize>,
+    /// Maximum number of named portals of each session, least recently used
+    /// ones are evicted over it.
+    pub max_portals: Option<usize>,
+    /// Middleware of protocol messages, the first is the outermost layer.
+    pub middleware: Vec<Arc<dyn Middleware>>,
+    /// Hook rewriting or vetoing queries before they are dispatched.
+    pub query_rewriter: Option<Arc<dyn QueryRewriter>>,
+    /// Whether TLS is required, preferred or disabled.
+    pub ssl_policy: SslPolicy,
+    /// Options of TCP sockets.
+    pub tcp_options: TcpOptions,
+    /// Logging and dumping of protocol messages.
+    pub wire_tap: Option<WireTap>,
+    /// Maximum bytes of responses buffered in memory by each connection,
+    /// like rows of suspended portals. Queries buffering more fail with
+    /// `53200`. See `memory::SendMemory`.
+    pub max_send_memory: Option<usize>,
+    /// Probe queries of load balancers answered without handlers, and
+    /// recognition of probe connections. See `health::HealthCheck`.
+    pub health_check: Option<HealthCheck>,
+    /// Whether `Terminate` from client cancels the running query, like a
+    /// disconnect. Messages of client are read ahead while a query runs,
+    /// and a `Terminate` found among them closes the session without
+    /// waiting for the query. Off by default, as postgres completes queries
+    /// of clients terminating without waiting for results.
+    pub terminate_cancels_query: bool,
+    /// Heartbeat sent to client during long queries.
+    pub query_heartbeat: Option<QueryHeartbeat>,
+}
+
+impl std::fmt::Debug for ServerOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerOptions")
+            .field("foreign_protocol_response", &self.foreign_protocol_response)
+            .field("result_limits", &self.result_limits)
+            .field("flush_policy", &self.flush_policy)
+            .field("statement_timeout", &self.statement_timeout)
+            .field("replication_handler", &self.replication_handler.is_some())
+            .field("write_buffer_size", &self.write_buffer_size)
+            .field("metrics", &self.metrics.is_some())
+            .field("query_auditor", &self.query_auditor.is_some())
+            .field("graceful_shutdown", &self.graceful_shutdown)
+            .field("authentication_timeout", &self.authentication_timeout)
+            .field("idle_session_timeout", &self.idle_session_timeout)
+            .field(
+                "idle_in_transaction_session_timeout",
+                &self.idle_in_transaction_session_timeout,
+            )
+            .field("message_limits", &self.message_limits)
+            .field("connection_limiter", &self.connection_limiter)
+            .field("max_prepared_statements", &self.max_prepared_statements)
+            .field("max_portals", &self.max_portals)
+            .field("middleware", &self.middleware.len())
+            .field("query_rewriter", &self.query_rewriter.is_some())
+            .field("ssl_policy", &self.ssl_policy)
+            .field("tcp_options", &self.tcp_options)
+            .field("wire_tap", &self.wire_tap)
+            .field("max_send_memory", &self.max_send_memory)
+            .field("health_check", &self.health_check)
+            .field("terminate_cancels_query", &self.terminate_cancels_query)
+            .field("query_heartbeat", &self.query_heartbeat)
+            .finish()
+    }
+}
+
+impl ServerOptions {
+    pub fn new() -> ServerOptions {
+        ServerOptions::default()
+    }
+
+    pub fn with_foreign_protocol_response(mut self, response: Bytes) -> ServerOptions {
+        self.foreign_protocol_response = Some(response);
+        self
+    }
+
+    pub fn with_result_limits(mut self, limits: ResultLimits) -> ServerOptions {
+        self.result_limits = limits;
+        self
+    }
+
+    pub fn with_flush_policy(mut self, policy: FlushPolicy) -> ServerOptions {
+        self.flush_policy = policy;
+        self
+    }
+
+    pub fn with_statement_timeout(mut self, timeout: Duration) -> ServerOptions {
+        self.statement_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_replication_handler(
+        mut self,
+        handler: Arc<dyn ReplicationHandler>,
+    ) -> ServerOptions {
+        self.replication_handler = Some(handler);
+        self
+    }
+
+    pub fn with_write_buffer_size(mut self, size: usize) -> ServerOptions {
+        self.write_buffer_size = Some(size);
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> ServerOptions {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn with_query_auditor(mut self, auditor: Arc<dyn QueryAuditor>) -> ServerOptions {
+        self.query_auditor = Some(auditor);
+        self
+    }
+
+    pub fn with_graceful_shutdown(mut self, shutdown: GracefulShutdown) -> ServerOptions {
+        self.graceful_shutdown = Some(shutdown);
+        self
+    }
+
+    pub fn with_authentication_timeout(mut self, timeout: Duration) -> ServerOptions {
+        self.authentication_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_idle_session_timeout(mut self, timeout: Duration) -> ServerOptions {
+        self.idle_session_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_idle_in_transaction_session_timeout(mut self, timeout: Duration) -> ServerOptions {
+        self.idle_in_transaction_session_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_message_limits(mut self, limits: MessageLimits) -> ServerOptions {
+        self.message_limits = limits;
+        self
+    }
+
+    pub fn with_connection_limiter(mut self, limiter: ConnectionLimiter) -> ServerOptions {
+        self.connection_limiter = Some(limiter);
+        self
+    }
+
+    pub fn with_max_prepared_statements(mut self, max: usize) -> ServerOptions {
+        self.max_prepared_statements = Some(max);
+        self
+    }
+
+    pub fn with_max_portals(mut self, max: usize) -> ServerOptions {
+        self.max_portals = Some(max);
+        self
+    }
+
+    /// Add a middleware, inside the ones added before.
+    pub fn with_middleware(mut self, middleware: Arc<dyn Middleware>) -> ServerOptions {
+        self.middleware.push(middleware);
+        self
+    }
+
+    pub fn with_query_rewriter(mut self, rewriter: Arc<dyn QueryRewriter>) -> ServerOptions {
+        self.query_rewriter = Some(rewriter);
+        self
+    }
+
+    pub fn with_ssl_policy(mut self, policy: SslPolicy) -> ServerOptions {
+        self.ssl_policy = policy;
+        self
+    }
+
+    pub fn with_tcp_options(mut self, options: TcpOptions) -> ServerOptions {
+        self.tcp_options = options;
+        self
+    }
+
+    pub fn with_wire_tap(mut self, tap: WireTap) -> ServerOptions {
+        self.wire_tap = Some(tap);
+        self
+    }
+
+    pub fn with_max_send_memory(mut self, bytes: usize) -> ServerOptions {
+        self.max_send_memory = Some(bytes);
+        self
+    }
+
+    pub fn with_health_check(mut self, health_check: HealthCheck) -> ServerOptions {
+        self.health_check = Some(health_check);
+        self
+    }
+
+    pub fn with_terminate_cancels_query(mut self, cancels: bool) -> ServerOptions {
+        self.terminate_cancels_query = cancels;
+        self
+    }
+
+    pub fn with_query_heartbeat(mut self, heartbeat: QueryHeartbeat) -> ServerOptions {
+        self.query_heartbeat = Some(heartbeat);
+        self
+    }
+}
+
+const HTTP_METHODS: [&[u8]; 10] = [
+    b"GET ",
+    b"POST ",
+    b"PUT ",
+    b"HEAD ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+    // http/2 connection preface
+    b"PRI * HTTP",
+];
+
+/// Check if the first bytes from client are a tls handshake record, with
+/// major version 3. Clients with `sslnegotiation=direct` start tls without
+/// `SslRequest`.
+fn is_tls_handshake(buf: &[u8]) -> bool {
+    buf.len() >= 2 && buf[0] == 0x16 && buf[1] == 0x03
+}
+
+/// Check the first bytes from client for protocols that are obviously not
+/// postgres, returns a description of the protocol.
+fn detect_foreign_protocol(buf: &[u8]) -> Option<&'static str> {
+    if is_tls_handshake(buf) {
+        return Some("TLS handshake");
+    }
+    // the buffer may hold less than a whole method name
+    if HTTP_METHODS.iter().any(|m| {
+        let n = m.len().min(buf.len());
+        n >= 4 && buf[..n] == m[..n]
+    }) {
+        return Some("HTTP request");
+    }
+    None
+}
+
+async fn reject_foreign_client<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    protocol: &str,
+    options: &ServerOptions,
+) -> Result<(), IOError> {
+    if let Some(response) = &options.foreign_protocol_response {
+        stream.write_all(response).await?;
+        stream.shutdown().await
+    } else {
+        let error_info = ErrorInfo::new(
+            "FATAL".to_owned(),
+            SqlState::PROTOCOL_VIOLATION.into(),
+            format!("{protocol} received on a port that expects postgres protocol"),
+        );
+        reject_client(stream, error_info).await
+    }
+}
+
+/// Send `error_info` and close the connection, before startup.
+async fn reject_client<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    error_info: ErrorInfo,
+) -> Result<(), IOError> {
+    let mut buf = BytesMut::new();
+    PgWireBackendMessage::ErrorResponse(error_info.into()).encode(&mut buf)?;
+    stream.write_all(&buf).await?;
+    stream.shutdown().await
+}
+
+/// Get the protocol version a protocol 2.0 or earlier startup packet asks
+/// for. Special requests like `SslRequest` use major version 1234.
+fn legacy_protocol_version(buf: &[u8]) -> Option<(u16, u16)> {
+    if buf.len() < 8 {
+        return None;
+    }
+    let major = u16::from_be_bytes([buf[4], buf[5]]);
+    let minor = u16::from_be_bytes([buf[6], buf[7]]);
+    if (1..3).contains(&major) {
+        Some((major, minor))
+    } else {
+        None
+    }
+}
+
+/// Legacy clients can't read a protocol 3.0 `ErrorResponse`. Like postgres,
+/// reply in protocol 2.0 format, a `E` followed by a null-terminated message.
+async fn reject_legacy_protocol<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    (major, minor): (u16, u16),
+) -> Result<(), IOError> {
+    let mut buf = BytesMut::new();
+    buf.put_u8(b'E');
+    buf.put_slice(
+        format!(
+            "FATAL:  unsupported frontend protocol {major}.{minor}: server supports 3.0 to 3.0\n"
+        )
+        .as_bytes(),
+    );
+    buf.put_u8(0);
+    stream.write_all(&buf).await?;
+    stream.shutdown().await
+}
+
+/// Apply options of the codec and write buffer. With `write_buffer_size`,
+/// messages are flushed to socket in batches of this size.
+fn apply_socket_options<S, ST>(
+    socket: &mut Framed<S, PgWireMessageServerCodec<ST>>,
+    options: &ServerOptions,
+) {
+    if let Some(metrics) = &options.metrics {
+        socket.codec_mut().metrics = Some(ConnectionMetrics::new(metrics.clone()));
+    }
+    socket.codec_mut().limits = options.message_limits;
+    socket.codec_mut().middleware = options.middleware.clone();
+    socket.codec_mut().report_encoded = options.query_heartbeat.is_some();
+    if let Some(tap) = &options.wire_tap {
+        let addr = socket.codec().client_info.socket_addr;
+        socket.codec_mut().wire_tap = Some(ConnectionTap::new(tap.clone(), addr));
+    }
+    if let Some(size) = options.write_buffer_size {
+        socket.set_backpressure_boundary(size);
+        socket.write_buffer_mut().reserve(size);
+    }
+}
+
+fn framed_with_read_buf<S, ST>(
+    socket: S,
+    client_info: DefaultClient<ST>,
+    read_buf: BytesMut,
+) -> Framed<S, PgWireMessageServerCodec<ST>> {
+    let mut parts = FramedParts::new::<PgWireBackendMessage>(
+        socket,
+        PgWireMessageServerCodec::new(client_info),
+    );
+    parts.read_buf = read_buf;
+    Framed::from_parts(parts)
+}
+
+/// Serve messages from client until the connection is closed. Notifications
+/// are delivered when the session is idle.
+async fn process_connection<S, A, Q, EQ, CH>(
+    socket: &mut Framed<S, PgWireMessageServerCodec<EQ::Statement>>,
+    mut notifications: NotificationReceiver,
+    handlers: &Handlers<A, Q, EQ, CH>,
+    options: &ServerOptions,
+    auth_deadline: Option<Instant>,
+) -> Result<(), IOError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    A: StartupHandler,
+    Q: SimpleQueryHandler,
+    EQ: ExtendedQueryHandler,
+    CH: CopyHandler,
+{
+    #[cfg(feature = "tracing")]
+    trace::record_connection_id(socket);
+    let shutdown = options.graceful_shutdown.as_ref();
+    let mut pending_notifications = VecDeque::new();
+    // whether client has run any query, or it's a probe connection
+    let mut queried = false;
+    // end of the last message from client
+    let mut idle_since = Instant::now();
+    loop {
+        if shutdown.is_some_and(GracefulShutdown::is_requested) && is_idle(socket.state()) {
+            return terminate_session(socket, admin_shutdown_error()).await;
+        }
+        let idle_deadline = idle_timeout(socket, options).map(|timeout| idle_since + timeout);
+        tokio::select! {
+            _ = shutdown_requested(shutdown), if is_idle(socket.state()) => {}
+            _ = deadline(auth_deadline), if is_authenticating(socket.state()) => {
+                return Err(authentication_timeout_error());
+            }
+            _ = deadline(idle_deadline) => {
+                let error = idle_timeout_error(socket.transaction_status());
+                return terminate_session(socket, error).await;
+            }
+            msg = socket.next() => {
+                idle_since = Instant::now();
+                let msg = match msg {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(e)) => {
+                        #[cfg(feature = "tracing")]
+                        tracing::warn!(error = %e, "invalid message from client");
+                        if !matches!(e, PgWireError::IoError(_)) {
+                            let error_info =
+                                ErrorInfo::new("FATAL".to_owned(), SqlState::PROTOCOL_VIOLATION.into(), e.to_string());
+                            socket
+                                .send(PgWireBackendMessage::ErrorResponse(error_info.into()))
+                                .await?;
+                        }
+                        break;
+                    }
+                    None => break,
+                };
+                if !is_authenticating(socket.state())
+                    && !matches!(msg, PgWireFrontendMessage::Terminate(_))
+                {
+                    queried = true;
+                }
+                let msg = match answer_health_probe(socket, msg, options).await {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        process_error(socket, e, false).await?;
+                        continue;
+                    }
+                };
+                let is_extended_query = msg.is_extended_query();
+                let msg = match intercept_frontend_message(socket, msg).await {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        process_error(socket, e, is_extended_query).await?;
+                        continue;
+                    }
+                };
+                if let PgWireFrontendMessage::CancelRequest(request) = msg {
+                    // the connection is closed without response
+                    CancelRegistry::global().cancel(&request);
+                    break;
+                }
+                let is_extended_query = match socket.state() {
+                    PgWireConnectionState::CopyInProgress(is_extended_query) => is_extended_query,
+                    _ => msg.is_extended_query(),
+                };
+                let authenticating = is_authenticating(socket.state());
+                let result = tokio::select! {
+                    result = process_message(
+                        msg,
+                        socket,
+                        handlers.startup_handler.clone(),
+                        handlers.query_handler.clone(),
+                        handlers.extended_query_handler.clone(),
+                        handlers.copy_handler.clone(),
+                        options,
+                    ) => result,
+                    // query is still running at the deadline of shutdown
+                    _ = shutdown_forced(shutdown) => {
+                        if let Some(handle) = socket.cancel_handle() {
+                            handle.token().cancel();
+                        }
+                        return terminate_session(socket, admin_shutdown_error()).await;
+                    }
+                    // a slow `StartupHandler` is bounded as well
+                    _ = deadline(auth_deadline), if authenticating => {
+                        return Err(authentication_timeout_error());
+                    }
+                };
+                if let Err(e) = result {
+                    process_error(socket, e, is_extended_query).await?;
+                }
+                idle_since = Instant::now();
+                #[cfg(feature = "tracing")]
+                if authenticating && socket.state() == PgWireConnectionState::ReadyForQuery {
+                    trace::record_session(socket.metadata());
+                }
+            }
+            Some(notification) = notifications.next() => {
+                pending_notifications.push_back(notification);
+            }
+        }
+
+        // notifications arrived during a query wait until it completes
+        if matches!(socket.state(), PgWireConnectionState::ReadyForQuery)
+            && !pending_notifications.is_empty()
+        {
+            for notification in pending_notifications.drain(..) {
+                socket
+                    .feed(PgWireBackendMessage::NotificationResponse(notification))
+                    .await?;
+            }
+            socket.flush().await?;
+        }
+    }
+    if !queried && options.health_check.is_some() {
+        if let Some(metrics) = &options.metrics {
+            metrics.on_health_probe(HealthProbe::Connect);
+        }
+    }
+    Ok(())
+}
+
+/// Answer a probe query of `ServerOptions::health_check`, returns other
+/// messages to process.
+async fn answer_health_probe<S, ST>(
+    socket: &mut Framed<S, PgWireMessageServerCodec<ST>>,
+    message: PgWireFrontendMessage,
+    options: &ServerOptions,
+) -> PgWireResult<Option<PgWireFrontendMessage>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    ST: Send + Sync,
+{
+    let Some(health_check) = &options.health_check else {
+        return Ok(Some(message));
+    };
+    match &message {
+        PgWireFrontendMessage::Query(query)
+            if socket.state() == PgWireConnectionState::ReadyForQuery
+                && health_check.is_probe(&query.query) => {}
+        _ => return Ok(Some(message)),
+    }
+
+    let ready = health_check.is_ready()
+        && !options
+            .graceful_shutdown
+            .as_ref()
+            .is_some_and(GracefulShutdown::is_requested);
+    if let Some(metrics) = &options.metrics {
+        metrics.on_health_probe(HealthProbe::Query { ready });
+    }
+    if !ready {
+        return Err(PgWireError::UserError(Box::new(not_ready_error())));
+    }
+    // not recorded as a query in metrics
+    let metrics = socket.codec_mut().metrics.take();
+    let result = send_rewriter_answer(socket, vec![health_check.response()?]).await;
+    socket.codec_mut().metrics = metrics;
+    result.map(|_| None)
+}
+
+/// Pass a message from client through middleware, the outermost first.
+async fn intercept_frontend_message<S, ST>(
+    socket: &Framed<S, PgWireMessageServerCodec<ST>>,
+    message: PgWireFrontendMessage,
+) -> PgWireResult<Option<PgWireFrontendMessage>>
+where
+    S: Sync,
+    ST: Send + Sync,
+{
+    let mut message = message;
+    for middleware in &socket.codec().middleware {
+        match middleware.on_frontend_message(socket, message).await? {
+            Some(next) => message = next,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(message))
+}
+
+fn is_authenticating(state: PgWireConnectionState) -> bool {
+    matches!(
+        state,
+        PgWireConnectionState::AwaitingStartup | PgWireConnectionState::AuthenticationInProgress
+    )
+}
+
+async fn deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Run `f` of connection setup, failing when `deadline` is reached first.
+async fn before_deadline<F, T>(deadline: Option<Instant>, f: F) -> Result<T, IOError>
+where
+    F: Future<Output = Result<T, IOError>>,
+{
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), f)
+            .await
+            .unwrap_or_else(|_| Err(authentication_timeout_error())),
+        None => f.await,
+    }
+}
+
+fn authentication_timeout_error() -> IOError {
+    IOError::new(ErrorKind::TimedOut, "authentication timeout")
+}
+
+async fn shutdown_requested(shutdown: Option<&GracefulShutdown>) {
+    match shutdown {
+        Some(shutdown) => shutdown.requested().await,
+        None => futures::future::pending().await,
+    }
+}
+
+async fn shutdown_forced(shutdown: Option<&GracefulShutdown>) {
+    match shutdown {
+        Some(shutdown) => shutdown.forced().await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Idle timeout of a session waiting for next query, by its transaction
+/// status.
+fn idle_timeout<S, ST>(
+    socket: &Framed<S, PgWireMessageServerCodec<ST>>,
+    options: &ServerOptions,
+) -> Option<Duration> {
+    if socket.state() != PgWireConnectionState::ReadyForQuery {
+        return None;
+    }
+    match socket.transaction_status() {
+        TransactionStatus::Idle => options.idle_session_timeout,
+        TransactionStatus::Transaction | TransactionStatus::Error => {
+            options.idle_in_transaction_session_timeout
+        }
+    }
+}
+
+fn idle_timeout_error(status: TransactionStatus) -> ErrorInfo {
+    match status {
+        TransactionStatus::Idle => ErrorInfo::new(
+            "FATAL".to_owned(),
+            SqlState::IDLE_SESSION_TIMEOUT.into(),
+            "terminating connection due to idle-session timeout".to_owned(),
+        ),
+        TransactionStatus::Transaction | TransactionStatus::Error => ErrorInfo::new(
+            "FATAL".to_owned(),
+            SqlState::IDLE_IN_TRANSACTION_SESSION_TIMEOUT.into(),
+            "terminating connection due to idle-in-transaction timeout".to_owned(),
+        ),
+    }
+}
+
+/// Close a session with a FATAL `error`, like for shutdown of server.
+async fn terminate_session<S, ST>(
+    socket: &mut Framed<S, PgWireMessageServerCodec<ST>>,
+    error: ErrorInfo,
+) -> Result<(), IOError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    socket
+        .send(PgWireBackendMessage::ErrorResponse(error.into()))
+        .await?;
+    socket.close().await
+}
+
+/// Handlers serving a connection. `COPY FROM STDIN` is refused unless a copy
+/// handler is set with `with_copy_handler`.
+pub struct Handlers<A, Q, EQ, CH = NoopCopyHandler> {
+    pub startup_handler: Arc<A>,
+    pub query_handler: Arc<Q>,
+    pub extended_query_handler: Arc<EQ>,
+    pub copy_handler: Arc<CH>,
+}
+
+impl<A, Q, EQ> Handlers<A, Q, EQ> {
+    pub fn new(
+        startup_handler: Arc<A>,
+        query_handler: Arc<Q>,
+        extended_query_handler: Arc<EQ>,
+    ) -> Handlers<A, Q, EQ> {
+        Handlers {
+            startup_handler,
+            query_handler,
+            extended_query_handler,
+            copy_handler: Arc::new(NoopCopyHandler),
+        }
+    }
+}
+
+impl<H: PgWireHandler> Handlers<H, H, H, H> {
+    /// Serve all parts of the protocol with `handler`.
+    pub fn from_handler(handler: Arc<H>) -> Handlers<H, H, H, H> {
+        Handlers {
+            startup_handler: handler.clone(),
+            query_handler: handler.clone(),
+            extended_query_handler: handler.clone(),
+            copy_handler: handler,
+        }
+    }
+}
+
+impl<A, Q, EQ, CH> Handlers<A, Q, EQ, CH> {
+    pub fn with_copy_handler<CH2>(self, copy_handler: Arc<CH2>) -> Handlers<A, Q, EQ, CH2> {
+        Handlers {
+            startup_handler: self.startup_handler,
+            query_handler: self.query_handler,
+            extended_query_handler: self.extended_query_handler,
+            copy_handler,
+        }
+    }
+}
+
+impl<A, Q, EQ, CH> Clone for Handlers<A, Q, EQ, CH> {
+    fn clone(&self) -> Self {
+        Handlers {
+            startup_handler: self.startup_handler.clone(),
+            query_handler: self.query_handler.clone(),
+            extended_query_handler: self.extended_query_handler.clone(),
+            copy_handler: self.copy_handler.clone(),
+        }
+    }
+}
+
+impl<A, Q, EQ, CH> std::fmt::Debug for Handlers<A, Q, EQ, CH> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Handlers").finish_non_exhaustive()
+    }
+}
+
+/// Process a client connection. `COPY FROM STDIN` is refused, serve
+/// `Handlers` with a copy handler by `process_socket_with_tls` to support it.
+pub async fn process_socket<A, Q, EQ>(
+    tcp_socket: TcpStream,
+    tls_acceptor: Option<Arc<TlsAcceptor>>,
+    startup_handler: Arc<A>,
+    query_handler: Arc<Q>,
+    extended_query_handler: Arc<EQ>,
+) -> Result<(), IOError>
+where
+    A: StartupHandler,
+    Q: SimpleQueryHandler,
+    EQ: ExtendedQueryHandler,
+{
+    process_socket_with_initial_bytes(
+        tcp_socket,
+        BytesMut::new(),
+        tls_acceptor,
+        startup_handler,
+        query_handler,
+        extended_query_handler,
+    )
+    .await
+}
+
+/// Like `process_socket`, for connections whose first bytes have already
+/// been read from the socket, for example to sniff the protocol when
+/// serving postgres and other protocols on the same port. `initial_bytes`
+/// are processed as if they were read from `tcp_socket`.
+pub async fn process_socket_with_initial_bytes<A, Q, EQ>(
+    tcp_socket: TcpStream,
+    initial_bytes: BytesMut,
+    tls_acceptor: Option<Arc<TlsAcceptor>>,
+    startup_handler: Arc<A>,
+    query_handler: Arc<Q>,
+    extended_query_handler: Arc<EQ>,
+) -> Result<(), IOError>
+where
+    A: StartupHandler,
+    Q: SimpleQueryHandler,
+    EQ: ExtendedQueryHandler,
+{
+    process_socket_with_options(
+        tcp_socket,
+        initial_bytes,
+        tls_acceptor,
+        Arc::new(ServerOptions::default()),
+        startup_handler,
+        query_handler,
+        extended_query_handler,
+    )
+    .await
+}
+
+/// Process a client connection with given `ServerOptions`. `initial_bytes`
+/// are bytes already read from `tcp_socket`, it can be empty.
+pub async fn process_socket_with_options<A, Q, EQ>(
+    tcp_socket: TcpStream,
+    initial_bytes: BytesMut,
+    tls_acceptor: Option<Arc<TlsAcceptor>>,
+    options: Arc<ServerOptions>,
+    startup_handler: Arc<A>,
+    query_handler: Arc<Q>,
+    extended_query_handler: Arc<EQ>,
+) -> Result<(), IOError>
+where
+    A: StartupHandler,
+    Q: SimpleQueryHandler,
+    EQ: ExtendedQueryHandler,
+{
+    process_socket_with_tls(
+        tcp_socket,
+        initial_bytes,
+        tls_acceptor,
+        options,
+        Handlers::new(startup_handler, query_handler, extended_query_handler),
+    )
+    .await
+}
+
+/// Process a client connection with a single handler implementing all of
+/// `PgWireHandler`.
+pub async fn process_socket_with_handler<H>(
+    tcp_socket: TcpStream,
+    tls_acceptor: Option<Arc<TlsAcceptor>>,
+    options: Arc<ServerOptions>,
+    handler: Arc<H>,
+) -> Result<(), IOError>
+where
+    H: PgWireHandler,
+{
+    process_socket_with_tls(
+        tcp_socket,
+        BytesMut::new(),
+        tls_acceptor,
+        options,
+        Handlers::from_handler(handler),
+    )
+    .await
+}
+
+/// A stream clients connect with, like `TcpStream`, `UnixStream` or
+/// `tokio::io::DuplexStream`.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static {}
+
+impl<T> Transport for T where T: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static {}
+
+/// Stream with bytes already read from it, which are read again before the
+/// rest of the stream. Used to start tls on bytes read to detect the
+/// protocol.
+#[derive(Debug)]
+pub struct ReplayStream<S> {
+    replay: BytesMut,
+    inner: S,
+}
+
+impl<S> ReplayStream<S> {
+    pub fn new(replay: BytesMut, inner: S) -> ReplayStream<S> {
+        ReplayStream { replay, inner }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ReplayStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if !self.replay.is_empty() {
+            let n = self.replay.len().min(buf.remaining());
+            buf.put_slice(&self.replay.split_to(n));
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ReplayStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// bytes read ahead while watching for disconnect, reading stops after that
+const READ_AHEAD_LIMIT: usize = 64 * 1024;
+
+/// How a client left during a query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Disconnect {
+    Closed,
+    Terminated,
+}
+
+/// Scans frames of client for `Terminate`, from a frame boundary.
+#[derive(Debug, Default)]
+struct FrameScanner {
+    // tag and length of next frame, while incomplete
+    header: Vec<u8>,
+    // body bytes of current frame not scanned yet
+    remaining: usize,
+}
+
+impl FrameScanner {
+    /// Scan `bytes` following the bytes scanned already, returns true once a
+    /// `Terminate` is found.
+    fn scan(&mut self, mut bytes: &[u8]) -> bool {
+        while !bytes.is_empty() {
+            if self.remaining > 0 {
+                let n = self.remaining.min(bytes.len());
+                self.remaining -= n;
+                bytes = &bytes[n..];
+                continue;
+            }
+            let n = (5 - self.header.len()).min(bytes.len());
+            self.header.extend_from_slice(&bytes[..n]);
+            bytes = &bytes[n..];
+            if self.header.len() == 5 {
+                let len = i32::from_be_bytes([
+                    self.header[1],
+                    self.header[2],
+                    self.header[3],
+                    self.header[4],
+                ]);
+                if self.header[0] == b'X' && len == 4 {
+                    return true;
+                }
+                self.remaining = (len.max(4) - 4) as usize;
+                self.header.clear();
+            }
+        }
+        false
+    }
+}
+
+#[derive(Debug)]
+struct WatchState<S> {
+    stream: S,
+    read_ahead: BytesMut,
+    closed: bool,
+    // `Terminate` is watched for during queries
+    watch_terminate: bool,
+    scanner: Option<FrameScanner>,
+    terminated: bool,
+    // bytes of frames encoded by codec, and of them written to stream, the
+    // stream is at a frame boundary when they are equal
+    encoded: u64,
+    written: u64,
+    // heartbeat not written yet, written before any other bytes
+    injected: BytesMut,
+    last_write: Instant,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WatchState<S> {
+    fn disconnect(&self) -> Disconnect {
+        if self.terminated {
+            Disconnect::Terminated
+        } else {
+            Disconnect::Closed
+        }
+    }
+
+    fn terminate(&mut self) {
+        // messages after it are never processed
+        self.terminated = true;
+        self.closed = true;
+        self.read_ahead.clear();
+    }
+
+    fn poll_write_injected(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while !self.injected.is_empty() {
+            let n = match Pin::new(&mut self.stream).poll_write(cx, &self.injected) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            let _ = self.injected.split_to(n);
+            self.last_write = Instant::now();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Watches the stream of a connection while a query is running, for
+/// disconnect and `Terminate` of client by reading ahead from it, and writes
+/// heartbeats to it.
+trait WatchDisconnect: Send + Sync {
+    /// Start watching a query, `unread` are bytes read from stream but not
+    /// decoded yet.
+    fn start_query(&self, unread: &[u8]);
+
+    fn poll_disconnected(&self, cx: &mut Context<'_>) -> Poll<Disconnect>;
+
+    /// `bytes` of frames are encoded by codec.
+    fn on_encoded(&self, bytes: usize);
+
+    /// Queue `frame` if nothing is written for `idle`, and the stream is at a
+    /// frame boundary.
+    fn inject(&self, frame: &[u8], idle: Duration);
+
+    /// Write and flush the queued frame.
+    fn poll_injected(&self, cx: &mut Context<'_>) -> Poll<()>;
+}
+
+type DisconnectWatcher = Arc<dyn WatchDisconnect>;
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> WatchDisconnect for Mutex<WatchState<S>> {
+    fn start_query(&self, unread: &[u8]) {
+        let mut state = self.lock().unwrap();
+        if !state.watch_terminate {
+            return;
+        }
+        let mut scanner = FrameScanner::default();
+        if scanner.scan(unread) || scanner.scan(&state.read_ahead) {
+            state.terminate();
+        }
+        state.scanner = Some(scanner);
+    }
+
+    fn poll_disconnected(&self, cx: &mut Context<'_>) -> Poll<Disconnect> {
+        let mut state = self.lock().unwrap();
+        let mut chunk = [0u8; 4096];
+        loop {
+            if state.closed {
+                return Poll::Ready(state.disconnect());
+            }
+            if state.read_ahead.len() >= READ_AHEAD_LIMIT {
+                // a client sending this much while waiting is alive
+                return Poll::Pending;
+            }
+            let mut buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut state.stream).poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(())) if buf.filled().is_empty() => state.closed = true,
+                Poll::Ready(Ok(())) => {
+                    state.read_ahead.extend_from_slice(buf.filled());
+                    if let Some(scanner) = &mut state.scanner {
+                        if scanner.scan(buf.filled()) {
+                            state.terminate();
+                        }
+                    }
+                }
+                Poll::Ready(Err(_)) => state.closed = true,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    fn on_encoded(&self, bytes: usize) {
+        self.lock().unwrap().encoded += bytes as u64;
+    }
+
+    fn inject(&self, frame: &[u8], idle: Duration) {
+        let mut state = self.lock().unwrap();
+        if state.injected.is_empty()
+            && state.encoded == state.written
+            && state.last_write.elapsed() >= idle
+        {
+            state.injected.extend_from_slice(frame);
+        }
+    }
+
+    fn poll_injected(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.lock().unwrap();
+        if state.injected.is_empty() {
+            return Poll::Ready(());
+        }
+        // errors are left to the writes of response
+        match state.poll_write_injected(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut state.stream).poll_flush(cx).map(|_| ()),
+            Poll::Ready(Err(_)) => Poll::Ready(()),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Stream of a connection, watched for disconnect while a query is running.
+/// Messages read ahead by the watcher, like pipelined queries, are read
+/// again from it.
+#[derive(Debug)]
+struct WatchedStream<S> {
+    state: Arc<Mutex<WatchState<S>>>,
+}
+
+impl<S: Transport> WatchedStream<S> {
+    fn new(stream: S, watch_terminate: bool) -> (WatchedStream<S>, DisconnectWatcher) {
+        let state = Arc::new(Mutex::new(WatchState {
+            stream,
+            read_ahead: BytesMut::new(),
+            closed: false,
+            watch_terminate,
+            scanner: None,
+            terminated: false,
+            encoded: 0,
+            written: 0,
+            injected: BytesMut::new(),
+            last_write: Instant::now(),
+        }));
+        (
+            WatchedStream {
+                state: state.clone(),
+            },
+            state,
+        )
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for WatchedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.read_ahead.is_empty() {
+            let n = state.read_ahead.len().min(buf.remaining());
+            buf.put_slice(&state.read_ahead.split_to(n));
+            return Poll::Ready(Ok(()));
+        }
+        if state.closed {
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut state.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WatchedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut state = self.state.lock().unwrap();
+        ready!(state.poll_write_injected(cx))?;
+        let n = ready!(Pin::new(&mut state.stream).poll_write(cx, buf))?;
+        state.written += n as u64;
+        state.last_write = Instant::now();
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let mut state = self.state.lock().unwrap();
+        ready!(state.poll_write_injected(cx))?;
+        Pin::new(&mut state.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.state.lock().unwrap().stream).poll_shutdown(cx)
+    }
+}
+
+/// ALPN protocol required for direct tls connections.
+pub const POSTGRESQL_ALPN: &[u8] = b"postgresql";
+
+/// Upgrade of a connection to tls, after `SSLRequest` of client is accepted,
+/// or when client starts tls directly.
+///
+/// Implemented for rustls `TlsAcceptor`, and for
+/// `tokio_native_tls::TlsAcceptor` with feature `native-tls`. Implement it to
+/// use other tls libraries with `process_socket_with_tls` or
+/// `process_stream`.
+#[async_trait]
+pub trait TlsUpgrade: Send + Sync {
+    type Stream<S: Transport>: Transport;
+
+    /// Perform tls handshake on `socket`.
+    async fn upgrade<S: Transport>(
+        &self,
+        socket: ReplayStream<S>,
+    ) -> Result<Self::Stream<S>, IOError>;
+
+    /// DER encoded certificate chain presented by client, end-entity
+    /// certificate first.
+    fn client_certificates<S: Transport>(_stream: &Self::Stream<S>) -> Option<Vec<Vec<u8>>> {
+        None
+    }
+
+    /// Protocol negotiated by ALPN. Like postgres, direct tls connections
+    /// are refused unless `postgresql` is negotiated, so the server config
+    /// should offer `POSTGRESQL_ALPN`.
+    fn alpn_protocol<S: Transport>(_stream: &Self::Stream<S>) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Details of the tls session, for `ClientInfo::tls_info`.
+    fn tls_info<S: Transport>(_stream: &Self::Stream<S>) -> Option<TlsInfo> {
+        None
+    }
+}
+
+#[async_trait]
+impl TlsUpgrade for TlsAcceptor {
+    type Stream<S: Transport> = TlsStream<ReplayStream<S>>;
+
+    async fn upgrade<S: Transport>(
+        &self,
+        socket: ReplayStream<S>,
+    ) -> Result<Self::Stream<S>, IOError> {
+        self.accept(socket).await
+    }
+
+    fn client_certificates<S: Transport>(stream: &Self::Stream<S>) -> Option<Vec<Vec<u8>>> {
+        stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .map(|certs| certs.iter().map(|cert| cert.to_vec()).collect())
+    }
+
+    fn alpn_protocol<S: Transport>(stream: &Self::Stream<S>) -> Option<Vec<u8>> {
+        stream.get_ref().1.alpn_protocol().map(|p| p.to_vec())
+    }
+
+    fn tls_info<S: Transport>(stream: &Self::Stream<S>) -> Option<TlsInfo> {
+        use tokio_rustls::rustls::ProtocolVersion;
+
+        let conn = stream.get_ref().1;
+        let version = match conn.protocol_version()? {
+            ProtocolVersion::TLSv1_2 => "TLSv1.2".to_owned(),
+            ProtocolVersion::TLSv1_3 => "TLSv1.3".to_owned(),
+            other => format!("{other:?}"),
+        };
+        let suite = conn.negotiated_cipher_suite()?.suite();
+        let cipher = suite
+            .as_str()
+            .map_or_else(|| format!("{suite:?}"), str::to_owned);
+        let mut info = TlsInfo::new(version, cipher);
+        info.server_name = conn.server_name().map(str::to_owned);
+        info.alpn_protocol = Self::alpn_protocol::<S>(stream);
+        Some(info)
+    }
+}
+
+/// native-tls can't offer ALPN from server, so direct tls is not supported.
+#[cfg(feature = "native-tls")]
+#[async_trait]
+impl TlsUpgrade for tokio_native_tls::TlsAcceptor {
+    type Stream<S: Transport> = tokio_native_tls::TlsStream<ReplayStream<S>>;
+
+    async fn upgrade<S: Transport>(
+        &self,
+        socket: ReplayStream<S>,
+    ) -> Result<Self::Stream<S>, IOError> {
+        self.accept(socket)
+            .await
+            .map_err(|e| IOError::new(ErrorKind::ConnectionAborted, e))
+    }
+
+    /// Only the end-entity certificate is available from native-tls.
+    fn client_certificates<S: Transport>(stream: &Self::Stream<S>) -> Option<Vec<Vec<u8>>> {
+        let cert = stream.get_ref().peer_certificate().ok()??;
+        cert.to_der().ok().map(|der| vec![der])
+    }
+}
+
+fn new_client_info<ST>(
+    addr: SocketAddr,
+    is_secure: bool,
+    options: &ServerOptions,
+    listener: Option<Arc<dyn PortalStoreListener<ST>>>,
+) -> (DefaultClient<ST>, NotificationReceiver) {
+    let mut client_info = DefaultClient::new(addr, is_secure);
+    let mut portal_store = MemPortalStore::new();
+    if let Some(max) = options.max_prepared_statements {
+        portal_store = portal_store.with_max_statements(max);
+    }
+    if let Some(max) = options.max_portals {
+        portal_store = portal_store.with_max_portals(max);
+    }
+    if let Some(listener) = listener {
+        portal_store = portal_store.with_listener(listener);
+    }
+    client_info.portal_store = portal_store;
+    client_info.result_limits = options.result_limits.clone();
+    client_info.flush_policy = options.flush_policy;
+    client_info.statement_timeout = options.statement_timeout;
+    client_info.cancel_handle = Some(CancelRegistry::global().register());
+    let (notification_sink, notifications) = NotificationSink::channel();
+    client_info.notification_sink = Some(notification_sink);
+    client_info.send_memory = Some(SendMemory::with_metrics(
+        options.max_send_memory,
+        options.metrics.clone(),
+    ));
+    (client_info, notifications)
+}
+
+/// Like `process_socket_with_options`, with any tls library implementing
+/// `TlsUpgrade`, and `Handlers` which may include a copy handler.
+pub async fn process_socket_with_tls<T, A, Q, EQ, CH>(
+    tcp_socket: TcpStream,
+    initial_bytes: BytesMut,
+    tls: Option<Arc<T>>,
+    options: Arc<ServerOptions>,
+    handlers: Handlers<A, Q, EQ, CH>,
+) -> Result<(), IOError>
+where
+    T: TlsUpgrade,
+    A: StartupHandler,
+    Q: SimpleQueryHandler,
+    EQ: ExtendedQueryHandler,
+    CH: CopyHandler,
+{
+    let addr = tcp_socket.peer_addr()?;
+    options.tcp_options.apply(&tcp_socket)?;
+
+    process_stream(tcp_socket, addr, initial_bytes, tls, options, handlers).await
+}
+
+/// Process a client connection on any stream, like unix domain sockets or
+/// in-memory pipes. `socket_addr` is reported by `ClientInfo::socket_addr`,
+/// use an unspecified address like `0.0.0.0:0` for streams without one.
+/// `initial_bytes` are bytes already read from `stream`, it can be empty.
+///
+/// Pass `None::<Arc<TlsAcceptor>>` for streams without tls.
+pub async fn process_stream<S, T, A, Q, EQ, CH>(
+    mut stream: S,
+    addr: SocketAddr,
+    initial_bytes: BytesMut,
+    tls: Option<Arc<T>>,
+    options: Arc<ServerOptions>,
+    handlers: Handlers<A, Q, EQ, CH>,
+) -> Result<(), IOError>
+where
+    S: Transport,
+    T: TlsUpgrade,
+    A: StartupHandler,
+    Q: SimpleQueryHandler,
+    EQ: ExtendedQueryHandler,
+    CH: CopyHandler,
+{
+    let _connection = options
+        .metrics
+        .clone()
+        .map(|metrics| ConnectionGuard::new(metrics, addr));
+    let admission = options
+        .connection_limiter
+        .as_ref()
+        .map(|limiter| limiter.try_admit(addr))
+        .transpose();
+    let _admitted = match admission {
+        Ok(permit) => permit,
+        Err(error) => {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(peer = %addr, error = %error, "rejected connection");
+            return reject_client(&mut stream, error.to_error_info()).await;
+        }
+    };
+    let _tracked = options
+        .graceful_shutdown
+        .as_ref()
+        .map(GracefulShutdown::track);
+    let serve = serve_stream(stream, addr, initial_bytes, tls, options, handlers);
+    #[cfg(feature = "tracing")]
+    let serve = tracing::Instrument::instrument(serve, trace::connection_span(addr));
+    serve.await
+}
+
+async fn serve_stream<S, T, A, Q, EQ, CH>(
+    mut stream: S,
+    addr: SocketAddr,
+    initial_bytes: BytesMut,
+    tls: Option<Arc<T>>,
+    options: Arc<ServerOptions>,
+    handlers: Handlers<A, Q, EQ, CH>,
+) -> Result<(), IOError>
+where
+    S: Transport,
+    T: TlsUpgrade,
+    A: StartupHandler,
+    Q: SimpleQueryHandler,
+    EQ: ExtendedQueryHandler,
+    CH: CopyHandler,
+{
+    let auth_deadline = options
+        .authentication_timeout
+        .map(|timeout| Instant::now() + timeout);
+    let ssl_mode = options.ssl_policy.mode_for(addr.ip());
+    let tls = tls.filter(|_| ssl_mode != SslMode::Disable);
+    let mut read_buf = initial_bytes;
+    before_deadline(
+        auth_deadline,
+        read_sslrequest_prefix(&mut stream, &mut read_buf),
+    )
+    .await?;
+    before_deadline(auth_deadline, decline_gssenc(&mut stream, &mut read_buf)).await?;
+    let direct_tls = tls.is_some() && is_tls_handshake(&read_buf);
+    if !direct_tls {
+        if let Some(protocol) = detect_foreign_protocol(&read_buf) {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(protocol, "rejected client of foreign protocol");
+            return reject_foreign_client(&mut stream, protocol, &options).await;
+        }
+        if let Some(version) = legacy_protocol_version(&read_buf) {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(?version, "rejected client of legacy protocol");
+            return reject_legacy_protocol(&mut stream, version).await;
+        }
+    }
+
+    let ssl = direct_tls
+        || before_deadline(
+            auth_deadline,
+            negotiate_ssl(&mut stream, &mut read_buf, tls.is_some()),
+        )
+        .await?;
+
+    match tls {
+        Some(tls) if ssl => {
+            let (mut client_info, notifications) = new_client_info(
+                addr,
+                true,
+                &options,
+                handlers.extended_query_handler.portal_store_listener(),
+            );
+            // bytes of direct tls handshake are replayed to tls library,
+            // it's empty after `SslRequest`
+            let replay = std::mem::take(&mut read_buf);
+            let ssl_socket = before_deadline(
+                auth_deadline,
+                tls.upgrade(ReplayStream::new(replay, stream)),
+            )
+            .await?;
+            if direct_tls && T::alpn_protocol::<S>(&ssl_socket).as_deref() != Some(POSTGRESQL_ALPN)
+            {
+                return Err(IOError::new(
+                    ErrorKind::InvalidData,
+                    "direct tls connection without ALPN postgresql",
+                ));
+            }
+            client_info.client_certificates = T::client_certificates::<S>(&ssl_socket);
+            client_info.tls_info = T::tls_info::<S>(&ssl_socket);
+            let (ssl_socket, watcher) =
+                WatchedStream::new(ssl_socket, options.terminate_cancels_query);
+            let mut socket = Framed::new(ssl_socket, PgWireMessageServerCodec::new(client_info));
+            socket.codec_mut().disconnect = Some(watcher);
+            apply_socket_options(&mut socket, &options);
+
+            process_connection(
+                &mut socket,
+                notifications,
+                &handlers,
+                &options,
+                auth_deadline,
+            )
+            .await?;
+        }
+        _ => {
+            if ssl_mode == SslMode::Require && !addr.ip().is_unspecified() {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(peer = %addr, "rejected connection without tls");
+                let error_info = ErrorInfo::new(
+                    "FATAL".to_owned(),
+                    SqlState::INVALID_AUTHORIZATION_SPECIFICATION.into(),
+                    format!("TLS is required for connections from {}", addr.ip()),
+                );
+                return reject_client(&mut stream, error_info).await;
+            }
+            let (client_info, notifications) = new_client_info(
+                addr,
+                false,
+                &options,
+                handlers.extended_query_handler.portal_store_listener(),
+            );
+            let (stream, watcher) = WatchedStream::new(stream, options.terminate_cancels_query);
+            let mut socket = framed_with_read_buf(stream, client_info, read_buf);
+            socket.codec_mut().disconnect = Some(watcher);
+            apply_socket_options(&mut socket, &options);
+
+            process_connection(
+                &mut socket,
+                notifications,
+                &handlers,
+                &options,
+                auth_deadline,
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Spans and events of feature `tracing`.
+#[cfg(feature = "tracing")]
+mod trace {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+
+    use tracing::field::Empty;
+    use tracing::Span;
+
+    use crate::api::comment::SqlComment;
+    use crate::api::store::PortalStore;
+    use crate::api::{ClientInfo, METADATA_DATABASE, METADATA_USER};
+    use crate::error::PgWireError;
+    use crate::messages::PgWireFrontendMessage;
+
+    /// Span of a connection, `id` is the process id sent in `BackendKeyData`,
+    /// `user` and `database` are recorded after startup.
+    pub(super) fn connection_span(addr: SocketAddr) -> Span {
+        tracing::info_span!(
+            "pgwire_connection",
+            peer = %addr,
+            id = Empty,
+            user = Empty,
+            database = Empty
+        )
+    }
+
+    pub(super) fn record_connection_id<C: ClientInfo>(client: &C) {
+        if let Some(handle) = client.cancel_handle() {
+            Span::current().record("id", handle.backend_key_data().pid);
+        }
+    }
+
+    pub(super) fn record_session(metadata: &HashMap<String, String>) {
+        let span = Span::current();
+        if let Some(user) = metadata.get(METADATA_USER) {
+            span.record("user", user.as_str());
+        }
+        if let Some(database) = metadata.get(METADATA_DATABASE) {
+            span.record("database", database.as_str());
+        }
+        tracing::debug!("session started");
+    }
+
+    /// Span of a statement run by `Query`, `Parse` or `Execute`, with its
+    /// sqlcommenter metadata. Other messages run in the connection span.
+    pub(super) fn statement_span<P: PortalStore>(
+        message: &PgWireFrontendMessage,
+        portal_store: &P,
+    ) -> Span {
+        match message {
+            PgWireFrontendMessage::Query(query) => {
+                let comment = SqlComment::parse(&query.query);
+                tracing::info_span!(
+                    "query",
+                    statement = %query.query,
+                    traceparent = comment.as_ref().and_then(SqlComment::traceparent),
+                    comment = comment.as_ref().map(tracing::field::display)
+                )
+            }
+            PgWireFrontendMessage::Parse(parse) => {
+                let comment = SqlComment::parse(&parse.query);
+                tracing::info_span!(
+                    "parse",
+                    name = parse.name.as_deref().unwrap_or_default(),
+                    statement = %parse.query,
+                    traceparent = comment.as_ref().and_then(SqlComment::traceparent),
+                    comment = comment.as_ref().map(tracing::field::display)
+                )
+            }
+            PgWireFrontendMessage::Execute(execute) => {
+                let portal = execute.name.as_deref().unwrap_or_default();
+                let (statement, comment) = portal_store
+                    .get_portal(execute.name.as_deref().unwrap_or(crate::api::DEFAULT_NAME))
+                    .map(|portal| {
+                        (
+                            portal.statement.id.clone(),
+                            portal.statement.comment.clone(),
+                        )
+                    })
+                    .unzip();
+                let comment = comment.flatten();
+                tracing::info_span!(
+                    "execute",
+                    portal,
+                    statement = statement.as_deref(),
+                    traceparent = comment.as_ref().and_then(SqlComment::traceparent),
+                    comment = comment.as_ref().map(tracing::field::display)
+                )
+            }
+            _ => Span::none(),
+        }
+    }
+
+    pub(super) fn error_event(error: &PgWireError) {
+        match error {
+            PgWireError::UserError(info) => {
+                tracing::debug!(code = %info.code, message = %info.message, "error response");
+            }
+            PgWireError::ApiError(e) => tracing::warn!(error = %e, "handler error"),
+            e => tracing::warn!(error = %e, "protocol error"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::fmt::Debug;
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+    use futures::Sink;
+    use tokio::net::TcpListener;
+
+    use crate::api::auth::noop::NoopStartupHandler;
+    use crate::api::copy::NoopCopyHandler;
+    use crate::api::portal::Portal;
+    use crate::api::query::PlaceholderExtendedQueryHandler;
+    use crate::api::results::{CopyResponse, FieldFormat, Response};
+    use crate::api::stmt::NoopQueryParser;
+    use crate::messages::copy::CopyData;
+    use crate::messages::extendedquery::{Bind, Execute, Flush, Parse, Sync as PgSync};
+    use crate::messages::replication::{StandbyStatusUpdate, XLogData};
+    use crate::messages::simplequery::Query;
+    use crate::messages::startup::{Authentication, CancelRequest, Startup};
+
+    #[tokio::test]
+    async fn test_negotiate_ssl_with_initial_bytes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            // first 3 bytes of SslRequest were consumed by the sniffer
+            let mut request = BytesMut::new();
+            SslRequest.encode(&mut request).unwrap();
+            client.write_all(&request[3..]).await.unwrap();
+            client.read_u8().await.unwrap()
+        });
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut request = BytesMut::new();
+        SslRequest.encode(&mut request).unwrap();
+        let mut buf = BytesMut::from(&request[..3]);
+        read_sslrequest_prefix(&mut server, &mut buf).await.unwrap();
+        assert!(!negotiate_ssl(&mut server, &mut buf, false).await.unwrap());
+        assert!(buf.is_empty());
+        assert_eq!(SslResponse::BYTE_REFUSE, client.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_decline_gssenc() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let mut request = BytesMut::new();
+        GssEncRequest.encode(&mut request).unwrap();
+        SslRequest.encode(&mut request).unwrap();
+        client.write_all(&request).await.unwrap();
+
+        let mut buf = BytesMut::new();
+        read_sslrequest_prefix(&mut server, &mut buf).await.unwrap();
+        decline_gssenc(&mut server, &mut buf).await.unwrap();
+        assert_eq!(SslResponse::BYTE_REFUSE, client.read_u8().await.unwrap());
+        // the next request is read for ssl negotiation
+        assert!(!negotiate_ssl(&mut server, &mut buf, false).await.unwrap());
+        assert_eq!(SslResponse::BYTE_REFUSE, client.read_u8().await.unwrap());
+
+        // data pipelined after the request is refused
+        let mut request = BytesMut::new();
+        GssEncRequest.encode(&mut request).unwrap();
+        Startup::new().encode(&mut request).unwrap();
+        let mut buf = request;
+        assert!(decline_gssenc(&mut server, &mut buf).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_ssl_startup_in_initial_bytes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move { TcpStream::connect(addr).await.unwrap() });
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut startup = BytesMut::new();
+        Startup::new().encode(&mut startup).unwrap();
+        let mut buf = startup.clone();
+        assert!(!negotiate_ssl(&mut server, &mut buf, true).await.unwrap());
+        // nothing consumed, the startup message is decoded later
+        assert_eq!(startup, buf);
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn test_cancellable() {
+        let registry = CancelRegistry::new();
+        let handle = registry.register();
+        let key = handle.backend_key_data();
+        let token = handle.reset();
+
+        let query = cancellable(Some(token), None, None, futures::future::pending());
+        registry.cancel(&CancelRequest::new(key.pid, key.secret_key));
+        let Err(PgWireError::UserError(error)) = query.await else {
+            panic!("expected query to be cancelled");
+        };
+        assert_eq!("57014", error.code);
+
+        assert!(
+            cancellable(Some(handle.reset()), None, None, async { Ok(()) })
+                .await
+                .is_ok()
+        );
+
+        let token = handle.reset();
+        let timeout = Some(Duration::from_millis(10));
+        let query = cancellable(
+            Some(token.clone()),
+            None,
+            timeout,
+            futures::future::pending(),
+        );
+        let Err(PgWireError::UserError(error)) = query.await else {
+            panic!("expected query to time out");
+        };
+        assert_eq!("57014", error.code);
+        assert_eq!(
+            "canceling statement due to statement timeout",
+            error.message
+        );
+        assert!(token.is_cancelled());
+
+        let query = cancellable(None, None, timeout, futures::future::pending());
+        assert!(matches!(query.await, Err(PgWireError::UserError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_statement_timeout() {
+        struct SleepQueryHandler;
+
+        #[async_trait]
+        impl SimpleQueryHandler for SleepQueryHandler {
+            async fn do_query<'a, 'b: 'a, C>(
+                &'b self,
+                _client: &mut C,
+                _query: &'a str,
+            ) -> PgWireResult<Vec<Response<'a>>>
+            where
+                C: ClientInfo + Unpin + Send + Sync,
+            {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok(vec![Response::Execution(Tag::select(1))])
+            }
+
+            fn statement_timeout(
+                &self,
+                query: &str,
+                timeout: Option<Duration>,
+            ) -> Option<Duration> {
+                if query.contains("/* no timeout */") {
+                    None
+                } else {
+                    timeout
+                }
+            }
+        }
+
+        let (server, mut client) = tokio::io::duplex(4096);
+        let server = tokio::spawn(process_stream(
+            server,
+            "0.0.0.0:0".parse().unwrap(),
+            BytesMut::new(),
+            None::<Arc<TlsAcceptor>>,
+            Arc::new(ServerOptions::new().with_statement_timeout(Duration::from_millis(10))),
+            Handlers::new(
+                Arc::new(NoopStartupHandler),
+                Arc::new(SleepQueryHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+            )
+            .with_copy_handler(Arc::new(CountingCopyHandler::default())),
+        ));
+        startup(&mut client).await;
+        let mut buf = BytesMut::new();
+        PgWireFrontendMessage::Query(Query::new("SELECT pg_sleep(1)".to_owned()))
+            .encode(&mut buf)
+            .unwrap();
+        PgWireFrontendMessage::Query(Query::new("/* no timeout */ SELECT 1".to_owned()))
+            .encode(&mut buf)
+            .unwrap();
+        client.write_all(&buf).await.unwrap();
+
+        let mut buf = Vec::new();
+        while !String::from_utf8_lossy(&buf).contains("SELECT 1") {
+            assert!(client.read_buf(&mut buf).await.unwrap() > 0);
+        }
+        let response = String::from_utf8_lossy(&buf);
+        let timed_out = response
+            .find("canceling statement due to statement timeout")
+            .unwrap();
+        // the session continues, and the overridden query completes
+        assert!(response[timed_out..].contains("SELECT 1"));
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_decode_cancel_request() {
+        let mut codec = PgWireMessageServerCodec::<String>::new(DefaultClient::new(
+            "127.0.0.1:5432".parse().unwrap(),
+            false,
+        ));
+        let mut buf = BytesMut::new();
+        CancelRequest::new(42, 1024).encode(&mut buf).unwrap();
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(PgWireFrontendMessage::CancelRequest(CancelRequest {
+                pid: 42,
+                secret_key: 1024
+            }))
+        ));
+        assert!(buf.is_empty());
+    }
+
+    struct CopyQueryHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for CopyQueryHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(vec![Response::CopyIn(CopyResponse::new(
+                FieldFormat::Text,
+                2,
+            ))])
+        }
+    }
+
+    #[derive(Default)]
+    struct CountingCopyHandler(Mutex<usize>);
+
+    #[async_trait]
+    impl CopyHandler for CountingCopyHandler {
+        async fn on_copy_data<C>(&self, _client: &mut C, copy_data: CopyData) -> PgWireResult<()>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            let rows = copy_data.data.iter().filter(|b| **b == b'\n').count();
+            *self.0.lock().unwrap() += rows;
+            Ok(())
+        }
+
+        async fn on_copy_done<C>(&self, client: &mut C, _done: CopyDone) -> PgWireResult<()>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            let rows = *self.0.lock().unwrap();
+            client
+                .send(PgWireBackendMessage::CommandComplete(
+                    Tag::copy(rows).into(),
+                ))
+                .await?;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copy_in() {
+        let (server, mut client) = tokio::io::duplex(4096);
+        let mut client_info = DefaultClient::new("127.0.0.1:5432".parse().unwrap(), false);
+        client_info.state = PgWireConnectionState::ReadyForQuery;
+        let mut socket = Framed::new(server, PgWireMessageServerCodec::new(client_info));
+        let copy_handler = Arc::new(CountingCopyHandler::default());
+
+        for message in [
+            PgWireFrontendMessage::Query(Query::new("COPY t FROM STDIN".to_owned())),
+            PgWireFrontendMessage::CopyData(CopyData::new(Bytes::from_static(b"1\ta\n2\t"))),
+            PgWireFrontendMessage::CopyData(CopyData::new(Bytes::from_static(b"b\n"))),
+            PgWireFrontendMessage::CopyDone(CopyDone::new()),
+        ] {
+            process_message(
+                message,
+                &mut socket,
+                Arc::new(NoopStartupHandler),
+                Arc::new(CopyQueryHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+                copy_handler.clone(),
+                &ServerOptions::default(),
+            )
+            .await
+            .unwrap();
+        }
+        assert!(matches!(
+            socket.state(),
+            PgWireConnectionState::ReadyForQuery
+        ));
+        drop(socket);
+
+        let mut buf = BytesMut::new();
+        client.read_buf(&mut buf).await.unwrap();
+        let mut messages = Vec::new();
+        while let Some(message) = PgWireBackendMessage::decode(&mut buf).unwrap() {
+            messages.push(message);
+        }
+        assert!(matches!(
+            messages[0],
+            PgWireBackendMessage::CopyInResponse(ref resp) if resp.columns == 2
+        ));
+        let PgWireBackendMessage::CommandComplete(ref complete) = messages[1] else {
+            panic!("expected CommandComplete");
+        };
+        assert_eq!("COPY 2", complete.tag);
+        assert!(matches!(
+            messages[2],
+            PgWireBackendMessage::ReadyForQuery(_)
+        ));
+    }
+
+    struct FailingQueryHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for FailingQueryHandler {
+        async fn on_query<C>(&self, _client: &mut C, query: Query) -> PgWireResult<()>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            panic!("handler called with {:?}", query.query);
+        }
+
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            unreachable!()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_empty_query() {
+        let (server, mut client) = tokio::io::duplex(4096);
+        let mut client_info = DefaultClient::new("127.0.0.1:5432".parse().unwrap(), false);
+        client_info.state = PgWireConnectionState::ReadyForQuery;
+        let mut socket = Framed::new(server, PgWireMessageServerCodec::new(client_info));
+
+        for query in ["", " ; ", "-- nothing"] {
+            process_message(
+                PgWireFrontendMessage::Query(Query::new(query.to_owned())),
+                &mut socket,
+                Arc::new(NoopStartupHandler),
+                Arc::new(FailingQueryHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+                Arc::new(NoopCopyHandler),
+                &ServerOptions::default(),
+            )
+            .await
+            .unwrap();
+        }
+        drop(socket);
+
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        let mut buf = BytesMut::from(&buf[..]);
+        let mut messages = Vec::new();
+        while let Some(message) = PgWireBackendMessage::decode(&mut buf).unwrap() {
+            messages.push(message);
+        }
+        assert_eq!(6, messages.len());
+        for pair in messages.chunks(2) {
+            assert!(matches!(
+                pair[0],
+                PgWireBackendMessage::EmptyQueryResponse(_)
+            ));
+            assert!(matches!(pair[1], PgWireBackendMessage::ReadyForQuery(_)));
+        }
+    }
+
+    // startup, queries and copy handled by one type
+    struct UnifiedHandler {
+        queries: Mutex<usize>,
+    }
+
+    #[async_trait]
+    impl StartupHandler for UnifiedHandler {
+        async fn on_startup<C>(
+            &self,
+            client: &mut C,
+            message: PgWireFrontendMessage,
+        ) -> PgWireResult<()>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            NoopStartupHandler.on_startup(client, message).await
+        }
+    }
+
+    #[async_trait]
+    impl SimpleQueryHandler for UnifiedHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            *self.queries.lock().unwrap() += 1;
+            Ok(vec![Response::Execution(Tag::new("SIMPLE"))])
+        }
+    }
+
+    #[async_trait]
+    impl ExtendedQueryHandler for UnifiedHandler {
+        type Statement = String;
+        type QueryParser = NoopQueryParser;
+
+        fn query_parser(&self) -> Arc<Self::QueryParser> {
+            Arc::new(NoopQueryParser)
+        }
+
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            _portal: &'a Portal<Self::Statement>,
+            _max_rows: usize,
+        ) -> PgWireResult<Response<'a>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            *self.queries.lock().unwrap() += 1;
+            Ok(Response::Execution(Tag::new("EXTENDED")))
+        }
+    }
+
+    impl CopyHandler for UnifiedHandler {}
+
+    // executes statements as their tag, statement `FAIL` fails
+    struct PipelineHandler;
+
+    #[async_trait]
+    impl ExtendedQueryHandler for PipelineHandler {
+        type Statement = String;
+        type QueryParser = NoopQueryParser;
+
+        fn query_parser(&self) -> Arc<Self::QueryParser> {
+            Arc::new(NoopQueryParser)
+        }
+
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            portal: &'a Portal<Self::Statement>,
+            _max_rows: usize,
+        ) -> PgWireResult<Response<'a>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            if portal.statement.statement == "FAIL" {
+                Ok(Response::Error(Box::new(ErrorInfo::new(
+                    "ERROR".to_owned(),
+                    "XX000".to_owned(),
+                    "failed".to_owned(),
+                ))))
+            } else {
+                Ok(Response::Execution(Tag::new(&portal.statement.statement)))
+            }
+        }
+    }
+
+    fn execute(query: &str, request: &mut BytesMut) {
+        Parse::new(None, query.to_owned(), vec![])
+            .encode(request)
+            .unwrap();
+        Bind::new(None, None, vec![], vec![], vec![])
+            .encode(request)
+            .unwrap();
+        Execute::new(None, 0).encode(request).unwrap();
+    }
+
+    // read messages until one matching `last`, as names of their types
+    async fn read_until(
+        client: &mut tokio::io::DuplexStream,
+        buf: &mut BytesMut,
+        last: fn(&PgWireBackendMessage) -> bool,
+    ) -> Vec<String> {
+        let mut messages = Vec::new();
+        loop {
+            while let Some(message) = PgWireBackendMessage::decode(buf).unwrap() {
+                let name = match &message {
+                    PgWireBackendMessage::CommandComplete(complete) => complete.tag.clone(),
+                    message => format!("{message:?}")
+                        .split(['(', ' '])
+                        .next()
+                        .unwrap()
+                        .to_owned(),
+                };
+                messages.push(name);
+                if last(&message) {
+                    return messages;
+                }
+            }
+            client.read_buf(buf).await.unwrap();
+        }
+    }
+    #[tokio::test]
+    async fn test_pipeline() {
+        let is_ready =
+            |m: &PgWireBackendMessage| matches!(m, PgWireBackendMessage::ReadyForQuery(_));
+
+        let (server, mut client) = tokio::io::duplex(4096);
+        let server = tokio::spawn(process_stream(
+            server,
+            "0.0.0.0:0".parse().unwrap(),
+            BytesMut::new(),
+            None::<Arc<TlsAcceptor>>,
+            Arc::new(ServerOptions::default()),
+            Handlers::new(
+                Arc::new(NoopStartupHandler),
+                Arc::new(CopyQueryHandler),
+                Arc::new(PipelineHandler),
+            ),
+        ));
+        let mut request = BytesMut::new();
+        Startup::new().encode(&mut request).unwrap();
+        client.write_all(&request).await.unwrap();
+        let mut buf = BytesMut::new();
+        read_until(&mut client, &mut buf, is_ready).await;
+
+        // responses are flushed by `Flush`, without `ReadyForQuery`
+        let mut request = BytesMut::new();
+        execute("FIRST", &mut request);
+        Flush.encode(&mut request).unwrap();
+        client.write_all(&request).await.unwrap();
+        let messages = tokio::time::timeout(
+            Duration::from_secs(5),
+            read_until(&mut client, &mut buf, |m| {
+                matches!(m, PgWireBackendMessage::CommandComplete(_))
+            }),
+        )
+        .await
+        .expect("responses not flushed");
+        assert_eq!(vec!["ParseComplete", "BindComplete", "FIRST"], messages);
+
+        // messages after an error are skipped until `Sync`
+        let mut request = BytesMut::new();
+        execute("FAIL", &mut request);
+        execute("SKIPPED", &mut request);
+        Flush.encode(&mut request).unwrap();
+        PgSync::new().encode(&mut request).unwrap();
+        execute("LAST", &mut request);
+        PgSync::new().encode(&mut request).unwrap();
+        client.write_all(&request).await.unwrap();
+        let messages = read_until(&mut client, &mut buf, is_ready).await;
+        assert_eq!(
+            vec![
+                "ParseComplete",
+                "BindComplete",
+                "ErrorResponse",
+                "ReadyForQuery"
+            ],
+            messages
+        );
+        let messages = read_until(&mut client, &mut buf, is_ready).await;
+        assert_eq!(
+            vec!["ParseComplete", "BindComplete", "LAST", "ReadyForQuery"],
+            messages
+        );
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    // answers with the `action` of sqlcommenter comment as tag
+    struct CommentHandler;
+
+    fn comment_tag<C: ClientInfo>(client: &C) -> Tag {
+        Tag::new(
+            client
+                .sql_comment()
+                .and_then(|comment| comment.get("action"))
+                .unwrap_or("NONE"),
+        )
+    }
+
+    #[async_trait]
+    impl SimpleQueryHandler for CommentHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            client: &mut C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(vec![Response::Execution(comment_tag(client))])
+        }
+    }
+
+    #[async_trait]
+    impl ExtendedQueryHandler for CommentHandler {
+        type Statement = String;
+        type QueryParser = NoopQueryParser;
+
+        fn query_parser(&self) -> Arc<Self::QueryParser> {
+            Arc::new(NoopQueryParser)
+        }
+
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            client: &mut C,
+            _portal: &'a Portal<Self::Statement>,
+            _max_rows: usize,
+        ) -> PgWireResult<Response<'a>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(Response::Execution(comment_tag(client)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sql_comment() {
+        let is_ready =
+            |m: &PgWireBackendMessage| matches!(m, PgWireBackendMessage::ReadyForQuery(_));
+
+        let (server, mut client) = tokio::io::duplex(4096);
+        let handler = Arc::new(CommentHandler);
+        let server = tokio::spawn(process_stream(
+            server,
+            "0.0.0.0:0".parse().unwrap(),
+            BytesMut::new(),
+            None::<Arc<TlsAcceptor>>,
+            Arc::new(ServerOptions::default()),
+            Handlers::new(Arc::new(NoopStartupHandler), handler.clone(), handler),
+        ));
+        let mut request = BytesMut::new();
+        Startup::new().encode(&mut request).unwrap();
+        client.write_all(&request).await.unwrap();
+        let mut buf = BytesMut::new();
+        read_until(&mut client, &mut buf, is_ready).await;
+
+        let mut request = BytesMut::new();
+        Query::new("SELECT 1 /*action='simple'*/".to_owned())
+            .encode(&mut request)
+            .unwrap();
+        execute("SELECT 1 /*action='extended'*/", &mut request);
+        execute("SELECT 1", &mut request);
+        PgSync::new().encode(&mut request).unwrap();
+        client.write_all(&request).await.unwrap();
+        let messages = read_until(&mut client, &mut buf, is_ready).await;
+        assert_eq!(vec!["simple", "ReadyForQuery"], messages);
+        let messages = read_until(&mut client, &mut buf, is_ready).await;
+        assert_eq!(
+            vec![
+                "ParseComplete",
+                "BindComplete",
+                "extended",
+                "ParseComplete",
+                "BindComplete",
+                "NONE",
+                "ReadyForQuery"
+            ],
+            messages
+        );
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_query_rewriter() {
+        use crate::api::rewrite::{QueryRewrite, QueryRewriter};
+
+        struct Rewriter;
+
+        impl QueryRewriter for Rewriter {
+            fn rewrite(
+                &self,
+                _client: &dyn ClientInfo,
+                query: &str,
+                _extended: bool,
+            ) -> QueryRewrite {
+                match query {
+                    "SHIM" => QueryRewrite::Rewrite("REWRITTEN".to_owned()),
+                    "CANNED" => {
+                        QueryRewrite::Respond(vec![Response::Execution(Tag::new("CANNED"))])
+                    }
+                    "DROP" => QueryRewrite::Reject(Box::new(ErrorInfo::new(
+                        "ERROR".to_owned(),
+                        SqlState::INSUFFICIENT_PRIVILEGE.into(),
+                        "DROP is not allowed".to_owned(),
+                    ))),
+                    _ => QueryRewrite::Unchanged,
+                }
+            }
+        }
+
+        let is_ready =
+            |m: &PgWireBackendMessage| matches!(m, PgWireBackendMessage::ReadyForQuery(_));
+        let (server, mut client) = tokio::io::duplex(4096);
+        let server = tokio::spawn(process_stream(
+            server,
+            "0.0.0.0:0".parse().unwrap(),
+            BytesMut::new(),
+            None::<Arc<TlsAcceptor>>,
+            Arc::new(ServerOptions::new().with_query_rewriter(Arc::new(Rewriter))),
+            Handlers::new(
+                Arc::new(NoopStartupHandler),
+                Arc::new(CopyQueryHandler),
+                Arc::new(PipelineHandler),
+            ),
+        ));
+        let mut request = BytesMut::new();
+        Startup::new().encode(&mut request).unwrap();
+        client.write_all(&request).await.unwrap();
+        let mut buf = BytesMut::new();
+        read_until(&mut client, &mut buf, is_ready).await;
+
+        // simple queries answered or rejected without the handler
+        let mut request = BytesMut::new();
+        Query::new("CANNED".to_owned())
+            .encode(&mut request)
+            .unwrap();
+        Query::new("DROP".to_owned()).encode(&mut request).unwrap();
+        client.write_all(&request).await.unwrap();
+        let messages = read_until(&mut client, &mut buf, is_ready).await;
+        assert_eq!(vec!["CANNED", "ReadyForQuery"], messages);
+        let messages = read_until(&mut client, &mut buf, is_ready).await;
+        assert_eq!(vec!["ErrorResponse", "ReadyForQuery"], messages);
+
+        // handler prepares the rewritten query, canned responses can't be
+        // prepared
+        let mut request = BytesMut::new();
+        execute("SHIM", &mut request);
+        PgSync::new().encode(&mut request).unwrap();
+        execute("CANNED", &mut request);
+        PgSync::new().encode(&mut request).unwrap();
+        client.write_all(&request).await.unwrap();
+        let messages = read_until(&mut client, &mut buf, is_ready).await;
+        assert_eq!(
+            vec![
+                "ParseComplete",
+                "BindComplete",
+                "REWRITTEN",
+                "ReadyForQuery"
+            ],
+            messages
+        );
+        let messages = read_until(&mut client, &mut buf, is_ready).await;
+        assert_eq!(vec!["ErrorResponse", "ReadyForQuery"], messages);
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_process_socket_with_handler() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = Arc::new(UnifiedHandler {
+            queries: Mutex::new(0),
+        });
+        let server = {
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                let (socket, _) = listener.accept().await.unwrap();
+                process_socket_with_handler(socket, None, Arc::new(ServerOptions::new()), handler)
+                    .await
+            })
+        };
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut request = BytesMut::new();
+        Startup::new().encode(&mut request).unwrap();
+        Query::new("SELECT 1".to_owned())
+            .encode(&mut request)
+            .unwrap();
+        Parse::new(None, "SELECT 1".to_owned(), vec![])
+            .encode(&mut request)
+            .unwrap();
+        Bind::new(None, None, vec![], vec![], vec![])
+            .encode(&mut request)
+            .unwrap();
+        Execute::new(None, 0).encode(&mut request).unwrap();
+        PgSync::new().encode(&mut request).unwrap();
+        client.write_all(&request).await.unwrap();
+
+        let mut buf = BytesMut::new();
+        let mut tags = Vec::new();
+        while tags.len() < 2 {
+            client.read_buf(&mut buf).await.unwrap();
+            while let Some(message) = PgWireBackendMessage::decode(&mut buf).unwrap() {
+                if let PgWireBackendMessage::CommandComplete(complete) = message {
+                    tags.push(complete.tag);
+                }
+            }
+        }
+        assert_eq!(vec!["SIMPLE", "EXTENDED"], tags);
+        assert_eq!(2, *handler.queries.lock().unwrap());
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    struct TestReplicationHandler {
+        flushed: Mutex<Option<u64>>,
+    }
+
+    #[async_trait]
+    impl ReplicationHandler for TestReplicationHandler {
+        async fn start_replication(
+            &self,
+            _metadata: &HashMap<String, String>,
+            command: &StartReplication,
+        ) -> PgWireResult<futures::stream::BoxStream<'static, PgWireResult<XLogData>>> {
+            let data = XLogData::new(
+                command.start_lsn,
+                command.start_lsn + 5,
+                current_timestamp(),
+                Bytes::from_static(b"hello"),
+            );
+            Ok(futures::stream::iter(vec![Ok(data)]).boxed())
+        }
+
+        async fn on_standby_status_update(
+            &self,
+            _command: &StartReplication,
+            update: StandbyStatusUpdate,
+        ) -> PgWireResult<()> {
+            *self.flushed.lock().unwrap() = Some(update.flush_lsn);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replication() {
+        let (server, mut client) = tokio::io::duplex(4096);
+        let mut client_info =
+            DefaultClient::<String>::new("127.0.0.1:5432".parse().unwrap(), false);
+        client_info.state = PgWireConnectionState::ReadyForQuery;
+        client_info
+            .metadata
+            .insert("replication".to_owned(), "database".to_owned());
+        assert!(client_info.is_replication());
+        let mut socket = Framed::new(server, PgWireMessageServerCodec::new(client_info));
+        let handler = TestReplicationHandler {
+            flushed: Mutex::new(None),
+        };
+        let command = StartReplication::parse("START_REPLICATION SLOT s LOGICAL 0/10").unwrap();
+
+        let client = async move {
+            let mut buf = BytesMut::new();
+            let mut messages = Vec::new();
+            // wait for the end of wal stream, then reply
+            while !matches!(messages.last(), Some(PgWireBackendMessage::CopyDone(_))) {
+                client.read_buf(&mut buf).await.unwrap();
+                while let Some(message) = PgWireBackendMessage::decode(&mut buf).unwrap() {
+                    messages.push(message);
+                }
+            }
+            let mut out = BytesMut::new();
+            let update = StandbyStatusUpdate::new(0x15, 0x15, 0x15, current_timestamp(), false);
+            PgWireFrontendMessage::CopyData(
+                ReplicationFrontendMessage::StandbyStatusUpdate(update).into_copy_data(),
+            )
+            .encode(&mut out)
+            .unwrap();
+            PgWireFrontendMessage::CopyDone(CopyDone::new())
+                .encode(&mut out)
+                .unwrap();
+            client.write_all(&out).await.unwrap();
+
+            while !matches!(
+                messages.last(),
+                Some(PgWireBackendMessage::ReadyForQuery(_))
+            ) {
+                client.read_buf(&mut buf).await.unwrap();
+                while let Some(message) = PgWireBackendMessage::decode(&mut buf).unwrap() {
+                    messages.push(message);
+                }
+            }
+            messages
+        };
+        let (result, messages) =
+            tokio::join!(run_replication(&mut socket, &handler, command), client);
+        result.unwrap();
+
+        assert!(matches!(
+            messages[0],
+            PgWireBackendMessage::CopyBothResponse(_)
+        ));
+        let xlog = messages
+            .iter()
+            .find_map(|message| match message {
+                PgWireBackendMessage::CopyData(data) => {
+                    match ReplicationBackendMessage::decode(data.data.clone()).unwrap() {
+                        ReplicationBackendMessage::XLogData(xlog) => Some(xlog),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(0x10, xlog.start_lsn);
+        assert_eq!(&b"hello"[..], &xlog.data[..]);
+        let PgWireBackendMessage::CommandComplete(ref complete) = messages[messages.len() - 2]
+        else {
+            panic!("expected CommandComplete");
+        };
+        assert_eq!("START_REPLICATION", complete.tag);
+        assert_eq!(Some(0x15), *handler.flushed.lock().unwrap());
+        assert!(matches!(
+            socket.state(),
+            PgWireConnectionState::ReadyForQuery
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_notification_delivery() {
+        let (server, mut client) = tokio::io::duplex(4096);
+        let mut client_info = DefaultClient::new("127.0.0.1:5432".parse().unwrap(), false);
+        client_info.state = PgWireConnectionState::ReadyForQuery;
+        let (sink, notifications) = NotificationSink::channel();
+        client_info.notification_sink = Some(sink.clone());
+        let mut socket = Framed::new(server, PgWireMessageServerCodec::new(client_info));
+        let options = ServerOptions::default();
+        let handlers = Handlers::new(
+            Arc::new(NoopStartupHandler),
+            Arc::new(CopyQueryHandler),
+            Arc::new(PlaceholderExtendedQueryHandler),
+        );
+
+        let client = async move {
+            assert!(sink.notify(42, "jobs", "done"));
+            let mut buf = BytesMut::new();
+            loop {
+                client.read_buf(&mut buf).await.unwrap();
+                if let Some(message) = PgWireBackendMessage::decode(&mut buf).unwrap() {
+                    // closing the client ends the connection
+                    return message;
+                }
+            }
+        };
+        let (result, message) = tokio::join!(
+            process_connection(&mut socket, notifications, &handlers, &options, None),
+            client
+        );
+        result.unwrap();
+        let PgWireBackendMessage::NotificationResponse(notification) = message else {
+            panic!("expected NotificationResponse");
+        };
+        assert_eq!(42, notification.pid);
+        assert_eq!("jobs", notification.channel);
+        assert_eq!("done", notification.payload);
+    }
+
+    #[tokio::test]
+    async fn test_query_auditor() {
+        use crate::api::results::{DataRowEncoder, FieldInfo, QueryResponse};
+        use crate::api::Type;
+
+        struct RowsQueryHandler;
+
+        #[async_trait]
+        impl SimpleQueryHandler for RowsQueryHandler {
+            async fn do_query<'a, 'b: 'a, C>(
+                &'b self,
+                _client: &mut C,
+                query: &'a str,
+            ) -> PgWireResult<Vec<Response<'a>>>
+            where
+                C: ClientInfo + Unpin + Send + Sync,
+            {
+                let count: i32 = query
+                    .parse()
+                    .map_err(|e| PgWireError::ApiError(Box::new(e)))?;
+                let fields = Arc::new(vec![FieldInfo::new(
+                    "n".to_owned(),
+                    None,
+                    None,
+                    Type::INT4,
+                    FieldFormat::Text,
+                )]);
+                let rows = (0..count)
+                    .map(|i| {
+                        let mut encoder = DataRowEncoder::new(fields.clone());
+                        encoder.encode_field(&i)?;
+                        encoder.finish()
+                    })
+                    .collect::<Vec<_>>();
+                Ok(vec![Response::Query(QueryResponse::new(
+                    fields,
+                    futures::stream::iter(rows),
+                ))])
+            }
+        }
+
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<(String, usize, Option<String>)>>);
+
+        impl QueryAuditor for Recorder {
+            fn on_query_end(&self, query: &AuditQuery, outcome: &QueryOutcome) {
+                self.0.lock().unwrap().push((
+                    query.query.clone(),
+                    outcome.rows,
+                    outcome.error.as_ref().map(|e| e.code.clone()),
+                ));
+            }
+        }
+
+        let (server, _client) = tokio::io::duplex(4096);
+        let mut client_info = DefaultClient::new("127.0.0.1:5432".parse().unwrap(), false);
+        client_info.state = PgWireConnectionState::ReadyForQuery;
+        let mut socket = Framed::new(server, PgWireMessageServerCodec::new(client_info));
+        let recorder = Arc::new(Recorder::default());
+        let options = ServerOptions::new().with_query_auditor(recorder.clone());
+
+        for query in ["3", "x"] {
+            let _ = process_message(
+                PgWireFrontendMessage::Query(Query::new(query.to_owned())),
+                &mut socket,
+                Arc::new(NoopStartupHandler),
+                Arc::new(RowsQueryHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+                Arc::new(CountingCopyHandler::default()),
+                &options,
+            )
+            .await;
+        }
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![
+                ("3".to_owned(), 3, None),
+                ("x".to_owned(), 0, Some("XX000".to_owned()))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_middleware() {
+        use crate::api::middleware::Middleware;
+        use crate::messages::extendedquery::Sync as PgSync;
+        use crate::messages::response::NoticeResponse;
+
+        struct Layer(&'static str, Arc<Mutex<Vec<String>>>);
+
+        #[async_trait]
+        impl Middleware for Layer {
+            async fn on_frontend_message(
+                &self,
+                _client: &(dyn ClientInfo + Sync),
+                message: PgWireFrontendMessage,
+            ) -> PgWireResult<Option<PgWireFrontendMessage>> {
+                self.1.lock().unwrap().push(format!("{} frontend", self.0));
+                match message {
+                    PgWireFrontendMessage::Query(query) if query.query == "deny" => {
+                        Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                            "ERROR".to_owned(),
+                            SqlState::FEATURE_NOT_SUPPORTED.into(),
+                            "denied".to_owned(),
+                        ))))
+                    }
+                    PgWireFrontendMessage::Query(query) => Ok(Some(PgWireFrontendMessage::Query(
+                        Query::new(format!("{} {}", query.query, self.0)),
+                    ))),
+                    PgWireFrontendMessage::Sync(_) => Ok(None),
+                    message => Ok(Some(message)),
+                }
+            }
+
+            fn on_backend_message(
+                &self,
+                _client: &dyn ClientInfo,
+                message: PgWireBackendMessage,
+            ) -> Option<PgWireBackendMessage> {
+                self.1.lock().unwrap().push(format!("{} backend", self.0));
+                match message {
+                    PgWireBackendMessage::NoticeResponse(_) => None,
+                    message => Some(message),
+                }
+            }
+        }
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (server, _client) = tokio::io::duplex(4096);
+        let client_info = DefaultClient::<String>::new("127.0.0.1:5432".parse().unwrap(), false);
+        let mut socket = Framed::new(server, PgWireMessageServerCodec::new(client_info));
+        let options = ServerOptions::new()
+            .with_middleware(Arc::new(Layer("outer", log.clone())))
+            .with_middleware(Arc::new(Layer("inner", log.clone())));
+        apply_socket_options(&mut socket, &options);
+
+        let query = PgWireFrontendMessage::Query(Query::new("SELECT 1".to_owned()));
+        let Ok(Some(PgWireFrontendMessage::Query(query))) =
+            intercept_frontend_message(&socket, query).await
+        else {
+            panic!("expected rewritten query");
+        };
+        assert_eq!("SELECT 1 outer inner", query.query);
+        let sync = PgWireFrontendMessage::Sync(PgSync::new());
+        assert!(matches!(
+            intercept_frontend_message(&socket, sync).await,
+            Ok(None)
+        ));
+        let denied = PgWireFrontendMessage::Query(Query::new("deny".to_owned()));
+        assert!(intercept_frontend_message(&socket, denied).await.is_err());
+
+        // the outermost layer sees backend messages last
+        log.lock().unwrap().clear();
+        let notice = NoticeResponse::from(ErrorInfo::new(
+            "NOTICE".to_owned(),
+            "00000".to_owned(),
+            "dropped".to_owned(),
+        ));
+        socket
+            .feed(PgWireBackendMessage::NoticeResponse(notice))
+            .await
+            .unwrap();
+        assert!(socket.write_buffer().is_empty());
+        socket
+            .feed(PgWireBackendMessage::EmptyQueryResponse(
+                EmptyQueryResponse::new(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(5, socket.write_buffer().len());
+        assert_eq!(
+            vec!["inner backend", "inner backend", "outer backend"],
+            *log.lock().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wire_tap() {
+        use crate::api::wiretap::{WireDump, WireDumpReader, WireRecordKind};
+
+        #[derive(Clone, Default)]
+        struct Sink(Arc<Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Sink {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let (server, mut client) = tokio::io::duplex(4096);
+        let client_info = DefaultClient::<String>::new("127.0.0.1:5432".parse().unwrap(), false);
+        let mut socket = Framed::new(server, PgWireMessageServerCodec::new(client_info));
+        let sink = Sink::default();
+        let tap = WireTap::new().with_dump(WireDump::new(sink.clone()).unwrap());
+        apply_socket_options(&mut socket, &ServerOptions::new().with_wire_tap(tap));
+        socket.set_state(PgWireConnectionState::ReadyForQuery);
+
+        // a query split over two reads is dumped as one frame
+        let mut query = BytesMut::new();
+        Query::new("SELECT 1".to_owned())
+            .encode(&mut query)
+            .unwrap();
+        client.write_all(&query[..6]).await.unwrap();
+        client.flush().await.unwrap();
+        let next = tokio::spawn(async move {
+            let message = socket.next().await;
+            (socket, message)
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        client.write_all(&query[6..]).await.unwrap();
+        let (mut socket, message) = next.await.unwrap();
+        assert!(matches!(message, Some(Ok(PgWireFrontendMessage::Query(_)))));
+        socket
+            .send(PgWireBackendMessage::EmptyQueryResponse(
+                EmptyQueryResponse::new(),
+            ))
+            .await
+            .unwrap();
+
+        let dump = sink.0.lock().unwrap().clone();
+        let records = WireDumpReader::new(&dump[..])
+            .unwrap()
+            .map(|r| r.map(|r| (r.kind, r.frame)))
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            vec![
+                (WireRecordKind::Connected, Bytes::from("127.0.0.1:5432")),
+                (WireRecordKind::Frontend, query.freeze()),
+                (WireRecordKind::Backend, Bytes::from_static(b"I\0\0\0\x04")),
+            ],
+            records
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_buffer_size() {
+        let (server, mut client) = tokio::io::duplex(1 << 16);
+        let client_info = DefaultClient::<String>::new("127.0.0.1:5432".parse().unwrap(), false);
+        let mut socket = Framed::new(server, PgWireMessageServerCodec::new(client_info));
+        apply_socket_options(
+            &mut socket,
+            &ServerOptions::new().with_write_buffer_size(1 << 15),
+        );
+
+        // rows are coalesced in buffer until the boundary
+        let row = || {
+            PgWireBackendMessage::DataRow(crate::messages::data::DataRow::new(
+                BytesMut::from(&[0u8; 16][..]),
+                1,
+            ))
+        };
+        for _ in 0..1000 {
+            socket.feed(row()).await.unwrap();
+        }
+        let buffered = socket.write_buffer().len();
+        assert!(buffered > 0 && buffered < 1 << 15);
+
+        socket.flush().await.unwrap();
+        assert!(socket.write_buffer().is_empty());
+        drop(socket);
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(1000 * (1 + 4 + 2 + 16), buf.len());
+    }
+
+    #[test]
+    fn test_legacy_protocol_version() {
+        let mut startup = Startup::new();
+        assert_eq!(None, legacy_protocol_version(&[0, 0, 0, 8]));
+
+        let mut buf = BytesMut::new();
+        startup.encode(&mut buf).unwrap();
+        assert_eq!(None, legacy_protocol_version(&buf));
+
+        startup.protocol_number_major = 2;
+        buf.clear();
+        startup.encode(&mut buf).unwrap();
+        assert_eq!(Some((2, 0)), legacy_protocol_version(&buf));
+
+        buf.clear();
+        SslRequest.encode(&mut buf).unwrap();
+        assert_eq!(None, legacy_protocol_version(&buf));
+    }
+
+    #[test]
+    fn test_detect_foreign_protocol() {
+        assert_eq!(Some("HTTP request"), detect_foreign_protocol(b"GET / HT"));
+        assert_eq!(Some("HTTP request"), detect_foreign_protocol(b"PRI * HT"));
+        assert_eq!(
+            Some("TLS handshake"),
+            detect_foreign_protocol(&[0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01])
+        );
+
+        let mut buf = BytesMut::new();
+        Startup::new().encode(&mut buf).unwrap();
+        assert_eq!(None, detect_foreign_protocol(&buf[..8]));
+        buf.clear();
+        SslRequest.encode(&mut buf).unwrap();
+        assert_eq!(None, detect_foreign_protocol(&buf));
+    }
+
+    // send startup on `stream` and read the first response
+    async fn startup<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> PgWireBackendMessage {
+        let mut startup = Startup::new();
+        startup
+            .parameters
+            .insert("user".to_owned(), "pgwire".to_owned());
+        let mut buf = BytesMut::new();
+        startup.encode(&mut buf).unwrap();
+        stream.write_all(&buf).await.unwrap();
+
+        let mut buf = BytesMut::new();
+        loop {
+            stream.read_buf(&mut buf).await.unwrap();
+            if let Some(message) = PgWireBackendMessage::decode(&mut buf).unwrap() {
+                return message;
+            }
+        }
+    }
+
+    async fn serve_stream<S: Transport>(stream: S) -> Result<(), IOError> {
+        process_stream(
+            stream,
+            "0.0.0.0:0".parse().unwrap(),
+            BytesMut::new(),
+            None::<Arc<TlsAcceptor>>,
+            Arc::new(ServerOptions::default()),
+            Handlers::new(
+                Arc::new(NoopStartupHandler),
+                Arc::new(CopyQueryHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+            )
+            .with_copy_handler(Arc::new(CountingCopyHandler::default())),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_process_stream() {
+        let (server, mut client) = tokio::io::duplex(4096);
+        let server = tokio::spawn(serve_stream(server));
+        let message = startup(&mut client).await;
+        assert!(matches!(message, PgWireBackendMessage::Authentication(_)));
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_protocol_version() {
+        let (server, mut client) = tokio::io::duplex(4096);
+        let server = tokio::spawn(serve_stream(server));
+
+        let mut startup = Startup::new();
+        startup.protocol_number_minor = 2;
+        startup
+            .parameters
+            .insert("user".to_owned(), "pgwire".to_owned());
+        startup
+            .parameters
+            .insert("_pq_.test_option".to_owned(), "on".to_owned());
+        let mut buf = BytesMut::new();
+        startup.encode(&mut buf).unwrap();
+        client.write_all(&buf).await.unwrap();
+
+        let mut messages = Vec::new();
+        let mut buf = BytesMut::new();
+        while messages.len() < 2 {
+            client.read_buf(&mut buf).await.unwrap();
+            while let Some(message) = PgWireBackendMessage::decode(&mut buf).unwrap() {
+                messages.push(message);
+            }
+        }
+        let PgWireBackendMessage::NegotiateProtocolVersion(negotiate) = &messages[0] else {
+            panic!("expected NegotiateProtocolVersion, got {:?}", messages[0]);
+        };
+        assert_eq!(0, negotiate.newest_minor_version);
+        assert_eq!(vec!["_pq_.test_option"], negotiate.unsupported_options);
+        assert!(matches!(
+            messages[1],
+            PgWireBackendMessage::Authentication(Authentication::Ok)
+        ));
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Default)]
+        struct ByteCounter {
+            connections: AtomicUsize,
+            received: AtomicUsize,
+            sent: AtomicUsize,
+        }
+
+        impl Metrics for ByteCounter {
+            fn on_connection_opened(&self, _addr: SocketAddr) {
+                self.connections.fetch_add(1, Ordering::SeqCst);
+            }
+
+            fn on_connection_closed(&self, _addr: SocketAddr) {
+                self.connections.fetch_sub(1, Ordering::SeqCst);
+            }
+
+            fn on_bytes_received(&self, bytes: usize) {
+                self.received.fetch_add(bytes, Ordering::SeqCst);
+            }
+
+            fn on_bytes_sent(&self, bytes: usize) {
+                self.sent.fetch_add(bytes, Ordering::SeqCst);
+            }
+        }
+
+        let metrics = Arc::new(ByteCounter::default());
+        let (server, mut client) = tokio::io::duplex(4096);
+        let server = tokio::spawn(process_stream(
+            server,
+            "0.0.0.0:0".parse().unwrap(),
+            BytesMut::new(),
+            None::<Arc<TlsAcceptor>>,
+            Arc::new(ServerOptions::new().with_metrics(metrics.clone())),
+            Handlers::new(
+                Arc::new(NoopStartupHandler),
+                Arc::new(CopyQueryHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+            )
+            .with_copy_handler(Arc::new(CountingCopyHandler::default())),
+        ));
+        startup(&mut client).await;
+        assert_eq!(metrics.connections.load(Ordering::SeqCst), 1);
+        drop(client);
+        server.await.unwrap().unwrap();
+
+        assert_eq!(metrics.connections.load(Ordering::SeqCst), 0);
+        let mut buf = BytesMut::new();
+        let mut startup = Startup::new();
+        startup
+            .parameters
+            .insert("user".to_owned(), "pgwire".to_owned());
+        startup.encode(&mut buf).unwrap();
+        assert_eq!(metrics.received.load(Ordering::SeqCst), buf.len());
+        assert!(metrics.sent.load(Ordering::SeqCst) > 0);
+    }
+
+    #[tokio::test]
+    async fn test_health_check() {
+        #[derive(Default)]
+        struct ProbeRecorder {
+            probes: Mutex<Vec<HealthProbe>>,
+            queries: Mutex<Vec<String>>,
+        }
+
+        impl Metrics for ProbeRecorder {
+            fn on_query_completed(&self, tag: &str) {
+                self.queries.lock().unwrap().push(tag.to_owned());
+            }
+
+            fn on_health_probe(&self, probe: HealthProbe) {
+                self.probes.lock().unwrap().push(probe);
+            }
+        }
+
+        let is_ready =
+            |m: &PgWireBackendMessage| matches!(m, PgWireBackendMessage::ReadyForQuery(_));
+        let metrics = Arc::new(ProbeRecorder::default());
+        let health_check = HealthCheck::new();
+        let options = Arc::new(
+            ServerOptions::new()
+                .with_metrics(metrics.clone())
+                .with_health_check(health_check.clone()),
+        );
+        let connect = || {
+            let (server, client) = tokio::io::duplex(4096);
+            let server = tokio::spawn(process_stream(
+                server,
+                "0.0.0.0:0".parse().unwrap(),
+                BytesMut::new(),
+                None::<Arc<TlsAcceptor>>,
+                options.clone(),
+                Handlers::new(
+                    Arc::new(NoopStartupHandler),
+                    Arc::new(CopyQueryHandler),
+                    Arc::new(PipelineHandler),
+                ),
+            ));
+            (server, client)
+        };
+
+        // probe queries are answered without handler
+        let (server, mut client) = connect();
+        let mut request = BytesMut::new();
+        Startup::new().encode(&mut request).unwrap();
+        Query::new("select 1;".to_owned())
+            .encode(&mut request)
+            .unwrap();
+        client.write_all(&request).await.unwrap();
+        let mut buf = BytesMut::new();
+        read_until(&mut client, &mut buf, is_ready).await;
+        let messages = read_until(&mut client, &mut buf, is_ready).await;
+        assert_eq!(
+            vec!["RowDescription", "DataRow", "SELECT 1", "ReadyForQuery"],
+            messages
+        );
+
+        health_check.set_ready(false);
+        let mut request = BytesMut::new();
+        Query::new("SELECT 1".to_owned())
+            .encode(&mut request)
+            .unwrap();
+        client.write_all(&request).await.unwrap();
+        let messages = read_until(&mut client, &mut buf, is_ready).await;
+        assert_eq!(vec!["ErrorResponse", "ReadyForQuery"], messages);
+        drop(client);
+        server.await.unwrap().unwrap();
+        assert_eq!(
+            vec![
+                HealthProbe::Query { ready: true },
+                HealthProbe::Query { ready: false }
+            ],
+            *metrics.probes.lock().unwrap()
+        );
+        assert!(metrics.queries.lock().unwrap().is_empty());
+
+        // connect then terminate
+        let (server, mut client) = connect();
+        let mut request = BytesMut::new();
+        Startup::new().encode(&mut request).unwrap();
+        client.write_all(&request).await.unwrap();
+        read_until(&mut client, &mut buf, is_ready).await;
+        let mut request = BytesMut::new();
+        crate::messages::terminate::Terminate::new()
+            .encode(&mut request)
+            .unwrap();
+        client.write_all(&request).await.unwrap();
+        drop(client);
+        server.await.unwrap().unwrap();
+        assert_eq!(
+            Some(&HealthProbe::Connect),
+            metrics.probes.lock().unwrap().last()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeouts() {
+        struct TransactionHandler;
+
+        #[async_trait]
+        impl SimpleQueryHandler for TransactionHandler {
+            async fn do_query<'a, 'b: 'a, C>(
+                &'b self,
+                client: &mut C,
+                query: &'a str,
+            ) -> PgWireResult<Vec<Response<'a>>>
+            where
+                C: ClientInfo + Unpin + Send + Sync,
+            {
+                client.set_transaction_status(TransactionStatus::Transaction);
+                Ok(vec![Response::Execution(Tag::new(query))])
+            }
+        }
+
+        let options = Arc::new(
+            ServerOptions::new()
+                .with_idle_session_timeout(Duration::from_millis(10))
+                .with_idle_in_transaction_session_timeout(Duration::from_millis(30)),
+        );
+        let is_ready =
+            |m: &PgWireBackendMessage| matches!(m, PgWireBackendMessage::ReadyForQuery(_));
+        for begin in [false, true] {
+            let (server, mut client) = tokio::io::duplex(4096);
+            let server = tokio::spawn(process_stream(
+                server,
+                "0.0.0.0:0".parse().unwrap(),
+                BytesMut::new(),
+                None::<Arc<TlsAcceptor>>,
+                options.clone(),
+                Handlers::new(
+                    Arc::new(NoopStartupHandler),
+                    Arc::new(TransactionHandler),
+                    Arc::new(PipelineHandler),
+                ),
+            ));
+            let mut request = BytesMut::new();
+            Startup::new().encode(&mut request).unwrap();
+            if begin {
+                Query::new("BEGIN".to_owned()).encode(&mut request).unwrap();
+            }
+            client.write_all(&request).await.unwrap();
+            let mut buf = BytesMut::new();
+            read_until(&mut client, &mut buf, is_ready).await;
+            if begin {
+                read_until(&mut client, &mut buf, is_ready).await;
+            }
+
+            // the session is terminated after being idle
+            let message = loop {
+                client.read_buf(&mut buf).await.unwrap();
+                if let Some(message) = PgWireBackendMessage::decode(&mut buf).unwrap() {
+                    break message;
+                }
+            };
+            let PgWireBackendMessage::ErrorResponse(error) = message else {
+                panic!("expect idle timeout");
+            };
+            server.await.unwrap().unwrap();
+            let code = if begin { "25P03" } else { "57P05" };
+            assert!(error.fields.contains(&(b'C', code.to_owned())));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_cancels_query() {
+        use futures::channel::oneshot;
+
+        struct SlowQueryHandler(Mutex<Option<oneshot::Sender<()>>>);
+
+        #[async_trait]
+        impl SimpleQueryHandler for SlowQueryHandler {
+            async fn do_query<'a, 'b: 'a, C>(
+                &'b self,
+                client: &mut C,
+                _query: &'a str,
+            ) -> PgWireResult<Vec<Response<'a>>>
+            where
+                C: ClientInfo + Unpin + Send + Sync,
+            {
+                let token = client.cancellation_token();
+                let done = self.0.lock().unwrap().take().unwrap();
+                tokio::spawn(async move {
+                    token.cancelled().await;
+                    done.send(()).unwrap();
+                });
+                futures::future::pending().await
+            }
+        }
+
+        let (done, cancelled) = oneshot::channel();
+        let (server, mut client) = tokio::io::duplex(4096);
+        let server = tokio::spawn(process_stream(
+            server,
+            "0.0.0.0:0".parse().unwrap(),
+            BytesMut::new(),
+            None::<Arc<TlsAcceptor>>,
+            Arc::new(ServerOptions::default()),
+            Handlers::new(
+                Arc::new(NoopStartupHandler),
+                Arc::new(SlowQueryHandler(Mutex::new(Some(done)))),
+                Arc::new(PlaceholderExtendedQueryHandler),
+            )
+            .with_copy_handler(Arc::new(CountingCopyHandler::default())),
+        ));
+        startup(&mut client).await;
+        let mut buf = BytesMut::new();
+        PgWireFrontendMessage::Query(Query::new("SELECT pg_sleep(1000)".to_owned()))
+            .encode(&mut buf)
+            .unwrap();
+        client.write_all(&buf).await.unwrap();
+        tokio::task::yield_now().await;
+        drop(client);
+
+        cancelled.await.unwrap();
+        assert!(server.await.unwrap().is_err());
+    }
+
+    #[test]
+    fn test_frame_scanner() {
+        let mut frames = BytesMut::new();
+        Query::new("SELECT 'X'".to_owned())
+            .encode(&mut frames)
+            .unwrap();
+        PgSync::new().encode(&mut frames).unwrap();
+        let mut scanner = FrameScanner::default();
+        assert!(!scanner.scan(&frames));
+
+        // split at any byte
+        crate::messages::terminate::Terminate::new()
+            .encode(&mut frames)
+            .unwrap();
+        for at in 0..frames.len() {
+            let mut scanner = FrameScanner::default();
+            let found = scanner.scan(&frames[..at]);
+            assert!(!found);
+            assert!(scanner.scan(&frames[at..]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_terminate_cancels_query() {
+        use futures::channel::oneshot;
+
+        struct SlowQueryHandler(Mutex<Option<oneshot::Sender<()>>>);
+
+        #[async_trait]
+        impl SimpleQueryHandler for SlowQueryHandler {
+            async fn do_query<'a, 'b: 'a, C>(
+                &'b self,
+                client: &mut C,
+                _query: &'a str,
+            ) -> PgWireResult<Vec<Response<'a>>>
+            where
+                C: ClientInfo + Unpin + Send + Sync,
+            {
+                let token = client.cancellation_token();
+                let done = self.0.lock().unwrap().take().unwrap();
+                tokio::spawn(async move {
+                    token.cancelled().await;
+                    done.send(()).unwrap();
+                });
+                futures::future::pending().await
+            }
+        }
+
+        let (done, cancelled) = oneshot::channel();
+        let (server, mut client) = tokio::io::duplex(4096);
+        let server = tokio::spawn(process_stream(
+            server,
+            "0.0.0.0:0".parse().unwrap(),
+            BytesMut::new(),
+            None::<Arc<TlsAcceptor>>,
+            Arc::new(ServerOptions::new().with_terminate_cancels_query(true)),
+            Handlers::new(
+                Arc::new(NoopStartupHandler),
+                Arc::new(SlowQueryHandler(Mutex::new(Some(done)))),
+                Arc::new(PlaceholderExtendedQueryHandler),
+            )
+            .with_copy_handler(Arc::new(CountingCopyHandler::default())),
+        ));
+        startup(&mut client).await;
+        let mut buf = BytesMut::new();
+        Query::new("SELECT pg_sleep(1000)".to_owned())
+            .encode(&mut buf)
+            .unwrap();
+        client.write_all(&buf).await.unwrap();
+        tokio::task::yield_now().await;
+
+        // the client is still connected
+        let mut buf = BytesMut::new();
+        crate::messages::terminate::Terminate::new()
+            .encode(&mut buf)
+            .unwrap();
+        client.write_all(&buf).await.unwrap();
+        cancelled.await.unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_query_heartbeat() {
+        struct SlowQueryHandler;
+
+        #[async_trait]
+        impl SimpleQueryHandler for SlowQueryHandler {
+            async fn do_query<'a, 'b: 'a, C>(
+                &'b self,
+                _client: &mut C,
+                _query: &'a str,
+            ) -> PgWireResult<Vec<Response<'a>>>
+            where
+                C: ClientInfo + Unpin + Send + Sync,
+            {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                Ok(vec![Response::Execution(Tag::new("SLOW"))])
+            }
+        }
+
+        let heartbeat = QueryHeartbeat::notice(Duration::from_millis(20), "still running");
+        let is_ready =
+            |m: &PgWireBackendMessage| matches!(m, PgWireBackendMessage::ReadyForQuery(_));
+        let (server, mut client) = tokio::io::duplex(4096);
+        let server = tokio::spawn(process_stream(
+            server,
+            "0.0.0.0:0".parse().unwrap(),
+            BytesMut::new(),
+            None::<Arc<TlsAcceptor>>,
+            Arc::new(ServerOptions::new().with_query_heartbeat(heartbeat)),
+            Handlers::new(
+                Arc::new(NoopStartupHandler),
+                Arc::new(SlowQueryHandler),
+                Arc::new(PipelineHandler),
+            ),
+        ));
+        let mut request = BytesMut::new();
+        Startup::new().encode(&mut request).unwrap();
+        Query::new("SELECT pg_sleep(0.1)".to_owned())
+            .encode(&mut request)
+            .unwrap();
+        client.write_all(&request).await.unwrap();
+        let mut buf = BytesMut::new();
+        read_until(&mut client, &mut buf, is_ready).await;
+
+        // notices while the handler is silent, then its response
+        let messages = read_until(&mut client, &mut buf, is_ready).await;
+        let (notices, rest) = messages.split_at(messages.len() - 2);
+        assert!(!notices.is_empty());
+        assert!(notices.iter().all(|m| m == "NoticeResponse"));
+        assert_eq!(["SLOW", "ReadyForQuery"], rest);
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_authentication_timeout() {
+        use crate::api::auth::cleartext::CleartextPasswordAuthStartupHandler;
+        use crate::api::auth::{AuthSource, DefaultServerParameterProvider, LoginInfo, Verifier};
+
+        struct Pencil;
+
+        #[async_trait]
+        impl AuthSource for Pencil {
+            async fn get_verifier(&self, _login: &LoginInfo) -> PgWireResult<Option<Verifier>> {
+                Ok(Some(Verifier::Cleartext("pencil".to_owned())))
+            }
+        }
+
+        let options =
+            Arc::new(ServerOptions::new().with_authentication_timeout(Duration::from_millis(10)));
+
+        // client never sends startup
+        let (server, _client) = tokio::io::duplex(4096);
+        let error = process_stream(
+            server,
+            "0.0.0.0:0".parse().unwrap(),
+            BytesMut::new(),
+            None::<Arc<TlsAcceptor>>,
+            options.clone(),
+            Handlers::new(
+                Arc::new(NoopStartupHandler),
+                Arc::new(CopyQueryHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+            )
+            .with_copy_handler(Arc::new(CountingCopyHandler::default())),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(ErrorKind::TimedOut, error.kind());
+
+        // client stops after startup message, without password
+        let (server, mut client) = tokio::io::duplex(4096);
+        let server = tokio::spawn(process_stream(
+            server,
+            "0.0.0.0:0".parse().unwrap(),
+            BytesMut::new(),
+            None::<Arc<TlsAcceptor>>,
+            options.clone(),
+            Handlers::new(
+                Arc::new(CleartextPasswordAuthStartupHandler::new(
+                    Pencil,
+                    DefaultServerParameterProvider::default(),
+                )),
+                Arc::new(CopyQueryHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+            )
+            .with_copy_handler(Arc::new(CountingCopyHandler::default())),
+        ));
+        assert!(matches!(
+            startup(&mut client).await,
+            PgWireBackendMessage::Authentication(Authentication::CleartextPassword)
+        ));
+        let error = server.await.unwrap().unwrap_err();
+        assert_eq!(ErrorKind::TimedOut, error.kind());
+
+        // authenticated sessions are not affected
+        let (server, mut client) = tokio::io::duplex(4096);
+        let server = tokio::spawn(process_stream(
+            server,
+            "0.0.0.0:0".parse().unwrap(),
+            BytesMut::new(),
+            None::<Arc<TlsAcceptor>>,
+            options,
+            Handlers::new(
+                Arc::new(NoopStartupHandler),
+                Arc::new(CopyQueryHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+            )
+            .with_copy_handler(Arc::new(CountingCopyHandler::default())),
+        ));
+        startup(&mut client).await;
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_message_limits() {
+        let limits = MessageLimits::new()
+            .with_max_message_size(1024)
+            .with_max_query_length(16);
+        let (server, mut client) = tokio::io::duplex(4096);
+        let server = tokio::spawn(process_stream(
+            server,
+            "0.0.0.0:0".parse().unwrap(),
+            BytesMut::new(),
+            None::<Arc<TlsAcceptor>>,
+            Arc::new(ServerOptions::new().with_message_limits(limits)),
+            Handlers::new(
+                Arc::new(NoopStartupHandler),
+                Arc::new(CopyQueryHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+            )
+            .with_copy_handler(Arc::new(CountingCopyHandler::default())),
+        ));
+        startup(&mut client).await;
+
+        // the session continues after a long query
+        let mut buf = BytesMut::new();
+        PgWireFrontendMessage::Query(Query::new("SELECT ".repeat(10)))
+            .encode(&mut buf)
+            .unwrap();
+        client.write_all(&buf).await.unwrap();
+        let mut buf = Vec::new();
+        while !String::from_utf8_lossy(&buf).contains("exceeds the limit of 16 bytes") {
+            assert!(client.read_buf(&mut buf).await.unwrap() > 0);
+        }
+
+        // header of a huge message is enough to close the connection
+        client.write_all(&[b'Q', 0x10, 0, 0, 0]).await.unwrap();
+        server.await.unwrap().unwrap();
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        let response = String::from_utf8_lossy(&buf);
+        assert!(response.contains("08P01"));
+        assert!(response.contains("exceeds the limit of 1024 bytes"));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move { TcpStream::connect(addr).await.unwrap() });
+        let (socket, _) = listener.accept().await.unwrap();
+
+        let socket_ref = SockRef::from(&socket);
+        assert!(!socket_ref.keepalive().unwrap());
+        TcpOptions::new().apply(&socket).unwrap();
+        assert!(socket.nodelay().unwrap());
+
+        TcpOptions::new()
+            .with_nodelay(false)
+            .with_keepalive_idle(Duration::from_secs(60))
+            .with_keepalive_interval(Duration::from_secs(10))
+            .with_keepalive_retries(3)
+            .with_send_buffer_size(64 * 1024)
+            .with_recv_buffer_size(64 * 1024)
+            .apply(&socket)
+            .unwrap();
+        assert!(!socket.nodelay().unwrap());
+        assert!(socket_ref.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(
+                Duration::from_secs(60),
+                socket_ref.tcp_keepalive_time().unwrap()
+            );
+            assert_eq!(
+                Duration::from_secs(10),
+                socket_ref.tcp_keepalive_interval().unwrap()
+            );
+            assert_eq!(3, socket_ref.tcp_keepalive_retries().unwrap());
+        }
+        // the kernel may round sizes up
+        assert!(socket_ref.send_buffer_size().unwrap() >= 64 * 1024);
+        assert!(socket_ref.recv_buffer_size().unwrap() >= 64 * 1024);
+        drop(client.await.unwrap());
+    }
+
+    #[test]
+    fn test_ssl_policy_mode_for() {
+        let policy = SslPolicy::new(SslMode::Require)
+            .with_network("127.0.0.1".parse().unwrap(), 8, SslMode::Prefer)
+            .with_network("10.1.0.0".parse().unwrap(), 16, SslMode::Disable);
+        assert_eq!(
+            SslMode::Prefer,
+            policy.mode_for("127.0.0.2".parse().unwrap())
+        );
+        assert_eq!(
+            SslMode::Disable,
+            policy.mode_for("10.1.2.3".parse().unwrap())
+        );
+        assert_eq!(
+            SslMode::Require,
+            policy.mode_for("10.2.0.1".parse().unwrap())
+        );
+        assert_eq!(
+            SslMode::Prefer,
+            SslPolicy::default().mode_for("10.2.0.1".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ssl_policy() {
+        let serve = |stream, addr: &str, tls: Option<Arc<TlsAcceptor>>, policy: SslPolicy| {
+            tokio::spawn(process_stream(
+                stream,
+                addr.parse().unwrap(),
+                BytesMut::new(),
+                tls,
+                Arc::new(ServerOptions::new().with_ssl_policy(policy)),
+                Handlers::new(
+                    Arc::new(NoopStartupHandler),
+                    Arc::new(CopyQueryHandler),
+                    Arc::new(PlaceholderExtendedQueryHandler),
+                )
+                .with_copy_handler(Arc::new(CountingCopyHandler::default())),
+            ))
+        };
+        let require = SslPolicy::new(SslMode::Require).with_network(
+            "127.0.0.1".parse().unwrap(),
+            8,
+            SslMode::Prefer,
+        );
+
+        // plaintext rejected before startup
+        let (server, mut client) = tokio::io::duplex(4096);
+        let server = serve(server, "10.0.0.1:5000", None, require.clone());
+        let mut buf = BytesMut::new();
+        Startup::new().encode(&mut buf).unwrap();
+        client.write_all(&buf).await.unwrap();
+        server.await.unwrap().unwrap();
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        let Some(PgWireBackendMessage::ErrorResponse(error)) =
+            PgWireBackendMessage::decode(&mut BytesMut::from(&buf[..])).unwrap()
+        else {
+            panic!("expected ErrorResponse");
+        };
+        assert!(error.fields.contains(&(b'C', "28000".to_owned())));
+
+        // allowed from exempt network and local streams
+        for addr in ["127.0.0.1:5000", "0.0.0.0:0"] {
+            let (server, mut client) = tokio::io::duplex(4096);
+            let server = serve(server, addr, None, require.clone());
+            let message = startup(&mut client).await;
+            assert!(matches!(message, PgWireBackendMessage::Authentication(_)));
+            drop(client);
+            server.await.unwrap().unwrap();
+        }
+
+        // SslRequest refused even with a tls acceptor
+        let cert = include_bytes!("../examples/ssl/server.crt");
+        let key = include_bytes!("../examples/ssl/server.key");
+        let config = tokio_rustls::rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                rustls_pemfile::certs(&mut &cert[..])
+                    .collect::<Result<Vec<_>, _>>()
+                    .unwrap(),
+                rustls_pemfile::private_key(&mut &key[..]).unwrap().unwrap(),
+            )
+            .unwrap();
+        let acceptor = Arc::new(TlsAcceptor::from(Arc::new(config)));
+        let (server, mut client) = tokio::io::duplex(4096);
+        let server = serve(
+            server,
+            "10.0.0.1:5000",
+            Some(acceptor),
+            SslPolicy::new(SslMode::Disable),
+        );
+        let mut buf = BytesMut::new();
+        SslRequest.encode(&mut buf).unwrap();
+        client.write_all(&buf).await.unwrap();
+        assert_eq!(SslResponse::BYTE_REFUSE, client.read_u8().await.unwrap());
+        let message = startup(&mut client).await;
+        assert!(matches!(message, PgWireBackendMessage::Authentication(_)));
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connection_limiter() {
+        let limiter = ConnectionLimiter::new().with_max_connections(1);
+        let options = Arc::new(ServerOptions::new().with_connection_limiter(limiter.clone()));
+        let serve = |stream| {
+            tokio::spawn(process_stream(
+                stream,
+                "10.0.0.1:5000".parse().unwrap(),
+                BytesMut::new(),
+                None::<Arc<TlsAcceptor>>,
+                options.clone(),
+                Handlers::new(
+                    Arc::new(NoopStartupHandler),
+                    Arc::new(CopyQueryHandler),
+                    Arc::new(PlaceholderExtendedQueryHandler),
+                )
+                .with_copy_handler(Arc::new(CountingCopyHandler::default())),
+            ))
+        };
+
+        let (server, mut client) = tokio::io::duplex(4096);
+        let first = serve(server);
+        startup(&mut client).await;
+        assert_eq!(1, limiter.connections());
+
+        let (server, mut rejected) = tokio::io::duplex(4096);
+        serve(server).await.unwrap().unwrap();
+        // rejected before client sends startup
+        let mut buf = Vec::new();
+        rejected.read_to_end(&mut buf).await.unwrap();
+        let Some(PgWireBackendMessage::ErrorResponse(error)) =
+            PgWireBackendMessage::decode(&mut BytesMut::from(&buf[..])).unwrap()
+        else {
+            panic!("expected ErrorResponse");
+        };
+        assert!(error.fields.contains(&(b'C', "53300".to_owned())));
+
+        drop(client);
+        first.await.unwrap().unwrap();
+        assert_eq!(0, limiter.connections());
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown() {
+        let shutdown = GracefulShutdown::new();
+        let (server, mut client) = tokio::io::duplex(4096);
+        let server = tokio::spawn(process_stream(
+            server,
+            "0.0.0.0:0".parse().unwrap(),
+            BytesMut::new(),
+            None::<Arc<TlsAcceptor>>,
+            Arc::new(ServerOptions::new().with_graceful_shutdown(shutdown.clone())),
+            Handlers::new(
+                Arc::new(NoopStartupHandler),
+                Arc::new(CopyQueryHandler),
+                Arc::new(PlaceholderExtendedQueryHandler),
+            )
+            .with_copy_handler(Arc::new(CountingCopyHandler::default())),
+        ));
+        startup(&mut client).await;
+        assert_eq!(1, shutdown.connections());
+
+        // idle session is terminated
+        assert!(shutdown.shutdown(std::time::Duration::from_secs(10)).await);
+        server.await.unwrap().unwrap();
+        // rest of startup response is left unread by `startup`
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.windows(5).any(|w| w == b"57P01"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() {
+        use tokio::net::{UnixListener, UnixStream};
+
+        let path = std::env::temp_dir().join(format!(".s.PGSQL.{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve_stream(stream).await
+        });
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let message = startup(&mut client).await;
+        assert!(matches!(message, PgWireBackendMessage::Authentication(_)));
+        drop(client);
+        server.await.unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "native-tls")]
+    #[tokio::test]
+    async fn test_native_tls() {
+        use tokio_native_tls::native_tls;
+
+        let cert = include_bytes!("../examples/ssl/server.crt");
+        let key = include_bytes!("../examples/ssl/server.key");
+        let identity = native_tls::Identity::from_pkcs8(cert, key).unwrap();
+        let acceptor =
+            tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            process_socket_with_tls(
+                socket,
+                BytesMut::new(),
+                Some(Arc::new(acceptor)),
+                Arc::new(ServerOptions::default()),
+                Handlers::new(
+                    Arc::new(NoopStartupHandler),
+                    Arc::new(CopyQueryHandler),
+                    Arc::new(PlaceholderExtendedQueryHandler),
+                )
+                .with_copy_handler(Arc::new(CountingCopyHandler::default())),
+            )
+            .await
+        });
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let mut buf = BytesMut::new();
+        SslRequest::new().encode(&mut buf).unwrap();
+        socket.write_all(&buf).await.unwrap();
+        assert_eq!(b'S', socket.read_u8().await.unwrap());
+
+        // the test certificate is self-signed
+        let connector = tokio_native_tls::TlsConnector::from(
+            native_tls::TlsConnector::builder()
+                .danger_accept_invalid_certs(true)
+                .build()
+                .unwrap(),
+        );
+        let mut tls = connector.connect("localhost", socket).await.unwrap();
+        let message = startup(&mut tls).await;
+        assert!(matches!(message, PgWireBackendMessage::Authentication(_)));
+
+        drop(tls);
+        server.await.unwrap().unwrap();
+    }
 }
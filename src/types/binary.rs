@@ -0,0 +1,635 @@
+//! Conversion between text format and binary format of builtin types.
+//!
+//! Values are encoded in binary with their `ToSql` implementation when it
+//! accepts the column type. For others, like a `String` or `f64` in a
+//! `NUMERIC` column, an `i32` in an `INT8` column or a nested `Vec` for a
+//! multi-dimensional array, the value is encoded in text first, then
+//! converted here. Binary parameters are converted to text the same way.
+
+use std::error::Error;
+
+use bytes::{Buf, BufMut, BytesMut};
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use postgres_types::{Date, FromSql, Kind, Timestamp, ToSql, Type, WrongType};
+
+use super::from_sql_text::{parse_array, ArrayElement};
+use super::{write_array_element, FromSqlText, Inet, Interval, MacAddr, ToSqlText};
+
+fn convert<T>(
+    ty: &Type,
+    text: &[u8],
+    out: &mut BytesMut,
+) -> Result<(), Box<dyn Error + Sync + Send>>
+where
+    T: FromSqlText + ToSql,
+{
+    T::from_sql_text(ty, text)?.to_sql(ty, out)?;
+    Ok(())
+}
+
+/// Write binary format of `ty` for the value in text format.
+pub(crate) fn text_to_binary(
+    ty: &Type,
+    text: &[u8],
+    out: &mut BytesMut,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    match *ty {
+        Type::BOOL => convert::<bool>(ty, text, out),
+        Type::CHAR => {
+            out.put_u8(char_from_text(text));
+            Ok(())
+        }
+        Type::INT2 => convert::<i16>(ty, text, out),
+        Type::INT4 => convert::<i32>(ty, text, out),
+        Type::INT8 => convert::<i64>(ty, text, out),
+        Type::OID => convert::<u32>(ty, text, out),
+        Type::FLOAT4 => convert::<f32>(ty, text, out),
+        Type::FLOAT8 => convert::<f64>(ty, text, out),
+        Type::NUMERIC => encode_numeric(std::str::from_utf8(text)?, out),
+        // binary format of text types is the text itself
+        Type::TEXT | Type::VARCHAR | Type::BPCHAR | Type::NAME | Type::UNKNOWN => {
+            out.put_slice(text);
+            Ok(())
+        }
+        Type::BYTEA => convert::<Vec<u8>>(ty, text, out),
+        Type::DATE => convert::<Date<NaiveDate>>(ty, text, out),
+        Type::TIME => convert::<NaiveTime>(ty, text, out),
+        Type::TIMESTAMP => convert::<Timestamp<NaiveDateTime>>(ty, text, out),
+        Type::TIMESTAMPTZ => convert::<Timestamp<DateTime<FixedOffset>>>(ty, text, out),
+        Type::INTERVAL => convert::<Interval>(ty, text, out),
+        Type::INET | Type::CIDR => convert::<Inet>(ty, text, out),
+        Type::JSON => {
+            std::str::from_utf8(text)?;
+            out.put_slice(text);
+            Ok(())
+        }
+        Type::JSONB => {
+            std::str::from_utf8(text)?;
+            // version of jsonb binary format
+            out.put_u8(1);
+            out.put_slice(text);
+            Ok(())
+        }
+        Type::MACADDR => convert::<MacAddr>(ty, text, out),
+        #[cfg(feature = "uuid")]
+        Type::UUID => convert::<uuid::Uuid>(ty, text, out),
+        _ => match ty.kind() {
+            Kind::Array(member) => encode_array(member, std::str::from_utf8(text)?, out),
+            _ => Err(Box::new(WrongType::new::<String>(ty.clone()))),
+        },
+    }
+}
+
+// collect elements of array and its dimensions, in row-major order
+fn flatten_array<'a>(
+    text: &'a str,
+    depth: usize,
+    dims: &mut Vec<i32>,
+    elements: &mut Vec<ArrayElement<'a>>,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let items = parse_array(text)?;
+    if dims.len() == depth {
+        dims.push(items.len() as i32);
+    } else if dims.len() < depth || dims[depth] != items.len() as i32 {
+        return Err("multidimensional arrays must have sub-arrays with matching dimensions".into());
+    }
+    for item in items {
+        // elements are only allowed at the innermost dimension
+        let innermost = dims.len() == depth + 1;
+        match item {
+            ArrayElement::Array(_) if innermost && !elements.is_empty() => {
+                return Err(format!("malformed array literal: \"{text}\"").into());
+            }
+            ArrayElement::Array(array) => flatten_array(array, depth + 1, dims, elements)?,
+            element if innermost => elements.push(element),
+            _ => return Err(format!("malformed array literal: \"{text}\"").into()),
+        }
+    }
+    Ok(())
+}
+
+/// Write binary format of array from text format like `{{1,2},{3,4}}`.
+fn encode_array(
+    member: &Type,
+    text: &str,
+    out: &mut BytesMut,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let mut dims = Vec::new();
+    let mut elements = Vec::new();
+    flatten_array(text, 0, &mut dims, &mut elements)?;
+    if dims == [0] {
+        dims.clear();
+    }
+
+    out.put_i32(dims.len() as i32);
+    out.put_i32(elements.contains(&ArrayElement::Null).into());
+    out.put_u32(member.oid());
+    for dim in dims {
+        out.put_i32(dim);
+        // lower bound
+        out.put_i32(1);
+    }
+    for element in elements {
+        let value = match element {
+            ArrayElement::Value(value) => value,
+            _ => {
+                out.put_i32(-1);
+                continue;
+            }
+        };
+        let len_index = out.len();
+        out.put_i32(0);
+        text_to_binary(member, value.as_bytes(), out)?;
+        let len = (out.len() - len_index - 4) as i32;
+        out[len_index..len_index + 4].copy_from_slice(&len.to_be_bytes());
+    }
+    Ok(())
+}
+
+fn convert_to_text<'a, T>(
+    ty: &Type,
+    raw: &'a [u8],
+    out: &mut BytesMut,
+) -> Result<(), Box<dyn Error + Sync + Send>>
+where
+    T: FromSql<'a> + ToSqlText,
+{
+    T::from_sql(ty, raw)?.to_sql_text(ty, out)?;
+    Ok(())
+}
+
+// `"char"` is a single byte, its text is the byte itself like `charin` of
+// postgres, or `\ooo` in octal for bytes with the high bit set
+pub(crate) fn char_from_text(text: &[u8]) -> u8 {
+    match text {
+        [b'\\', high @ b'0'..=b'3', mid @ b'0'..=b'7', low @ b'0'..=b'7'] => {
+            ((high - b'0') << 6) | ((mid - b'0') << 3) | (low - b'0')
+        }
+        _ => text.first().copied().unwrap_or(0),
+    }
+}
+
+pub(crate) fn char_to_text(byte: u8, out: &mut BytesMut) {
+    match byte {
+        0 => {}
+        0x80.. => out.put_slice(format!("\\{byte:03o}").as_bytes()),
+        _ => out.put_u8(byte),
+    }
+}
+
+/// Write text format of `ty` for the value in binary format.
+pub(crate) fn binary_to_text(
+    ty: &Type,
+    raw: &[u8],
+    out: &mut BytesMut,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    match *ty {
+        Type::BOOL => convert_to_text::<bool>(ty, raw, out),
+        Type::CHAR => match raw {
+            [byte] => {
+                char_to_text(*byte, out);
+                Ok(())
+            }
+            _ => Err("invalid length of \"char\"".into()),
+        },
+        Type::INT2 => convert_to_text::<i16>(ty, raw, out),
+        Type::INT4 => convert_to_text::<i32>(ty, raw, out),
+        Type::INT8 => convert_to_text::<i64>(ty, raw, out),
+        Type::OID => convert_to_text::<u32>(ty, raw, out),
+        Type::FLOAT4 => convert_to_text::<f32>(ty, raw, out),
+        Type::FLOAT8 => convert_to_text::<f64>(ty, raw, out),
+        Type::NUMERIC => {
+            out.put_slice(decode_numeric(raw)?.as_bytes());
+            Ok(())
+        }
+        Type::TEXT | Type::VARCHAR | Type::BPCHAR | Type::NAME | Type::UNKNOWN => {
+            out.put_slice(std::str::from_utf8(raw)?.as_bytes());
+            Ok(())
+        }
+        Type::BYTEA => convert_to_text::<&[u8]>(ty, raw, out),
+        Type::DATE => convert_to_text::<Date<NaiveDate>>(ty, raw, out),
+        Type::TIME => convert_to_text::<NaiveTime>(ty, raw, out),
+        Type::TIMESTAMP => convert_to_text::<Timestamp<NaiveDateTime>>(ty, raw, out),
+        Type::TIMESTAMPTZ => convert_to_text::<Timestamp<DateTime<Utc>>>(ty, raw, out),
+        Type::INTERVAL => convert_to_text::<Interval>(ty, raw, out),
+        Type::INET | Type::CIDR => convert_to_text::<Inet>(ty, raw, out),
+        Type::JSON => {
+            out.put_slice(std::str::from_utf8(raw)?.as_bytes());
+            Ok(())
+        }
+        Type::JSONB => match raw.split_first() {
+            Some((1, json)) => {
+                out.put_slice(std::str::from_utf8(json)?.as_bytes());
+                Ok(())
+            }
+            _ => Err("unsupported jsonb version".into()),
+        },
+        Type::MACADDR => convert_to_text::<MacAddr>(ty, raw, out),
+        #[cfg(feature = "uuid")]
+        Type::UUID => convert_to_text::<uuid::Uuid>(ty, raw, out),
+        _ => match ty.kind() {
+            Kind::Array(member) => decode_array(member, raw, out),
+            _ => Err(Box::new(WrongType::new::<String>(ty.clone()))),
+        },
+    }
+}
+
+/// Write text format of array in binary format.
+fn decode_array(
+    member: &Type,
+    mut raw: &[u8],
+    out: &mut BytesMut,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let invalid = || "invalid binary format of array";
+    if raw.remaining() < 12 {
+        return Err(invalid().into());
+    }
+    let ndim = raw.get_i32();
+    let _has_null = raw.get_i32();
+    let _member_oid = raw.get_u32();
+    if !(0..=6).contains(&ndim) || raw.remaining() < ndim as usize * 8 {
+        return Err(invalid().into());
+    }
+    let mut dims = Vec::with_capacity(ndim as usize);
+    let mut lower_bounds = Vec::with_capacity(ndim as usize);
+    for _ in 0..ndim {
+        dims.push(usize::try_from(raw.get_i32()).map_err(|_| invalid())?);
+        lower_bounds.push(raw.get_i32());
+    }
+    if dims.is_empty() || dims.contains(&0) {
+        out.put_slice(b"{}");
+        return Ok(());
+    }
+    // dimension decoration is only written for non-default lower bounds
+    if lower_bounds.iter().any(|lb| *lb != 1) {
+        for (dim, lb) in dims.iter().zip(&lower_bounds) {
+            // dimensions are non-negative i32, only the sum may overflow
+            let ub = lb.checked_add(*dim as i32 - 1).ok_or_else(invalid)?;
+            out.put_slice(format!("[{lb}:{ub}]").as_bytes());
+        }
+        out.put_u8(b'=');
+    }
+
+    fn write_dim(
+        member: &Type,
+        dims: &[usize],
+        raw: &mut &[u8],
+        out: &mut BytesMut,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        out.put_u8(b'{');
+        for i in 0..dims[0] {
+            if i > 0 {
+                out.put_u8(b',');
+            }
+            if dims.len() > 1 {
+                write_dim(member, &dims[1..], raw, out)?;
+                continue;
+            }
+            if raw.remaining() < 4 {
+                return Err("invalid binary format of array".into());
+            }
+            let len = raw.get_i32();
+            if len < 0 {
+                out.put_slice(b"NULL");
+                continue;
+            }
+            let len = len as usize;
+            if raw.remaining() < len {
+                return Err("invalid binary format of array".into());
+            }
+            let mut text = BytesMut::new();
+            binary_to_text(member, &raw[..len], &mut text)?;
+            raw.advance(len);
+            write_array_element(&text, out);
+        }
+        out.put_u8(b'}');
+        Ok(())
+    }
+    write_dim(member, &dims, &mut raw, out)
+}
+
+const NUMERIC_POS: u16 = 0x0000;
+const NUMERIC_NEG: u16 = 0x4000;
+const NUMERIC_NAN: u16 = 0xC000;
+const NUMERIC_PINF: u16 = 0xD000;
+const NUMERIC_NINF: u16 = 0xF000;
+
+/// Write binary format of `NUMERIC` for a decimal like `-123.45`.
+///
+/// The value is written as digits in base 10000, with the weight of the
+/// first digit, sign, and number of decimal digits after the point.
+pub(crate) fn encode_numeric(
+    text: &str,
+    out: &mut BytesMut,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let text = text.trim();
+    let special = match text.to_ascii_lowercase().as_str() {
+        "nan" => Some(NUMERIC_NAN),
+        "infinity" | "+infinity" | "inf" => Some(NUMERIC_PINF),
+        "-infinity" | "-inf" => Some(NUMERIC_NINF),
+        _ => None,
+    };
+    if let Some(sign) = special {
+        out.put_i16(0);
+        out.put_i16(0);
+        out.put_u16(sign);
+        out.put_u16(0);
+        return Ok(());
+    }
+
+    let (sign, unsigned) = match text.as_bytes().first() {
+        Some(b'-') => (NUMERIC_NEG, &text[1..]),
+        Some(b'+') => (NUMERIC_POS, &text[1..]),
+        _ => (NUMERIC_POS, text),
+    };
+    let (int_part, frac_part) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+    if (int_part.is_empty() && frac_part.is_empty())
+        || !int_part
+            .bytes()
+            .chain(frac_part.bytes())
+            .all(|b| b.is_ascii_digit())
+    {
+        return Err(format!("invalid input syntax for type numeric: \"{text}\"").into());
+    }
+    let dscale = frac_part.len();
+
+    // group digits by 4 from the decimal point
+    let int_part = int_part.trim_start_matches('0');
+    let mut digits = String::with_capacity(int_part.len() + frac_part.len() + 6);
+    digits.extend(std::iter::repeat('0').take((4 - int_part.len() % 4) % 4));
+    digits.push_str(int_part);
+    let int_groups = digits.len() / 4;
+    digits.push_str(frac_part);
+    digits.extend(std::iter::repeat('0').take((4 - frac_part.len() % 4) % 4));
+
+    let mut groups = digits
+        .as_bytes()
+        .chunks(4)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .fold(0i16, |acc, digit| acc * 10 + (digit - b'0') as i16)
+        })
+        .collect::<Vec<i16>>();
+    let mut weight = int_groups as i32 - 1;
+
+    // leading and trailing zeros are not stored
+    let leading = groups.iter().take_while(|g| **g == 0).count();
+    groups.drain(..leading);
+    weight -= leading as i32;
+    while groups.last() == Some(&0) {
+        groups.pop();
+    }
+    if groups.is_empty() {
+        weight = 0;
+    }
+
+    let overflow = || format!("value overflows numeric format: \"{text}\"");
+    out.put_i16(i16::try_from(groups.len()).map_err(|_| overflow())?);
+    out.put_i16(i16::try_from(weight).map_err(|_| overflow())?);
+    out.put_u16(if groups.is_empty() { NUMERIC_POS } else { sign });
+    out.put_u16(u16::try_from(dscale).map_err(|_| overflow())?);
+    for group in groups {
+        out.put_i16(group);
+    }
+    Ok(())
+}
+
+/// Decode binary format of `NUMERIC` into a decimal like `-123.45`.
+pub(crate) fn decode_numeric(mut raw: &[u8]) -> Result<String, Box<dyn Error + Sync + Send>> {
+    let invalid = || "invalid binary format of numeric";
+    if raw.remaining() < 8 {
+        return Err(invalid().into());
+    }
+    let ndigits = raw.get_i16();
+    let weight = raw.get_i16() as i32;
+    let sign = raw.get_u16();
+    let dscale = raw.get_u16() as usize;
+    if ndigits < 0 || raw.remaining() != ndigits as usize * 2 {
+        return Err(invalid().into());
+    }
+    let digits = (0..ndigits).map(|_| raw.get_i16()).collect::<Vec<i16>>();
+    let digit = |idx: i32| {
+        usize::try_from(idx)
+            .ok()
+            .and_then(|idx| digits.get(idx))
+            .copied()
+            .unwrap_or(0)
+    };
+
+    let mut text = match sign {
+        NUMERIC_NAN => return Ok("NaN".to_owned()),
+        NUMERIC_PINF => return Ok("Infinity".to_owned()),
+        NUMERIC_NINF => return Ok("-Infinity".to_owned()),
+        NUMERIC_NEG => "-".to_owned(),
+        NUMERIC_POS => String::new(),
+        _ => return Err(invalid().into()),
+    };
+    if weight < 0 {
+        text.push('0');
+    } else {
+        text.push_str(&digit(0).to_string());
+        for idx in 1..=weight {
+            text.push_str(&format!("{:04}", digit(idx)));
+        }
+    }
+    if dscale > 0 {
+        let mut fraction = String::with_capacity(dscale + 4);
+        let mut idx = weight + 1;
+        while fraction.len() < dscale {
+            fraction.push_str(&format!("{:04}", digit(idx)));
+            idx += 1;
+        }
+        fraction.truncate(dscale);
+        text.push('.');
+        text.push_str(&fraction);
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numeric(text: &str) -> Vec<i16> {
+        let mut buf = BytesMut::new();
+        encode_numeric(text, &mut buf).unwrap();
+        buf.chunks(2)
+            .map(|b| i16::from_be_bytes([b[0], b[1]]))
+            .collect()
+    }
+
+    #[test]
+    fn test_encode_numeric() {
+        // ndigits, weight, sign, dscale, digits...
+        assert_eq!(vec![3, 1, 0, 1, 1, 2345, 6000], numeric("12345.6"));
+        assert_eq!(vec![1, -1, 0x4000, 4, 1], numeric("-0.0001"));
+        assert_eq!(vec![1, -2, 0, 5, 1000], numeric("0.00001"));
+        assert_eq!(vec![1, 1, 0, 0, 1], numeric("10000"));
+        assert_eq!(vec![0, 0, 0, 2], numeric("-0.00"));
+        assert_eq!(vec![0, 0, NUMERIC_NAN as i16, 0], numeric("NaN"));
+
+        let mut buf = BytesMut::new();
+        assert!(encode_numeric("1e10", &mut buf).is_err());
+        assert!(encode_numeric(".", &mut buf).is_err());
+    }
+
+    #[test]
+    fn test_decode_numeric() {
+        for text in [
+            "12345.6",
+            "-0.0001",
+            "0.00001",
+            "10000",
+            "0.00",
+            "1.000",
+            "NaN",
+            "-Infinity",
+        ] {
+            let mut buf = BytesMut::new();
+            encode_numeric(text, &mut buf).unwrap();
+            assert_eq!(text, decode_numeric(&buf).unwrap());
+        }
+        assert!(decode_numeric(&[0, 1]).is_err());
+    }
+
+    #[test]
+    fn test_array() {
+        let mut buf = BytesMut::new();
+        text_to_binary(&Type::INT4_ARRAY, b"{{1,NULL},{3,4}}", &mut buf).unwrap();
+        let mut expected = BytesMut::new();
+        // ndim, has null, element oid, dims and lower bounds
+        expected.put_i32(2);
+        expected.put_i32(1);
+        expected.put_u32(Type::INT4.oid());
+        expected.put_slice(&[0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1]);
+        for value in [Some(1), None, Some(3), Some(4)] {
+            match value {
+                Some(value) => {
+                    expected.put_i32(4);
+                    expected.put_i32(value);
+                }
+                None => expected.put_i32(-1),
+            }
+        }
+        assert_eq!(expected, buf);
+
+        let mut text = BytesMut::new();
+        binary_to_text(&Type::INT4_ARRAY, &buf, &mut text).unwrap();
+        assert_eq!(&b"{{1,NULL},{3,4}}"[..], &text[..]);
+
+        // 1-d array from postgres-types
+        let mut buf = BytesMut::new();
+        vec!["a b", "c"]
+            .to_sql(&Type::TEXT_ARRAY, &mut buf)
+            .unwrap();
+        let mut text = BytesMut::new();
+        binary_to_text(&Type::TEXT_ARRAY, &buf, &mut text).unwrap();
+        assert_eq!(&b"{\"a b\",c}"[..], &text[..]);
+
+        let mut buf = BytesMut::new();
+        text_to_binary(&Type::TEXT_ARRAY, b"{}", &mut buf).unwrap();
+        assert_eq!(
+            Vec::<String>::new(),
+            Vec::<String>::from_sql(&Type::TEXT_ARRAY, &buf).unwrap()
+        );
+
+        let mut buf = BytesMut::new();
+        assert!(text_to_binary(&Type::INT4_ARRAY, b"{{1,2},{3}}", &mut buf).is_err());
+        assert!(text_to_binary(&Type::INT4_ARRAY, b"{{1,2},3}", &mut buf).is_err());
+        assert!(text_to_binary(&Type::INT4_ARRAY, b"{1,{2}}", &mut buf).is_err());
+
+        // upper bound overflows i32
+        let mut buf = BytesMut::new();
+        buf.put_i32(1);
+        buf.put_i32(0);
+        buf.put_u32(Type::INT4.oid());
+        buf.put_i32(2);
+        buf.put_i32(i32::MAX);
+        buf.put_slice(&[0, 0, 0, 4, 0, 0, 0, 1, 0, 0, 0, 4, 0, 0, 0, 2]);
+        let mut text = BytesMut::new();
+        assert!(binary_to_text(&Type::INT4_ARRAY, &buf, &mut text).is_err());
+    }
+
+    #[test]
+    fn test_text_to_binary() {
+        let mut buf = BytesMut::new();
+        text_to_binary(&Type::INT8, b"42", &mut buf).unwrap();
+        assert_eq!(&42i64.to_be_bytes()[..], &buf[..]);
+
+        let mut buf = BytesMut::new();
+        text_to_binary(&Type::DATE, b"2000-01-02", &mut buf).unwrap();
+        assert_eq!(&1i32.to_be_bytes()[..], &buf[..]);
+
+        let mut buf = BytesMut::new();
+        text_to_binary(&Type::CIDR, b"10.0.0.0/8", &mut buf).unwrap();
+        let mut text = BytesMut::new();
+        binary_to_text(&Type::CIDR, &buf, &mut text).unwrap();
+        assert_eq!(&b"10.0.0.0/8"[..], &text[..]);
+
+        let mut buf = BytesMut::new();
+        text_to_binary(&Type::JSONB, br#"{"a":1}"#, &mut buf).unwrap();
+        assert_eq!(&b"\x01{\"a\":1}"[..], &buf[..]);
+        let mut text = BytesMut::new();
+        binary_to_text(&Type::JSONB, &buf, &mut text).unwrap();
+        assert_eq!(&br#"{"a":1}"#[..], &text[..]);
+
+        let mut buf = BytesMut::new();
+        assert!(text_to_binary(&Type::INT4, b"abc", &mut buf).is_err());
+        assert!(text_to_binary(&Type::POINT, b"(1,2)", &mut buf).is_err());
+    }
+
+    #[test]
+    fn test_special_values() {
+        fn round_trip(ty: &Type, text: &[u8], binary: &[u8]) {
+            let mut buf = BytesMut::new();
+            text_to_binary(ty, text, &mut buf).unwrap();
+            assert_eq!(binary, &buf[..], "{}", String::from_utf8_lossy(text));
+            let mut buf = BytesMut::new();
+            binary_to_text(ty, binary, &mut buf).unwrap();
+            assert_eq!(text, &buf[..]);
+        }
+
+        round_trip(&Type::DATE, b"infinity", &i32::MAX.to_be_bytes());
+        round_trip(&Type::DATE, b"-infinity", &i32::MIN.to_be_bytes());
+        round_trip(&Type::TIMESTAMP, b"infinity", &i64::MAX.to_be_bytes());
+        round_trip(&Type::TIMESTAMPTZ, b"-infinity", &i64::MIN.to_be_bytes());
+        round_trip(&Type::FLOAT8, b"NaN", &f64::NAN.to_be_bytes());
+        round_trip(
+            &Type::FLOAT4,
+            b"-Infinity",
+            &f32::NEG_INFINITY.to_be_bytes(),
+        );
+
+        // days and microseconds from 2000-01-01, negative before
+        round_trip(&Type::DATE, b"0044-03-15 BC", &(-746_117i32).to_be_bytes());
+        round_trip(
+            &Type::TIMESTAMP,
+            b"0001-01-01 00:00:00.000000 BC",
+            &(-63_113_904_000_000_000i64).to_be_bytes(),
+        );
+        round_trip(
+            &Type::TIMESTAMP,
+            b"1999-12-31 23:59:59.999999",
+            &(-1i64).to_be_bytes(),
+        );
+
+        // "char" is a single byte, not an integer
+        round_trip(&Type::CHAR, b"a", b"a");
+        round_trip(&Type::CHAR, b"1", b"1");
+        round_trip(&Type::CHAR, b"", &[0]);
+        round_trip(&Type::CHAR, b"\\351", &[0o351]);
+
+        // `i8` in text is the same "char" as in binary
+        for value in [b'a' as i8, 0, -23] {
+            let mut text = BytesMut::new();
+            value.to_sql_text(&Type::CHAR, &mut text).unwrap();
+            let mut binary = BytesMut::new();
+            text_to_binary(&Type::CHAR, &text, &mut binary).unwrap();
+            let mut expected = BytesMut::new();
+            value.to_sql(&Type::CHAR, &mut expected).unwrap();
+            assert_eq!(expected, binary);
+            assert_eq!(value, i8::from_sql_text(&Type::CHAR, &text).unwrap());
+        }
+    }
+}
@@ -0,0 +1,429 @@
+use std::borrow::Cow;
+use std::error::Error;
+
+use chrono::{
+    DateTime, Datelike, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, SubsecRound, Utc,
+};
+use postgres_types::{Date, Kind, Timestamp, Type};
+
+use super::binary::char_from_text;
+
+/// Parse values from text format of Postgres type, which is the default
+/// format of parameters sent by clients in `Bind`.
+///
+/// This trait is modelled after `FromSql` from postgres-types, which is for
+/// binary encoding. Null values are handled by the caller.
+pub trait FromSqlText: Sized {
+    /// Parse the value of type `ty` from its text format.
+    fn from_sql_text(ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>>;
+
+    /// Value for `NULL` element of array type `ty`, only accepted by
+    /// `Option`.
+    fn from_sql_null(ty: &Type) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        Err(format!("unexpected null value in array of type {}", ty.name()).into())
+    }
+}
+
+fn text(input: &[u8]) -> Result<&str, Box<dyn Error + Sync + Send>> {
+    Ok(std::str::from_utf8(input)?)
+}
+
+impl<T: FromSqlText> FromSqlText for Option<T> {
+    fn from_sql_text(ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        T::from_sql_text(ty, input).map(Some)
+    }
+
+    fn from_sql_null(_ty: &Type) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        Ok(None)
+    }
+}
+
+impl FromSqlText for bool {
+    fn from_sql_text(_ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        // accepts the same literals as postgres boolin
+        match text(input)?.trim().to_ascii_lowercase().as_str() {
+            "t" | "true" | "y" | "yes" | "on" | "1" => Ok(true),
+            "f" | "false" | "n" | "no" | "off" | "0" => Ok(false),
+            other => Err(format!("invalid input syntax for type boolean: \"{other}\"").into()),
+        }
+    }
+}
+
+impl FromSqlText for String {
+    fn from_sql_text(_ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        text(input).map(ToOwned::to_owned)
+    }
+}
+
+impl FromSqlText for char {
+    fn from_sql_text(_ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        let mut chars = text(input)?.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Ok(c),
+            _ => Err("invalid input for type char, expect a single character".into()),
+        }
+    }
+}
+
+impl FromSqlText for i8 {
+    fn from_sql_text(ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        if *ty == Type::CHAR {
+            Ok(char_from_text(input) as i8)
+        } else {
+            Ok(text(input)?.trim().parse::<i8>()?)
+        }
+    }
+}
+
+macro_rules! impl_from_sql_text {
+    ($t:ty) => {
+        impl FromSqlText for $t {
+            fn from_sql_text(
+                _ty: &Type,
+                input: &[u8],
+            ) -> Result<Self, Box<dyn Error + Sync + Send>> {
+                Ok(text(input)?.trim().parse::<$t>()?)
+            }
+        }
+    };
+}
+
+impl_from_sql_text!(i16);
+impl_from_sql_text!(i32);
+impl_from_sql_text!(i64);
+impl_from_sql_text!(u32);
+// `Infinity`, `-Infinity` and `NaN` are parsed by rust as well
+impl_from_sql_text!(f32);
+impl_from_sql_text!(f64);
+
+impl FromSqlText for Vec<u8> {
+    fn from_sql_text(_ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        // hex format like `\x0102`, the default output of postgres
+        if let Some(hex) = input.strip_prefix(b"\\x") {
+            return Ok(hex::decode(hex)?);
+        }
+
+        // escape format, `\\` and `\nnn` in octal are escaped
+        let mut out = Vec::with_capacity(input.len());
+        let mut i = 0;
+        while i < input.len() {
+            if input[i] != b'\\' {
+                out.push(input[i]);
+                i += 1;
+            } else if input.get(i + 1) == Some(&b'\\') {
+                out.push(b'\\');
+                i += 2;
+            } else {
+                let octal = input
+                    .get(i + 1..i + 4)
+                    .and_then(|digits| std::str::from_utf8(digits).ok())
+                    .and_then(|digits| u8::from_str_radix(digits, 8).ok())
+                    .ok_or("invalid input syntax for type bytea")?;
+                out.push(octal);
+                i += 4;
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Split ` BC` suffix of dates before 1 AD, like `0044-03-15 BC`.
+fn split_era(input: &str) -> (&str, bool) {
+    match input.len().checked_sub(3).map(|idx| input.split_at(idx)) {
+        Some((date, era)) if era.eq_ignore_ascii_case(" bc") => (date.trim_end(), true),
+        _ => (input, false),
+    }
+}
+
+/// Year 44 BC is year -43 of chrono, which counts 1 BC as year 0.
+fn with_era<T: Datelike>(value: T, bc: bool) -> Result<T, Box<dyn Error + Sync + Send>> {
+    if bc {
+        if value.year() < 1 {
+            return Err("invalid year of date before 1 AD".into());
+        }
+        value
+            .with_year(1 - value.year())
+            .ok_or_else(|| "date out of range".into())
+    } else {
+        Ok(value)
+    }
+}
+
+impl FromSqlText for NaiveDate {
+    fn from_sql_text(_ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        let (input, bc) = split_era(text(input)?.trim());
+        with_era(NaiveDate::parse_from_str(input, "%Y-%m-%d")?, bc)
+    }
+}
+
+// Fractional seconds are rounded to microseconds, like postgres
+impl FromSqlText for NaiveTime {
+    fn from_sql_text(_ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        Ok(NaiveTime::parse_from_str(text(input)?.trim(), "%H:%M:%S%.f")?.round_subsecs(6))
+    }
+}
+
+impl FromSqlText for NaiveDateTime {
+    fn from_sql_text(_ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        let (input, bc) = split_era(text(input)?.trim());
+        let value = NaiveDateTime::parse_from_str(input, "%Y-%m-%d %H:%M:%S%.f")
+            .or_else(|_| NaiveDateTime::parse_from_str(input, "%Y-%m-%dT%H:%M:%S%.f"))?;
+        with_era(value.round_subsecs(6), bc)
+    }
+}
+
+impl FromSqlText for DateTime<FixedOffset> {
+    fn from_sql_text(_ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        let (input, bc) = split_era(text(input)?.trim());
+        // offset can be `+08`, `+0800` or `+08:00`
+        let value = DateTime::parse_from_str(input, "%Y-%m-%d %H:%M:%S%.f%#z")
+            .or_else(|_| DateTime::parse_from_str(input, "%Y-%m-%dT%H:%M:%S%.f%#z"))?;
+        with_era(value.round_subsecs(6), bc)
+    }
+}
+
+impl FromSqlText for DateTime<Utc> {
+    fn from_sql_text(ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        DateTime::<FixedOffset>::from_sql_text(ty, input).map(|dt| dt.with_timezone(&Utc))
+    }
+}
+
+/// `Some(true)` for `infinity`, `Some(false)` for `-infinity` of dates and
+/// timestamps.
+fn parse_infinity(input: &[u8]) -> Result<Option<bool>, Box<dyn Error + Sync + Send>> {
+    let input = text(input)?.trim();
+    if input.eq_ignore_ascii_case("infinity") || input.eq_ignore_ascii_case("+infinity") {
+        Ok(Some(true))
+    } else if input.eq_ignore_ascii_case("-infinity") {
+        Ok(Some(false))
+    } else {
+        Ok(None)
+    }
+}
+
+impl<T: FromSqlText> FromSqlText for Date<T> {
+    fn from_sql_text(ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        match parse_infinity(input)? {
+            Some(true) => Ok(Date::PosInfinity),
+            Some(false) => Ok(Date::NegInfinity),
+            None => T::from_sql_text(ty, input).map(Date::Value),
+        }
+    }
+}
+
+impl<T: FromSqlText> FromSqlText for Timestamp<T> {
+    fn from_sql_text(ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        match parse_infinity(input)? {
+            Some(true) => Ok(Timestamp::PosInfinity),
+            Some(false) => Ok(Timestamp::NegInfinity),
+            None => T::from_sql_text(ty, input).map(Timestamp::Value),
+        }
+    }
+}
+
+/// Element of array in text format.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ArrayElement<'a> {
+    Null,
+    Value(Cow<'a, str>),
+    /// Nested array of multi-dimensional array, in text format
+    Array(&'a str),
+}
+
+fn invalid_array(input: &str) -> Box<dyn Error + Sync + Send> {
+    format!("malformed array literal: \"{input}\"").into()
+}
+
+/// Split array in text format like `{1,NULL,"a b"}` into its elements.
+pub(crate) fn parse_array(
+    input: &str,
+) -> Result<Vec<ArrayElement<'_>>, Box<dyn Error + Sync + Send>> {
+    let mut array = input.trim();
+    // skip dimension decoration like `[0:1]=`
+    if array.starts_with('[') {
+        array = array
+            .split_once('=')
+            .ok_or_else(|| invalid_array(input))?
+            .1
+            .trim_start();
+    }
+    let body = array
+        .strip_prefix('{')
+        .and_then(|body| body.strip_suffix('}'))
+        .ok_or_else(|| invalid_array(input))?;
+    let bytes = body.as_bytes();
+
+    let mut elements = Vec::new();
+    if body.trim().is_empty() {
+        return Ok(elements);
+    }
+    let mut i = 0;
+    loop {
+        while bytes.get(i).is_some_and(u8::is_ascii_whitespace) {
+            i += 1;
+        }
+        match bytes.get(i).ok_or_else(|| invalid_array(input))? {
+            b'{' => {
+                let start = i;
+                let mut depth = 0;
+                let mut quoted = false;
+                loop {
+                    match bytes.get(i).ok_or_else(|| invalid_array(input))? {
+                        b'\\' if quoted => i += 1,
+                        b'"' => quoted = !quoted,
+                        b'{' if !quoted => depth += 1,
+                        b'}' if !quoted => depth -= 1,
+                        _ => {}
+                    }
+                    i += 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                elements.push(ArrayElement::Array(&body[start..i]));
+            }
+            b'"' => {
+                i += 1;
+                let mut value = Vec::new();
+                loop {
+                    match bytes.get(i).ok_or_else(|| invalid_array(input))? {
+                        b'"' => break,
+                        b'\\' => {
+                            i += 1;
+                            value.push(*bytes.get(i).ok_or_else(|| invalid_array(input))?);
+                        }
+                        b => value.push(*b),
+                    }
+                    i += 1;
+                }
+                i += 1;
+                elements.push(ArrayElement::Value(Cow::Owned(String::from_utf8(value)?)));
+            }
+            _ => {
+                let start = i;
+                while i < bytes.len() && !matches!(bytes[i], b',' | b'{' | b'}' | b'"') {
+                    i += 1;
+                }
+                let value = body[start..i].trim_end();
+                elements.push(if value.eq_ignore_ascii_case("NULL") {
+                    ArrayElement::Null
+                } else {
+                    ArrayElement::Value(Cow::Borrowed(value))
+                });
+            }
+        }
+
+        while bytes.get(i).is_some_and(u8::is_ascii_whitespace) {
+            i += 1;
+        }
+        match bytes.get(i) {
+            None => return Ok(elements),
+            Some(b',') => i += 1,
+            Some(_) => return Err(invalid_array(input)),
+        }
+    }
+}
+
+/// Arrays like `{1,2,3}`, or `{{1,2},{3,4}}` with nested `Vec` for
+/// multi-dimensional arrays. Use `Option` for elements to accept `NULL`.
+impl<T: FromSqlText> FromSqlText for Vec<T> {
+    fn from_sql_text(ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        let member = match ty.kind() {
+            Kind::Array(member) => member,
+            _ => ty,
+        };
+        parse_array(text(input)?)?
+            .into_iter()
+            .map(|element| match element {
+                ArrayElement::Null => T::from_sql_null(ty),
+                ArrayElement::Value(value) => T::from_sql_text(member, value.as_bytes()),
+                // nested arrays are parsed with the array type
+                ArrayElement::Array(array) => T::from_sql_text(ty, array.as_bytes()),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_sql_text() {
+        assert!(bool::from_sql_text(&Type::BOOL, b"t").unwrap());
+        assert!(!bool::from_sql_text(&Type::BOOL, b"OFF").unwrap());
+        assert!(bool::from_sql_text(&Type::BOOL, b"maybe").is_err());
+
+        assert_eq!(-42, i32::from_sql_text(&Type::INT4, b"-42").unwrap());
+        assert!(i16::from_sql_text(&Type::INT2, b"70000").is_err());
+        assert!(f64::from_sql_text(&Type::FLOAT8, b"-Infinity")
+            .unwrap()
+            .is_infinite());
+        assert_eq!(
+            "hello",
+            String::from_sql_text(&Type::TEXT, b"hello").unwrap()
+        );
+
+        assert_eq!(
+            vec![1, 2, 0xff],
+            Vec::<u8>::from_sql_text(&Type::BYTEA, b"\\x0102ff").unwrap()
+        );
+        assert_eq!(
+            b"a\\\x01".to_vec(),
+            Vec::<u8>::from_sql_text(&Type::BYTEA, b"a\\\\\\001").unwrap()
+        );
+
+        let date = NaiveDate::from_ymd_opt(2023, 3, 5).unwrap();
+        assert_eq!(
+            date,
+            NaiveDate::from_sql_text(&Type::DATE, b"2023-03-05").unwrap()
+        );
+        assert_eq!(
+            date.and_hms_micro_opt(1, 2, 3, 456).unwrap(),
+            NaiveDateTime::from_sql_text(&Type::TIMESTAMP, b"2023-03-05 01:02:03.000456").unwrap()
+        );
+        assert_eq!(
+            date.and_hms_opt(0, 2, 3).unwrap().and_utc(),
+            DateTime::<Utc>::from_sql_text(&Type::TIMESTAMPTZ, b"2023-03-05 01:02:03+01").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_array_from_sql_text() {
+        assert_eq!(
+            vec![1, 2, 3],
+            Vec::<i32>::from_sql_text(&Type::INT4_ARRAY, b"{1, 2 ,3}").unwrap()
+        );
+        assert_eq!(
+            Vec::<i32>::new(),
+            Vec::<i32>::from_sql_text(&Type::INT4_ARRAY, b"{}").unwrap()
+        );
+        assert_eq!(
+            vec![
+                Some("a b".to_owned()),
+                None,
+                Some("NULL".to_owned()),
+                Some("x\"}".to_owned())
+            ],
+            Vec::<Option<String>>::from_sql_text(
+                &Type::TEXT_ARRAY,
+                br#"{"a b",NULL,"NULL","x\"}"}"#
+            )
+            .unwrap()
+        );
+        assert!(Vec::<String>::from_sql_text(&Type::TEXT_ARRAY, b"{a,NULL}").is_err());
+
+        assert_eq!(
+            vec![vec![1, 2], vec![3, 4]],
+            Vec::<Vec<i64>>::from_sql_text(&Type::INT8_ARRAY, b"[1:2][1:2]={{1,2},{3,4}}").unwrap()
+        );
+        assert_eq!(
+            vec![NaiveDate::from_ymd_opt(2023, 3, 5).unwrap()],
+            Vec::<NaiveDate>::from_sql_text(&Type::DATE_ARRAY, b"{2023-03-05}").unwrap()
+        );
+
+        assert!(Vec::<i32>::from_sql_text(&Type::INT4_ARRAY, b"1,2").is_err());
+        assert!(Vec::<i32>::from_sql_text(&Type::INT4_ARRAY, b"{1,}").is_err());
+        assert!(Vec::<i32>::from_sql_text(&Type::INT4_ARRAY, b"{\"1}").is_err());
+    }
+}
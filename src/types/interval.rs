@@ -0,0 +1,358 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use bytes::{Buf, BufMut, BytesMut};
+use postgres_types::{to_sql_checked, FromSql, IsNull, ToSql, Type};
+
+use super::{FromSqlText, ToSqlText};
+
+const MICROS_PER_SECOND: i64 = 1_000_000;
+const MICROS_PER_MINUTE: i64 = 60 * MICROS_PER_SECOND;
+const MICROS_PER_HOUR: i64 = 60 * MICROS_PER_MINUTE;
+
+/// Postgres `INTERVAL`.
+///
+/// Like postgres, months, days and microseconds are stored separately as
+/// the length of a month or a day varies, so `1 mon` is not `30 days`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, new)]
+pub struct Interval {
+    pub months: i32,
+    pub days: i32,
+    pub microseconds: i64,
+}
+
+impl Interval {
+    /// Convert to `chrono::Duration`, with 24 hours for a day. Returns `None`
+    /// when the interval has months.
+    pub fn to_duration(&self) -> Option<chrono::Duration> {
+        if self.months != 0 {
+            return None;
+        }
+        chrono::Duration::try_days(self.days as i64)?
+            .checked_add(&chrono::Duration::microseconds(self.microseconds))
+    }
+}
+
+impl From<chrono::Duration> for Interval {
+    /// Converted to microseconds only, saturated at the range of `i64`.
+    fn from(duration: chrono::Duration) -> Self {
+        let microseconds =
+            duration
+                .num_microseconds()
+                .unwrap_or(if duration < chrono::Duration::zero() {
+                    i64::MIN
+                } else {
+                    i64::MAX
+                });
+        Interval::new(0, 0, microseconds)
+    }
+}
+
+impl fmt::Display for Interval {
+    /// Format like postgres with the default `IntervalStyle`, for example
+    /// `1 year 2 mons -3 days +04:05:06.5`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        // fields after a negative one have explicit sign
+        let mut is_before = false;
+        for (value, unit) in [
+            (self.months / 12, "year"),
+            (self.months % 12, "mon"),
+            (self.days, "day"),
+        ] {
+            if value == 0 {
+                continue;
+            }
+            let sign = if is_before && value > 0 { "+" } else { "" };
+            let plural = if value != 1 { "s" } else { "" };
+            parts.push(format!("{sign}{value} {unit}{plural}"));
+            is_before |= value < 0;
+        }
+
+        if parts.is_empty() || self.microseconds != 0 {
+            let sign = if self.microseconds < 0 {
+                "-"
+            } else if is_before {
+                "+"
+            } else {
+                ""
+            };
+            let micros = self.microseconds.unsigned_abs();
+            let mut time = format!(
+                "{sign}{:02}:{:02}:{:02}",
+                micros / MICROS_PER_HOUR as u64,
+                micros / MICROS_PER_MINUTE as u64 % 60,
+                micros / MICROS_PER_SECOND as u64 % 60
+            );
+            let fraction = micros % MICROS_PER_SECOND as u64;
+            if fraction != 0 {
+                time.push_str(format!(".{fraction:06}").trim_end_matches('0'));
+            }
+            parts.push(time);
+        }
+        f.write_str(&parts.join(" "))
+    }
+}
+
+fn invalid(text: &str) -> Box<dyn Error + Sync + Send> {
+    format!("invalid input syntax for type interval: \"{text}\"").into()
+}
+
+fn fraction_micros(value: &str, unit: i64) -> Option<i64> {
+    let micros = (value.parse::<f64>().ok()? * unit as f64).round();
+    (micros.abs() < i64::MAX as f64).then_some(micros as i64)
+}
+
+impl Interval {
+    fn add(&mut self, value: &str, unit: &str) -> Option<()> {
+        let int = || value.parse::<i32>().ok();
+        match unit.to_ascii_lowercase().as_str() {
+            "y" | "yr" | "yrs" | "year" | "years" => {
+                self.months = self.months.checked_add(int()?.checked_mul(12)?)?
+            }
+            "mon" | "mons" | "month" | "months" => self.months = self.months.checked_add(int()?)?,
+            "w" | "week" | "weeks" => self.days = self.days.checked_add(int()?.checked_mul(7)?)?,
+            "d" | "day" | "days" => self.days = self.days.checked_add(int()?)?,
+            other => {
+                let unit = match other {
+                    "h" | "hr" | "hrs" | "hour" | "hours" => MICROS_PER_HOUR,
+                    "m" | "min" | "mins" | "minute" | "minutes" => MICROS_PER_MINUTE,
+                    "s" | "sec" | "secs" | "second" | "seconds" => MICROS_PER_SECOND,
+                    "ms" | "millisecond" | "milliseconds" => 1_000,
+                    "us" | "microsecond" | "microseconds" => 1,
+                    _ => return None,
+                };
+                self.microseconds = self
+                    .microseconds
+                    .checked_add(fraction_micros(value, unit)?)?;
+            }
+        }
+        Some(())
+    }
+
+    // `[-]hh:mm[:ss[.ffffff]]`
+    fn add_time(&mut self, time: &str) -> Option<()> {
+        let (negative, time) = match time.strip_prefix('-') {
+            Some(time) => (true, time),
+            None => (false, time.strip_prefix('+').unwrap_or(time)),
+        };
+        let mut fields = time.split(':');
+        let hours = fields.next()?.parse::<i64>().ok()?;
+        let minutes = fields.next()?.parse::<i64>().ok()?;
+        let seconds = fields
+            .next()
+            .map_or(Some(0), |s| fraction_micros(s, MICROS_PER_SECOND))?;
+        if fields.next().is_some() {
+            return None;
+        }
+        let micros = hours
+            .checked_mul(MICROS_PER_HOUR)?
+            .checked_add(minutes.checked_mul(MICROS_PER_MINUTE)?)?
+            .checked_add(seconds)?;
+        self.microseconds =
+            self.microseconds
+                .checked_add(if negative { -micros } else { micros })?;
+        Some(())
+    }
+
+    // ISO 8601 format with designators, like `P1Y2M3DT4H5M6.5S`
+    fn parse_iso(text: &str) -> Option<Interval> {
+        let mut interval = Interval::default();
+        let (date, time) = text.split_once('T').unwrap_or((text, ""));
+        for (part, is_time) in [(date, false), (time, true)] {
+            let mut value = String::new();
+            for c in part.chars() {
+                if c.is_ascii_digit() || matches!(c, '.' | '-' | '+') {
+                    value.push(c);
+                    continue;
+                }
+                let unit = match (c, is_time) {
+                    ('Y', false) => "y",
+                    ('M', false) => "mon",
+                    ('W', false) => "w",
+                    ('D', false) => "d",
+                    ('H', true) => "h",
+                    ('M', true) => "m",
+                    ('S', true) => "s",
+                    _ => return None,
+                };
+                interval.add(&value, unit)?;
+                value.clear();
+            }
+            if !value.is_empty() {
+                return None;
+            }
+        }
+        Some(interval)
+    }
+}
+
+impl FromStr for Interval {
+    type Err = Box<dyn Error + Sync + Send>;
+
+    /// Parse interval in postgres format like `1 year 2 mons 3 days 04:05:06`
+    /// or `@ 1 hour ago`, or ISO 8601 format like `P1Y2M3DT4H5M6S`.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let trimmed = text.trim();
+        if let Some(iso) = trimmed.strip_prefix('P') {
+            return Interval::parse_iso(iso).ok_or_else(|| invalid(text));
+        }
+
+        if trimmed.is_empty() {
+            return Err(invalid(text));
+        }
+
+        let mut interval = Interval::default();
+        let mut ago = false;
+        let mut tokens = trimmed.split_whitespace();
+        while let Some(token) = tokens.next() {
+            let ok = if token == "@" {
+                Some(())
+            } else if token.eq_ignore_ascii_case("ago") {
+                ago = true;
+                Some(())
+            } else if token.contains(':') {
+                interval.add_time(token)
+            } else {
+                tokens
+                    .next()
+                    .and_then(|unit| interval.add(token, unit.trim_end_matches(',')))
+            };
+            ok.ok_or_else(|| invalid(text))?;
+        }
+
+        if ago {
+            interval = Interval::new(
+                interval.months.checked_neg().ok_or_else(|| invalid(text))?,
+                interval.days.checked_neg().ok_or_else(|| invalid(text))?,
+                interval
+                    .microseconds
+                    .checked_neg()
+                    .ok_or_else(|| invalid(text))?,
+            );
+        }
+        Ok(interval)
+    }
+}
+
+impl ToSql for Interval {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.put_i64(self.microseconds);
+        out.put_i32(self.days);
+        out.put_i32(self.months);
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::INTERVAL
+    }
+
+    to_sql_checked!();
+}
+
+impl<'a> FromSql<'a> for Interval {
+    fn from_sql(_ty: &Type, mut raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        if raw.len() != 16 {
+            return Err("invalid binary format of interval".into());
+        }
+        let microseconds = raw.get_i64();
+        let days = raw.get_i32();
+        let months = raw.get_i32();
+        Ok(Interval::new(months, days, microseconds))
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::INTERVAL
+    }
+}
+
+impl ToSqlText for Interval {
+    fn to_sql_text(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.put_slice(self.to_string().as_bytes());
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSqlText for Interval {
+    fn from_sql_text(_ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        std::str::from_utf8(input)?.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interval_text() {
+        for (interval, text) in [
+            (Interval::default(), "00:00:00"),
+            (Interval::new(14, 1, 0), "1 year 2 mons 1 day"),
+            (
+                Interval::new(-14, 3, -3_723_500_000),
+                "-1 years -2 mons +3 days -01:02:03.5",
+            ),
+            (
+                Interval::new(0, -1, 3_600_000_001),
+                "-1 days +01:00:00.000001",
+            ),
+            (Interval::new(0, 0, 90 * MICROS_PER_HOUR), "90:00:00"),
+        ] {
+            assert_eq!(text, interval.to_string());
+            assert_eq!(interval, text.parse().unwrap());
+        }
+
+        assert_eq!(
+            Interval::new(0, -7, -MICROS_PER_HOUR / 2),
+            "@ 1 week 30 mins ago".parse().unwrap()
+        );
+        assert_eq!(
+            Interval::new(14, 3, 4 * MICROS_PER_HOUR + 6_500_000),
+            "P1Y2M3DT4H6.5S".parse().unwrap()
+        );
+        assert_eq!(Interval::new(0, 0, 1_500), "1.5 ms".parse().unwrap());
+        assert!("".parse::<Interval>().is_err());
+        assert!("1 fortnight".parse::<Interval>().is_err());
+        assert!("1 day 1".parse::<Interval>().is_err());
+        assert!("P1H".parse::<Interval>().is_err());
+        assert!("99999999999 years".parse::<Interval>().is_err());
+        assert!("-2147483648 days ago".parse::<Interval>().is_err());
+        assert_eq!(
+            Interval::new(0, i32::MAX, 0),
+            "-2147483647 days ago".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_interval_binary() {
+        let interval = Interval::new(-14, 3, 3_723_500_000);
+        let mut buf = BytesMut::new();
+        interval.to_sql(&Type::INTERVAL, &mut buf).unwrap();
+        assert_eq!(16, buf.len());
+        assert_eq!(&3_723_500_000i64.to_be_bytes()[..], &buf[..8]);
+        assert_eq!(interval, Interval::from_sql(&Type::INTERVAL, &buf).unwrap());
+        assert!(Interval::from_sql(&Type::INTERVAL, &buf[..8]).is_err());
+    }
+
+    #[test]
+    fn test_duration() {
+        let duration = chrono::Duration::try_hours(25).unwrap();
+        let interval = Interval::from(duration);
+        assert_eq!(Interval::new(0, 0, 25 * MICROS_PER_HOUR), interval);
+        assert_eq!(Some(duration), interval.to_duration());
+        assert_eq!(
+            Some(duration),
+            Interval::new(0, 1, MICROS_PER_HOUR).to_duration()
+        );
+        assert_eq!(None, Interval::new(1, 0, 0).to_duration());
+    }
+}
@@ -3,8 +3,27 @@ use std::{error::Error, fmt};
 
 use bytes::{BufMut, BytesMut};
 use chrono::offset::Utc;
-use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
-use postgres_types::{IsNull, Type, WrongType};
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, NaiveTime, SubsecRound, TimeZone};
+use postgres_types::{Date, IsNull, Kind, Timestamp, Type, WrongType};
+
+pub(crate) mod binary;
+mod from_sql_text;
+mod interval;
+mod network;
+#[cfg(any(feature = "rust-decimal", feature = "bigdecimal"))]
+mod numeric;
+#[cfg(feature = "serde_json")]
+mod serde_json_1;
+#[cfg(feature = "time")]
+mod time_03;
+#[cfg(feature = "uuid")]
+mod uuid_1;
+
+pub use from_sql_text::FromSqlText;
+pub use interval::Interval;
+pub use network::{Inet, MacAddr};
+#[cfg(feature = "bigdecimal")]
+pub use numeric::Numeric;
 
 pub trait ToSqlText: fmt::Debug {
     /// Converts value to text format of Postgres type.
@@ -18,9 +37,56 @@ pub trait ToSqlText: fmt::Debug {
     ) -> Result<IsNull, Box<dyn Error + Sync + Send>>
     where
         Self: Sized;
+
+    /// Converts value to text format as an element of array type `ty`.
+    ///
+    /// The value is quoted when needed, arrays override this to write nested
+    /// arrays of multi-dimensional arrays as is.
+    fn to_sql_text_element(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>>
+    where
+        Self: Sized,
+    {
+        let member = match ty.kind() {
+            Kind::Array(member) => member,
+            _ => ty,
+        };
+        let mut text = BytesMut::new();
+        let is_null = self.to_sql_text(member, &mut text)?;
+        if let IsNull::No = is_null {
+            write_array_element(&text, out);
+        }
+        Ok(is_null)
+    }
+}
+
+/// Write value in text format as an element of array, quoted like postgres
+/// when it's empty, `NULL` or contains special characters.
+pub(crate) fn write_array_element(text: &[u8], out: &mut BytesMut) {
+    let needs_quote = text.is_empty()
+        || text.eq_ignore_ascii_case(b"NULL")
+        || text
+            .iter()
+            .any(|b| matches!(b, b'{' | b'}' | b',' | b'"' | b'\\') || b.is_ascii_whitespace());
+    if !needs_quote {
+        out.put_slice(text);
+        return;
+    }
+
+    out.put_u8(b'"');
+    for b in text {
+        if matches!(b, b'"' | b'\\') {
+            out.put_u8(b'\\');
+        }
+        out.put_u8(*b);
+    }
+    out.put_u8(b'"');
 }
 
-impl<'a, T> ToSqlText for &'a T
+impl<T> ToSqlText for &T
 where
     T: ToSqlText,
 {
@@ -31,6 +97,14 @@ where
     ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
         (*self).to_sql_text(ty, out)
     }
+
+    fn to_sql_text_element(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        (*self).to_sql_text_element(ty, out)
+    }
 }
 
 impl<T: ToSqlText> ToSqlText for Option<T> {
@@ -44,6 +118,17 @@ impl<T: ToSqlText> ToSqlText for Option<T> {
             None => Ok(IsNull::Yes),
         }
     }
+
+    fn to_sql_text_element(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        match *self {
+            Some(ref val) => val.to_sql_text_element(ty, out),
+            None => Ok(IsNull::Yes),
+        }
+    }
 }
 
 impl ToSqlText for bool {
@@ -71,7 +156,7 @@ impl ToSqlText for String {
     }
 }
 
-impl<'a> ToSqlText for &'a str {
+impl ToSqlText for &str {
     fn to_sql_text(
         &self,
         _ty: &Type,
@@ -82,6 +167,23 @@ impl<'a> ToSqlText for &'a str {
     }
 }
 
+// `i8` is the byte of `"char"`, like `ToSql` of postgres-types, and an
+// integer for other types
+impl ToSqlText for i8 {
+    fn to_sql_text(
+        &self,
+        ty: &Type,
+        w: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        if *ty == Type::CHAR {
+            binary::char_to_text(*self as u8, w);
+        } else {
+            w.put_slice(self.to_string().as_bytes());
+        }
+        Ok(IsNull::No)
+    }
+}
+
 macro_rules! impl_to_sql_text {
     ($t:ty) => {
         impl ToSqlText for $t {
@@ -97,21 +199,62 @@ macro_rules! impl_to_sql_text {
     };
 }
 
-impl_to_sql_text!(i8);
 impl_to_sql_text!(i16);
 impl_to_sql_text!(i32);
 impl_to_sql_text!(i64);
 impl_to_sql_text!(u32);
-impl_to_sql_text!(f32);
-impl_to_sql_text!(f64);
 impl_to_sql_text!(char);
 
+// Floats are written like postgres, with the shortest digits that read back
+// the same value, in exponential form like `1e+20` when the exponent is
+// below -4 or from the number of significant digits of the type. In a
+// `NUMERIC` column they are written without exponent.
+macro_rules! impl_float_to_sql_text {
+    ($t:ty, $max_exp:expr) => {
+        impl ToSqlText for $t {
+            fn to_sql_text(
+                &self,
+                ty: &Type,
+                w: &mut BytesMut,
+            ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+                if self.is_nan() {
+                    w.put_slice(b"NaN");
+                } else if self.is_infinite() {
+                    w.put_slice(if *self > 0.0 {
+                        b"Infinity"
+                    } else {
+                        b"-Infinity"
+                    });
+                } else if *ty == Type::NUMERIC {
+                    w.put_slice(self.to_string().as_bytes());
+                } else {
+                    let exp = format!("{:e}", self);
+                    let (mantissa, exp) = exp.split_once('e').expect("exponent of float");
+                    let exp: i32 = exp.parse()?;
+                    if (-4..$max_exp).contains(&exp) {
+                        w.put_slice(self.to_string().as_bytes());
+                    } else {
+                        let sign = if exp < 0 { '-' } else { '+' };
+                        w.put_slice(format!("{mantissa}e{sign}{:02}", exp.abs()).as_bytes());
+                    }
+                }
+                Ok(IsNull::No)
+            }
+        }
+    };
+}
+
+impl_float_to_sql_text!(f32, 6);
+impl_float_to_sql_text!(f64, 15);
+
 impl ToSqlText for &[u8] {
     fn to_sql_text(
         &self,
         _ty: &Type,
         out: &mut BytesMut,
     ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        // hex format, the default output of postgres
+        out.put_slice(b"\\x");
         out.put_slice(hex::encode(self).as_bytes());
         Ok(IsNull::No)
     }
@@ -137,6 +280,18 @@ impl<const N: usize> ToSqlText for [u8; N] {
     }
 }
 
+// Write date and time after the year like postgres, with years before 1 AD
+// as `0044-03-15 BC`, as chrono counts 1 BC as year 0, and years after 9999
+// without the `+` of chrono.
+fn put_date_time(year: i32, rest: &str, out: &mut BytesMut) {
+    let text = if year > 0 {
+        format!("{year:04}-{rest}")
+    } else {
+        format!("{:04}-{rest} BC", 1 - year)
+    };
+    out.put_slice(text.as_bytes());
+}
+
 impl ToSqlText for SystemTime {
     fn to_sql_text(
         &self,
@@ -144,9 +299,7 @@ impl ToSqlText for SystemTime {
         out: &mut BytesMut,
     ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
         let datetime: DateTime<Utc> = DateTime::<Utc>::from(*self);
-        let fmt = datetime.format("%Y-%m-%d %H:%M:%S%.6f").to_string();
-        out.put_slice(fmt.as_bytes());
-        Ok(IsNull::No)
+        datetime.naive_utc().to_sql_text(&Type::TIMESTAMP, out)
     }
 }
 
@@ -159,15 +312,22 @@ where
         ty: &Type,
         out: &mut BytesMut,
     ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
-        let fmt = match *ty {
-            Type::TIMESTAMP => "%Y-%m-%d %H:%M:%S%.6f",
-            Type::TIMESTAMPTZ => "%Y-%m-%d %H:%M:%S%.6f%:::z",
-            Type::DATE => "%Y-%m-%d",
+        // postgres keeps microseconds, rounding the rest
+        let value = self.clone().round_subsecs(6);
+        let rest = match *ty {
+            Type::TIMESTAMP => "%m-%d %H:%M:%S%.6f",
+            Type::TIMESTAMPTZ => "%m-%d %H:%M:%S%.6f%:::z",
+            Type::DATE => "%m-%d",
             Type::TIME => "%H:%M:%S%.6f",
             Type::TIMETZ => "%H:%M:%S%.6f%:::z",
             _ => Err(Box::new(WrongType::new::<DateTime<Tz>>(ty.clone())))?,
         };
-        out.put_slice(self.format(fmt).to_string().as_bytes());
+        let rest = value.format(rest).to_string();
+        if matches!(*ty, Type::TIME | Type::TIMETZ) {
+            out.put_slice(rest.as_bytes());
+        } else {
+            put_date_time(value.year(), &rest, out);
+        }
         Ok(IsNull::No)
     }
 }
@@ -178,13 +338,17 @@ impl ToSqlText for NaiveDateTime {
         ty: &Type,
         out: &mut BytesMut,
     ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
-        let fmt = match *ty {
-            Type::TIMESTAMP => "%Y-%m-%d %H:%M:%S%.6f",
-            Type::DATE => "%Y-%m-%d",
-            Type::TIME => "%H:%M:%S%.6f",
+        let value = self.round_subsecs(6);
+        match *ty {
+            Type::TIMESTAMP => put_date_time(
+                value.year(),
+                &value.format("%m-%d %H:%M:%S%.6f").to_string(),
+                out,
+            ),
+            Type::DATE => value.date().to_sql_text(ty, out).map(|_| ())?,
+            Type::TIME => value.time().to_sql_text(ty, out).map(|_| ())?,
             _ => Err(Box::new(WrongType::new::<NaiveDateTime>(ty.clone())))?,
-        };
-        out.put_slice(self.format(fmt).to_string().as_bytes());
+        }
         Ok(IsNull::No)
     }
 }
@@ -195,12 +359,10 @@ impl ToSqlText for NaiveDate {
         ty: &Type,
         out: &mut BytesMut,
     ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
-        let fmt = match *ty {
-            Type::DATE => self.format("%Y-%m-%d").to_string(),
+        match *ty {
+            Type::DATE => put_date_time(self.year(), &self.format("%m-%d").to_string(), out),
             _ => Err(Box::new(WrongType::new::<NaiveDate>(ty.clone())))?,
-        };
-
-        out.put_slice(fmt.as_bytes());
+        }
         Ok(IsNull::No)
     }
 }
@@ -212,7 +374,7 @@ impl ToSqlText for NaiveTime {
         out: &mut BytesMut,
     ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
         let fmt = match *ty {
-            Type::TIME => self.format("%H:%M:%S%.6f").to_string(),
+            Type::TIME => self.round_subsecs(6).format("%H:%M:%S%.6f").to_string(),
             _ => Err(Box::new(WrongType::new::<NaiveTime>(ty.clone())))?,
         };
         out.put_slice(fmt.as_bytes());
@@ -220,6 +382,38 @@ impl ToSqlText for NaiveTime {
     }
 }
 
+// `infinity` and `-infinity` of dates and timestamps, with the binary format
+// from postgres-types.
+impl<T: ToSqlText> ToSqlText for Date<T> {
+    fn to_sql_text(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        match self {
+            Date::PosInfinity => out.put_slice(b"infinity"),
+            Date::NegInfinity => out.put_slice(b"-infinity"),
+            Date::Value(value) => return value.to_sql_text(ty, out),
+        }
+        Ok(IsNull::No)
+    }
+}
+
+impl<T: ToSqlText> ToSqlText for Timestamp<T> {
+    fn to_sql_text(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        match self {
+            Timestamp::PosInfinity => out.put_slice(b"infinity"),
+            Timestamp::NegInfinity => out.put_slice(b"-infinity"),
+            Timestamp::Value(value) => return value.to_sql_text(ty, out),
+        }
+        Ok(IsNull::No)
+    }
+}
+
 impl<T: ToSqlText> ToSqlText for &[T] {
     fn to_sql_text(
         &self,
@@ -232,13 +426,21 @@ impl<T: ToSqlText> ToSqlText for &[T] {
                 out.put_slice(b",");
             }
             // put NULL for null value in array
-            if let IsNull::Yes = val.to_sql_text(ty, out)? {
+            if let IsNull::Yes = val.to_sql_text_element(ty, out)? {
                 out.put_slice(b"NULL");
             }
         }
         out.put_slice(b"}");
         Ok(IsNull::No)
     }
+
+    fn to_sql_text_element(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        self.to_sql_text(ty, out)
+    }
 }
 
 impl<T: ToSqlText> ToSqlText for Vec<T> {
@@ -249,6 +451,14 @@ impl<T: ToSqlText> ToSqlText for Vec<T> {
     ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
         <&[T] as ToSqlText>::to_sql_text(&&**self, ty, out)
     }
+
+    fn to_sql_text_element(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        <&[T] as ToSqlText>::to_sql_text(&&**self, ty, out)
+    }
 }
 
 impl<T: ToSqlText, const N: usize> ToSqlText for [T; N] {
@@ -259,6 +469,14 @@ impl<T: ToSqlText, const N: usize> ToSqlText for [T; N] {
     ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
         <&[T] as ToSqlText>::to_sql_text(&&self[..], ty, out)
     }
+
+    fn to_sql_text_element(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        <&[T] as ToSqlText>::to_sql_text(&&self[..], ty, out)
+    }
 }
 
 #[cfg(test)]
@@ -292,6 +510,122 @@ mod test {
         assert_eq!("{NULL,8}", String::from_utf8_lossy(buf.freeze().as_ref()));
     }
 
+    #[test]
+    fn test_array() {
+        let data = vec!["a b", "", "NULL", "x\"y", "plain"];
+        let mut buf = BytesMut::new();
+        data.to_sql_text(&Type::TEXT_ARRAY, &mut buf).unwrap();
+        assert_eq!(
+            r#"{"a b","","NULL","x\"y",plain}"#,
+            String::from_utf8_lossy(buf.freeze().as_ref())
+        );
+
+        let data = vec![vec![Some(1), None], vec![Some(3), Some(4)]];
+        let mut buf = BytesMut::new();
+        data.to_sql_text(&Type::INT4_ARRAY, &mut buf).unwrap();
+        assert_eq!(
+            "{{1,NULL},{3,4}}",
+            String::from_utf8_lossy(buf.freeze().as_ref())
+        );
+
+        // elements are formatted with the member type
+        let data = vec![NaiveDate::from_ymd_opt(2023, 3, 5).unwrap()];
+        let mut buf = BytesMut::new();
+        data.to_sql_text(&Type::DATE_ARRAY, &mut buf).unwrap();
+        assert_eq!(
+            "{2023-03-05}",
+            String::from_utf8_lossy(buf.freeze().as_ref())
+        );
+
+        let data = vec![vec![1u8, 2]];
+        let mut buf = BytesMut::new();
+        data.to_sql_text(&Type::BYTEA_ARRAY, &mut buf).unwrap();
+        assert_eq!(
+            r#"{"\\x0102"}"#,
+            String::from_utf8_lossy(buf.freeze().as_ref())
+        );
+    }
+
+    #[test]
+    fn test_float() {
+        fn text<T: ToSqlText>(value: T, ty: &Type) -> String {
+            let mut buf = BytesMut::new();
+            value.to_sql_text(ty, &mut buf).unwrap();
+            String::from_utf8(buf.to_vec()).unwrap()
+        }
+
+        assert_eq!("NaN", text(f64::NAN, &Type::FLOAT8));
+        assert_eq!("Infinity", text(f64::INFINITY, &Type::FLOAT8));
+        assert_eq!("-Infinity", text(f32::NEG_INFINITY, &Type::FLOAT4));
+        assert_eq!("-Infinity", text(f64::NEG_INFINITY, &Type::NUMERIC));
+        assert_eq!("0.1", text(0.1f64, &Type::FLOAT8));
+        assert_eq!("-0", text(-0.0f64, &Type::FLOAT8));
+        assert_eq!("0.0001", text(0.0001f64, &Type::FLOAT8));
+        assert_eq!("1e-05", text(0.00001f64, &Type::FLOAT8));
+        assert_eq!("123456789012345", text(123456789012345f64, &Type::FLOAT8));
+        assert_eq!(
+            "1.2345678901234568e+15",
+            text(1234567890123456.8, &Type::FLOAT8)
+        );
+        assert_eq!("1e+100", text(1e100f64, &Type::FLOAT8));
+        assert_eq!("123456", text(123456f32, &Type::FLOAT4));
+        assert_eq!("1.234567e+06", text(1234567f32, &Type::FLOAT4));
+        assert_eq!("100000000000000000000", text(1e20f64, &Type::NUMERIC));
+    }
+
+    #[test]
+    fn test_special_dates() {
+        fn text<T: ToSqlText>(value: T, ty: &Type) -> String {
+            let mut buf = BytesMut::new();
+            value.to_sql_text(ty, &mut buf).unwrap();
+            String::from_utf8(buf.to_vec()).unwrap()
+        }
+
+        let bc = NaiveDate::from_ymd_opt(-43, 3, 15).unwrap();
+        assert_eq!("0044-03-15 BC", text(bc, &Type::DATE));
+        assert_eq!(
+            bc,
+            NaiveDate::from_sql_text(&Type::DATE, b"0044-03-15 BC").unwrap()
+        );
+        let far = NaiveDate::from_ymd_opt(10000, 1, 1).unwrap();
+        assert_eq!("10000-01-01", text(far, &Type::DATE));
+
+        assert_eq!(
+            "infinity",
+            text(Date::<NaiveDate>::PosInfinity, &Type::DATE)
+        );
+        assert_eq!(
+            "-infinity",
+            text(Timestamp::<NaiveDateTime>::NegInfinity, &Type::TIMESTAMP)
+        );
+        assert_eq!(
+            Timestamp::<NaiveDateTime>::PosInfinity,
+            Timestamp::from_sql_text(&Type::TIMESTAMP, b"Infinity").unwrap()
+        );
+
+        // nanoseconds are rounded to microseconds, carrying to the next day
+        let late = NaiveDate::from_ymd_opt(2023, 12, 31)
+            .unwrap()
+            .and_hms_nano_opt(23, 59, 59, 999_999_600)
+            .unwrap();
+        assert_eq!("2024-01-01 00:00:00.000000", text(late, &Type::TIMESTAMP));
+        assert_eq!(
+            late.round_subsecs(6),
+            NaiveDateTime::from_sql_text(&Type::TIMESTAMP, b"2023-12-31 23:59:59.9999996").unwrap()
+        );
+        assert_eq!(
+            "0001-12-31 23:59:59.000000+00 BC",
+            text(
+                NaiveDate::from_ymd_opt(0, 12, 31)
+                    .unwrap()
+                    .and_hms_opt(23, 59, 59)
+                    .unwrap()
+                    .and_utc(),
+                &Type::TIMESTAMPTZ
+            )
+        );
+    }
+
     #[test]
     fn test_bool() {
         let yes = true;
@@ -0,0 +1,324 @@
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use bytes::{Buf, BufMut, BytesMut};
+use postgres_types::{to_sql_checked, FromSql, IsNull, ToSql, Type};
+
+use super::{FromSqlText, ToSqlText};
+
+// address family in binary format, `PGSQL_AF_INET` and `PGSQL_AF_INET6`
+const AF_INET: u8 = 2;
+const AF_INET6: u8 = 3;
+
+/// Postgres `INET` or `CIDR`, an IP address with netmask length.
+///
+/// `std::net::IpAddr` can be used for `INET` without netmask.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, new)]
+pub struct Inet {
+    pub addr: IpAddr,
+    pub netmask: u8,
+}
+
+fn max_netmask(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+impl Inet {
+    /// Returns error when netmask exceeds the address length, or the address
+    /// has bits set right of the netmask for a `CIDR`.
+    fn check(&self, ty: &Type) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let bits = max_netmask(&self.addr);
+        if self.netmask > bits {
+            return Err(
+                format!("invalid netmask length {} for {}", self.netmask, self.addr).into(),
+            );
+        }
+        if *ty == Type::CIDR {
+            let addr = match self.addr {
+                IpAddr::V4(addr) => u32::from(addr) as u128,
+                IpAddr::V6(addr) => u128::from(addr),
+            };
+            // all bits are host bits of /0, no bits of a full netmask
+            let host_bits = u32::from(bits - self.netmask);
+            let host_mask = match host_bits {
+                0 => 0,
+                _ => u128::MAX >> (128 - host_bits),
+            };
+            if addr & host_mask != 0 {
+                return Err(format!(
+                    "invalid cidr value \"{self}\": has bits set to right of mask"
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<IpAddr> for Inet {
+    fn from(addr: IpAddr) -> Self {
+        Inet::new(addr, max_netmask(&addr))
+    }
+}
+
+impl fmt::Display for Inet {
+    /// Netmask is omitted when it covers the whole address, like postgres
+    /// output of `INET`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.netmask == max_netmask(&self.addr) {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.netmask)
+        }
+    }
+}
+
+impl FromStr for Inet {
+    type Err = Box<dyn Error + Sync + Send>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let inet = match s.split_once('/') {
+            Some((addr, netmask)) => Inet::new(addr.parse()?, netmask.parse()?),
+            None => Inet::from(s.parse::<IpAddr>()?),
+        };
+        inet.check(&Type::INET)?;
+        Ok(inet)
+    }
+}
+
+impl ToSql for Inet {
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        self.check(ty)?;
+        let (family, addr) = match self.addr {
+            IpAddr::V4(addr) => (AF_INET, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (AF_INET6, addr.octets().to_vec()),
+        };
+        out.put_u8(family);
+        out.put_u8(self.netmask);
+        out.put_u8((*ty == Type::CIDR).into());
+        out.put_u8(addr.len() as u8);
+        out.put_slice(&addr);
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        matches!(*ty, Type::INET | Type::CIDR)
+    }
+
+    to_sql_checked!();
+}
+
+impl<'a> FromSql<'a> for Inet {
+    fn from_sql(_ty: &Type, mut raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        let invalid = || "invalid binary format of inet";
+        if raw.remaining() < 4 {
+            return Err(invalid().into());
+        }
+        let family = raw.get_u8();
+        let netmask = raw.get_u8();
+        let _is_cidr = raw.get_u8();
+        let len = raw.get_u8() as usize;
+        if raw.remaining() != len {
+            return Err(invalid().into());
+        }
+        let addr = match (family, len) {
+            (AF_INET, 4) => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(raw)?)),
+            (AF_INET6, 16) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(raw)?)),
+            _ => return Err(invalid().into()),
+        };
+        Ok(Inet::new(addr, netmask))
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        matches!(*ty, Type::INET | Type::CIDR)
+    }
+}
+
+impl ToSqlText for Inet {
+    fn to_sql_text(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        self.check(ty)?;
+        // netmask is always written for `CIDR`
+        if *ty == Type::CIDR {
+            out.put_slice(format!("{}/{}", self.addr, self.netmask).as_bytes());
+        } else {
+            out.put_slice(self.to_string().as_bytes());
+        }
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSqlText for Inet {
+    fn from_sql_text(ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        let inet = std::str::from_utf8(input)?.parse::<Inet>()?;
+        inet.check(ty)?;
+        Ok(inet)
+    }
+}
+
+impl ToSqlText for IpAddr {
+    fn to_sql_text(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        Inet::from(*self).to_sql_text(ty, out)
+    }
+}
+
+impl FromSqlText for IpAddr {
+    /// Netmask is ignored like `FromSql` of `IpAddr`.
+    fn from_sql_text(ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        Inet::from_sql_text(ty, input).map(|inet| inet.addr)
+    }
+}
+
+/// Postgres `MACADDR`, written like `08:00:2b:01:02:03`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+impl FromStr for MacAddr {
+    type Err = Box<dyn Error + Sync + Send>;
+
+    /// Bytes can be separated by `:` or `-`, or not separated.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s
+            .trim()
+            .chars()
+            .filter(|c| !matches!(c, ':' | '-'))
+            .collect::<String>();
+        let mut addr = [0u8; 6];
+        hex::decode_to_slice(&digits, &mut addr)
+            .map_err(|_| format!("invalid input syntax for type macaddr: \"{s}\""))?;
+        Ok(MacAddr(addr))
+    }
+}
+
+impl ToSql for MacAddr {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.put_slice(&self.0);
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::MACADDR
+    }
+
+    to_sql_checked!();
+}
+
+impl<'a> FromSql<'a> for MacAddr {
+    fn from_sql(_ty: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        Ok(MacAddr(
+            raw.try_into()
+                .map_err(|_| "invalid binary format of macaddr")?,
+        ))
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::MACADDR
+    }
+}
+
+impl ToSqlText for MacAddr {
+    fn to_sql_text(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.put_slice(self.to_string().as_bytes());
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSqlText for MacAddr {
+    fn from_sql_text(_ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        std::str::from_utf8(input)?.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inet() {
+        let inet = Inet::from_sql_text(&Type::INET, b"192.168.1.5/24").unwrap();
+        assert_eq!(Inet::new(Ipv4Addr::new(192, 168, 1, 5).into(), 24), inet);
+        assert_eq!("192.168.1.5/24", inet.to_string());
+
+        let mut buf = BytesMut::new();
+        inet.to_sql(&Type::INET, &mut buf).unwrap();
+        assert_eq!(&[AF_INET, 24, 0, 4, 192, 168, 1, 5][..], &buf[..]);
+        assert_eq!(inet, Inet::from_sql(&Type::INET, &buf).unwrap());
+
+        // host bits are not allowed in cidr
+        assert!(inet.to_sql(&Type::CIDR, &mut buf).is_err());
+        assert!(Inet::from_sql_text(&Type::CIDR, b"192.168.1.5/24").is_err());
+
+        let cidr = Inet::from_sql_text(&Type::CIDR, b"10.0.0.0/8").unwrap();
+        let mut buf = BytesMut::new();
+        cidr.to_sql(&Type::CIDR, &mut buf).unwrap();
+        assert_eq!(&[AF_INET, 8, 1, 4, 10, 0, 0, 0][..], &buf[..]);
+
+        let addr: IpAddr = "::1".parse().unwrap();
+        let mut buf = BytesMut::new();
+        addr.to_sql_text(&Type::CIDR, &mut buf).unwrap();
+        assert_eq!(&b"::1/128"[..], &buf[..]);
+        let mut buf = BytesMut::new();
+        addr.to_sql_text(&Type::INET, &mut buf).unwrap();
+        assert_eq!(&b"::1"[..], &buf[..]);
+        assert_eq!(addr, IpAddr::from_sql_text(&Type::INET, b"::1/64").unwrap());
+
+        assert!("10.0.0.1/33".parse::<Inet>().is_err());
+        assert!("10.0.0".parse::<Inet>().is_err());
+
+        // every bit is a host bit of /0, and none is of a full netmask
+        assert!(Inet::from_sql_text(&Type::CIDR, b"0.0.0.0/0").is_ok());
+        assert!(Inet::from_sql_text(&Type::CIDR, b"10.0.0.0/0").is_err());
+        assert!(Inet::from_sql_text(&Type::CIDR, b"::/0").is_ok());
+        assert!(Inet::from_sql_text(&Type::CIDR, b"::1/0").is_err());
+        assert!(Inet::from_sql_text(&Type::CIDR, b"8000::/0").is_err());
+        assert!(Inet::from_sql_text(&Type::CIDR, b"10.0.0.1/32").is_ok());
+        assert!(Inet::from_sql_text(&Type::CIDR, b"ffff::1/128").is_ok());
+    }
+
+    #[test]
+    fn test_macaddr() {
+        let mac = MacAddr::from_sql_text(&Type::MACADDR, b"08-00-2B-01-02-03").unwrap();
+        assert_eq!(MacAddr([8, 0, 0x2b, 1, 2, 3]), mac);
+        assert_eq!("08:00:2b:01:02:03", mac.to_string());
+        assert_eq!(mac, "08002b010203".parse().unwrap());
+
+        let mut buf = BytesMut::new();
+        mac.to_sql(&Type::MACADDR, &mut buf).unwrap();
+        assert_eq!(mac, MacAddr::from_sql(&Type::MACADDR, &buf).unwrap());
+        assert!(MacAddr::from_sql(&Type::MACADDR, &buf[..5]).is_err());
+
+        assert!("08:00:2b:01:02".parse::<MacAddr>().is_err());
+    }
+}
@@ -0,0 +1,220 @@
+//! `NUMERIC` type backed by decimal libraries, with feature `rust-decimal` for
+//! `rust_decimal::Decimal` and feature `bigdecimal` for
+//! `bigdecimal::BigDecimal`.
+//!
+//! `Decimal` has binary encoding from postgres-types. `BigDecimal` has no
+//! postgres support, wrap it in `Numeric` to encode or decode binary format.
+
+use std::error::Error;
+
+use bytes::{BufMut, BytesMut};
+use postgres_types::{IsNull, Type};
+
+use super::{FromSqlText, ToSqlText};
+
+fn text(input: &[u8]) -> Result<&str, Box<dyn Error + Sync + Send>> {
+    Ok(std::str::from_utf8(input)?.trim())
+}
+
+#[cfg(feature = "rust-decimal")]
+impl ToSqlText for rust_decimal::Decimal {
+    fn to_sql_text(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.put_slice(self.to_string().as_bytes());
+        Ok(IsNull::No)
+    }
+}
+
+#[cfg(feature = "rust-decimal")]
+impl FromSqlText for rust_decimal::Decimal {
+    fn from_sql_text(_ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        // error instead of rounding when digits exceed the precision
+        Ok(rust_decimal::Decimal::from_str_exact(text(input)?)?)
+    }
+}
+
+#[cfg(feature = "bigdecimal")]
+pub use self::big::Numeric;
+
+#[cfg(feature = "bigdecimal")]
+mod big {
+    use std::str::FromStr;
+
+    use bigdecimal::num_bigint::Sign;
+    use bigdecimal::BigDecimal;
+    use postgres_types::{to_sql_checked, FromSql, ToSql};
+
+    use super::*;
+    use crate::types::binary::{decode_numeric, encode_numeric};
+
+    // decimal without scientific notation, like postgres output
+    fn plain_string(value: &BigDecimal) -> String {
+        let (int, scale) = value.as_bigint_and_exponent();
+        let mut text = if int.sign() == Sign::Minus {
+            "-".to_owned()
+        } else {
+            String::new()
+        };
+        let digits = int.magnitude().to_string();
+        if scale <= 0 {
+            text.push_str(&digits);
+            text.extend(std::iter::repeat('0').take(scale.unsigned_abs() as usize));
+        } else {
+            let scale = scale as usize;
+            let padded = format!("{digits:0>width$}", width = scale + 1);
+            let (int_part, frac_part) = padded.split_at(padded.len() - scale);
+            text.push_str(int_part);
+            text.push('.');
+            text.push_str(frac_part);
+        }
+        text
+    }
+
+    /// `NUMERIC` value backed by `BigDecimal`, which can be encoded in binary
+    /// format.
+    #[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct Numeric(pub BigDecimal);
+
+    impl From<BigDecimal> for Numeric {
+        fn from(value: BigDecimal) -> Self {
+            Numeric(value)
+        }
+    }
+
+    impl From<Numeric> for BigDecimal {
+        fn from(value: Numeric) -> Self {
+            value.0
+        }
+    }
+
+    impl ToSql for Numeric {
+        fn to_sql(
+            &self,
+            _ty: &Type,
+            out: &mut BytesMut,
+        ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+            encode_numeric(&plain_string(&self.0), out)?;
+            Ok(IsNull::No)
+        }
+
+        fn accepts(ty: &Type) -> bool {
+            *ty == Type::NUMERIC
+        }
+
+        to_sql_checked!();
+    }
+
+    impl<'a> FromSql<'a> for Numeric {
+        fn from_sql(_ty: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+            // NaN and infinity are not supported by `BigDecimal`
+            Ok(Numeric(BigDecimal::from_str(&decode_numeric(raw)?)?))
+        }
+
+        fn accepts(ty: &Type) -> bool {
+            *ty == Type::NUMERIC
+        }
+    }
+
+    impl ToSqlText for BigDecimal {
+        fn to_sql_text(
+            &self,
+            _ty: &Type,
+            out: &mut BytesMut,
+        ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+            out.put_slice(plain_string(self).as_bytes());
+            Ok(IsNull::No)
+        }
+    }
+
+    impl FromSqlText for BigDecimal {
+        fn from_sql_text(_ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+            Ok(BigDecimal::from_str(text(input)?)?)
+        }
+    }
+
+    impl ToSqlText for Numeric {
+        fn to_sql_text(
+            &self,
+            ty: &Type,
+            out: &mut BytesMut,
+        ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+            self.0.to_sql_text(ty, out)
+        }
+    }
+
+    impl FromSqlText for Numeric {
+        fn from_sql_text(ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+            BigDecimal::from_sql_text(ty, input).map(Numeric)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "rust-decimal")]
+    #[test]
+    fn test_rust_decimal() {
+        use postgres_types::{FromSql, ToSql};
+        use rust_decimal::Decimal;
+
+        let value = Decimal::from_sql_text(&Type::NUMERIC, b"-12345.6700").unwrap();
+        let mut text = BytesMut::new();
+        value.to_sql_text(&Type::NUMERIC, &mut text).unwrap();
+        assert_eq!(&b"-12345.6700"[..], &text[..]);
+
+        // binary format of postgres-types matches ours
+        let mut binary = BytesMut::new();
+        value.to_sql(&Type::NUMERIC, &mut binary).unwrap();
+        assert_eq!(
+            "-12345.6700",
+            crate::types::binary::decode_numeric(&binary).unwrap()
+        );
+        assert_eq!(value, Decimal::from_sql(&Type::NUMERIC, &binary).unwrap());
+
+        assert!(Decimal::from_sql_text(&Type::NUMERIC, b"1.2.3").is_err());
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    #[test]
+    fn test_bigdecimal() {
+        use std::str::FromStr;
+
+        use bigdecimal::BigDecimal;
+        use postgres_types::{FromSql, ToSql};
+
+        let value =
+            Numeric::from_sql_text(&Type::NUMERIC, b"123456789012345678901234567890.000001")
+                .unwrap();
+        let mut text = BytesMut::new();
+        value.to_sql_text(&Type::NUMERIC, &mut text).unwrap();
+        assert_eq!(&b"123456789012345678901234567890.000001"[..], &text[..]);
+
+        let mut binary = BytesMut::new();
+        value.to_sql(&Type::NUMERIC, &mut binary).unwrap();
+        assert_eq!(value, Numeric::from_sql(&Type::NUMERIC, &binary).unwrap());
+
+        let mut text = BytesMut::new();
+        BigDecimal::from_str("1e3")
+            .unwrap()
+            .to_sql_text(&Type::NUMERIC, &mut text)
+            .unwrap();
+        assert_eq!(&b"1000"[..], &text[..]);
+
+        let mut text = BytesMut::new();
+        BigDecimal::from_str("-0.0012")
+            .unwrap()
+            .to_sql_text(&Type::NUMERIC, &mut text)
+            .unwrap();
+        assert_eq!(&b"-0.0012"[..], &text[..]);
+
+        let mut binary = BytesMut::new();
+        crate::types::binary::encode_numeric("NaN", &mut binary).unwrap();
+        assert!(Numeric::from_sql(&Type::NUMERIC, &binary).is_err());
+        assert!(!<Numeric as ToSql>::accepts(&Type::FLOAT8));
+    }
+}
@@ -0,0 +1,75 @@
+//! Text format of `serde_json::Value` and `postgres_types::Json` for `JSON`
+//! and `JSONB`, enabled by feature `serde_json`. Binary format, with version
+//! prefix for `JSONB`, is from postgres-types.
+
+use std::error::Error;
+use std::fmt::Debug;
+
+use bytes::{BufMut, BytesMut};
+use postgres_types::{IsNull, Json, Type};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::{FromSqlText, ToSqlText};
+
+impl ToSqlText for Value {
+    fn to_sql_text(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        Json(self).to_sql_text(ty, out)
+    }
+}
+
+impl FromSqlText for Value {
+    fn from_sql_text(_ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        Ok(serde_json::from_slice(input)?)
+    }
+}
+
+impl<T: Serialize + Debug> ToSqlText for Json<T> {
+    fn to_sql_text(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        serde_json::to_writer(out.writer(), &self.0)?;
+        Ok(IsNull::No)
+    }
+}
+
+impl<T: DeserializeOwned> FromSqlText for Json<T> {
+    fn from_sql_text(_ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        Ok(Json(serde_json::from_slice(input)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use postgres_types::{FromSql, ToSql};
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_json() {
+        let value = json!({"id": 1, "tags": ["a", "b"]});
+        let mut buf = BytesMut::new();
+        value.to_sql_text(&Type::JSONB, &mut buf).unwrap();
+        assert_eq!(&br#"{"id":1,"tags":["a","b"]}"#[..], &buf[..]);
+        assert_eq!(value, Value::from_sql_text(&Type::JSONB, &buf).unwrap());
+
+        // jsonb has version prefix in binary format
+        let mut binary = BytesMut::new();
+        value.to_sql(&Type::JSONB, &mut binary).unwrap();
+        assert_eq!(1, binary[0]);
+        assert_eq!(&buf[..], &binary[1..]);
+        assert_eq!(value, Value::from_sql(&Type::JSONB, &binary).unwrap());
+
+        let tags = Json::<Vec<String>>::from_sql_text(&Type::JSON, br#"["x"]"#).unwrap();
+        assert_eq!(vec!["x".to_owned()], tags.0);
+        assert!(Value::from_sql_text(&Type::JSON, b"{").is_err());
+    }
+}
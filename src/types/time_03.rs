@@ -0,0 +1,226 @@
+//! Text format of `time` crate types, enabled by feature `time`. Binary format
+//! is from postgres-types.
+//!
+//! Values are converted to their chrono equivalents, so both crates have the
+//! same text format.
+
+use std::error::Error;
+
+use bytes::BytesMut;
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+use postgres_types::{IsNull, Type};
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset};
+
+use super::{FromSqlText, Interval, ToSqlText};
+
+fn to_chrono_date(date: Date) -> Result<NaiveDate, Box<dyn Error + Sync + Send>> {
+    NaiveDate::from_ymd_opt(
+        date.year(),
+        u8::from(date.month()).into(),
+        date.day().into(),
+    )
+    .ok_or_else(|| "date out of range".into())
+}
+
+fn to_chrono_time(time: Time) -> Result<NaiveTime, Box<dyn Error + Sync + Send>> {
+    NaiveTime::from_hms_nano_opt(
+        time.hour().into(),
+        time.minute().into(),
+        time.second().into(),
+        time.nanosecond(),
+    )
+    .ok_or_else(|| "time out of range".into())
+}
+
+fn from_chrono_date(date: NaiveDate) -> Result<Date, Box<dyn Error + Sync + Send>> {
+    use chrono::Datelike;
+
+    Ok(Date::from_calendar_date(
+        date.year(),
+        Month::try_from(date.month() as u8)?,
+        date.day() as u8,
+    )?)
+}
+
+fn from_chrono_time(time: NaiveTime) -> Result<Time, Box<dyn Error + Sync + Send>> {
+    Ok(Time::from_hms_nano(
+        time.hour() as u8,
+        time.minute() as u8,
+        time.second() as u8,
+        time.nanosecond(),
+    )?)
+}
+
+impl ToSqlText for Date {
+    fn to_sql_text(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        to_chrono_date(*self)?.to_sql_text(ty, out)
+    }
+}
+
+impl ToSqlText for Time {
+    fn to_sql_text(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        to_chrono_time(*self)?.to_sql_text(ty, out)
+    }
+}
+
+impl ToSqlText for PrimitiveDateTime {
+    fn to_sql_text(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        NaiveDateTime::new(to_chrono_date(self.date())?, to_chrono_time(self.time())?)
+            .to_sql_text(ty, out)
+    }
+}
+
+impl ToSqlText for OffsetDateTime {
+    fn to_sql_text(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        let offset = FixedOffset::east_opt(self.offset().whole_seconds())
+            .ok_or("time zone offset out of range")?;
+        NaiveDateTime::new(to_chrono_date(self.date())?, to_chrono_time(self.time())?)
+            .and_local_timezone(offset)
+            .single()
+            .ok_or("timestamp out of range")?
+            .to_sql_text(ty, out)
+    }
+}
+
+impl FromSqlText for Date {
+    fn from_sql_text(ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        from_chrono_date(NaiveDate::from_sql_text(ty, input)?)
+    }
+}
+
+impl FromSqlText for Time {
+    fn from_sql_text(ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        from_chrono_time(NaiveTime::from_sql_text(ty, input)?)
+    }
+}
+
+impl FromSqlText for PrimitiveDateTime {
+    fn from_sql_text(ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        let datetime = NaiveDateTime::from_sql_text(ty, input)?;
+        Ok(PrimitiveDateTime::new(
+            from_chrono_date(datetime.date())?,
+            from_chrono_time(datetime.time())?,
+        ))
+    }
+}
+
+impl FromSqlText for OffsetDateTime {
+    fn from_sql_text(ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        let datetime = DateTime::<FixedOffset>::from_sql_text(ty, input)?;
+        let local = datetime.naive_local();
+        Ok(PrimitiveDateTime::new(
+            from_chrono_date(local.date())?,
+            from_chrono_time(local.time())?,
+        )
+        .assume_offset(UtcOffset::from_whole_seconds(
+            datetime.offset().local_minus_utc(),
+        )?))
+    }
+}
+
+impl Interval {
+    /// Convert to `time::Duration`, with 24 hours for a day. Returns `None`
+    /// when the interval has months.
+    pub fn to_time_duration(&self) -> Option<time::Duration> {
+        if self.months != 0 {
+            return None;
+        }
+        time::Duration::days(self.days.into())
+            .checked_add(time::Duration::microseconds(self.microseconds))
+    }
+}
+
+impl From<time::Duration> for Interval {
+    /// Converted to microseconds only, saturated at the range of `i64`.
+    fn from(duration: time::Duration) -> Self {
+        let microseconds = duration
+            .whole_microseconds()
+            .clamp(i64::MIN.into(), i64::MAX.into());
+        Interval::new(0, 0, microseconds as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use postgres_types::ToSql;
+
+    use super::*;
+
+    #[test]
+    fn test_time_text() {
+        let datetime = PrimitiveDateTime::new(
+            Date::from_calendar_date(2023, Month::March, 5).unwrap(),
+            Time::from_hms_micro(1, 2, 3, 456).unwrap(),
+        )
+        .assume_offset(UtcOffset::from_hms(1, 0, 0).unwrap());
+
+        let mut buf = BytesMut::new();
+        datetime.to_sql_text(&Type::TIMESTAMPTZ, &mut buf).unwrap();
+        assert_eq!(&b"2023-03-05 01:02:03.000456+01"[..], &buf[..]);
+        assert_eq!(
+            datetime,
+            OffsetDateTime::from_sql_text(&Type::TIMESTAMPTZ, &buf).unwrap()
+        );
+
+        let primitive = PrimitiveDateTime::new(datetime.date(), datetime.time());
+        let mut buf = BytesMut::new();
+        primitive.to_sql_text(&Type::TIMESTAMP, &mut buf).unwrap();
+        assert_eq!(&b"2023-03-05 01:02:03.000456"[..], &buf[..]);
+        assert_eq!(
+            primitive,
+            PrimitiveDateTime::from_sql_text(&Type::TIMESTAMP, &buf).unwrap()
+        );
+
+        let mut buf = BytesMut::new();
+        datetime.date().to_sql_text(&Type::DATE, &mut buf).unwrap();
+        assert_eq!(&b"2023-03-05"[..], &buf[..]);
+        assert_eq!(
+            datetime.date(),
+            Date::from_sql_text(&Type::DATE, &buf).unwrap()
+        );
+
+        let mut buf = BytesMut::new();
+        datetime.time().to_sql_text(&Type::TIME, &mut buf).unwrap();
+        assert_eq!(&b"01:02:03.000456"[..], &buf[..]);
+        assert_eq!(
+            datetime.time(),
+            Time::from_sql_text(&Type::TIME, &buf).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_time_binary() {
+        // days since postgres epoch 2000-01-01
+        let mut buf = BytesMut::new();
+        Date::from_calendar_date(2000, Month::January, 2)
+            .unwrap()
+            .to_sql(&Type::DATE, &mut buf)
+            .unwrap();
+        assert_eq!(&1i32.to_be_bytes()[..], &buf[..]);
+    }
+
+    #[test]
+    fn test_time_duration() {
+        let duration = time::Duration::hours(25);
+        let interval = Interval::from(duration);
+        assert_eq!(Interval::new(0, 0, 90_000_000_000), interval);
+        assert_eq!(Some(duration), interval.to_time_duration());
+        assert_eq!(None, Interval::new(1, 0, 0).to_time_duration());
+    }
+}
@@ -0,0 +1,56 @@
+//! Text format of `uuid::Uuid`, enabled by feature `uuid`. Binary format is
+//! from postgres-types.
+
+use std::error::Error;
+
+use bytes::{BufMut, BytesMut};
+use postgres_types::{IsNull, Type};
+use uuid::Uuid;
+
+use super::{FromSqlText, ToSqlText};
+
+impl ToSqlText for Uuid {
+    fn to_sql_text(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        // lowercase with hyphens, like postgres output
+        out.put_slice(self.hyphenated().to_string().as_bytes());
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSqlText for Uuid {
+    fn from_sql_text(_ty: &Type, input: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        // accepts formats with or without hyphens and braces
+        Ok(Uuid::parse_str(std::str::from_utf8(input)?.trim())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use postgres_types::{FromSql, ToSql};
+
+    use super::*;
+
+    #[test]
+    fn test_uuid() {
+        let uuid =
+            Uuid::from_sql_text(&Type::UUID, b"{A0EEBC99-9C0B-4EF8-BB6D-6BB9BD380A11}").unwrap();
+        let mut buf = BytesMut::new();
+        uuid.to_sql_text(&Type::UUID, &mut buf).unwrap();
+        assert_eq!(&b"a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11"[..], &buf[..]);
+        assert_eq!(
+            uuid,
+            Uuid::from_sql_text(&Type::UUID, b"a0eebc999c0b4ef8bb6d6bb9bd380a11").unwrap()
+        );
+
+        let mut buf = BytesMut::new();
+        uuid.to_sql(&Type::UUID, &mut buf).unwrap();
+        assert_eq!(uuid.as_bytes(), &buf[..]);
+        assert_eq!(uuid, Uuid::from_sql(&Type::UUID, &buf).unwrap());
+
+        assert!(Uuid::from_sql_text(&Type::UUID, b"a0eebc99").is_err());
+    }
+}
@@ -0,0 +1,245 @@
+//! Serve connections accepted with `tokio_uring`, enabled by feature
+//! `tokio-uring` on linux.
+//!
+//! Socket reads and writes are submitted to io_uring with owned buffers of
+//! `BUFFER_SIZE`, so streaming wide results takes fewer syscalls than with
+//! epoll. The bytes are passed through an in-memory pipe to
+//! `pgwire::tokio::process_stream`, which runs the protocol as for any
+//! other stream, including TLS.
+//!
+//! Futures here are not `Send`, run them on the `tokio_uring` runtime:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use pgwire::api::auth::StartupHandler;
+//! use pgwire::api::copy::CopyHandler;
+//! use pgwire::api::query::{ExtendedQueryHandler, SimpleQueryHandler};
+//! use pgwire::tokio::{Handlers, ServerOptions};
+//! use tokio_rustls::TlsAcceptor;
+//!
+//! fn serve<A, Q, EQ, CH>(startup: Arc<A>, query: Arc<Q>, extended: Arc<EQ>, copy: Arc<CH>)
+//! where
+//!     A: StartupHandler + 'static,
+//!     Q: SimpleQueryHandler + 'static,
+//!     EQ: ExtendedQueryHandler + 'static,
+//!     CH: CopyHandler + 'static,
+//! {
+//!     tokio_uring::start(async move {
+//!         let addr = "127.0.0.1:5432".parse().unwrap();
+//!         let listener = tokio_uring::net::TcpListener::bind(addr).unwrap();
+//!         let options = Arc::new(ServerOptions::new());
+//!         let handlers = Handlers::new(startup, query, extended).with_copy_handler(copy);
+//!         loop {
+//!             let (socket, addr) = listener.accept().await.unwrap();
+//!             tokio_uring::spawn(pgwire::uring::process_socket(
+//!                 socket,
+//!                 addr,
+//!                 None::<Arc<TlsAcceptor>>,
+//!                 options.clone(),
+//!                 handlers.clone(),
+//!             ));
+//!         }
+//!     });
+//! }
+//! ```
+
+use std::io::Error as IOError;
+use std::net::{Shutdown, SocketAddr};
+use std::os::fd::{AsRawFd, BorrowedFd};
+use std::sync::Arc;
+
+use bytes::BytesMut;
+use socket2::SockRef;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio_uring::buf::BoundedBuf;
+use tokio_uring::net::TcpStream;
+
+use crate::api::auth::StartupHandler;
+use crate::api::copy::CopyHandler;
+use crate::api::query::{ExtendedQueryHandler, SimpleQueryHandler};
+use crate::tokio::{process_stream, Handlers, ServerOptions, TlsUpgrade};
+
+/// Size of buffers of socket reads and writes, and of the pipe to
+/// `process_stream`.
+pub const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Process a client connection accepted by `tokio_uring::net::TcpListener`,
+/// `addr` is the peer address returned by `accept`. `tls` is like in
+/// `pgwire::tokio::process_socket_with_tls`.
+pub async fn process_socket<T, A, Q, EQ, CH>(
+    socket: TcpStream,
+    addr: SocketAddr,
+    tls: Option<Arc<T>>,
+    options: Arc<ServerOptions>,
+    handlers: Handlers<A, Q, EQ, CH>,
+) -> Result<(), IOError>
+where
+    T: TlsUpgrade,
+    A: StartupHandler,
+    Q: SimpleQueryHandler,
+    EQ: ExtendedQueryHandler,
+    CH: CopyHandler,
+{
+    // safety: the fd is owned by `socket`, which outlives the borrow
+    let fd = unsafe { BorrowedFd::borrow_raw(socket.as_raw_fd()) };
+    options.tcp_options.apply_to(SockRef::from(&fd))?;
+
+    let (server, client) = tokio::io::duplex(BUFFER_SIZE);
+    let (from_server, to_server) = tokio::io::split(client);
+    let serve = process_stream(server, addr, BytesMut::new(), tls, options, handlers);
+    let pump = async {
+        let outbound = outbound(&socket, from_server);
+        tokio::pin!(outbound);
+        tokio::select! {
+            // client has closed, wait for the server to finish
+            result = inbound(&socket, to_server) => {
+                result?;
+                outbound.await
+            }
+            // server has closed the connection
+            result = &mut outbound => result,
+        }
+    };
+    let (served, pumped) = tokio::join!(serve, pump);
+    served.and(pumped)
+}
+
+/// Copy bytes from socket to server, shutting down the pipe on end of
+/// socket.
+async fn inbound(
+    socket: &TcpStream,
+    mut to_server: WriteHalf<DuplexStream>,
+) -> Result<(), IOError> {
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    loop {
+        let (result, read_buf) = socket.read(buf).await;
+        buf = read_buf;
+        let n = result?;
+        if n == 0 {
+            return to_server.shutdown().await;
+        }
+        if to_server.write_all(&buf[..n]).await.is_err() {
+            // server has closed the pipe
+            return Ok(());
+        }
+    }
+}
+
+/// Copy bytes from server to socket, shutting down the socket when the
+/// server has finished.
+async fn outbound(
+    socket: &TcpStream,
+    mut from_server: ReadHalf<DuplexStream>,
+) -> Result<(), IOError> {
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    loop {
+        let n = from_server.read(&mut buf).await?;
+        if n == 0 {
+            return socket.shutdown(Shutdown::Write);
+        }
+        let (result, slice) = socket.write_all(buf.slice(..n)).await;
+        buf = slice.into_inner();
+        result?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use async_trait::async_trait;
+    use tokio_rustls::TlsAcceptor;
+
+    use super::*;
+    use crate::api::auth::noop::NoopStartupHandler;
+    use crate::api::query::PlaceholderExtendedQueryHandler;
+    use crate::api::results::{Response, Tag};
+    use crate::api::ClientInfo;
+    use crate::error::PgWireResult;
+    use crate::messages::simplequery::Query;
+    use crate::messages::startup::Startup;
+    use crate::messages::{Message, PgWireBackendMessage};
+
+    struct TagQueryHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for TagQueryHandler {
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(vec![Response::Execution(Tag::new(query))])
+        }
+    }
+
+    // read messages until `ReadyForQuery`
+    fn read_until_ready(stream: &mut std::net::TcpStream) -> Vec<PgWireBackendMessage> {
+        let mut buf = BytesMut::new();
+        let mut messages = Vec::new();
+        loop {
+            while let Some(message) = PgWireBackendMessage::decode(&mut buf).unwrap() {
+                let ready = matches!(message, PgWireBackendMessage::ReadyForQuery(_));
+                messages.push(message);
+                if ready {
+                    return messages;
+                }
+            }
+            let mut chunk = [0u8; 1024];
+            let n = stream.read(&mut chunk).unwrap();
+            assert!(n > 0, "connection closed");
+            buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    #[test]
+    fn test_process_socket() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            let mut buf = BytesMut::new();
+            let mut startup = Startup::new();
+            startup
+                .parameters
+                .insert("user".to_owned(), "pgwire".to_owned());
+            startup.encode(&mut buf).unwrap();
+            stream.write_all(&buf).unwrap();
+            read_until_ready(&mut stream);
+
+            buf.clear();
+            Query::new("VACUUM".to_owned()).encode(&mut buf).unwrap();
+            stream.write_all(&buf).unwrap();
+            read_until_ready(&mut stream)
+        });
+
+        let served = tokio_uring::start(async move {
+            let listener = tokio_uring::net::TcpListener::from_std(listener);
+            let (socket, addr) = listener.accept().await.unwrap();
+            process_socket(
+                socket,
+                addr,
+                None::<Arc<TlsAcceptor>>,
+                Arc::new(ServerOptions::new()),
+                Handlers::new(
+                    Arc::new(NoopStartupHandler),
+                    Arc::new(TagQueryHandler),
+                    Arc::new(PlaceholderExtendedQueryHandler),
+                ),
+            )
+            .await
+        });
+
+        let messages = client.join().unwrap();
+        let PgWireBackendMessage::CommandComplete(ref complete) = messages[0] else {
+            panic!("expected CommandComplete");
+        };
+        assert_eq!("VACUUM", complete.tag);
+        // the connection is closed by client
+        served.unwrap();
+    }
+}
@@ -4,8 +4,8 @@ use std::time::{Duration, SystemTime};
 use async_trait::async_trait;
 use futures::stream;
 use futures::StreamExt;
-use pgwire::api::auth::scram::{gen_salted_password, MakeSASLScramAuthStartupHandler};
-use pgwire::api::auth::{AuthSource, DefaultServerParameterProvider, LoginInfo, Password};
+use pgwire::api::auth::scram::{MakeScramSha256StartupHandler, ScramSecret};
+use pgwire::api::auth::{AuthSource, DefaultServerParameterProvider, LoginInfo, Verifier};
 use pgwire::api::portal::{Format, Portal};
 use pgwire::api::query::{ExtendedQueryHandler, SimpleQueryHandler};
 use pgwire::api::results::{
@@ -23,14 +23,14 @@ struct DummyAuthSource;
 
 #[async_trait]
 impl AuthSource for DummyAuthSource {
-    async fn get_password(&self, login_info: &LoginInfo) -> PgWireResult<Password> {
+    async fn get_verifier(&self, login_info: &LoginInfo) -> PgWireResult<Option<Verifier>> {
         println!("login info: {:?}", login_info);
 
         let password = "pencil";
         let salt = vec![0, 20, 40, 80];
 
-        let hash_password = gen_salted_password(password, salt.as_ref(), ITERATIONS);
-        Ok(Password::new(Some(salt), hash_password))
+        let secret = ScramSecret::new(password, salt.as_ref(), ITERATIONS);
+        Ok(Some(Verifier::ScramSha256(secret)))
     }
 }
 
@@ -96,7 +96,7 @@ impl SimpleQueryHandler for DummyDatabase {
                 ),
                 (Some(2), None, None, None),
             ];
-            let data_row_stream = stream::iter(data.into_iter()).map(move |r| {
+            let data_row_stream = stream::iter(data).map(move |r| {
                 let mut encoder = DataRowEncoder::new(schema_ref.clone());
 
                 encoder.encode_field(&r.0)?;
@@ -150,7 +150,7 @@ impl ExtendedQueryHandler for DummyDatabase {
             ];
             let schema = Arc::new(self.schema(&portal.result_column_format));
             let schema_ref = schema.clone();
-            let data_row_stream = stream::iter(data.into_iter()).map(move |r| {
+            let data_row_stream = stream::iter(data).map(move |r| {
                 let mut encoder = DataRowEncoder::new(schema_ref.clone());
 
                 encoder.encode_field(&r.0)?;
@@ -207,11 +207,11 @@ impl MakeHandler for MakeDummyDatabase {
 
 #[tokio::main]
 pub async fn main() {
-    let mut authenticator = MakeSASLScramAuthStartupHandler::new(
+    let mut authenticator = MakeScramSha256StartupHandler::new(
         Arc::new(DummyAuthSource),
         Arc::new(DefaultServerParameterProvider::default()),
     );
-    authenticator.set_iterations(ITERATIONS);
+    authenticator.set_iterations(ITERATIONS).unwrap();
     let processor = Arc::new(MakeDummyDatabase);
 
     let server_addr = "127.0.0.1:5432";